@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
 
-mod serdes;
+// Public so that other configs can read keys, addresses and durations the same way
+pub mod serdes;
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct WarpConfig {
@@ -11,8 +12,55 @@ pub struct WarpConfig {
     pub private_key: warp_protocol::PrivateKey,
     pub interfaces: InterfacesConfig,
     pub warp_map: WarpMapConfig,
+    // The peer for tunnels that don't name their own far gate
     pub far_gate: WarpFarGateConfig,
     pub tunnels: BTreeMap<String, WarpTunnelConfig>,
+    // How often the session key shared with each far gate is replaced; the defaults are used if this is omitted
+    pub rekey: Option<RekeyConfig>,
+    // The cipher suites session keys may use, most preferred first; all of them are allowed if this is omitted
+    pub cipher_suites: Option<Vec<warp_protocol::CipherSuite>>,
+    // If set, Prometheus metrics are served over HTTP at http://<bind>/metrics
+    pub metrics: Option<MetricsConfig>,
+    // How much the queues between warp's tasks hold before they drop datagrams; the defaults are used if this is
+    // omitted. Queues are sized when they are created, so a reload only affects interfaces detected after it.
+    pub queues: Option<QueuesConfig>,
+    // How long shutting down waits for queued work to be sent; the defaults are used if this is omitted
+    pub shutdown: Option<ShutdownConfig>,
+    // Where to save the addresses warp learns while running, so that after a restart it can send straight away instead
+    // of waiting to hear from warp-map; nothing is saved if this is omitted. Only read when warp starts.
+    pub state_file: Option<std::path::PathBuf>,
+    // Settings for running warp as a service; none of them apply if this is omitted. Only read when warp starts.
+    pub daemon: Option<DaemonConfig>,
+    // Opening and closing tunnels while warp runs; if this is omitted there is no control socket, far gates aren't told
+    // about tunnels opened through the library and can't open any here
+    pub control: Option<ControlConfig>,
+    // Exporting the hops of each tunnel payload as OpenTelemetry spans; nothing is exported if this is omitted. Only
+    // read when warp starts.
+    pub telemetry: Option<TelemetryConfig>,
+}
+
+impl WarpConfig {
+    /// The far gate at the other end of `tunnel`
+    pub fn far_gate_of(&self, tunnel: &WarpTunnelConfig) -> warp_protocol::PublicKey {
+        tunnel.far_gate.as_ref().unwrap_or(&self.far_gate).public_key
+    }
+
+    /// The cipher suites session keys may use, most preferred first
+    pub fn cipher_suites(&self) -> Vec<warp_protocol::CipherSuite> {
+        self.cipher_suites
+            .clone()
+            .unwrap_or_else(|| warp_protocol::CipherSuite::ALL.to_vec())
+    }
+
+    /// Every peer warp exchanges tunnel payloads with: each tunnel's far gate, and the default far gate
+    pub fn far_gates(&self) -> Vec<warp_protocol::PublicKey> {
+        let mut far_gates: Vec<_> = std::iter::once(self.far_gate.public_key)
+            .chain(self.tunnels.values().map(|tunnel| self.far_gate_of(tunnel)))
+            .collect();
+        far_gates.sort();
+        far_gates.dedup();
+        far_gates
+    }
 }
 
 // When a new interface is detected, warp will use it if and only if:
@@ -25,6 +73,8 @@ pub struct InterfacesConfig {
         deserialize_with = "serdes::deserialize_duration"
     )]
     pub interface_scan_interval: std::time::Duration,
+    // Whether interfaces are also rescanned as soon as the system says they changed; `netlink` if omitted
+    pub interface_watch: Option<InterfaceWatch>,
     #[serde(
         serialize_with = "serdes::serialize_duration",
         deserialize_with = "serdes::deserialize_duration"
@@ -42,9 +92,296 @@ pub struct InterfacesConfig {
     )]
     pub inclusion_patterns: regex::RegexSet,
     pub max_consecutive_failures: usize,
+    // How many datagrams each interface sends or receives per system call at most; 32 if omitted
+    pub batch_size: Option<usize>,
+    // The sizes of the kernel's receive and send buffers for each interface's socket, in bytes; the system's defaults
+    // are used if these are omitted
+    pub so_rcvbuf: Option<usize>,
+    pub so_sndbuf: Option<usize>,
+    // The DSCP to mark datagrams with, so routers can give them the QoS treatment it stands for; unmarked if omitted
+    pub dscp: Option<Dscp>,
+    // Limits on how fast datagrams are sent from the interfaces they name, e.g. to keep a metered LTE link from being
+    // saturated; interfaces that aren't named are unlimited
+    pub rate_limits: Option<BTreeMap<String, RateLimitConfig>>,
+    // What using the interfaces they name costs, so that tunnels keep off metered or expensive links while they can;
+    // interfaces that aren't named are unmetered and cost 0
+    pub policies: Option<BTreeMap<String, InterfacePolicyConfig>>,
+    // FreeBSD has no way to bind a socket to an interface, so with bind_to_device each interface's sockets use the
+    // routing table (FIB) given for it here instead, which should route out of that interface; ignored elsewhere
+    pub fibs: Option<BTreeMap<String, u32>>,
+    // How often each path to a peer is probed for its round trip time and loss; the defaults are used if this is omitted
+    pub path_probing: Option<PathProbingConfig>,
+    // How often heartbeats are sent to each peer address, and how long until one that hasn't been heard from is taken
+    // for dead; the defaults are used if this is omitted
+    pub liveness: Option<LivenessConfig>,
+    // How long interfaces that die or disappear are kept out of use before they are brought back; the defaults are used
+    // if this is omitted
+    pub flap_damping: Option<FlapDampingConfig>,
+    // When to fall back to sending to a far gate through warp-map, which has to be started with `--relay`; nothing is
+    // relayed if this is omitted
+    pub relay: Option<RelayConfig>,
+    // How often what each interface has sent and received so far, in all and by tunnel, is logged; not logged if this
+    // is omitted
+    pub traffic_log: Option<TrafficLogConfig>,
+}
+
+// How warp finds out about interfaces coming, going or changing address between scans
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterfaceWatch {
+    // It doesn't; changes are only seen at the next scan
+    Scan,
+    // From rtnetlink's link and address notifications, on Linux; elsewhere the same as `scan`
+    #[default]
+    Netlink,
+}
+
+// Sending is held to `rate` bytes per second on average, with bursts of up to `burst` bytes. Once a burst is used up,
+// the sender waits at least `pacing_interval` at a time for it to refill, so it wakes up at most once per interval.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RateLimitConfig {
+    pub rate: u64,
+    pub burst: u64,
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub pacing_interval: std::time::Duration,
+}
+
+// Among the paths a tunnel may use, those over interfaces with a lower `cost` are picked first. Metered interfaces are
+// only used by tunnels that allow it (see `WarpTransportConfig::metered`), unless no other interface can be.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct InterfacePolicyConfig {
+    // Not metered if omitted
+    pub metered: Option<bool>,
+    // 0 if omitted
+    pub cost: Option<u32>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TrafficLogConfig {
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub interval: std::time::Duration,
+}
+
+impl Default for TrafficLogConfig {
+    fn default() -> Self {
+        Self {
+            interval: std::time::Duration::from_secs(60),
+        }
+    }
+}
+
+// A probe that hasn't been answered within `timeout` counts as lost
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PathProbingConfig {
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub interval: std::time::Duration,
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub timeout: std::time::Duration,
+}
+
+impl Default for PathProbingConfig {
+    fn default() -> Self {
+        Self {
+            interval: std::time::Duration::from_secs(1),
+            timeout: std::time::Duration::from_secs(2),
+        }
+    }
+}
+
+// Every path to a peer carries a heartbeat each `heartbeat_interval`; a peer address that nothing has been received from
+// for `timeout` is dead, and so is a peer all of whose addresses are
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct LivenessConfig {
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub heartbeat_interval: std::time::Duration,
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub timeout: std::time::Duration,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: std::time::Duration::from_secs(1),
+            timeout: std::time::Duration::from_secs(5),
+        }
+    }
+}
+
+// An interface that dies or disappears isn't used again for `hold_down`, doubled for each other time it has failed
+// lately, up to `max_hold_down`. Failures count for half as much every `half_life`, so an interface that has been
+// stable for a while gets the short hold-down again.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct FlapDampingConfig {
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub hold_down: std::time::Duration,
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub max_hold_down: std::time::Duration,
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub half_life: std::time::Duration,
+}
+
+impl Default for FlapDampingConfig {
+    fn default() -> Self {
+        Self {
+            hold_down: std::time::Duration::from_secs(5),
+            max_hold_down: std::time::Duration::from_secs(300),
+            half_life: std::time::Duration::from_secs(600),
+        }
+    }
+}
+
+// A far gate none of whose addresses has been heard from directly for `timeout`, e.g. because both ends are behind
+// symmetric NATs, is sent to through warp-map instead. Its direct paths are still tried, and used again as soon as one
+// of them is heard from.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RelayConfig {
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub timeout: std::time::Duration,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            timeout: std::time::Duration::from_secs(10),
+        }
+    }
+}
+
+// Each interface's send queue, and each queue of datagrams received on any of them, holds up to `capacity` datagrams;
+// when one is full, `overflow` decides which datagram is dropped. Send queues are ordered by deadline, so for them the
+// oldest datagram is the one queued longest ago rather than the one nearest its deadline. Tunnel payloads waiting to be
+// sent are held to the same capacity, but make their gate wait for room instead of being dropped. Received datagrams
+// are processed by `rx_workers` tasks, each with a queue of its own for the datagrams from its share of the source
+// addresses; by default there is one per core, up to 4. Read when warp starts.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct QueuesConfig {
+    pub capacity: usize,
+    pub overflow: QueueOverflow,
+    pub rx_workers: Option<usize>,
+}
+
+impl Default for QueuesConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 4096,
+            overflow: QueueOverflow::DropOldest,
+            rx_workers: None,
+        }
+    }
 }
 
+// On shutdown, gates stop taking data from applications, and what they already took is sent along with whatever is
+// waiting in the interfaces' send queues. Whatever is left after `drain_timeout` is dropped.
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ShutdownConfig {
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub drain_timeout: std::time::Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout: std::time::Duration::from_secs(1),
+        }
+    }
+}
+
+// Starting as root is only needed for `interfaces.bind_to_device`; with `user` or `group` set, warp switches to them once
+// the interfaces found when it starts are bound. Interfaces that appear later are bound without root, which works for
+// bind_to_device on Linux 5.7 and later.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DaemonConfig {
+    // Write warp's process id here when it starts, and remove it on shutdown
+    pub pidfile: Option<std::path::PathBuf>,
+    // The user to run as, along with its groups
+    pub user: Option<String>,
+    // The group to run as, instead of the user's own
+    pub group: Option<String>,
+}
+
+// Tunnels opened while warp runs last until it stops, alongside those in `tunnels`
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ControlConfig {
+    // The Unix socket to take control requests on, if any; only the user warp starts as can connect to it. Only read
+    // when warp starts.
+    pub socket: Option<std::path::PathBuf>,
+    // Whether far gates may open tunnels here, with gates of their choosing; they are refused if this is omitted
+    pub accept_far_gate_tunnels: Option<bool>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TelemetryConfig {
+    // The OTLP/gRPC endpoint of the collector to export spans to, e.g. "http://localhost:4317"
+    pub otlp_endpoint: String,
+    // The service name the spans are exported under; "warp" if this is omitted
+    pub service_name: Option<String>,
+    // The share of tunnel payloads whose spans are exported, from 0 to 1; all of them if this is omitted
+    pub sample_ratio: Option<f64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueOverflow {
+    // Drop the datagram that has waited longest
+    DropOldest,
+    // Drop the datagram that didn't fit
+    DropNewest,
+}
+
+// A session key is replaced once it is `interval` old or has carried `max_bytes`, whichever comes first
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RekeyConfig {
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub interval: std::time::Duration,
+    pub max_bytes: u64,
+}
+
+impl Default for RekeyConfig {
+    fn default() -> Self {
+        Self {
+            interval: std::time::Duration::from_secs(120),
+            max_bytes: 1 << 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct WarpMapConfig {
     #[serde(deserialize_with = "serdes::deserialize_address")]
     pub address: std::net::SocketAddr,
@@ -53,29 +390,59 @@ pub struct WarpMapConfig {
         deserialize_with = "serdes::deserialize_public_key"
     )]
     pub public_key: warp_protocol::PublicKey,
+    // Where warp-map also serves clients over TCP, if it does; interfaces whose registrations over UDP go unanswered
+    // register over a TCP connection to it instead
+    pub tcp_address: Option<std::net::SocketAddr>,
 }
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MetricsConfig {
+    pub bind: std::net::SocketAddr,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct WarpTunnelConfig {
     pub gate: WarpGateConfig,
     pub transport: WarpTransportConfig,
     // If tunnel_id is not set, it's string name will be used instead in the transport protocol
     pub tunnel_id: Option<u64>,
+    // If far_gate is not set, the tunnel goes to the top-level far_gate
+    pub far_gate: Option<WarpFarGateConfig>,
+    // Payloads of tunnels with a higher priority are sent ahead of any lower priority payloads waiting on the same
+    // interface; 0 if omitted
+    pub priority: Option<u8>,
 }
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 #[serde(untagged)]
 pub enum WarpGateConfig {
     Loopback(LoopbackConfig),
     UnixDomainSocket(UnixDomainSocketConfig),
+    TcpListener(TcpListenerConfig),
+    Channel(ChannelConfig),
 }
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct UnixDomainSocketConfig {
     pub path: std::path::PathBuf,
 }
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+// TCP connections accepted at `listen` are carried to the far gate as streams, and streams the far gate opens are
+// connected to `connect`. Either can be omitted to only open streams from one end.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TcpListenerConfig {
+    pub listen: Option<std::net::SocketAddr>,
+    pub connect: Option<std::net::SocketAddr>,
+}
+
+// In-process channels that an application embedding warp registers as `channel`, for it to exchange payloads with the
+// gate directly; only applications embedding warp can have these
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ChannelConfig {
+    pub channel: String,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct LoopbackConfig {
     pub ipv4: bool,
     pub application_to_gate: u16,
@@ -84,7 +451,7 @@ pub struct LoopbackConfig {
     pub gate_to_application: Option<u16>,
 }
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct WarpFarGateConfig {
     #[serde(
         serialize_with = "serdes::serialize_public_key",
@@ -93,7 +460,7 @@ pub struct WarpFarGateConfig {
     pub public_key: warp_protocol::PublicKey,
 }
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct WarpTransportConfig {
     pub redundancy: RedundancyConfig,
     pub mtu: u16,
@@ -104,10 +471,169 @@ pub struct WarpTransportConfig {
         deserialize_with = "serdes::deserialize_duration"
     )]
     pub send_deadline: std::time::Duration,
+    // How long payloads wait for the ones before them when `ordered` is set; the defaults are used if this is omitted
+    pub reordering: Option<ReorderingConfig>,
+    // Which paths (pairs of a local interface and a peer address) each payload is sent over; all of them if omitted
+    pub path_selection: Option<PathSelection>,
+    // Patterns matched against interface names: while any interface that matches can be used, the tunnel's payloads
+    // only go over those. Any interface if omitted
+    pub interfaces: Option<InterfacePatterns>,
+    // Whether payloads may go over metered interfaces (see `InterfacesConfig::policies`) while there are unmetered
+    // ones, e.g. for redundancy; only if the tunnel has a priority above 0 if omitted
+    pub metered: Option<bool>,
+    // Have the far gate acknowledge each payload, and send it again until it does; off if omitted
+    pub reliable: Option<bool>,
+    // The DSCP to mark this tunnel's payloads with instead of `interfaces.dscp`
+    pub dscp: Option<Dscp>,
+    // Limits how fast the gate takes payloads from the application, counting the bytes of their FEC shards; the gate
+    // waits, holding the application back, when it is exceeded. Unlimited if omitted
+    pub rate_limit: Option<RateLimitConfig>,
+    // Compress payloads before sealing them, for far gates that agreed to decompress them; a payload that doesn't get
+    // any smaller is sent as it is. Off if omitted
+    pub compression: Option<Compression>,
+    // Pad sealed payloads with zeros to a multiple of this many bytes, so that their sizes on the wire don't give away
+    // those of the application's messages; the far gate must be new enough to strip the padding. Off if omitted
+    pub pad_to: Option<std::num::NonZeroU16>,
+    // Send payloads to the same address together in datagrams of up to `mtu` bytes, for chatty applications whose
+    // payloads are small; each datagram is sent once it is full or its first payload has waited `packing.delay`. Every
+    // payload gets a datagram of its own if omitted
+    pub packing: Option<PackingConfig>,
 }
 
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PackingConfig {
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub delay: std::time::Duration,
+}
+
+// In TOML: `compression = "lz4"` or `compression = { zstd = 3 }`, with the zstd compression level
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Lz4,
+    Zstd(i32),
+}
+
+// A Differentiated Services Code Point: the upper six bits of the IPv4 TOS and IPv6 traffic class bytes, e.g. 46 for
+// expedited forwarding
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct Dscp(u8);
+
+impl Dscp {
+    /// The TOS or traffic class byte carrying this code point, with the ECN bits left clear
+    pub fn tos(self) -> u8 {
+        self.0 << 2
+    }
+}
+
+impl TryFrom<u8> for Dscp {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value < 64 {
+            Ok(Self(value))
+        } else {
+            Err(format!("DSCP {} is out of range; it must be less than 64", value))
+        }
+    }
+}
+
+impl From<Dscp> for u8 {
+    fn from(dscp: Dscp) -> Self {
+        dscp.0
+    }
+}
+
+// Regular expressions matched against interface names, e.g. `["wlan.*", "eth0"]`
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct InterfacePatterns(regex::RegexSet);
+
+impl InterfacePatterns {
+    pub fn is_match(&self, interface_name: &str) -> bool {
+        self.0.is_match(interface_name)
+    }
+}
+
+impl PartialEq for InterfacePatterns {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.patterns() == other.0.patterns()
+    }
+}
+
+impl TryFrom<Vec<String>> for InterfacePatterns {
+    type Error = regex::Error;
+
+    fn try_from(patterns: Vec<String>) -> Result<Self, Self::Error> {
+        regex::RegexSet::new(patterns).map(Self)
+    }
+}
+
+impl From<InterfacePatterns> for Vec<String> {
+    fn from(patterns: InterfacePatterns) -> Self {
+        patterns.0.patterns().to_vec()
+    }
+}
+
+// In TOML: `path_selection = "best"`, `path_selection = { redundant = 2 }` or `path_selection = "all"`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathSelection {
+    // The path with the lowest expected latency, taking its loss and send queue into account
+    Best,
+    // The n best paths
+    Redundant(usize),
+    #[default]
+    All,
+}
+
+// Payloads that arrive ahead of a missing one are held back until it arrives, `window` payloads are waiting, or the
+// missing payload has been waited on for `timeout`; then it is given up on
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ReorderingConfig {
+    pub window: usize,
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub timeout: std::time::Duration,
+}
+
+impl Default for ReorderingConfig {
+    fn default() -> Self {
+        Self {
+            window: 64,
+            timeout: std::time::Duration::from_millis(50),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct RedundancyConfig {
     pub num_shards: u8,
     pub required_shards: u8,
+    // Adjust `num_shards` to the loss measured on the tunnel's paths; fixed if omitted
+    pub adaptive: Option<AdaptiveRedundancyConfig>,
+}
+
+// Every `interval`, a shard is added when the loss on the tunnel's paths is above `raise_loss`, and one is removed when
+// it is below `lower_loss`, keeping `num_shards` between `min_shards` and `max_shards`. Loss while the round trip time is
+// more than `congestion_rtt_ratio` times the lowest seen is taken as congestion, which more shards would only add to,
+// so a shard is removed instead.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AdaptiveRedundancyConfig {
+    pub min_shards: u8,
+    pub max_shards: u8,
+    pub raise_loss: f64,
+    pub lower_loss: f64,
+    pub congestion_rtt_ratio: f64,
+    #[serde(
+        serialize_with = "serdes::serialize_duration",
+        deserialize_with = "serdes::deserialize_duration"
+    )]
+    pub interval: std::time::Duration,
 }
@@ -6,11 +6,37 @@ fn main() {
             .unwrap(),
         interfaces: warp_config::InterfacesConfig {
             interface_scan_interval: std::time::Duration::from_secs(10),
+            interface_watch: Some(warp_config::InterfaceWatch::Netlink),
             holepunch_keep_alive_interval: std::time::Duration::from_secs(5),
             bind_to_device: Some(false),
             exclusion_patterns: regex::RegexSet::new(vec!["eth.*"]).unwrap(),
             inclusion_patterns: regex::RegexSet::new(vec![".*"]).unwrap(),
             max_consecutive_failures: 10,
+            batch_size: Some(32),
+            so_rcvbuf: None,
+            so_sndbuf: None,
+            dscp: None,
+            rate_limits: Some(std::collections::BTreeMap::from([(
+                "wwan0".to_string(),
+                warp_config::RateLimitConfig {
+                    rate: 1_000_000,
+                    burst: 64_000,
+                    pacing_interval: std::time::Duration::from_millis(2),
+                },
+            )])),
+            policies: Some(std::collections::BTreeMap::from([(
+                "wwan0".to_string(),
+                warp_config::InterfacePolicyConfig {
+                    metered: Some(true),
+                    cost: Some(10),
+                },
+            )])),
+            fibs: None,
+            path_probing: Some(warp_config::PathProbingConfig::default()),
+            liveness: Some(warp_config::LivenessConfig::default()),
+            flap_damping: Some(warp_config::FlapDampingConfig::default()),
+            relay: Some(warp_config::RelayConfig::default()),
+            traffic_log: Some(warp_config::TrafficLogConfig::default()),
         },
         warp_map: warp_config::WarpMapConfig {
             address: std::net::SocketAddr::from_str("1.2.3.4:13116").unwrap(),
@@ -18,6 +44,7 @@ fn main() {
                 "0B2XTQXPMCXTKYFPYR5DY8T61W2186HD569YQWMPTV56E1VH7ZS82",
             )
             .unwrap(),
+            tcp_address: Some(std::net::SocketAddr::from_str("1.2.3.4:13116").unwrap()),
         },
         far_gate: warp_config::WarpFarGateConfig {
             public_key: warp_protocol::crypto::pubkey_from_string(
@@ -26,12 +53,39 @@ fn main() {
             .unwrap(),
         },
         tunnels: std::collections::BTreeMap::new(),
+        rekey: Some(warp_config::RekeyConfig::default()),
+        cipher_suites: Some(warp_protocol::CipherSuite::ALL.to_vec()),
+        metrics: Some(warp_config::MetricsConfig {
+            bind: std::net::SocketAddr::from_str("127.0.0.1:9464").unwrap(),
+        }),
+        queues: Some(warp_config::QueuesConfig {
+            rx_workers: Some(4),
+            ..Default::default()
+        }),
+        shutdown: Some(warp_config::ShutdownConfig::default()),
+        state_file: Some("/var/lib/warp/state.toml".into()),
+        daemon: Some(warp_config::DaemonConfig {
+            pidfile: Some("/run/warp.pid".into()),
+            user: Some("warp".to_string()),
+            group: None,
+        }),
+        control: Some(warp_config::ControlConfig {
+            socket: Some("/run/warp/control.sock".into()),
+            accept_far_gate_tunnels: Some(false),
+        }),
+        telemetry: Some(warp_config::TelemetryConfig {
+            otlp_endpoint: "http://localhost:4317".to_string(),
+            service_name: None,
+            sample_ratio: Some(0.01),
+        }),
     };
 
     config.tunnels.insert(
         "video_streams".to_string(),
         warp_config::WarpTunnelConfig {
             tunnel_id: None,
+            far_gate: None,
+            priority: None,
             gate: warp_config::WarpGateConfig::UnixDomainSocket(warp_config::UnixDomainSocketConfig {
                 path: "/tmp/socket".into(),
             }),
@@ -39,10 +93,29 @@ fn main() {
                 redundancy: warp_config::RedundancyConfig {
                     num_shards: 5,
                     required_shards: 3,
+                    adaptive: Some(warp_config::AdaptiveRedundancyConfig {
+                        min_shards: 4,
+                        max_shards: 8,
+                        raise_loss: 0.05,
+                        lower_loss: 0.01,
+                        congestion_rtt_ratio: 2.0,
+                        interval: std::time::Duration::from_secs(1),
+                    }),
                 },
                 mtu: 1400,
                 send_deadline: std::time::Duration::from_millis(10),
                 ordered: false,
+                reordering: None,
+                path_selection: None,
+                interfaces: None,
+                metered: None,
+                reliable: None,
+                // AF41, for interactive video
+                dscp: Some(warp_config::Dscp::try_from(34).unwrap()),
+                rate_limit: None,
+                compression: None,
+                pad_to: None,
+                packing: None,
             },
         },
     );
@@ -51,6 +124,8 @@ fn main() {
         "wireguard".to_string(),
         warp_config::WarpTunnelConfig {
             tunnel_id: Some(5),
+            far_gate: None,
+            priority: None,
             gate: warp_config::WarpGateConfig::Loopback(warp_config::LoopbackConfig {
                 ipv4: true,
                 application_to_gate: 9000,
@@ -60,10 +135,21 @@ fn main() {
                 redundancy: warp_config::RedundancyConfig {
                     num_shards: 5,
                     required_shards: 3,
+                    adaptive: None,
                 },
                 mtu: 1400,
                 send_deadline: std::time::Duration::from_micros(10),
                 ordered: false,
+                reordering: None,
+                path_selection: Some(warp_config::PathSelection::Redundant(2)),
+                interfaces: Some(vec!["wlan.*".to_string()].try_into().unwrap()),
+                metered: Some(false),
+                reliable: None,
+                dscp: None,
+                rate_limit: None,
+                compression: None,
+                pad_to: None,
+                packing: None,
             },
         },
     );
@@ -72,6 +158,14 @@ fn main() {
         "control_messages".to_string(),
         warp_config::WarpTunnelConfig {
             tunnel_id: Some(42),
+            far_gate: Some(warp_config::WarpFarGateConfig {
+                public_key: warp_protocol::crypto::pubkey_from_string(
+                    "0AWRZ14762AKENKAY9AX57RXY0SANPVXEQG2MC7G8M6X1SHJVTM9C",
+                )
+                .unwrap(),
+            }),
+            // Sent ahead of the video and wireguard tunnels when an interface falls behind
+            priority: Some(10),
             gate: warp_config::WarpGateConfig::Loopback(warp_config::LoopbackConfig {
                 ipv4: true,
                 application_to_gate: 9010,
@@ -81,10 +175,22 @@ fn main() {
                 redundancy: warp_config::RedundancyConfig {
                     num_shards: 5,
                     required_shards: 3,
+                    adaptive: None,
                 },
                 mtu: 1400,
                 send_deadline: std::time::Duration::from_nanos(10),
-                ordered: false,
+                ordered: true,
+                reordering: Some(warp_config::ReorderingConfig::default()),
+                path_selection: None,
+                interfaces: None,
+                metered: None,
+                reliable: Some(true),
+                dscp: None,
+                rate_limit: None,
+                // Control messages are mostly text
+                compression: Some(warp_config::Compression::Zstd(3)),
+                pad_to: None,
+                packing: None,
             },
         },
     );
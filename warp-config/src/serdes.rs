@@ -1,4 +1,4 @@
-pub(crate) fn serialize_regex_set<S>(regex_set: &regex::RegexSet, serializer: S) -> Result<S::Ok, S::Error>
+pub fn serialize_regex_set<S>(regex_set: &regex::RegexSet, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
@@ -8,7 +8,7 @@ where
     patterns.serialize(serializer)
 }
 
-pub(crate) fn deserialize_regex_set<'de, D>(deserializer: D) -> Result<regex::RegexSet, D::Error>
+pub fn deserialize_regex_set<'de, D>(deserializer: D) -> Result<regex::RegexSet, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -26,7 +26,7 @@ where
     regex::RegexSet::new(&patterns).map_err(serde::de::Error::custom)
 }
 
-pub(crate) fn deserialize_address<'de, D>(deserializer: D) -> Result<std::net::SocketAddr, D::Error>
+pub fn deserialize_address<'de, D>(deserializer: D) -> Result<std::net::SocketAddr, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -44,10 +44,7 @@ where
     }
 }
 
-pub(crate) fn serialize_private_key<S>(
-    private_key: &warp_protocol::PrivateKey,
-    serializer: S,
-) -> Result<S::Ok, S::Error>
+pub fn serialize_private_key<S>(private_key: &warp_protocol::PrivateKey, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
@@ -56,7 +53,7 @@ where
     string.serialize(serializer)
 }
 
-pub(crate) fn deserialize_private_key<'de, D>(deserializer: D) -> Result<warp_protocol::PrivateKey, D::Error>
+pub fn deserialize_private_key<'de, D>(deserializer: D) -> Result<warp_protocol::PrivateKey, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -65,7 +62,7 @@ where
     warp_protocol::crypto::privkey_from_string(&string).map_err(serde::de::Error::custom)
 }
 
-pub(crate) fn serialize_public_key<S>(private_key: &warp_protocol::PublicKey, serializer: S) -> Result<S::Ok, S::Error>
+pub fn serialize_public_key<S>(private_key: &warp_protocol::PublicKey, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
@@ -74,7 +71,7 @@ where
     string.serialize(serializer)
 }
 
-pub(crate) fn deserialize_public_key<'de, D>(deserializer: D) -> Result<warp_protocol::PublicKey, D::Error>
+pub fn deserialize_public_key<'de, D>(deserializer: D) -> Result<warp_protocol::PublicKey, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -84,7 +81,7 @@ where
 }
 
 // TODO: Make this support values like "100us"/"100ns"/"100ms" etc.
-pub(crate) fn serialize_duration<S>(duration: &std::time::Duration, serializer: S) -> Result<S::Ok, S::Error>
+pub fn serialize_duration<S>(duration: &std::time::Duration, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
@@ -93,7 +90,7 @@ where
 }
 
 // TODO: Make this support values like "100us"/"100ns"/"100ms" etc.
-pub(crate) fn deserialize_duration<'de, D>(deserializer: D) -> Result<std::time::Duration, D::Error>
+pub fn deserialize_duration<'de, D>(deserializer: D) -> Result<std::time::Duration, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
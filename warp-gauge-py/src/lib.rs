@@ -0,0 +1,188 @@
+//! Python module `warp_gauge`, exposing the analysis the inspector does so results can be post-processed in notebooks
+//!
+//! Build and install it into the current virtualenv with `maturin develop` from this directory.
+//!
+//! ```python
+//! import warp_gauge
+//!
+//! points = warp_gauge.load_csv("rx.csv")
+//! print(warp_gauge.statistics(points).p99_latency)
+//! bins, bin_width = warp_gauge.histogram([p.latency_ms for p in points])
+//! ```
+
+use pyo3::prelude::*;
+use warp_gauge::analysis;
+
+/// One received packet, as written by `warp-gauge rx`; `latency_ms` is in seconds despite its name
+#[pyclass(name = "DataPoint", module = "warp_gauge", frozen, get_all)]
+pub struct PyDataPoint {
+    counter: u64,
+    target_pps: u64,
+    sender_achieved_pps: u64,
+    receiver_calculated_pps: u64,
+    latency_ms: f64,
+}
+
+#[pymethods]
+impl PyDataPoint {
+    #[new]
+    fn new(
+        counter: u64,
+        target_pps: u64,
+        sender_achieved_pps: u64,
+        receiver_calculated_pps: u64,
+        latency_ms: f64,
+    ) -> Self {
+        Self {
+            counter,
+            target_pps,
+            sender_achieved_pps,
+            receiver_calculated_pps,
+            latency_ms,
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "DataPoint(counter={}, target_pps={}, sender_achieved_pps={}, receiver_calculated_pps={}, latency_ms={})",
+            self.counter, self.target_pps, self.sender_achieved_pps, self.receiver_calculated_pps, self.latency_ms
+        )
+    }
+}
+
+impl From<analysis::DataPoint> for PyDataPoint {
+    fn from(point: analysis::DataPoint) -> Self {
+        Self {
+            counter: point.counter,
+            target_pps: point.target_pps,
+            sender_achieved_pps: point.sender_achieved_pps,
+            receiver_calculated_pps: point.receiver_calculated_pps,
+            latency_ms: point.latency_ms,
+        }
+    }
+}
+
+impl From<&PyDataPoint> for analysis::DataPoint {
+    fn from(point: &PyDataPoint) -> Self {
+        Self {
+            counter: point.counter,
+            target_pps: point.target_pps,
+            sender_achieved_pps: point.sender_achieved_pps,
+            receiver_calculated_pps: point.receiver_calculated_pps,
+            latency_ms: point.latency_ms,
+        }
+    }
+}
+
+/// Latencies are in the same units as the data points'; percentages are 0 to 100
+#[pyclass(name = "Statistics", module = "warp_gauge", frozen, get_all)]
+pub struct PyStatistics {
+    min_latency: f64,
+    max_latency: f64,
+    mean_latency: f64,
+    p50_latency: f64,
+    p90_latency: f64,
+    p99_latency: f64,
+    jitter: f64,
+    packet_drop_percentage: f64,
+    out_of_order_percentage: f64,
+    data_point_count: usize,
+}
+
+#[pymethods]
+impl PyStatistics {
+    fn __repr__(&self) -> String {
+        format!(
+            "Statistics(data_point_count={}, min_latency={}, mean_latency={}, max_latency={}, p50_latency={}, \
+             p90_latency={}, p99_latency={}, jitter={}, packet_drop_percentage={}, out_of_order_percentage={})",
+            self.data_point_count,
+            self.min_latency,
+            self.mean_latency,
+            self.max_latency,
+            self.p50_latency,
+            self.p90_latency,
+            self.p99_latency,
+            self.jitter,
+            self.packet_drop_percentage,
+            self.out_of_order_percentage
+        )
+    }
+}
+
+impl From<analysis::DataStatistics> for PyStatistics {
+    fn from(stats: analysis::DataStatistics) -> Self {
+        Self {
+            min_latency: stats.min_latency,
+            max_latency: stats.max_latency,
+            mean_latency: stats.mean_latency,
+            p50_latency: stats.p50_latency,
+            p90_latency: stats.p90_latency,
+            p99_latency: stats.p99_latency,
+            jitter: stats.jitter,
+            packet_drop_percentage: stats.packet_drop_percentage,
+            out_of_order_percentage: stats.out_of_order_percentage,
+            data_point_count: stats.data_point_count,
+        }
+    }
+}
+
+fn to_points(points: Vec<PyRef<'_, PyDataPoint>>) -> Vec<analysis::DataPoint> {
+    points.iter().map(|point| (&**point).into()).collect()
+}
+
+/// Load the CSV written by `warp-gauge rx`, in the order the packets were received
+#[pyfunction]
+fn load_csv(path: std::path::PathBuf) -> anyhow::Result<Vec<PyDataPoint>> {
+    Ok(analysis::load_csv_data(path)?.into_iter().map(Into::into).collect())
+}
+
+/// Write points in the format `load_csv` and the inspector read
+#[pyfunction]
+fn write_csv(points: Vec<PyRef<'_, PyDataPoint>>, path: std::path::PathBuf) -> anyhow::Result<()> {
+    analysis::write_csv_data(&to_points(points), path)
+}
+
+/// The inspector's statistics panel for `points`, which must be in the order they were received
+#[pyfunction]
+fn statistics(points: Vec<PyRef<'_, PyDataPoint>>) -> PyStatistics {
+    analysis::calculate_statistics(&to_points(points)).into()
+}
+
+/// Returns (packet drop percentage, out of order percentage)
+#[pyfunction]
+fn packet_metrics(points: Vec<PyRef<'_, PyDataPoint>>) -> (f64, f64) {
+    analysis::calculate_packet_metrics(&to_points(points))
+}
+
+/// Mean absolute difference in latency between consecutively received packets
+#[pyfunction]
+fn jitter(points: Vec<PyRef<'_, PyDataPoint>>) -> f64 {
+    analysis::calculate_jitter(&to_points(points))
+}
+
+/// The value at fraction `p` (0 to 1) of the way through the already sorted `sorted_data`
+#[pyfunction]
+fn percentile(sorted_data: Vec<f64>, p: f64) -> f64 {
+    analysis::percentile(&sorted_data, p)
+}
+
+/// Returns ([(bin center, count)], bin width), with the bin width chosen by Scott's rule as in the inspector
+#[pyfunction]
+fn histogram(latencies: Vec<f64>) -> (Vec<(f64, f64)>, f64) {
+    warp_gauge::histogram::calculate_histogram(&latencies)
+}
+
+#[pymodule]
+#[pyo3(name = "warp_gauge")]
+fn warp_gauge_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyDataPoint>()?;
+    m.add_class::<PyStatistics>()?;
+    m.add_function(wrap_pyfunction!(load_csv, m)?)?;
+    m.add_function(wrap_pyfunction!(write_csv, m)?)?;
+    m.add_function(wrap_pyfunction!(statistics, m)?)?;
+    m.add_function(wrap_pyfunction!(packet_metrics, m)?)?;
+    m.add_function(wrap_pyfunction!(jitter, m)?)?;
+    m.add_function(wrap_pyfunction!(percentile, m)?)?;
+    m.add_function(wrap_pyfunction!(histogram, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,219 @@
+//! Loading rx mode CSV output and the statistics the inspector shows for a selection of it
+
+use serde::{Deserialize, Serialize};
+
+/// One received packet, as written by rx mode
+///
+/// Despite its name, `latency_ms` is in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DataPoint {
+    pub counter: u64,
+    pub target_pps: u64,
+    pub sender_achieved_pps: u64,
+    pub receiver_calculated_pps: u64,
+    pub latency_ms: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DataStatistics {
+    pub min_latency: f64,
+    pub max_latency: f64,
+    pub mean_latency: f64,
+    pub p50_latency: f64,
+    pub p90_latency: f64,
+    pub p99_latency: f64,
+    /// Mean absolute difference between the latencies of consecutively received packets
+    pub jitter: f64,
+    pub packet_drop_percentage: f64,
+    pub out_of_order_percentage: f64,
+    pub data_point_count: usize,
+}
+
+pub const CSV_HEADER: [&str; 5] = [
+    "counter",
+    "target_pps",
+    "sender_achieved_pps",
+    "receiver_calculated_pps",
+    "latency_ms",
+];
+
+pub fn load_csv_data(file_path: impl AsRef<std::path::Path>) -> Result<Vec<DataPoint>, anyhow::Error> {
+    let file = std::fs::File::open(file_path)?;
+    let mut reader = csv::ReaderBuilder::new().has_headers(true).from_reader(file);
+
+    let mut points = Vec::new();
+
+    for result in reader.deserialize() {
+        let point: DataPoint = result?;
+        points.push(point);
+    }
+
+    Ok(points)
+}
+
+pub fn write_csv_data<'a>(
+    data: impl IntoIterator<Item = &'a DataPoint>,
+    file_path: impl AsRef<std::path::Path>,
+) -> Result<(), anyhow::Error> {
+    let file = std::fs::File::create(file_path)?;
+    let mut writer = csv::Writer::from_writer(file);
+
+    writer.write_record(CSV_HEADER)?;
+
+    for point in data {
+        writer.write_record(&[
+            point.counter.to_string(),
+            point.target_pps.to_string(),
+            point.sender_achieved_pps.to_string(),
+            point.receiver_calculated_pps.to_string(),
+            point.latency_ms.to_string(),
+        ])?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// The value at fraction `p` (0 to 1) of the way through `sorted_data`, without interpolation
+pub fn percentile(sorted_data: &[f64], p: f64) -> f64 {
+    if sorted_data.is_empty() {
+        return 0.0;
+    }
+    let index = (p * (sorted_data.len() - 1) as f64) as usize;
+    sorted_data[index.min(sorted_data.len() - 1)]
+}
+
+/// Points must be in the order they were received for the jitter and out of order figures to mean anything
+pub fn calculate_statistics(points: &[DataPoint]) -> DataStatistics {
+    if points.is_empty() {
+        return DataStatistics::default();
+    }
+
+    let mut latencies: Vec<f64> = points.iter().map(|p| p.latency_ms).collect();
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let min_latency = latencies[0];
+    let max_latency = latencies[latencies.len() - 1];
+    let mean_latency = latencies.iter().sum::<f64>() / latencies.len() as f64;
+
+    let p50_latency = percentile(&latencies, 0.5);
+    let p90_latency = percentile(&latencies, 0.9);
+    let p99_latency = percentile(&latencies, 0.99);
+
+    let (packet_drop_percentage, out_of_order_percentage) = calculate_packet_metrics(points);
+
+    DataStatistics {
+        min_latency,
+        max_latency,
+        mean_latency,
+        p50_latency,
+        p90_latency,
+        p99_latency,
+        jitter: calculate_jitter(points),
+        packet_drop_percentage,
+        out_of_order_percentage,
+        data_point_count: points.len(),
+    }
+}
+
+/// Returns (packet drop percentage, out of order percentage)
+///
+/// Drops are counted against the range of counters seen, so packets lost before the first or after the last received
+/// one aren't included.
+pub fn calculate_packet_metrics(points: &[DataPoint]) -> (f64, f64) {
+    if points.len() < 2 {
+        return (0.0, 0.0);
+    }
+
+    // Extract min/max counter values.
+    let min_counter = points.iter().min_by_key(|p| p.counter).unwrap().counter;
+    let max_counter = points.iter().max_by_key(|p| p.counter).unwrap().counter;
+
+    // Count out-of-order pairs (where the later point has a smaller counter).
+    let out_of_order = points
+        .windows(2)
+        .filter(|pair| pair[1].counter < pair[0].counter)
+        .count();
+
+    // Compute percentages.
+    let expected_packets = (max_counter - min_counter + 1) as f64;
+    let packet_drop_percentage = if expected_packets > 0.0 {
+        100.0 * (expected_packets - points.len() as f64) / expected_packets
+    } else {
+        0.0
+    };
+
+    let out_of_order_percentage = 100.0 * out_of_order as f64 / (points.len() - 1) as f64;
+
+    (packet_drop_percentage, out_of_order_percentage)
+}
+
+/// Mean absolute difference in latency between consecutively received packets, in the same units as the latencies
+///
+/// This is the unsmoothed form of the RFC 3550 interarrival jitter.
+pub fn calculate_jitter(points: &[DataPoint]) -> f64 {
+    if points.len() < 2 {
+        return 0.0;
+    }
+
+    let total: f64 = points
+        .windows(2)
+        .map(|pair| (pair[1].latency_ms - pair[0].latency_ms).abs())
+        .sum();
+    total / (points.len() - 1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(counter: u64, latency: f64) -> DataPoint {
+        DataPoint {
+            counter,
+            target_pps: 100,
+            sender_achieved_pps: 100,
+            receiver_calculated_pps: 100,
+            latency_ms: latency,
+        }
+    }
+
+    #[test]
+    fn test_statistics() {
+        // Counter 3 is lost and 5 arrives before 4
+        let points = vec![
+            point(1, 0.010),
+            point(2, 0.012),
+            point(5, 0.010),
+            point(4, 0.014),
+            point(6, 0.010),
+        ];
+        let stats = calculate_statistics(&points);
+
+        assert_eq!(stats.data_point_count, 5);
+        assert_eq!(stats.min_latency, 0.010);
+        assert_eq!(stats.max_latency, 0.014);
+        assert_eq!(stats.p50_latency, 0.010);
+        assert!((stats.mean_latency - 0.0112).abs() < 1e-12);
+        assert!((stats.jitter - 0.003).abs() < 1e-12);
+        assert!((stats.packet_drop_percentage - 100.0 / 6.0).abs() < 1e-9);
+        assert_eq!(stats.out_of_order_percentage, 25.0);
+    }
+
+    #[test]
+    fn test_statistics_of_nothing() {
+        assert_eq!(calculate_statistics(&[]), DataStatistics::default());
+        assert_eq!(calculate_jitter(&[point(1, 0.5)]), 0.0);
+    }
+
+    #[test]
+    fn test_csv_roundtrip() {
+        let path = std::env::temp_dir().join(format!("warp-gauge-analysis-{}.csv", std::process::id()));
+        let points = vec![point(1, 0.25), point(2, 0.5)];
+
+        write_csv_data(&points, &path).unwrap();
+        let loaded = load_csv_data(&path);
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded.unwrap(), points);
+    }
+}
@@ -1,114 +1,11 @@
-pub(crate) mod histogram;
+use warp_gauge::analysis::{DataPoint, DataStatistics, calculate_statistics, load_csv_data, write_csv_data};
+
 pub(crate) mod shaded_range;
 pub(crate) mod time_series;
 
-fn load_csv_data(file_path: &str) -> Result<DataSet, anyhow::Error> {
-    let file = std::fs::File::open(file_path)?;
-    let mut reader = csv::ReaderBuilder::new().has_headers(true).from_reader(file);
-
-    let mut points = Vec::new();
-
-    for result in reader.deserialize() {
-        let point: crate::DataPoint = result?;
-        points.push(point);
-    }
-
-    Ok(DataSet { points })
-}
-
-fn percentile(sorted_data: &[f64], p: f64) -> f64 {
-    if sorted_data.is_empty() {
-        return 0.0;
-    }
-    let index = (p * (sorted_data.len() - 1) as f64) as usize;
-    sorted_data[index.min(sorted_data.len() - 1)]
-}
-
-fn calculate_statistics(points: &[crate::DataPoint]) -> DataStatistics {
-    if points.is_empty() {
-        return DataStatistics {
-            min_latency: 0.0,
-            max_latency: 0.0,
-            mean_latency: 0.0,
-            p50_latency: 0.0,
-            p90_latency: 0.0,
-            p99_latency: 0.0,
-            packet_drop_percentage: 0.0,
-            out_of_order_percentage: 0.0,
-            data_point_count: 0,
-        };
-    }
-
-    let mut latencies: Vec<f64> = points.iter().map(|p| p.latency_ms).collect();
-    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
-
-    let min_latency = latencies[0];
-    let max_latency = latencies[latencies.len() - 1];
-    let mean_latency = latencies.iter().sum::<f64>() / latencies.len() as f64;
-
-    let p50_latency = percentile(&latencies, 0.5);
-    let p90_latency = percentile(&latencies, 0.9);
-    let p99_latency = percentile(&latencies, 0.99);
-
-    let (packet_drop_percentage, out_of_order_percentage) = calculate_packet_metrics(points);
-
-    DataStatistics {
-        min_latency,
-        max_latency,
-        mean_latency,
-        p50_latency,
-        p90_latency,
-        p99_latency,
-        packet_drop_percentage,
-        out_of_order_percentage,
-        data_point_count: points.len(),
-    }
-}
-
-fn calculate_packet_metrics(points: &[crate::DataPoint]) -> (f64, f64) {
-    if points.len() < 2 {
-        return (0.0, 0.0);
-    }
-
-    // Extract min/max counter values.
-    let min_counter = points.iter().min_by_key(|p| p.counter).unwrap().counter;
-    let max_counter = points.iter().max_by_key(|p| p.counter).unwrap().counter;
-
-    // Count out-of-order pairs (where the later point has a smaller counter).
-    let out_of_order = points
-        .windows(2)
-        .filter(|pair| pair[1].counter < pair[0].counter)
-        .count();
-
-    // Compute percentages.
-    let expected_packets = (max_counter - min_counter + 1) as f64;
-    let packet_drop_percentage = if expected_packets > 0.0 {
-        100.0 * (expected_packets - points.len() as f64) / expected_packets
-    } else {
-        0.0
-    };
-
-    let out_of_order_percentage = 100.0 * out_of_order as f64 / (points.len() - 1) as f64;
-
-    (packet_drop_percentage, out_of_order_percentage)
-}
-
-#[derive(Debug, Clone)]
-struct DataStatistics {
-    min_latency: f64,
-    max_latency: f64,
-    mean_latency: f64,
-    p50_latency: f64,
-    p90_latency: f64,
-    p99_latency: f64,
-    packet_drop_percentage: f64,
-    out_of_order_percentage: f64,
-    data_point_count: usize,
-}
-
 #[derive(Debug, Clone)]
 struct DataSet {
-    points: Vec<crate::DataPoint>,
+    points: Vec<DataPoint>,
 }
 #[derive(Default)]
 pub struct Inspector {
@@ -130,9 +27,9 @@ impl Inspector {
         {
             self.load_error = None;
 
-            match load_csv_data(file_path.to_str().unwrap_or("")) {
-                Ok(data_set) => {
-                    self.data_set = Some(data_set);
+            match load_csv_data(&file_path) {
+                Ok(points) => {
+                    self.data_set = Some(DataSet { points });
                 }
                 Err(e) => {
                     self.load_error = Some(format!("Failed to load CSV: {e}"));
@@ -141,11 +38,11 @@ impl Inspector {
         }
     }
 
-    fn get_selected_data(&self) -> Option<Vec<&crate::DataPoint>> {
+    fn get_selected_data(&self) -> Option<Vec<&DataPoint>> {
         if let Some(ref data_set) = self.data_set
             && let Some((min_x, max_x)) = self.selected_x_range
         {
-            let selected_points: Vec<&crate::DataPoint> = data_set
+            let selected_points: Vec<&DataPoint> = data_set
                 .points
                 .iter()
                 .filter(|point| {
@@ -176,7 +73,7 @@ impl Inspector {
     fn generate_histogram_data(&self) -> egui_plot::BarChart {
         if let Some(selected_data) = self.get_selected_data() {
             let latencies: Vec<f64> = selected_data.iter().map(|p| p.latency_ms).collect();
-            let (histogram, bin_width) = crate::inspector::histogram::calculate_histogram(&latencies);
+            let (histogram, bin_width) = warp_gauge::histogram::calculate_histogram(&latencies);
 
             // Create bar chart data
             let bars: Vec<egui_plot::Bar> = histogram
@@ -210,7 +107,7 @@ impl Inspector {
 
     fn get_statistics(&self) -> Option<DataStatistics> {
         if let Some(selected_data) = self.get_selected_data() {
-            let points: Vec<crate::DataPoint> = selected_data.iter().map(|p| (*p).clone()).collect();
+            let points: Vec<DataPoint> = selected_data.iter().map(|p| (*p).clone()).collect();
             let stats = calculate_statistics(&points);
             Some(stats)
         } else {
@@ -382,11 +279,12 @@ impl Inspector {
 
                     // Use columns for better space utilization
                     ui.columns(3, |columns| {
-                        // Column 1: Min, Mean, Max
+                        // Column 1: Min, Mean, Max, Jitter
                         columns[0].vertical(|ui| {
                             ui.label(format!("Min: {:.6} ms", stats.min_latency * 1e3));
                             ui.label(format!("Mean: {:.6} ms", stats.mean_latency * 1e3));
                             ui.label(format!("Max: {:.6} ms", stats.max_latency * 1e3));
+                            ui.label(format!("Jitter: {:.6} ms", stats.jitter * 1e3));
                         });
 
                         // Column 2: P50, P90, P99
@@ -467,7 +365,7 @@ impl Inspector {
         if let Some(selected_data) = self.get_selected_data() {
             // Open file dialog to choose save location
             if let Some(file_path) = rfd::FileDialog::new().add_filter("CSV files", &["csv"]).save_file() {
-                match self.write_csv_data(&selected_data, &file_path) {
+                match write_csv_data(selected_data.iter().copied(), &file_path) {
                     Ok(_) => {
                         self.load_error = Some(format!(
                             "Successfully exported {} data points to CSV",
@@ -483,34 +381,6 @@ impl Inspector {
             self.load_error = Some("No data selected for export. Use Shift+drag to select a range first.".to_string());
         }
     }
-
-    fn write_csv_data(&self, data: &[&crate::DataPoint], file_path: &std::path::Path) -> Result<(), anyhow::Error> {
-        let file = std::fs::File::create(file_path)?;
-        let mut writer = csv::Writer::from_writer(file);
-
-        // Write header
-        writer.write_record([
-            "counter",
-            "target_pps",
-            "sender_achieved_pps",
-            "receiver_calculated_pps",
-            "latency_ms",
-        ])?;
-
-        // Write data points
-        for point in data {
-            writer.write_record(&[
-                point.counter.to_string(),
-                point.target_pps.to_string(),
-                point.sender_achieved_pps.to_string(),
-                point.receiver_calculated_pps.to_string(),
-                point.latency_ms.to_string(),
-            ])?;
-        }
-
-        writer.flush()?;
-        Ok(())
-    }
 }
 
 impl eframe::App for Inspector {
@@ -0,0 +1,4 @@
+//! Analysis of warp-gauge benchmark results, shared by the inspector and the Python bindings in `warp-gauge-py`
+
+pub mod analysis;
+pub mod histogram;
@@ -1,11 +1,11 @@
 const PACKET_SIZE: usize = 1000;
 
 use clap::Parser;
-use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::{BufWriter, Write};
 
 mod inspector;
+mod metrics;
 
 #[derive(clap::Parser)]
 #[command(name = "warp-gauge")]
@@ -28,20 +28,14 @@ enum Mode {
     Rx {
         destination: String,
         output_path: String,
+        /// Serve Prometheus metrics at http://<METRICS_BIND>/metrics
+        #[arg(long)]
+        metrics_bind: Option<std::net::SocketAddr>,
     },
     // Default
     Inspector,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct DataPoint {
-    counter: u64,
-    target_pps: u64,
-    sender_achieved_pps: u64,
-    receiver_calculated_pps: u64,
-    latency_ms: f64,
-}
-
 #[derive(Clone)]
 enum DestinationAddress {
     Ip(std::net::SocketAddr),
@@ -134,6 +128,10 @@ impl Receiver {
                 .map(|d| d.as_secs_f64())
                 .unwrap_or_else(|d| -d.duration().as_secs_f64());
 
+            metrics::RX_PACKETS.inc();
+            metrics::RX_PPS.set(receiver_pps as i64);
+            metrics::RX_LATENCY_SECONDS.observe(latency);
+
             writeln!(
                 file,
                 "{},{},{},{},{}",
@@ -267,7 +265,17 @@ async fn main() -> Result<(), anyhow::Error> {
         Some(Mode::Rx {
             destination,
             output_path,
+            metrics_bind,
         }) => {
+            if let Some(metrics_bind) = metrics_bind {
+                tokio::spawn(async move {
+                    if let Err(e) =
+                        warp_metrics::exporter::bind_and_serve(warp_metrics::global().clone(), None, metrics_bind).await
+                    {
+                        eprintln!("Metrics exporter on {metrics_bind} stopped: {e}");
+                    }
+                });
+            }
             let dest = parse_destination(&destination)?;
             let mut receiver = Receiver::new(dest)?;
             run_rx(&mut receiver, &output_path).await?;
@@ -348,10 +356,7 @@ async fn run_tx(sender: &mut Sender) -> Result<(), anyhow::Error> {
 async fn run_rx(receiver: &mut Receiver, output_path: &str) -> Result<(), anyhow::Error> {
     let file = File::create(output_path)?;
     let mut buf_writer = BufWriter::with_capacity(64 * 1024, file);
-    writeln!(
-        buf_writer,
-        "counter,target_pps,sender_achieved_pps,receiver_calculated_pps,latency_ms"
-    )?;
+    writeln!(buf_writer, "{}", warp_gauge::analysis::CSV_HEADER.join(","))?;
 
     let mut buf = vec![0u8; PACKET_SIZE];
 
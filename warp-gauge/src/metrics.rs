@@ -0,0 +1,22 @@
+//! Metrics exported in rx mode; see `warp_metrics` for the naming conventions
+
+use std::sync::LazyLock;
+use warp_metrics::{Counter, Gauge, Histogram};
+
+pub static RX_PACKETS: LazyLock<Counter> =
+    LazyLock::new(|| warp_metrics::global().counter("warp_gauge_rx_packets_total", "Benchmark packets received"));
+
+pub static RX_PPS: LazyLock<Gauge> = LazyLock::new(|| {
+    warp_metrics::global().gauge(
+        "warp_gauge_rx_packets_per_second",
+        "Benchmark packets received over the last second",
+    )
+});
+
+pub static RX_LATENCY_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    warp_metrics::global().histogram(
+        "warp_gauge_rx_latency_seconds",
+        "One-way latency of benchmark packets (includes clock skew between sender and receiver)",
+        warp_metrics::LATENCY_BUCKETS,
+    )
+});
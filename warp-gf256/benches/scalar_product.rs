@@ -33,9 +33,8 @@ pub fn scalar_product(c: &mut Criterion) {
     group.bench_with_input(BenchmarkId::new("scalar_product_fallback", 8), &input, |b, i| {
         b.iter(|| warp_gf256::simd::scalar_product_fallback(SCALAR, i))
     });
-    #[cfg(target_feature = "neon")]
-    group.bench_with_input(BenchmarkId::new("scalar_product_neon", 8), &input, |b, i| {
-        b.iter(|| warp_gf256::simd::scalar_product_neon(SCALAR, i))
+    group.bench_with_input(BenchmarkId::new("scalar_product", 8), &input, |b, i| {
+        b.iter(|| warp_gf256::simd::scalar_product(SCALAR, i))
     });
 
     let input: [u8; 16] = std::array::from_fn(|i| i as u8);
@@ -43,9 +42,8 @@ pub fn scalar_product(c: &mut Criterion) {
     group.bench_with_input(BenchmarkId::new("scalar_product_fallback", 16), &input, |b, i| {
         b.iter(|| warp_gf256::simd::scalar_product_fallback(SCALAR, i))
     });
-    #[cfg(target_feature = "neon")]
-    group.bench_with_input(BenchmarkId::new("scalar_product_neon", 16), &input, |b, i| {
-        b.iter(|| warp_gf256::simd::scalar_product_neon(SCALAR, i))
+    group.bench_with_input(BenchmarkId::new("scalar_product", 16), &input, |b, i| {
+        b.iter(|| warp_gf256::simd::scalar_product(SCALAR, i))
     });
 
     let input: [u8; 32] = std::array::from_fn(|i| i as u8);
@@ -53,9 +51,8 @@ pub fn scalar_product(c: &mut Criterion) {
     group.bench_with_input(BenchmarkId::new("scalar_product_fallback", 32), &input, |b, i| {
         b.iter(|| warp_gf256::simd::scalar_product_fallback(SCALAR, i))
     });
-    #[cfg(target_feature = "neon")]
-    group.bench_with_input(BenchmarkId::new("scalar_product_neon", 32), &input, |b, i| {
-        b.iter(|| warp_gf256::simd::scalar_product_neon(SCALAR, i))
+    group.bench_with_input(BenchmarkId::new("scalar_product", 32), &input, |b, i| {
+        b.iter(|| warp_gf256::simd::scalar_product(SCALAR, i))
     });
 
     let input: [u8; 64] = std::array::from_fn(|i| i as u8);
@@ -63,9 +60,8 @@ pub fn scalar_product(c: &mut Criterion) {
     group.bench_with_input(BenchmarkId::new("scalar_product_fallback", 64), &input, |b, i| {
         b.iter(|| warp_gf256::simd::scalar_product_fallback(SCALAR, i))
     });
-    #[cfg(target_feature = "neon")]
-    group.bench_with_input(BenchmarkId::new("scalar_product_neon", 64), &input, |b, i| {
-        b.iter(|| warp_gf256::simd::scalar_product_neon(SCALAR, i))
+    group.bench_with_input(BenchmarkId::new("scalar_product", 64), &input, |b, i| {
+        b.iter(|| warp_gf256::simd::scalar_product(SCALAR, i))
     });
 
     let input: [u8; 128] = std::array::from_fn(|i| i as u8);
@@ -73,9 +69,8 @@ pub fn scalar_product(c: &mut Criterion) {
     group.bench_with_input(BenchmarkId::new("scalar_product_fallback", 128), &input, |b, i| {
         b.iter(|| warp_gf256::simd::scalar_product_fallback(SCALAR, i))
     });
-    #[cfg(target_feature = "neon")]
-    group.bench_with_input(BenchmarkId::new("scalar_product_neon", 128), &input, |b, i| {
-        b.iter(|| warp_gf256::simd::scalar_product_neon(SCALAR, i))
+    group.bench_with_input(BenchmarkId::new("scalar_product", 128), &input, |b, i| {
+        b.iter(|| warp_gf256::simd::scalar_product(SCALAR, i))
     });
 
     let input: [u8; 256] = std::array::from_fn(|i| i as u8);
@@ -83,9 +78,8 @@ pub fn scalar_product(c: &mut Criterion) {
     group.bench_with_input(BenchmarkId::new("scalar_product_fallback", 256), &input, |b, i| {
         b.iter(|| warp_gf256::simd::scalar_product_fallback(SCALAR, i))
     });
-    #[cfg(target_feature = "neon")]
-    group.bench_with_input(BenchmarkId::new("scalar_product_neon", 256), &input, |b, i| {
-        b.iter(|| warp_gf256::simd::scalar_product_neon(SCALAR, i))
+    group.bench_with_input(BenchmarkId::new("scalar_product", 256), &input, |b, i| {
+        b.iter(|| warp_gf256::simd::scalar_product(SCALAR, i))
     });
 
     let input: [u8; 512] = std::array::from_fn(|i| i as u8);
@@ -93,9 +87,8 @@ pub fn scalar_product(c: &mut Criterion) {
     group.bench_with_input(BenchmarkId::new("scalar_product_fallback", 512), &input, |b, i| {
         b.iter(|| warp_gf256::simd::scalar_product_fallback(SCALAR, i))
     });
-    #[cfg(target_feature = "neon")]
-    group.bench_with_input(BenchmarkId::new("scalar_product_neon", 512), &input, |b, i| {
-        b.iter(|| warp_gf256::simd::scalar_product_neon(SCALAR, i))
+    group.bench_with_input(BenchmarkId::new("scalar_product", 512), &input, |b, i| {
+        b.iter(|| warp_gf256::simd::scalar_product(SCALAR, i))
     });
 
     let input: [u8; 1024] = std::array::from_fn(|i| i as u8);
@@ -103,9 +96,8 @@ pub fn scalar_product(c: &mut Criterion) {
     group.bench_with_input(BenchmarkId::new("scalar_product_fallback", 1024), &input, |b, i| {
         b.iter(|| warp_gf256::simd::scalar_product_fallback(SCALAR, i))
     });
-    #[cfg(target_feature = "neon")]
-    group.bench_with_input(BenchmarkId::new("scalar_product_neon", 1024), &input, |b, i| {
-        b.iter(|| warp_gf256::simd::scalar_product_neon(SCALAR, i))
+    group.bench_with_input(BenchmarkId::new("scalar_product", 1024), &input, |b, i| {
+        b.iter(|| warp_gf256::simd::scalar_product(SCALAR, i))
     });
 
     let input: [u8; 2048] = std::array::from_fn(|i| i as u8);
@@ -113,9 +105,8 @@ pub fn scalar_product(c: &mut Criterion) {
     group.bench_with_input(BenchmarkId::new("scalar_product_fallback", 2048), &input, |b, i| {
         b.iter(|| warp_gf256::simd::scalar_product_fallback(SCALAR, i))
     });
-    #[cfg(target_feature = "neon")]
-    group.bench_with_input(BenchmarkId::new("scalar_product_neon", 2048), &input, |b, i| {
-        b.iter(|| warp_gf256::simd::scalar_product_neon(SCALAR, i))
+    group.bench_with_input(BenchmarkId::new("scalar_product", 2048), &input, |b, i| {
+        b.iter(|| warp_gf256::simd::scalar_product(SCALAR, i))
     });
 
     let input: [u8; 4096] = std::array::from_fn(|i| i as u8);
@@ -123,11 +114,44 @@ pub fn scalar_product(c: &mut Criterion) {
     group.bench_with_input(BenchmarkId::new("scalar_product_fallback", 4096), &input, |b, i| {
         b.iter(|| warp_gf256::simd::scalar_product_fallback(SCALAR, i))
     });
-    #[cfg(target_feature = "neon")]
-    group.bench_with_input(BenchmarkId::new("scalar_product_neon", 4096), &input, |b, i| {
-        b.iter(|| warp_gf256::simd::scalar_product_neon(SCALAR, i))
+    group.bench_with_input(BenchmarkId::new("scalar_product", 4096), &input, |b, i| {
+        b.iter(|| warp_gf256::simd::scalar_product(SCALAR, i))
     });
 }
 
-criterion_group!(benches, scalar_product);
+pub fn mul_add_assign(c: &mut Criterion) {
+    use warp_gf256::GF256;
+    const SCALAR: GF256 = GF256(7);
+
+    let mut group = c.benchmark_group("mul_add_assign");
+
+    // About a shard's worth, at a typical MTU
+    let input: Vec<u8> = (0..=255).cycle().take(1400).collect();
+    let mut output = vec![0u8; 1400];
+    group.bench_with_input(BenchmarkId::new("mul_add_assign_fallback", 1400), &input, |b, i| {
+        b.iter(|| warp_gf256::simd::mul_add_assign_fallback(SCALAR, i, &mut output))
+    });
+    group.bench_with_input(BenchmarkId::new("mul_add_assign", 1400), &input, |b, i| {
+        b.iter(|| warp_gf256::simd::mul_add_assign(SCALAR, i, &mut output))
+    });
+}
+
+// Build with and without the small-tables feature to compare how each multiplies
+pub fn mul(c: &mut Criterion) {
+    use warp_gf256::GF256;
+
+    let mut group = c.benchmark_group("mul");
+
+    let pairs: Vec<(GF256, GF256)> = (0..4096).map(|i| (GF256(i as u8), GF256((i * 7 / 3) as u8))).collect();
+    group.bench_with_input(BenchmarkId::new("mul", pairs.len()), &pairs, |b, pairs| {
+        b.iter(|| {
+            pairs
+                .iter()
+                .map(|(x, y)| *x * *y)
+                .fold(GF256(0), |sum, product| sum + product)
+        })
+    });
+}
+
+criterion_group!(benches, scalar_product, mul_add_assign, mul);
 criterion_main!(benches);
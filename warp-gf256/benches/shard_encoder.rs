@@ -0,0 +1,42 @@
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use warp_gf256::rs::ReedSolomon;
+
+// A packet's worth of data per shard, at a typical MTU
+const SHARD_SIZE: usize = 1400;
+
+pub fn shard_encoder(c: &mut Criterion) {
+    let mut group = c.benchmark_group("shard_encoder");
+
+    for (data_shards, parity_shards) in [(1, 1), (3, 2), (8, 4)] {
+        let code = ReedSolomon::new(data_shards, parity_shards).unwrap();
+        let packets: Vec<Vec<u8>> = (0..data_shards)
+            .map(|packet| (0..SHARD_SIZE).map(|byte| (packet * 31 + byte) as u8).collect())
+            .collect();
+        let parameter = format!("{data_shards}+{parity_shards}");
+
+        group.bench_with_input(BenchmarkId::new("encode", &parameter), &packets, |b, packets| {
+            b.iter(|| {
+                let mut shards = packets.clone();
+                shards.resize(data_shards + parity_shards, vec![0; SHARD_SIZE]);
+                code.encode(&mut shards).unwrap();
+                shards
+            })
+        });
+        group.bench_with_input(
+            BenchmarkId::new("stream_encoder", &parameter),
+            &packets,
+            |b, packets| {
+                b.iter(|| {
+                    let mut encoder = code.stream_encoder(SHARD_SIZE);
+                    for packet in packets {
+                        encoder.update(packet).unwrap();
+                    }
+                    encoder.finish()
+                })
+            },
+        );
+    }
+}
+
+criterion_group!(benches, shard_encoder);
+criterion_main!(benches);
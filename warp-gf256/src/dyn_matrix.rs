@@ -0,0 +1,356 @@
+//! Matrices whose dimensions are only known at runtime, such as those sized by configured shard counts
+//!
+//! Arithmetic between matrices of mismatched dimensions panics, as the compiler can't rule it out the way it does for
+//! [`Matrix`](super::matrix::Matrix).
+
+use super::matrix::gauss_jordan;
+use super::{Additive, Error, GF256, Multiplicative};
+use std::ops::{Add, AddAssign, Index, IndexMut, Mul, Sub, SubAssign};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DynMatrix<const PRIMITIVE_POLYNOMIAL: u16 = { super::DEFAULT_POLYNOMIAL }> {
+    cols: usize,
+    data: Vec<Vec<GF256<PRIMITIVE_POLYNOMIAL>>>,
+}
+
+impl<const PRIMITIVE_POLYNOMIAL: u16> DynMatrix<PRIMITIVE_POLYNOMIAL> {
+    /// A matrix of `data`'s rows, which must all be the same length
+    pub fn new(data: Vec<Vec<u8>>) -> Self {
+        let cols = data.first().map_or(0, Vec::len);
+        assert!(data.iter().all(|row| row.len() == cols), "rows differ in length");
+        Self {
+            cols,
+            data: data
+                .into_iter()
+                .map(|row| row.into_iter().map(GF256).collect())
+                .collect(),
+        }
+    }
+
+    pub fn zeros(rows: usize, cols: usize) -> Self {
+        Self {
+            cols,
+            data: vec![vec![<GF256<PRIMITIVE_POLYNOMIAL> as Additive>::identity(); cols]; rows],
+        }
+    }
+
+    pub fn identity(size: usize) -> Self {
+        let mut matrix = Self::zeros(size, size);
+        for i in 0..size {
+            matrix.data[i][i] = <GF256<PRIMITIVE_POLYNOMIAL> as Multiplicative>::identity();
+        }
+        matrix
+    }
+
+    /// The Cauchy matrix with element `1 / (xs[i] + ys[j])` in row `i` and column `j`. No `x` may equal a `y`; if the
+    /// `xs` are distinct and the `ys` are too, every square submatrix is invertible.
+    pub fn cauchy(xs: &[u8], ys: &[u8]) -> Result<Self, Error> {
+        let mut matrix = Self::zeros(xs.len(), ys.len());
+        for (row, x) in xs.iter().enumerate() {
+            for (col, y) in ys.iter().enumerate() {
+                matrix.data[row][col] = Multiplicative::inverse(&(GF256(*x) + GF256(*y)))?;
+            }
+        }
+        Ok(matrix)
+    }
+
+    /// The Vandermonde matrix with element `points[i]^j` in row `i` and column `j`, invertible if square and the
+    /// `points` are distinct
+    pub fn vandermonde(points: &[u8], cols: usize) -> Self {
+        let mut matrix = Self::zeros(points.len(), cols);
+        for (row, point) in points.iter().enumerate() {
+            let mut power = <GF256<PRIMITIVE_POLYNOMIAL> as Multiplicative>::identity();
+            for col in 0..cols {
+                matrix.data[row][col] = power;
+                power *= GF256(*point);
+            }
+        }
+        matrix
+    }
+
+    /// The generator of a systematic code that makes `total_shards` shards of `data_shards`: the identity, so data
+    /// shards are sent as they are, over a Cauchy matrix for the parity shards. Any `data_shards` of its rows are
+    /// invertible, so any `data_shards` of the shards are enough to reconstruct the rest.
+    pub fn systematic_cauchy(data_shards: usize, total_shards: usize) -> Result<Self, Error> {
+        validate_code(data_shards, total_shards)?;
+        // Rows are indexed by shard and columns by data shard, so the two never coincide
+        let parity_points: Vec<u8> = (data_shards..total_shards).map(|index| index as u8).collect();
+        let data_points: Vec<u8> = (0..data_shards).map(|index| index as u8).collect();
+        Ok(Self::identity(data_shards).stack(&Self::cauchy(&parity_points, &data_points)?))
+    }
+
+    /// Like [`DynMatrix::systematic_cauchy`], but with the Vandermonde matrix of a distinct point per shard in place of
+    /// the Cauchy matrix, multiplied by the inverse of its first `data_shards` rows to make them the identity
+    pub fn systematic_vandermonde(data_shards: usize, total_shards: usize) -> Result<Self, Error> {
+        validate_code(data_shards, total_shards)?;
+        let points: Vec<u8> = (0..total_shards).map(|index| index as u8).collect();
+        let vandermonde = Self::vandermonde(&points, data_shards);
+        let top = vandermonde.select_rows(&(0..data_shards).collect::<Vec<_>>());
+        Ok(vandermonde * top.inverse()?)
+    }
+
+    pub fn rows(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn row(&self, row: usize) -> &[GF256<PRIMITIVE_POLYNOMIAL>] {
+        &self.data[row]
+    }
+
+    pub fn transpose(&self) -> Self {
+        let mut transposed = Self::zeros(self.cols, self.rows());
+        for (i, row) in self.data.iter().enumerate() {
+            for (j, value) in row.iter().enumerate() {
+                transposed.data[j][i] = *value;
+            }
+        }
+        transposed
+    }
+
+    /// The matrix of `self`'s rows followed by `below`'s
+    pub fn stack(&self, below: &Self) -> Self {
+        assert_eq!(self.cols, below.cols, "stacked matrices differ in columns");
+        Self {
+            cols: self.cols,
+            data: self.data.iter().chain(&below.data).cloned().collect(),
+        }
+    }
+
+    /// The matrix of the given rows of `self`, in the order given
+    pub fn select_rows(&self, rows: &[usize]) -> Self {
+        Self {
+            cols: self.cols,
+            data: rows.iter().map(|&row| self.data[row].clone()).collect(),
+        }
+    }
+
+    pub fn inverse(&self) -> Result<Self, Error> {
+        if self.rows() != self.cols {
+            return Err(Error::NotSquare {
+                rows: self.rows(),
+                cols: self.cols,
+            });
+        }
+        let mut reduced = self.data.clone();
+        let mut inverse = Self::identity(self.cols);
+        gauss_jordan(&mut reduced, &mut inverse.data)?;
+        Ok(inverse)
+    }
+
+    fn assert_same_dimensions(&self, rhs: &Self) {
+        assert!(
+            self.rows() == rhs.rows() && self.cols == rhs.cols,
+            "{}x{} and {}x{} matrices differ in dimensions",
+            self.rows(),
+            self.cols,
+            rhs.rows(),
+            rhs.cols
+        );
+    }
+}
+
+fn validate_code(data_shards: usize, total_shards: usize) -> Result<(), Error> {
+    if data_shards == 0 || data_shards > total_shards || total_shards > super::rs::MAX_SHARDS {
+        return Err(Error::InvalidShardCounts {
+            data_shards,
+            parity_shards: total_shards.saturating_sub(data_shards),
+        });
+    }
+    Ok(())
+}
+
+impl<const PRIMITIVE_POLYNOMIAL: u16> Index<(usize, usize)> for DynMatrix<PRIMITIVE_POLYNOMIAL> {
+    type Output = GF256<PRIMITIVE_POLYNOMIAL>;
+
+    #[inline]
+    fn index(&self, index: (usize, usize)) -> &Self::Output {
+        &self.data[index.0][index.1]
+    }
+}
+
+impl<const PRIMITIVE_POLYNOMIAL: u16> IndexMut<(usize, usize)> for DynMatrix<PRIMITIVE_POLYNOMIAL> {
+    #[inline]
+    fn index_mut(&mut self, index: (usize, usize)) -> &mut GF256<PRIMITIVE_POLYNOMIAL> {
+        &mut self.data[index.0][index.1]
+    }
+}
+
+impl<const PRIMITIVE_POLYNOMIAL: u16> Add for DynMatrix<PRIMITIVE_POLYNOMIAL> {
+    type Output = Self;
+
+    fn add(mut self, rhs: Self) -> Self::Output {
+        self += rhs;
+        self
+    }
+}
+
+impl<const PRIMITIVE_POLYNOMIAL: u16> AddAssign for DynMatrix<PRIMITIVE_POLYNOMIAL> {
+    fn add_assign(&mut self, rhs: Self) {
+        self.assert_same_dimensions(&rhs);
+        for (row, rhs_row) in self.data.iter_mut().zip(rhs.data) {
+            for (value, rhs_value) in row.iter_mut().zip(rhs_row) {
+                *value += rhs_value;
+            }
+        }
+    }
+}
+
+impl<const PRIMITIVE_POLYNOMIAL: u16> Sub for DynMatrix<PRIMITIVE_POLYNOMIAL> {
+    type Output = Self;
+
+    fn sub(mut self, rhs: Self) -> Self::Output {
+        self -= rhs;
+        self
+    }
+}
+
+impl<const PRIMITIVE_POLYNOMIAL: u16> SubAssign for DynMatrix<PRIMITIVE_POLYNOMIAL> {
+    fn sub_assign(&mut self, rhs: Self) {
+        self.assert_same_dimensions(&rhs);
+        for (row, rhs_row) in self.data.iter_mut().zip(rhs.data) {
+            for (value, rhs_value) in row.iter_mut().zip(rhs_row) {
+                *value -= rhs_value;
+            }
+        }
+    }
+}
+
+impl<const PRIMITIVE_POLYNOMIAL: u16> Mul for DynMatrix<PRIMITIVE_POLYNOMIAL> {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        assert_eq!(
+            self.cols,
+            rhs.rows(),
+            "can't multiply a {}x{} matrix by a {}x{} one",
+            self.rows(),
+            self.cols,
+            rhs.rows(),
+            rhs.cols
+        );
+        let rhs_t = rhs.transpose();
+        let mut product = Self::zeros(self.rows(), rhs.cols);
+        for (row, product_row) in self.data.iter().zip(&mut product.data) {
+            for (col, value) in rhs_t.data.iter().zip(product_row.iter_mut()) {
+                *value = row.iter().zip(col).map(|(x, y)| *x * *y).sum();
+            }
+        }
+        product
+    }
+}
+
+#[test]
+fn test_inverse_matches_fixed_size() {
+    use super::matrix::Matrix;
+
+    let data = [[1, 1, 1, 1], [1, 2, 4, 8], [1, 3, 5, 15], [7, 0, 9, 64]];
+    let fixed = Multiplicative::inverse(&Matrix::<4, 4>::new(data)).unwrap();
+    let dynamic = DynMatrix::<{ super::DEFAULT_POLYNOMIAL }>::new(data.map(Vec::from).to_vec())
+        .inverse()
+        .unwrap();
+    for i in 0..4 {
+        for j in 0..4 {
+            assert_eq!(dynamic[(i, j)], fixed[(i, j)]);
+        }
+    }
+}
+
+#[test]
+fn test_constructors_are_invertible() {
+    let identity = DynMatrix::<{ super::DEFAULT_POLYNOMIAL }>::identity(6);
+
+    let vandermonde = DynMatrix::vandermonde(&[1, 2, 3, 4, 5, 6], 6);
+    assert_eq!(vandermonde[(2, 0)], GF256(1));
+    assert_eq!(vandermonde[(2, 2)], GF256(3) * GF256(3));
+    assert_eq!(vandermonde.clone() * vandermonde.inverse().unwrap(), identity);
+
+    let cauchy = DynMatrix::cauchy(&[6, 7, 8, 9, 10, 11], &[0, 1, 2, 3, 4, 5]).unwrap();
+    assert_eq!(cauchy[(1, 3)], Multiplicative::inverse(&GF256(7 ^ 3)).unwrap());
+    assert_eq!(cauchy.inverse().unwrap() * cauchy, identity);
+
+    assert!(matches!(
+        DynMatrix::<{ super::DEFAULT_POLYNOMIAL }>::cauchy(&[1, 2], &[2, 3]),
+        Err(Error::DivideByZero)
+    ));
+}
+
+#[test]
+fn test_non_invertible() {
+    let singular = DynMatrix::<{ super::DEFAULT_POLYNOMIAL }>::new(vec![vec![1, 2], vec![2, 4]]);
+    assert!(matches!(singular.inverse(), Err(Error::SingularMatrix)));
+    let rectangular = DynMatrix::<{ super::DEFAULT_POLYNOMIAL }>::vandermonde(&[1, 2, 3], 2);
+    assert!(matches!(
+        rectangular.inverse(),
+        Err(Error::NotSquare { rows: 3, cols: 2 })
+    ));
+}
+
+#[test]
+fn test_arithmetic() {
+    let a = DynMatrix::<{ super::DEFAULT_POLYNOMIAL }>::new(vec![vec![1, 2, 3], vec![4, 5, 6]]);
+    assert_eq!(a.clone() + a.clone(), DynMatrix::zeros(2, 3));
+    assert_eq!(a.clone() - DynMatrix::zeros(2, 3), a);
+    assert_eq!(a.clone() * DynMatrix::identity(3), a);
+    assert_eq!(a.transpose().transpose(), a);
+    assert_eq!(
+        a.select_rows(&[1]).stack(&a.select_rows(&[0])),
+        DynMatrix::new(vec![vec![4, 5, 6], vec![1, 2, 3]])
+    );
+}
+
+#[test]
+#[should_panic]
+fn test_mismatched_dimensions() {
+    let a = DynMatrix::<{ super::DEFAULT_POLYNOMIAL }>::zeros(2, 3);
+    let _ = a.clone() * a;
+}
+
+#[test]
+fn test_systematic_generators_reconstruct_any_erasures() {
+    use rand::Rng;
+    use rand::seq::index::sample;
+
+    let mut rng = rand::rng();
+    for _ in 0..200 {
+        let data_shards = rng.random_range(1..=16);
+        let total_shards = rng.random_range(data_shards..=data_shards + 16);
+        for generator in [
+            DynMatrix::<{ super::DEFAULT_POLYNOMIAL }>::systematic_cauchy(data_shards, total_shards).unwrap(),
+            DynMatrix::<{ super::DEFAULT_POLYNOMIAL }>::systematic_vandermonde(data_shards, total_shards).unwrap(),
+        ] {
+            assert_eq!((generator.rows(), generator.cols()), (total_shards, data_shards));
+            let data_rows: Vec<usize> = (0..data_shards).collect();
+            assert_eq!(generator.select_rows(&data_rows), DynMatrix::identity(data_shards));
+
+            // The shards that survive some erasure pattern
+            let surviving = sample(&mut rng, total_shards, data_shards).into_vec();
+            let submatrix = generator.select_rows(&surviving);
+            assert_eq!(
+                submatrix.clone() * submatrix.inverse().unwrap(),
+                DynMatrix::identity(data_shards)
+            );
+        }
+    }
+}
+
+#[test]
+fn test_largest_systematic_generators() {
+    for generator in [
+        DynMatrix::<{ super::DEFAULT_POLYNOMIAL }>::systematic_cauchy(16, 256).unwrap(),
+        DynMatrix::<{ super::DEFAULT_POLYNOMIAL }>::systematic_vandermonde(16, 256).unwrap(),
+    ] {
+        // Only the last of the parity shards survive
+        let parity_rows: Vec<usize> = (240..256).collect();
+        assert!(generator.select_rows(&parity_rows).inverse().is_ok());
+    }
+    for (data_shards, total_shards) in [(0, 4), (5, 4), (10, 257)] {
+        assert!(matches!(
+            DynMatrix::<{ super::DEFAULT_POLYNOMIAL }>::systematic_cauchy(data_shards, total_shards),
+            Err(Error::InvalidShardCounts { .. })
+        ));
+    }
+}
@@ -1,6 +1,17 @@
+//! Arithmetic over GF(256), and the Reed-Solomon erasure coding built on it
+//!
+//! By default, multiplying looks the product up in a 64 KiB table, one per primitive polynomial. Building with the
+//! `small-tables` feature multiplies with the 512 bytes of log and exp tables that inversion needs anyway, and slices
+//! with 32 bytes of nibble tables per scalar. That leaves the cache to the rest of the program, at a cost where there is
+//! no SIMD to fall back on: in `benches/scalar_product.rs` on an x86_64 desktop, whose cache holds the big table
+//! comfortably, single multiplications and the scalar `mul_add_assign_fallback` took about three times as long. The
+//! SSSE3, AVX2 and NEON kernels use nibble tables either way, and are as fast with the feature as without.
+
+pub mod dyn_matrix;
 mod lut;
 //pub mod matrix;
 pub mod matrix;
+pub mod rs;
 pub mod simd;
 use std::ops::{Add, AddAssign, Mul, MulAssign, Sub, SubAssign};
 
@@ -14,6 +25,20 @@ pub struct GF256<const PRIMITIVE_POLYNOMIAL: u16 = DEFAULT_POLYNOMIAL>(pub u8);
 pub enum Error {
     #[error("division by zero")]
     DivideByZero,
+    #[error("matrix is singular")]
+    SingularMatrix,
+    #[error("a {rows}x{cols} matrix has no inverse")]
+    NotSquare { rows: usize, cols: usize },
+    #[error("{data_shards} data shards and {parity_shards} parity shards is not a valid code")]
+    InvalidShardCounts { data_shards: usize, parity_shards: usize },
+    #[error("expected {expected} shards, got {actual}")]
+    WrongShardCount { expected: usize, actual: usize },
+    #[error("shards differ in size")]
+    ShardSizeMismatch,
+    #[error("{present} shards present, {required} needed to reconstruct the rest")]
+    TooFewShards { present: usize, required: usize },
+    #[error("more data than the {capacity} bytes the data shards hold")]
+    TooMuchData { capacity: usize },
 }
 
 pub trait Additive {
@@ -31,6 +56,7 @@ pub trait Multiplicative {
 impl<const PRIMITIVE_POLYNOMIAL: u16> GF256<PRIMITIVE_POLYNOMIAL> {
     pub(crate) const LOG_TABLE: [u8; 256] = lut::generate_log_table(PRIMITIVE_POLYNOMIAL);
     pub(crate) const EXP_TABLE: [u8; 256] = lut::generate_exp_table(PRIMITIVE_POLYNOMIAL);
+    #[cfg(not(feature = "small-tables"))]
     pub(crate) const MUL_TABLE: [[u8; 256]; 256] = lut::generate_mul_table(PRIMITIVE_POLYNOMIAL);
 }
 
@@ -105,7 +131,17 @@ impl<const PRIMITIVE_POLYNOMIAL: u16> Mul for GF256<PRIMITIVE_POLYNOMIAL> {
 
     #[inline]
     fn mul(self, rhs: Self) -> Self::Output {
-        GF256(Self::MUL_TABLE[self.0 as usize][rhs.0 as usize])
+        #[cfg(not(feature = "small-tables"))]
+        let product = Self::MUL_TABLE[self.0 as usize][rhs.0 as usize];
+        // a * b = exp(log(a) + log(b)), the logs adding modulo 255 as the multiplicative group has 255 elements
+        #[cfg(feature = "small-tables")]
+        let product = if self.0 == 0 || rhs.0 == 0 {
+            0
+        } else {
+            let log = Self::LOG_TABLE[self.0 as usize] as usize + Self::LOG_TABLE[rhs.0 as usize] as usize;
+            Self::EXP_TABLE[log % 255]
+        };
+        GF256(product)
     }
 }
 
@@ -146,3 +182,16 @@ fn test_mul_inv() {
         assert_eq!(i, (i * i) * inv);
     }
 }
+
+#[test]
+fn test_mul_matches_table() {
+    let table = lut::generate_mul_table(DEFAULT_POLYNOMIAL);
+    for a in 0..=255 {
+        for b in 0..=255 {
+            assert_eq!(
+                (GF256::<DEFAULT_POLYNOMIAL>(a) * GF256(b)).0,
+                table[a as usize][b as usize]
+            );
+        }
+    }
+}
@@ -32,6 +32,7 @@ pub const fn generate_log_table(primitive_polynomial: u16) -> [u8; 256] {
     log
 }
 
+#[cfg(any(test, not(feature = "small-tables")))]
 pub const fn generate_mul_table(primitive_polynomial: u16) -> [[u8; 256]; 256] {
     let exp = generate_exp_table(primitive_polynomial);
     let log = generate_log_table(primitive_polynomial);
@@ -23,60 +23,7 @@ impl<const ROWS: usize, const COLS: usize, const PRIMITIVE_POLYNOMIAL: u16> Matr
     }
 }
 
-pub fn scalar_product<const SIZE: usize, const PRIMITIVE_POLYNOMIAL: u16>(
-    scalar: super::GF256<PRIMITIVE_POLYNOMIAL>,
-    vector: &[super::GF256<PRIMITIVE_POLYNOMIAL>; SIZE],
-) -> [super::GF256<PRIMITIVE_POLYNOMIAL>; SIZE] {
-    #[cfg(target_feature = "neon")]
-    return scalar_product_neon(scalar, vector);
-    #[allow(unreachable_code)]
-    scalar_product_fallback(scalar, vector)
-}
-
-pub fn scalar_product_fallback<const SIZE: usize, const PRIMITIVE_POLYNOMIAL: u16>(
-    scalar: super::GF256<PRIMITIVE_POLYNOMIAL>,
-    vector: &[super::GF256<PRIMITIVE_POLYNOMIAL>; SIZE],
-) -> [super::GF256<PRIMITIVE_POLYNOMIAL>; SIZE] {
-    let mul_lookup_table = GF256::<PRIMITIVE_POLYNOMIAL>::MUL_TABLE[scalar.0 as usize];
-    vector.map(|x| super::GF256(mul_lookup_table[x.0 as usize]))
-}
-
-#[cfg(target_feature = "neon")]
-pub fn scalar_product_neon<const SIZE: usize, const PRIMITIVE_POLYNOMIAL: u16>(
-    scalar: super::GF256<PRIMITIVE_POLYNOMIAL>,
-    vector: &[super::GF256<PRIMITIVE_POLYNOMIAL>; SIZE],
-) -> [super::GF256<PRIMITIVE_POLYNOMIAL>; SIZE] {
-    let mut product = [<super::GF256<PRIMITIVE_POLYNOMIAL> as Additive>::identity(); SIZE];
-    let mul_lookup_table = GF256::<PRIMITIVE_POLYNOMIAL>::MUL_TABLE[scalar.0 as usize];
-    unsafe {
-        // Stolen from: https://users.rust-lang.org/t/ensure-that-struct-t-has-size-n-at-compile-time/61108/4
-        // Compile time check that GF256 is the same size as u8 so that ptr cast below is valid
-        const _: () = [(); 1][(core::mem::size_of::<super::GF256<0>>() == core::mem::size_of::<u8>()) as usize ^ 1];
-
-        let mut i = 0;
-        while i + 16 < SIZE {
-            let simd_slice_chunk = std::arch::aarch64::vld1q_u8(vector[i..].as_ptr() as *mut u8);
-            let simd_slice_chunk_low = std::arch::aarch64::vget_low_u8(simd_slice_chunk);
-            let simd_slice_chunk_high = std::arch::aarch64::vget_high_u8(simd_slice_chunk);
-            let low_result = std::arch::aarch64::vqtbl1_u8(
-                std::arch::aarch64::vld1q_u8(mul_lookup_table.as_ptr()),
-                simd_slice_chunk_low,
-            );
-            let high_result = std::arch::aarch64::vqtbl1_u8(
-                std::arch::aarch64::vld1q_u8(mul_lookup_table.as_ptr()),
-                simd_slice_chunk_high,
-            );
-            let result = std::arch::aarch64::vcombine_u8(low_result, high_result);
-            std::arch::aarch64::vst1q_u8(product[i..].as_mut_ptr() as *mut u8, result);
-            i += 16;
-        }
-
-        for j in i..SIZE {
-            product[j] = super::GF256(mul_lookup_table[vector[j].0 as usize]);
-        }
-    }
-    product
-}
+pub use super::simd::{scalar_product, scalar_product_fallback};
 
 #[test]
 fn test_scalar_product() {
@@ -185,8 +132,50 @@ impl<const SIZE: usize, const PRIMITIVE_POLYNOMIAL: u16> Multiplicative for Matr
     where
         Self: Sized,
     {
-        todo!()
+        let mut reduced = self.0;
+        let mut inverse = <Self as Multiplicative>::identity().0;
+        gauss_jordan(&mut reduced, &mut inverse)?;
+        Ok(Self(inverse))
+    }
+}
+
+/// Gauss-Jordan elimination over square matrices of any size: the row operations that reduce `matrix` to the identity
+/// are applied to `inverse` as well, which turns an identity passed as `inverse` into the inverse of `matrix`
+pub(crate) fn gauss_jordan<const PRIMITIVE_POLYNOMIAL: u16>(
+    matrix: &mut [impl AsMut<[GF256<PRIMITIVE_POLYNOMIAL>]>],
+    inverse: &mut [impl AsMut<[GF256<PRIMITIVE_POLYNOMIAL>]>],
+) -> Result<(), crate::Error> {
+    let zero = <GF256<PRIMITIVE_POLYNOMIAL> as Additive>::identity();
+    let size = matrix.len();
+
+    for column in 0..size {
+        let pivot = (column..size)
+            .find(|&row| matrix[row].as_mut()[column] != zero)
+            .ok_or(crate::Error::SingularMatrix)?;
+        matrix.swap(column, pivot);
+        inverse.swap(column, pivot);
+
+        let scale = Multiplicative::inverse(&matrix[column].as_mut()[column])?;
+        matrix[column].as_mut().iter_mut().for_each(|value| *value *= scale);
+        inverse[column].as_mut().iter_mut().for_each(|value| *value *= scale);
+
+        let pivot_row = matrix[column].as_mut().to_vec();
+        let pivot_inverse_row = inverse[column].as_mut().to_vec();
+        for row in (0..size).filter(|&row| row != column) {
+            let factor = matrix[row].as_mut()[column];
+            if factor == zero {
+                continue;
+            }
+            // Subtraction is addition in GF(256), so this clears the column
+            for (value, pivot_value) in matrix[row].as_mut().iter_mut().zip(&pivot_row) {
+                *value -= factor * *pivot_value;
+            }
+            for (value, pivot_value) in inverse[row].as_mut().iter_mut().zip(&pivot_inverse_row) {
+                *value -= factor * *pivot_value;
+            }
+        }
     }
+    Ok(())
 }
 
 impl<const ROWS: usize, const COLS: usize, const PRIMITIVE_POLYNOMIAL: u16> Add
@@ -280,3 +269,53 @@ fn test_mul() {
     let a_x2 = a.clone() + a.clone();
     assert_eq!(a_x2[(0, 0)], GF256(0));
 }
+
+#[test]
+fn test_inverse() {
+    let identity = <Matrix<4, 4> as Multiplicative>::identity();
+    assert_eq!(Multiplicative::inverse(&identity).unwrap(), identity);
+
+    // Its own inverse, as 1 + 1 = 0
+    let a = Matrix::<2, 2>::new([[1, 1], [0, 1]]);
+    assert_eq!(Multiplicative::inverse(&a).unwrap(), a);
+
+    // Needs a row swap, as the first pivot is zero
+    let b = Matrix::<3, 3>::new([[0, 1, 0], [0, 0, 1], [1, 0, 0]]);
+    assert_eq!(
+        Multiplicative::inverse(&b).unwrap(),
+        Matrix::<3, 3>::new([[0, 0, 1], [1, 0, 0], [0, 1, 0]])
+    );
+
+    // Vandermonde matrices of distinct points are invertible, as Reed-Solomon decoding relies on
+    let mut vandermonde = <Matrix<5, 5> as Additive>::identity();
+    for row in 0..5 {
+        let mut power = <GF256 as Multiplicative>::identity();
+        for column in 0..5 {
+            vandermonde[(row, column)] = power;
+            power *= GF256(row as u8 + 1);
+        }
+    }
+    let inverse = Multiplicative::inverse(&vandermonde).unwrap();
+    let identity = <Matrix<5, 5> as Multiplicative>::identity();
+    assert_eq!(vandermonde.clone() * inverse.clone(), identity);
+    assert_eq!(inverse * vandermonde, identity);
+}
+
+#[test]
+fn test_inverse_of_singular_matrix() {
+    let singular = [
+        <Matrix<3, 3> as Additive>::identity(),
+        // A row of zeros
+        Matrix::<3, 3>::new([[1, 2, 3], [0, 0, 0], [4, 5, 6]]),
+        // The second row is twice the first
+        Matrix::<3, 3>::new([[1, 2, 3], [2, 4, 6], [7, 8, 9]]),
+        // The third row is the sum of the others
+        Matrix::<3, 3>::new([[1, 2, 3], [4, 5, 6], [5, 7, 5]]),
+    ];
+    for matrix in singular {
+        assert!(matches!(
+            Multiplicative::inverse(&matrix),
+            Err(crate::Error::SingularMatrix)
+        ));
+    }
+}
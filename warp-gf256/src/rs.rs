@@ -0,0 +1,354 @@
+//! Reed-Solomon erasure coding over GF(256)
+//!
+//! A code with `data_shards = k` and `parity_shards = m` has k data shards followed by m parity shards, all of the same
+//! size. Parity shard `i` is a combination of the data shards using row `i` of a Cauchy matrix, which makes any k of the
+//! k + m shards enough to reconstruct the others.
+
+use super::dyn_matrix::DynMatrix;
+use super::simd::mul_add_assign;
+use super::{Error, GF256};
+
+/// The most shards a code can have, as each shard's index is an element of GF(256)
+pub const MAX_SHARDS: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct ReedSolomon {
+    data_shards: usize,
+    parity_shards: usize,
+    // Row `i` holds the coefficients that combine the data shards into shard `i`
+    encoding: DynMatrix,
+}
+
+impl ReedSolomon {
+    pub fn new(data_shards: usize, parity_shards: usize) -> Result<Self, Error> {
+        Ok(Self {
+            data_shards,
+            parity_shards,
+            encoding: DynMatrix::systematic_cauchy(data_shards, data_shards + parity_shards)?,
+        })
+    }
+
+    pub fn data_shards(&self) -> usize {
+        self.data_shards
+    }
+
+    pub fn parity_shards(&self) -> usize {
+        self.parity_shards
+    }
+
+    pub fn total_shards(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    /// A [`StreamEncoder`] of the parity shards for data shards of `shard_size` bytes
+    pub fn stream_encoder(&self, shard_size: usize) -> StreamEncoder<'_> {
+        StreamEncoder {
+            code: self,
+            shard_size,
+            position: 0,
+            parity: vec![vec![0; shard_size]; self.parity_shards],
+        }
+    }
+
+    // The size all `shards` share
+    fn shard_size(&self, shards: &[impl AsRef<[u8]>]) -> Result<usize, Error> {
+        if shards.len() != self.total_shards() {
+            return Err(Error::WrongShardCount {
+                expected: self.total_shards(),
+                actual: shards.len(),
+            });
+        }
+        let shard_size = shards[0].as_ref().len();
+        if shards.iter().any(|shard| shard.as_ref().len() != shard_size) {
+            return Err(Error::ShardSizeMismatch);
+        }
+        Ok(shard_size)
+    }
+
+    // The shard that `coefficients` combine `sources` into
+    fn combine(coefficients: &[GF256], sources: &[&[u8]], shard_size: usize) -> Vec<u8> {
+        let mut shard = vec![0u8; shard_size];
+        for (coefficient, source) in coefficients.iter().zip(sources) {
+            mul_add_assign(*coefficient, source, &mut shard);
+        }
+        shard
+    }
+
+    /// Fill in the parity shards from the data shards; `shards` holds all of them, parity shards last
+    pub fn encode<S: AsRef<[u8]> + AsMut<[u8]>>(&self, shards: &mut [S]) -> Result<(), Error> {
+        let shard_size = self.shard_size(shards)?;
+        let (data, parity) = shards.split_at_mut(self.data_shards);
+        let data: Vec<&[u8]> = data.iter().map(AsRef::as_ref).collect();
+        for (offset, parity) in parity.iter_mut().enumerate() {
+            let coefficients = self.encoding.row(self.data_shards + offset);
+            parity
+                .as_mut()
+                .copy_from_slice(&Self::combine(coefficients, &data, shard_size));
+        }
+        Ok(())
+    }
+
+    /// Fill in every shard that `present` says is missing from those that aren't; missing shards must already be the
+    /// same size as the others, but what they hold is ignored
+    pub fn reconstruct<S: AsRef<[u8]> + AsMut<[u8]>>(&self, shards: &mut [S], present: &[bool]) -> Result<(), Error> {
+        self.reconstruct_data(shards, present)?;
+        let shard_size = self.shard_size(shards)?;
+        for index in (self.data_shards..self.total_shards()).filter(|&index| !present[index]) {
+            let data: Vec<&[u8]> = shards[..self.data_shards].iter().map(AsRef::as_ref).collect();
+            let parity = Self::combine(self.encoding.row(index), &data, shard_size);
+            shards[index].as_mut().copy_from_slice(&parity);
+        }
+        Ok(())
+    }
+
+    /// Like [`ReedSolomon::reconstruct`], but only fills in missing data shards
+    pub fn reconstruct_data<S: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        shards: &mut [S],
+        present: &[bool],
+    ) -> Result<(), Error> {
+        let shard_size = self.shard_size(shards)?;
+        if present.len() != shards.len() {
+            return Err(Error::WrongShardCount {
+                expected: shards.len(),
+                actual: present.len(),
+            });
+        }
+        // Lower indices come first, so this prefers data shards, which need no decoding
+        let sources: Vec<usize> = (0..self.total_shards())
+            .filter(|&index| present[index])
+            .take(self.data_shards)
+            .collect();
+        if sources.len() < self.data_shards {
+            return Err(Error::TooFewShards {
+                present: sources.len(),
+                required: self.data_shards,
+            });
+        }
+        let missing: Vec<usize> = (0..self.data_shards).filter(|&index| !present[index]).collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        // Rows of the encoding matrix that made the shards present; their inverse turns those shards back into data
+        let decoding = self.encoding.select_rows(&sources).inverse()?;
+
+        let reconstructed: Vec<Vec<u8>> = {
+            let sources: Vec<&[u8]> = sources.iter().map(|&index| shards[index].as_ref()).collect();
+            missing
+                .iter()
+                .map(|&index| Self::combine(decoding.row(index), &sources, shard_size))
+                .collect()
+        };
+        for (index, shard) in missing.into_iter().zip(reconstructed) {
+            shards[index].as_mut().copy_from_slice(&shard);
+        }
+        Ok(())
+    }
+}
+
+/// Builds parity shards from data fed to it a chunk at a time, as the data shards laid end to end
+///
+/// Each chunk is folded into the parity shards as it arrives, so the data shards never have to be assembled; data
+/// shards the chunks stop short of filling are taken to be zero padded.
+pub struct StreamEncoder<'a> {
+    code: &'a ReedSolomon,
+    shard_size: usize,
+    // Bytes of data fed so far
+    position: usize,
+    parity: Vec<Vec<u8>>,
+}
+
+impl StreamEncoder<'_> {
+    /// Fold in the next `data`
+    pub fn update(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        let capacity = self.shard_size * self.code.data_shards;
+        if data.len() > capacity - self.position {
+            return Err(Error::TooMuchData { capacity });
+        }
+        while !data.is_empty() {
+            let (data_shard, offset) = (self.position / self.shard_size, self.position % self.shard_size);
+            let (chunk, rest) = data.split_at(data.len().min(self.shard_size - offset));
+            for (parity_shard, parity) in self.parity.iter_mut().enumerate() {
+                let coefficient = self.code.encoding[(self.code.data_shards + parity_shard, data_shard)];
+                mul_add_assign(coefficient, chunk, &mut parity[offset..offset + chunk.len()]);
+            }
+            self.position += chunk.len();
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// The parity shards of the data fed so far
+    pub fn finish(self) -> Vec<Vec<u8>> {
+        self.parity
+    }
+}
+
+#[cfg(test)]
+fn encoded_shards(code: &ReedSolomon, shard_size: usize) -> Vec<Vec<u8>> {
+    let mut shards: Vec<Vec<u8>> = (0..code.total_shards())
+        .map(|index| {
+            (0..shard_size)
+                .map(|byte| {
+                    if index < code.data_shards() {
+                        (index * 31 + byte * 7) as u8
+                    } else {
+                        0
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    code.encode(&mut shards).unwrap();
+    shards
+}
+
+#[test]
+fn test_single_data_shard_is_repeated() {
+    let code = ReedSolomon::new(1, 1).unwrap();
+    let shards = encoded_shards(&code, 10);
+    assert_eq!(shards[0], shards[1]);
+}
+
+#[test]
+fn test_reconstruct_from_any_data_shards() {
+    let code = ReedSolomon::new(4, 3).unwrap();
+    let encoded = encoded_shards(&code, 100);
+
+    // Every way of losing up to 3 of the 7 shards
+    for lost in 0u32..(1 << code.total_shards()) {
+        if lost.count_ones() as usize > code.parity_shards() {
+            continue;
+        }
+        let present: Vec<bool> = (0..code.total_shards()).map(|index| lost & (1 << index) == 0).collect();
+        let mut shards: Vec<Vec<u8>> = encoded
+            .iter()
+            .zip(&present)
+            .map(|(shard, present)| {
+                if *present {
+                    shard.clone()
+                } else {
+                    vec![0xAA; shard.len()]
+                }
+            })
+            .collect();
+
+        let mut data_only = shards.clone();
+        code.reconstruct_data(&mut data_only, &present).unwrap();
+        assert_eq!(data_only[..code.data_shards()], encoded[..code.data_shards()]);
+
+        code.reconstruct(&mut shards, &present).unwrap();
+        assert_eq!(shards, encoded);
+    }
+}
+
+#[test]
+fn test_too_few_shards() {
+    let code = ReedSolomon::new(3, 2).unwrap();
+    let mut shards = encoded_shards(&code, 8);
+    assert!(matches!(
+        code.reconstruct(&mut shards, &[true, false, true, false, false]),
+        Err(Error::TooFewShards {
+            present: 2,
+            required: 3
+        })
+    ));
+}
+
+#[test]
+fn test_invalid_codes_and_shards() {
+    for (data_shards, parity_shards) in [(0, 2), (200, 57)] {
+        assert!(matches!(
+            ReedSolomon::new(data_shards, parity_shards),
+            Err(Error::InvalidShardCounts { .. })
+        ));
+    }
+    assert!(ReedSolomon::new(200, 56).is_ok());
+
+    let code = ReedSolomon::new(2, 1).unwrap();
+    assert!(matches!(
+        code.encode(&mut [vec![0u8; 4], vec![0u8; 4]]),
+        Err(Error::WrongShardCount { expected: 3, actual: 2 })
+    ));
+    assert!(matches!(
+        code.encode(&mut [vec![0u8; 4], vec![0u8; 3], vec![0u8; 4]]),
+        Err(Error::ShardSizeMismatch)
+    ));
+    assert!(matches!(
+        code.reconstruct(&mut [vec![0u8; 4], vec![0u8; 4], vec![0u8; 4]], &[true, true]),
+        Err(Error::WrongShardCount { expected: 3, actual: 2 })
+    ));
+}
+
+#[test]
+fn test_reconstruct_random_erasures() {
+    use rand::Rng;
+    use rand::seq::index::sample;
+
+    let mut rng = rand::rng();
+    for _ in 0..100 {
+        let code = ReedSolomon::new(rng.random_range(1..=20), rng.random_range(0..=20)).unwrap();
+        let shard_size = rng.random_range(0..=64);
+        let mut encoded: Vec<Vec<u8>> = (0..code.total_shards())
+            .map(|_| (0..shard_size).map(|_| rng.random()).collect())
+            .collect();
+        code.encode(&mut encoded).unwrap();
+
+        let erasures = rng.random_range(0..=code.parity_shards());
+        let lost = sample(&mut rng, code.total_shards(), erasures).into_vec();
+        let present: Vec<bool> = (0..code.total_shards()).map(|index| !lost.contains(&index)).collect();
+        let mut shards = encoded.clone();
+        for &index in &lost {
+            shards[index].fill(0);
+        }
+        code.reconstruct(&mut shards, &present).unwrap();
+        assert_eq!(shards, encoded);
+    }
+}
+
+#[test]
+fn test_stream_encoder_matches_encode() {
+    use rand::Rng;
+
+    let mut rng = rand::rng();
+    for _ in 0..50 {
+        let code = ReedSolomon::new(rng.random_range(1..=8), rng.random_range(0..=4)).unwrap();
+        let shard_size = rng.random_range(1..=64);
+        // Short of filling the data shards, at times
+        let data: Vec<u8> = (0..rng.random_range(0..=shard_size * code.data_shards()))
+            .map(|_| rng.random())
+            .collect();
+
+        let mut encoder = code.stream_encoder(shard_size);
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let (chunk, remaining) = rest.split_at(rng.random_range(1..=rest.len()));
+            encoder.update(chunk).unwrap();
+            rest = remaining;
+        }
+
+        let mut shards = vec![vec![0u8; shard_size]; code.total_shards()];
+        for (shard, chunk) in shards.iter_mut().zip(data.chunks(shard_size)) {
+            shard[..chunk.len()].copy_from_slice(chunk);
+        }
+        code.encode(&mut shards).unwrap();
+        assert_eq!(encoder.finish(), shards[code.data_shards()..]);
+    }
+}
+
+#[test]
+fn test_stream_encoder_capacity() {
+    let code = ReedSolomon::new(2, 1).unwrap();
+    let mut encoder = code.stream_encoder(4);
+    encoder.update(&[1; 6]).unwrap();
+    assert!(matches!(
+        encoder.update(&[1; 3]),
+        Err(Error::TooMuchData { capacity: 8 })
+    ));
+    encoder.update(&[1; 2]).unwrap();
+    assert!(encoder.update(&[1]).is_err());
+    let mut shards = [vec![1; 4], vec![1; 4], vec![0; 4]];
+    code.encode(&mut shards).unwrap();
+    assert_eq!(encoder.finish(), [shards[2].clone()]);
+}
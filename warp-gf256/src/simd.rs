@@ -4,9 +4,12 @@ pub fn scalar_product<const SIZE: usize, const PRIMITIVE_POLYNOMIAL: u16>(
     scalar: GF256<PRIMITIVE_POLYNOMIAL>,
     vector: &[GF256<PRIMITIVE_POLYNOMIAL>; SIZE],
 ) -> [GF256<PRIMITIVE_POLYNOMIAL>; SIZE] {
-    // TODO: Benchmarks show this to be slower than the fallback! Make SIMD faster?
-    // #[cfg(target_feature = "neon")]
-    // return scalar_product_neon(scalar, vector);
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    {
+        let mut product = [GF256(0); SIZE];
+        mul_add_assign(scalar, as_bytes(vector), as_bytes_mut(&mut product));
+        return product;
+    }
     #[allow(unreachable_code)]
     scalar_product_fallback(scalar, vector)
 }
@@ -15,148 +18,475 @@ pub fn scalar_product_fallback<const SIZE: usize, const PRIMITIVE_POLYNOMIAL: u1
     scalar: GF256<PRIMITIVE_POLYNOMIAL>,
     vector: &[GF256<PRIMITIVE_POLYNOMIAL>; SIZE],
 ) -> [GF256<PRIMITIVE_POLYNOMIAL>; SIZE] {
-    let mul_lookup_table = GF256::<PRIMITIVE_POLYNOMIAL>::MUL_TABLE[scalar.0 as usize];
-    vector.map(|x| GF256(mul_lookup_table[x.0 as usize]))
+    #[cfg(not(feature = "small-tables"))]
+    {
+        let mul_lookup_table = GF256::<PRIMITIVE_POLYNOMIAL>::MUL_TABLE[scalar.0 as usize];
+        vector.map(|x| GF256(mul_lookup_table[x.0 as usize]))
+    }
+    #[cfg(feature = "small-tables")]
+    vector.map(|x| scalar * x)
+}
+
+// GF256 is a transparent wrapper around u8, so a slice of one is a slice of the other
+fn as_bytes<const PRIMITIVE_POLYNOMIAL: u16>(vector: &[GF256<PRIMITIVE_POLYNOMIAL>]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(vector.as_ptr().cast::<u8>(), vector.len()) }
 }
 
-#[cfg(target_feature = "neon")]
-pub fn scalar_product_neon<const SIZE: usize, const PRIMITIVE_POLYNOMIAL: u16>(
+fn as_bytes_mut<const PRIMITIVE_POLYNOMIAL: u16>(vector: &mut [GF256<PRIMITIVE_POLYNOMIAL>]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(vector.as_mut_ptr().cast::<u8>(), vector.len()) }
+}
+
+/// `output += scalar * vector`, element by element, over byte slices of any length
+pub fn mul_add_assign<const PRIMITIVE_POLYNOMIAL: u16>(
     scalar: GF256<PRIMITIVE_POLYNOMIAL>,
-    vector: &[GF256<PRIMITIVE_POLYNOMIAL>; SIZE],
-) -> [GF256<PRIMITIVE_POLYNOMIAL>; SIZE] {
-    let mul_table_row = &GF256::<PRIMITIVE_POLYNOMIAL>::MUL_TABLE[scalar.0 as usize];
-    let mut result = [GF256(0); SIZE];
+    vector: &[u8],
+    output: &mut [u8],
+) {
+    let length = vector.len().min(output.len());
+    #[allow(unused_mut)]
+    let mut done = 0;
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx2") {
+        done = unsafe { mul_add_assign_avx2(scalar, &vector[..length], &mut output[..length]) };
+    } else if is_x86_feature_detected!("ssse3") {
+        done = unsafe { mul_add_assign_ssse3(scalar, &vector[..length], &mut output[..length]) };
+    }
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        done = unsafe { mul_add_assign_neon(scalar, &vector[..length], &mut output[..length]) };
+    }
+    mul_add_assign_fallback(scalar, &vector[done..length], &mut output[done..length]);
+}
+
+/// `output = scalar * vector`, element by element, over as many bytes as both slices have
+pub fn mul_slice<const PRIMITIVE_POLYNOMIAL: u16>(
+    scalar: GF256<PRIMITIVE_POLYNOMIAL>,
+    vector: &[u8],
+    output: &mut [u8],
+) {
+    let length = vector.len().min(output.len());
+    output[..length].fill(0);
+    mul_add_assign(scalar, &vector[..length], &mut output[..length]);
+}
 
+/// `output += vector`, element by element, over as many bytes as both slices have; addition in GF(256) is XOR
+pub fn xor_slice(vector: &[u8], output: &mut [u8]) {
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx2") {
+        return unsafe { xor_slice_avx2(vector, output) };
+    }
+    // SSE2 and NEON are part of the baseline, so this loop is already vectorized with them
+    xor_slice_fallback(vector, output)
+}
+
+pub fn xor_slice_fallback(vector: &[u8], output: &mut [u8]) {
+    for (output, byte) in output.iter_mut().zip(vector) {
+        *output ^= byte;
+    }
+}
+
+/// [`xor_slice`], with the loop vectorized into AVX2's 32 byte registers
+///
+/// # Safety
+///
+/// The CPU must have AVX2.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+pub unsafe fn xor_slice_avx2(vector: &[u8], output: &mut [u8]) {
+    for (output, byte) in output.iter_mut().zip(vector) {
+        *output ^= byte;
+    }
+}
+
+pub fn mul_add_assign_fallback<const PRIMITIVE_POLYNOMIAL: u16>(
+    scalar: GF256<PRIMITIVE_POLYNOMIAL>,
+    vector: &[u8],
+    output: &mut [u8],
+) {
+    #[cfg(not(feature = "small-tables"))]
+    {
+        let mul_lookup_table = &GF256::<PRIMITIVE_POLYNOMIAL>::MUL_TABLE[scalar.0 as usize];
+        for (output, byte) in output.iter_mut().zip(vector) {
+            *output ^= mul_lookup_table[*byte as usize];
+        }
+    }
+    // 32 bytes of tables per scalar, rather than a row of the product table
+    #[cfg(feature = "small-tables")]
+    {
+        let (low, high) = nibble_tables(scalar);
+        for (output, byte) in output.iter_mut().zip(vector) {
+            *output ^= low[(byte & 0x0F) as usize] ^ high[(byte >> 4) as usize];
+        }
+    }
+}
+
+// Products of `scalar` with every value of a low nibble, and of a high nibble. Multiplication distributes over the
+// addition (XOR) of a byte's two nibbles, so looking both up and adding the results multiplies the byte.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64", feature = "small-tables"))]
+fn nibble_tables<const PRIMITIVE_POLYNOMIAL: u16>(scalar: GF256<PRIMITIVE_POLYNOMIAL>) -> ([u8; 16], [u8; 16]) {
+    (
+        std::array::from_fn(|nibble| (scalar * GF256(nibble as u8)).0),
+        std::array::from_fn(|nibble| (scalar * GF256((nibble << 4) as u8)).0),
+    )
+}
+
+/// [`mul_add_assign`] of the leading 16 byte chunks, returning how many bytes that covered
+///
+/// # Safety
+///
+/// The CPU must have SSSE3.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "ssse3")]
+pub unsafe fn mul_add_assign_ssse3<const PRIMITIVE_POLYNOMIAL: u16>(
+    scalar: GF256<PRIMITIVE_POLYNOMIAL>,
+    vector: &[u8],
+    output: &mut [u8],
+) -> usize {
+    use std::arch::x86_64::*;
+
+    let (low, high) = nibble_tables(scalar);
+    let length = vector.len().min(output.len());
     let mut i = 0;
     unsafe {
-        let mul_table_row_ptr = mul_table_row.as_ptr();
-
-        while i + 16 <= SIZE {
-            use std::arch::aarch64::*;
-
-            // Load input vector (16 bytes)
-            let input = vld1q_u8(vector.as_ptr().add(i).cast::<u8>());
-
-            // Split into low/high nibbles (4-bit halves)
-            let lo_nibble = vandq_u8(input, vdupq_n_u8(0x0F)); // Lower 4 bits (0-15)
-            let hi_nibble = vshrq_n_u8(input, 4); // Upper 4 bits (0-15)
-
-            // Load 16x 16-byte chunks of the multiplication table
-            let tables = [
-                vld1q_u8(mul_table_row_ptr.add(0)),
-                vld1q_u8(mul_table_row_ptr.add(16)),
-                vld1q_u8(mul_table_row_ptr.add(32)),
-                vld1q_u8(mul_table_row_ptr.add(48)),
-                vld1q_u8(mul_table_row_ptr.add(64)),
-                vld1q_u8(mul_table_row_ptr.add(80)),
-                vld1q_u8(mul_table_row_ptr.add(96)),
-                vld1q_u8(mul_table_row_ptr.add(112)),
-                vld1q_u8(mul_table_row_ptr.add(128)),
-                vld1q_u8(mul_table_row_ptr.add(144)),
-                vld1q_u8(mul_table_row_ptr.add(160)),
-                vld1q_u8(mul_table_row_ptr.add(176)),
-                vld1q_u8(mul_table_row_ptr.add(192)),
-                vld1q_u8(mul_table_row_ptr.add(208)),
-                vld1q_u8(mul_table_row_ptr.add(224)),
-                vld1q_u8(mul_table_row_ptr.add(240)),
-            ];
-
-            // Lookup results for each nibble in each table segment
-            let mut res = vdupq_n_u8(0);
-            for (table_idx, &table) in tables.iter().enumerate() {
-                let mask = vceqq_u8(hi_nibble, vdupq_n_u8(table_idx as u8));
-                let lookup = vqtbl1q_u8(table, lo_nibble);
-                res = vbslq_u8(mask, lookup, res);
-            }
-
-            vst1q_u8(result.as_mut_ptr().add(i).cast::<u8>(), res);
+        let low_table = _mm_loadu_si128(low.as_ptr().cast());
+        let high_table = _mm_loadu_si128(high.as_ptr().cast());
+        let low_nibbles = _mm_set1_epi8(0x0F);
+
+        while i + 16 <= length {
+            let input = _mm_loadu_si128(vector.as_ptr().add(i).cast());
+            // PSHUFB looks each nibble up in its table
+            let low_products = _mm_shuffle_epi8(low_table, _mm_and_si128(input, low_nibbles));
+            let high_products = _mm_shuffle_epi8(high_table, _mm_and_si128(_mm_srli_epi64(input, 4), low_nibbles));
+            let destination = output.as_mut_ptr().add(i).cast();
+            let accumulated = _mm_xor_si128(_mm_loadu_si128(destination), _mm_xor_si128(low_products, high_products));
+            _mm_storeu_si128(destination, accumulated);
             i += 16;
         }
     }
+    i
+}
+
+/// [`mul_add_assign`] of the leading 32 byte chunks, returning how many bytes that covered
+///
+/// # Safety
+///
+/// The CPU must have AVX2.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+pub unsafe fn mul_add_assign_avx2<const PRIMITIVE_POLYNOMIAL: u16>(
+    scalar: GF256<PRIMITIVE_POLYNOMIAL>,
+    vector: &[u8],
+    output: &mut [u8],
+) -> usize {
+    use std::arch::x86_64::*;
+
+    let (low, high) = nibble_tables(scalar);
+    let length = vector.len().min(output.len());
+    let mut i = 0;
+    unsafe {
+        // VPSHUFB looks up within each 128 bit lane, so both lanes get a copy of the tables
+        let low_table = _mm256_broadcastsi128_si256(_mm_loadu_si128(low.as_ptr().cast()));
+        let high_table = _mm256_broadcastsi128_si256(_mm_loadu_si128(high.as_ptr().cast()));
+        let low_nibbles = _mm256_set1_epi8(0x0F);
+
+        while i + 32 <= length {
+            let input = _mm256_loadu_si256(vector.as_ptr().add(i).cast());
+            let low_products = _mm256_shuffle_epi8(low_table, _mm256_and_si256(input, low_nibbles));
+            let high_products =
+                _mm256_shuffle_epi8(high_table, _mm256_and_si256(_mm256_srli_epi64(input, 4), low_nibbles));
+            let destination = output.as_mut_ptr().add(i).cast();
+            let accumulated = _mm256_xor_si256(
+                _mm256_loadu_si256(destination),
+                _mm256_xor_si256(low_products, high_products),
+            );
+            _mm256_storeu_si256(destination, accumulated);
+            i += 32;
+        }
+    }
+    i
+}
 
-    // Handle remaining elements
-    for j in i..SIZE {
-        result[j] = GF256(mul_table_row[vector[j].0 as usize]);
+/// [`mul_add_assign`] of the leading 16 byte chunks, returning how many bytes that covered
+///
+/// # Safety
+///
+/// The CPU must have NEON.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+pub unsafe fn mul_add_assign_neon<const PRIMITIVE_POLYNOMIAL: u16>(
+    scalar: GF256<PRIMITIVE_POLYNOMIAL>,
+    vector: &[u8],
+    output: &mut [u8],
+) -> usize {
+    use std::arch::aarch64::*;
+
+    let (low, high) = nibble_tables(scalar);
+    let length = vector.len().min(output.len());
+    let mut i = 0;
+    unsafe {
+        let low_table = vld1q_u8(low.as_ptr());
+        let high_table = vld1q_u8(high.as_ptr());
+        let low_nibbles = vdupq_n_u8(0x0F);
+
+        while i + 16 <= length {
+            let input = vld1q_u8(vector.as_ptr().add(i));
+            // TBL looks each nibble up in its table
+            let low_products = vqtbl1q_u8(low_table, vandq_u8(input, low_nibbles));
+            let high_products = vqtbl1q_u8(high_table, vshrq_n_u8(input, 4));
+            let destination = output.as_mut_ptr().add(i);
+            let accumulated = veorq_u8(vld1q_u8(destination), veorq_u8(low_products, high_products));
+            vst1q_u8(destination, accumulated);
+            i += 16;
+        }
     }
+    i
+}
 
-    result
+/// [`scalar_product`] using NEON alone for whole 16 byte chunks
+///
+/// # Safety
+///
+/// The CPU must have NEON.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+pub unsafe fn scalar_product_neon<const SIZE: usize, const PRIMITIVE_POLYNOMIAL: u16>(
+    scalar: GF256<PRIMITIVE_POLYNOMIAL>,
+    vector: &[GF256<PRIMITIVE_POLYNOMIAL>; SIZE],
+) -> [GF256<PRIMITIVE_POLYNOMIAL>; SIZE] {
+    let mut product = [GF256(0); SIZE];
+    let done = unsafe { mul_add_assign_neon(scalar, as_bytes(vector), as_bytes_mut(&mut product)) };
+    mul_add_assign_fallback(
+        scalar,
+        &as_bytes(vector)[done..],
+        &mut as_bytes_mut(&mut product)[done..],
+    );
+    product
 }
 
-#[cfg(target_feature = "neon")]
+#[test]
+fn test_mul_add_assign() {
+    let input: Vec<u8> = (0..=255).cycle().take(1000).collect();
+    for scalar in [0, 1, 2, 77, 255] {
+        let scalar: GF256 = GF256(scalar);
+        // Lengths either side of whole SIMD chunks leave something for the fallback to finish
+        for length in [0, 15, 16, 17, 31, 32, 33, 100, 1000] {
+            let mut output = vec![3u8; length];
+            mul_add_assign(scalar, &input[..length], &mut output);
+            let mut expected = vec![3u8; length];
+            mul_add_assign_fallback(scalar, &input[..length], &mut expected);
+            assert_eq!(output, expected);
+        }
+    }
+
+    let input: [u8; 300] = std::array::from_fn(|i| i as u8);
+    let mut output = [3u8; 300];
+    let scalar: GF256 = GF256(77);
+    mul_add_assign_fallback(scalar, &input, &mut output);
+    let expected = scalar_product_fallback(scalar, &input.map(GF256)).map(|product| (product + GF256(3)).0);
+    assert_eq!(output, expected);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_mul_add_assign_x86() {
+    let input: Vec<u8> = (0..=255).cycle().take(300).collect();
+    for scalar in 0..=255 {
+        let scalar: GF256 = GF256(scalar);
+        let mut expected = vec![3u8; 300];
+        mul_add_assign_fallback(scalar, &input, &mut expected);
+
+        if is_x86_feature_detected!("ssse3") {
+            let mut output = vec![3u8; 300];
+            let done = unsafe { mul_add_assign_ssse3(scalar, &input, &mut output) };
+            assert_eq!(done, 288);
+            assert_eq!(output[..done], expected[..done]);
+        }
+        if is_x86_feature_detected!("avx2") {
+            let mut output = vec![3u8; 300];
+            let done = unsafe { mul_add_assign_avx2(scalar, &input, &mut output) };
+            assert_eq!(done, 288);
+            assert_eq!(output[..done], expected[..done]);
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
 #[test]
 fn test_scalar_product_neon() {
-    let scalar = GF256(77);
+    if !std::arch::is_aarch64_feature_detected!("neon") {
+        return;
+    }
     let input: [u8; 300] = std::array::from_fn(|i| i as u8);
     let input: [GF256; 300] = input.map(GF256);
-    assert_eq!(
-        scalar_product_neon(scalar, &input),
-        scalar_product_fallback(scalar, &input)
-    )
+    for scalar in 0..=255 {
+        let scalar: GF256 = GF256(scalar);
+        assert_eq!(
+            unsafe { scalar_product_neon(scalar, &input) },
+            scalar_product_fallback(scalar, &input)
+        );
+    }
+}
+
+#[test]
+fn test_slices() {
+    let scalar: GF256 = GF256(77);
+    let input: Vec<u8> = (0..=255).cycle().take(1400).collect();
+    for length in [0, 17, 100, 1400] {
+        let mut output = vec![3u8; length];
+        mul_slice(
+            GF256::<{ super::DEFAULT_POLYNOMIAL }>(77),
+            &input[..length],
+            &mut output,
+        );
+        let expected: Vec<u8> = input[..length].iter().map(|byte| (scalar * GF256(*byte)).0).collect();
+        assert_eq!(output, expected);
+
+        let mut output = vec![3u8; length];
+        xor_slice(&input[..length], &mut output);
+        let expected: Vec<u8> = input[..length].iter().map(|byte| byte ^ 3).collect();
+        assert_eq!(output, expected);
+
+        let expected = input[..length].iter().fold(0, |sum, byte| sum ^ byte);
+        assert_eq!(sum_slice::<{ super::DEFAULT_POLYNOMIAL }>(&input[..length]).0, expected);
+    }
+
+    // Only as many bytes as both slices have are written
+    let mut output = vec![3u8; 10];
+    mul_slice(<GF256 as super::Multiplicative>::identity(), &input[..4], &mut output);
+    xor_slice(&input[..2], &mut output);
+    assert_eq!(output, [0, 0, 2, 3, 3, 3, 3, 3, 3, 3]);
+}
+
+#[test]
+fn test_scalar_product() {
+    let input: [u8; 300] = std::array::from_fn(|i| i as u8);
+    let input: [GF256; 300] = input.map(GF256);
+    for scalar in [0, 1, 77, 255] {
+        assert_eq!(
+            scalar_product(GF256(scalar), &input),
+            scalar_product_fallback(GF256(scalar), &input)
+        );
+    }
 }
 
 pub fn sum<const SIZE: usize, const PRIMITIVE_POLYNOMIAL: u16>(
     vector: &[GF256<PRIMITIVE_POLYNOMIAL>; SIZE],
 ) -> GF256<PRIMITIVE_POLYNOMIAL> {
-    #[cfg(target_feature = "neon")]
-    return sum_neon(vector);
+    sum_slice(as_bytes(vector))
+}
+
+/// The sum of `vector`'s bytes, as elements of GF(256)
+pub fn sum_slice<const PRIMITIVE_POLYNOMIAL: u16>(vector: &[u8]) -> GF256<PRIMITIVE_POLYNOMIAL> {
+    #[cfg(target_arch = "aarch64")]
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        return GF256(unsafe { sum_neon(vector) });
+    }
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx2") {
+        return GF256(unsafe { sum_avx2(vector) });
+    }
+    // Every x86_64 CPU has SSE2
+    #[cfg(target_arch = "x86_64")]
+    return GF256(unsafe { sum_sse2(vector) });
     #[allow(unreachable_code)]
-    sum_fallback(vector)
+    GF256(vector.iter().fold(0, |sum, byte| sum ^ byte))
 }
 
-#[cfg(target_feature = "neon")]
-pub fn sum_neon<const SIZE: usize, const PRIMITIVE_POLYNOMIAL: u16>(
-    vector: &[GF256<PRIMITIVE_POLYNOMIAL>; SIZE],
-) -> GF256<PRIMITIVE_POLYNOMIAL> {
-    GF256::<PRIMITIVE_POLYNOMIAL>(unsafe {
-        // Stolen from: https://users.rust-lang.org/t/ensure-that-struct-t-has-size-n-at-compile-time/61108/4
-        // Compile time check that GF256 is the same size as u8 so that ptr cast below is valid
-        const _: () = [(); 1][(core::mem::size_of::<GF256<0>>() == core::mem::size_of::<u8>()) as usize ^ 1];
-
-        // Initialize result vector with zeros
-        let mut result = std::arch::aarch64::vdupq_n_u8(0);
-
-        let mut i = 0;
-        // Process 16 bytes at a time
-        while i + 16 <= SIZE {
-            // Load 16 bytes from the array
-            let chunk = std::arch::aarch64::vld1q_u8(vector[i..].as_ptr() as *mut u8);
-            // XOR with the result
-            result = std::arch::aarch64::veorq_u8(result, chunk);
+/// The sum (XOR) of `vector`'s bytes
+///
+/// # Safety
+///
+/// The CPU must have SSE2, which every x86_64 CPU does.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+pub unsafe fn sum_sse2(vector: &[u8]) -> u8 {
+    use std::arch::x86_64::*;
+
+    let mut i = 0;
+    let mut lanes = [0u8; 16];
+    unsafe {
+        let mut result = _mm_setzero_si128();
+        while i + 16 <= vector.len() {
+            result = _mm_xor_si128(result, _mm_loadu_si128(vector.as_ptr().add(i).cast()));
             i += 16;
         }
+        _mm_storeu_si128(lanes.as_mut_ptr().cast(), result);
+    }
+    lanes.iter().chain(&vector[i..]).fold(0, |sum, byte| sum ^ byte)
+}
 
-        // Horizontal XOR of the 16 bytes in the result vector
-        let temp = std::arch::aarch64::veor_u8(
-            std::arch::aarch64::vget_low_u8(result),
-            std::arch::aarch64::vget_high_u8(result),
-        );
+/// The sum (XOR) of `vector`'s bytes
+///
+/// # Safety
+///
+/// The CPU must have AVX2.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+pub unsafe fn sum_avx2(vector: &[u8]) -> u8 {
+    use std::arch::x86_64::*;
+
+    let mut i = 0;
+    let mut lanes = [0u8; 32];
+    unsafe {
+        let mut result = _mm256_setzero_si256();
+        while i + 32 <= vector.len() {
+            result = _mm256_xor_si256(result, _mm256_loadu_si256(vector.as_ptr().add(i).cast()));
+            i += 32;
+        }
+        _mm256_storeu_si256(lanes.as_mut_ptr().cast(), result);
+    }
+    lanes.iter().chain(&vector[i..]).fold(0, |sum, byte| sum ^ byte)
+}
 
-        let temp2 = std::arch::aarch64::vreinterpret_u32_u8(temp);
-        let temp3 = std::arch::aarch64::vdup_lane_u32(temp2, 0);
-        let temp4 = std::arch::aarch64::vdup_lane_u32(temp2, 1);
-        let temp5 = std::arch::aarch64::veor_u32(temp3, temp4);
-        let temp6 = std::arch::aarch64::vreinterpret_u8_u32(temp5);
-
-        // Further reduce from 4 bytes to 2 bytes
-        let temp7 = std::arch::aarch64::vget_lane_u32(std::arch::aarch64::vreinterpret_u32_u8(temp6), 0);
-        let xor_value = (temp7 & 0xFF) ^ ((temp7 >> 8) & 0xFF) ^ ((temp7 >> 16) & 0xFF) ^ ((temp7 >> 24) & 0xFF);
-        let mut result_u8 = xor_value as u8;
-
-        // Process remaining bytes
-        while i < SIZE {
-            result_u8 ^= vector[i].0;
-            i += 1;
+/// The sum (XOR) of `vector`'s bytes
+///
+/// # Safety
+///
+/// The CPU must have NEON.
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+pub unsafe fn sum_neon(vector: &[u8]) -> u8 {
+    use std::arch::aarch64::*;
+
+    let mut i = 0;
+    let mut lanes = [0u8; 16];
+    unsafe {
+        let mut result = vdupq_n_u8(0);
+        while i + 16 <= vector.len() {
+            result = veorq_u8(result, vld1q_u8(vector.as_ptr().add(i)));
+            i += 16;
         }
-        result_u8
-    })
+        vst1q_u8(lanes.as_mut_ptr(), result);
+    }
+    lanes.iter().chain(&vector[i..]).fold(0, |sum, byte| sum ^ byte)
 }
 
-#[cfg(target_feature = "neon")]
+#[cfg(target_arch = "aarch64")]
 #[test]
 fn test_sum_neon() {
+    if !std::arch::is_aarch64_feature_detected!("neon") {
+        return;
+    }
     let input: [u8; 200] = std::array::from_fn(|i| i as u8);
-    let input: [GF256; 200] = input.map(GF256);
-    assert_eq!(sum_neon(&input), sum_fallback(&input))
+    for length in [0, 15, 16, 17, 33, 200] {
+        let expected = input[..length].iter().fold(0, |sum, byte| sum ^ byte);
+        assert_eq!(unsafe { sum_neon(&input[..length]) }, expected);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_sum_x86() {
+    let input: [u8; 200] = std::array::from_fn(|i| i as u8);
+    let total = sum_fallback(&input.map(GF256::<{ super::DEFAULT_POLYNOMIAL }>)).0;
+    for length in [0, 15, 16, 17, 33, 200] {
+        let expected = input[..length].iter().fold(0, |sum, byte| sum ^ byte);
+        assert_eq!(unsafe { sum_sse2(&input[..length]) }, expected);
+        if is_x86_feature_detected!("avx2") {
+            assert_eq!(unsafe { sum_avx2(&input[..length]) }, expected);
+        }
+    }
+    assert_eq!(sum(&input.map(GF256::<{ super::DEFAULT_POLYNOMIAL }>)).0, total);
 }
 
 pub fn sum_fallback<const SIZE: usize, const PRIMITIVE_POLYNOMIAL: u16>(
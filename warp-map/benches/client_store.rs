@@ -0,0 +1,96 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::net::SocketAddr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use warp_map::map::{ClientStore, ShardedClientStore};
+
+const CLIENTS: u16 = 1000;
+const REQUESTS_PER_THREAD: u64 = 10_000;
+
+fn pubkey(i: u16) -> warp_protocol::PublicKey {
+    let mut bytes = [1u8; 32];
+    bytes[..2].copy_from_slice(&i.to_be_bytes());
+    warp_protocol::PrivateKey::from_bytes(&bytes.into())
+        .unwrap()
+        .public_key()
+}
+
+fn address(i: u16) -> SocketAddr {
+    SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 13116))
+}
+
+// What a warp-map does per request: one in four registers, the rest ask for a peer's mapping
+fn requests(threads: u64, register: impl Fn(u16) + Sync, map: impl Fn(u16) + Sync) {
+    std::thread::scope(|scope| {
+        for thread in 0..threads {
+            let (register, map) = (&register, &map);
+            scope.spawn(move || {
+                for request in 0..REQUESTS_PER_THREAD {
+                    let client = ((thread * REQUESTS_PER_THREAD + request) % u64::from(CLIENTS)) as u16;
+                    if request % 4 == 0 {
+                        register(client);
+                    } else {
+                        map(client);
+                    }
+                }
+            });
+        }
+    });
+}
+
+pub fn concurrent_requests(c: &mut Criterion) {
+    let pubkeys: Vec<_> = (0..CLIENTS).map(pubkey).collect();
+    let expiry = Duration::from_secs(60);
+
+    let mut group = c.benchmark_group("Concurrent requests");
+    for threads in [1, 4, 16] {
+        group.throughput(Throughput::Elements(threads * REQUESTS_PER_THREAD));
+
+        // The whole store behind one lock, as warp-map had it
+        let store = RwLock::new(ClientStore::new(expiry));
+        group.bench_with_input(BenchmarkId::new("Single lock", threads), &threads, |b, &threads| {
+            b.iter(|| {
+                requests(
+                    threads,
+                    |client| {
+                        let mut store = store.write().unwrap();
+                        store.register_client(pubkeys[client as usize], address(client), Instant::now());
+                        store.set_host_candidates(address(client), &[address(client)]);
+                    },
+                    |client| {
+                        let store = store.read().unwrap();
+                        let peer = &pubkeys[(client as usize + 1) % pubkeys.len()];
+                        criterion::black_box(store.get_addresses(peer, Instant::now()));
+                        criterion::black_box(store.get_host_candidates(peer, Instant::now()));
+                    },
+                )
+            })
+        });
+
+        let store = ShardedClientStore::new(expiry, 16);
+        group.bench_with_input(BenchmarkId::new("Sharded", threads), &threads, |b, &threads| {
+            b.iter(|| {
+                requests(
+                    threads,
+                    |client| {
+                        store.register_client(
+                            pubkeys[client as usize],
+                            address(client),
+                            &[address(client)],
+                            Instant::now(),
+                        );
+                    },
+                    |client| {
+                        let peer = &pubkeys[(client as usize + 1) % pubkeys.len()];
+                        criterion::black_box(store.get_addresses(peer, Instant::now()));
+                        criterion::black_box(store.get_host_candidates(peer, Instant::now()));
+                    },
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_requests);
+criterion_main!(benches);
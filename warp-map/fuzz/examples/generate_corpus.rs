@@ -0,0 +1,13 @@
+//! Write valid datagrams to corpus/process_rx_buffer so fuzzing starts from messages that get past decryption
+//!
+//! cargo run --example generate_corpus
+
+fn main() -> std::io::Result<()> {
+    let directory = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus/process_rx_buffer");
+    std::fs::create_dir_all(&directory)?;
+    for (name, datagram) in warp_map_fuzz::seed_datagrams() {
+        std::fs::write(directory.join(name), datagram)?;
+    }
+    println!("Wrote seeds to {}", directory.display());
+    Ok(())
+}
@@ -0,0 +1,42 @@
+//! Datagrams that decrypt correctly but whose contents are fuzzed, to reach the message decoding and handling that
+//! random bytes never get past authentication for
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use warp_map_fuzz::{client_address, encrypt_raw, register_associated_data, Target};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    /// Selects between a few source addresses, some of which will have registered
+    host: u8,
+    /// Sent back to back in one datagram
+    messages: Vec<FuzzMessage>,
+}
+
+#[derive(Arbitrary, Debug)]
+struct FuzzMessage {
+    message_id: u8,
+    nonce: [u8; 12],
+    /// Replaces the valid registration associated data that's used otherwise
+    associated_data: Option<Vec<u8>>,
+    secret: Vec<u8>,
+    /// Flip one bit of the encrypted message at this (wrapped) position
+    corrupt: Option<u16>,
+}
+
+fuzz_target!(|input: Input| {
+    let mut datagram = Vec::new();
+    for message in input.messages {
+        let public = message.associated_data.unwrap_or_else(register_associated_data);
+        let mut bytes = encrypt_raw(message.message_id, message.nonce, public, message.secret);
+        if let Some(position) = message.corrupt {
+            let position = position as usize % bytes.len();
+            bytes[position] ^= 1;
+        }
+        datagram.extend_from_slice(&bytes);
+    }
+
+    let _ = Target::get().handle_datagram(&datagram, &client_address(input.host % 4));
+});
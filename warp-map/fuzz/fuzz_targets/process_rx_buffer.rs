@@ -0,0 +1,11 @@
+//! Arbitrary bytes straight off the wire, as any host on the internet can send to warp-map
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use warp_map_fuzz::{client_address, Target};
+
+fuzz_target!(|data: &[u8]| {
+    // Errors are expected; only panics, hangs and crashes are bugs
+    let _ = Target::get().handle_datagram(data, &client_address(1));
+});
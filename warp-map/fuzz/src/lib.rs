@@ -0,0 +1,127 @@
+//! Shared setup for the warp-map fuzz targets
+//!
+//! Keys are fixed so inputs found by one run (and the seeds from `examples/generate_corpus.rs`) stay meaningful in the
+//! next.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::LazyLock;
+use std::time::Duration;
+use warp_protocol::codec::{Message, UnencryptedWireMessage};
+use warp_protocol::messages;
+
+pub const MAP_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 13116);
+
+pub fn map_key() -> warp_protocol::PrivateKey {
+    warp_protocol::PrivateKey::from_slice(&[0x11; 32]).expect("valid scalar")
+}
+
+pub fn client_key() -> warp_protocol::PrivateKey {
+    warp_protocol::PrivateKey::from_slice(&[0x22; 32]).expect("valid scalar")
+}
+
+/// Where fuzzed datagrams appear to come from; the last octet is up to the target so some come from registered clients
+pub fn client_address(host: u8) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, host)), 40000)
+}
+
+/// The cipher [`client_key`] shares with warp-map
+pub fn client_cipher() -> warp_protocol::Cipher {
+    warp_protocol::crypto::cipher_from_shared_secret(&client_key(), &map_key().public_key())
+}
+
+/// A warp-map server and a runtime to drive it, kept for the whole fuzzing session so the client store accumulates
+/// state like a long-running server's would
+pub struct Target {
+    runtime: tokio::runtime::Runtime,
+    server: warp_map::WarpMapServer,
+}
+
+impl Target {
+    pub fn get() -> &'static Target {
+        static TARGET: LazyLock<Target> = LazyLock::new(|| Target {
+            runtime: tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()
+                .expect("failed to build runtime"),
+            server: warp_map::WarpMapServer::new(map_key(), MAP_ADDRESS, Duration::from_secs(60)).with_relay(),
+        });
+        &TARGET
+    }
+
+    pub fn handle_datagram(&self, buf: &[u8], from: &SocketAddr) -> anyhow::Result<Vec<(SocketAddr, Vec<u8>)>> {
+        self.runtime.block_on(self.server.handle_datagram(buf, from))
+    }
+}
+
+/// The associated data of a valid [`messages::RegisterRequest`] from [`client_key`], which is what warp-map expects
+/// from clients it doesn't know yet
+pub fn register_associated_data() -> Vec<u8> {
+    register_request()
+        .encode()
+        .expect("failed to encode register request")
+        .public_bytes()
+        .to_vec()
+}
+
+/// Encrypt a message with the given raw parts as [`client_key`] would
+pub fn encrypt_raw(message_id: u8, nonce: [u8; 12], public: Vec<u8>, secret: Vec<u8>) -> Vec<u8> {
+    UnencryptedWireMessage::from_raw_parts(message_id, nonce, public, secret)
+        .encrypt(&client_cipher())
+        .and_then(|message| message.to_bytes())
+        .expect("failed to encrypt message")
+}
+
+/// Valid datagrams for each request warp-map handles, for seeding corpora
+pub fn seed_datagrams() -> Vec<(&'static str, Vec<u8>)> {
+    let cipher = client_cipher();
+    let encrypt = |message: UnencryptedWireMessage| {
+        message
+            .encrypt(&cipher)
+            .and_then(|message| message.to_bytes())
+            .expect("failed to encrypt message")
+    };
+
+    let register = encrypt(register_request().encode().expect("failed to encode"));
+    let mapping = encrypt(
+        messages::MappingRequest {
+            peer_pubkey: client_key().public_key(),
+            timestamp: std::time::SystemTime::now(),
+            subscribe: true,
+        }
+        .encode()
+        .expect("failed to encode"),
+    );
+    let deregister = encrypt(
+        messages::DeregisterRequest {
+            pubkey: client_key().public_key(),
+            timestamp: std::time::SystemTime::now(),
+        }
+        .encode()
+        .expect("failed to encode"),
+    );
+    let relay = encrypt(
+        messages::RelayPayload {
+            peer_pubkey: client_key().public_key(),
+            data: vec![0; 64],
+        }
+        .encode()
+        .expect("failed to encode"),
+    );
+    let all = [register.clone(), mapping.clone(), deregister.clone()].concat();
+
+    vec![
+        ("register", register),
+        ("mapping", mapping),
+        ("deregister", deregister),
+        ("relay", relay),
+        ("register_mapping_deregister", all),
+    ]
+}
+
+fn register_request() -> messages::RegisterRequest {
+    messages::RegisterRequest {
+        pubkey: client_key().public_key(),
+        timestamp: std::time::SystemTime::now(),
+        host_candidates: vec!["192.168.1.2:4000".parse().unwrap()],
+    }
+}
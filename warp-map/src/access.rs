@@ -0,0 +1,107 @@
+//! Which clients may register, for warp-maps that only serve their operator's own peers
+//!
+//! Keys are read from files with one public key per line, in the form warp prints them, ignoring blank lines and
+//! anything after a `#`. The files are reread every few seconds, so keys can be added and removed without a restart.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// The public keys allowed and denied registration
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Access {
+    // Every key not denied is allowed if there's no allowlist
+    allowed: Option<BTreeSet<warp_protocol::PublicKey>>,
+    denied: BTreeSet<warp_protocol::PublicKey>,
+}
+
+impl Access {
+    /// Nobody may register; what a warp-map with an allowlist falls back to until it can read it
+    pub fn deny_all() -> Self {
+        Self {
+            allowed: Some(BTreeSet::new()),
+            denied: BTreeSet::new(),
+        }
+    }
+
+    pub fn permits(&self, pubkey: &warp_protocol::PublicKey) -> bool {
+        !self.denied.contains(pubkey) && self.allowed.as_ref().is_none_or(|allowed| allowed.contains(pubkey))
+    }
+}
+
+/// Where [`Access`] is read from
+#[derive(Debug, Clone, Default)]
+pub struct AccessFiles {
+    pub allowed: Option<PathBuf>,
+    pub denied: Option<PathBuf>,
+}
+
+impl AccessFiles {
+    pub fn is_empty(&self) -> bool {
+        self.allowed.is_none() && self.denied.is_none()
+    }
+
+    pub async fn load(&self) -> anyhow::Result<Access> {
+        let allowed = match &self.allowed {
+            Some(path) => Some(load_keys(path).await?),
+            None => None,
+        };
+        let denied = match &self.denied {
+            Some(path) => load_keys(path).await?,
+            None => BTreeSet::new(),
+        };
+        Ok(Access { allowed, denied })
+    }
+}
+
+async fn load_keys(path: &Path) -> anyhow::Result<BTreeSet<warp_protocol::PublicKey>> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to read {}: {}", path.display(), e))?;
+    parse_keys(&contents).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+}
+
+fn parse_keys(contents: &str) -> anyhow::Result<BTreeSet<warp_protocol::PublicKey>> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(number, line)| (number, line.split('#').next().unwrap_or_default().trim()))
+        .filter(|(_, key)| !key.is_empty())
+        .map(|(number, key)| {
+            warp_protocol::crypto::pubkey_from_string(key).map_err(|e| anyhow::anyhow!("line {}: {}", number + 1, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public_key(seed: u8) -> warp_protocol::PublicKey {
+        let mut bytes = [1u8; 32];
+        bytes[0] = seed;
+        warp_protocol::PrivateKey::from_bytes(&bytes.into())
+            .unwrap()
+            .public_key()
+    }
+
+    #[test]
+    fn test_denied_keys_override_allowed_ones() {
+        let (a, b, c) = (public_key(1), public_key(2), public_key(3));
+        let allowed = format!(
+            "# Our peers\n{}\n\n{} # retired\n",
+            warp_protocol::crypto::pubkey_to_string(&a),
+            warp_protocol::crypto::pubkey_to_string(&b)
+        );
+        let access = Access {
+            allowed: Some(parse_keys(&allowed).unwrap()),
+            denied: parse_keys(&warp_protocol::crypto::pubkey_to_string(&b)).unwrap(),
+        };
+        assert!(access.permits(&a));
+        assert!(!access.permits(&b));
+        assert!(!access.permits(&c));
+
+        assert!(Access::default().permits(&c));
+        assert!(!Access::deny_all().permits(&a));
+        assert!(parse_keys("not a key").is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! An append-only log of who used warp-map and when, one JSON object per line, for operators who must be able to account
+//! for it
+//!
+//! Events are queued as they happen and written by one task, so that handling a request never waits on the disk; if the
+//! writer falls AUDIT_QUEUE events behind, the rest are dropped and counted in `warp_map_audit_events_dropped_total`.
+
+use crate::metrics;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+use tracing::error;
+
+// Events waiting to be written; well beyond what a burst of registrations queues up while the log is rotated
+const AUDIT_QUEUE: usize = 65536;
+
+// Events written at once, then flushed together
+const AUDIT_BATCH: usize = 256;
+
+/// What a client did, and what warp-map did about it
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A registration, `new` if the address wasn't registered to the public key already
+    Register {
+        pubkey: String,
+        address: SocketAddr,
+        new: bool,
+        request_timestamp: f64,
+    },
+    /// A registration turned down, as the public key isn't allowed
+    Unauthorized {
+        pubkey: String,
+        address: SocketAddr,
+        request_timestamp: f64,
+    },
+    /// An address dropped to keep the public key within the maximum number of addresses
+    Evict { pubkey: String, address: SocketAddr },
+    Deregister {
+        pubkey: String,
+        address: SocketAddr,
+        removed: bool,
+        request_timestamp: f64,
+    },
+    /// A query for `peer_pubkey`'s addresses, `addresses` of which were returned
+    Mapping {
+        pubkey: String,
+        address: SocketAddr,
+        peer_pubkey: String,
+        addresses: usize,
+        subscribe: bool,
+        request_timestamp: f64,
+    },
+}
+
+/// Seconds since the Unix epoch, as the log has its timestamps
+pub fn unix_time(time: std::time::SystemTime) -> f64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |since_epoch| since_epoch.as_secs_f64())
+}
+
+#[derive(serde::Serialize)]
+struct Record<'a> {
+    timestamp: f64,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// Where the log is written, and when it is rotated
+#[derive(Clone, Debug)]
+pub struct Rotation {
+    /// The log is moved to `<path>.1` once it is this large, and a new one started; never if `None`
+    pub max_bytes: Option<u64>,
+    /// Rotated logs kept, `<path>.1` being the newest; older ones are deleted
+    pub max_files: usize,
+}
+
+/// Queues events for the [`AuditWriter`] made with it
+#[derive(Clone)]
+pub struct AuditLog {
+    sender: tokio::sync::mpsc::Sender<String>,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf, rotation: Rotation) -> (Self, AuditWriter) {
+        let (sender, receiver) = tokio::sync::mpsc::channel(AUDIT_QUEUE);
+        (
+            Self { sender },
+            AuditWriter {
+                path,
+                rotation,
+                receiver,
+            },
+        )
+    }
+
+    /// Queue `event` as having happened now
+    pub fn record(&self, event: AuditEvent) {
+        let record = Record {
+            timestamp: unix_time(std::time::SystemTime::now()),
+            event: &event,
+        };
+        let mut line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to encode audit event {:?}: {}", event, e);
+                return;
+            }
+        };
+        line.push('\n');
+        if self.sender.try_send(line).is_err() {
+            metrics::AUDIT_EVENTS_DROPPED.inc();
+        }
+    }
+}
+
+/// Appends what its [`AuditLog`] queues to the file
+pub struct AuditWriter {
+    path: PathBuf,
+    rotation: Rotation,
+    receiver: tokio::sync::mpsc::Receiver<String>,
+}
+
+impl AuditWriter {
+    /// Write events until every [`AuditLog`] is gone; events that can't be written are logged and dropped
+    pub async fn run(mut self) {
+        let mut file = None;
+        let mut batch = Vec::with_capacity(AUDIT_BATCH);
+        while self.receiver.recv_many(&mut batch, AUDIT_BATCH).await > 0 {
+            if let Err(e) = self.write(&mut file, &batch).await {
+                metrics::AUDIT_EVENTS_DROPPED.add(batch.len() as u64);
+                error!("Failed to write to the audit log {}: {}", self.path.display(), e);
+                // Opened again for the next batch, in case it was moved or deleted
+                file = None;
+            }
+            batch.clear();
+        }
+    }
+
+    async fn write(&self, file: &mut Option<(tokio::fs::File, u64)>, lines: &[String]) -> std::io::Result<()> {
+        let (open, written) = match file {
+            Some(open) => open,
+            None => {
+                let open = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .await?;
+                let written = open.metadata().await?.len();
+                file.insert((open, written))
+            }
+        };
+        let bytes = lines.concat();
+        open.write_all(bytes.as_bytes()).await?;
+        open.flush().await?;
+        *written += bytes.len() as u64;
+
+        if self.rotation.max_bytes.is_some_and(|max_bytes| *written >= max_bytes) {
+            *file = None;
+            self.rotate().await?;
+        }
+        Ok(())
+    }
+
+    // Move each rotated log one along, dropping the oldest, and the log itself to `<path>.1`
+    async fn rotate(&self) -> std::io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.as_os_str().to_owned();
+            path.push(format!(".{n}"));
+            PathBuf::from(path)
+        };
+        if self.rotation.max_files == 0 {
+            return tokio::fs::remove_file(&self.path).await;
+        }
+        for n in (1..self.rotation.max_files).rev() {
+            match tokio::fs::rename(rotated(n), rotated(n + 1)).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        tokio::fs::rename(&self.path, rotated(1)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_audit_log_rotates() {
+        let path = std::env::temp_dir().join(format!("warp-map-audit-test-{}", std::process::id()));
+        let rotated = |n: usize| PathBuf::from(format!("{}.{n}", path.display()));
+        let (log, mut writer) = AuditLog::new(
+            path.clone(),
+            Rotation {
+                max_bytes: Some(1),
+                max_files: 2,
+            },
+        );
+        let address: SocketAddr = "198.51.100.1:4000".parse().unwrap();
+        let mut file = None;
+        for n in 0..3 {
+            log.record(AuditEvent::Deregister {
+                pubkey: format!("key-{n}"),
+                address,
+                removed: true,
+                request_timestamp: 0.0,
+            });
+            // Each write fills the log, so each event ends up in a file of its own
+            let line = writer.receiver.recv().await.unwrap();
+            writer.write(&mut file, &[line]).await.unwrap();
+        }
+
+        // Only the newest two rotated logs are kept
+        assert!(!path.exists());
+        assert!(!rotated(3).exists());
+        for (n, key) in [(1, "key-2"), (2, "key-1")] {
+            let contents = std::fs::read_to_string(rotated(n)).unwrap();
+            let record: serde_json::Value = serde_json::from_str(contents.trim_end()).unwrap();
+            assert_eq!(record["event"], "deregister");
+            assert_eq!(record["pubkey"], key);
+            assert_eq!(record["address"], "198.51.100.1:4000");
+            assert!(record["timestamp"].as_f64().unwrap() > 0.0);
+        }
+
+        for n in 1..=2 {
+            std::fs::remove_file(rotated(n)).unwrap();
+        }
+    }
+}
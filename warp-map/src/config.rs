@@ -0,0 +1,188 @@
+//! warp-map's config file, in TOML; every setting can also be given on the command line, which takes precedence
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct MapConfig {
+    pub bind: SocketAddr,
+    // Also listen on this IPv6 address, such as `[::]:13116`, so clients can register and be reached over both families
+    pub bind_v6: Option<SocketAddr>,
+    // A second address, on another port of the same host, where clients check how their NATs map them
+    pub nat_probe: Option<SocketAddr>,
+    // Also serve clients over TCP here, for those on networks that block UDP
+    pub tcp: Option<SocketAddr>,
+    // A file holding nothing but the private key, in the form warp prints them; created with a new key if it doesn't
+    // exist
+    pub private_key_file: Option<PathBuf>,
+    #[serde(deserialize_with = "warp_config::serdes::deserialize_duration")]
+    pub client_expiry: std::time::Duration,
+    // Relay datagrams between registered clients that can't reach each other directly
+    pub relay: bool,
+    // Where registrations are saved every few seconds, and restored from on startup
+    pub state_file: Option<PathBuf>,
+    // The warp-maps to share registrations with
+    pub federate: Vec<FederatedMapConfig>,
+    pub limits: LimitsConfig,
+    pub access: AccessConfig,
+    // If set, registrations and mapping requests are appended here, for operators to account for who used warp-map
+    pub audit: Option<AuditConfig>,
+    // If set, Prometheus metrics are served over HTTP at http://<bind>/metrics
+    pub metrics: Option<MetricsConfig>,
+    // The most verbose level logged: `error`, `warn`, `info`, `debug` or `trace`
+    #[serde(deserialize_with = "deserialize_level")]
+    pub log_level: tracing_subscriber::filter::LevelFilter,
+}
+
+impl Default for MapConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 13116)),
+            bind_v6: None,
+            nat_probe: None,
+            tcp: None,
+            private_key_file: None,
+            client_expiry: std::time::Duration::from_secs(60),
+            relay: false,
+            state_file: None,
+            federate: Vec::new(),
+            limits: LimitsConfig::default(),
+            access: AccessConfig::default(),
+            audit: None,
+            metrics: None,
+            log_level: tracing_subscriber::filter::LevelFilter::INFO,
+        }
+    }
+}
+
+impl MapConfig {
+    pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        Ok(toml::from_str(std::fs::read_to_string(path)?.as_str())?)
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct FederatedMapConfig {
+    #[serde(deserialize_with = "warp_config::serdes::deserialize_public_key")]
+    pub public_key: warp_protocol::PublicKey,
+    #[serde(deserialize_with = "warp_config::serdes::deserialize_address")]
+    pub address: SocketAddr,
+}
+
+// How much work warp-map takes on; see `WarpMapServer::with_rate_limit` and the others
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    // Datagrams a second handled from any one IP address; unlimited if omitted
+    pub rate: Option<u32>,
+    // Datagrams an IP address may send at once before `rate` applies; twice `rate` if omitted
+    pub burst: Option<u32>,
+    // Bytes a second relayed from any one client to any other; unlimited if omitted
+    pub relay_rate: Option<u32>,
+    // Bytes one client may relay to another at once before `relay_rate` applies; twice `relay_rate` if omitted
+    pub relay_burst: Option<u32>,
+    pub max_in_flight: usize,
+    // Addresses any one public key may have registered at once; those it registered from longest ago make way
+    pub max_addresses_per_client: usize,
+    // Have addresses that aren't registered send back a cookie before decrypting anything they send
+    pub cookies: bool,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            rate: None,
+            burst: None,
+            relay_rate: None,
+            relay_burst: None,
+            max_in_flight: 1024,
+            max_addresses_per_client: 32,
+            cookies: false,
+        }
+    }
+}
+
+// Files of public keys, one per line, reread as they change
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct AccessConfig {
+    // Only these keys may register, if given
+    pub allowed_keys: Option<PathBuf>,
+    // These keys may not register, even if allowed
+    pub denied_keys: Option<PathBuf>,
+}
+
+// An append-only log of registrations and mapping requests, one JSON object per line
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    // The log is moved to `<path>.1` once it is this large, and a new one started; never if omitted
+    pub max_bytes: Option<u64>,
+    // How many of the logs moved aside are kept, `<path>.1` being the newest
+    #[serde(default = "default_audit_max_files")]
+    pub max_files: usize,
+}
+
+pub fn default_audit_max_files() -> usize {
+    5
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct MetricsConfig {
+    pub bind: SocketAddr,
+    // Also list the registered clients at http://<bind>/clients; keep `bind` private if so
+    #[serde(default)]
+    pub clients: bool,
+}
+
+fn deserialize_level<'de, D>(deserializer: D) -> Result<tracing_subscriber::filter::LevelFilter, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    let string = String::deserialize(deserializer)?;
+    string.parse().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_omitted_settings_take_defaults() {
+        let config: MapConfig = toml::from_str(
+            r#"
+            private_key_file = "/etc/warp-map/private_key"
+            client_expiry = 30.0
+            log_level = "debug"
+
+            [[federate]]
+            public_key = "0B2XTQXPMCXTKYFPYR5DY8T61W2186HD569YQWMPTV56E1VH7ZS82"
+            address = "192.0.2.2:13116"
+
+            [limits]
+            rate = 50
+
+            [audit]
+            path = "/var/log/warp-map/audit.jsonl"
+
+            [metrics]
+            bind = "127.0.0.1:9100"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.bind, MapConfig::default().bind);
+        assert_eq!(config.client_expiry, std::time::Duration::from_secs(30));
+        assert_eq!(config.log_level, tracing_subscriber::filter::LevelFilter::DEBUG);
+        assert_eq!(config.federate.len(), 1);
+        assert_eq!(config.limits.rate, Some(50));
+        assert_eq!(config.limits.max_in_flight, 1024);
+        assert!(!config.metrics.unwrap().clients);
+        assert!(config.access.allowed_keys.is_none());
+        let audit = config.audit.unwrap();
+        assert_eq!(audit.max_bytes, None);
+        assert_eq!(audit.max_files, 5);
+    }
+}
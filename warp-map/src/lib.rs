@@ -0,0 +1,9 @@
+mod access;
+mod audit;
+pub mod config;
+mod limits;
+pub mod map;
+mod metrics;
+mod server;
+
+pub use server::WarpMapServer;
@@ -0,0 +1,120 @@
+//! Token bucket rate limits, so that no one address can keep warp-map busy and no one pair of clients can take all of
+//! its relaying bandwidth
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+// Beyond this many keys with partly empty buckets, those that have refilled are forgotten; were sources to be spoofed
+// from ever more addresses, this is what keeps the limiter itself from growing without bound
+const MAX_TRACKED_KEYS: usize = 65536;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token bucket per key: each key is given `rate` tokens a second, up to `burst`
+pub(crate) struct Limiter<K> {
+    rate: f64,
+    burst: f64,
+    buckets: BTreeMap<K, Bucket>,
+}
+
+/// A token bucket per source IP address, of which each datagram takes one
+pub(crate) type SourceLimiter = Limiter<IpAddr>;
+
+/// A token bucket per sending and receiving public key, of which each relayed byte takes one
+pub(crate) type RelayQuota = Limiter<(warp_protocol::PublicKey, warp_protocol::PublicKey)>;
+
+impl<K: Ord> Limiter<K> {
+    pub(crate) fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    /// Whether `key` may go ahead now, taking a token for it if so
+    pub(crate) fn allow(&mut self, key: K, now: Instant) -> bool {
+        self.take(key, 1, now)
+    }
+
+    /// Whether `key` has `tokens` to spend now, taking them if so
+    pub(crate) fn take(&mut self, key: K, tokens: usize, now: Instant) -> bool {
+        if self.buckets.len() >= MAX_TRACKED_KEYS && !self.buckets.contains_key(&key) {
+            self.prune(now);
+            if self.buckets.len() >= MAX_TRACKED_KEYS {
+                return false;
+            }
+        }
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            last_refill: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.last_refill = now;
+        let tokens = tokens as f64;
+        if bucket.tokens < tokens {
+            return false;
+        }
+        bucket.tokens -= tokens;
+        true
+    }
+
+    /// Forget the keys whose buckets would be full again by `now`, as they are no different from new ones
+    pub(crate) fn prune(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens + elapsed * rate < burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sources_are_limited_separately() {
+        let mut limiter = SourceLimiter::new(10, 2);
+        let a: IpAddr = "198.51.100.1".parse().unwrap();
+        let b: IpAddr = "198.51.100.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.allow(a, now));
+        assert!(limiter.allow(a, now));
+        assert!(!limiter.allow(a, now));
+        assert!(limiter.allow(b, now));
+
+        // A token a tenth of a second
+        assert!(limiter.allow(a, now + Duration::from_millis(100)));
+        assert!(!limiter.allow(a, now + Duration::from_millis(100)));
+
+        limiter.prune(now + Duration::from_secs(1));
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn test_relay_quota_counts_bytes_per_direction() {
+        let mut quota = RelayQuota::new(1000, 1500);
+        let key = |seed: u8| {
+            warp_protocol::PrivateKey::from_bytes(&[seed; 32].into())
+                .unwrap()
+                .public_key()
+        };
+        let (a, b) = (key(1), key(2));
+        let now = Instant::now();
+
+        assert!(quota.take((a, b), 1200, now));
+        assert!(!quota.take((a, b), 1200, now));
+        // The other way round has a quota of its own
+        assert!(quota.take((b, a), 1200, now));
+        assert!(quota.take((a, b), 1200, now + Duration::from_secs(1)));
+    }
+}
@@ -1,230 +1,294 @@
-mod map;
-
 use clap::Parser;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::RwLock;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::prelude::*;
-use warp_protocol::codec::Message;
+use warp_map::config::MapConfig;
+use warp_map::WarpMapServer;
+
+// Read for the private key if --private-key isn't given, so that it needn't be on the command line
+const PRIVATE_KEY_VAR: &str = "WARP_MAP_PRIVATE_KEY";
 
 #[derive(Parser)]
 #[command(name = "warp-map")]
 #[command(about = "UDP hole-punching mapping server")]
 struct Args {
-    #[arg(short, long, default_value = "0.0.0.0:13116")]
-    bind: SocketAddr,
-
-    #[arg(short, long, default_value = "A2FP3SPBZ7RDXQPADFDYC9MZ0WQAW7S8RNW6J01C7FENTXY93WSG")]
-    private_key: String,
-
-    #[arg(short, long, default_value = "60")]
-    client_expiry_seconds: u64,
+    /// Read settings from CONFIG, a TOML file; the arguments below override them
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+
+    /// The address to listen for clients on [default: 0.0.0.0:13116]
+    #[arg(short, long)]
+    bind: Option<SocketAddr>,
+
+    /// Also listen on this IPv6 address, e.g. [::]:13116, so clients can register over both IPv4 and IPv6
+    #[arg(long)]
+    bind_v6: Option<SocketAddr>,
+
+    /// Answer NAT probes on this address, on another port than --bind, so clients can tell whether they are behind
+    /// symmetric NATs
+    #[arg(long)]
+    nat_probe: Option<SocketAddr>,
+
+    /// Also serve clients over TCP on this address, e.g. 0.0.0.0:13116, for those on networks that block UDP
+    #[arg(long)]
+    tcp: Option<SocketAddr>,
+
+    /// The private key, in the form warp prints them; prefer --private-key-file or WARP_MAP_PRIVATE_KEY, as arguments
+    /// can be seen by other users
+    #[arg(short, long)]
+    private_key: Option<String>,
+
+    /// A file holding nothing but the private key, created with a new key if it doesn't exist
+    #[arg(long)]
+    private_key_file: Option<std::path::PathBuf>,
+
+    /// How long a registration lasts unless renewed [default: 60]
+    #[arg(short, long)]
+    client_expiry_seconds: Option<u64>,
+
+    /// Serve Prometheus metrics at http://<METRICS_BIND>/metrics
+    #[arg(long)]
+    metrics_bind: Option<SocketAddr>,
+
+    /// Also list the registered clients, with when each was last seen, at http://<METRICS_BIND>/clients; keep
+    /// METRICS_BIND private, as this shows every client's public key and address
+    #[arg(long)]
+    metrics_clients: bool,
+
+    /// Relay datagrams between registered clients that can't reach each other directly
+    #[arg(long)]
+    relay: bool,
+
+    /// Relay at most RELAY_RATE bytes a second from any one client to any other, dropping the rest
+    #[arg(long, value_name = "RELAY_RATE")]
+    relay_rate: Option<u32>,
+
+    /// How many bytes one client may relay to another at once before --relay-rate applies; twice the rate by default
+    #[arg(long, value_name = "RELAY_BURST")]
+    relay_burst: Option<u32>,
+
+    /// Save registrations to STATE_FILE every few seconds, and restore those that haven't expired from it on startup
+    #[arg(long)]
+    state_file: Option<std::path::PathBuf>,
+
+    /// Share registrations with another warp-map, given as PUBLIC_KEY@ADDRESS, as well as those in the config; may be
+    /// repeated
+    #[arg(long, value_name = "PUBLIC_KEY@ADDRESS")]
+    federate: Vec<String>,
+
+    /// Handle at most RATE datagrams a second from any one IP address, dropping the rest; raise it when relaying, as
+    /// relayed tunnel traffic counts too
+    #[arg(long, value_name = "RATE")]
+    rate_limit: Option<u32>,
+
+    /// How many datagrams an IP address may send at once before --rate-limit applies; twice the rate by default
+    #[arg(long, value_name = "BURST")]
+    rate_burst: Option<u32>,
+
+    /// Drop datagrams that arrive while this many are still being handled [default: 1024]
+    #[arg(long)]
+    max_in_flight: Option<usize>,
+
+    /// Addresses any one public key may have registered at once, evicting the oldest beyond that [default: 32]
+    #[arg(long)]
+    max_addresses_per_client: Option<usize>,
+
+    /// Only let the public keys listed in ALLOWED_KEYS, one per line, register; reread as it changes
+    #[arg(long)]
+    allowed_keys: Option<std::path::PathBuf>,
+
+    /// Turn away the public keys listed in DENIED_KEYS, one per line, even if allowed; reread as it changes
+    #[arg(long)]
+    denied_keys: Option<std::path::PathBuf>,
+
+    /// Have addresses that aren't registered send back a cookie before decrypting anything they send
+    #[arg(long)]
+    cookies: bool,
+
+    /// Append every registration, deregistration and mapping request to AUDIT_LOG, one JSON object per line
+    #[arg(long)]
+    audit_log: Option<std::path::PathBuf>,
+
+    /// Move the audit log to AUDIT_LOG.1 once it is this many bytes, and start a new one; never by default
+    #[arg(long, value_name = "BYTES")]
+    audit_log_max_bytes: Option<u64>,
+
+    /// How many audit logs moved aside are kept [default: 5]
+    #[arg(long, value_name = "FILES")]
+    audit_log_max_files: Option<usize>,
+
+    /// The most verbose level logged: error, warn, info, debug or trace [default: info]
+    #[arg(long)]
+    log_level: Option<tracing_subscriber::filter::LevelFilter>,
 }
 
-struct WarpMapServer {
-    private_key: warp_protocol::PrivateKey,
-    bind_addr: SocketAddr,
-    client_store: Arc<RwLock<map::ClientStore>>,
-}
-//
-// #[derive(bincode::Decode)]
-// struct RegistrationAad {
-//     #[bincode(with_serde)]
-//     public_key: warp_protocol::PublicKey,
-// }
-
-impl WarpMapServer {
-    fn new(private_key: warp_protocol::PrivateKey, bind_addr: SocketAddr, client_expiry: std::time::Duration) -> Self {
-        Self {
-            private_key,
-            bind_addr,
-            client_store: Arc::new(RwLock::new(map::ClientStore::new(client_expiry))),
-        }
-    }
-
-    async fn run(&self) {
-        let socket = Arc::new(tokio::net::UdpSocket::bind(self.bind_addr).await.unwrap());
-        info!("Listening on: {}", socket.local_addr().unwrap());
-
-        // Spawn garbage collection task
-        let gc_store = self.client_store.clone();
-        tokio::task::Builder::new()
-            .name("client store garbage collector")
-            .spawn(async move {
-                let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
-                loop {
-                    interval.tick().await;
-                    gc_store.write().await.garbage_collect(Instant::now());
-                }
-            })
-            .unwrap();
-
-        loop {
-            let mut buf = [0; 2 << 9];
-            match socket.recv_from(&mut buf).await {
-                Ok((len, address)) => {
-                    let socket_clone = socket.clone();
-                    let private_key = self.private_key.clone();
-                    let client_store = self.client_store.clone();
-
-                    let task_name = format!("Handle data from {address}");
-
-                    // TODO: I think spawning a new task for each message is overkill; do something better
-                    let spawn_result = tokio::task::Builder::new().name(&task_name).spawn(async move {
-                        match Self::process_rx_buffer(&private_key, &client_store, &buf[..len], &address).await {
-                            Ok(response) => {
-                                if let Err(e) = socket_clone.send_to(&response, address).await {
-                                    error!("Failed to send response to {}: {}", address, e);
-                                }
-                            }
-                            Err(e) => {
-                                error!("Error processing message from {}: {}", address, e);
-                            }
-                        }
-                    });
-                    match spawn_result {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("Error spawning task for message from {}: {}", address, e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("Error receiving from socket: {}", e);
-                }
+impl Args {
+    // The config file's settings, or the defaults, with those given as arguments in their place
+    fn config(&self) -> anyhow::Result<MapConfig> {
+        let mut config = match &self.config {
+            Some(path) => {
+                MapConfig::load(path).map_err(|e| anyhow::anyhow!("failed to load {}: {}", path.display(), e))?
             }
+            None => MapConfig::default(),
+        };
+        if let Some(bind) = self.bind {
+            config.bind = bind;
         }
-    }
-
-    async fn process_rx_buffer(
-        private_key: &warp_protocol::PrivateKey,
-        client_store: &Arc<RwLock<map::ClientStore>>,
-        buf: &[u8],
-        from: &SocketAddr,
-    ) -> anyhow::Result<Vec<u8>> {
-        let mut response_bytes: Vec<u8> = Vec::new();
-        let mut remaining_buf = buf;
-
-        loop {
-            let (msg, buf) = warp_protocol::codec::WireMessage::from_slice(remaining_buf)?;
-
-            let client_key = {
-                let store = client_store.read().await;
-                match store.get_pubkey(from) {
-                    None => {
-                        let (aad, _): (warp_protocol::messages::RegisterRequestAssociatedData, usize) =
-                            bincode::decode_from_slice(&msg.associated_data, bincode::config::standard())?;
-                        aad.pubkey
-                    }
-                    Some(client_key) => client_key,
-                }
-            };
-
-            let cipher = warp_protocol::crypto::cipher_from_shared_secret(private_key, &client_key);
-            let decrypted = msg.decrypt(&cipher)?;
-            let client_key_string = warp_protocol::crypto::pubkey_to_string(&client_key);
-
-            match decrypted.message_id {
-                warp_protocol::messages::RegisterRequest::MESSAGE_ID => {
-                    let registration_msg: warp_protocol::messages::RegisterRequest = decrypted.decode()?;
-
-                    {
-                        let mut store = client_store.write().await;
-                        store.register_client(client_key, *from, Instant::now());
-                    }
-
-                    let response = warp_protocol::messages::RegisterResponse {
-                        address: *from,
-                        timestamp: std::time::SystemTime::now(),
-                        request_timestamp: registration_msg.timestamp,
-                    };
-                    let dt = response.timestamp.duration_since(registration_msg.timestamp)?;
-                    tracing::event!(
-                        name: "RegistrationRequest",
-                        tracing::Level::INFO,
-                        public_key = client_key_string,
-                        address = from.to_string().as_str(),
-                        clock_network_skew = dt.as_secs_f32());
-
-                    let bytes = response.encode()?.encrypt(&cipher)?.to_bytes()?;
-                    response_bytes.extend_from_slice(bytes.as_slice());
-                }
-                warp_protocol::messages::MappingRequest::MESSAGE_ID => {
-                    println!("MappingRequest");
-                    let mapping_msg: warp_protocol::messages::MappingRequest = decrypted.decode()?;
-
-                    let addresses = {
-                        let store = client_store.read().await;
-                        store.get_addresses(&mapping_msg.peer_pubkey, Instant::now())
-                    };
-
-                    let n_addresses = addresses.len();
-                    let response = warp_protocol::messages::MappingResponse {
-                        peer_pubkey: mapping_msg.peer_pubkey,
-                        endpoints: addresses,
-                        timestamp: std::time::SystemTime::now(),
-                    };
-                    let dt = response.timestamp.duration_since(mapping_msg.timestamp)?;
-                    info!(
-                        "Mapping request received from {}, returned {} addresses, transit time + clock skew = {}",
-                        client_key_string,
-                        n_addresses,
-                        dt.as_secs()
-                    );
-
-                    let bytes = response.encode()?.encrypt(&cipher)?.to_bytes()?;
-                    response_bytes.extend_from_slice(bytes.as_slice());
-                }
-                warp_protocol::messages::DeregisterRequest::MESSAGE_ID => {
-                    let deregister_msg: warp_protocol::messages::DeregisterRequest = decrypted.decode()?;
-
-                    let removed = {
-                        let mut store = client_store.write().await;
-                        store.deregister_client(&client_key, *from)
-                    };
-
-                    let response = warp_protocol::messages::DeregisterResponse {
-                        timestamp: std::time::SystemTime::now(),
-                        request_timestamp: deregister_msg.timestamp,
-                    };
-
-                    let dt = response.timestamp.duration_since(deregister_msg.timestamp)?;
-                    tracing::event!(
-                        name: "DeregisterRequest",
-                        tracing::Level::INFO,
-                        public_key = client_key_string,
-                        address = from.to_string().as_str(),
-                        removed = removed,
-                        clock_network_skew = dt.as_secs_f32()
-                    );
-
-                    let bytes = response.encode()?.encrypt(&cipher)?.to_bytes()?;
-                    response_bytes.extend_from_slice(bytes.as_slice());
+        if let Some(bind_v6) = self.bind_v6 {
+            config.bind_v6 = Some(bind_v6);
+        }
+        if let Some(nat_probe) = self.nat_probe {
+            config.nat_probe = Some(nat_probe);
+        }
+        if let Some(tcp) = self.tcp {
+            config.tcp = Some(tcp);
+        }
+        if let Some(private_key_file) = &self.private_key_file {
+            config.private_key_file = Some(private_key_file.clone());
+        }
+        if let Some(client_expiry_seconds) = self.client_expiry_seconds {
+            config.client_expiry = std::time::Duration::from_secs(client_expiry_seconds);
+        }
+        if let Some(metrics_bind) = self.metrics_bind {
+            config.metrics = Some(warp_map::config::MetricsConfig {
+                bind: metrics_bind,
+                clients: false,
+            });
+        }
+        if self.metrics_clients {
+            config
+                .metrics
+                .as_mut()
+                .ok_or_else(|| anyhow::anyhow!("--metrics-clients needs --metrics-bind or [metrics] in the config"))?
+                .clients = true;
+        }
+        config.relay |= self.relay;
+        if let Some(path) = &self.audit_log {
+            match &mut config.audit {
+                Some(audit) => audit.path = path.clone(),
+                None => {
+                    config.audit = Some(warp_map::config::AuditConfig {
+                        path: path.clone(),
+                        max_bytes: None,
+                        max_files: warp_map::config::default_audit_max_files(),
+                    })
                 }
-                id => return Err(warp_protocol::DecodeError::UnexpectedMessageId(id).into()),
-            }
-
-            remaining_buf = buf;
-            if remaining_buf.is_empty() {
-                break;
             }
+        }
+        if let Some(max_bytes) = self.audit_log_max_bytes {
+            config
+                .audit
+                .as_mut()
+                .ok_or_else(|| anyhow::anyhow!("--audit-log-max-bytes needs --audit-log or [audit] in the config"))?
+                .max_bytes = Some(max_bytes);
+        }
+        if let Some(max_files) = self.audit_log_max_files {
+            config
+                .audit
+                .as_mut()
+                .ok_or_else(|| anyhow::anyhow!("--audit-log-max-files needs --audit-log or [audit] in the config"))?
+                .max_files = max_files;
+        }
+        if let Some(state_file) = &self.state_file {
+            config.state_file = Some(state_file.clone());
+        }
+        for federated_map in &self.federate {
+            let (public_key, address) = federated_map
+                .split_once('@')
+                .ok_or_else(|| anyhow::anyhow!("--federate {federated_map} is not PUBLIC_KEY@ADDRESS"))?;
+            config.federate.push(warp_map::config::FederatedMapConfig {
+                public_key: warp_protocol::crypto::pubkey_from_string(public_key)?,
+                address: address.parse()?,
+            });
+        }
+        if let Some(rate) = self.rate_limit {
+            config.limits.rate = Some(rate);
+        }
+        if let Some(burst) = self.rate_burst {
+            config.limits.burst = Some(burst);
+        }
+        if let Some(relay_rate) = self.relay_rate {
+            config.limits.relay_rate = Some(relay_rate);
+        }
+        if let Some(relay_burst) = self.relay_burst {
+            config.limits.relay_burst = Some(relay_burst);
+        }
+        if let Some(max_in_flight) = self.max_in_flight {
+            config.limits.max_in_flight = max_in_flight;
+        }
+        if let Some(max_addresses_per_client) = self.max_addresses_per_client {
+            config.limits.max_addresses_per_client = max_addresses_per_client;
+        }
+        config.limits.cookies |= self.cookies;
+        if let Some(allowed_keys) = &self.allowed_keys {
+            config.access.allowed_keys = Some(allowed_keys.clone());
+        }
+        if let Some(denied_keys) = &self.denied_keys {
+            config.access.denied_keys = Some(denied_keys.clone());
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        Ok(config)
+    }
 
-            // Yield to allow other tasks to run
-            tokio::task::yield_now().await;
+    // From the first of --private-key, WARP_MAP_PRIVATE_KEY and the key file, generating the key file if it doesn't
+    // exist yet
+    fn private_key(&self, config: &MapConfig) -> anyhow::Result<warp_protocol::PrivateKey> {
+        if let Some(key) = &self.private_key {
+            warn!(
+                "--private-key can be seen by other users and is kept in shell history; prefer --private-key-file or {}",
+                PRIVATE_KEY_VAR
+            );
+            return Ok(warp_protocol::crypto::privkey_from_string(key.trim())?);
+        }
+        if let Ok(key) = std::env::var(PRIVATE_KEY_VAR) {
+            return Ok(warp_protocol::crypto::privkey_from_string(key.trim())?);
         }
-        Ok(response_bytes)
+        let Some(path) = &config.private_key_file else {
+            anyhow::bail!(
+                "no private key; give --private-key-file, private_key_file in the config or {}",
+                PRIVATE_KEY_VAR
+            );
+        };
+        match std::fs::read_to_string(path) {
+            Ok(key) => Ok(warp_protocol::crypto::privkey_from_string(key.trim())?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => generate_private_key_file(path),
+            Err(e) => Err(anyhow::anyhow!("failed to read {}: {}", path.display(), e)),
+        }
+    }
+}
+
+// Save a new private key at `path`, readable only by the current user, for a warp-map's first run
+fn generate_private_key_file(path: &std::path::Path) -> anyhow::Result<warp_protocol::PrivateKey> {
+    use std::io::Write;
+
+    let private_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
     }
+    let mut file = options
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("failed to create {}: {}", path.display(), e))?;
+    writeln!(file, "{}", warp_protocol::crypto::privkey_to_string(&private_key))?;
+    info!("Generated a new private key in {}", path.display());
+    Ok(private_key)
 }
 
 fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let config = args.config()?;
+
     let rt = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
 
     let stdout_layer = tracing_subscriber::fmt::layer()
         .with_span_events(FmtSpan::CLOSE)
-        .with_filter(tracing_subscriber::filter::LevelFilter::INFO);
+        .with_filter(config.log_level);
     let tokio_console_layer = console_subscriber::spawn();
 
     tracing_subscriber::registry()
@@ -232,24 +296,77 @@ fn main() -> anyhow::Result<()> {
         .with(stdout_layer)
         .init();
 
-    rt.block_on(async_main())
-}
+    let private_key = args.private_key(&config)?;
 
-async fn async_main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let private_key = warp_protocol::crypto::privkey_from_string(&args.private_key)?;
+    rt.block_on(async_main(config, private_key))
+}
 
+async fn async_main(config: MapConfig, private_key: warp_protocol::PrivateKey) -> anyhow::Result<()> {
     info!(
         "Public key: {}",
         warp_protocol::crypto::pubkey_to_string(&private_key.public_key())
     );
 
-    WarpMapServer::new(
-        private_key,
-        args.bind,
-        std::time::Duration::from_secs(args.client_expiry_seconds),
-    )
-    .run()
-    .await;
+    let mut server = WarpMapServer::new(private_key, config.bind, config.client_expiry);
+    if let Some(bind_v6) = config.bind_v6 {
+        server = server.with_bind(bind_v6);
+    }
+    if let Some(nat_probe) = config.nat_probe {
+        server = server.with_nat_probe(nat_probe);
+    }
+    if let Some(tcp) = config.tcp {
+        server = server.with_tcp(tcp);
+    }
+    if config.relay {
+        server = server.with_relay();
+    }
+    if let Some(rate) = config.limits.relay_rate {
+        server = server.with_relay_quota(rate, config.limits.relay_burst.unwrap_or(rate.saturating_mul(2)));
+    }
+    if let Some(state_file) = config.state_file {
+        server = server.with_state_file(state_file);
+    }
+    if let Some(rate) = config.limits.rate {
+        server = server.with_rate_limit(rate, config.limits.burst.unwrap_or(rate.saturating_mul(2)));
+    }
+    server = server
+        .with_max_in_flight(config.limits.max_in_flight)
+        .with_max_addresses_per_client(config.limits.max_addresses_per_client);
+    if config.limits.cookies {
+        server = server.with_cookies();
+    }
+    if let Some(allowed_keys) = config.access.allowed_keys {
+        server = server.with_allowed_keys(allowed_keys);
+    }
+    if let Some(denied_keys) = config.access.denied_keys {
+        server = server.with_denied_keys(denied_keys);
+    }
+    for federated_map in config.federate {
+        server = server.with_federated_map(federated_map.address, federated_map.public_key);
+    }
+    if let Some(audit) = config.audit {
+        server = server.with_audit_log(audit.path, audit.max_bytes, audit.max_files);
+    }
+
+    if let Some(metrics) = config.metrics {
+        let mut pages = Vec::new();
+        if metrics.clients {
+            pages.push(("/clients".to_string(), server.clients_page()));
+        }
+        tokio::task::Builder::new().name("metrics exporter").spawn(async move {
+            if let Err(e) = warp_metrics::exporter::bind_and_serve_with_pages(
+                warp_metrics::global().clone(),
+                None,
+                pages,
+                metrics.bind,
+            )
+            .await
+            {
+                error!("Metrics exporter on {} stopped: {}", metrics.bind, e);
+            }
+        })?;
+    }
+
+    server.run().await;
     Ok(())
 }
@@ -1,6 +1,73 @@
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::SocketAddr;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
+
+// How many host candidates are kept per registered address; a client's sockets each register separately, so one
+// address has few of its own
+const MAX_HOST_CANDIDATES: usize = 8;
+
+// How long a public key whose addresses have all expired or been deregistered is remembered as having been registered
+const DEPARTED_REMEMBERED_FOR: Duration = Duration::from_secs(60 * 60);
+
+/// What registering an address did: whether the address is new to its public key, and the public key's addresses that
+/// were evicted to keep it within the maximum
+#[derive(Debug, Default, PartialEq)]
+pub struct Registered {
+    pub new: bool,
+    pub evicted: Vec<SocketAddr>,
+}
+
+/// A [`ClientStore`]'s registrations and subscriptions as saved to warp-map's state file, so that a restarted warp-map
+/// still knows its clients until they next register
+#[derive(Debug, bincode::Encode, bincode::Decode)]
+pub struct Snapshot {
+    saved_at: SystemTime,
+    registrations: Vec<Registration>,
+    subscriptions: Vec<SavedSubscription>,
+}
+
+/// One of a client's registered addresses, as saved or as another warp-map knows it
+///
+/// Instants don't outlive the process or cross to another host, so it comes with how long ago it was last renewed.
+#[derive(Debug, Clone, PartialEq, bincode::Encode, bincode::Decode)]
+pub struct Registration {
+    #[bincode(with_serde)]
+    pub pubkey: warp_protocol::PublicKey,
+    pub address: SocketAddr,
+    pub age: Duration,
+    pub host_candidates: Vec<SocketAddr>,
+}
+
+// Saved, like registrations, with how long before `saved_at` it was last renewed
+#[derive(Debug, bincode::Encode, bincode::Decode)]
+struct SavedSubscription {
+    #[bincode(with_serde)]
+    pubkey: warp_protocol::PublicKey,
+    subscriber: SocketAddr,
+    age: Duration,
+}
+
+impl Snapshot {
+    /// The snapshot saved at `path`, or `None` if nothing has been saved there yet
+    pub async fn load(path: &std::path::Path) -> anyhow::Result<Option<Self>> {
+        match tokio::fs::read(path).await {
+            Ok(contents) => Ok(Some(
+                bincode::decode_from_slice(&contents, bincode::config::standard())?.0,
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save to `path`, replacing what was there only once the new snapshot has been written in full
+    pub async fn save(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        tokio::fs::write(&temporary, bincode::encode_to_vec(self, bincode::config::standard())?).await?;
+        tokio::fs::rename(&temporary, path).await?;
+        Ok(())
+    }
+}
 
 pub struct ClientStore {
     client_expiry: std::time::Duration,
@@ -8,6 +75,13 @@ pub struct ClientStore {
     pubkey_to_addresses: BTreeMap<warp_protocol::PublicKey, HashSet<SocketAddr>>,
     address_to_pubkey: HashMap<SocketAddr, warp_protocol::PublicKey>,
     address_last_seen: HashMap<SocketAddr, Instant>,
+    // The addresses the client's socket behind each registered address says it is bound to
+    address_host_candidates: HashMap<SocketAddr, Vec<SocketAddr>>,
+    // The addresses that asked to be sent each public key's mappings as it registers new addresses, and when they last
+    // asked
+    subscribers: BTreeMap<warp_protocol::PublicKey, HashMap<SocketAddr, Instant>>,
+    // The public keys that had addresses here but have none left, with when their last one was last seen
+    departed: BTreeMap<warp_protocol::PublicKey, Instant>,
 }
 
 impl ClientStore {
@@ -17,10 +91,14 @@ impl ClientStore {
             pubkey_to_addresses: BTreeMap::new(),
             address_to_pubkey: HashMap::new(),
             address_last_seen: HashMap::new(),
+            address_host_candidates: HashMap::new(),
+            subscribers: BTreeMap::new(),
+            departed: BTreeMap::new(),
         }
     }
 
-    pub fn register_client(&mut self, pubkey: warp_protocol::PublicKey, address: SocketAddr, now: Instant) {
+    /// Register `address` for `pubkey`, or renew its registration; returns whether `address` is new to `pubkey`
+    pub fn register_client(&mut self, pubkey: warp_protocol::PublicKey, address: SocketAddr, now: Instant) -> bool {
         // Clean up old mapping if address was associated with different pubkey
         if let Some(old_pubkey) = self.address_to_pubkey.get(&address) {
             if *old_pubkey != pubkey {
@@ -34,10 +112,46 @@ impl ClientStore {
         }
 
         // Insert into set (automatically handles duplicates)
-        self.pubkey_to_addresses.entry(pubkey).or_default().insert(address);
+        let new = self.pubkey_to_addresses.entry(pubkey).or_default().insert(address);
+        self.departed.remove(&pubkey);
 
         self.address_to_pubkey.insert(address, pubkey);
         self.address_last_seen.insert(address, now);
+        new
+    }
+
+    /// Have `pubkey`'s mappings sent to `subscriber` as it registers new addresses, for as long as a registration lasts
+    pub fn subscribe(&mut self, pubkey: warp_protocol::PublicKey, subscriber: SocketAddr, now: Instant) {
+        self.subscribers.entry(pubkey).or_default().insert(subscriber, now);
+    }
+
+    /// The addresses to send `pubkey`'s mappings to when it registers a new address, with the public keys they are
+    /// registered for; subscribers that are no longer registered are left out
+    pub fn get_subscribers(
+        &self,
+        pubkey: &warp_protocol::PublicKey,
+        now: Instant,
+    ) -> Vec<(SocketAddr, warp_protocol::PublicKey)> {
+        self.subscribers
+            .get(pubkey)
+            .into_iter()
+            .flatten()
+            .filter(|&(_, &subscribed)| now.duration_since(subscribed) < self.client_expiry)
+            .filter_map(|(&subscriber, _)| Some((subscriber, self.get_pubkey(&subscriber)?)))
+            .collect()
+    }
+
+    /// Replace the host candidates of the registered `address` with `candidates`, up to MAX_HOST_CANDIDATES of them
+    pub fn set_host_candidates(&mut self, address: SocketAddr, candidates: &[SocketAddr]) {
+        if !self.address_to_pubkey.contains_key(&address) {
+            return;
+        }
+        if candidates.is_empty() {
+            self.address_host_candidates.remove(&address);
+        } else {
+            let candidates = candidates.iter().take(MAX_HOST_CANDIDATES).copied().collect();
+            self.address_host_candidates.insert(address, candidates);
+        }
     }
 
     pub fn deregister_client(&mut self, pubkey: &warp_protocol::PublicKey, address: SocketAddr) -> bool {
@@ -51,6 +165,9 @@ impl ClientStore {
                 // If this was the last address for this pubkey, remove the pubkey entry
                 if addresses.is_empty() {
                     self.pubkey_to_addresses.remove(pubkey);
+                    if let Some(&last_seen) = self.address_last_seen.get(&address) {
+                        self.departed.insert(*pubkey, last_seen);
+                    }
                 }
             }
         }
@@ -59,11 +176,29 @@ impl ClientStore {
         if removed {
             self.address_to_pubkey.remove(&address);
             self.address_last_seen.remove(&address);
+            self.address_host_candidates.remove(&address);
         }
 
         removed
     }
 
+    /// Deregister every address of the public keys `keep` returns false for; returns how many addresses that was
+    pub fn retain_clients(&mut self, keep: impl Fn(&warp_protocol::PublicKey) -> bool) -> usize {
+        let removed: Vec<_> = self
+            .address_to_pubkey
+            .iter()
+            .filter(|(_, pubkey)| !keep(pubkey))
+            .map(|(&address, &pubkey)| (pubkey, address))
+            .collect();
+        for (pubkey, address) in &removed {
+            self.deregister_client(pubkey, *address);
+        }
+        self.subscribers.retain(|pubkey, _| keep(pubkey));
+        // They weren't so much departed as turned away
+        self.departed.retain(|pubkey, _| keep(pubkey));
+        removed.len()
+    }
+
     pub fn get_addresses(&self, pubkey: &warp_protocol::PublicKey, now: Instant) -> Vec<SocketAddr> {
         self.pubkey_to_addresses
             .get(pubkey)
@@ -82,11 +217,139 @@ impl ClientStore {
             .unwrap_or_default()
     }
 
+    /// The host candidates sent with each of `pubkey`'s unexpired registrations
+    pub fn get_host_candidates(&self, pubkey: &warp_protocol::PublicKey, now: Instant) -> Vec<SocketAddr> {
+        let mut candidates: Vec<SocketAddr> = self
+            .get_addresses(pubkey, now)
+            .iter()
+            .filter_map(|address| self.address_host_candidates.get(address))
+            .flatten()
+            .copied()
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
     pub fn get_pubkey(&self, address: &SocketAddr) -> Option<warp_protocol::PublicKey> {
         self.address_to_pubkey.get(address).copied()
     }
 
-    pub fn garbage_collect(&mut self, now: Instant) {
+    /// Whether `pubkey` has an unexpired address here, had one until lately, or neither
+    pub fn peer_status(&self, pubkey: &warp_protocol::PublicKey, now: Instant) -> warp_protocol::messages::PeerStatus {
+        use warp_protocol::messages::PeerStatus;
+        if !self.get_addresses(pubkey, now).is_empty() {
+            PeerStatus::Registered
+        } else if self.pubkey_to_addresses.contains_key(pubkey) || self.departed.contains_key(pubkey) {
+            PeerStatus::Expired
+        } else {
+            PeerStatus::Unknown
+        }
+    }
+
+    /// Each of `pubkey`'s addresses, expired or not, with when it last registered from there
+    pub fn get_last_seen(&self, pubkey: &warp_protocol::PublicKey) -> Vec<(SocketAddr, Instant)> {
+        self.pubkey_to_addresses
+            .get(pubkey)
+            .into_iter()
+            .flatten()
+            .filter_map(|address| Some((*address, *self.address_last_seen.get(address)?)))
+            .collect()
+    }
+
+    /// Number of distinct public keys with at least one registered address
+    pub fn client_count(&self) -> usize {
+        self.pubkey_to_addresses.len()
+    }
+
+    /// Number of registered addresses across all clients
+    pub fn address_count(&self) -> usize {
+        self.address_to_pubkey.len()
+    }
+
+    /// Every registration that hasn't expired
+    pub fn registrations(&self, now: Instant) -> Vec<Registration> {
+        self.address_to_pubkey
+            .iter()
+            .filter_map(|(&address, &pubkey)| {
+                let age = now.duration_since(*self.address_last_seen.get(&address)?);
+                (age < self.client_expiry).then(|| Registration {
+                    pubkey,
+                    address,
+                    age,
+                    host_candidates: self.address_host_candidates.get(&address).cloned().unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    /// Take in `registration` from elsewhere unless it has expired or its address has been renewed since; returns
+    /// whether the address is new to its public key, or `None` if the registration was left out
+    pub fn merge(&mut self, registration: Registration, now: Instant) -> Option<bool> {
+        if registration.age >= self.client_expiry {
+            return None;
+        }
+        // An Instant can't go back further than the host's uptime, so just after a boot the oldest are as good as new
+        let last_seen = now.checked_sub(registration.age).unwrap_or(now);
+        if self
+            .address_last_seen
+            .get(&registration.address)
+            .is_some_and(|&seen| seen >= last_seen)
+        {
+            return None;
+        }
+        let new = self.register_client(registration.pubkey, registration.address, last_seen);
+        self.set_host_candidates(registration.address, &registration.host_candidates);
+        Some(new)
+    }
+
+    /// Everything registered or subscribed to that hasn't expired
+    pub fn snapshot(&self, now: Instant) -> Snapshot {
+        let registrations = self.registrations(now);
+        let subscriptions = self
+            .subscribers
+            .iter()
+            .flat_map(|(&pubkey, subscribers)| {
+                subscribers
+                    .iter()
+                    .map(move |(&subscriber, &subscribed)| SavedSubscription {
+                        pubkey,
+                        subscriber,
+                        age: now.duration_since(subscribed),
+                    })
+            })
+            .filter(|subscription| subscription.age < self.client_expiry)
+            .collect();
+        Snapshot {
+            saved_at: SystemTime::now(),
+            registrations,
+            subscriptions,
+        }
+    }
+
+    /// Add what `snapshot` holds that hasn't expired since it was saved, counting the time in between; returns how many
+    /// registrations were restored
+    pub fn restore(&mut self, snapshot: Snapshot, now: Instant) -> usize {
+        let since_saved = SystemTime::now().duration_since(snapshot.saved_at).unwrap_or_default();
+        let mut restored = 0;
+        for mut registration in snapshot.registrations {
+            registration.age += since_saved;
+            if self.merge(registration, now).is_some() {
+                restored += 1;
+            }
+        }
+        for subscription in snapshot.subscriptions {
+            let age = subscription.age + since_saved;
+            if age < self.client_expiry {
+                let subscribed = now.checked_sub(age).unwrap_or(now);
+                self.subscribe(subscription.pubkey, subscription.subscriber, subscribed);
+            }
+        }
+        restored
+    }
+
+    /// Remove expired registrations and subscriptions; returns how many addresses expired
+    pub fn garbage_collect(&mut self, now: Instant) -> usize {
         let _span = tracing::span!(tracing::Level::INFO, "garbage collection").entered();
 
         let mut expired_addresses = 0;
@@ -96,12 +359,14 @@ impl ClientStore {
             let expired = now.duration_since(last_seen) >= self.client_expiry;
             if expired {
                 expired_addresses += 1;
+                self.address_host_candidates.remove(&addr);
                 // Clean up reverse mapping with O(1) HashSet removal
                 if let Some(pubkey) = self.address_to_pubkey.remove(&addr) {
                     if let Some(addresses) = self.pubkey_to_addresses.get_mut(&pubkey) {
                         addresses.remove(&addr); // O(1) instead of O(n)
                         if addresses.is_empty() {
                             self.pubkey_to_addresses.remove(&pubkey);
+                            self.departed.insert(pubkey, last_seen);
                             expired_pubkeys += 1;
                         }
                     }
@@ -110,11 +375,244 @@ impl ClientStore {
             !expired
         });
 
+        self.subscribers.retain(|_, subscribers| {
+            subscribers.retain(|_, &mut subscribed| now.duration_since(subscribed) < self.client_expiry);
+            !subscribers.is_empty()
+        });
+        self.departed
+            .retain(|_, &mut last_seen| now.duration_since(last_seen) < DEPARTED_REMEMBERED_FOR);
+
         tracing::event!(
             tracing::Level::INFO,
             expired_addresses,
             expired_public_keys = expired_pubkeys
         );
+        expired_addresses
+    }
+}
+
+/// A [`ClientStore`] split into shards by address, each behind its own lock, so that a registration only holds up the
+/// requests that land on the same shard
+///
+/// Everything a `ClientStore` keeps is per address, apart from which addresses each public key has, so an address's
+/// registration, host candidates and subscriptions all sit in one shard; looking a public key up reads every shard in
+/// turn, without ever holding more than one lock.
+pub struct ShardedClientStore {
+    shards: Vec<std::sync::RwLock<ClientStore>>,
+    hasher: std::collections::hash_map::RandomState,
+    // How many addresses a public key may have registered at once, so that registering from ever more ports can't grow
+    // the store without bound; usize::MAX if unlimited
+    max_addresses: std::sync::atomic::AtomicUsize,
+}
+
+impl ShardedClientStore {
+    pub fn new(client_expiry: std::time::Duration, shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| std::sync::RwLock::new(ClientStore::new(client_expiry)))
+                .collect(),
+            hasher: std::collections::hash_map::RandomState::new(),
+            max_addresses: std::sync::atomic::AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    /// Keep each public key to `max` registered addresses, evicting those it registered from longest ago to make room
+    pub fn set_max_addresses(&self, max: usize) {
+        self.max_addresses
+            .store(max.max(1), std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn index(&self, address: &SocketAddr) -> usize {
+        use std::hash::BuildHasher;
+        self.hasher.hash_one(address) as usize % self.shards.len()
+    }
+
+    fn shard(&self, address: &SocketAddr) -> &std::sync::RwLock<ClientStore> {
+        &self.shards[self.index(address)]
+    }
+
+    fn read(&self) -> impl Iterator<Item = std::sync::RwLockReadGuard<'_, ClientStore>> {
+        self.shards.iter().map(|shard| shard.read().unwrap())
+    }
+
+    /// Register `address` for `pubkey` with `host_candidates`, or renew its registration
+    pub fn register_client(
+        &self,
+        pubkey: warp_protocol::PublicKey,
+        address: SocketAddr,
+        host_candidates: &[SocketAddr],
+        now: Instant,
+    ) -> Registered {
+        let new = {
+            let mut shard = self.shard(&address).write().unwrap();
+            let new = shard.register_client(pubkey, address, now);
+            shard.set_host_candidates(address, host_candidates);
+            new
+        };
+        let evicted = if new { self.evict(&pubkey, address) } else { Vec::new() };
+        Registered { new, evicted }
+    }
+
+    // Deregister the addresses `pubkey` last registered from longest ago, other than `address` which it just did, until
+    // it has no more than `max_addresses`; the addresses are counted without holding every shard, so registrations
+    // racing each other may briefly take a public key over
+    fn evict(&self, pubkey: &warp_protocol::PublicKey, address: SocketAddr) -> Vec<SocketAddr> {
+        let max = self.max_addresses.load(std::sync::atomic::Ordering::Relaxed);
+        let mut addresses: Vec<_> = self.read().flat_map(|shard| shard.get_last_seen(pubkey)).collect();
+        if addresses.len() <= max {
+            return Vec::new();
+        }
+        addresses.sort_by_key(|&(other, last_seen)| (last_seen, other));
+        let excess = addresses.len() - max;
+        let mut evicted = Vec::new();
+        for (oldest, _) in addresses
+            .into_iter()
+            .filter(|&(other, _)| other != address)
+            .take(excess)
+        {
+            if self.deregister_client(pubkey, oldest) {
+                evicted.push(oldest);
+            }
+        }
+        evicted
+    }
+
+    /// Have `pubkey`'s mappings sent to `subscriber`, if `subscriber` is registered for `subscriber_key`
+    pub fn subscribe(
+        &self,
+        pubkey: warp_protocol::PublicKey,
+        subscriber: SocketAddr,
+        subscriber_key: &warp_protocol::PublicKey,
+        now: Instant,
+    ) {
+        let mut shard = self.shard(&subscriber).write().unwrap();
+        if shard.get_pubkey(&subscriber).as_ref() == Some(subscriber_key) {
+            shard.subscribe(pubkey, subscriber, now);
+        }
+    }
+
+    pub fn get_subscribers(
+        &self,
+        pubkey: &warp_protocol::PublicKey,
+        now: Instant,
+    ) -> Vec<(SocketAddr, warp_protocol::PublicKey)> {
+        self.read()
+            .flat_map(|shard| shard.get_subscribers(pubkey, now))
+            .collect()
+    }
+
+    pub fn deregister_client(&self, pubkey: &warp_protocol::PublicKey, address: SocketAddr) -> bool {
+        self.shard(&address).write().unwrap().deregister_client(pubkey, address)
+    }
+
+    pub fn retain_clients(&self, keep: impl Fn(&warp_protocol::PublicKey) -> bool) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.write().unwrap().retain_clients(&keep))
+            .sum()
+    }
+
+    pub fn get_addresses(&self, pubkey: &warp_protocol::PublicKey, now: Instant) -> Vec<SocketAddr> {
+        self.read().flat_map(|shard| shard.get_addresses(pubkey, now)).collect()
+    }
+
+    pub fn get_host_candidates(&self, pubkey: &warp_protocol::PublicKey, now: Instant) -> Vec<SocketAddr> {
+        let mut candidates: Vec<SocketAddr> = self
+            .read()
+            .flat_map(|shard| shard.get_host_candidates(pubkey, now))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    /// Whether `pubkey` has an unexpired address in any shard, had one until lately, or neither
+    pub fn peer_status(&self, pubkey: &warp_protocol::PublicKey, now: Instant) -> warp_protocol::messages::PeerStatus {
+        use warp_protocol::messages::PeerStatus;
+        self.read().map(|shard| shard.peer_status(pubkey, now)).fold(
+            PeerStatus::Unknown,
+            |status, shard_status| match (status, shard_status) {
+                (PeerStatus::Registered, _) | (_, PeerStatus::Registered) => PeerStatus::Registered,
+                (PeerStatus::Expired, _) | (_, PeerStatus::Expired) => PeerStatus::Expired,
+                _ => PeerStatus::Unknown,
+            },
+        )
+    }
+
+    pub fn get_pubkey(&self, address: &SocketAddr) -> Option<warp_protocol::PublicKey> {
+        self.shard(address).read().unwrap().get_pubkey(address)
+    }
+
+    /// Number of distinct public keys with at least one registered address; a public key's addresses may be spread
+    /// over several shards, so this goes through all of them
+    pub fn client_count(&self) -> usize {
+        let mut pubkeys = std::collections::BTreeSet::new();
+        for shard in self.read() {
+            pubkeys.extend(shard.pubkey_to_addresses.keys().copied());
+        }
+        pubkeys.len()
+    }
+
+    pub fn address_count(&self) -> usize {
+        self.read().map(|shard| shard.address_count()).sum()
+    }
+
+    pub fn registrations(&self, now: Instant) -> Vec<Registration> {
+        self.read().flat_map(|shard| shard.registrations(now)).collect()
+    }
+
+    pub fn merge(&self, registration: Registration, now: Instant) -> Option<Registered> {
+        let (pubkey, address) = (registration.pubkey, registration.address);
+        let new = self.shard(&address).write().unwrap().merge(registration, now)?;
+        let evicted = if new { self.evict(&pubkey, address) } else { Vec::new() };
+        Some(Registered { new, evicted })
+    }
+
+    pub fn snapshot(&self, now: Instant) -> Snapshot {
+        let mut snapshot = Snapshot {
+            saved_at: SystemTime::now(),
+            registrations: Vec::new(),
+            subscriptions: Vec::new(),
+        };
+        for shard in self.read() {
+            let shard = shard.snapshot(now);
+            snapshot.registrations.extend(shard.registrations);
+            snapshot.subscriptions.extend(shard.subscriptions);
+        }
+        snapshot
+    }
+
+    pub fn restore(&self, snapshot: Snapshot, now: Instant) -> usize {
+        let mut shards: Vec<Snapshot> = (0..self.shards.len())
+            .map(|_| Snapshot {
+                saved_at: snapshot.saved_at,
+                registrations: Vec::new(),
+                subscriptions: Vec::new(),
+            })
+            .collect();
+        for registration in snapshot.registrations {
+            shards[self.index(&registration.address)]
+                .registrations
+                .push(registration);
+        }
+        for subscription in snapshot.subscriptions {
+            shards[self.index(&subscription.subscriber)]
+                .subscriptions
+                .push(subscription);
+        }
+        self.shards
+            .iter()
+            .zip(shards)
+            .map(|(shard, snapshot)| shard.write().unwrap().restore(snapshot, now))
+            .sum()
+    }
+
+    /// Remove expired registrations and subscriptions a shard at a time; returns how many addresses expired
+    pub fn garbage_collect(&self, now: Instant) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.write().unwrap().garbage_collect(now))
+            .sum()
     }
 }
 
@@ -187,6 +685,24 @@ mod tests {
         assert!(addresses.contains(&addr2));
     }
 
+    #[test]
+    fn test_client_and_address_counts() {
+        let mut store = create_test_store();
+        let pubkey1 = create_test_pubkey(1);
+        let pubkey2 = create_test_pubkey(2);
+        let now = Instant::now();
+
+        store.register_client(pubkey1, create_test_address(8080), now);
+        store.register_client(pubkey1, create_test_address(8081), now);
+        store.register_client(pubkey2, create_test_address(8082), now);
+        assert_eq!(store.client_count(), 2);
+        assert_eq!(store.address_count(), 3);
+
+        store.deregister_client(&pubkey2, create_test_address(8082));
+        assert_eq!(store.client_count(), 1);
+        assert_eq!(store.address_count(), 2);
+    }
+
     #[test]
     fn test_register_duplicate_address_same_pubkey() {
         let mut store = create_test_store();
@@ -215,7 +731,7 @@ mod tests {
         store.register_client(pubkey2, address, now);
 
         // Address should be removed from first pubkey and added to second
-        assert!(store.pubkey_to_addresses.get(&pubkey1).is_none());
+        assert!(!store.pubkey_to_addresses.contains_key(&pubkey1));
         assert!(store.pubkey_to_addresses.get(&pubkey2).unwrap().contains(&address));
         assert_eq!(store.address_to_pubkey.get(&address), Some(&pubkey2));
     }
@@ -387,7 +903,7 @@ mod tests {
         // Verify specific mappings
         assert_eq!(store.get_pubkey(&addr1), Some(pubkey1));
         assert_eq!(store.get_pubkey(&addr2), Some(pubkey1));
-        assert!(store.pubkey_to_addresses.get(&pubkey2).is_none());
+        assert!(!store.pubkey_to_addresses.contains_key(&pubkey2));
     }
 
     #[test]
@@ -413,6 +929,32 @@ mod tests {
         assert_eq!(addresses.len(), 1);
     }
 
+    #[test]
+    fn test_host_candidates_follow_registrations() {
+        let mut store = create_test_store();
+        let pubkey = create_test_pubkey(1);
+        let (nat_address, other_nat_address) = (create_test_address(8080), create_test_address(8081));
+        let lan_address: SocketAddr = "192.168.1.2:4000".parse().unwrap();
+        let now = Instant::now();
+
+        // Only registered addresses have candidates
+        store.set_host_candidates(nat_address, &[lan_address]);
+        store.register_client(pubkey, nat_address, now);
+        assert!(store.get_host_candidates(&pubkey, now).is_empty());
+
+        store.set_host_candidates(nat_address, &[lan_address]);
+        store.register_client(pubkey, other_nat_address, now);
+        store.set_host_candidates(other_nat_address, &[lan_address]);
+        assert_eq!(store.get_host_candidates(&pubkey, now), vec![lan_address]);
+
+        // A registration without candidates clears them, and so does deregistering
+        store.set_host_candidates(nat_address, &[]);
+        assert_eq!(store.get_host_candidates(&pubkey, now), vec![lan_address]);
+        store.deregister_client(&pubkey, other_nat_address);
+        assert!(store.get_host_candidates(&pubkey, now).is_empty());
+        assert!(store.address_host_candidates.is_empty());
+    }
+
     #[test]
     fn test_deregister_client_existing_address() {
         let mut store = create_test_store();
@@ -430,7 +972,7 @@ mod tests {
 
         // Verify complete removal
         assert_eq!(store.get_pubkey(&address), None);
-        assert!(store.pubkey_to_addresses.get(&pubkey).is_none());
+        assert!(!store.pubkey_to_addresses.contains_key(&pubkey));
         assert!(!store.address_last_seen.contains_key(&address));
     }
 
@@ -522,4 +1064,210 @@ mod tests {
         assert_eq!(store.get_pubkey(&addr2), Some(pubkey1));
         assert_eq!(store.get_pubkey(&addr3), Some(pubkey2));
     }
+
+    #[test]
+    fn test_subscribers_follow_registrations() {
+        let mut store = create_test_store();
+        let peer = create_test_pubkey(1);
+        let subscriber = create_test_pubkey(2);
+        let now = Instant::now();
+
+        assert!(store.register_client(peer, create_test_address(8080), now));
+        assert!(!store.register_client(peer, create_test_address(8080), now));
+
+        // Subscribers only hear of new addresses while they are registered themselves
+        store.subscribe(peer, create_test_address(9090), now);
+        assert!(store.get_subscribers(&peer, now).is_empty());
+        store.register_client(subscriber, create_test_address(9090), now);
+        assert_eq!(
+            store.get_subscribers(&peer, now),
+            vec![(create_test_address(9090), subscriber)]
+        );
+        assert!(store.get_subscribers(&subscriber, now).is_empty());
+
+        // And until they stop renewing their subscription
+        let later = now + Duration::from_secs(61);
+        store.register_client(subscriber, create_test_address(9090), later);
+        assert!(store.get_subscribers(&peer, later).is_empty());
+        store.garbage_collect(later);
+        assert!(store.subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_restores_unexpired_entries() {
+        let mut store = create_test_store();
+        let fresh = create_test_pubkey(1);
+        let stale = create_test_pubkey(2);
+        let registered = Instant::now();
+        let saved = registered + Duration::from_secs(50);
+
+        store.register_client(stale, create_test_address(8081), registered);
+        store.register_client(fresh, create_test_address(8080), saved);
+        store.set_host_candidates(create_test_address(8080), &[create_test_address(5000)]);
+        store.subscribe(stale, create_test_address(8080), saved);
+        let mut snapshot = store.snapshot(saved);
+        // warp-map was down for 20 seconds, which expires the registration that was already 50 seconds old
+        snapshot.saved_at -= Duration::from_secs(20);
+
+        let encoded = bincode::encode_to_vec(&snapshot, bincode::config::standard()).unwrap();
+        let (decoded, _): (Snapshot, usize) =
+            bincode::decode_from_slice(&encoded, bincode::config::standard()).unwrap();
+        let mut restored = create_test_store();
+        let now = Instant::now();
+        assert_eq!(restored.restore(decoded, now), 1);
+        assert_eq!(restored.get_addresses(&fresh, now), vec![create_test_address(8080)]);
+        assert_eq!(
+            restored.get_host_candidates(&fresh, now),
+            vec![create_test_address(5000)]
+        );
+        assert!(restored.get_addresses(&stale, now).is_empty());
+        assert_eq!(
+            restored.get_subscribers(&stale, now),
+            vec![(create_test_address(8080), fresh)]
+        );
+    }
+
+    #[test]
+    fn test_merge_keeps_latest_registration() {
+        let mut store = create_test_store();
+        let pubkey1 = create_test_pubkey(1);
+        let pubkey2 = create_test_pubkey(2);
+        let address = create_test_address(8080);
+        let now = Instant::now() + Duration::from_secs(60);
+        let registration = |pubkey, age| Registration {
+            pubkey,
+            address,
+            age: Duration::from_secs(age),
+            host_candidates: vec![],
+        };
+
+        assert_eq!(store.merge(registration(pubkey1, 10), now), Some(true));
+        // Renewed before the one already known, or expired
+        assert_eq!(store.merge(registration(pubkey2, 20), now), None);
+        assert_eq!(store.merge(registration(pubkey1, 60), now), None);
+        assert_eq!(store.get_pubkey(&address), Some(pubkey1));
+
+        assert_eq!(store.merge(registration(pubkey1, 5), now), Some(false));
+        assert_eq!(store.merge(registration(pubkey2, 1), now), Some(true));
+        assert_eq!(store.get_pubkey(&address), Some(pubkey2));
+        assert!(store.get_addresses(&pubkey1, now).is_empty());
+        assert_eq!(store.registrations(now), vec![registration(pubkey2, 1)]);
+    }
+
+    #[test]
+    fn test_retain_clients_removes_every_address_of_others() {
+        let mut store = ClientStore::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let (kept, removed) = (create_test_pubkey(1), create_test_pubkey(2));
+        store.register_client(kept, create_test_address(8001), now);
+        store.register_client(removed, create_test_address(8002), now);
+        store.register_client(removed, create_test_address(8003), now);
+        store.subscribe(removed, create_test_address(8001), now);
+
+        assert_eq!(store.retain_clients(|pubkey| *pubkey == kept), 2);
+        assert_eq!(store.get_addresses(&kept, now), vec![create_test_address(8001)]);
+        assert!(store.get_addresses(&removed, now).is_empty());
+        assert_eq!(store.get_pubkey(&create_test_address(8002)), None);
+        assert!(store.get_subscribers(&removed, now).is_empty());
+    }
+
+    #[test]
+    fn test_sharded_store_spreads_a_client_over_shards() {
+        let store = ShardedClientStore::new(Duration::from_secs(60), 4);
+        let now = Instant::now();
+        let (pubkey1, pubkey2) = (create_test_pubkey(1), create_test_pubkey(2));
+        let candidate = create_test_address(9000);
+        for port in 8000..8016 {
+            assert!(
+                store
+                    .register_client(pubkey1, create_test_address(port), &[candidate], now)
+                    .new
+            );
+        }
+        store.register_client(pubkey2, create_test_address(8100), &[], now);
+        store.subscribe(pubkey1, create_test_address(8100), &pubkey2, now);
+        // Only a registered address may subscribe
+        store.subscribe(pubkey1, create_test_address(8200), &pubkey2, now);
+
+        assert_eq!(store.get_addresses(&pubkey1, now).len(), 16);
+        assert_eq!(store.get_host_candidates(&pubkey1, now), vec![candidate]);
+        assert_eq!(store.client_count(), 2);
+        assert_eq!(store.address_count(), 17);
+        assert_eq!(
+            store.get_subscribers(&pubkey1, now),
+            vec![(create_test_address(8100), pubkey2)]
+        );
+
+        let restored = ShardedClientStore::new(Duration::from_secs(60), 3);
+        assert_eq!(restored.restore(store.snapshot(now), now), 17);
+        assert_eq!(restored.get_pubkey(&create_test_address(8007)), Some(pubkey1));
+        assert_eq!(restored.get_subscribers(&pubkey1, now).len(), 1);
+
+        assert_eq!(store.garbage_collect(now + Duration::from_secs(60)), 17);
+        assert_eq!(store.client_count(), 0);
+    }
+
+    #[test]
+    fn test_peer_status_tells_expired_from_unknown() {
+        use warp_protocol::messages::PeerStatus;
+        let mut store = create_test_store();
+        let (pubkey, never_seen) = (create_test_pubkey(1), create_test_pubkey(2));
+        let start = Instant::now();
+        store.register_client(pubkey, create_test_address(8080), start);
+        assert_eq!(store.peer_status(&pubkey, start), PeerStatus::Registered);
+        assert_eq!(store.peer_status(&never_seen, start), PeerStatus::Unknown);
+
+        // Expired, whether or not it has been collected yet
+        let later = start + Duration::from_secs(61);
+        assert_eq!(store.peer_status(&pubkey, later), PeerStatus::Expired);
+        store.garbage_collect(later);
+        assert_eq!(store.peer_status(&pubkey, later), PeerStatus::Expired);
+
+        // Until it has been gone for long enough to be forgotten
+        let much_later = start + DEPARTED_REMEMBERED_FOR + Duration::from_secs(1);
+        store.garbage_collect(much_later);
+        assert_eq!(store.peer_status(&pubkey, much_later), PeerStatus::Unknown);
+
+        // Deregistering its last address counts too
+        store.register_client(pubkey, create_test_address(8080), much_later);
+        store.deregister_client(&pubkey, create_test_address(8080));
+        assert_eq!(store.peer_status(&pubkey, much_later), PeerStatus::Expired);
+    }
+
+    #[test]
+    fn test_addresses_beyond_the_maximum_evict_the_oldest() {
+        let store = ShardedClientStore::new(Duration::from_secs(60), 4);
+        store.set_max_addresses(3);
+        let start = Instant::now();
+        let pubkey = create_test_pubkey(1);
+        for port in 8000..8003 {
+            let now = start + Duration::from_secs(u64::from(port - 8000));
+            assert!(store
+                .register_client(pubkey, create_test_address(port), &[], now)
+                .evicted
+                .is_empty());
+        }
+        // Renewing the oldest makes the next oldest the one to go
+        store.register_client(pubkey, create_test_address(8000), &[], start + Duration::from_secs(3));
+
+        let now = start + Duration::from_secs(4);
+        assert_eq!(
+            store.register_client(pubkey, create_test_address(8003), &[], now),
+            Registered {
+                new: true,
+                evicted: vec![create_test_address(8001)],
+            }
+        );
+        let mut addresses = store.get_addresses(&pubkey, now);
+        addresses.sort();
+        assert_eq!(
+            addresses,
+            vec![
+                create_test_address(8000),
+                create_test_address(8002),
+                create_test_address(8003)
+            ]
+        );
+        assert_eq!(store.get_pubkey(&create_test_address(8001)), None);
+    }
 }
@@ -0,0 +1,144 @@
+//! Metrics exported by the mapping server; see `warp_metrics` for the naming conventions
+
+use std::sync::LazyLock;
+use warp_metrics::{Counter, Gauge, Histogram};
+
+/// Requests handled, labelled by request kind (`register`, `mapping`, `deregister`, `relay` or `sync`)
+pub fn requests(kind: &str) -> Counter {
+    warp_metrics::global().counter_with_labels(
+        "warp_map_requests_total",
+        "Requests successfully decrypted, by kind",
+        &[("kind", kind)],
+    )
+}
+
+/// Datagrams dropped unread, labelled by why (`rate_limited` or `busy`)
+pub fn dropped(reason: &str) -> Counter {
+    warp_metrics::global().counter_with_labels(
+        "warp_map_dropped_datagrams_total",
+        "Datagrams dropped before being handled, by reason",
+        &[("reason", reason)],
+    )
+}
+
+pub static COOKIE_CHALLENGES: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_map_cookie_challenges_total",
+        "Cookies sent to unknown sources instead of handling what they sent",
+    )
+});
+
+pub static UNAUTHORIZED_REGISTRATIONS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_map_unauthorized_registrations_total",
+        "Registrations turned down because the public key isn't allowed",
+    )
+});
+
+/// ErrorResponses sent, labelled by error code
+pub fn error_responses(code: warp_protocol::messages::ErrorCode) -> Counter {
+    warp_metrics::global().counter_with_labels(
+        "warp_map_error_responses_total",
+        "Requests answered with an ErrorResponse, by code",
+        &[("code", format!("{code:?}").as_str())],
+    )
+}
+
+pub static PROCESSING_ERRORS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_map_processing_errors_total",
+        "Datagrams that could not be decoded, decrypted or handled",
+    )
+});
+
+pub static DECRYPTION_FAILURES: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_map_decryption_failures_total",
+        "Messages that failed to decrypt, from clients with the wrong key or not clients at all",
+    )
+});
+
+pub static SEND_ERRORS: LazyLock<Counter> =
+    LazyLock::new(|| warp_metrics::global().counter("warp_map_send_errors_total", "Responses that failed to send"));
+
+pub static PROCESSING_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    warp_metrics::global().histogram(
+        "warp_map_processing_seconds",
+        "Time spent handling each received datagram, including sending the response",
+        warp_metrics::LATENCY_BUCKETS,
+    )
+});
+
+pub static RELAYED_BYTES: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_map_relayed_bytes_total",
+        "Bytes of relayed payloads passed on to clients, counting each address they went to",
+    )
+});
+
+pub static RELAY_QUOTA_DROPPED_BYTES: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_map_relay_quota_dropped_bytes_total",
+        "Bytes not relayed as the pair of clients had used up their relay quota",
+    )
+});
+
+pub static AUDIT_EVENTS_DROPPED: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_map_audit_events_dropped_total",
+        "Audit events not written, as the audit log fell too far behind or couldn't be written to",
+    )
+});
+
+pub static EVICTED_ADDRESSES: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_map_evicted_addresses_total",
+        "Registered addresses evicted to keep a public key within the maximum number of addresses",
+    )
+});
+
+pub static PUSHED_MAPPINGS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_map_pushed_mappings_total",
+        "Mapping responses sent unasked to subscribers when a client registered a new address",
+    )
+});
+
+pub static GC_RUNS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter("warp_map_gc_runs_total", "Garbage collections of the client store")
+});
+
+pub static GC_EXPIRED_ADDRESSES: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_map_gc_expired_addresses_total",
+        "Registered addresses removed by garbage collection as they had expired",
+    )
+});
+
+pub static GC_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    warp_metrics::global().histogram(
+        "warp_map_gc_seconds",
+        "Time spent on each garbage collection of the client store, which locks a shard at a time",
+        warp_metrics::LATENCY_BUCKETS,
+    )
+});
+
+pub static CLIENTS: LazyLock<Gauge> = LazyLock::new(|| {
+    warp_metrics::global().gauge(
+        "warp_map_clients",
+        "Public keys with at least one registered address, as of the last garbage collection",
+    )
+});
+
+pub static ADDRESSES: LazyLock<Gauge> = LazyLock::new(|| {
+    warp_metrics::global().gauge(
+        "warp_map_addresses",
+        "Registered addresses across all clients, as of the last garbage collection",
+    )
+});
+
+// Counting clients goes through every shard, so it is left to garbage collection rather than done on each request
+pub fn record_client_store(store: &crate::map::ShardedClientStore) {
+    CLIENTS.set(store.client_count() as i64);
+    ADDRESSES.set(store.address_count() as i64);
+}
@@ -0,0 +1,1649 @@
+use crate::{access, audit, limits, map, metrics};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{error, info};
+use warp_protocol::codec::Message;
+use warp_protocol::cookie::{Cookie, CookieJar};
+
+// Large enough for any UDP datagram, as relayed payloads are as large as the tunnel payloads they carry
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+// How often the client store is saved to the state file, if there is one
+const SNAPSHOT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+// How often registrations are sent to federated warp-maps; a client registered with another is found through this one
+// within this long
+const FEDERATION_SYNC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+// Datagrams handled at once by default; those that arrive with this many still being handled are dropped
+const DEFAULT_MAX_IN_FLIGHT: usize = 1024;
+
+// A client registers each of its sockets, usually one per interface, so this leaves room for many interfaces and for
+// addresses that have changed but not yet expired
+const DEFAULT_MAX_ADDRESSES_PER_CLIENT: usize = 32;
+
+// How often the rate limiter forgets sources that have been quiet long enough for their buckets to refill
+const LIMITER_PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+// How often the allowed and denied keys are reread
+const ACCESS_RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Registrations per MapSync, keeping each well within a datagram that needn't be fragmented much
+const REGISTRATIONS_PER_SYNC: usize = 32;
+
+// How many shards the client store is split into, each locked on its own; a registration only holds up the requests for
+// the addresses that share its shard
+const CLIENT_STORE_SHARDS: usize = 16;
+
+// TCP connections served at once; those accepted beyond this are closed straight away
+const MAX_TCP_CONNECTIONS: usize = 4096;
+
+// How long a TCP connection can go without a frame before it is closed; clients register more often than this
+const TCP_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
+// Datagrams queued for each TCP connection; those sent to a connection with this many still to write are dropped
+const TCP_SEND_QUEUE: usize = 64;
+
+pub struct WarpMapServer {
+    private_key: warp_protocol::PrivateKey,
+    // The first of each address family is where datagrams to that family are sent from
+    bind_addrs: Vec<SocketAddr>,
+    // A second address, on another port, that only answers NatProbeRequests
+    probe_addr: Option<SocketAddr>,
+    // Where the control plane is also served over TCP, for clients whose networks block UDP
+    tcp_addr: Option<SocketAddr>,
+    client_store: Arc<map::ShardedClientStore>,
+    relay: bool,
+    // Set if the bytes relayed from each client to each other client are limited
+    relay_quota: Option<Arc<std::sync::Mutex<limits::RelayQuota>>>,
+    state_file: Option<std::path::PathBuf>,
+    // The addresses and public keys of the warp-maps this one shares its registrations with
+    federation: Arc<Vec<(SocketAddr, warp_protocol::PublicKey)>>,
+    // Datagrams per second and burst allowed from each source IP address, if they are limited
+    rate_limit: Option<(u32, u32)>,
+    max_in_flight: usize,
+    // Set if sources that aren't registered must send back a cookie before anything they send is decrypted
+    cookies: Option<Arc<CookieJar>>,
+    access_files: access::AccessFiles,
+    access: Arc<std::sync::RwLock<access::Access>>,
+    // Set if registrations and mapping requests are recorded in an audit log, with what writes it until `run` takes it
+    audit: Option<audit::AuditLog>,
+    audit_writer: std::sync::Mutex<Option<audit::AuditWriter>>,
+}
+
+// A request turned down rather than failed, answered with an ErrorResponse saying why
+#[derive(Debug)]
+struct Rejected {
+    code: warp_protocol::messages::ErrorCode,
+    reason: &'static str,
+    request_timestamp: Option<std::time::SystemTime>,
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.reason)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+// The sockets warp-map listens on, with their local addresses, and the TCP connections clients have made to it
+struct Sockets {
+    sockets: Vec<(SocketAddr, Arc<tokio::net::UdpSocket>)>,
+    // The queue of datagrams to write to each connection, by its remote address
+    connections: std::sync::Mutex<std::collections::HashMap<SocketAddr, tokio::sync::mpsc::Sender<Vec<u8>>>>,
+}
+
+impl Sockets {
+    fn bind(addresses: &[SocketAddr]) -> std::io::Result<Self> {
+        let sockets = addresses
+            .iter()
+            .map(|address| {
+                let socket = socket2::Socket::new(
+                    socket2::Domain::for_address(*address),
+                    socket2::Type::DGRAM,
+                    Some(socket2::Protocol::UDP),
+                )?;
+                if address.is_ipv6() {
+                    socket.set_only_v6(true)?;
+                }
+                socket.set_nonblocking(true)?;
+                socket.bind(&(*address).into())?;
+                let socket = tokio::net::UdpSocket::from_std(socket.into())?;
+                let local_addr = socket.local_addr()?;
+                info!("Listening on: {}", local_addr);
+                Ok((local_addr, Arc::new(socket)))
+            })
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self {
+            sockets,
+            connections: std::sync::Mutex::new(std::collections::HashMap::new()),
+        })
+    }
+
+    // Send each datagram over the TCP connection from the address it is for if there is one, and otherwise from the first
+    // socket of the family of the address it is for, as clients registered over
+    // IPv6 can only be reached from an IPv6 socket and vice versa
+    async fn send(&self, datagrams: Vec<(SocketAddr, Vec<u8>)>) {
+        for (to, datagram) in datagrams {
+            let connection = self.connections.lock().unwrap().get(&to).cloned();
+            if let Some(connection) = connection {
+                if connection.try_send(datagram).is_err() {
+                    metrics::SEND_ERRORS.inc();
+                    error!("Failed to send to {}: its connection is backed up or closed", to);
+                }
+                continue;
+            }
+            let Some((_, socket)) = self
+                .sockets
+                .iter()
+                .find(|(local_addr, _)| local_addr.is_ipv4() == to.is_ipv4())
+            else {
+                metrics::SEND_ERRORS.inc();
+                error!("Failed to send to {}: not listening on any address of its family", to);
+                continue;
+            };
+            if let Err(e) = socket.send_to(&datagram, to).await {
+                metrics::SEND_ERRORS.inc();
+                error!("Failed to send to {}: {}", to, e);
+            }
+        }
+    }
+}
+
+// What handling a datagram needs from the server
+#[derive(Clone)]
+struct RxContext {
+    private_key: warp_protocol::PrivateKey,
+    client_store: Arc<map::ShardedClientStore>,
+    relay: bool,
+    relay_quota: Option<Arc<std::sync::Mutex<limits::RelayQuota>>>,
+    federation: Arc<Vec<(SocketAddr, warp_protocol::PublicKey)>>,
+    cookies: Option<Arc<CookieJar>>,
+    access: Arc<std::sync::RwLock<access::Access>>,
+    // The port of the probe address, told to clients as they register
+    probe_port: Option<u16>,
+    // Set when handling what came in on the probe address
+    probe_only: bool,
+    // Set when handling what came over a TCP connection, whose source can't be spoofed
+    connected: bool,
+    audit: Option<audit::AuditLog>,
+}
+//
+// #[derive(bincode::Decode)]
+// struct RegistrationAad {
+//     #[bincode(with_serde)]
+//     public_key: warp_protocol::PublicKey,
+// }
+
+impl WarpMapServer {
+    pub fn new(
+        private_key: warp_protocol::PrivateKey,
+        bind_addr: SocketAddr,
+        client_expiry: std::time::Duration,
+    ) -> Self {
+        Self {
+            private_key,
+            bind_addrs: vec![bind_addr],
+            probe_addr: None,
+            tcp_addr: None,
+            client_store: Arc::new({
+                let store = map::ShardedClientStore::new(client_expiry, CLIENT_STORE_SHARDS);
+                store.set_max_addresses(DEFAULT_MAX_ADDRESSES_PER_CLIENT);
+                store
+            }),
+            relay: false,
+            relay_quota: None,
+            state_file: None,
+            federation: Arc::new(Vec::new()),
+            rate_limit: None,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            cookies: None,
+            access_files: access::AccessFiles::default(),
+            access: Arc::new(std::sync::RwLock::new(access::Access::default())),
+            audit: None,
+            audit_writer: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Also listen on `address`, such as an IPv6 address alongside the IPv4 one, so clients can register addresses of
+    /// both families; an IPv6 address only takes IPv6, so it can share the port of a wildcard IPv4 address
+    pub fn with_bind(mut self, address: SocketAddr) -> Self {
+        self.bind_addrs.push(address);
+        self
+    }
+
+    /// Let each public key have at most `max` addresses registered, evicting those it registered from longest ago, so
+    /// that registering from ever more ports can't grow the client store without bound
+    pub fn with_max_addresses_per_client(self, max: usize) -> Self {
+        self.client_store.set_max_addresses(max);
+        self
+    }
+
+    /// Answer NatProbeRequests on `address` as well, on another port than the one clients register with, so that they can
+    /// tell whether their NATs map them to the same address whoever they send to; `address` needs a fixed port, as
+    /// that is what clients are told to probe
+    pub fn with_nat_probe(mut self, address: SocketAddr) -> Self {
+        self.probe_addr = Some(address);
+        self
+    }
+
+    /// Serve the control plane over TCP on `address` as well, for clients whose networks block outbound UDP; each frame
+    /// is handled like a datagram from the connection's remote address, and whatever is sent to that address while the
+    /// connection is open goes back over it
+    pub fn with_tcp(mut self, address: SocketAddr) -> Self {
+        self.tcp_addr = Some(address);
+        self
+    }
+
+    /// Pass RelayPayloads on between registered clients, for peers that can't reach each other directly
+    pub fn with_relay(mut self) -> Self {
+        self.relay = true;
+        self
+    }
+
+    /// Relay at most `rate` bytes a second, in bursts of up to `burst`, from any one client to any other, dropping the
+    /// rest, so that no pair of clients can take all of warp-map's bandwidth; a burst holds a datagram at least
+    pub fn with_relay_quota(mut self, rate: u32, burst: u32) -> Self {
+        let burst = burst.max(MAX_DATAGRAM_SIZE as u32);
+        self.relay_quota = Some(Arc::new(std::sync::Mutex::new(limits::RelayQuota::new(rate, burst))));
+        self
+    }
+
+    /// Save the client store to `path` now and then, and start from what was saved there last
+    pub fn with_state_file(mut self, path: std::path::PathBuf) -> Self {
+        self.state_file = Some(path);
+        self
+    }
+
+    /// Share registrations with the warp-map at `address` with public key `public_key`, which should be federated with
+    /// this one too, so that clients registered with either can be found through both
+    pub fn with_federated_map(mut self, address: SocketAddr, public_key: warp_protocol::PublicKey) -> Self {
+        Arc::make_mut(&mut self.federation).push((address, public_key));
+        self
+    }
+
+    /// Drop datagrams from any one IP address beyond `rate` a second, after a burst of `burst`
+    pub fn with_rate_limit(mut self, rate: u32, burst: u32) -> Self {
+        self.rate_limit = Some((rate, burst));
+        self
+    }
+
+    /// Drop datagrams that arrive while `max_in_flight` are still being handled, rather than queueing ever more work
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    /// Answer datagrams from sources that aren't registered with a cookie for their address, and only decrypt what they
+    /// send once they send it back, so that datagrams from spoofed addresses cost no more than a hash
+    pub fn with_cookies(mut self) -> Self {
+        self.cookies = Some(Arc::new(CookieJar::default()));
+        self
+    }
+
+    /// Only let the public keys listed in the file at `path` register, rereading it as it changes
+    pub fn with_allowed_keys(mut self, path: std::path::PathBuf) -> Self {
+        self.access_files.allowed = Some(path);
+        self
+    }
+
+    /// Turn away the public keys listed in the file at `path`, even if they are allowed, rereading it as it changes
+    pub fn with_denied_keys(mut self, path: std::path::PathBuf) -> Self {
+        self.access_files.denied = Some(path);
+        self
+    }
+
+    /// Append each registration, deregistration, eviction and mapping request to `path`, one JSON object per line with
+    /// the client's public key and address and when it happened, moving the log to `<path>.1` once it reaches
+    /// `max_bytes` and keeping `max_files` of those
+    pub fn with_audit_log(mut self, path: std::path::PathBuf, max_bytes: Option<u64>, max_files: usize) -> Self {
+        let (log, writer) = audit::AuditLog::new(path, audit::Rotation { max_bytes, max_files });
+        self.audit = Some(log);
+        self.audit_writer = std::sync::Mutex::new(Some(writer));
+        self
+    }
+
+    fn rx_context(&self) -> RxContext {
+        RxContext {
+            private_key: self.private_key.clone(),
+            client_store: self.client_store.clone(),
+            relay: self.relay,
+            relay_quota: self.relay_quota.clone(),
+            federation: self.federation.clone(),
+            cookies: self.cookies.clone(),
+            access: self.access.clone(),
+            probe_port: self.probe_addr.map(|address| address.port()).filter(|&port| port != 0),
+            probe_only: false,
+            connected: false,
+            audit: self.audit.clone(),
+        }
+    }
+
+    /// Read the allowed and denied keys, and drop the registrations of keys no longer allowed; until the files can be
+    /// read, nobody is allowed if there's an allowlist, as otherwise everyone would be
+    pub async fn reload_access(&self) -> anyhow::Result<()> {
+        Self::update_access(&self.access_files, &self.access, &self.client_store).await
+    }
+
+    async fn update_access(
+        files: &access::AccessFiles,
+        access: &std::sync::RwLock<access::Access>,
+        client_store: &map::ShardedClientStore,
+    ) -> anyhow::Result<()> {
+        let loaded = match files.load().await {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                if files.allowed.is_some() && *access.read().unwrap() == access::Access::default() {
+                    *access.write().unwrap() = access::Access::deny_all();
+                }
+                return Err(e);
+            }
+        };
+        if *access.read().unwrap() == loaded {
+            return Ok(());
+        }
+        let removed = client_store.retain_clients(|pubkey| loaded.permits(pubkey));
+        metrics::record_client_store(client_store);
+        *access.write().unwrap() = loaded;
+        info!("Reloaded allowed and denied keys, dropping {} registrations", removed);
+        Ok(())
+    }
+
+    pub async fn run(&self) {
+        let sockets = Arc::new(Sockets::bind(&self.bind_addrs).unwrap());
+        let probe_sockets = self
+            .probe_addr
+            .map(|address| Arc::new(Sockets::bind(&[address]).unwrap()));
+        let tcp_listener = match self.tcp_addr {
+            Some(address) => {
+                let listener = tokio::net::TcpListener::bind(address).await.unwrap();
+                info!("Listening for TCP connections on: {}", listener.local_addr().unwrap());
+                Some(listener)
+            }
+            None => None,
+        };
+
+        if let Some(writer) = self.audit_writer.lock().unwrap().take() {
+            tokio::task::Builder::new()
+                .name("audit log writer")
+                .spawn(writer.run())
+                .unwrap();
+        }
+
+        if let Some(path) = &self.state_file {
+            // Start from the registrations saved before the last restart, so clients can find each other straight away
+            match map::Snapshot::load(path).await {
+                Ok(Some(snapshot)) => {
+                    let restored = self.client_store.restore(snapshot, Instant::now());
+                    metrics::record_client_store(&self.client_store);
+                    info!("Restored {} registrations from {}", restored, path.display());
+                }
+                Ok(None) => {}
+                Err(e) => error!("Failed to load the client store from {}: {}", path.display(), e),
+            }
+
+            let saver_store = self.client_store.clone();
+            let path = path.clone();
+            tokio::task::Builder::new()
+                .name("client store saver")
+                .spawn(async move {
+                    let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+                    loop {
+                        interval.tick().await;
+                        let snapshot = saver_store.snapshot(Instant::now());
+                        if let Err(e) = snapshot.save(&path).await {
+                            error!("Failed to save the client store to {}: {}", path.display(), e);
+                        }
+                    }
+                })
+                .unwrap();
+        }
+
+        if !self.access_files.is_empty() {
+            if let Err(e) = self.reload_access().await {
+                error!("Failed to load the allowed and denied keys: {}", e);
+            }
+            let files = self.access_files.clone();
+            let access = self.access.clone();
+            let access_store = self.client_store.clone();
+            tokio::task::Builder::new()
+                .name("access reloader")
+                .spawn(async move {
+                    let mut interval = tokio::time::interval(ACCESS_RELOAD_INTERVAL);
+                    loop {
+                        interval.tick().await;
+                        if let Err(e) = Self::update_access(&files, &access, &access_store).await {
+                            error!(
+                                "Failed to reload the allowed and denied keys, keeping the last ones: {}",
+                                e
+                            );
+                        }
+                    }
+                })
+                .unwrap();
+        }
+
+        if !self.federation.is_empty() {
+            let sync_sockets = sockets.clone();
+            let private_key = self.private_key.clone();
+            let sync_store = self.client_store.clone();
+            let federation = self.federation.clone();
+            tokio::task::Builder::new()
+                .name("federation sync")
+                .spawn(async move {
+                    let mut interval = tokio::time::interval(FEDERATION_SYNC_INTERVAL);
+                    loop {
+                        interval.tick().await;
+                        match Self::sync_datagrams(&private_key, &sync_store, &federation).await {
+                            Ok(datagrams) => sync_sockets.send(datagrams).await,
+                            Err(e) => error!("Failed to prepare registrations for federated warp-maps: {}", e),
+                        }
+                    }
+                })
+                .unwrap();
+        }
+
+        // Spawn garbage collection task
+        let gc_store = self.client_store.clone();
+        tokio::task::Builder::new()
+            .name("client store garbage collector")
+            .spawn(async move {
+                let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+                loop {
+                    interval.tick().await;
+                    let start_time = Instant::now();
+                    let expired = gc_store.garbage_collect(start_time);
+                    metrics::GC_SECONDS.observe_duration(start_time.elapsed());
+                    metrics::GC_RUNS.inc();
+                    metrics::GC_EXPIRED_ADDRESSES.add(expired as u64);
+                    metrics::record_client_store(&gc_store);
+                }
+            })
+            .unwrap();
+
+        // Shared by the sockets, so that no more are handled at once however many there are
+        let in_flight = Arc::new(tokio::sync::Semaphore::new(self.max_in_flight));
+        let receivers: Vec<_> = sockets
+            .sockets
+            .iter()
+            .map(|(_, socket)| {
+                self.spawn_receiver(self.rx_context(), socket.clone(), sockets.clone(), in_flight.clone())
+            })
+            .chain(probe_sockets.iter().flat_map(|probe_sockets| {
+                // Answered from the probe address, or they would look like they came from the usual one
+                probe_sockets.sockets.iter().map(|(_, socket)| {
+                    let context = RxContext {
+                        probe_only: true,
+                        ..self.rx_context()
+                    };
+                    self.spawn_receiver(context, socket.clone(), probe_sockets.clone(), in_flight.clone())
+                })
+            }))
+            .chain(tcp_listener.map(|listener| self.spawn_tcp_listener(listener, sockets.clone(), in_flight.clone())))
+            .collect();
+        for receiver in receivers {
+            if let Err(e) = receiver.await {
+                error!("Receiver stopped: {}", e);
+            }
+        }
+    }
+
+    // Receive on `socket` for good, handling each datagram on its own task and sending what that returns through
+    // `sockets`
+    fn spawn_receiver(
+        &self,
+        context: RxContext,
+        socket: Arc<tokio::net::UdpSocket>,
+        sockets: Arc<Sockets>,
+        in_flight: Arc<tokio::sync::Semaphore>,
+    ) -> tokio::task::JoinHandle<()> {
+        let rate_limit = self.rate_limit;
+        let local_addr = socket.local_addr().unwrap();
+        tokio::task::Builder::new()
+            .name(&format!("receiver on {local_addr}"))
+            .spawn(async move {
+                // Both checked before a task is spawned, so that dropping a datagram costs next to nothing
+                let mut limiter = rate_limit.map(|(rate, burst)| limits::SourceLimiter::new(rate, burst));
+                let mut last_limiter_prune = Instant::now();
+
+                let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+                loop {
+                    match socket.recv_from(&mut buf).await {
+                        Ok((len, address)) => {
+                            if let Some(limiter) = &mut limiter {
+                                let now = Instant::now();
+                                if now.duration_since(last_limiter_prune) >= LIMITER_PRUNE_INTERVAL {
+                                    limiter.prune(now);
+                                    last_limiter_prune = now;
+                                }
+                                if !limiter.allow(address.ip(), now) {
+                                    metrics::dropped("rate_limited").inc();
+                                    continue;
+                                }
+                            }
+                            let Ok(permit) = in_flight.clone().try_acquire_owned() else {
+                                metrics::dropped("busy").inc();
+                                continue;
+                            };
+
+                            let sockets = sockets.clone();
+                            let context = context.clone();
+                            let data = buf[..len].to_vec();
+
+                            let task_name = format!("Handle data from {address}");
+
+                            // TODO: I think spawning a new task for each message is overkill; do something better
+                            let spawn_result = tokio::task::Builder::new().name(&task_name).spawn(async move {
+                                let _permit = permit;
+                                let start_time = Instant::now();
+                                match Self::process_rx_buffer(&context, &data, &address).await {
+                                    Ok(datagrams) => sockets.send(datagrams).await,
+                                    Err(e) => {
+                                        metrics::PROCESSING_ERRORS.inc();
+                                        error!("Error processing message from {}: {}", address, e);
+                                    }
+                                }
+                                metrics::PROCESSING_SECONDS.observe_duration(start_time.elapsed());
+                            });
+                            match spawn_result {
+                                Ok(_) => {}
+                                Err(e) => {
+                                    error!("Error spawning task for message from {}: {}", address, e);
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            error!("Error receiving from socket: {}", e);
+                        }
+                    }
+                }
+            })
+            .unwrap()
+    }
+
+    // Accept connections on `listener` for good, serving each on its own task
+    fn spawn_tcp_listener(
+        &self,
+        listener: tokio::net::TcpListener,
+        sockets: Arc<Sockets>,
+        in_flight: Arc<tokio::sync::Semaphore>,
+    ) -> tokio::task::JoinHandle<()> {
+        let context = RxContext {
+            connected: true,
+            ..self.rx_context()
+        };
+        let rate_limit = self.rate_limit;
+        let connections = Arc::new(tokio::sync::Semaphore::new(MAX_TCP_CONNECTIONS));
+        let local_addr = listener.local_addr().unwrap();
+        tokio::task::Builder::new()
+            .name(&format!("TCP listener on {local_addr}"))
+            .spawn(async move {
+                loop {
+                    let (stream, address) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            error!("Error accepting a connection: {}", e);
+                            continue;
+                        }
+                    };
+                    let Ok(permit) = connections.clone().try_acquire_owned() else {
+                        metrics::dropped("too_many_connections").inc();
+                        continue;
+                    };
+                    let context = context.clone();
+                    let sockets = sockets.clone();
+                    let in_flight = in_flight.clone();
+                    let spawn_result = tokio::task::Builder::new()
+                        .name(&format!("Connection from {address}"))
+                        .spawn(async move {
+                            let _permit = permit;
+                            // Each connection comes from one address, so it has a bucket to itself
+                            let limiter = rate_limit.map(|(rate, burst)| limits::SourceLimiter::new(rate, burst));
+                            if let Err(e) =
+                                Self::serve_connection(&context, stream, address, &sockets, &in_flight, limiter).await
+                            {
+                                info!("Connection from {} closed: {}", address, e);
+                            }
+                        });
+                    if let Err(e) = spawn_result {
+                        error!("Error spawning task for the connection from {}: {}", address, e);
+                    }
+                }
+            })
+            .unwrap()
+    }
+
+    // Handle the frames that come over `stream` in turn until it closes or goes idle, writing back whatever is sent to
+    // `address` in the meantime
+    async fn serve_connection(
+        context: &RxContext,
+        stream: tokio::net::TcpStream,
+        address: SocketAddr,
+        sockets: &Sockets,
+        in_flight: &tokio::sync::Semaphore,
+        mut limiter: Option<limits::SourceLimiter>,
+    ) -> anyhow::Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Responses are small and shouldn't wait on the next one
+        stream.set_nodelay(true)?;
+        let (mut reader, mut writer) = stream.into_split();
+        let (sender, mut outbound) = tokio::sync::mpsc::channel::<Vec<u8>>(TCP_SEND_QUEUE);
+        sockets.connections.lock().unwrap().insert(address, sender.clone());
+
+        let writing = async move {
+            while let Some(datagram) = outbound.recv().await {
+                writer.write_all(&warp_protocol::codec::frame(&datagram)?).await?;
+            }
+            anyhow::Ok(())
+        };
+        let reading = async {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                let read = tokio::time::timeout(TCP_IDLE_TIMEOUT, async {
+                    let mut header = [0; warp_protocol::codec::FRAME_HEADER_SIZE];
+                    reader.read_exact(&mut header).await?;
+                    let length = warp_protocol::codec::frame_length(header);
+                    reader.read_exact(&mut buf[..length]).await?;
+                    std::io::Result::Ok(length)
+                })
+                .await;
+                let frame = match read {
+                    Ok(Ok(length)) => &buf[..length],
+                    Ok(Err(e)) => break Err(anyhow::Error::from(e)),
+                    Err(_) => break Err(anyhow::anyhow!("nothing received for {:?}", TCP_IDLE_TIMEOUT)),
+                };
+
+                if let Some(limiter) = &mut limiter {
+                    if !limiter.allow(address.ip(), Instant::now()) {
+                        metrics::dropped("rate_limited").inc();
+                        continue;
+                    }
+                }
+                let Ok(_permit) = in_flight.try_acquire() else {
+                    metrics::dropped("busy").inc();
+                    continue;
+                };
+                let start_time = Instant::now();
+                match Self::process_rx_buffer(context, frame, &address).await {
+                    Ok(datagrams) => sockets.send(datagrams).await,
+                    Err(e) => {
+                        metrics::PROCESSING_ERRORS.inc();
+                        error!("Error processing message from {}: {}", address, e);
+                    }
+                }
+                metrics::PROCESSING_SECONDS.observe_duration(start_time.elapsed());
+            }
+        };
+        let result = tokio::select! {
+            result = reading => result,
+            result = writing => result,
+        };
+
+        // Unless the address has since connected again
+        let mut connections = sockets.connections.lock().unwrap();
+        if connections
+            .get(&address)
+            .is_some_and(|connection| connection.same_channel(&sender))
+        {
+            connections.remove(&address);
+        }
+        result
+    }
+
+    /// Handle one datagram received from `from`, returning the datagrams to send and where to: the response to `from`,
+    /// if there is one, then any payloads relayed to other clients and mappings pushed to those following the client
+    ///
+    /// This is everything [`Self::run`] does per datagram apart from the socket I/O, so callers can drive the server
+    /// over their own transport.
+    pub async fn handle_datagram(&self, buf: &[u8], from: &SocketAddr) -> anyhow::Result<Vec<(SocketAddr, Vec<u8>)>> {
+        Self::process_rx_buffer(&self.rx_context(), buf, from).await
+    }
+
+    /// Handle one datagram received on the probe address from `from`, like [`Self::handle_datagram`]; the responses are
+    /// to be sent from the probe address
+    pub async fn handle_probe_datagram(
+        &self,
+        buf: &[u8],
+        from: &SocketAddr,
+    ) -> anyhow::Result<Vec<(SocketAddr, Vec<u8>)>> {
+        let context = RxContext {
+            probe_only: true,
+            ..self.rx_context()
+        };
+        Self::process_rx_buffer(&context, buf, from).await
+    }
+
+    /// A page listing every unexpired registration, one per line as the public key, the address and the seconds since
+    /// the client last registered from there, for operators to see who is registered
+    pub fn clients_page(&self) -> warp_metrics::exporter::Page {
+        let client_store = self.client_store.clone();
+        Arc::new(move || {
+            let client_store = client_store.clone();
+            Box::pin(async move {
+                let registrations = client_store.registrations(Instant::now());
+                registrations
+                    .iter()
+                    .map(|registration| {
+                        format!(
+                            "{} {} {}\n",
+                            warp_protocol::crypto::pubkey_to_string(&registration.pubkey),
+                            registration.address,
+                            registration.age.as_secs()
+                        )
+                    })
+                    .collect()
+            })
+        })
+    }
+
+    /// The MapSyncs to send to the federated warp-maps, and where to; [`Self::run`] sends them every
+    /// FEDERATION_SYNC_INTERVAL
+    pub async fn federation_datagrams(&self) -> anyhow::Result<Vec<(SocketAddr, Vec<u8>)>> {
+        Self::sync_datagrams(&self.private_key, &self.client_store, &self.federation).await
+    }
+
+    // Every unexpired registration, learned from other warp-maps too so that they needn't all be federated with each
+    // other, in MapSyncs sealed for each federated warp-map
+    async fn sync_datagrams(
+        private_key: &warp_protocol::PrivateKey,
+        client_store: &map::ShardedClientStore,
+        federation: &[(SocketAddr, warp_protocol::PublicKey)],
+    ) -> anyhow::Result<Vec<(SocketAddr, Vec<u8>)>> {
+        if federation.is_empty() {
+            return Ok(Vec::new());
+        }
+        let registrations = client_store.registrations(Instant::now());
+        let now = std::time::SystemTime::now();
+        let syncs = registrations
+            .chunks(REGISTRATIONS_PER_SYNC)
+            .map(|chunk| {
+                warp_protocol::messages::MapSync {
+                    registrations: chunk
+                        .iter()
+                        .map(|registration| warp_protocol::messages::SyncedRegistration {
+                            pubkey: registration.pubkey,
+                            address: registration.address,
+                            last_seen: now - registration.age,
+                            host_candidates: registration.host_candidates.clone(),
+                        })
+                        .collect(),
+                }
+                .encode()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut datagrams = Vec::with_capacity(syncs.len() * federation.len());
+        for (address, public_key) in federation {
+            let cipher = warp_protocol::crypto::cipher_from_shared_secret(private_key, public_key);
+            for sync in &syncs {
+                datagrams.push((*address, sync.clone().encrypt(&cipher)?.to_bytes()?));
+            }
+        }
+        Ok(datagrams)
+    }
+
+    async fn process_rx_buffer(
+        context: &RxContext,
+        buf: &[u8],
+        from: &SocketAddr,
+    ) -> anyhow::Result<Vec<(SocketAddr, Vec<u8>)>> {
+        let RxContext {
+            private_key,
+            client_store,
+            relay,
+            relay_quota,
+            federation,
+            cookies,
+            access,
+            probe_port,
+            probe_only,
+            connected,
+            audit,
+        } = context;
+        let mut response_bytes: Vec<u8> = Vec::new();
+        let mut relayed = Vec::new();
+        let mut pushed = Vec::new();
+        let mut remaining_buf = buf;
+
+        // A cookie comes ahead of the messages it vouches for, and is checked before any of them are decrypted
+        let (first, after_first) = warp_protocol::codec::WireMessage::from_slice(buf)?;
+        let cookie = Cookie::from_wire_message(&first);
+        if cookie.is_some() {
+            remaining_buf = after_first;
+        }
+        // Probes are never answered with more than was sent, so they needn't send cookies back, and can come from wherever
+        // the client's NAT maps it to for the probe address; the handshake of a TCP connection has already shown its
+        // source is real
+        if let Some(cookies) = cookies.as_ref().filter(|_| !*probe_only && !*connected) {
+            let known =
+                federation.iter().any(|(address, _)| address == from) || client_store.get_pubkey(from).is_some();
+            let now = std::time::SystemTime::now();
+            if !known && !cookie.is_some_and(|cookie| cookies.check(&cookie, from, now)) {
+                let challenge = cookies.issue(from, now).to_wire_message().to_bytes()?;
+                // Never more than was sent, so that spoofing the source gets nothing amplified to it
+                if challenge.len() > buf.len() {
+                    anyhow::bail!("datagram from unknown address {from} too short to be answered with a cookie");
+                }
+                metrics::COOKIE_CHALLENGES.inc();
+                tracing::event!(
+                    name: "CookieChallenge",
+                    tracing::Level::DEBUG,
+                    address = from.to_string().as_str()
+                );
+                return Ok(vec![(*from, challenge)]);
+            }
+        }
+        if remaining_buf.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        loop {
+            let (msg, buf) = warp_protocol::codec::WireMessage::from_slice(remaining_buf)?;
+
+            // Federated warp-maps are known by their addresses, and only they may send MapSyncs
+            let federated_map = federation
+                .iter()
+                .find(|(address, _)| address == from)
+                .map(|(_, public_key)| *public_key);
+            let client_key = match federated_map {
+                Some(public_key) => public_key,
+                None => match client_store.get_pubkey(from) {
+                    None => {
+                        let (aad, _): (warp_protocol::messages::RegisterRequestAssociatedData, usize) =
+                            bincode::decode_from_slice(&msg.associated_data, bincode::config::standard())?;
+                        aad.pubkey
+                    }
+                    Some(client_key) => client_key,
+                },
+            };
+
+            let cipher = warp_protocol::crypto::cipher_from_shared_secret(private_key, &client_key);
+            let decrypted = msg
+                .decrypt(&cipher)
+                .inspect_err(|_| metrics::DECRYPTION_FAILURES.inc())?;
+            let client_key_string = warp_protocol::crypto::pubkey_to_string(&client_key);
+
+            // Failures from here on are answered, now that the client is known
+            let message_id = decrypted.message_id;
+            let handled = async {
+                match message_id {
+                    warp_protocol::messages::NatProbeRequest::MESSAGE_ID => {
+                        let probe: warp_protocol::messages::NatProbeRequest = decrypted.decode()?;
+                        metrics::requests("nat_probe").inc();
+
+                        if !access.read().unwrap().permits(&client_key) {
+                            return Err(Rejected {
+                                code: warp_protocol::messages::ErrorCode::Unauthorized,
+                                reason: "this public key may not register with this warp-map",
+                                request_timestamp: Some(probe.timestamp),
+                            }
+                            .into());
+                        }
+                        let response = warp_protocol::messages::NatProbeResponse {
+                            address: *from,
+                            request_timestamp: probe.timestamp,
+                        };
+                        let bytes = response.encode()?.encrypt(&cipher)?.to_bytes()?;
+                        // Never more than was sent, so that spoofing the source gets nothing amplified to it
+                        if bytes.len() > remaining_buf.len() - buf.len() {
+                            anyhow::bail!("NAT probe from {from} too short to be answered");
+                        }
+                        tracing::event!(
+                            name: "NatProbe",
+                            tracing::Level::DEBUG,
+                            public_key = client_key_string,
+                            address = from.to_string().as_str()
+                        );
+                        response_bytes.extend_from_slice(bytes.as_slice());
+                    }
+                    id if *probe_only => return Err(warp_protocol::DecodeError::UnexpectedMessageId(id).into()),
+                    warp_protocol::messages::RegisterRequest::MESSAGE_ID
+                        if !access.read().unwrap().permits(&client_key) =>
+                    {
+                        let registration_msg: warp_protocol::messages::RegisterRequest = decrypted.decode()?;
+
+                        metrics::requests("register").inc();
+                        metrics::UNAUTHORIZED_REGISTRATIONS.inc();
+                        tracing::event!(
+                            name: "UnauthorizedRegistration",
+                            tracing::Level::WARN,
+                            public_key = client_key_string,
+                            address = from.to_string().as_str()
+                        );
+                        if let Some(audit) = audit {
+                            audit.record(audit::AuditEvent::Unauthorized {
+                                pubkey: client_key_string.clone(),
+                                address: *from,
+                                request_timestamp: audit::unix_time(registration_msg.timestamp),
+                            });
+                        }
+
+                        return Err(Rejected {
+                            code: warp_protocol::messages::ErrorCode::Unauthorized,
+                            reason: "this public key may not register with this warp-map",
+                            request_timestamp: Some(registration_msg.timestamp),
+                        }
+                        .into());
+                    }
+                    warp_protocol::messages::RegisterRequest::MESSAGE_ID => {
+                        let registration_msg: warp_protocol::messages::RegisterRequest = decrypted.decode()?;
+
+                        metrics::requests("register").inc();
+                        let now = Instant::now();
+                        let registered =
+                            client_store.register_client(client_key, *from, &registration_msg.host_candidates, now);
+                        Self::log_evictions(audit.as_ref(), &client_key, &registered.evicted);
+                        if let Some(audit) = audit {
+                            audit.record(audit::AuditEvent::Register {
+                                pubkey: client_key_string.clone(),
+                                address: *from,
+                                new: registered.new,
+                                request_timestamp: audit::unix_time(registration_msg.timestamp),
+                            });
+                        }
+                        // Those following the client hear of its new address now rather than when they next ask
+                        if registered.new {
+                            pushed.extend(Self::push_mapping(private_key, client_store, &client_key, now)?);
+                        }
+
+                        let response = warp_protocol::messages::RegisterResponse {
+                            address: *from,
+                            timestamp: std::time::SystemTime::now(),
+                            request_timestamp: registration_msg.timestamp,
+                            probe_port: *probe_port,
+                        };
+                        let dt = response.timestamp.duration_since(registration_msg.timestamp)?;
+                        tracing::event!(
+                            name: "RegistrationRequest",
+                            tracing::Level::INFO,
+                            public_key = client_key_string,
+                            address = from.to_string().as_str(),
+                            clock_network_skew = dt.as_secs_f32(),
+                            correlation_id = warp_protocol::messages::registration_correlation_id(registration_msg.timestamp));
+
+                        let bytes = response.encode()?.encrypt(&cipher)?.to_bytes()?;
+                        response_bytes.extend_from_slice(bytes.as_slice());
+                    }
+                    warp_protocol::messages::MappingRequest::MESSAGE_ID => {
+                        let mapping_msg: warp_protocol::messages::MappingRequest = decrypted.decode()?;
+                        metrics::requests("mapping").inc();
+
+                        let (addresses, host_candidates, peer_status) = {
+                            let now = Instant::now();
+                            // Only registered clients may subscribe, so that mappings are only ever pushed to where a
+                            // client registered from
+                            if mapping_msg.subscribe {
+                                client_store.subscribe(mapping_msg.peer_pubkey, *from, &client_key, now);
+                            }
+                            (
+                                client_store.get_addresses(&mapping_msg.peer_pubkey, now),
+                                client_store.get_host_candidates(&mapping_msg.peer_pubkey, now),
+                                client_store.peer_status(&mapping_msg.peer_pubkey, now),
+                            )
+                        };
+
+                        let n_addresses = addresses.len();
+                        if let Some(audit) = audit {
+                            audit.record(audit::AuditEvent::Mapping {
+                                pubkey: client_key_string.clone(),
+                                address: *from,
+                                peer_pubkey: warp_protocol::crypto::pubkey_to_string(&mapping_msg.peer_pubkey),
+                                addresses: n_addresses,
+                                subscribe: mapping_msg.subscribe,
+                                request_timestamp: audit::unix_time(mapping_msg.timestamp),
+                            });
+                        }
+                        let response = warp_protocol::messages::MappingResponse {
+                            peer_pubkey: mapping_msg.peer_pubkey,
+                            endpoints: addresses,
+                            timestamp: std::time::SystemTime::now(),
+                            host_candidates,
+                            peer_status: Some(peer_status),
+                        };
+                        let dt = response.timestamp.duration_since(mapping_msg.timestamp)?;
+                        info!(
+                            "Mapping request received from {}, returned {} addresses ({:?}), transit time + clock skew = {}",
+                            client_key_string,
+                            n_addresses,
+                            peer_status,
+                            dt.as_secs()
+                        );
+
+                        let bytes = response.encode()?.encrypt(&cipher)?.to_bytes()?;
+                        response_bytes.extend_from_slice(bytes.as_slice());
+                    }
+                    warp_protocol::messages::DeregisterRequest::MESSAGE_ID => {
+                        let deregister_msg: warp_protocol::messages::DeregisterRequest = decrypted.decode()?;
+
+                        metrics::requests("deregister").inc();
+                        let removed = client_store.deregister_client(&client_key, *from);
+                        if let Some(audit) = audit {
+                            audit.record(audit::AuditEvent::Deregister {
+                                pubkey: client_key_string.clone(),
+                                address: *from,
+                                removed,
+                                request_timestamp: audit::unix_time(deregister_msg.timestamp),
+                            });
+                        }
+
+                        let response = warp_protocol::messages::DeregisterResponse {
+                            timestamp: std::time::SystemTime::now(),
+                            request_timestamp: deregister_msg.timestamp,
+                        };
+
+                        let dt = response.timestamp.duration_since(deregister_msg.timestamp)?;
+                        tracing::event!(
+                            name: "DeregisterRequest",
+                            tracing::Level::INFO,
+                            public_key = client_key_string,
+                            address = from.to_string().as_str(),
+                            removed = removed,
+                            clock_network_skew = dt.as_secs_f32()
+                        );
+
+                        let bytes = response.encode()?.encrypt(&cipher)?.to_bytes()?;
+                        response_bytes.extend_from_slice(bytes.as_slice());
+                    }
+                    warp_protocol::messages::MapSync::MESSAGE_ID if federated_map.is_some() => {
+                        let sync: warp_protocol::messages::MapSync = decrypted.decode()?;
+                        metrics::requests("sync").inc();
+
+                        let num_registrations = sync.registrations.len();
+                        let mut merged = 0;
+                        {
+                            let now = Instant::now();
+                            let wall_now = std::time::SystemTime::now();
+                            let access = access.read().unwrap().clone();
+                            for synced in sync.registrations {
+                                let pubkey = synced.pubkey;
+                                // Clients that may not register here can't be found through here either
+                                if !access.permits(&pubkey) {
+                                    continue;
+                                }
+                                let registration = map::Registration {
+                                    pubkey,
+                                    address: synced.address,
+                                    age: wall_now.duration_since(synced.last_seen).unwrap_or_default(),
+                                    host_candidates: synced.host_candidates,
+                                };
+                                let Some(registered) = client_store.merge(registration, now) else {
+                                    continue;
+                                };
+                                merged += 1;
+                                Self::log_evictions(audit.as_ref(), &pubkey, &registered.evicted);
+                                // As if the client had registered here
+                                if registered.new {
+                                    pushed.extend(Self::push_mapping(private_key, client_store, &pubkey, now)?);
+                                }
+                            }
+                        }
+                        tracing::event!(
+                            name: "MapSync",
+                            tracing::Level::DEBUG,
+                            public_key = client_key_string,
+                            address = from.to_string().as_str(),
+                            registrations = num_registrations,
+                            merged = merged
+                        );
+                    }
+                    warp_protocol::messages::RelayPayload::MESSAGE_ID if *relay => {
+                        let relay_msg: warp_protocol::messages::RelayPayload = decrypted.decode()?;
+
+                        // Only registered clients may relay, so that knowing a client's key isn't enough to send to it
+                        let addresses = {
+                            let now = Instant::now();
+                            // An expired registration may not have been collected yet
+                            if client_store.get_pubkey(from) != Some(client_key)
+                                || !client_store.get_addresses(&client_key, now).contains(from)
+                            {
+                                return Err(Rejected {
+                                    code: warp_protocol::messages::ErrorCode::NotRegistered,
+                                    reason: "relaying needs a registration from this address",
+                                    request_timestamp: None,
+                                }
+                                .into());
+                            }
+                            client_store.get_addresses(&relay_msg.peer_pubkey, now)
+                        };
+                        metrics::requests("relay").inc();
+                        // Dropped like a datagram lost on the way, as the tunnels relayed have their own ways of coping
+                        if let Some(relay_quota) = relay_quota {
+                            let bytes = relay_msg.data.len() * addresses.len();
+                            let pair = (client_key, relay_msg.peer_pubkey);
+                            if !relay_quota.lock().unwrap().take(pair, bytes, Instant::now()) {
+                                metrics::RELAY_QUOTA_DROPPED_BYTES.add(bytes as u64);
+                                tracing::event!(
+                                    name: "RelayQuotaExceeded",
+                                    tracing::Level::DEBUG,
+                                    public_key = client_key_string,
+                                    peer = warp_protocol::crypto::pubkey_to_string(&relay_msg.peer_pubkey),
+                                    size = relay_msg.data.len()
+                                );
+                                return Ok(());
+                            }
+                        }
+                        metrics::RELAYED_BYTES.add((relay_msg.data.len() * addresses.len()) as u64);
+                        tracing::event!(
+                            name: "RelayRequest",
+                            tracing::Level::DEBUG,
+                            public_key = client_key_string,
+                            peer = warp_protocol::crypto::pubkey_to_string(&relay_msg.peer_pubkey),
+                            peer_addresses = addresses.len(),
+                            size = relay_msg.data.len()
+                        );
+
+                        // Passed on to every address the peer registered, as warp-map can't tell which of them work best
+                        let peer_cipher =
+                            warp_protocol::crypto::cipher_from_shared_secret(private_key, &relay_msg.peer_pubkey);
+                        let forwarded = warp_protocol::messages::RelayPayload {
+                            peer_pubkey: client_key,
+                            data: relay_msg.data,
+                        };
+                        let bytes = forwarded.encode()?.encrypt(&peer_cipher)?.to_bytes()?;
+                        relayed.extend(addresses.into_iter().map(|address| (address, bytes.clone())));
+                    }
+                    // From a federated warp-map that couldn't handle a MapSync; never answered, so that two warp-maps
+                    // can't keep answering each other's
+                    warp_protocol::messages::ErrorResponse::MESSAGE_ID => {
+                        let error: warp_protocol::messages::ErrorResponse = decrypted.decode()?;
+                        tracing::event!(
+                            name: "ErrorResponse",
+                            tracing::Level::WARN,
+                            public_key = client_key_string,
+                            address = from.to_string().as_str(),
+                            code = ?error.code,
+                            message = error.message.as_str()
+                        );
+                    }
+                    id => return Err(warp_protocol::DecodeError::UnexpectedMessageId(id).into()),
+                }
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+            match handled {
+                Ok(()) => {}
+                // Nothing but probes is answered on the probe address, as an ErrorResponse may be larger than the request
+                Err(e) if *probe_only => {
+                    tracing::event!(
+                        name: "NatProbeIgnored",
+                        tracing::Level::DEBUG,
+                        address = from.to_string().as_str(),
+                        error = e.to_string().as_str()
+                    );
+                }
+                Err(e) => {
+                    let response = Self::error_response(&e, message_id);
+                    if e.downcast_ref::<Rejected>().is_none() {
+                        metrics::PROCESSING_ERRORS.inc();
+                        error!("Error handling message {:#04x} from {}: {}", message_id, from, e);
+                    }
+                    metrics::error_responses(response.code).inc();
+                    let bytes = response.encode()?.encrypt(&cipher)?.to_bytes()?;
+                    response_bytes.extend_from_slice(bytes.as_slice());
+                }
+            }
+
+            remaining_buf = buf;
+            if remaining_buf.is_empty() {
+                break;
+            }
+
+            // Yield to allow other tasks to run
+            tokio::task::yield_now().await;
+        }
+
+        let response = (!response_bytes.is_empty()).then_some((*from, response_bytes));
+        Ok(response.into_iter().chain(relayed).chain(pushed).collect())
+    }
+
+    // The ErrorResponse answering the request with `message_id` that failed with `error`
+    fn error_response(error: &anyhow::Error, message_id: u8) -> warp_protocol::messages::ErrorResponse {
+        use warp_protocol::messages::ErrorCode;
+        if let Some(rejected) = error.downcast_ref::<Rejected>() {
+            return warp_protocol::messages::ErrorResponse {
+                code: rejected.code,
+                message: rejected.reason.to_string(),
+                request_message_id: message_id,
+                request_timestamp: rejected.request_timestamp,
+            };
+        }
+        let code = match error.downcast_ref::<warp_protocol::DecodeError>() {
+            Some(warp_protocol::DecodeError::UnexpectedMessageId(_)) => ErrorCode::UnknownMessage,
+            Some(_) => ErrorCode::Malformed,
+            None => ErrorCode::Internal,
+        };
+        warp_protocol::messages::ErrorResponse {
+            code,
+            message: error.to_string(),
+            request_message_id: message_id,
+            request_timestamp: None,
+        }
+    }
+
+    // Addresses that made way for a newer one of the same public key, over the maximum it may have
+    fn log_evictions(audit: Option<&audit::AuditLog>, pubkey: &warp_protocol::PublicKey, evicted: &[SocketAddr]) {
+        for address in evicted {
+            metrics::EVICTED_ADDRESSES.inc();
+            tracing::event!(
+                name: "AddressEvicted",
+                tracing::Level::INFO,
+                public_key = warp_protocol::crypto::pubkey_to_string(pubkey),
+                address = address.to_string().as_str()
+            );
+            if let Some(audit) = audit {
+                audit.record(audit::AuditEvent::Evict {
+                    pubkey: warp_protocol::crypto::pubkey_to_string(pubkey),
+                    address: *address,
+                });
+            }
+        }
+    }
+
+    // MappingResponses for `pubkey`, sealed for and addressed to each of its subscribers
+    fn push_mapping(
+        private_key: &warp_protocol::PrivateKey,
+        store: &map::ShardedClientStore,
+        pubkey: &warp_protocol::PublicKey,
+        now: Instant,
+    ) -> anyhow::Result<Vec<(SocketAddr, Vec<u8>)>> {
+        let subscribers = store.get_subscribers(pubkey, now);
+        if subscribers.is_empty() {
+            return Ok(Vec::new());
+        }
+        let response = warp_protocol::messages::MappingResponse {
+            peer_pubkey: *pubkey,
+            endpoints: store.get_addresses(pubkey, now),
+            timestamp: std::time::SystemTime::now(),
+            host_candidates: store.get_host_candidates(pubkey, now),
+            peer_status: Some(store.peer_status(pubkey, now)),
+        };
+        let encoded = response.encode()?;
+
+        metrics::PUSHED_MAPPINGS.add(subscribers.len() as u64);
+        tracing::event!(
+            name: "MappingPush",
+            tracing::Level::DEBUG,
+            public_key = warp_protocol::crypto::pubkey_to_string(pubkey),
+            addresses = response.endpoints.len(),
+            subscribers = subscribers.len()
+        );
+        subscribers
+            .into_iter()
+            .map(
+                |(subscriber, subscriber_key)| -> anyhow::Result<(SocketAddr, Vec<u8>)> {
+                    let cipher = warp_protocol::crypto::cipher_from_shared_secret(private_key, &subscriber_key);
+                    Ok((subscriber, encoded.clone().encrypt(&cipher)?.to_bytes()?))
+                },
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn private_key(seed: u8) -> warp_protocol::PrivateKey {
+        let mut bytes = [1u8; 32];
+        bytes[0] = seed;
+        warp_protocol::PrivateKey::from_bytes(&bytes.into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_federated_maps_share_registrations() {
+        let a_key = private_key(1);
+        let b_key = private_key(2);
+        let client_key = private_key(3);
+        let a_address: SocketAddr = "192.0.2.1:13116".parse().unwrap();
+        let b_address: SocketAddr = "192.0.2.2:13116".parse().unwrap();
+        let client_address: SocketAddr = "198.51.100.1:4000".parse().unwrap();
+        let expiry = Duration::from_secs(60);
+        let a = WarpMapServer::new(a_key.clone(), a_address, expiry).with_federated_map(b_address, b_key.public_key());
+        let b = WarpMapServer::new(b_key.clone(), b_address, expiry).with_federated_map(a_address, a_key.public_key());
+
+        // The client only registers with A
+        let cipher = warp_protocol::crypto::cipher_from_shared_secret(&client_key, &a_key.public_key());
+        let registration = warp_protocol::messages::RegisterRequest {
+            pubkey: client_key.public_key(),
+            timestamp: std::time::SystemTime::now(),
+            host_candidates: vec!["10.0.0.2:4000".parse().unwrap()],
+        };
+        let datagram = registration
+            .encode()
+            .unwrap()
+            .encrypt(&cipher)
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        a.handle_datagram(&datagram, &client_address).await.unwrap();
+
+        let syncs = a.federation_datagrams().await.unwrap();
+        assert_eq!(syncs.len(), 1);
+        assert_eq!(syncs[0].0, b_address);
+        // Only from A's address
+        assert!(b.handle_datagram(&syncs[0].1, &client_address).await.is_err());
+        b.handle_datagram(&syncs[0].1, &a_address).await.unwrap();
+
+        let now = Instant::now();
+        let store = &b.client_store;
+        assert_eq!(store.get_addresses(&client_key.public_key(), now), vec![client_address]);
+        assert_eq!(
+            store.get_host_candidates(&client_key.public_key(), now),
+            registration.host_candidates
+        );
+
+        // What B passes back is no newer than what A has
+        for (to, datagram) in b.federation_datagrams().await.unwrap() {
+            assert_eq!(to, a_address);
+            a.handle_datagram(&datagram, &b_address).await.unwrap();
+        }
+        assert_eq!(a.client_store.address_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_sources_send_back_a_cookie_first() {
+        let map_key = private_key(1);
+        let client_key = private_key(3);
+        let client_address: SocketAddr = "198.51.100.1:4000".parse().unwrap();
+        let map = WarpMapServer::new(
+            map_key.clone(),
+            "192.0.2.1:13116".parse().unwrap(),
+            Duration::from_secs(60),
+        )
+        .with_cookies();
+
+        let registration = registration(&client_key, &map_key);
+
+        let challenge = map.handle_datagram(&registration, &client_address).await.unwrap();
+        assert_eq!(challenge.len(), 1);
+        assert!(challenge[0].1.len() <= registration.len());
+        let (message, _) = warp_protocol::codec::WireMessage::from_slice(&challenge[0].1).unwrap();
+        let cookie = Cookie::from_wire_message(&message).unwrap();
+        assert_eq!(map.client_store.client_count(), 0);
+
+        // Only good for the address it was sent to
+        let mut with_cookie = cookie.to_wire_message().to_bytes().unwrap();
+        with_cookie.extend_from_slice(&registration);
+        let other_address: SocketAddr = "198.51.100.2:4000".parse().unwrap();
+        let rechallenge = map.handle_datagram(&with_cookie, &other_address).await.unwrap();
+        assert!(Cookie::from_wire_message(
+            &warp_protocol::codec::WireMessage::from_slice(&rechallenge[0].1)
+                .unwrap()
+                .0
+        )
+        .is_some());
+
+        let response = map.handle_datagram(&with_cookie, &client_address).await.unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(map.client_store.client_count(), 1);
+
+        // Once registered, the client needn't send it
+        map.handle_datagram(&registration, &client_address).await.unwrap();
+        assert_eq!(map.client_store.client_count(), 1);
+
+        // Too little is sent to be answered at all
+        assert!(map.handle_datagram(&[0, 0, 0], &other_address).await.is_err());
+    }
+
+    fn registration(client_key: &warp_protocol::PrivateKey, map_key: &warp_protocol::PrivateKey) -> Vec<u8> {
+        let cipher = warp_protocol::crypto::cipher_from_shared_secret(client_key, &map_key.public_key());
+        warp_protocol::messages::RegisterRequest {
+            pubkey: client_key.public_key(),
+            timestamp: std::time::SystemTime::now(),
+            host_candidates: Vec::new(),
+        }
+        .encode()
+        .unwrap()
+        .encrypt(&cipher)
+        .unwrap()
+        .to_bytes()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_only_allowed_keys_register() {
+        let map_key = private_key(1);
+        let (allowed_key, other_key) = (private_key(3), private_key(4));
+        let allowed_address: SocketAddr = "198.51.100.1:4000".parse().unwrap();
+        let other_address: SocketAddr = "198.51.100.2:4000".parse().unwrap();
+        let path = std::env::temp_dir().join(format!("warp-map-allowed-keys-test-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            format!(
+                "{}\n",
+                warp_protocol::crypto::pubkey_to_string(&allowed_key.public_key())
+            ),
+        )
+        .unwrap();
+        let map = WarpMapServer::new(
+            map_key.clone(),
+            "192.0.2.1:13116".parse().unwrap(),
+            Duration::from_secs(60),
+        )
+        .with_allowed_keys(path.clone());
+        map.reload_access().await.unwrap();
+
+        map.handle_datagram(&registration(&allowed_key, &map_key), &allowed_address)
+            .await
+            .unwrap();
+        let response = map
+            .handle_datagram(&registration(&other_key, &map_key), &other_address)
+            .await
+            .unwrap();
+        assert_eq!(map.client_store.client_count(), 1);
+
+        // The turned down client hears why
+        let cipher = warp_protocol::crypto::cipher_from_shared_secret(&other_key, &map_key.public_key());
+        let (message, _) = warp_protocol::codec::WireMessage::from_slice(&response[0].1).unwrap();
+        let error: warp_protocol::messages::ErrorResponse = message.decrypt(&cipher).unwrap().decode().unwrap();
+        assert_eq!(error.code, warp_protocol::messages::ErrorCode::Unauthorized);
+
+        // Taking a key off the list drops its registrations
+        std::fs::write(&path, "# Nobody\n").unwrap();
+        map.reload_access().await.unwrap();
+        assert_eq!(map.client_store.client_count(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_requests_are_answered() {
+        let map_key = private_key(1);
+        let client_key = private_key(3);
+        let client_address: SocketAddr = "198.51.100.1:4000".parse().unwrap();
+        let map = WarpMapServer::new(
+            map_key.clone(),
+            "192.0.2.1:13116".parse().unwrap(),
+            Duration::from_secs(60),
+        );
+        let cipher = warp_protocol::crypto::cipher_from_shared_secret(&client_key, &map_key.public_key());
+
+        // Relaying is off, and the registration ahead of the request is still handled
+        let mut datagram = registration(&client_key, &map_key);
+        datagram.extend(
+            warp_protocol::messages::RelayPayload {
+                peer_pubkey: private_key(4).public_key(),
+                data: vec![1, 2, 3],
+            }
+            .encode()
+            .unwrap()
+            .encrypt(&cipher)
+            .unwrap()
+            .to_bytes()
+            .unwrap(),
+        );
+        let response = map.handle_datagram(&datagram, &client_address).await.unwrap();
+        assert_eq!(map.client_store.client_count(), 1);
+
+        let (registered, rest) = warp_protocol::codec::WireMessage::from_slice(&response[0].1).unwrap();
+        assert_eq!(
+            registered.decrypt(&cipher).unwrap().message_id,
+            warp_protocol::messages::RegisterResponse::MESSAGE_ID
+        );
+        let (message, _) = warp_protocol::codec::WireMessage::from_slice(rest).unwrap();
+        let error: warp_protocol::messages::ErrorResponse = message.decrypt(&cipher).unwrap().decode().unwrap();
+        assert_eq!(error.code, warp_protocol::messages::ErrorCode::UnknownMessage);
+        assert_eq!(
+            error.request_message_id,
+            warp_protocol::messages::RelayPayload::MESSAGE_ID
+        );
+
+        // Relaying needs an unexpired registration
+        let relay_map =
+            WarpMapServer::new(map_key.clone(), "192.0.2.1:13116".parse().unwrap(), Duration::ZERO).with_relay();
+        let response = relay_map.handle_datagram(&datagram, &client_address).await.unwrap();
+        let (_, rest) = warp_protocol::codec::WireMessage::from_slice(&response[0].1).unwrap();
+        let (message, _) = warp_protocol::codec::WireMessage::from_slice(rest).unwrap();
+        let error: warp_protocol::messages::ErrorResponse = message.decrypt(&cipher).unwrap().decode().unwrap();
+        assert_eq!(error.code, warp_protocol::messages::ErrorCode::NotRegistered);
+    }
+
+    #[tokio::test]
+    async fn test_relaying_stops_at_the_quota() {
+        let map_key = private_key(1);
+        let (a_key, b_key) = (private_key(3), private_key(4));
+        let (a_address, b_address): (SocketAddr, SocketAddr) = (
+            "198.51.100.1:4000".parse().unwrap(),
+            "198.51.100.2:4000".parse().unwrap(),
+        );
+        let map = WarpMapServer::new(
+            map_key.clone(),
+            "192.0.2.1:13116".parse().unwrap(),
+            Duration::from_secs(60),
+        )
+        .with_relay()
+        .with_relay_quota(1000, 0);
+        map.handle_datagram(&registration(&a_key, &map_key), &a_address)
+            .await
+            .unwrap();
+        map.handle_datagram(&registration(&b_key, &map_key), &b_address)
+            .await
+            .unwrap();
+
+        let cipher = warp_protocol::crypto::cipher_from_shared_secret(&a_key, &map_key.public_key());
+        let relay = |peer_pubkey| {
+            warp_protocol::messages::RelayPayload {
+                peer_pubkey,
+                data: vec![0; 40000],
+            }
+            .encode()
+            .unwrap()
+            .encrypt(&cipher)
+            .unwrap()
+            .to_bytes()
+            .unwrap()
+        };
+        // The burst holds a datagram at least, but not two of these
+        let relayed = map
+            .handle_datagram(&relay(b_key.public_key()), &a_address)
+            .await
+            .unwrap();
+        assert_eq!(relayed.len(), 1);
+        assert_eq!(relayed[0].0, b_address);
+        let relayed = map
+            .handle_datagram(&relay(b_key.public_key()), &a_address)
+            .await
+            .unwrap();
+        assert!(relayed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_nat_probes_report_the_address_seen() {
+        let map_key = private_key(1);
+        let client_key = private_key(3);
+        let client_address: SocketAddr = "198.51.100.1:4000".parse().unwrap();
+        // Behind a symmetric NAT, the client's datagrams to the probe port come from another port
+        let probed_address: SocketAddr = "198.51.100.1:4001".parse().unwrap();
+        let map = WarpMapServer::new(
+            map_key.clone(),
+            "192.0.2.1:13116".parse().unwrap(),
+            Duration::from_secs(60),
+        )
+        .with_nat_probe("192.0.2.1:13117".parse().unwrap());
+        let cipher = warp_protocol::crypto::cipher_from_shared_secret(&client_key, &map_key.public_key());
+
+        // Registering tells the client where to probe
+        let response = map
+            .handle_datagram(&registration(&client_key, &map_key), &client_address)
+            .await
+            .unwrap();
+        let (message, _) = warp_protocol::codec::WireMessage::from_slice(&response[0].1).unwrap();
+        let registered: warp_protocol::messages::RegisterResponse = message.decrypt(&cipher).unwrap().decode().unwrap();
+        assert_eq!(registered.probe_port, Some(13117));
+
+        let probe = warp_protocol::messages::NatProbeRequest {
+            pubkey: client_key.public_key(),
+            timestamp: std::time::SystemTime::now(),
+        }
+        .encode()
+        .unwrap()
+        .encrypt(&cipher)
+        .unwrap()
+        .to_bytes()
+        .unwrap();
+        let response = map.handle_probe_datagram(&probe, &probed_address).await.unwrap();
+        assert_eq!(response[0].0, probed_address);
+        let (message, _) = warp_protocol::codec::WireMessage::from_slice(&response[0].1).unwrap();
+        let probed: warp_protocol::messages::NatProbeResponse = message.decrypt(&cipher).unwrap().decode().unwrap();
+        assert_eq!(probed.address, probed_address);
+
+        // Nothing else is answered on the probe address
+        let response = map
+            .handle_probe_datagram(&registration(&client_key, &map_key), &probed_address)
+            .await
+            .unwrap();
+        assert!(response.is_empty());
+        assert_eq!(map.client_store.address_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_registrations_over_tcp_are_answered_over_the_connection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let map_key = private_key(1);
+        let client_key = private_key(3);
+        let map =
+            WarpMapServer::new(map_key.clone(), "127.0.0.1:0".parse().unwrap(), Duration::from_secs(60)).with_cookies();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, address) = listener.accept().await.unwrap();
+        let sockets = Sockets::bind(&["127.0.0.1:0".parse().unwrap()]).unwrap();
+        let in_flight = tokio::sync::Semaphore::new(1);
+        let context = RxContext {
+            connected: true,
+            ..map.rx_context()
+        };
+
+        // No cookie is asked for, as the connection has shown where it comes from
+        let exchange = async {
+            let registration = warp_protocol::codec::frame(&registration(&client_key, &map_key)).unwrap();
+            client.write_all(&registration).await.unwrap();
+            let mut header = [0; warp_protocol::codec::FRAME_HEADER_SIZE];
+            client.read_exact(&mut header).await.unwrap();
+            let mut response = vec![0; warp_protocol::codec::frame_length(header)];
+            client.read_exact(&mut response).await.unwrap();
+            response
+        };
+        let response = tokio::select! {
+            response = exchange => response,
+            result = WarpMapServer::serve_connection(&context, stream, address, &sockets, &in_flight, None) => {
+                panic!("connection closed: {result:?}")
+            }
+        };
+
+        let cipher = warp_protocol::crypto::cipher_from_shared_secret(&client_key, &map_key.public_key());
+        let (message, _) = warp_protocol::codec::WireMessage::from_slice(&response).unwrap();
+        let registered: warp_protocol::messages::RegisterResponse = message.decrypt(&cipher).unwrap().decode().unwrap();
+        assert_eq!(registered.address, client.local_addr().unwrap());
+        assert_eq!(
+            map.client_store.get_addresses(&client_key.public_key(), Instant::now()),
+            vec![address]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Minimal HTTP endpoint serving a [`Registry`] for Prometheus to scrape, and optionally a health check
+
+use crate::Registry;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Says whether the process is healthy, and if not, why
+pub type HealthCheck = std::sync::Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+/// Renders the plain text body of an extra page, for state that doesn't fit in metrics
+pub type Page =
+    std::sync::Arc<dyn Fn() -> std::pin::Pin<Box<dyn std::future::Future<Output = String> + Send>> + Send + Sync>;
+
+/// Serve `GET /metrics` on `listener` until an accept error occurs, and `GET /health` if `health` is given
+///
+/// Each connection is handled on its own task and closed after a single response; anything else gets a 404.
+/// `/health` answers 200 while `health` passes and 503 with its reason while it doesn't.
+pub async fn serve(
+    registry: Registry,
+    health: Option<HealthCheck>,
+    listener: tokio::net::TcpListener,
+) -> std::io::Result<()> {
+    serve_with_pages(registry, health, Vec::new(), listener).await
+}
+
+/// [`serve`], and also `GET` each of `pages` at its path
+pub async fn serve_with_pages(
+    registry: Registry,
+    health: Option<HealthCheck>,
+    pages: Vec<(String, Page)>,
+    listener: tokio::net::TcpListener,
+) -> std::io::Result<()> {
+    let pages = std::sync::Arc::new(pages);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let registry = registry.clone();
+        let health = health.clone();
+        let pages = pages.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&registry, health.as_deref(), &pages, stream).await {
+                tracing::debug!("Metrics request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Bind to `address` and [`serve`] `registry`, and `health` if given, on it
+pub async fn bind_and_serve(
+    registry: Registry,
+    health: Option<HealthCheck>,
+    address: std::net::SocketAddr,
+) -> std::io::Result<()> {
+    bind_and_serve_with_pages(registry, health, Vec::new(), address).await
+}
+
+/// Bind to `address` and [`serve_with_pages`] on it
+pub async fn bind_and_serve_with_pages(
+    registry: Registry,
+    health: Option<HealthCheck>,
+    pages: Vec<(String, Page)>,
+    address: std::net::SocketAddr,
+) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    tracing::info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+    serve_with_pages(registry, health, pages, listener).await
+}
+
+async fn handle_connection(
+    registry: &Registry,
+    health: Option<&(dyn Fn() -> Result<(), String> + Send + Sync)>,
+    pages: &[(String, Page)],
+    mut stream: tokio::net::TcpStream,
+) -> std::io::Result<()> {
+    // Only the request line matters, and it always fits in the first read for any sane scraper
+    let mut buf = [0u8; 1024];
+    let len = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+
+    let (method, path) = (request_line.next(), request_line.next());
+    let page = pages
+        .iter()
+        .find(|(page_path, _)| Some(page_path.as_str()) == path)
+        .map(|(_, page)| page);
+
+    let response = match (method, path, health, page) {
+        (Some("GET"), Some("/metrics"), _, _) => {
+            let body = registry.encode_prometheus();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        (Some("GET"), Some("/health"), Some(health), _) => {
+            let (status, body) = match health() {
+                Ok(()) => ("200 OK", "healthy\n".to_string()),
+                Err(reason) => ("503 Service Unavailable", format!("{reason}\n")),
+            };
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        (Some("GET"), _, _, Some(page)) => {
+            let body = page().await;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(address: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let registry = Registry::new();
+        registry.counter("warp_test_total", "Test counter").add(7);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(registry, None, listener));
+
+        let response = get(address, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("warp_test_total 7\n"));
+
+        let response = get(address, "/").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get(address, "/health").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_serve_health() {
+        let healthy = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let health: HealthCheck = {
+            let healthy = healthy.clone();
+            std::sync::Arc::new(move || {
+                if healthy.load(std::sync::atomic::Ordering::Relaxed) {
+                    Ok(())
+                } else {
+                    Err("stalled: rx worker 0".to_string())
+                }
+            })
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(Registry::new(), Some(health), listener));
+
+        assert!(get(address, "/health").await.starts_with("HTTP/1.1 200 OK\r\n"));
+        healthy.store(false, std::sync::atomic::Ordering::Relaxed);
+        let response = get(address, "/health").await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.ends_with("stalled: rx worker 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_serve_pages() {
+        let page: Page = std::sync::Arc::new(|| Box::pin(async { "a 1\nb 2\n".to_string() }));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_with_pages(
+            Registry::new(),
+            None,
+            vec![("/clients".to_string(), page)],
+            listener,
+        ));
+
+        let response = get(address, "/clients").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\na 1\nb 2\n"));
+        assert!(get(address, "/metrics").await.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(address, "/other").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,197 @@
+//! Minimal metrics facade shared by the warp binaries
+//!
+//! Metrics are registered with a [`Registry`] (usually the process-wide [`global`] one), updated through cheap
+//! cloneable handles, and exposed in the Prometheus text format by [`Registry::encode_prometheus`] or over HTTP with
+//! [`exporter::serve`].
+//!
+//! Metric names are prefixed with the binary they come from (`warp_`, `warp_map_`, `warp_gauge_`), counters end in
+//! `_total`, and durations are recorded in seconds with a `_seconds` suffix.
+
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub mod exporter;
+mod prometheus;
+mod registry;
+
+pub use registry::{global, Registry};
+
+/// Histogram buckets (in seconds) suited to per-packet processing and queueing latencies
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+/// Monotonically increasing count
+#[derive(Clone, Debug, Default)]
+pub struct Counter {
+    value: Arc<AtomicU64>,
+}
+
+impl Counter {
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// Value that can go up and down, e.g. a queue depth
+#[derive(Clone, Debug, Default)]
+pub struct Gauge {
+    value: Arc<AtomicI64>,
+}
+
+impl Gauge {
+    #[inline]
+    pub fn set(&self, value: i64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn dec(&self) {
+        self.add(-1);
+    }
+
+    #[inline]
+    pub fn add(&self, n: i64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// Distribution of observed values counted into fixed buckets
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    inner: Arc<HistogramInner>,
+}
+
+#[derive(Debug)]
+struct HistogramInner {
+    // Upper bounds (inclusive) of each bucket, sorted ascending; observations above the last bound go into an
+    // implicit +Inf bucket
+    bounds: Vec<f64>,
+    // Non-cumulative count per bucket, with one extra for +Inf
+    buckets: Vec<AtomicU64>,
+    sum_bits: AtomicU64,
+    count: AtomicU64,
+}
+
+/// Point-in-time copy of a [`Histogram`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistogramSnapshot {
+    /// `(upper_bound, cumulative_count)` for each bucket, ending with `f64::INFINITY`
+    pub buckets: Vec<(f64, u64)>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    /// Create a histogram with the given bucket upper bounds; NaN bounds are ignored and duplicates merged
+    pub fn new(bounds: &[f64]) -> Self {
+        let mut bounds: Vec<f64> = bounds.iter().copied().filter(|bound| !bound.is_nan()).collect();
+        bounds.sort_by(f64::total_cmp);
+        bounds.dedup();
+
+        let buckets = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Self {
+            inner: Arc::new(HistogramInner {
+                bounds,
+                buckets,
+                sum_bits: AtomicU64::new(0f64.to_bits()),
+                count: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn observe(&self, value: f64) {
+        let index = self.inner.bounds.partition_point(|&bound| bound < value);
+        self.inner.buckets[index].fetch_add(1, Ordering::Relaxed);
+        // There is no atomic f64 add, so retry until no other thread updated the sum underneath us
+        let _ = self
+            .inner
+            .sum_bits
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+        self.inner.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a duration in seconds
+    #[inline]
+    pub fn observe_duration(&self, duration: std::time::Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self
+            .inner
+            .bounds
+            .iter()
+            .copied()
+            .chain(std::iter::once(f64::INFINITY))
+            .zip(&self.inner.buckets)
+            .map(|(bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                (bound, cumulative)
+            })
+            .collect();
+
+        HistogramSnapshot {
+            buckets,
+            sum: f64::from_bits(self.inner.sum_bits.load(Ordering::Relaxed)),
+            count: self.inner.count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_and_gauge() {
+        let counter = Counter::default();
+        counter.inc();
+        counter.clone().add(4);
+        assert_eq!(counter.get(), 5);
+
+        let gauge = Gauge::default();
+        gauge.set(10);
+        gauge.dec();
+        gauge.add(-4);
+        assert_eq!(gauge.get(), 5);
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = Histogram::new(&[1.0, 0.1, f64::NAN, 1.0]);
+        for value in [0.05, 0.1, 0.5, 2.0, 3.0] {
+            histogram.observe(value);
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.buckets, vec![(0.1, 2), (1.0, 3), (f64::INFINITY, 5)]);
+        assert_eq!(snapshot.count, 5);
+        assert!((snapshot.sum - 5.65).abs() < 1e-9);
+    }
+}
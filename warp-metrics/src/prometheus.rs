@@ -0,0 +1,108 @@
+//! Prometheus text exposition format (version 0.0.4)
+
+use crate::registry::{Family, Labels, Metric};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+pub(crate) fn encode(families: &BTreeMap<String, Family>) -> String {
+    let mut output = String::new();
+
+    for (name, family) in families {
+        let Some((_, first)) = family.series.first() else {
+            continue;
+        };
+        let _ = writeln!(output, "# HELP {name} {}", escape_help(&family.help));
+        let _ = writeln!(output, "# TYPE {name} {}", first.type_name());
+
+        for (labels, metric) in &family.series {
+            match metric {
+                Metric::Counter(counter) => write_sample(&mut output, name, labels, None, counter.get()),
+                Metric::Gauge(gauge) => write_sample(&mut output, name, labels, None, gauge.get()),
+                Metric::Histogram(histogram) => {
+                    let snapshot = histogram.snapshot();
+                    let bucket_name = format!("{name}_bucket");
+                    for (bound, count) in snapshot.buckets {
+                        write_sample(&mut output, &bucket_name, labels, Some(format_float(bound)), count);
+                    }
+                    write_sample(&mut output, &format!("{name}_sum"), labels, None, snapshot.sum);
+                    write_sample(&mut output, &format!("{name}_count"), labels, None, snapshot.count);
+                }
+            }
+        }
+    }
+
+    output
+}
+
+fn write_sample(output: &mut String, name: &str, labels: &Labels, le: Option<String>, value: impl std::fmt::Display) {
+    output.push_str(name);
+
+    let le = le.map(|le| ("le".to_string(), le));
+    let mut all_labels = labels.iter().chain(le.as_ref()).peekable();
+    if all_labels.peek().is_some() {
+        output.push('{');
+        for (index, (label, value)) in all_labels.enumerate() {
+            if index > 0 {
+                output.push(',');
+            }
+            let _ = write!(output, "{label}=\"{}\"", escape_label_value(value));
+        }
+        output.push('}');
+    }
+
+    let _ = writeln!(output, " {value}");
+}
+
+fn format_float(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Registry;
+
+    #[test]
+    fn test_encode() {
+        let registry = Registry::new();
+        registry
+            .counter_with_labels("warp_map_requests_total", "Requests handled", &[("kind", "register")])
+            .add(3);
+        registry
+            .counter_with_labels("warp_map_requests_total", "Requests handled", &[("kind", "say \"hi\"")])
+            .inc();
+        registry.gauge("warp_interfaces", "Active interfaces\nper scan").set(-2);
+        let histogram = registry.histogram("warp_rx_seconds", "RX time", &[0.5, 1.0]);
+        histogram.observe(0.25);
+        histogram.observe(2.0);
+
+        assert_eq!(
+            registry.encode_prometheus(),
+            "# HELP warp_interfaces Active interfaces\\nper scan\n\
+             # TYPE warp_interfaces gauge\n\
+             warp_interfaces -2\n\
+             # HELP warp_map_requests_total Requests handled\n\
+             # TYPE warp_map_requests_total counter\n\
+             warp_map_requests_total{kind=\"register\"} 3\n\
+             warp_map_requests_total{kind=\"say \\\"hi\\\"\"} 1\n\
+             # HELP warp_rx_seconds RX time\n\
+             # TYPE warp_rx_seconds histogram\n\
+             warp_rx_seconds_bucket{le=\"0.5\"} 1\n\
+             warp_rx_seconds_bucket{le=\"1\"} 1\n\
+             warp_rx_seconds_bucket{le=\"+Inf\"} 2\n\
+             warp_rx_seconds_sum 2.25\n\
+             warp_rx_seconds_count 2\n"
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{Counter, Gauge, Histogram};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+#[derive(Clone, Debug)]
+pub(crate) enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+impl Metric {
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Metric::Counter(_) => "counter",
+            Metric::Gauge(_) => "gauge",
+            Metric::Histogram(_) => "histogram",
+        }
+    }
+}
+
+pub(crate) type Labels = Vec<(String, String)>;
+
+/// All series sharing a metric name
+#[derive(Debug)]
+pub(crate) struct Family {
+    pub(crate) help: String,
+    pub(crate) series: Vec<(Labels, Metric)>,
+}
+
+/// Set of named metrics that can be encoded together
+///
+/// Registering the same name and labels twice returns a handle to the existing metric, so metrics can be registered
+/// lazily wherever they are first needed.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    families: Arc<Mutex<BTreeMap<String, Family>>>,
+}
+
+/// Process-wide registry used by the warp binaries
+pub fn global() -> &'static Registry {
+    static GLOBAL: OnceLock<Registry> = OnceLock::new();
+    GLOBAL.get_or_init(Registry::default)
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn counter(&self, name: &str, help: &str) -> Counter {
+        self.counter_with_labels(name, help, &[])
+    }
+
+    pub fn counter_with_labels(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Counter {
+        match self.get_or_register(name, help, labels, || Metric::Counter(Counter::default())) {
+            Metric::Counter(counter) => counter,
+            other => panic!("metric {name} is already registered as a {}", other.type_name()),
+        }
+    }
+
+    pub fn gauge(&self, name: &str, help: &str) -> Gauge {
+        self.gauge_with_labels(name, help, &[])
+    }
+
+    pub fn gauge_with_labels(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Gauge {
+        match self.get_or_register(name, help, labels, || Metric::Gauge(Gauge::default())) {
+            Metric::Gauge(gauge) => gauge,
+            other => panic!("metric {name} is already registered as a {}", other.type_name()),
+        }
+    }
+
+    /// Register a histogram with the given bucket upper bounds; see [`crate::LATENCY_BUCKETS`]
+    pub fn histogram(&self, name: &str, help: &str, buckets: &[f64]) -> Histogram {
+        self.histogram_with_labels(name, help, buckets, &[])
+    }
+
+    pub fn histogram_with_labels(&self, name: &str, help: &str, buckets: &[f64], labels: &[(&str, &str)]) -> Histogram {
+        match self.get_or_register(name, help, labels, || Metric::Histogram(Histogram::new(buckets))) {
+            Metric::Histogram(histogram) => histogram,
+            other => panic!("metric {name} is already registered as a {}", other.type_name()),
+        }
+    }
+
+    /// Encode every registered metric in the Prometheus text exposition format
+    pub fn encode_prometheus(&self) -> String {
+        let families = self.families.lock().unwrap();
+        crate::prometheus::encode(&families)
+    }
+
+    fn get_or_register(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        new_metric: impl FnOnce() -> Metric,
+    ) -> Metric {
+        assert!(is_valid_name(name), "invalid metric name: {name:?}");
+        for (label, _) in labels {
+            assert!(
+                is_valid_name(label) && !label.contains(':'),
+                "invalid label name {label:?} on metric {name}"
+            );
+        }
+
+        let labels: Labels = labels
+            .iter()
+            .map(|(label, value)| (label.to_string(), value.to_string()))
+            .collect();
+
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name.to_string()).or_insert_with(|| Family {
+            help: help.to_string(),
+            series: Vec::new(),
+        });
+
+        if let Some((_, metric)) = family.series.iter().find(|(existing, _)| *existing == labels) {
+            return metric.clone();
+        }
+
+        let metric = new_metric();
+        if let Some((_, existing)) = family.series.first() {
+            assert_eq!(
+                existing.type_name(),
+                metric.type_name(),
+                "metric {name} is already registered as a {}",
+                existing.type_name()
+            );
+        }
+        family.series.push((labels, metric.clone()));
+        metric
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_' || first == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registering_twice_returns_the_same_metric() {
+        let registry = Registry::new();
+        registry.counter("requests_total", "Requests").inc();
+        registry.counter("requests_total", "Requests").inc();
+        assert_eq!(registry.counter("requests_total", "Requests").get(), 2);
+
+        // Different labels are a different series of the same family
+        registry
+            .counter_with_labels("requests_total", "Requests", &[("kind", "mapping")])
+            .inc();
+        assert_eq!(registry.counter("requests_total", "Requests").get(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "already registered as a counter")]
+    fn test_kind_mismatch_panics() {
+        let registry = Registry::new();
+        registry.counter("depth", "Depth");
+        registry.gauge("depth", "Depth");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid metric name")]
+    fn test_invalid_name_panics() {
+        Registry::new().gauge("queue-depth", "Depth");
+    }
+}
@@ -129,6 +129,43 @@ fn bench_realistic_usage(c: &mut Criterion) {
         });
     }
 
+    // Priority MPSC draining each batch with a single recv_many
+    for &batch_size in &batch_sizes {
+        let bench_name = format!("priority_mpsc_recv_many_batch_{}", batch_size);
+        group.bench_function(&bench_name, |b| {
+            b.iter(|| {
+                rt.block_on(async {
+                    let (tx, mut rx) = unbounded_priority_queue_with_ordering::<BenchMessage, MaxPriority>();
+
+                    let num_batches = total_messages / batch_size;
+                    let mut message_id = 0;
+
+                    for _batch in 0..num_batches {
+                        for _i in 0..batch_size {
+                            let msg = BenchMessage {
+                                id: message_id,
+                                priority: (message_id % 100) as i64,
+                                data: vec![0u8; 64],
+                            };
+                            tx.send(msg);
+                            message_id += 1;
+                        }
+
+                        let mut batch_received = Vec::with_capacity(batch_size as usize);
+                        rx.recv_many(&mut batch_received, batch_size as usize).await;
+                        black_box(batch_received);
+                    }
+
+                    drop(tx);
+
+                    let mut remaining = Vec::new();
+                    while rx.recv_many(&mut remaining, batch_size as usize).await > 0 {}
+                    black_box(remaining);
+                });
+            });
+        });
+    }
+
     group.finish();
 }
 
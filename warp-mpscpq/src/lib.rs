@@ -1,8 +1,16 @@
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, OnceLock};
 use std::task::Poll;
 use tokio::sync::mpsc;
 
+pub mod metrics;
+pub mod shared;
+pub mod weighted;
+
+use metrics::QueueMetrics;
+
 /// Marker type for max-heap behavior (higher values = higher priority)
 pub struct MaxPriority;
 
@@ -22,70 +30,193 @@ impl PriorityOrdering for MinPriority {
     const REVERSE: bool = true; // Reverse ordering (min-heap)
 }
 
+/// What to do when an item arrives at a priority queue that is already at its capacity limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the newly arrived item
+    RejectNew,
+    /// Drop whichever item (including the newly arrived one) has the lowest priority
+    DropLowestPriority,
+    /// Drop whichever item has been waiting the longest
+    DropOldest,
+}
+
+/// Error returned by [`Receiver::recv_timeout`] and [`Receiver::recv_until`] when nothing arrived in time
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("timed out waiting for an item")]
+pub struct Elapsed;
+
+/// Wrapper for items that are only worth delivering before a deadline
+///
+/// Ordering is delegated to the wrapped item; the deadline only decides whether the item is still deliverable when it
+/// reaches the front of the queue. Use [`Receiver::recv_unexpired`] and [`Receiver::recv_many_unexpired`] to silently
+/// drop (and count) items whose deadline has passed instead of delivering them late.
+#[derive(Debug, Clone)]
+pub struct Deadlined<T> {
+    pub item: T,
+    pub deadline: std::time::Instant,
+}
+
+impl<T> Deadlined<T> {
+    #[inline]
+    pub fn new(item: T, deadline: std::time::Instant) -> Self {
+        Self { item, deadline }
+    }
+
+    #[inline]
+    pub fn is_expired(&self, now: std::time::Instant) -> bool {
+        self.deadline < now
+    }
+}
+
+impl<T: PartialEq> PartialEq for Deadlined<T> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.item == other.item
+    }
+}
+
+impl<T: Eq> Eq for Deadlined<T> {}
+
+impl<T: Ord> PartialOrd for Deadlined<T> {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Ord> Ord for Deadlined<T> {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.item.cmp(&other.item)
+    }
+}
+
+/// Trait for configuring what an item is prioritised by
+///
+/// The key is computed once when an item enters the priority queue and is stored alongside it.
+pub trait PriorityKey<T> {
+    type Key;
+
+    fn key(&self, item: &T) -> Self::Key;
+
+    fn compare(a: &T, a_key: &Self::Key, b: &T, b_key: &Self::Key) -> Ordering;
+}
+
+/// Prioritise items by their own `Ord` implementation (the default)
+pub struct ItemOrd;
+
+impl<T: Ord> PriorityKey<T> for ItemOrd {
+    type Key = ();
+
+    #[inline]
+    fn key(&self, _item: &T) -> Self::Key {}
+
+    #[inline]
+    fn compare(a: &T, _a_key: &Self::Key, b: &T, _b_key: &Self::Key) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Prioritise items by a key extracted with a closure; see [`unbounded_priority_queue_with_key`]
+pub struct KeyFn<F, K> {
+    key: F,
+    _key: std::marker::PhantomData<fn() -> K>,
+}
+
+impl<T, K, F> PriorityKey<T> for KeyFn<F, K>
+where
+    K: Ord,
+    F: Fn(&T) -> K,
+{
+    type Key = K;
+
+    #[inline]
+    fn key(&self, item: &T) -> Self::Key {
+        (self.key)(item)
+    }
+
+    #[inline]
+    fn compare(_a: &T, a_key: &Self::Key, _b: &T, b_key: &Self::Key) -> Ordering {
+        a_key.cmp(b_key)
+    }
+}
+
+/// Internal wrapper for items in the channel, timestamped only when metrics are attached
+struct Queued<T> {
+    item: T,
+    sent_at: Option<std::time::Instant>,
+}
+
+/// State shared between the senders and the receiver
+struct Shared {
+    queued: AtomicUsize,
+    metrics: OnceLock<Arc<dyn QueueMetrics>>,
+}
+
 /// Internal wrapper for items in the priority queue
-#[derive(Debug)]
-struct PriorityItem<T, O> {
+struct PriorityItem<T, O, P: PriorityKey<T>> {
     item: T,
+    key: P::Key,
     sequence: u64,
-    _ordering: std::marker::PhantomData<O>,
+    sent_at: Option<std::time::Instant>,
+    _ordering: std::marker::PhantomData<fn() -> (O, P)>,
 }
 
-impl<T, O> PriorityItem<T, O> {
+impl<T, O, P: PriorityKey<T>> PriorityItem<T, O, P> {
     #[inline]
-    fn new(item: T, sequence: u64) -> Self {
+    fn new(item: T, key: P::Key, sequence: u64, sent_at: Option<std::time::Instant>) -> Self {
         Self {
             item,
+            key,
             sequence,
+            sent_at,
             _ordering: std::marker::PhantomData,
         }
     }
 }
 
-impl<T, O> PartialEq for PriorityItem<T, O>
+impl<T, O, P> PartialEq for PriorityItem<T, O, P>
 where
-    T: Ord,
+    O: PriorityOrdering,
+    P: PriorityKey<T>,
 {
     #[inline]
     fn eq(&self, other: &Self) -> bool {
-        self.item == other.item && self.sequence == other.sequence
+        self.cmp(other) == Ordering::Equal
     }
 }
 
-impl<T, O> Eq for PriorityItem<T, O> where T: Ord {}
+impl<T, O, P> Eq for PriorityItem<T, O, P>
+where
+    O: PriorityOrdering,
+    P: PriorityKey<T>,
+{
+}
 
-impl<T, O> PartialOrd for PriorityItem<T, O>
+impl<T, O, P> PartialOrd for PriorityItem<T, O, P>
 where
-    T: Ord,
     O: PriorityOrdering,
+    P: PriorityKey<T>,
 {
     #[inline]
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        // Use the same ordering logic as Ord implementation
-        let item_cmp = if O::REVERSE {
-            other.item.cmp(&self.item)
-        } else {
-            self.item.cmp(&other.item)
-        };
-
-        Some(match item_cmp {
-            Ordering::Equal => other.sequence.cmp(&self.sequence), // Earlier sequence first
-            other => other,
-        })
+        Some(self.cmp(other))
     }
 }
 
-impl<T, O> Ord for PriorityItem<T, O>
+impl<T, O, P> Ord for PriorityItem<T, O, P>
 where
-    T: Ord,
     O: PriorityOrdering,
+    P: PriorityKey<T>,
 {
     #[inline]
     fn cmp(&self, other: &Self) -> Ordering {
         // Use const generics for compile-time optimization
         let item_cmp = if O::REVERSE {
-            other.item.cmp(&self.item)
+            P::compare(&other.item, &other.key, &self.item, &self.key)
         } else {
-            self.item.cmp(&other.item)
+            P::compare(&self.item, &self.key, &other.item, &other.key)
         };
 
         match item_cmp {
@@ -97,63 +228,473 @@ where
 
 /// Sender half of the priority queue - wraps tokio::sync::mpsc::UnboundedSender
 pub struct Sender<T> {
-    inner: mpsc::UnboundedSender<T>,
+    inner: mpsc::UnboundedSender<Queued<T>>,
+    shared: Arc<Shared>,
 }
 
 impl<T> Clone for Sender<T> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            shared: self.shared.clone(),
         }
     }
 }
 
 impl<T> Sender<T> {
     /// Send an item to the priority queue (infallible for unbounded queue)
+    ///
+    /// Items sent after the receiver has been closed or dropped are silently discarded; use [`Self::is_closed`] to
+    /// check for this.
     #[inline]
     pub fn send(&self, item: T) {
-        // This is infallible for unbounded channels, so we ignore the result
-        let _ = self.inner.send(item);
+        let metrics = self.shared.metrics.get();
+        let sent_at = metrics.map(|_| std::time::Instant::now());
+
+        // Count the item before it is visible to the receiver so that the receiver can never decrement first
+        self.shared.queued.fetch_add(1, AtomicOrdering::Relaxed);
+        // This is infallible for unbounded channels (unless the receiver is closed), so we ignore the result
+        if self.inner.send(Queued { item, sent_at }).is_err() {
+            self.shared.queued.fetch_sub(1, AtomicOrdering::Relaxed);
+        } else if let Some(metrics) = metrics {
+            metrics.on_enqueue();
+        }
+    }
+
+    /// Approximate number of items sent but not yet received, across all senders
+    ///
+    /// This is only a hint: it is updated without synchronising with the receiver so it may briefly lag behind.
+    #[inline]
+    pub fn queued_hint(&self) -> usize {
+        self.shared.queued.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Returns true if the receiver has been closed or dropped; nothing sent from now on will be received
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    /// Wait until the receiver has been closed or dropped
+    #[inline]
+    pub async fn closed(&self) {
+        self.inner.closed().await
+    }
+
+    /// Returns true if both senders feed the same receiver
+    #[inline]
+    pub fn same_channel(&self, other: &Self) -> bool {
+        self.inner.same_channel(&other.inner)
+    }
+
+    /// Create a [`WeakSender`] that does not keep the channel open
+    #[inline]
+    pub fn downgrade(&self) -> WeakSender<T> {
+        WeakSender {
+            inner: self.inner.downgrade(),
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Number of (strong) senders currently feeding the receiver, including this one
+    #[inline]
+    pub fn strong_count(&self) -> usize {
+        self.inner.strong_count()
+    }
+
+    /// Number of weak senders currently referencing the channel
+    #[inline]
+    pub fn weak_count(&self) -> usize {
+        self.inner.weak_count()
+    }
+}
+
+/// A sender that does not keep the channel open
+///
+/// Once every [`Sender`] has been dropped the receiver will drain the remaining items and then return `None`, even if
+/// weak senders are still around. Use [`WeakSender::upgrade`] to get a [`Sender`] while the channel is still open.
+pub struct WeakSender<T> {
+    inner: mpsc::WeakUnboundedSender<Queued<T>>,
+    shared: Arc<Shared>,
+}
+
+impl<T> Clone for WeakSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> WeakSender<T> {
+    /// Get a [`Sender`] if at least one other [`Sender`] is still alive
+    #[inline]
+    pub fn upgrade(&self) -> Option<Sender<T>> {
+        self.inner.upgrade().map(|inner| Sender {
+            inner,
+            shared: self.shared.clone(),
+        })
+    }
+}
+
+impl<T> Sender<Deadlined<T>> {
+    /// Send an item that should be dropped by the receiver if it is still queued after `deadline`
+    #[inline]
+    pub fn send_with_deadline(&self, item: T, deadline: std::time::Instant) {
+        self.send(Deadlined::new(item, deadline));
     }
 }
 
 /// Receiver half of the priority queue - maintains a BinaryHeap for priority ordering
-pub struct Receiver<T, O> {
-    inner: mpsc::UnboundedReceiver<T>,
-    priority_queue: BinaryHeap<PriorityItem<T, O>>,
+pub struct Receiver<T, O, P: PriorityKey<T> = ItemOrd> {
+    inner: mpsc::UnboundedReceiver<Queued<T>>,
+    shared: Arc<Shared>,
+    priority_key: P,
+    priority_queue: BinaryHeap<PriorityItem<T, O, P>>,
     sequence_counter: u64,
+    expired_count: u64,
+    capacity_limit: Option<(usize, OverflowPolicy)>,
+    evicted_count: u64,
     _ordering: std::marker::PhantomData<O>,
 }
 
-impl<T, O> Receiver<T, O>
+impl<T, O, P> Receiver<T, O, P>
 where
-    T: Ord,
     O: PriorityOrdering,
+    P: PriorityKey<T>,
 {
+    /// Limit the priority queue to `capacity` items, applying `policy` whenever it would be exceeded
+    ///
+    /// The limit is enforced whenever items are moved out of the channel and into the priority queue (i.e. on every
+    /// receive), so items sent between receives can still temporarily exceed it. Items dropped to enforce the limit are
+    /// counted in [`Self::evicted_count`].
+    #[inline]
+    pub fn with_capacity_limit(mut self, capacity: usize, policy: OverflowPolicy) -> Self {
+        self.capacity_limit = Some((capacity, policy));
+        self.enforce_capacity_limit();
+        self
+    }
+
+    /// Report enqueues, dequeues, evictions and per-item queue latency to `metrics`
+    ///
+    /// Only items sent after this is called are reported. Metrics can only be attached once per queue; later calls
+    /// are ignored.
+    #[inline]
+    pub fn with_metrics(self, metrics: Arc<dyn QueueMetrics>) -> Self {
+        let _ = self.shared.metrics.set(metrics);
+        self
+    }
+
+    /// Number of items dropped so far to keep the priority queue within its capacity limit
+    #[inline]
+    pub fn evicted_count(&self) -> u64 {
+        self.evicted_count
+    }
+
+    /// Number of items waiting to be received: both those already in the priority queue and those not yet drained from
+    /// the channel
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.priority_queue.len() + self.inner.len()
+    }
+
+    /// Returns true if there is nothing waiting to be received right now
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.priority_queue.is_empty() && self.inner.is_empty()
+    }
+
+    /// Close the receiving half of the queue without dropping it
+    ///
+    /// Senders will observe [`Sender::is_closed`] and anything they send from now on is discarded. Items that were
+    /// already sent are still delivered; once they have been drained [`Self::recv`] returns `None`.
+    #[inline]
+    pub fn close(&mut self) {
+        self.inner.close();
+    }
+
+    /// Returns true if no more items can be sent, i.e. [`Self::close`] was called or all senders have been dropped
+    ///
+    /// Items that were already sent may still be waiting to be received; see [`Self::is_terminated`].
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    /// Returns true if the queue is closed and every item has been received; [`Self::recv`] will never yield again
+    #[inline]
+    pub fn is_terminated(&self) -> bool {
+        self.inner.is_closed() && self.inner.is_empty() && self.priority_queue.is_empty()
+    }
+
+    /// Number of (strong) senders still feeding this receiver
+    #[inline]
+    pub fn sender_strong_count(&self) -> usize {
+        self.inner.sender_strong_count()
+    }
+
+    /// Number of weak senders still referencing this receiver
+    #[inline]
+    pub fn sender_weak_count(&self) -> usize {
+        self.inner.sender_weak_count()
+    }
+
     /// Receive the next highest priority item
+    ///
+    /// This is cancel safe: items drained from the channel stay in the priority queue if the future is dropped, so no
+    /// items are lost when used in `tokio::select!` or with a timeout.
     #[inline]
     pub async fn recv(&mut self) -> Option<T> {
-        std::future::poll_fn(|cx| {
-            // First, drain any available messages from the channel into the priority queue
-            let len = self.inner.len();
+        std::future::poll_fn(|cx| match self.poll_fill(cx) {
+            Poll::Ready(true) => Poll::Ready(self.pop()),
+            Poll::Ready(false) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        })
+        .await
+    }
+
+    /// Receive the next highest priority item, giving up after `timeout`
+    ///
+    /// Returns `Ok(None)` if the queue is empty and all senders have been dropped, like [`Self::recv`].
+    #[inline]
+    pub async fn recv_timeout(&mut self, timeout: std::time::Duration) -> Result<Option<T>, Elapsed> {
+        tokio::time::timeout(timeout, self.recv()).await.map_err(|_| Elapsed)
+    }
+
+    /// Receive the next highest priority item, giving up once `deadline` has passed
+    ///
+    /// Anything already queued is still returned if `deadline` is in the past.
+    #[inline]
+    pub async fn recv_until(&mut self, deadline: std::time::Instant) -> Result<Option<T>, Elapsed> {
+        tokio::time::timeout_at(deadline.into(), self.recv())
+            .await
+            .map_err(|_| Elapsed)
+    }
+
+    /// Receive up to `limit` of the highest priority items, appending them to `buffer` in priority order
+    ///
+    /// Mirrors `tokio::sync::mpsc::Receiver::recv_many`: this waits until at least one item is available and returns
+    /// the number of items appended. Returns 0 if `limit` is 0 or if the queue is empty and all senders have been
+    /// dropped.
+    #[inline]
+    pub async fn recv_many(&mut self, buffer: &mut Vec<T>, limit: usize) -> usize {
+        if limit == 0 {
+            return 0;
+        }
+
+        std::future::poll_fn(|cx| match self.poll_fill(cx) {
+            Poll::Ready(true) => {
+                let count = limit.min(self.priority_queue.len());
+                buffer.reserve(count);
+                buffer.extend(std::iter::from_fn(|| self.pop()).take(count));
+                Poll::Ready(count)
+            }
+            Poll::Ready(false) => Poll::Ready(0),
+            Poll::Pending => Poll::Pending,
+        })
+        .await
+    }
+
+    /// Look at the next highest priority item without removing it
+    ///
+    /// Anything already waiting in the channel is moved into the priority queue first, so this returns the same item
+    /// the next [`Self::recv`] would (unless more items arrive in between). Returns `None` if nothing is waiting; this
+    /// never waits for items to arrive.
+    #[inline]
+    pub fn peek(&mut self) -> Option<&T> {
+        self.fill_now();
+        self.priority_queue.peek().map(|priority_item| &priority_item.item)
+    }
+
+    /// Move everything currently waiting in the channel into the priority queue without waiting
+    fn fill_now(&mut self) {
+        let mut pushed = false;
+        while let Ok(item) = self.inner.try_recv() {
+            self.push(item);
+            pushed = true;
+        }
+        if pushed {
+            self.enforce_capacity_limit();
+        }
+    }
+
+    /// Move everything currently waiting in the channel into the priority queue
+    ///
+    /// Resolves to `true` once the priority queue has at least one item in it, or `false` if the priority queue is
+    /// empty and all senders have been dropped.
+    fn poll_fill(&mut self, cx: &mut std::task::Context<'_>) -> Poll<bool> {
+        // First, drain any available messages from the channel into the priority queue
+        let len = self.inner.len();
+        if len > 0 {
             let mut buffer = Vec::with_capacity(len);
             if self.inner.poll_recv_many(cx, &mut buffer, len).is_ready() {
                 for item in buffer {
-                    let priority_item = PriorityItem::new(item, self.sequence_counter);
-                    self.sequence_counter += 1;
-                    self.priority_queue.push(priority_item);
+                    self.push(item);
                 }
+                self.enforce_capacity_limit();
             }
+        }
+
+        if !self.priority_queue.is_empty() {
+            return Poll::Ready(true);
+        }
 
-            // Now return the next item from the priority queue
-            if let Some(priority_item) = self.priority_queue.pop() {
-                return Poll::Ready(Some(priority_item.item));
+        // Priority queue is empty, poll for new messages
+        loop {
+            match self.inner.poll_recv(cx) {
+                Poll::Ready(Some(item)) => {
+                    self.push(item);
+                    self.enforce_capacity_limit();
+                    // With a capacity limit of 0 the item was dropped, and the senders may still send more
+                    if !self.priority_queue.is_empty() {
+                        return Poll::Ready(true);
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(false),
+                Poll::Pending => return Poll::Pending,
             }
+        }
+    }
 
-            // Priority queue is empty, poll for new messages
-            self.inner.poll_recv(cx)
-        })
-        .await
+    #[inline]
+    fn pop(&mut self) -> Option<T> {
+        let priority_item = self.priority_queue.pop()?;
+        self.shared.queued.fetch_sub(1, AtomicOrdering::Relaxed);
+        if let (Some(sent_at), Some(metrics)) = (priority_item.sent_at, self.shared.metrics.get()) {
+            metrics.on_dequeue(sent_at.elapsed());
+        }
+        Some(priority_item.item)
+    }
+
+    #[inline]
+    fn push(&mut self, queued: Queued<T>) {
+        if let Some((capacity, OverflowPolicy::RejectNew)) = self.capacity_limit {
+            if self.priority_queue.len() >= capacity {
+                self.evict(queued.sent_at);
+                return;
+            }
+        }
+
+        let key = self.priority_key.key(&queued.item);
+        let priority_item = PriorityItem::new(queued.item, key, self.sequence_counter, queued.sent_at);
+        self.sequence_counter += 1;
+        self.priority_queue.push(priority_item);
+    }
+
+    /// Evict items until the priority queue is back within its capacity limit
+    ///
+    /// This is done once per batch of pushes rather than per item so that sustained overflow costs O(n) per receive
+    /// instead of O(n) per item.
+    fn enforce_capacity_limit(&mut self) {
+        let Some((capacity, policy)) = self.capacity_limit else {
+            return;
+        };
+        if self.priority_queue.len() <= capacity {
+            return;
+        }
+
+        let excess = self.priority_queue.len() - capacity;
+        let mut items = std::mem::take(&mut self.priority_queue).into_vec();
+        if excess < items.len() {
+            // Partition so that the `excess` items to be evicted come first
+            match policy {
+                // RejectNew is enforced as items are pushed; only reachable if the limit was set on a non-empty queue
+                OverflowPolicy::RejectNew | OverflowPolicy::DropOldest => {
+                    items.select_nth_unstable_by_key(excess, |priority_item| priority_item.sequence);
+                }
+                OverflowPolicy::DropLowestPriority => {
+                    items.select_nth_unstable(excess);
+                }
+            }
+        }
+        for priority_item in items.drain(..excess) {
+            self.evict(priority_item.sent_at);
+        }
+        self.priority_queue = BinaryHeap::from(items);
+    }
+
+    #[inline]
+    fn evict(&mut self, sent_at: Option<std::time::Instant>) {
+        self.shared.queued.fetch_sub(1, AtomicOrdering::Relaxed);
+        self.evicted_count += 1;
+        if let (Some(_), Some(metrics)) = (sent_at, self.shared.metrics.get()) {
+            metrics.on_evict();
+        }
+    }
+}
+
+impl<T, O, K, F> Receiver<T, O, KeyFn<F, K>>
+where
+    O: PriorityOrdering,
+    K: Ord,
+    F: Fn(&T) -> K,
+{
+    /// Look at the priority key of the next highest priority item without removing it; see [`Self::peek`]
+    #[inline]
+    pub fn peek_priority(&mut self) -> Option<&K> {
+        self.fill_now();
+        self.priority_queue.peek().map(|priority_item| &priority_item.key)
+    }
+}
+
+impl<T, O, P> Receiver<Deadlined<T>, O, P>
+where
+    O: PriorityOrdering,
+    P: PriorityKey<Deadlined<T>>,
+{
+    /// Receive the next highest priority item whose deadline has not yet passed
+    ///
+    /// Expired items reaching the front of the queue are dropped and counted in [`Self::expired_count`].
+    #[inline]
+    pub async fn recv_unexpired(&mut self) -> Option<T> {
+        loop {
+            let deadlined = self.recv().await?;
+            if deadlined.is_expired(std::time::Instant::now()) {
+                self.expired_count += 1;
+                continue;
+            }
+            return Some(deadlined.item);
+        }
+    }
+
+    /// Receive up to `limit` of the highest priority items whose deadlines have not yet passed
+    ///
+    /// Behaves like [`Self::recv_many`], except expired items are dropped and counted in [`Self::expired_count`]. This
+    /// only returns 0 if `limit` is 0 or if the queue is empty and all senders have been dropped.
+    #[inline]
+    pub async fn recv_many_unexpired(&mut self, buffer: &mut Vec<T>, limit: usize) -> usize {
+        if limit == 0 {
+            return 0;
+        }
+
+        let mut received = Vec::with_capacity(limit);
+        loop {
+            if self.recv_many(&mut received, limit).await == 0 {
+                return 0;
+            }
+
+            let now = std::time::Instant::now();
+            let count_before = buffer.len();
+            for deadlined in received.drain(..) {
+                if deadlined.is_expired(now) {
+                    self.expired_count += 1;
+                } else {
+                    buffer.push(deadlined.item);
+                }
+            }
+
+            let count = buffer.len() - count_before;
+            if count > 0 {
+                return count;
+            }
+        }
+    }
+
+    /// Number of items dropped so far because their deadline passed while they were queued
+    #[inline]
+    pub fn expired_count(&self) -> u64 {
+        self.expired_count
     }
 }
 
@@ -162,15 +703,53 @@ pub fn unbounded_priority_queue_with_ordering<T, O>() -> (Sender<T>, Receiver<T,
 where
     T: Ord,
     O: PriorityOrdering,
+{
+    unbounded_priority_queue_with_priority_key(ItemOrd)
+}
+
+/// Create a priority queue that prioritises items by `key(&item)` instead of requiring `T: Ord`
+///
+/// The key is extracted once as each item enters the priority queue; items with equal keys are received in the order
+/// they were sent.
+#[inline]
+pub fn unbounded_priority_queue_with_key<T, O, K, F>(key: F) -> (Sender<T>, Receiver<T, O, KeyFn<F, K>>)
+where
+    O: PriorityOrdering,
+    K: Ord,
+    F: Fn(&T) -> K,
+{
+    unbounded_priority_queue_with_priority_key(KeyFn {
+        key,
+        _key: std::marker::PhantomData,
+    })
+}
+
+#[inline]
+fn unbounded_priority_queue_with_priority_key<T, O, P>(priority_key: P) -> (Sender<T>, Receiver<T, O, P>)
+where
+    O: PriorityOrdering,
+    P: PriorityKey<T>,
 {
     let (tx, rx) = mpsc::unbounded_channel();
 
-    let sender = Sender { inner: tx };
+    let shared = Arc::new(Shared {
+        queued: AtomicUsize::new(0),
+        metrics: OnceLock::new(),
+    });
+    let sender = Sender {
+        inner: tx,
+        shared: shared.clone(),
+    };
 
     let receiver = Receiver {
         inner: rx,
+        shared,
+        priority_key,
         priority_queue: BinaryHeap::new(),
         sequence_counter: 0,
+        expired_count: 0,
+        capacity_limit: None,
+        evicted_count: 0,
         _ordering: std::marker::PhantomData,
     };
 
@@ -356,4 +935,468 @@ mod tests {
         let msg3 = rx.recv().await.unwrap();
         assert_eq!(msg3.priority, 10);
     }
+
+    #[tokio::test]
+    async fn test_recv_many_priority_ordering() {
+        let (tx, mut rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+
+        for (id, priority) in [(1, 10), (2, 50), (3, 30), (4, 40), (5, 20)] {
+            tx.send(TestMessage {
+                id,
+                priority,
+                data: "batch".to_string(),
+            });
+        }
+
+        drop(tx);
+
+        let mut buffer = Vec::new();
+        assert_eq!(rx.recv_many(&mut buffer, 3).await, 3);
+        assert_eq!(
+            buffer.iter().map(|msg| msg.priority).collect::<Vec<_>>(),
+            vec![50, 40, 30]
+        );
+
+        assert_eq!(rx.recv_many(&mut buffer, 3).await, 2);
+        assert_eq!(
+            buffer.iter().map(|msg| msg.priority).collect::<Vec<_>>(),
+            vec![50, 40, 30, 20, 10]
+        );
+
+        assert_eq!(rx.recv_many(&mut buffer, 3).await, 0);
+        assert_eq!(buffer.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_recv_many_zero_limit() {
+        let (tx, mut rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+
+        tx.send(TestMessage {
+            id: 1,
+            priority: 10,
+            data: "unread".to_string(),
+        });
+
+        let mut buffer = Vec::new();
+        assert_eq!(rx.recv_many(&mut buffer, 0).await, 0);
+        assert!(buffer.is_empty());
+
+        assert_eq!(rx.recv().await.unwrap().id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_recv_unexpired_drops_expired_items() {
+        let (tx, mut rx) = unbounded_priority_queue_with_ordering::<Deadlined<TestMessage>, MaxPriority>();
+        let now = std::time::Instant::now();
+        let past = now - std::time::Duration::from_secs(1);
+        let future = now + std::time::Duration::from_secs(60);
+
+        for (id, priority, deadline) in [(1, 10, future), (2, 50, past), (3, 30, future), (4, 40, past)] {
+            tx.send_with_deadline(
+                TestMessage {
+                    id,
+                    priority,
+                    data: "deadlined".to_string(),
+                },
+                deadline,
+            );
+        }
+
+        drop(tx);
+
+        assert_eq!(rx.recv_unexpired().await.unwrap().id, 3);
+        assert_eq!(rx.expired_count(), 2);
+        assert_eq!(rx.recv_unexpired().await.unwrap().id, 1);
+        assert!(rx.recv_unexpired().await.is_none());
+        assert_eq!(rx.expired_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_recv_many_unexpired_skips_fully_expired_batches() {
+        let (tx, mut rx) = unbounded_priority_queue_with_ordering::<Deadlined<TestMessage>, MaxPriority>();
+        let now = std::time::Instant::now();
+        let past = now - std::time::Duration::from_secs(1);
+        let future = now + std::time::Duration::from_secs(60);
+
+        for (id, priority, deadline) in [(1, 10, future), (2, 50, past), (3, 40, past), (4, 20, future)] {
+            tx.send_with_deadline(
+                TestMessage {
+                    id,
+                    priority,
+                    data: "deadlined".to_string(),
+                },
+                deadline,
+            );
+        }
+
+        drop(tx);
+
+        // The two highest priority items have expired so the first batch of 2 should be skipped entirely
+        let mut buffer = Vec::new();
+        assert_eq!(rx.recv_many_unexpired(&mut buffer, 2).await, 2);
+        assert_eq!(buffer.iter().map(|msg| msg.id).collect::<Vec<_>>(), vec![4, 1]);
+        assert_eq!(rx.expired_count(), 2);
+
+        assert_eq!(rx.recv_many_unexpired(&mut buffer, 2).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_close_delivers_queued_items() {
+        let (tx, mut rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+
+        tx.send(TestMessage {
+            id: 1,
+            priority: 10,
+            data: "before close".to_string(),
+        });
+        tx.send(TestMessage {
+            id: 2,
+            priority: 20,
+            data: "before close".to_string(),
+        });
+
+        assert!(!tx.is_closed());
+        assert!(!rx.is_closed());
+
+        rx.close();
+
+        assert!(tx.is_closed());
+        assert!(rx.is_closed());
+        assert!(!rx.is_terminated());
+
+        tx.send(TestMessage {
+            id: 3,
+            priority: 30,
+            data: "after close".to_string(),
+        });
+
+        assert_eq!(rx.recv().await.unwrap().id, 2);
+        assert_eq!(rx.recv().await.unwrap().id, 1);
+        assert!(rx.is_terminated());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_weak_sender_does_not_keep_channel_open() {
+        let (tx, mut rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+        let weak_tx = tx.downgrade();
+
+        assert_eq!(rx.sender_strong_count(), 1);
+        assert_eq!(rx.sender_weak_count(), 1);
+
+        let upgraded = weak_tx.upgrade().unwrap();
+        assert!(upgraded.same_channel(&tx));
+        assert_eq!(tx.strong_count(), 2);
+        upgraded.send(TestMessage {
+            id: 1,
+            priority: 10,
+            data: "upgraded".to_string(),
+        });
+        drop(upgraded);
+        drop(tx);
+
+        assert!(weak_tx.upgrade().is_none());
+        assert!(rx.is_closed());
+        assert!(!rx.is_terminated());
+        assert_eq!(rx.recv().await.unwrap().id, 1);
+        assert!(rx.recv().await.is_none());
+        assert!(rx.is_terminated());
+    }
+
+    #[tokio::test]
+    async fn test_sender_closed_resolves_when_receiver_dropped() {
+        let (tx, rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+
+        let closed_task = tokio::spawn({
+            let tx = tx.clone();
+            async move { tx.closed().await }
+        });
+
+        drop(rx);
+        closed_task.await.unwrap();
+        assert!(tx.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_len_counts_heap_and_channel() {
+        let (tx, mut rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+        assert!(rx.is_empty());
+        assert_eq!(tx.queued_hint(), 0);
+
+        for id in 0..5 {
+            tx.send(TestMessage {
+                id,
+                priority: id as i64,
+                data: "depth".to_string(),
+            });
+        }
+
+        assert_eq!(rx.len(), 5);
+        assert_eq!(tx.queued_hint(), 5);
+
+        // Receiving one item drains the rest of the channel into the heap; the total depth should be unaffected
+        assert_eq!(rx.recv().await.unwrap().id, 4);
+        assert_eq!(rx.len(), 4);
+        assert_eq!(tx.queued_hint(), 4);
+
+        tx.send(TestMessage {
+            id: 5,
+            priority: 0,
+            data: "depth".to_string(),
+        });
+        assert_eq!(rx.len(), 5);
+        assert_eq!(tx.queued_hint(), 5);
+
+        let mut buffer = Vec::new();
+        rx.recv_many(&mut buffer, 10).await;
+        assert!(rx.is_empty());
+        assert_eq!(tx.queued_hint(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_queued_hint_ignores_sends_after_close() {
+        let (tx, mut rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+        rx.close();
+
+        tx.send(TestMessage {
+            id: 1,
+            priority: 10,
+            data: "discarded".to_string(),
+        });
+
+        assert_eq!(tx.queued_hint(), 0);
+        assert!(rx.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_key_function_priority() {
+        // Deliberately not Ord; only prioritised through the key function
+        struct Unordered {
+            id: u32,
+            deadline: std::time::Instant,
+        }
+
+        let (tx, mut rx) = unbounded_priority_queue_with_key::<_, MinPriority, _, _>(|msg: &Unordered| msg.deadline);
+        let now = std::time::Instant::now();
+
+        for (id, offset_ms) in [(1, 30), (2, 10), (3, 20), (4, 10)] {
+            tx.send(Unordered {
+                id,
+                deadline: now + std::time::Duration::from_millis(offset_ms),
+            });
+        }
+
+        drop(tx);
+
+        let mut ids = Vec::new();
+        while let Some(msg) = rx.recv().await {
+            ids.push(msg.id);
+        }
+
+        // Earliest deadline first; equal deadlines in the order they were sent
+        assert_eq!(ids, vec![2, 4, 3, 1]);
+    }
+
+    fn send_priorities(tx: &Sender<TestMessage>, priorities: &[i64]) {
+        for (id, &priority) in priorities.iter().enumerate() {
+            tx.send(TestMessage {
+                id: id as u32,
+                priority,
+                data: "overflow".to_string(),
+            });
+        }
+    }
+
+    async fn recv_priorities<O: PriorityOrdering>(rx: &mut Receiver<TestMessage, O>) -> Vec<i64> {
+        let mut priorities = Vec::new();
+        while let Some(msg) = rx.recv().await {
+            priorities.push(msg.priority);
+        }
+        priorities
+    }
+
+    #[tokio::test]
+    async fn test_capacity_limit_reject_new() {
+        let (tx, rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+        let mut rx = rx.with_capacity_limit(3, OverflowPolicy::RejectNew);
+
+        send_priorities(&tx, &[10, 20, 30, 40, 50]);
+        drop(tx);
+
+        assert_eq!(recv_priorities(&mut rx).await, vec![30, 20, 10]);
+        assert_eq!(rx.evicted_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_capacity_limit_drop_lowest_priority() {
+        let (tx, rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+        let mut rx = rx.with_capacity_limit(3, OverflowPolicy::DropLowestPriority);
+
+        send_priorities(&tx, &[30, 10, 50, 20, 40]);
+        let queued_before = tx.queued_hint();
+        drop(tx);
+
+        assert_eq!(queued_before, 5);
+        assert_eq!(recv_priorities(&mut rx).await, vec![50, 40, 30]);
+        assert_eq!(rx.evicted_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_capacity_limit_drop_lowest_priority_min_ordering() {
+        let (tx, rx) = unbounded_priority_queue_with_ordering::<TestMessage, MinPriority>();
+        let mut rx = rx.with_capacity_limit(2, OverflowPolicy::DropLowestPriority);
+
+        send_priorities(&tx, &[30, 10, 50, 20, 40]);
+        drop(tx);
+
+        assert_eq!(recv_priorities(&mut rx).await, vec![10, 20]);
+        assert_eq!(rx.evicted_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_capacity_limit_drop_oldest() {
+        let (tx, rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+        let mut rx = rx.with_capacity_limit(3, OverflowPolicy::DropOldest);
+
+        send_priorities(&tx, &[50, 40, 10, 20, 30]);
+        drop(tx);
+
+        assert_eq!(recv_priorities(&mut rx).await, vec![30, 20, 10]);
+        assert_eq!(rx.evicted_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_capacity_limit_of_zero() {
+        let (tx, rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+        let mut rx = rx.with_capacity_limit(0, OverflowPolicy::RejectNew);
+        let timeout = std::time::Duration::from_millis(50);
+
+        // Everything is dropped, but the queue isn't closed while there are senders
+        send_priorities(&tx, &[10, 20]);
+        assert!(tokio::time::timeout(timeout, rx.recv()).await.is_err());
+        let delayed_tx = tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            send_priorities(&delayed_tx, &[30]);
+        });
+        let mut buffer = Vec::new();
+        assert!(tokio::time::timeout(timeout, rx.recv_many(&mut buffer, 4))
+            .await
+            .is_err());
+        assert_eq!(rx.evicted_count(), 3);
+
+        drop(tx);
+        assert!(rx.recv().await.is_none());
+        assert_eq!(rx.recv_many(&mut buffer, 4).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_capacity_limit_keeps_queued_hint_consistent() {
+        let (tx, rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+        let mut rx = rx.with_capacity_limit(2, OverflowPolicy::DropOldest);
+
+        send_priorities(&tx, &[1, 2, 3, 4]);
+        assert!(rx.recv().await.is_some());
+        assert_eq!(tx.queued_hint(), 1);
+        assert_eq!(rx.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_peek() {
+        let (tx, mut rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+        assert!(rx.peek().is_none());
+
+        send_priorities(&tx, &[10, 30, 20]);
+        assert_eq!(rx.peek().map(|msg| msg.priority), Some(30));
+        // Peeking doesn't consume anything
+        assert_eq!(rx.len(), 3);
+        assert_eq!(tx.queued_hint(), 3);
+
+        assert_eq!(rx.recv().await.unwrap().priority, 30);
+        assert_eq!(rx.peek().map(|msg| msg.priority), Some(20));
+
+        // Newly arrived higher priority items are visible straight away
+        send_priorities(&tx, &[50]);
+        assert_eq!(rx.peek().map(|msg| msg.priority), Some(50));
+    }
+
+    #[tokio::test]
+    async fn test_peek_priority() {
+        let (tx, mut rx) = unbounded_priority_queue_with_key::<(u32, &str), MinPriority, _, _>(|item| item.0);
+        assert!(rx.peek_priority().is_none());
+
+        tx.send((7, "seven"));
+        tx.send((3, "three"));
+        assert_eq!(rx.peek_priority(), Some(&3));
+        assert_eq!(rx.peek(), Some(&(3, "three")));
+        assert_eq!(rx.recv().await, Some((3, "three")));
+        assert_eq!(rx.peek_priority(), Some(&7));
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let (tx, rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+        // Sent before the metrics were attached, so not reported
+        send_priorities(&tx, &[1]);
+
+        let counters = Arc::new(metrics::QueueCounters::default());
+        let mut rx = rx
+            .with_metrics(counters.clone())
+            .with_capacity_limit(3, OverflowPolicy::DropLowestPriority);
+
+        send_priorities(&tx, &[10, 20, 30, 40]);
+        assert_eq!(counters.enqueued(), 4);
+        assert_eq!(counters.depth(), 4);
+
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        assert_eq!(rx.recv().await.unwrap().priority, 40);
+        // The untracked item had the lowest priority and was evicted without being reported
+        assert_eq!(rx.evicted_count(), 2);
+        assert_eq!(counters.evicted(), 1);
+        assert_eq!(counters.dequeued(), 1);
+        assert_eq!(counters.depth(), 2);
+        assert!(counters.max_latency() >= std::time::Duration::from_millis(5));
+        assert!(counters.mean_latency() >= std::time::Duration::from_millis(5));
+
+        drop(tx);
+        assert_eq!(recv_priorities(&mut rx).await, vec![30, 20]);
+        assert_eq!(counters.dequeued(), 3);
+        assert_eq!(counters.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_recv_timeout() {
+        let (tx, mut rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+
+        let timeout = std::time::Duration::from_millis(50);
+        assert_eq!(rx.recv_timeout(timeout).await, Err(Elapsed));
+
+        send_priorities(&tx, &[10, 20]);
+        assert_eq!(rx.recv_timeout(timeout).await.unwrap().unwrap().priority, 20);
+        assert_eq!(rx.recv_timeout(timeout).await.unwrap().unwrap().priority, 10);
+
+        // Items that arrive before the timeout are delivered
+        let delayed_tx = tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            send_priorities(&delayed_tx, &[30]);
+        });
+        assert_eq!(rx.recv_timeout(timeout).await.unwrap().unwrap().priority, 30);
+
+        drop(tx);
+        assert_eq!(rx.recv_timeout(timeout).await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_recv_until() {
+        let (tx, mut rx) = unbounded_priority_queue_with_ordering::<TestMessage, MaxPriority>();
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(50);
+        assert_eq!(rx.recv_until(deadline).await, Err(Elapsed));
+
+        // Already queued items are returned even when the deadline has passed
+        send_priorities(&tx, &[10]);
+        assert_eq!(rx.recv_until(deadline).await.unwrap().unwrap().priority, 10);
+        assert_eq!(rx.recv_until(deadline).await, Err(Elapsed));
+    }
 }
@@ -0,0 +1,104 @@
+//! Instrumentation hooks for the priority queue
+//!
+//! Attach an implementation of [`QueueMetrics`] with [`crate::Receiver::with_metrics`] to observe enqueues, dequeues,
+//! evictions and how long each item waited between being sent and being received. [`QueueCounters`] is a ready-made
+//! implementation backed by atomic counters.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Callbacks invoked by the queue; every method defaults to doing nothing
+///
+/// These are called inline on the sending and receiving paths so implementations should be cheap and must not block.
+/// Only items sent after the metrics were attached are reported.
+pub trait QueueMetrics: Send + Sync {
+    /// An item was sent
+    #[inline]
+    fn on_enqueue(&self) {}
+
+    /// An item was received after waiting `latency` since it was sent
+    #[inline]
+    fn on_dequeue(&self, latency: Duration) {
+        let _ = latency;
+    }
+
+    /// An item was dropped without being received to keep the queue within its capacity limit
+    #[inline]
+    fn on_evict(&self) {}
+}
+
+/// [`QueueMetrics`] implementation that keeps running totals in atomic counters
+#[derive(Debug, Default)]
+pub struct QueueCounters {
+    enqueued: AtomicU64,
+    dequeued: AtomicU64,
+    evicted: AtomicU64,
+    total_latency_ns: AtomicU64,
+    max_latency_ns: AtomicU64,
+}
+
+impl QueueCounters {
+    /// Number of items sent
+    #[inline]
+    pub fn enqueued(&self) -> u64 {
+        self.enqueued.load(Ordering::Relaxed)
+    }
+
+    /// Number of items received
+    #[inline]
+    pub fn dequeued(&self) -> u64 {
+        self.dequeued.load(Ordering::Relaxed)
+    }
+
+    /// Number of items dropped to keep the queue within its capacity limit
+    #[inline]
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Number of items sent but not yet received or evicted
+    ///
+    /// Enqueues are recorded just after the item is sent, so this may briefly undercount while a send is in progress.
+    #[inline]
+    pub fn depth(&self) -> u64 {
+        self.enqueued()
+            .saturating_sub(self.dequeued())
+            .saturating_sub(self.evicted())
+    }
+
+    /// Mean time between an item being sent and being received, or zero if nothing has been received yet
+    #[inline]
+    pub fn mean_latency(&self) -> Duration {
+        let dequeued = self.dequeued();
+        if dequeued == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(self.total_latency_ns.load(Ordering::Relaxed) / dequeued)
+    }
+
+    /// Longest time any item waited between being sent and being received
+    #[inline]
+    pub fn max_latency(&self) -> Duration {
+        Duration::from_nanos(self.max_latency_ns.load(Ordering::Relaxed))
+    }
+}
+
+impl QueueMetrics for QueueCounters {
+    #[inline]
+    fn on_enqueue(&self) {
+        self.enqueued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn on_dequeue(&self, latency: Duration) {
+        let latency_ns = latency.as_nanos().min(u64::MAX as u128) as u64;
+        self.total_latency_ns.fetch_add(latency_ns, Ordering::Relaxed);
+        self.max_latency_ns.fetch_max(latency_ns, Ordering::Relaxed);
+        self.dequeued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn on_evict(&self) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,161 @@
+//! Work sharing between several consumers of one priority queue
+//!
+//! [`SharedReceiver`] is a cloneable handle to a [`Receiver`] so that a pool of worker tasks can each pull the next
+//! highest priority item. Only one worker waits on the channel at a time; the rest queue up (in FIFO order) behind an
+//! async mutex that is held just long enough to pop an item, never while the item is being processed.
+
+use crate::{Elapsed, ItemOrd, PriorityKey, PriorityOrdering, Receiver};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Cloneable receiving handle shared by several consumers; see [`Receiver::into_shared`]
+pub struct SharedReceiver<T, O, P: PriorityKey<T> = ItemOrd> {
+    inner: Arc<Mutex<Receiver<T, O, P>>>,
+}
+
+impl<T, O, P: PriorityKey<T>> Clone for SharedReceiver<T, O, P> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T, O, P> Receiver<T, O, P>
+where
+    O: PriorityOrdering,
+    P: PriorityKey<T>,
+{
+    /// Turn this receiver into a handle that can be cloned and handed to several worker tasks
+    #[inline]
+    pub fn into_shared(self) -> SharedReceiver<T, O, P> {
+        SharedReceiver {
+            inner: Arc::new(Mutex::new(self)),
+        }
+    }
+
+    /// Split this receiver into `n` handles sharing the same queue, e.g. one per worker task
+    #[inline]
+    pub fn split_consumers(self, n: usize) -> Vec<SharedReceiver<T, O, P>> {
+        let shared = self.into_shared();
+        std::iter::repeat_n(shared, n).collect()
+    }
+}
+
+impl<T, O, P> SharedReceiver<T, O, P>
+where
+    O: PriorityOrdering,
+    P: PriorityKey<T>,
+{
+    /// Receive the next highest priority item not already taken by another consumer
+    ///
+    /// Returns `None` once the queue is empty and all senders have been dropped. Cancel safe.
+    #[inline]
+    pub async fn recv(&self) -> Option<T> {
+        self.inner.lock().await.recv().await
+    }
+
+    /// Receive up to `limit` of the highest priority items; see [`Receiver::recv_many`]
+    #[inline]
+    pub async fn recv_many(&self, buffer: &mut Vec<T>, limit: usize) -> usize {
+        self.inner.lock().await.recv_many(buffer, limit).await
+    }
+
+    /// Receive the next highest priority item, giving up after `timeout` (including time spent waiting for other
+    /// consumers)
+    #[inline]
+    pub async fn recv_timeout(&self, timeout: std::time::Duration) -> Result<Option<T>, Elapsed> {
+        tokio::time::timeout(timeout, self.recv()).await.map_err(|_| Elapsed)
+    }
+
+    /// Receive the next highest priority item, giving up once `deadline` has passed
+    #[inline]
+    pub async fn recv_until(&self, deadline: std::time::Instant) -> Result<Option<T>, Elapsed> {
+        tokio::time::timeout_at(deadline.into(), self.recv())
+            .await
+            .map_err(|_| Elapsed)
+    }
+
+    /// Close the queue for every consumer; see [`Receiver::close`]
+    #[inline]
+    pub async fn close(&self) {
+        self.inner.lock().await.close();
+    }
+
+    /// Number of consumer handles sharing this queue
+    #[inline]
+    pub fn consumer_count(&self) -> usize {
+        Arc::strong_count(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{unbounded_priority_queue_with_ordering, MaxPriority};
+
+    #[tokio::test]
+    async fn test_consumers_share_items_in_priority_order() {
+        let (tx, rx) = unbounded_priority_queue_with_ordering::<u32, MaxPriority>();
+        let consumers = rx.split_consumers(2);
+        assert_eq!(consumers[0].consumer_count(), 2);
+
+        for item in [1, 4, 2, 3] {
+            tx.send(item);
+        }
+
+        assert_eq!(consumers[0].recv().await, Some(4));
+        assert_eq!(consumers[1].recv().await, Some(3));
+        assert_eq!(consumers[1].recv().await, Some(2));
+        assert_eq!(consumers[0].recv().await, Some(1));
+
+        drop(tx);
+        assert_eq!(consumers[0].recv().await, None);
+        assert_eq!(consumers[1].recv().await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_worker_pool_receives_every_item_once() {
+        let (tx, rx) = unbounded_priority_queue_with_ordering::<u32, MaxPriority>();
+        let shared = rx.into_shared();
+
+        let workers = (0..4)
+            .map(|_| {
+                let rx = shared.clone();
+                tokio::spawn(async move {
+                    let mut received = Vec::new();
+                    while let Some(item) = rx.recv().await {
+                        received.push(item);
+                        tokio::task::yield_now().await;
+                    }
+                    received
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(shared);
+
+        for item in 0..1000 {
+            tx.send(item);
+        }
+        drop(tx);
+
+        let mut received = Vec::new();
+        for worker in workers {
+            received.extend(worker.await.unwrap());
+        }
+        received.sort();
+        assert_eq!(received, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_close_from_any_consumer() {
+        let (tx, rx) = unbounded_priority_queue_with_ordering::<u32, MaxPriority>();
+        let rx = rx.into_shared();
+        let other = rx.clone();
+
+        tx.send(1);
+        other.close().await;
+        assert!(tx.is_closed());
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, None);
+    }
+}
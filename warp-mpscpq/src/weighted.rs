@@ -0,0 +1,306 @@
+//! Weighted fair queueing across a fixed set of priority classes
+//!
+//! Unlike the strict priority queue, lower priority classes still make progress under sustained load from higher
+//! priority classes: with weights of `[8, 2, 1]` and every class backlogged, items are received from the classes in
+//! an 8:2:1 ratio. Items within a class are received in the order they were sent.
+//!
+//! Classes are picked using smooth weighted round robin so that the classes are interleaved rather than served in
+//! bursts (e.g. `8, 2, 1` yields `A A B A A C A A A B A` rather than `A A A A A A A A B B C`).
+
+use std::collections::VecDeque;
+use std::task::Poll;
+use tokio::sync::mpsc;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("at least one priority class is required")]
+    NoClasses,
+    #[error("priority class {0} has a weight of zero")]
+    ZeroWeight(usize),
+    #[error("priority class {class} is out of range (queue has {num_classes} classes)")]
+    InvalidClass { class: usize, num_classes: usize },
+}
+
+/// Sender half of the weighted fair queue
+pub struct Sender<T> {
+    inner: mpsc::UnboundedSender<(usize, T)>,
+    num_classes: usize,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            num_classes: self.num_classes,
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Send an item into priority class `class` (0 is the first weight passed to [`weighted_fair_queue`])
+    ///
+    /// Items sent after the receiver has been closed or dropped are silently discarded; use [`Self::is_closed`] to
+    /// check for this.
+    #[inline]
+    pub fn send(&self, class: usize, item: T) -> Result<(), Error> {
+        if class >= self.num_classes {
+            return Err(Error::InvalidClass {
+                class,
+                num_classes: self.num_classes,
+            });
+        }
+        // This is infallible for unbounded channels (unless the receiver is closed), so we ignore the result
+        let _ = self.inner.send((class, item));
+        Ok(())
+    }
+
+    /// Number of priority classes this queue was created with
+    #[inline]
+    pub fn num_classes(&self) -> usize {
+        self.num_classes
+    }
+
+    /// Returns true if the receiver has been closed or dropped; nothing sent from now on will be received
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+}
+
+struct PriorityClass<T> {
+    weight: i64,
+    current_weight: i64,
+    items: VecDeque<T>,
+}
+
+/// Receiver half of the weighted fair queue
+pub struct Receiver<T> {
+    inner: mpsc::UnboundedReceiver<(usize, T)>,
+    classes: Vec<PriorityClass<T>>,
+    queued: usize,
+}
+
+impl<T> Receiver<T> {
+    /// Receive the next item according to the class weights
+    #[inline]
+    pub async fn recv(&mut self) -> Option<T> {
+        std::future::poll_fn(|cx| match self.poll_fill(cx) {
+            Poll::Ready(true) => Poll::Ready(self.pop()),
+            Poll::Ready(false) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        })
+        .await
+    }
+
+    /// Receive up to `limit` items according to the class weights, appending them to `buffer`
+    ///
+    /// Returns 0 if `limit` is 0 or if the queue is empty and all senders have been dropped.
+    #[inline]
+    pub async fn recv_many(&mut self, buffer: &mut Vec<T>, limit: usize) -> usize {
+        if limit == 0 {
+            return 0;
+        }
+
+        std::future::poll_fn(|cx| match self.poll_fill(cx) {
+            Poll::Ready(true) => {
+                let count = limit.min(self.queued);
+                buffer.reserve(count);
+                buffer.extend(std::iter::from_fn(|| self.pop()).take(count));
+                Poll::Ready(count)
+            }
+            Poll::Ready(false) => Poll::Ready(0),
+            Poll::Pending => Poll::Pending,
+        })
+        .await
+    }
+
+    /// Number of items waiting to be received across all classes
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.queued + self.inner.len()
+    }
+
+    /// Returns true if there is nothing waiting to be received right now
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.queued == 0 && self.inner.is_empty()
+    }
+
+    /// Number of items of `class` that have been drained from the channel but not yet received
+    #[inline]
+    pub fn class_len(&self, class: usize) -> usize {
+        self.classes.get(class).map(|class| class.items.len()).unwrap_or(0)
+    }
+
+    /// Close the receiving half of the queue without dropping it; items already sent are still delivered
+    #[inline]
+    pub fn close(&mut self) {
+        self.inner.close();
+    }
+
+    fn poll_fill(&mut self, cx: &mut std::task::Context<'_>) -> Poll<bool> {
+        // First, drain any available messages from the channel into their classes
+        let len = self.inner.len();
+        if len > 0 {
+            let mut buffer = Vec::with_capacity(len);
+            if self.inner.poll_recv_many(cx, &mut buffer, len).is_ready() {
+                for (class, item) in buffer {
+                    self.push(class, item);
+                }
+            }
+        }
+
+        if self.queued > 0 {
+            return Poll::Ready(true);
+        }
+
+        // Every class is empty, poll for new messages
+        match self.inner.poll_recv(cx) {
+            Poll::Ready(Some((class, item))) => {
+                self.push(class, item);
+                Poll::Ready(true)
+            }
+            Poll::Ready(None) => Poll::Ready(false),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    #[inline]
+    fn push(&mut self, class: usize, item: T) {
+        self.classes[class].items.push_back(item);
+        self.queued += 1;
+    }
+
+    /// Pick the next item using smooth weighted round robin across the non-empty classes
+    fn pop(&mut self) -> Option<T> {
+        let mut total_weight = 0;
+        let mut selected: Option<(usize, i64)> = None;
+
+        for (index, class) in self.classes.iter_mut().enumerate() {
+            if class.items.is_empty() {
+                continue;
+            }
+            class.current_weight += class.weight;
+            total_weight += class.weight;
+
+            // Ties go to the earlier (higher priority) class
+            if selected.is_none_or(|(_, current_weight)| class.current_weight > current_weight) {
+                selected = Some((index, class.current_weight));
+            }
+        }
+
+        let (selected, _) = selected?;
+        let selected = &mut self.classes[selected];
+        selected.current_weight -= total_weight;
+        self.queued -= 1;
+        selected.items.pop_front()
+    }
+}
+
+/// Create a weighted fair queue with one priority class per entry in `weights`
+pub fn weighted_fair_queue<T>(weights: &[u32]) -> Result<(Sender<T>, Receiver<T>), Error> {
+    if weights.is_empty() {
+        return Err(Error::NoClasses);
+    }
+    if let Some(class) = weights.iter().position(|&weight| weight == 0) {
+        return Err(Error::ZeroWeight(class));
+    }
+
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let sender = Sender {
+        inner: tx,
+        num_classes: weights.len(),
+    };
+
+    let receiver = Receiver {
+        inner: rx,
+        classes: weights
+            .iter()
+            .map(|&weight| PriorityClass {
+                weight: weight as i64,
+                current_weight: 0,
+                items: VecDeque::new(),
+            })
+            .collect(),
+        queued: 0,
+    };
+
+    Ok((sender, receiver))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_weights() {
+        assert_eq!(weighted_fair_queue::<u32>(&[]).err(), Some(Error::NoClasses));
+        assert_eq!(weighted_fair_queue::<u32>(&[4, 0, 1]).err(), Some(Error::ZeroWeight(1)));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_class() {
+        let (tx, _rx) = weighted_fair_queue::<u32>(&[2, 1]).unwrap();
+        assert_eq!(
+            tx.send(2, 0),
+            Err(Error::InvalidClass {
+                class: 2,
+                num_classes: 2
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_weighted_ratio_under_saturation() {
+        let (tx, mut rx) = weighted_fair_queue::<usize>(&[8, 2, 1]).unwrap();
+
+        for _ in 0..100 {
+            for class in 0..3 {
+                tx.send(class, class).unwrap();
+            }
+        }
+
+        let mut received = [0usize; 3];
+        for _ in 0..110 {
+            received[rx.recv().await.unwrap()] += 1;
+        }
+
+        assert_eq!(received, [80, 20, 10]);
+    }
+
+    #[tokio::test]
+    async fn test_smooth_interleaving() {
+        let (tx, mut rx) = weighted_fair_queue::<char>(&[8, 2, 1]).unwrap();
+
+        for _ in 0..8 {
+            tx.send(0, 'A').unwrap();
+        }
+        for _ in 0..2 {
+            tx.send(1, 'B').unwrap();
+        }
+        tx.send(2, 'C').unwrap();
+
+        let mut buffer = Vec::new();
+        assert_eq!(rx.recv_many(&mut buffer, 11).await, 11);
+        assert_eq!(buffer.into_iter().collect::<String>(), "AABAACAAABA");
+    }
+
+    #[tokio::test]
+    async fn test_fifo_within_class_and_idle_classes() {
+        let (tx, mut rx) = weighted_fair_queue::<u32>(&[8, 2, 1]).unwrap();
+
+        // Only the lowest priority class has traffic so it should get all the bandwidth
+        for id in 0..5 {
+            tx.send(2, id).unwrap();
+        }
+        drop(tx);
+
+        let mut ids = Vec::new();
+        while let Some(id) = rx.recv().await {
+            ids.push(id);
+        }
+        assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+        assert!(rx.is_empty());
+    }
+}
@@ -2,7 +2,7 @@ use proc_macro::TokenStream;
 use quote::quote;
 use syn::{Attribute, Data, DeriveInput, Fields, Meta, MetaNameValue, Type, parse_macro_input};
 
-#[proc_macro_derive(AeadMessage, attributes(message_id, Aead, AeadSerialisation))]
+#[proc_macro_derive(AeadMessage, attributes(message_id, Aead, AeadSerialisation, AeadExtension))]
 pub fn derive_aead_message(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
 
@@ -33,16 +33,32 @@ pub fn derive_aead_message(input: TokenStream) -> TokenStream {
 
     let public_struct = generate_public_struct(&public_struct_name, &fields.public_fields);
     let secret_struct = generate_secret_struct(&secret_struct_name, &fields.secret_fields);
+    let extension_structs = fields
+        .public_extensions
+        .iter()
+        .chain(fields.secret_extensions.iter())
+        .map(|field| generate_extension_struct(name, field));
 
     let nonce_impl = generate_nonce_impl(&fields.nonce_field);
-    let public_bytes_impl = generate_public_bytes_impl(&public_struct_name, &fields.public_fields);
-    let secret_bytes_impl = generate_secret_bytes_impl(&secret_struct_name, &fields.secret_fields);
+    let public_bytes_impl = generate_public_bytes_impl(
+        name,
+        &public_struct_name,
+        &fields.public_fields,
+        &fields.public_extensions,
+    );
+    let secret_bytes_impl = generate_secret_bytes_impl(
+        name,
+        &secret_struct_name,
+        &fields.secret_fields,
+        &fields.secret_extensions,
+    );
 
     let from_parts_impl = generate_from_parts_impl(name, &fields);
 
     let expanded = quote! {
         #public_struct
         #secret_struct
+        #(#extension_structs)*
 
         impl crate::codec::Message for #name {
             type AssociatedData = #public_struct_name;
@@ -87,12 +103,17 @@ type FieldInfo = (syn::Ident, syn::Type, Vec<Attribute>);
 struct FieldClassification {
     public_fields: Vec<FieldInfo>,
     secret_fields: Vec<FieldInfo>,
+    // Fields marked #[AeadExtension]; they are encoded after the other fields of their section
+    public_extensions: Vec<FieldInfo>,
+    secret_extensions: Vec<FieldInfo>,
     nonce_field: Option<FieldInfo>,
 }
 
 fn categorize_fields(fields: &syn::punctuated::Punctuated<syn::Field, syn::token::Comma>) -> FieldClassification {
     let mut public_fields = Vec::new();
     let mut secret_fields = Vec::new();
+    let mut public_extensions = Vec::new();
+    let mut secret_extensions = Vec::new();
     let mut nonce_field = None;
 
     for field in fields {
@@ -137,12 +158,26 @@ fn categorize_fields(fields: &syn::punctuated::Punctuated<syn::Field, syn::token
             )
         }
 
+        let is_extension = field.attrs.iter().any(|attr| attr.path().is_ident("AeadExtension"));
+        if is_extension && is_nonce {
+            panic!("Field {field_name} cannot be both a Nonce and an AeadExtension");
+        }
+        let field_info = (field_name.clone(), field_type.clone(), field.attrs.clone());
+
         if is_associated_data {
-            public_fields.push((field_name.clone(), field_type.clone(), field.attrs.clone()));
+            if is_extension {
+                public_extensions.push(field_info.clone());
+            } else {
+                public_fields.push(field_info.clone());
+            }
         }
 
         if is_encrypted {
-            secret_fields.push((field_name.clone(), field_type.clone(), field.attrs.clone()));
+            if is_extension {
+                secret_extensions.push(field_info.clone());
+            } else {
+                secret_fields.push(field_info.clone());
+            }
         }
 
         if is_nonce {
@@ -151,12 +186,16 @@ fn categorize_fields(fields: &syn::punctuated::Punctuated<syn::Field, syn::token
     }
 
     if public_fields.is_empty() && secret_fields.is_empty() {
-        panic!("Message must have at least one field marked as associated_data or encrypted");
+        panic!(
+            "Message must have at least one field marked as associated_data or encrypted that isn't an AeadExtension"
+        );
     }
 
     FieldClassification {
         public_fields,
         secret_fields,
+        public_extensions,
+        secret_extensions,
         nonce_field,
     }
 }
@@ -190,11 +229,22 @@ fn generate_public_struct(public_struct_name: &Type, public_fields: &[FieldInfo]
         quote! { #(#passthrough_attrs)* pub #name: #ty }
     });
 
+    let public_ref_name = borrowed_struct_name(public_struct_name);
+    let public_ref_field_defs = public_fields.iter().map(|(name, ty, attrs)| {
+        let passthrough_attrs = extract_passthrough_attributes(attrs);
+        quote! { #(#passthrough_attrs)* pub #name: &'a #ty }
+    });
+
     quote! {
         #[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
         pub struct #public_struct_name {
             #(#public_field_defs),*
         }
+
+        #[derive(bincode::Encode)]
+        pub(crate) struct #public_ref_name<'a> {
+            #(#public_ref_field_defs),*
+        }
     }
 }
 
@@ -208,14 +258,104 @@ fn generate_secret_struct(secret_struct_name: &Type, secret_fields: &[FieldInfo]
         quote! { #(#passthrough_attrs)* pub #name: #ty }
     });
 
+    let secret_ref_name = borrowed_struct_name(secret_struct_name);
+    let secret_ref_field_defs = secret_fields.iter().map(|(name, ty, attrs)| {
+        let passthrough_attrs = extract_passthrough_attributes(attrs);
+        quote! { #(#passthrough_attrs)* pub #name: &'a #ty }
+    });
+
     quote! {
         #[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
         pub(crate) struct #secret_struct_name {
             #(#secret_field_defs),*
         }
+
+        #[derive(bincode::Encode)]
+        pub(crate) struct #secret_ref_name<'a> {
+            #(#secret_ref_field_defs),*
+        }
     }
 }
 
+// The twin of a section's struct that borrows the message's fields, so that encoding doesn't copy them first
+fn borrowed_struct_name(struct_name: &Type) -> syn::Ident {
+    let syn::Type::Path(type_path) = struct_name else {
+        panic!("section structs are named");
+    };
+    let ident = type_path.path.get_ident().expect("section structs are named");
+    syn::Ident::new(&format!("{ident}Ref"), ident.span())
+}
+
+fn extension_struct_name(name: &syn::Ident, field_name: &syn::Ident) -> syn::Ident {
+    syn::Ident::new(&format!("{name}Extension_{field_name}"), name.span())
+}
+
+fn extension_ref_struct_name(name: &syn::Ident, field_name: &syn::Ident) -> syn::Ident {
+    syn::Ident::new(&format!("{name}ExtensionRef_{field_name}"), name.span())
+}
+
+// Extensions are encoded one at a time through a wrapper, so that their AeadSerialisation attributes still apply
+fn generate_extension_struct(name: &syn::Ident, (field_name, ty, attrs): &FieldInfo) -> proc_macro2::TokenStream {
+    let struct_name = extension_struct_name(name, field_name);
+    let ref_struct_name = extension_ref_struct_name(name, field_name);
+    let passthrough_attrs = extract_passthrough_attributes(attrs);
+    quote! {
+        #[allow(non_camel_case_types)]
+        #[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
+        pub(crate) struct #struct_name {
+            #(#passthrough_attrs)* pub value: #ty
+        }
+
+        #[allow(non_camel_case_types)]
+        #[derive(bincode::Encode)]
+        pub(crate) struct #ref_struct_name<'a> {
+            #(#passthrough_attrs)* pub value: &'a #ty
+        }
+    }
+}
+
+// Append each extension to `bytes`, in the order they are declared
+fn generate_extensions_encode(
+    name: &syn::Ident,
+    bytes: &syn::Ident,
+    extensions: &[FieldInfo],
+) -> proc_macro2::TokenStream {
+    let encodes = extensions.iter().map(|(field_name, _, _)| {
+        let struct_name = extension_ref_struct_name(name, field_name);
+        quote! {
+            bincode::encode_into_std_write(
+                #struct_name { value: &self.#field_name },
+                &mut #bytes,
+                crate::BINCODE_CONFIG,
+            )?;
+        }
+    });
+    quote! { #(#encodes)* }
+}
+
+// Decode each extension from what follows `read_size` bytes into `bytes`; one that an older peer left out takes its
+// default value, and whatever follows the extensions this version knows about was added by a newer peer and is ignored
+fn generate_extensions_decode(
+    name: &syn::Ident,
+    bytes: &syn::Ident,
+    extensions: &[FieldInfo],
+) -> proc_macro2::TokenStream {
+    let decodes = extensions.iter().map(|(field_name, _, _)| {
+        let struct_name = extension_struct_name(name, field_name);
+        quote! {
+            let #field_name = if read_size < #bytes.len() {
+                let (decoded, extension_size): (#struct_name, usize) =
+                    bincode::decode_from_slice(&#bytes[read_size..], crate::BINCODE_CONFIG)?;
+                read_size += extension_size;
+                decoded.value
+            } else {
+                Default::default()
+            };
+        }
+    });
+    quote! { #(#decodes)* }
+}
+
 fn generate_nonce_impl(nonce_field: &Option<FieldInfo>) -> proc_macro2::TokenStream {
     if let Some((nonce_name, nonce_type, _)) = nonce_field {
         // Generate specific implementations for known types
@@ -272,73 +412,104 @@ fn generate_nonce_impl(nonce_field: &Option<FieldInfo>) -> proc_macro2::TokenStr
     }
 }
 
-fn generate_public_bytes_impl(public_struct_name: &Type, public_fields: &[FieldInfo]) -> proc_macro2::TokenStream {
+fn generate_public_bytes_impl(
+    name: &syn::Ident,
+    public_struct_name: &Type,
+    public_fields: &[FieldInfo],
+    public_extensions: &[FieldInfo],
+) -> proc_macro2::TokenStream {
     let public_data = if !public_fields.is_empty() {
+        let public_ref_name = borrowed_struct_name(public_struct_name);
         let field_assignments = public_fields.iter().map(|(name, _, _)| {
-            quote! { #name: self.#name.clone() }
+            quote! { #name: &self.#name }
         });
         quote! {
-            let public_data = #public_struct_name { #(#field_assignments),* };
-            let public_bytes = bincode::encode_to_vec(&public_data, crate::BINCODE_CONFIG)?;
+            let public_data = #public_ref_name { #(#field_assignments),* };
+            let mut public_bytes = bincode::encode_to_vec(&public_data, crate::BINCODE_CONFIG)?;
         }
     } else {
-        quote! { let public_bytes : Vec<u8> = Vec::new(); }
+        quote! { let mut public_bytes : Vec<u8> = Vec::new(); }
     };
+    let extensions = generate_extensions_encode(name, &syn::Ident::new("public_bytes", name.span()), public_extensions);
 
     quote! {
+        #[allow(unused_mut)]
         fn public_bytes(&self) -> Result<Vec<u8>, crate::EncodeError> {
             #public_data
+            #extensions
             Ok(public_bytes)
         }
     }
 }
 
-fn generate_secret_bytes_impl(secret_struct_name: &Type, secret_fields: &[FieldInfo]) -> proc_macro2::TokenStream {
+fn generate_secret_bytes_impl(
+    name: &syn::Ident,
+    secret_struct_name: &Type,
+    secret_fields: &[FieldInfo],
+    secret_extensions: &[FieldInfo],
+) -> proc_macro2::TokenStream {
     let secret_data = if !secret_fields.is_empty() {
+        let secret_ref_name = borrowed_struct_name(secret_struct_name);
         let field_assignments = secret_fields.iter().map(|(name, _, _)| {
-            quote! { #name: self.#name.clone() }
+            quote! { #name: &self.#name }
         });
+        // Sealing appends to these bytes, so room is left for that up front
         quote! {
-            let secret_data = #secret_struct_name { #(#field_assignments),* };
-            let secret_bytes = bincode::encode_to_vec(&secret_data, crate::BINCODE_CONFIG)?;
+            let secret_data = #secret_ref_name { #(#field_assignments),* };
+            let mut secret_bytes = crate::codec::encode_with_spare_capacity(&secret_data, crate::codec::SEAL_OVERHEAD)?;
         }
     } else {
-        quote! { let secret_bytes : Vec<u8> = Vec::new(); }
+        quote! { let mut secret_bytes : Vec<u8> = Vec::new(); }
     };
+    let extensions = generate_extensions_encode(name, &syn::Ident::new("secret_bytes", name.span()), secret_extensions);
 
     quote! {
+        #[allow(unused_mut)]
         fn secret_bytes(&self) -> Result<Vec<u8>, crate::EncodeError> {
             #secret_data
+            #extensions
             Ok(secret_bytes)
         }
     }
 }
 
 fn generate_from_parts_impl(name: &syn::Ident, fields: &FieldClassification) -> proc_macro2::TokenStream {
+    let public_bytes = syn::Ident::new("public_bytes", name.span());
+    let public_extensions = generate_extensions_decode(name, &public_bytes, &fields.public_extensions);
     let public_decode = if !fields.public_fields.is_empty() {
         let public_struct_name = syn::Ident::new(&format!("{name}AssociatedData"), name.span());
         quote! {
-            let public_data: #public_struct_name = {
-                let (decoded, _): (#public_struct_name, usize) = bincode::decode_from_slice(public_bytes, crate::BINCODE_CONFIG).unwrap();
-                decoded
-            };
+            let (public_data, mut read_size): (#public_struct_name, usize) = bincode::decode_from_slice(public_bytes, crate::BINCODE_CONFIG)?;
+            #public_extensions
         }
     } else {
-        quote! {}
+        quote! {
+            let mut read_size = 0;
+            #public_extensions
+        }
     };
 
+    let secret_bytes = syn::Ident::new("secret_bytes", name.span());
+    let secret_extensions = generate_extensions_decode(name, &secret_bytes, &fields.secret_extensions);
     let secret_decode = if !fields.secret_fields.is_empty() {
         let secret_struct_name = syn::Ident::new(&format!("{name}EncryptedData"), name.span());
         quote! {
-            let secret_data: #secret_struct_name = {
-                let (decoded, _): (#secret_struct_name, usize) = bincode::decode_from_slice(secret_bytes, crate::BINCODE_CONFIG).unwrap();
-                decoded
-            };
+            let (secret_data, mut read_size): (#secret_struct_name, usize) = bincode::decode_from_slice(secret_bytes, crate::BINCODE_CONFIG)?;
+            #secret_extensions
         }
     } else {
-        quote! {}
+        quote! {
+            let mut read_size = 0;
+            #secret_extensions
+        }
     };
 
+    let extension_assignments = fields
+        .public_extensions
+        .iter()
+        .chain(fields.secret_extensions.iter())
+        .map(|(name, _, _)| quote! { #name });
+
     let field_assignments = fields
         .public_fields
         .iter()
@@ -412,13 +583,15 @@ fn generate_from_parts_impl(name: &syn::Ident, fields: &FieldClassification) ->
     };
 
     quote! {
-        fn from_parts(_nonce: &[u8; crate::codec::NONCE_SIZE], public_bytes: &[u8], secret_bytes: &[u8]) -> Self {
+        #[allow(unused_mut, unused_variables, unused_assignments)]
+        fn from_parts(_nonce: &[u8; crate::codec::NONCE_SIZE], public_bytes: &[u8], secret_bytes: &[u8]) -> Result<Self, crate::DecodeError> {
             #public_decode
             #secret_decode
-            Self {
+            Ok(Self {
                 #(#field_assignments,)*
+                #(#extension_assignments,)*
                 #nonce_assignment
-            }
+            })
         }
     }
 }
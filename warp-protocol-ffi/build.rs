@@ -0,0 +1,11 @@
+fn main() {
+    #[cfg(feature = "generate-header")]
+    {
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        cbindgen::generate(&crate_dir)
+            .expect("Unable to generate bindings")
+            .write_to_file(std::path::Path::new(&crate_dir).join("include/warp_protocol.h"));
+    }
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+}
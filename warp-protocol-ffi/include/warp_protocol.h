@@ -0,0 +1,188 @@
+#ifndef WARP_PROTOCOL_H
+#define WARP_PROTOCOL_H
+
+/* Generated by cbindgen from warp-protocol-ffi; rebuild with --features generate-header to update */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define WARP_PRIVATE_KEY_SIZE 32
+
+#define WARP_PUBLIC_KEY_SIZE 33
+
+#define WARP_NONCE_SIZE 12
+
+typedef enum WarpStatus {
+  WARP_STATUS_OK = 0,
+  WARP_STATUS_NULL_POINTER,
+  WARP_STATUS_BUFFER_TOO_SMALL,
+  WARP_STATUS_INVALID_KEY,
+  WARP_STATUS_INVALID_STRING,
+  WARP_STATUS_INVALID_ADDRESS,
+  WARP_STATUS_ENCODE_FAILED,
+  WARP_STATUS_DECODE_FAILED,
+  WARP_STATUS_DECRYPTION_FAILED,
+  // The message is not of the type the accessor expects
+  WARP_STATUS_UNEXPECTED_MESSAGE,
+  // A bug in this library; please report it
+  WARP_STATUS_PANIC,
+} WarpStatus;
+
+// Symmetric cipher shared between two keys, from [`warp_cipher_new`]
+typedef struct WarpCipher WarpCipher;
+
+// A decrypted wire message, from [`warp_message_decrypt`]
+typedef struct WarpMessage WarpMessage;
+
+typedef struct WarpSocketAddress {
+  // 4 or 6
+  uint8_t family;
+  // Network byte order; IPv4 addresses use the first four bytes
+  uint8_t ip[16];
+  uint16_t port;
+} WarpSocketAddress;
+
+// Borrowed view of a tunnel payload; the pointers are valid until the message is freed
+typedef struct WarpTunnelPayloadView {
+  uint64_t tracer;
+  // If true the tunnel is identified by `tunnel_id`, otherwise by the (not NUL-terminated) `tunnel_name`
+  bool has_numeric_id;
+  uint64_t tunnel_id;
+  const uint8_t *tunnel_name;
+  size_t tunnel_name_len;
+  const uint8_t *data;
+  size_t data_len;
+} WarpTunnelPayloadView;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Generate a new random private key
+enum WarpStatus warp_private_key_generate(uint8_t *out);
+
+enum WarpStatus warp_public_key_from_private(const uint8_t *private_key_bytes, uint8_t *out);
+
+// Write the key in the base32 form used by warp's config files, NUL-terminated
+enum WarpStatus warp_private_key_to_string(const uint8_t *private_key_bytes,
+                                           char *out,
+                                           size_t capacity);
+
+enum WarpStatus warp_private_key_from_string(const char *string, uint8_t *out);
+
+// Write the key in the base32 form used by warp's config files, NUL-terminated
+enum WarpStatus warp_public_key_to_string(const uint8_t *public_key_bytes,
+                                          char *out,
+                                          size_t capacity);
+
+enum WarpStatus warp_public_key_from_string(const char *string, uint8_t *out);
+
+// Create the cipher for talking to the holder of `peer_public_key`; returns null if either key is invalid
+//
+// Free it with [`warp_cipher_free`].
+struct WarpCipher *warp_cipher_new(const uint8_t *private_key_bytes,
+                                   const uint8_t *peer_public_key_bytes);
+
+void warp_cipher_free(struct WarpCipher *cipher);
+
+// Encrypt a message from its raw parts: bincode-encoded (standard config) associated data and encrypted fields
+//
+// `nonce` must be [`WARP_NONCE_SIZE`] bytes, or null for a random nonce. Prefer the typed `warp_*_encrypt`
+// functions where they exist.
+enum WarpStatus warp_wire_message_encrypt(const struct WarpCipher *cipher,
+                                          uint8_t message_id,
+                                          const uint8_t *nonce,
+                                          const uint8_t *associated_data,
+                                          size_t associated_data_len,
+                                          const uint8_t *encrypted_data,
+                                          size_t encrypted_data_len,
+                                          uint8_t *out,
+                                          size_t capacity,
+                                          size_t *out_len);
+
+// Encrypt a tunnel payload for the far gate; the tunnel is identified by `tunnel_name` unless it is null, in which
+// case `tunnel_id` is used
+enum WarpStatus warp_tunnel_payload_encrypt(const struct WarpCipher *cipher,
+                                            const char *tunnel_name,
+                                            uint64_t tunnel_id,
+                                            uint64_t tracer,
+                                            const uint8_t *data,
+                                            size_t data_len,
+                                            uint8_t *out,
+                                            size_t capacity,
+                                            size_t *out_len);
+
+// Encrypt a registration for warp-map, timestamped now; `cipher` must be shared with warp-map's key
+enum WarpStatus warp_register_request_encrypt(const struct WarpCipher *cipher,
+                                              const uint8_t *private_key_bytes,
+                                              uint8_t *out,
+                                              size_t capacity,
+                                              size_t *out_len);
+
+// Encrypt a query to warp-map for the addresses registered by `peer_public_key`, timestamped now
+enum WarpStatus warp_mapping_request_encrypt(const struct WarpCipher *cipher,
+                                             const uint8_t *peer_public_key_bytes,
+                                             uint8_t *out,
+                                             size_t capacity,
+                                             size_t *out_len);
+
+// Encrypt a request for the far gate to send to this message's source address instead of `replace`, sequenced by
+// the time now as warp does
+enum WarpStatus warp_peer_address_override_encrypt(const struct WarpCipher *cipher,
+                                                   const struct WarpSocketAddress *replace,
+                                                   uint8_t *out,
+                                                   size_t capacity,
+                                                   size_t *out_len);
+
+// Decrypt the first wire message in `input`, storing how many bytes it took up in `consumed`
+//
+// A datagram can hold several wire messages back to back, so call this again on the remaining bytes until they are
+// used up. Free the message with [`warp_message_free`].
+enum WarpStatus warp_message_decrypt(const struct WarpCipher *cipher,
+                                     const uint8_t *input_bytes,
+                                     size_t input_len,
+                                     size_t *consumed,
+                                     struct WarpMessage **out);
+
+void warp_message_free(struct WarpMessage *message);
+
+// The message's type id, e.g. 0xF1 for tunnel payloads
+uint8_t warp_message_id(const struct WarpMessage *message);
+
+// The raw bincode-encoded associated data fields of the message
+const uint8_t *warp_message_associated_data(const struct WarpMessage *message, size_t *len);
+
+// The raw bincode-encoded encrypted fields of the message, after decryption
+const uint8_t *warp_message_encrypted_data(const struct WarpMessage *message, size_t *len);
+
+enum WarpStatus warp_message_tunnel_payload(const struct WarpMessage *message,
+                                            struct WarpTunnelPayloadView *out);
+
+// The address warp-map saw the registration come from
+enum WarpStatus warp_message_register_response(const struct WarpMessage *message,
+                                               struct WarpSocketAddress *address);
+
+// Copy up to `capacity` of the peer's registered addresses into `endpoints`, storing the total number in `count`
+enum WarpStatus warp_message_mapping_response(const struct WarpMessage *message,
+                                              struct WarpSocketAddress *endpoints,
+                                              size_t capacity,
+                                              size_t *count);
+
+// Copy up to `capacity` of the host candidates the peer registered with into `candidates`, storing the total number
+// in `count`
+enum WarpStatus warp_message_mapping_response_host_candidates(const struct WarpMessage *message,
+                                                              struct WarpSocketAddress *candidates,
+                                                              size_t capacity,
+                                                              size_t *count);
+
+enum WarpStatus warp_message_peer_address_override(const struct WarpMessage *message,
+                                                   struct WarpSocketAddress *replace);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* WARP_PROTOCOL_H */
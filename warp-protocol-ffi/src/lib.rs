@@ -0,0 +1,803 @@
+//! C ABI for the warp wire format, so peers that aren't written in Rust can talk to warp and warp-map
+//!
+//! The generated header is `include/warp_protocol.h`; rebuild with `--features generate-header` after changing the
+//! API to refresh it.
+//!
+//! # Conventions
+//!
+//! - Every fallible function returns a [`WarpStatus`]; outputs are only valid when it is `WARP_STATUS_OK`.
+//! - Keys are passed as fixed-size byte arrays: [`WARP_PRIVATE_KEY_SIZE`] byte scalars and [`WARP_PUBLIC_KEY_SIZE`]
+//!   byte compressed SEC1 points.
+//! - Functions that produce bytes take an output buffer and its capacity, and always store the required length in
+//!   `out_len`. If the buffer is too small they return `WARP_STATUS_BUFFER_TOO_SMALL` without writing to it.
+//! - Input pointers may only be null when their length is zero.
+//! - Pointers returned by `warp_message_*` accessors borrow from the message and are invalid after
+//!   [`warp_message_free`].
+//!
+//! # Safety
+//!
+//! Every pointer must be null (where allowed) or valid for the documented number of bytes, and handles must come from
+//! the matching constructor in this library and not be used after they are freed.
+
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::{c_char, CStr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use warp_protocol::codec::{Message, UnencryptedWireMessage, WireMessage, NONCE_SIZE};
+use warp_protocol::messages;
+
+pub const WARP_PRIVATE_KEY_SIZE: usize = 32;
+pub const WARP_PUBLIC_KEY_SIZE: usize = 33;
+pub const WARP_NONCE_SIZE: usize = 12;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarpStatus {
+    Ok = 0,
+    NullPointer,
+    BufferTooSmall,
+    InvalidKey,
+    InvalidString,
+    InvalidAddress,
+    EncodeFailed,
+    DecodeFailed,
+    DecryptionFailed,
+    /// The message is not of the type the accessor expects
+    UnexpectedMessage,
+    /// A bug in this library; please report it
+    Panic,
+}
+
+/// Symmetric cipher shared between two keys, from [`warp_cipher_new`]
+pub struct WarpCipher(warp_protocol::Cipher);
+
+/// A decrypted wire message, from [`warp_message_decrypt`]
+pub struct WarpMessage {
+    message: UnencryptedWireMessage,
+    decoded: Decoded,
+}
+
+enum Decoded {
+    TunnelPayload(messages::TunnelPayload),
+    RegisterResponse(messages::RegisterResponse),
+    MappingResponse(messages::MappingResponse),
+    PeerAddressOverride(messages::PeerAddressOverride),
+    // Still readable through warp_message_associated_data and warp_message_encrypted_data
+    Other,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WarpSocketAddress {
+    /// 4 or 6
+    pub family: u8,
+    /// Network byte order; IPv4 addresses use the first four bytes
+    pub ip: [u8; 16],
+    pub port: u16,
+}
+
+/// Borrowed view of a tunnel payload; the pointers are valid until the message is freed
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct WarpTunnelPayloadView {
+    pub tracer: u64,
+    /// If true the tunnel is identified by `tunnel_id`, otherwise by the (not NUL-terminated) `tunnel_name`
+    pub has_numeric_id: bool,
+    pub tunnel_id: u64,
+    pub tunnel_name: *const u8,
+    pub tunnel_name_len: usize,
+    pub data: *const u8,
+    pub data_len: usize,
+}
+
+impl From<SocketAddr> for WarpSocketAddress {
+    fn from(address: SocketAddr) -> Self {
+        let mut ip = [0u8; 16];
+        let family = match address.ip() {
+            IpAddr::V4(v4) => {
+                ip[..4].copy_from_slice(&v4.octets());
+                4
+            }
+            IpAddr::V6(v6) => {
+                ip.copy_from_slice(&v6.octets());
+                6
+            }
+        };
+        Self {
+            family,
+            ip,
+            port: address.port(),
+        }
+    }
+}
+
+impl TryFrom<WarpSocketAddress> for SocketAddr {
+    type Error = WarpStatus;
+
+    fn try_from(address: WarpSocketAddress) -> Result<Self, Self::Error> {
+        let ip = match address.family {
+            4 => IpAddr::V4(Ipv4Addr::new(
+                address.ip[0],
+                address.ip[1],
+                address.ip[2],
+                address.ip[3],
+            )),
+            6 => IpAddr::V6(Ipv6Addr::from(address.ip)),
+            _ => return Err(WarpStatus::InvalidAddress),
+        };
+        Ok(SocketAddr::new(ip, address.port))
+    }
+}
+
+fn guard(f: impl FnOnce() -> Result<(), WarpStatus>) -> WarpStatus {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => WarpStatus::Ok,
+        Ok(Err(status)) => status,
+        Err(_) => WarpStatus::Panic,
+    }
+}
+
+unsafe fn input<'a>(ptr: *const u8, len: usize) -> Result<&'a [u8], WarpStatus> {
+    if len == 0 {
+        Ok(&[])
+    } else if ptr.is_null() {
+        Err(WarpStatus::NullPointer)
+    } else {
+        Ok(std::slice::from_raw_parts(ptr, len))
+    }
+}
+
+unsafe fn fixed_input<'a, const N: usize>(ptr: *const u8) -> Result<&'a [u8; N], WarpStatus> {
+    if ptr.is_null() {
+        Err(WarpStatus::NullPointer)
+    } else {
+        Ok(&*(ptr as *const [u8; N]))
+    }
+}
+
+unsafe fn reference<'a, T>(ptr: *const T) -> Result<&'a T, WarpStatus> {
+    ptr.as_ref().ok_or(WarpStatus::NullPointer)
+}
+
+unsafe fn write<T>(ptr: *mut T, value: T) -> Result<(), WarpStatus> {
+    if ptr.is_null() {
+        return Err(WarpStatus::NullPointer);
+    }
+    ptr.write(value);
+    Ok(())
+}
+
+unsafe fn write_addresses(
+    addresses: &[std::net::SocketAddr],
+    out: *mut WarpSocketAddress,
+    capacity: usize,
+    count: *mut usize,
+) -> Result<(), WarpStatus> {
+    write(count, addresses.len())?;
+    if addresses.len() > capacity {
+        return Err(WarpStatus::BufferTooSmall);
+    }
+    for (i, address) in addresses.iter().enumerate() {
+        write(out.add(i), (*address).into())?;
+    }
+    Ok(())
+}
+
+unsafe fn output(bytes: &[u8], out: *mut u8, capacity: usize, out_len: *mut usize) -> Result<(), WarpStatus> {
+    write(out_len, bytes.len())?;
+    if bytes.len() > capacity {
+        return Err(WarpStatus::BufferTooSmall);
+    }
+    if !bytes.is_empty() {
+        if out.is_null() {
+            return Err(WarpStatus::NullPointer);
+        }
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), out, bytes.len());
+    }
+    Ok(())
+}
+
+// Writes a NUL-terminated copy of `string`, which needs capacity for the terminator too
+unsafe fn output_string(string: &str, out: *mut c_char, capacity: usize) -> Result<(), WarpStatus> {
+    if out.is_null() {
+        return Err(WarpStatus::NullPointer);
+    }
+    if string.len() >= capacity {
+        return Err(WarpStatus::BufferTooSmall);
+    }
+    std::ptr::copy_nonoverlapping(string.as_ptr(), out as *mut u8, string.len());
+    *out.add(string.len()) = 0;
+    Ok(())
+}
+
+unsafe fn input_string<'a>(ptr: *const c_char) -> Result<&'a str, WarpStatus> {
+    if ptr.is_null() {
+        return Err(WarpStatus::NullPointer);
+    }
+    CStr::from_ptr(ptr).to_str().map_err(|_| WarpStatus::InvalidString)
+}
+
+fn private_key(bytes: &[u8; WARP_PRIVATE_KEY_SIZE]) -> Result<warp_protocol::PrivateKey, WarpStatus> {
+    warp_protocol::PrivateKey::from_slice(bytes).map_err(|_| WarpStatus::InvalidKey)
+}
+
+fn public_key(bytes: &[u8; WARP_PUBLIC_KEY_SIZE]) -> Result<warp_protocol::PublicKey, WarpStatus> {
+    warp_protocol::PublicKey::from_sec1_bytes(bytes).map_err(|_| WarpStatus::InvalidKey)
+}
+
+fn encrypt(cipher: &WarpCipher, message: impl Message) -> Result<Vec<u8>, WarpStatus> {
+    message
+        .encode()
+        .and_then(|encoded| encoded.encrypt(&cipher.0))
+        .and_then(|encrypted| encrypted.to_bytes())
+        .map_err(|_| WarpStatus::EncodeFailed)
+}
+
+/// Generate a new random private key
+#[no_mangle]
+pub unsafe extern "C" fn warp_private_key_generate(out: *mut u8) -> WarpStatus {
+    guard(|| {
+        let key = warp_protocol::PrivateKey::random(&mut rand::rng());
+        let mut out_len = 0;
+        output(&key.to_bytes(), out, WARP_PRIVATE_KEY_SIZE, &mut out_len)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn warp_public_key_from_private(private_key_bytes: *const u8, out: *mut u8) -> WarpStatus {
+    guard(|| {
+        let key = private_key(fixed_input(private_key_bytes)?)?;
+        let mut out_len = 0;
+        output(
+            &key.public_key().to_sec1_bytes(),
+            out,
+            WARP_PUBLIC_KEY_SIZE,
+            &mut out_len,
+        )
+    })
+}
+
+/// Write the key in the base32 form used by warp's config files, NUL-terminated
+#[no_mangle]
+pub unsafe extern "C" fn warp_private_key_to_string(
+    private_key_bytes: *const u8,
+    out: *mut c_char,
+    capacity: usize,
+) -> WarpStatus {
+    guard(|| {
+        let key = private_key(fixed_input(private_key_bytes)?)?;
+        output_string(&warp_protocol::crypto::privkey_to_string(&key), out, capacity)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn warp_private_key_from_string(string: *const c_char, out: *mut u8) -> WarpStatus {
+    guard(|| {
+        let key =
+            warp_protocol::crypto::privkey_from_string(input_string(string)?).map_err(|_| WarpStatus::InvalidKey)?;
+        let mut out_len = 0;
+        output(&key.to_bytes(), out, WARP_PRIVATE_KEY_SIZE, &mut out_len)
+    })
+}
+
+/// Write the key in the base32 form used by warp's config files, NUL-terminated
+#[no_mangle]
+pub unsafe extern "C" fn warp_public_key_to_string(
+    public_key_bytes: *const u8,
+    out: *mut c_char,
+    capacity: usize,
+) -> WarpStatus {
+    guard(|| {
+        let key = public_key(fixed_input(public_key_bytes)?)?;
+        output_string(&warp_protocol::crypto::pubkey_to_string(&key), out, capacity)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn warp_public_key_from_string(string: *const c_char, out: *mut u8) -> WarpStatus {
+    guard(|| {
+        let key =
+            warp_protocol::crypto::pubkey_from_string(input_string(string)?).map_err(|_| WarpStatus::InvalidKey)?;
+        let mut out_len = 0;
+        output(&key.to_sec1_bytes(), out, WARP_PUBLIC_KEY_SIZE, &mut out_len)
+    })
+}
+
+/// Create the cipher for talking to the holder of `peer_public_key`; returns null if either key is invalid
+///
+/// Free it with [`warp_cipher_free`].
+#[no_mangle]
+pub unsafe extern "C" fn warp_cipher_new(
+    private_key_bytes: *const u8,
+    peer_public_key_bytes: *const u8,
+) -> *mut WarpCipher {
+    let cipher = catch_unwind(|| -> Result<WarpCipher, WarpStatus> {
+        let private_key = private_key(fixed_input(private_key_bytes)?)?;
+        let peer_public_key = public_key(fixed_input(peer_public_key_bytes)?)?;
+        Ok(WarpCipher(warp_protocol::crypto::cipher_from_shared_secret(
+            &private_key,
+            &peer_public_key,
+        )))
+    });
+    match cipher {
+        Ok(Ok(cipher)) => Box::into_raw(Box::new(cipher)),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn warp_cipher_free(cipher: *mut WarpCipher) {
+    if !cipher.is_null() {
+        drop(Box::from_raw(cipher));
+    }
+}
+
+/// Encrypt a message from its raw parts: bincode-encoded (standard config) associated data and encrypted fields
+///
+/// `nonce` must be [`WARP_NONCE_SIZE`] bytes, or null for a random nonce. Prefer the typed `warp_*_encrypt`
+/// functions where they exist.
+#[no_mangle]
+pub unsafe extern "C" fn warp_wire_message_encrypt(
+    cipher: *const WarpCipher,
+    message_id: u8,
+    nonce: *const u8,
+    associated_data: *const u8,
+    associated_data_len: usize,
+    encrypted_data: *const u8,
+    encrypted_data_len: usize,
+    out: *mut u8,
+    capacity: usize,
+    out_len: *mut usize,
+) -> WarpStatus {
+    guard(|| {
+        let cipher = reference(cipher)?;
+        let nonce = if nonce.is_null() {
+            rand::random::<[u8; WARP_NONCE_SIZE]>()
+        } else {
+            *fixed_input::<WARP_NONCE_SIZE>(nonce)?
+        };
+        // The cipher only uses the first WARP_NONCE_SIZE bytes of the nonce
+        let mut padded_nonce = [0u8; NONCE_SIZE];
+        padded_nonce[..WARP_NONCE_SIZE].copy_from_slice(&nonce);
+        let message = UnencryptedWireMessage::from_raw_parts(
+            message_id,
+            padded_nonce,
+            input(associated_data, associated_data_len)?.to_vec(),
+            input(encrypted_data, encrypted_data_len)?.to_vec(),
+        );
+        let bytes = message
+            .encrypt(&cipher.0)
+            .and_then(|encrypted| encrypted.to_bytes())
+            .map_err(|_| WarpStatus::EncodeFailed)?;
+        output(&bytes, out, capacity, out_len)
+    })
+}
+
+/// Encrypt a tunnel payload for the far gate; the tunnel is identified by `tunnel_name` unless it is null, in which
+/// case `tunnel_id` is used
+#[no_mangle]
+pub unsafe extern "C" fn warp_tunnel_payload_encrypt(
+    cipher: *const WarpCipher,
+    tunnel_name: *const c_char,
+    tunnel_id: u64,
+    tracer: u64,
+    data: *const u8,
+    data_len: usize,
+    out: *mut u8,
+    capacity: usize,
+    out_len: *mut usize,
+) -> WarpStatus {
+    guard(|| {
+        let tunnel_id = if tunnel_name.is_null() {
+            messages::TunnelId::Id(tunnel_id)
+        } else {
+            messages::TunnelId::Name(input_string(tunnel_name)?.to_string())
+        };
+        let payload = messages::TunnelPayload::new(tunnel_id, tracer, input(data, data_len)?.to_vec());
+        output(&encrypt(reference(cipher)?, payload)?, out, capacity, out_len)
+    })
+}
+
+/// Encrypt a registration for warp-map, timestamped now; `cipher` must be shared with warp-map's key
+#[no_mangle]
+pub unsafe extern "C" fn warp_register_request_encrypt(
+    cipher: *const WarpCipher,
+    private_key_bytes: *const u8,
+    out: *mut u8,
+    capacity: usize,
+    out_len: *mut usize,
+) -> WarpStatus {
+    guard(|| {
+        let request = messages::RegisterRequest {
+            pubkey: private_key(fixed_input(private_key_bytes)?)?.public_key(),
+            timestamp: std::time::SystemTime::now(),
+            host_candidates: Vec::new(),
+        };
+        output(&encrypt(reference(cipher)?, request)?, out, capacity, out_len)
+    })
+}
+
+/// Encrypt a query to warp-map for the addresses registered by `peer_public_key`, timestamped now
+#[no_mangle]
+pub unsafe extern "C" fn warp_mapping_request_encrypt(
+    cipher: *const WarpCipher,
+    peer_public_key_bytes: *const u8,
+    out: *mut u8,
+    capacity: usize,
+    out_len: *mut usize,
+) -> WarpStatus {
+    guard(|| {
+        let request = messages::MappingRequest {
+            peer_pubkey: public_key(fixed_input(peer_public_key_bytes)?)?,
+            timestamp: std::time::SystemTime::now(),
+            subscribe: false,
+        };
+        output(&encrypt(reference(cipher)?, request)?, out, capacity, out_len)
+    })
+}
+
+/// Encrypt a request for the far gate to send to this message's source address instead of `replace`, sequenced by
+/// the time now as warp does
+#[no_mangle]
+pub unsafe extern "C" fn warp_peer_address_override_encrypt(
+    cipher: *const WarpCipher,
+    replace: *const WarpSocketAddress,
+    out: *mut u8,
+    capacity: usize,
+    out_len: *mut usize,
+) -> WarpStatus {
+    guard(|| {
+        let message = messages::PeerAddressOverride {
+            replace: (*reference(replace)?).try_into()?,
+            sequence: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_micros() as u64),
+        };
+        output(&encrypt(reference(cipher)?, message)?, out, capacity, out_len)
+    })
+}
+
+/// Decrypt the first wire message in `input`, storing how many bytes it took up in `consumed`
+///
+/// A datagram can hold several wire messages back to back, so call this again on the remaining bytes until they are
+/// used up. Free the message with [`warp_message_free`].
+#[no_mangle]
+pub unsafe extern "C" fn warp_message_decrypt(
+    cipher: *const WarpCipher,
+    input_bytes: *const u8,
+    input_len: usize,
+    consumed: *mut usize,
+    out: *mut *mut WarpMessage,
+) -> WarpStatus {
+    guard(|| {
+        let cipher = reference(cipher)?;
+        let bytes = input(input_bytes, input_len)?;
+        let (wire_message, remaining) = WireMessage::from_slice(bytes).map_err(|_| WarpStatus::DecodeFailed)?;
+        let message = wire_message
+            .decrypt(&cipher.0)
+            .map_err(|_| WarpStatus::DecryptionFailed)?;
+
+        // Decoding can still panic on malformed fields, which is a decode failure rather than a bug here
+        let decoded = catch_unwind(AssertUnwindSafe(|| match message.message_id {
+            messages::TunnelPayload::MESSAGE_ID => message.decode().map(Decoded::TunnelPayload),
+            messages::RegisterResponse::MESSAGE_ID => message.decode().map(Decoded::RegisterResponse),
+            messages::MappingResponse::MESSAGE_ID => message.decode().map(Decoded::MappingResponse),
+            messages::PeerAddressOverride::MESSAGE_ID => message.decode().map(Decoded::PeerAddressOverride),
+            _ => Ok(Decoded::Other),
+        }))
+        .map_err(|_| WarpStatus::DecodeFailed)?
+        .map_err(|_| WarpStatus::DecodeFailed)?;
+
+        write(consumed, bytes.len() - remaining.len())?;
+        write(out, Box::into_raw(Box::new(WarpMessage { message, decoded })))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn warp_message_free(message: *mut WarpMessage) {
+    if !message.is_null() {
+        drop(Box::from_raw(message));
+    }
+}
+
+/// The message's type id, e.g. 0xF1 for tunnel payloads
+#[no_mangle]
+pub unsafe extern "C" fn warp_message_id(message: *const WarpMessage) -> u8 {
+    message.as_ref().map_or(0, |message| message.message.message_id)
+}
+
+/// The raw bincode-encoded associated data fields of the message
+#[no_mangle]
+pub unsafe extern "C" fn warp_message_associated_data(message: *const WarpMessage, len: *mut usize) -> *const u8 {
+    borrowed_bytes(message.as_ref().map(|message| message.message.public_bytes()), len)
+}
+
+/// The raw bincode-encoded encrypted fields of the message, after decryption
+#[no_mangle]
+pub unsafe extern "C" fn warp_message_encrypted_data(message: *const WarpMessage, len: *mut usize) -> *const u8 {
+    borrowed_bytes(message.as_ref().map(|message| message.message.secret_bytes()), len)
+}
+
+unsafe fn borrowed_bytes(bytes: Option<&[u8]>, len: *mut usize) -> *const u8 {
+    let bytes = bytes.unwrap_or_default();
+    if !len.is_null() {
+        *len = bytes.len();
+    }
+    bytes.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn warp_message_tunnel_payload(
+    message: *const WarpMessage,
+    out: *mut WarpTunnelPayloadView,
+) -> WarpStatus {
+    guard(|| {
+        let Decoded::TunnelPayload(payload) = &reference(message)?.decoded else {
+            return Err(WarpStatus::UnexpectedMessage);
+        };
+        let (has_numeric_id, tunnel_id, tunnel_name) = match &payload.tunnel_id {
+            messages::TunnelId::Id(id) => (true, *id, &[][..]),
+            messages::TunnelId::Name(name) => (false, 0, name.as_bytes()),
+        };
+        write(
+            out,
+            WarpTunnelPayloadView {
+                tracer: payload.tracer,
+                has_numeric_id,
+                tunnel_id,
+                tunnel_name: tunnel_name.as_ptr(),
+                tunnel_name_len: tunnel_name.len(),
+                data: payload.data.as_ptr(),
+                data_len: payload.data.len(),
+            },
+        )
+    })
+}
+
+/// The address warp-map saw the registration come from
+#[no_mangle]
+pub unsafe extern "C" fn warp_message_register_response(
+    message: *const WarpMessage,
+    address: *mut WarpSocketAddress,
+) -> WarpStatus {
+    guard(|| {
+        let Decoded::RegisterResponse(response) = &reference(message)?.decoded else {
+            return Err(WarpStatus::UnexpectedMessage);
+        };
+        write(address, response.address.into())
+    })
+}
+
+/// Copy up to `capacity` of the peer's registered addresses into `endpoints`, storing the total number in `count`
+#[no_mangle]
+pub unsafe extern "C" fn warp_message_mapping_response(
+    message: *const WarpMessage,
+    endpoints: *mut WarpSocketAddress,
+    capacity: usize,
+    count: *mut usize,
+) -> WarpStatus {
+    guard(|| {
+        let Decoded::MappingResponse(response) = &reference(message)?.decoded else {
+            return Err(WarpStatus::UnexpectedMessage);
+        };
+        write_addresses(&response.endpoints, endpoints, capacity, count)
+    })
+}
+
+/// Copy up to `capacity` of the host candidates the peer registered with into `candidates`, storing the total number
+/// in `count`
+#[no_mangle]
+pub unsafe extern "C" fn warp_message_mapping_response_host_candidates(
+    message: *const WarpMessage,
+    candidates: *mut WarpSocketAddress,
+    capacity: usize,
+    count: *mut usize,
+) -> WarpStatus {
+    guard(|| {
+        let Decoded::MappingResponse(response) = &reference(message)?.decoded else {
+            return Err(WarpStatus::UnexpectedMessage);
+        };
+        write_addresses(&response.host_candidates, candidates, capacity, count)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn warp_message_peer_address_override(
+    message: *const WarpMessage,
+    replace: *mut WarpSocketAddress,
+) -> WarpStatus {
+    guard(|| {
+        let Decoded::PeerAddressOverride(message) = &reference(message)?.decoded else {
+            return Err(WarpStatus::UnexpectedMessage);
+        };
+        write(replace, message.replace.into())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr::{null, null_mut};
+
+    struct Keys {
+        private: [u8; WARP_PRIVATE_KEY_SIZE],
+        public: [u8; WARP_PUBLIC_KEY_SIZE],
+    }
+
+    fn keys() -> Keys {
+        let mut keys = Keys {
+            private: [0; WARP_PRIVATE_KEY_SIZE],
+            public: [0; WARP_PUBLIC_KEY_SIZE],
+        };
+        unsafe {
+            assert_eq!(warp_private_key_generate(keys.private.as_mut_ptr()), WarpStatus::Ok);
+            assert_eq!(
+                warp_public_key_from_private(keys.private.as_ptr(), keys.public.as_mut_ptr()),
+                WarpStatus::Ok
+            );
+        }
+        keys
+    }
+
+    #[test]
+    fn test_nonce_size_matches_protocol() {
+        assert_eq!(
+            WARP_NONCE_SIZE,
+            warp_protocol::CipherSuite::ChaCha20Poly1305.nonce_size()
+        );
+    }
+
+    #[test]
+    fn test_key_string_roundtrip() {
+        let keys = keys();
+        let mut string = [0 as c_char; 128];
+        let mut private = [0u8; WARP_PRIVATE_KEY_SIZE];
+        let mut public = [0u8; WARP_PUBLIC_KEY_SIZE];
+        unsafe {
+            assert_eq!(
+                warp_private_key_to_string(keys.private.as_ptr(), string.as_mut_ptr(), string.len()),
+                WarpStatus::Ok
+            );
+            assert_eq!(
+                warp_private_key_from_string(string.as_ptr(), private.as_mut_ptr()),
+                WarpStatus::Ok
+            );
+            assert_eq!(
+                warp_public_key_to_string(keys.public.as_ptr(), string.as_mut_ptr(), string.len()),
+                WarpStatus::Ok
+            );
+            assert_eq!(
+                warp_public_key_from_string(string.as_ptr(), public.as_mut_ptr()),
+                WarpStatus::Ok
+            );
+            assert_eq!(
+                warp_public_key_to_string(keys.public.as_ptr(), string.as_mut_ptr(), 4),
+                WarpStatus::BufferTooSmall
+            );
+        }
+        assert_eq!(private, keys.private);
+        assert_eq!(public, keys.public);
+    }
+
+    #[test]
+    fn test_tunnel_payload_roundtrip_with_rust_peer() {
+        let (a, b) = (keys(), keys());
+        let cipher_a = unsafe { warp_cipher_new(a.private.as_ptr(), b.public.as_ptr()) };
+        assert!(!cipher_a.is_null());
+
+        let data = b"from C with love";
+        let mut buf = [0u8; 256];
+        let mut len = 0;
+        unsafe {
+            assert_eq!(
+                warp_tunnel_payload_encrypt(
+                    cipher_a,
+                    null(),
+                    7,
+                    42,
+                    data.as_ptr(),
+                    data.len(),
+                    buf.as_mut_ptr(),
+                    4,
+                    &mut len
+                ),
+                WarpStatus::BufferTooSmall
+            );
+            assert_eq!(
+                warp_tunnel_payload_encrypt(
+                    cipher_a,
+                    null(),
+                    7,
+                    42,
+                    data.as_ptr(),
+                    data.len(),
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut len
+                ),
+                WarpStatus::Ok
+            );
+        }
+
+        // The Rust implementation on the other side understands it
+        let cipher_b = warp_protocol::crypto::cipher_from_shared_secret(
+            &warp_protocol::PrivateKey::from_slice(&b.private).unwrap(),
+            &warp_protocol::PublicKey::from_sec1_bytes(&a.public).unwrap(),
+        );
+        let (wire_message, remaining) = WireMessage::from_slice(&buf[..len]).unwrap();
+        assert!(remaining.is_empty());
+        let payload: messages::TunnelPayload = wire_message.decrypt(&cipher_b).unwrap().decode().unwrap();
+        assert_eq!(
+            payload,
+            messages::TunnelPayload::new(messages::TunnelId::Id(7), 42, data.to_vec())
+        );
+
+        // And the C API can read its reply
+        let reply = messages::TunnelPayload::new(messages::TunnelId::Name("video".into()), 43, b"ack".to_vec());
+        let reply = reply.encode().unwrap().encrypt(&cipher_b).unwrap().to_bytes().unwrap();
+        let mut message = null_mut();
+        let mut consumed = 0;
+        let mut view = std::mem::MaybeUninit::<WarpTunnelPayloadView>::uninit();
+        let mut address = std::mem::MaybeUninit::<WarpSocketAddress>::uninit();
+        unsafe {
+            assert_eq!(
+                warp_message_decrypt(cipher_a, reply.as_ptr(), reply.len(), &mut consumed, &mut message),
+                WarpStatus::Ok
+            );
+            assert_eq!(consumed, reply.len());
+            assert_eq!(warp_message_id(message), messages::TunnelPayload::MESSAGE_ID);
+            assert_eq!(
+                warp_message_register_response(message, address.as_mut_ptr()),
+                WarpStatus::UnexpectedMessage
+            );
+            assert_eq!(warp_message_tunnel_payload(message, view.as_mut_ptr()), WarpStatus::Ok);
+            let view = view.assume_init();
+            assert_eq!(view.tracer, 43);
+            assert!(!view.has_numeric_id);
+            assert_eq!(
+                std::slice::from_raw_parts(view.tunnel_name, view.tunnel_name_len),
+                b"video"
+            );
+            assert_eq!(std::slice::from_raw_parts(view.data, view.data_len), b"ack");
+
+            warp_message_free(message);
+            warp_cipher_free(cipher_a);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_rejects_tampering_and_garbage() {
+        let (a, b) = (keys(), keys());
+        let mut buf = [0u8; 256];
+        let mut len = 0;
+        let mut message = null_mut();
+        let mut consumed = 0;
+        unsafe {
+            let cipher = warp_cipher_new(a.private.as_ptr(), b.public.as_ptr());
+            let replace = WarpSocketAddress::from("192.0.2.1:4000".parse::<SocketAddr>().unwrap());
+            assert_eq!(
+                warp_peer_address_override_encrypt(cipher, &replace, buf.as_mut_ptr(), buf.len(), &mut len),
+                WarpStatus::Ok
+            );
+
+            // Flip a bit of the ciphertext, just past the nonce and the length prefixes
+            buf[WARP_NONCE_SIZE + 3] ^= 1;
+            assert_eq!(
+                warp_message_decrypt(cipher, buf.as_ptr(), len, &mut consumed, &mut message),
+                WarpStatus::DecryptionFailed
+            );
+            assert_eq!(
+                warp_message_decrypt(cipher, [0xFFu8; 3].as_ptr(), 3, &mut consumed, &mut message),
+                WarpStatus::DecodeFailed
+            );
+            assert_eq!(
+                warp_message_decrypt(cipher, null(), 0, &mut consumed, &mut message),
+                WarpStatus::DecodeFailed
+            );
+            assert!(message.is_null());
+
+            warp_cipher_free(cipher);
+        }
+    }
+}
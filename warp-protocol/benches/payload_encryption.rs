@@ -1,4 +1,3 @@
-use aead::KeyInit;
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use warp_protocol::codec::Message;
 use warp_protocol::*;
@@ -28,8 +27,8 @@ pub fn encrypted_vs_unencrypted(c: &mut Criterion) {
     let mut data = [0_u8; 1500];
     rand::fill(&mut data);
 
-    let key = crate::Cipher::generate_key().unwrap();
-    let cipher = crate::Cipher::new(&key);
+    let key: [u8; crypto::KEY_SIZE] = rand::random();
+    let cipher = crate::Cipher::new(CipherSuite::ChaCha20Poly1305, &key);
 
     let mut group = c.benchmark_group("Comparison");
 
@@ -61,24 +60,30 @@ pub fn encryption_time(c: &mut Criterion) {
     let mut data = [0u8; 2 << MAX_SIZE];
     rand::fill(&mut data);
 
-    let key = crate::Cipher::generate_key().unwrap();
-    let cipher = crate::Cipher::new(&key);
+    let key: [u8; crypto::KEY_SIZE] = rand::random();
 
     let mut group = c.benchmark_group("Encryption Time");
 
     group.measurement_time(core::time::Duration::from_secs(1));
     group.warm_up_time(core::time::Duration::from_millis(500));
 
-    for size in 0..MAX_SIZE {
-        group.bench_with_input(BenchmarkId::new("bytes", 2 << size), &size, |b, &size| {
-            b.iter(|| {
-                let message = TunnelPayloadEncrypted {
-                    tunnel_id: [1, 2, 3, 4, 5, 6, 7, 8],
-                    data: data[0..(2 << size)].to_vec(),
-                };
-                criterion::black_box(message.encode().unwrap().encrypt(&cipher).unwrap())
-            })
-        });
+    for suite in CipherSuite::ALL {
+        let cipher = crate::Cipher::new(suite, &key);
+        for size in 0..MAX_SIZE {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{suite:?} bytes"), 2 << size),
+                &size,
+                |b, &size| {
+                    b.iter(|| {
+                        let message = TunnelPayloadEncrypted {
+                            tunnel_id: [1, 2, 3, 4, 5, 6, 7, 8],
+                            data: data[0..(2 << size)].to_vec(),
+                        };
+                        criterion::black_box(message.encode().unwrap().encrypt(&cipher).unwrap())
+                    })
+                },
+            );
+        }
     }
 
     group.finish();
@@ -89,11 +94,11 @@ pub fn time_to_discover_incorrect_key(c: &mut Criterion) {
     let mut data = [0u8; 2 << MAX_SIZE];
     rand::fill(&mut data);
 
-    let key_encryption = crate::Cipher::generate_key().unwrap();
-    let cipher_encryption = crate::Cipher::new(&key_encryption);
+    let key_encryption: [u8; crypto::KEY_SIZE] = rand::random();
+    let cipher_encryption = crate::Cipher::new(CipherSuite::ChaCha20Poly1305, &key_encryption);
 
-    let key_decryption = crate::Cipher::generate_key().unwrap();
-    let cipher_decryption = crate::Cipher::new(&key_decryption);
+    let key_decryption: [u8; crypto::KEY_SIZE] = rand::random();
+    let cipher_decryption = crate::Cipher::new(CipherSuite::ChaCha20Poly1305, &key_decryption);
 
     let mut group = c.benchmark_group("Time to discover incorrect key");
 
@@ -107,7 +112,7 @@ pub fn time_to_discover_incorrect_key(c: &mut Criterion) {
         };
         let encrypted_message = message.encode().unwrap().encrypt(&cipher_encryption).unwrap();
         group.bench_with_input(BenchmarkId::new("bytes", 2 << size), &size, |b, &size| {
-            b.iter(|| match encrypted_message.clone().decrypt(&cipher_decryption) {
+            b.iter(|| match encrypted_message.decrypt(&cipher_decryption) {
                 Ok(_) => panic!("The message shouldn't be decipherable with the wrong key!"),
                 Err(e) => criterion::black_box(e),
             })
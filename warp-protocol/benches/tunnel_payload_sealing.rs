@@ -0,0 +1,87 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use warp_protocol::codec::Message;
+use warp_protocol::messages::{KeyedTunnelPayload, TunnelId, TunnelPayload};
+use warp_protocol::*;
+
+// Sealing one payload for every path: 3 interfaces, each with 2 addresses of the far gate
+const ROUTES: usize = 6;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+// As the accelerator sealed payloads before: the payload was copied to be kept for retransmission and again into a
+// KeyedTunnelPayload, and the ciphertext was copied for each path
+fn copy_per_send(payload: &TunnelPayload, key_tag: [u8; 4], cipher: &Cipher) -> Vec<Vec<u8>> {
+    let kept = payload.clone();
+    let mut encoded = KeyedTunnelPayload::new(payload.clone(), key_tag).encode().unwrap();
+    encoded.nonce = [0; codec::NONCE_SIZE];
+    let data = encoded.encrypt(cipher).unwrap().to_bytes().unwrap();
+    criterion::black_box(kept);
+    (0..ROUTES).map(|_| data.clone()).collect()
+}
+
+// As the accelerator seals them now: the payload is shared with the retransmission queue and sealed by reference, and
+// every path gets a reference to the same ciphertext
+fn seal_once(payload: &TunnelPayload, key_tag: [u8; 4], cipher: &Cipher) -> Vec<bytes::Bytes> {
+    let mut encoded = payload.encode_keyed(key_tag).unwrap();
+    encoded.nonce = [0; codec::NONCE_SIZE];
+    let data = bytes::Bytes::from(encoded.encrypt(cipher).unwrap().to_bytes().unwrap());
+    (0..ROUTES).map(|_| data.clone()).collect()
+}
+
+fn allocations<T>(mut f: impl FnMut() -> T) -> usize {
+    const ITERATIONS: usize = 1000;
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..ITERATIONS {
+        criterion::black_box(f());
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) / ITERATIONS
+}
+
+pub fn sealing_for_every_path(c: &mut Criterion) {
+    let mut data = vec![0u8; 1200];
+    rand::fill(&mut data[..]);
+    let payload = TunnelPayload::new(TunnelId::Name("video".to_string()), 7, data);
+
+    let key: [u8; crypto::KEY_SIZE] = rand::random();
+    let cipher = Cipher::new(CipherSuite::ChaCha20Poly1305, &key);
+    let key_tag = [1, 2, 3, 4];
+
+    println!(
+        "Allocations per payload sent over {ROUTES} paths: {} copying per send, {} sealing once",
+        allocations(|| copy_per_send(&payload, key_tag, &cipher)),
+        allocations(|| seal_once(&payload, key_tag, &cipher)),
+    );
+
+    let mut group = c.benchmark_group("Sealing for every path");
+    group.bench_function("Copy per send", |b| {
+        b.iter(|| copy_per_send(&payload, key_tag, &cipher))
+    });
+    group.bench_function("Seal once", |b| b.iter(|| seal_once(&payload, key_tag, &cipher)));
+    group.finish();
+}
+
+criterion_group!(benches, sealing_for_every_path);
+criterion_main!(benches);
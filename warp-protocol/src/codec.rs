@@ -1,6 +1,42 @@
-use aead::AeadCore;
+/// Size of the nonces messages are encoded with; only the first `CipherSuite::nonce_size` bytes are sent
+pub const NONCE_SIZE: usize = 24;
+
+/// What sealing a message adds to its encrypted fields: the message id and the authentication tag
+pub const SEAL_OVERHEAD: usize = 1 + crate::crypto::AEAD_TAG_SIZE;
+
+/// The message id padded messages are sealed under; their own message id is sealed just before it
+pub const PADDED_MESSAGE_ID: u8 = 0xFF;
+
+/// What padding a message adds to it besides the padding itself: the length of its encrypted fields and its own message
+/// id
+pub const PADDING_OVERHEAD: usize = 2 + 1;
+
+/// The length every frame of a stream transport starts with, as a big-endian u16 of the bytes after it
+pub const FRAME_HEADER_SIZE: usize = 2;
+
+/// Frame `datagram` for a stream transport such as TCP, where what would be one datagram is sent as its length followed
+/// by its bytes; as with a datagram, it can hold any number of wire messages
+pub fn frame(datagram: &[u8]) -> Result<Vec<u8>, crate::EncodeError> {
+    let length = u16::try_from(datagram.len()).map_err(|_| crate::EncodeError::TooLargeToFrame(datagram.len()))?;
+    let mut framed = Vec::with_capacity(FRAME_HEADER_SIZE + datagram.len());
+    framed.extend_from_slice(&length.to_be_bytes());
+    framed.extend_from_slice(datagram);
+    Ok(framed)
+}
 
-pub const NONCE_SIZE: usize = <<crate::Cipher as AeadCore>::NonceSize as aead::array::typenum::Unsigned>::USIZE;
+/// The length of the frame whose header is `header`, not counting the header
+pub fn frame_length(header: [u8; FRAME_HEADER_SIZE]) -> usize {
+    u16::from_be_bytes(header).into()
+}
+
+/// Encode `value` into a buffer with room for `spare` more bytes, so that appending them doesn't reallocate it
+pub fn encode_with_spare_capacity<E: bincode::Encode>(value: &E, spare: usize) -> Result<Vec<u8>, crate::EncodeError> {
+    let mut size_writer = bincode::enc::write::SizeWriter::default();
+    bincode::encode_into_writer(value, &mut size_writer, crate::BINCODE_CONFIG)?;
+    let mut bytes = Vec::with_capacity(size_writer.bytes_written + spare);
+    bincode::encode_into_std_write(value, &mut bytes, crate::BINCODE_CONFIG)?;
+    Ok(bytes)
+}
 
 /// Trait for types that can be converted to nonce bytes without allocation
 pub trait Nonceable {
@@ -36,7 +72,8 @@ impl Nonceable for u32 {
 // We can pack multiple of these into a single UDP datagram as they self-describe their size
 #[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
 pub struct WireMessage {
-    pub nonce: [u8; NONCE_SIZE],
+    // As many bytes as the cipher suite uses
+    pub nonce: Vec<u8>,
     pub encrypted_message: Vec<u8>,
     pub associated_data: Vec<u8>,
 }
@@ -52,22 +89,22 @@ impl WireMessage {
     }
 
     // Warning! This has not been authenticated! Make sure to decrypt the message before trusting it's contents
-    pub fn decode_public<M: Message>(self) -> Result<M::AssociatedData, crate::DecodeError>
+    pub fn decode_public<M: Message>(&self) -> Result<M::AssociatedData, crate::DecodeError>
     where
         <M as Message>::AssociatedData: bincode::Decode<()>,
     {
-        let (associated_data, read_size) = bincode::decode_from_slice(&self.associated_data, crate::BINCODE_CONFIG)?;
-        if read_size != self.associated_data.len() {
-            // The associated_data bytes should only contain the associated data; nothing else
-            Err(crate::DecodeError::InvalidMessageFormat)
-        } else {
-            Ok(associated_data)
-        }
+        // Any bytes after the associated data are extensions to the message, which callers of this don't need
+        let (associated_data, _) = bincode::decode_from_slice(&self.associated_data, crate::BINCODE_CONFIG)?;
+        Ok(associated_data)
     }
 
-    pub fn decrypt(self, cipher: &crate::Cipher) -> Result<UnencryptedWireMessage, crate::DecodeError> {
-        use aead::Aead;
-        let nonce = aead::Nonce::<crate::Cipher>::from(self.nonce);
+    /// Decrypt the message, leaving it as it was so that another cipher can be tried if this one isn't the right one
+    pub fn decrypt(&self, cipher: &crate::Cipher) -> Result<UnencryptedWireMessage, crate::DecodeError> {
+        if self.nonce.len() != cipher.suite().nonce_size() {
+            return Err(crate::DecodeError::Decryption);
+        }
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..self.nonce.len()].copy_from_slice(&self.nonce);
         let mut plaintext = cipher
             .decrypt(
                 &nonce,
@@ -78,12 +115,25 @@ impl WireMessage {
             )
             .map_err(|_| crate::DecodeError::Decryption)?;
 
-        let message_id = plaintext.pop().ok_or(crate::DecodeError::InvalidMessageFormat)?; // We stuffed the message id at the end
+        let mut message_id = plaintext.pop().ok_or(crate::DecodeError::InvalidMessageFormat)?; // We stuffed the message id at the end
+        if message_id == PADDED_MESSAGE_ID {
+            // The padding is followed by the length of the encrypted fields before it, then the message's own id
+            message_id = plaintext.pop().ok_or(crate::DecodeError::InvalidMessageFormat)?;
+            let length_at = plaintext
+                .len()
+                .checked_sub(2)
+                .ok_or(crate::DecodeError::InvalidMessageFormat)?;
+            let length = u16::from_le_bytes([plaintext[length_at], plaintext[length_at + 1]]) as usize;
+            if length > length_at {
+                return Err(crate::DecodeError::InvalidMessageFormat);
+            }
+            plaintext.truncate(length);
+        }
 
         Ok(UnencryptedWireMessage {
             message_id,
-            nonce: nonce.into(),
-            public: self.associated_data,
+            nonce,
+            public: self.associated_data.clone(),
             secret: plaintext,
         })
     }
@@ -93,38 +143,72 @@ impl WireMessage {
 pub struct UnencryptedWireMessage {
     pub message_id: u8,
     pub nonce: [u8; NONCE_SIZE],
-    public: Vec<u8>,
-    secret: Vec<u8>,
+    pub(crate) public: Vec<u8>,
+    pub(crate) secret: Vec<u8>,
 }
 
 impl UnencryptedWireMessage {
-    pub fn encrypt(self, cipher: &crate::Cipher) -> Result<WireMessage, crate::EncodeError> {
-        use aead::Aead;
-        let mut to_be_encrypted = self.secret;
-        to_be_encrypted.push(self.message_id);
+    /// Assemble a message from already-encoded parts, for callers that don't have a [`Message`] type for it
+    pub fn from_raw_parts(message_id: u8, nonce: [u8; NONCE_SIZE], public: Vec<u8>, secret: Vec<u8>) -> Self {
+        Self {
+            message_id,
+            nonce,
+            public,
+            secret,
+        }
+    }
 
-        let encrypted_data = cipher
-            .encrypt(
-                &self.nonce.into(),
-                aead::Payload {
-                    msg: &to_be_encrypted,
-                    aad: &self.public,
-                },
-            )
+    /// The bincode-encoded associated data fields, which were authenticated but not encrypted
+    pub fn public_bytes(&self) -> &[u8] {
+        &self.public
+    }
+
+    /// The bincode-encoded encrypted fields, after decryption
+    pub fn secret_bytes(&self) -> &[u8] {
+        &self.secret
+    }
+
+    /// Encrypt the message; its encrypted fields are encrypted where they are, without being copied
+    pub fn encrypt(self, cipher: &crate::Cipher) -> Result<WireMessage, crate::EncodeError> {
+        let mut encrypted_message = self.secret;
+        encrypted_message.reserve(SEAL_OVERHEAD);
+        encrypted_message.push(self.message_id);
+        cipher
+            .encrypt_in_place(&self.nonce, &self.public, &mut encrypted_message)
             .map_err(|_| crate::EncodeError::Encryption)?;
 
         Ok(WireMessage {
-            nonce: self.nonce,
-            encrypted_message: encrypted_data,
+            nonce: self.nonce[..cipher.suite().nonce_size()].to_vec(),
+            encrypted_message,
             associated_data: self.public,
         })
     }
 
+    /// Encrypt the message like [`Self::encrypt`], padded with zeros so that its encrypted part is a multiple of
+    /// `bucket` bytes long whatever the size of its fields; [`WireMessage::decrypt`] strips the padding again
+    pub fn encrypt_padded(
+        mut self,
+        cipher: &crate::Cipher,
+        bucket: std::num::NonZeroU16,
+    ) -> Result<WireMessage, crate::EncodeError> {
+        let secret_size = self.secret.len();
+        let length = u16::try_from(secret_size).map_err(|_| crate::EncodeError::TooLargeToPad(secret_size))?;
+        let unpadded_size = secret_size + PADDING_OVERHEAD + SEAL_OVERHEAD;
+        let padding = unpadded_size.next_multiple_of(bucket.get() as usize) - unpadded_size;
+
+        self.secret.reserve(padding + PADDING_OVERHEAD + SEAL_OVERHEAD);
+        self.secret.resize(secret_size + padding, 0);
+        self.secret.extend_from_slice(&length.to_le_bytes());
+        self.secret.push(self.message_id);
+        self.message_id = PADDED_MESSAGE_ID;
+        self.encrypt(cipher)
+    }
+
     pub fn decode<M: Message>(&self) -> Result<M, crate::DecodeError> {
         if self.message_id != M::MESSAGE_ID {
             return Err(crate::DecodeError::UnexpectedMessageId(self.message_id));
         }
-        Ok(M::from_parts(&self.nonce, &self.public, &self.secret))
+        M::from_parts(&self.nonce, &self.public, &self.secret)
     }
 }
 
@@ -133,30 +217,19 @@ pub trait Message: Sized {
 
     type AssociatedData;
 
-    fn encode(self) -> Result<UnencryptedWireMessage, crate::EncodeError> {
-        let mut final_nonce = [0u8; NONCE_SIZE];
-        let has_custom_nonce = self.with_nonce_bytes(|nonce_bytes| {
-            if nonce_bytes.len() >= final_nonce.len() {
-                final_nonce.copy_from_slice(&nonce_bytes[..NONCE_SIZE]);
-            } else {
-                final_nonce[..nonce_bytes.len()].copy_from_slice(nonce_bytes);
-                let random_nonce = crate::Cipher::generate_nonce().map_err(|_| crate::EncodeError::Encryption)?;
-                let remaining_len = final_nonce.len() - nonce_bytes.len();
-                final_nonce[nonce_bytes.len()..].copy_from_slice(&random_nonce.as_slice()[..remaining_len]);
-            }
+    /// Encode the message, leaving it as it was so that it can be encoded again, e.g. to be retransmitted
+    fn encode(&self) -> Result<UnencryptedWireMessage, crate::EncodeError> {
+        // A custom nonce replaces the start of a random one
+        let mut nonce: [u8; NONCE_SIZE] = rand::random();
+        self.with_nonce_bytes(|nonce_bytes| {
+            let len = nonce_bytes.len().min(NONCE_SIZE);
+            nonce[..len].copy_from_slice(&nonce_bytes[..len]);
             Ok(())
         })?;
 
-        let nonce = if has_custom_nonce {
-            aead::Nonce::<crate::Cipher>::from(final_nonce)
-        } else {
-            // No custom nonce provided, generate a random one
-            crate::Cipher::generate_nonce().map_err(|_| crate::EncodeError::Encryption)?
-        };
-
         Ok(UnencryptedWireMessage {
             message_id: Self::MESSAGE_ID,
-            nonce: nonce.into(),
+            nonce,
             public: self.public_bytes()?,
             secret: self.secret_bytes()?,
         })
@@ -176,7 +249,13 @@ pub trait Message: Sized {
     fn secret_bytes(&self) -> Result<Vec<u8>, crate::EncodeError>;
 
     // This will be implemented by the warp-protocol-derive::AeadMessage as the "inverse" of public_bytes() and private_bytes()
-    fn from_parts(nonce: &[u8; NONCE_SIZE], public_bytes: &[u8], secret_bytes: &[u8]) -> Self;
+    // The bytes may come from a peer, so truncated or malformed fields are an error. Bytes after the last field are
+    // skipped, as they are fields added by a newer version of the message.
+    fn from_parts(
+        nonce: &[u8; NONCE_SIZE],
+        public_bytes: &[u8],
+        secret_bytes: &[u8],
+    ) -> Result<Self, crate::DecodeError>;
 }
 
 #[cfg(test)]
@@ -220,12 +299,37 @@ mod tests {
         custom_nonce: u64,
     }
 
+    #[derive(Debug, Clone, PartialEq, AeadMessage)]
+    #[message_id = 4]
+    struct Versioned {
+        #[Aead(encrypted)]
+        string: String,
+        #[Aead(associated_data)]
+        number: u32,
+    }
+
+    // Versioned, after gaining a field in each section
+    #[derive(Debug, Clone, PartialEq, AeadMessage)]
+    #[message_id = 4]
+    struct VersionedWithExtensions {
+        #[Aead(encrypted)]
+        string: String,
+        #[Aead(associated_data)]
+        number: u32,
+        #[AeadExtension]
+        #[AeadSerialisation(bincode(with_serde))]
+        #[Aead(encrypted)]
+        address: Option<std::net::SocketAddr>,
+        #[AeadExtension]
+        #[Aead(associated_data)]
+        extra: u16,
+    }
+
     const TEST_KEY: [u8; 32] = [42; 32]; // I rolled a dice
 
     #[test]
     fn test_private_only_roundtrip() {
-        use aead::KeyInit;
-        let cipher = crate::Cipher::new(&aead::Key::<crate::Cipher>::from(TEST_KEY));
+        let cipher = crate::Cipher::new(crate::CipherSuite::ChaCha20Poly1305, &TEST_KEY);
         let msg = PrivateOnly {
             string: "The undertakings of pride".to_string(),
             number: 99,
@@ -245,8 +349,7 @@ mod tests {
 
     #[test]
     fn test_public_only_roundtrip() {
-        use aead::KeyInit;
-        let cipher = crate::Cipher::new(&aead::Key::<crate::Cipher>::from(TEST_KEY));
+        let cipher = crate::Cipher::new(crate::CipherSuite::ChaCha20Poly1305, &TEST_KEY);
         let msg = PublicOnly {
             string: "The undertakings of pride".to_string(),
             number: 99,
@@ -266,8 +369,7 @@ mod tests {
 
     #[test]
     fn test_mixed_roundtrip() {
-        use aead::KeyInit;
-        let cipher = crate::Cipher::new(&aead::Key::<crate::Cipher>::from(TEST_KEY));
+        let cipher = crate::Cipher::new(crate::CipherSuite::ChaCha20Poly1305, &TEST_KEY);
         let msg = Mixed {
             string: "The undertakings of pride".to_string(),
             number: 99,
@@ -287,8 +389,7 @@ mod tests {
 
     #[test]
     fn test_custom_nonce_roundtrip() {
-        use aead::KeyInit;
-        let cipher = crate::Cipher::new(&aead::Key::<crate::Cipher>::from(TEST_KEY));
+        let cipher = crate::Cipher::new(crate::CipherSuite::ChaCha20Poly1305, &TEST_KEY);
         let msg = WithCustomNonce {
             data: "Test data with custom nonce".to_string(),
             custom_nonce: 0x1234567890ABCDEF,
@@ -321,4 +422,148 @@ mod tests {
         // The nonce field retains its original value during reconstruction
         assert_eq!(reconstructed_msg.custom_nonce, 0x1234567890ABCDEFu64);
     }
+
+    #[test]
+    fn test_encrypt_without_copying() {
+        let cipher = crate::Cipher::new(crate::CipherSuite::ChaCha20Poly1305, &TEST_KEY);
+        let msg = Mixed {
+            string: "x".repeat(1400),
+            number: 99,
+        };
+
+        // The encrypted fields are encoded with room to be sealed in, so they are never moved
+        let encoded = msg.clone().encode().unwrap();
+        let secret = encoded.secret_bytes().as_ptr();
+        let encrypted = encoded.encrypt(&cipher).unwrap();
+        assert_eq!(encrypted.encrypted_message.as_ptr(), secret);
+
+        // Decrypting leaves the message as it was, for the next cipher to try
+        let wrong_cipher = crate::Cipher::new(crate::CipherSuite::ChaCha20Poly1305, &[7; 32]);
+        assert!(encrypted.decrypt(&wrong_cipher).is_err());
+        assert_eq!(encrypted.decrypt(&cipher).unwrap().decode::<Mixed>().unwrap(), msg);
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_parts() {
+        let garbage =
+            UnencryptedWireMessage::from_raw_parts(Mixed::MESSAGE_ID, [0; NONCE_SIZE], vec![0xFF; 3], vec![0xFF; 3]);
+        assert!(garbage.decode::<Mixed>().is_err());
+
+        let truncated =
+            UnencryptedWireMessage::from_raw_parts(PrivateOnly::MESSAGE_ID, [0; NONCE_SIZE], vec![], vec![5, b'a']);
+        assert!(truncated.decode::<PrivateOnly>().is_err());
+
+        // Bytes left over after the fields were added by a newer peer, and are ignored
+        let message = PublicOnly {
+            string: "The undertakings of pride".to_string(),
+            number: 99,
+        }
+        .encode()
+        .unwrap();
+        let mut public = message.public_bytes().to_vec();
+        public.push(0);
+        let padded = UnencryptedWireMessage::from_raw_parts(PublicOnly::MESSAGE_ID, message.nonce, public, vec![]);
+        assert_eq!(
+            padded.decode::<PublicOnly>().unwrap(),
+            message.decode::<PublicOnly>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_padded_roundtrip() {
+        let cipher = crate::Cipher::new(crate::CipherSuite::ChaCha20Poly1305, &TEST_KEY);
+        let bucket = std::num::NonZeroU16::new(128).unwrap();
+        let sizes: Vec<usize> = [0, 1, 100, 110, 300]
+            .into_iter()
+            .map(|length| {
+                let msg = Mixed {
+                    string: "x".repeat(length),
+                    number: 99,
+                };
+                let bytes = msg
+                    .encode()
+                    .unwrap()
+                    .encrypt_padded(&cipher, bucket)
+                    .unwrap()
+                    .to_bytes()
+                    .unwrap();
+                let (rx_encrypted_msg, remaining_bytes) = WireMessage::from_slice(&bytes).unwrap();
+                assert!(remaining_bytes.is_empty());
+                assert_eq!(rx_encrypted_msg.encrypted_message.len() % 128, 0);
+
+                let decrypted_msg = rx_encrypted_msg.decrypt(&cipher).unwrap();
+                assert_eq!(decrypted_msg.message_id, Mixed::MESSAGE_ID);
+                assert_eq!(decrypted_msg.decode::<Mixed>().unwrap(), msg);
+                rx_encrypted_msg.encrypted_message.len()
+            })
+            .collect();
+        // Messages of sizes within the same bucket can't be told apart
+        assert_eq!(sizes, [128, 128, 128, 256, 384]);
+    }
+
+    #[test]
+    fn test_padding_length_is_checked() {
+        let cipher = crate::Cipher::new(crate::CipherSuite::ChaCha20Poly1305, &TEST_KEY);
+        let seal = |secret: Vec<u8>| {
+            UnencryptedWireMessage::from_raw_parts(PADDED_MESSAGE_ID, [0; NONCE_SIZE], vec![], secret)
+                .encrypt(&cipher)
+                .unwrap()
+        };
+        // A length longer than what precedes it, and no room for a length at all
+        assert!(
+            seal(vec![0, 0, 3, 0, PrivateOnly::MESSAGE_ID])
+                .decrypt(&cipher)
+                .is_err()
+        );
+        assert!(seal(vec![0, PrivateOnly::MESSAGE_ID]).decrypt(&cipher).is_err());
+    }
+
+    #[test]
+    fn test_extensions_are_compatible() {
+        let cipher = crate::Cipher::new(crate::CipherSuite::ChaCha20Poly1305, &TEST_KEY);
+        let roundtrip = |message: UnencryptedWireMessage| {
+            let bytes = message.encrypt(&cipher).unwrap().to_bytes().unwrap();
+            WireMessage::from_slice(&bytes).unwrap().0.decrypt(&cipher).unwrap()
+        };
+        let old = Versioned {
+            string: "The undertakings of pride".to_string(),
+            number: 99,
+        };
+        let new = VersionedWithExtensions {
+            string: old.string.clone(),
+            number: old.number,
+            address: Some("10.0.0.1:5000".parse().unwrap()),
+            extra: 7,
+        };
+
+        // An old peer's message reaches a new peer with the extensions defaulted
+        let from_old: VersionedWithExtensions = roundtrip(old.clone().encode().unwrap()).decode().unwrap();
+        assert_eq!(
+            from_old,
+            VersionedWithExtensions {
+                address: None,
+                extra: 0,
+                ..new.clone()
+            }
+        );
+
+        // A new peer's message reaches an old peer without them
+        let from_new = roundtrip(new.clone().encode().unwrap());
+        assert_eq!(from_new.clone().decode::<Versioned>().unwrap(), old);
+        assert_eq!(from_new.decode::<VersionedWithExtensions>().unwrap(), new);
+    }
+
+    #[test]
+    fn test_frames_carry_their_length() {
+        let datagram = vec![7u8; 300];
+        let framed = frame(&datagram).unwrap();
+        assert_eq!(frame_length([framed[0], framed[1]]), datagram.len());
+        assert_eq!(&framed[FRAME_HEADER_SIZE..], &datagram[..]);
+
+        // Nothing larger than a datagram can be
+        assert!(matches!(
+            frame(&vec![0; 65536]),
+            Err(crate::EncodeError::TooLargeToFrame(65536))
+        ));
+    }
 }
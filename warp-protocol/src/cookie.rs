@@ -0,0 +1,111 @@
+//! Cookies warp-map can have unknown sources send back before it works out the key to open what they sent
+//!
+//! Every datagram from an address warp-map doesn't know costs it a Diffie-Hellman and a decryption attempt, so a flood
+//! of them from spoofed addresses would keep it busy. When it asks for cookies, warp-map answers such a datagram with
+//! only a [`Cookie`] for the address it came from, which costs it a hash. The client sends the cookie back ahead of its
+//! messages to show that it receives at that address, and only then are they worked on. The answer is never larger than
+//! the datagram that prompted it, so it can't be used to amplify a flood either.
+//!
+//! A cookie is a keyed hash of the address and the [`COOKIE_LIFETIME`]-long window it was issued in, so warp-map keeps
+//! no state for them; it accepts one during that window and the next. Cookies travel as [`WireMessage`]s with no nonce
+//! and nothing encrypted, which no sealed message ever is.
+
+use crate::codec::WireMessage;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+pub const COOKIE_SIZE: usize = 16;
+
+/// How long each window of cookies lasts; a cookie is accepted for up to twice this long
+pub const COOKIE_LIFETIME: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cookie(pub [u8; COOKIE_SIZE]);
+
+impl Cookie {
+    pub fn to_wire_message(self) -> WireMessage {
+        WireMessage {
+            nonce: Vec::new(),
+            encrypted_message: Vec::new(),
+            associated_data: self.0.to_vec(),
+        }
+    }
+
+    /// The cookie `message` carries, if it is one rather than a sealed message
+    pub fn from_wire_message(message: &WireMessage) -> Option<Self> {
+        if !message.nonce.is_empty() || !message.encrypted_message.is_empty() {
+            return None;
+        }
+        Some(Self(message.associated_data.as_slice().try_into().ok()?))
+    }
+}
+
+/// Issues cookies and checks those sent back, with a secret of its own
+pub struct CookieJar {
+    secret: [u8; 32],
+}
+
+impl Default for CookieJar {
+    fn default() -> Self {
+        Self { secret: rand::random() }
+    }
+}
+
+impl CookieJar {
+    /// The cookie for `source` to send back
+    pub fn issue(&self, source: &SocketAddr, now: SystemTime) -> Cookie {
+        self.bake(source, window(now))
+    }
+
+    /// Whether `cookie` was issued to `source` in this window or the last
+    pub fn check(&self, cookie: &Cookie, source: &SocketAddr, now: SystemTime) -> bool {
+        let window = window(now);
+        *cookie == self.bake(source, window) || *cookie == self.bake(source, window.saturating_sub(1))
+    }
+
+    fn bake(&self, source: &SocketAddr, window: u64) -> Cookie {
+        use sha3::Digest;
+        let mut hasher = sha3::Sha3_256::new();
+        hasher.update(self.secret);
+        hasher.update(window.to_le_bytes());
+        match source.ip() {
+            std::net::IpAddr::V4(ip) => hasher.update(ip.octets()),
+            std::net::IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(source.port().to_le_bytes());
+        let hash = hasher.finalize();
+        let mut cookie = [0; COOKIE_SIZE];
+        cookie.copy_from_slice(&hash[..COOKIE_SIZE]);
+        Cookie(cookie)
+    }
+}
+
+fn window(now: SystemTime) -> u64 {
+    now.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() / COOKIE_LIFETIME.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookies_are_checked_by_address_and_age() {
+        let jar = CookieJar::default();
+        let source: SocketAddr = "198.51.100.1:4000".parse().unwrap();
+        let now = SystemTime::now();
+        let cookie = jar.issue(&source, now);
+
+        assert!(jar.check(&cookie, &source, now));
+        assert!(jar.check(&cookie, &source, now + COOKIE_LIFETIME));
+        assert!(!jar.check(&cookie, &source, now + COOKIE_LIFETIME * 2));
+        assert!(!jar.check(&cookie, &"198.51.100.1:4001".parse().unwrap(), now));
+        assert!(!CookieJar::default().check(&cookie, &source, now));
+
+        let bytes = cookie.to_wire_message().to_bytes().unwrap();
+        let (message, rest) = WireMessage::from_slice(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(Cookie::from_wire_message(&message), Some(cookie));
+    }
+}
@@ -17,23 +17,322 @@ pub fn privkey_from_string(key: &str) -> Result<crate::PrivateKey, crate::Decode
     Ok(crate::PrivateKey::from_slice(&bytes)?)
 }
 
+/// Size of the keys every cipher suite takes, in bytes
+pub const KEY_SIZE: usize = 32;
+
+/// Size of the authentication tag every suite appends to what it encrypts, in bytes
+pub const AEAD_TAG_SIZE: usize = 16;
+
+/// The AEAD algorithms messages can be encrypted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum CipherSuite {
+    #[serde(rename = "chacha20_poly1305")]
+    ChaCha20Poly1305,
+    /// ChaCha20-Poly1305 with a 24-byte nonce
+    #[serde(rename = "xchacha20_poly1305")]
+    XChaCha20Poly1305,
+    /// AES-256-GCM, which is faster than either ChaCha20 suite on hardware with AES instructions
+    #[serde(rename = "aes_256_gcm")]
+    Aes256Gcm,
+}
+
+impl CipherSuite {
+    /// Every suite, most preferred first
+    pub const ALL: [CipherSuite; 3] = [
+        CipherSuite::XChaCha20Poly1305,
+        CipherSuite::ChaCha20Poly1305,
+        CipherSuite::Aes256Gcm,
+    ];
+
+    /// How many bytes of a message's nonce the suite uses, and so how many are sent
+    pub fn nonce_size(self) -> usize {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => 12,
+            CipherSuite::XChaCha20Poly1305 => 24,
+            CipherSuite::Aes256Gcm => 12,
+        }
+    }
+}
+
+/// A key for one of the [`CipherSuite`]s
+///
+/// Nonces are always [`NONCE_SIZE`](crate::codec::NONCE_SIZE) bytes, of which a suite with shorter nonces uses the start.
+#[derive(Clone)]
+pub struct Cipher {
+    algorithm: Algorithm,
+    // Kept to derive tunnel keys from
+    key: [u8; KEY_SIZE],
+}
+
+#[derive(Clone)]
+enum Algorithm {
+    ChaCha20Poly1305(chacha20poly1305::ChaCha20Poly1305),
+    XChaCha20Poly1305(chacha20poly1305::XChaCha20Poly1305),
+    // Boxed for its key schedule, which is over a kilobyte
+    Aes256Gcm(Box<aes_gcm::Aes256Gcm>),
+}
+
+/// Size of the tag that tells a far gate which of its tunnel keys a message was sealed with, in bytes
+pub const TUNNEL_KEY_TAG_SIZE: usize = 4;
+
+// Mixed into every tunnel key so it can't be confused with keys derived any other way
+const TUNNEL_KEY_LABEL: &[u8] = b"warp_tunnel_key_HKDF-SHA3-256";
+
+/// The key one tunnel's payloads are sealed with, derived from a session key by [`Cipher::tunnel_key`]
+#[derive(Clone)]
+pub struct TunnelKey {
+    pub cipher: Cipher,
+    /// Sent in the clear with each payload, so the far gate can pick the key without trying them all
+    pub tag: [u8; TUNNEL_KEY_TAG_SIZE],
+}
+
+impl Cipher {
+    pub fn new(suite: CipherSuite, key: &[u8; KEY_SIZE]) -> Self {
+        use aead::KeyInit;
+        let algorithm = match suite {
+            CipherSuite::ChaCha20Poly1305 => {
+                Algorithm::ChaCha20Poly1305(chacha20poly1305::ChaCha20Poly1305::new(&aead::Key::<
+                    chacha20poly1305::ChaCha20Poly1305,
+                >::from(*key)))
+            }
+            CipherSuite::XChaCha20Poly1305 => {
+                Algorithm::XChaCha20Poly1305(chacha20poly1305::XChaCha20Poly1305::new(&aead::Key::<
+                    chacha20poly1305::XChaCha20Poly1305,
+                >::from(*key)))
+            }
+            CipherSuite::Aes256Gcm => {
+                Algorithm::Aes256Gcm(Box::new(aes_gcm::Aes256Gcm::new(
+                    &aead::Key::<aes_gcm::Aes256Gcm>::from(*key),
+                )))
+            }
+        };
+        Self { algorithm, key: *key }
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        match self.algorithm {
+            Algorithm::ChaCha20Poly1305(_) => CipherSuite::ChaCha20Poly1305,
+            Algorithm::XChaCha20Poly1305(_) => CipherSuite::XChaCha20Poly1305,
+            Algorithm::Aes256Gcm(_) => CipherSuite::Aes256Gcm,
+        }
+    }
+
+    /// Derive the key for the tunnel `tunnel_id` from this one with HKDF-SHA3-256, taking the tunnel id as the context
+    ///
+    /// Each tunnel is then sealed with a key of its own in the same suite, and one tunnel's key says nothing about
+    /// another's or about the key it was derived from.
+    pub fn tunnel_key(&self, tunnel_id: &crate::messages::TunnelId) -> TunnelKey {
+        let info = bincode::encode_to_vec(tunnel_id, crate::BINCODE_CONFIG).expect("tunnel ids always encode");
+        let mut okm = [0u8; KEY_SIZE + TUNNEL_KEY_TAG_SIZE];
+        hkdf::Hkdf::<sha3::Sha3_256>::new(Some(TUNNEL_KEY_LABEL), &self.key)
+            .expand(&info, &mut okm)
+            .expect("far less than the most HKDF can expand to");
+        let (key, tag) = okm.split_at(KEY_SIZE);
+        TunnelKey {
+            cipher: Cipher::new(self.suite(), key.try_into().expect("split at KEY_SIZE")),
+            tag: tag.try_into().expect("the rest is the tag"),
+        }
+    }
+
+    pub fn encrypt(
+        &self,
+        nonce: &[u8; crate::codec::NONCE_SIZE],
+        payload: aead::Payload<'_, '_>,
+    ) -> Result<Vec<u8>, aead::Error> {
+        use aead::Aead;
+        match &self.algorithm {
+            Algorithm::ChaCha20Poly1305(cipher) => cipher.encrypt(
+                &Self::nonce_prefix::<chacha20poly1305::ChaCha20Poly1305>(nonce),
+                payload,
+            ),
+            Algorithm::XChaCha20Poly1305(cipher) => cipher.encrypt(
+                &Self::nonce_prefix::<chacha20poly1305::XChaCha20Poly1305>(nonce),
+                payload,
+            ),
+            Algorithm::Aes256Gcm(cipher) => cipher.encrypt(&Self::nonce_prefix::<aes_gcm::Aes256Gcm>(nonce), payload),
+        }
+    }
+
+    pub fn decrypt(
+        &self,
+        nonce: &[u8; crate::codec::NONCE_SIZE],
+        payload: aead::Payload<'_, '_>,
+    ) -> Result<Vec<u8>, aead::Error> {
+        use aead::Aead;
+        match &self.algorithm {
+            Algorithm::ChaCha20Poly1305(cipher) => cipher.decrypt(
+                &Self::nonce_prefix::<chacha20poly1305::ChaCha20Poly1305>(nonce),
+                payload,
+            ),
+            Algorithm::XChaCha20Poly1305(cipher) => cipher.decrypt(
+                &Self::nonce_prefix::<chacha20poly1305::XChaCha20Poly1305>(nonce),
+                payload,
+            ),
+            Algorithm::Aes256Gcm(cipher) => cipher.decrypt(&Self::nonce_prefix::<aes_gcm::Aes256Gcm>(nonce), payload),
+        }
+    }
+
+    /// Encrypt `buffer` where it is, appending the authentication tag; it needs [`AEAD_TAG_SIZE`] bytes of spare
+    /// capacity not to be reallocated
+    pub fn encrypt_in_place(
+        &self,
+        nonce: &[u8; crate::codec::NONCE_SIZE],
+        associated_data: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), aead::Error> {
+        use aead::AeadInOut;
+        match &self.algorithm {
+            Algorithm::ChaCha20Poly1305(cipher) => cipher.encrypt_in_place(
+                &Self::nonce_prefix::<chacha20poly1305::ChaCha20Poly1305>(nonce),
+                associated_data,
+                buffer,
+            ),
+            Algorithm::XChaCha20Poly1305(cipher) => cipher.encrypt_in_place(
+                &Self::nonce_prefix::<chacha20poly1305::XChaCha20Poly1305>(nonce),
+                associated_data,
+                buffer,
+            ),
+            Algorithm::Aes256Gcm(cipher) => cipher.encrypt_in_place(
+                &Self::nonce_prefix::<aes_gcm::Aes256Gcm>(nonce),
+                associated_data,
+                buffer,
+            ),
+        }
+    }
+
+    fn nonce_prefix<A: aead::AeadCore>(nonce: &[u8; crate::codec::NONCE_SIZE]) -> aead::Nonce<A> {
+        aead::Nonce::<A>::try_from(&nonce[..<A::NonceSize as aead::array::typenum::Unsigned>::USIZE])
+            .expect("no suite's nonce is longer than NONCE_SIZE")
+    }
+}
+
+/// The cipher two peers share before any handshake, derived from their static keys alone
+///
+/// This is always ChaCha20-Poly1305, so that peers can talk before they have agreed on a suite.
 pub fn cipher_from_shared_secret(private_key: &crate::PrivateKey, peer_pubkey: &crate::PublicKey) -> crate::Cipher {
-    use aead::KeyInit;
     use sha3::Digest;
-    let shared_secret =
-        k256::elliptic_curve::ecdh::diffie_hellman(private_key.to_nonzero_scalar(), peer_pubkey.as_affine());
+    let shared_secret = diffie_hellman(private_key, peer_pubkey);
     let mut hasher = sha3::Sha3_256::new();
     hasher.update(shared_secret.raw_secret_bytes().as_slice());
     let key = hasher.finalize();
 
-    crate::Cipher::new(&aead::Key::<crate::Cipher>::from(key))
+    crate::Cipher::new(CipherSuite::ChaCha20Poly1305, &key.into())
+}
+
+/// Size of the value a responder sends to prove it derived the same session key, in bytes
+pub const CONFIRMATION_SIZE: usize = 32;
+
+// Mixed into every handshake so its keys can't be confused with keys derived any other way
+const HANDSHAKE_PROTOCOL_NAME: &[u8] = b"warp_IK_secp256k1_ChaChaPoly_SHA3-256";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeRole {
+    /// Sends the first handshake message, already knowing the responder's public key
+    Initiator,
+    Responder,
+}
+
+/// One side of a handshake that gives a pair of peers a session key, in the manner of the Noise IK pattern
+///
+/// Each side contributes a fresh ephemeral key, and the session key is derived from all four Diffie-Hellman
+/// combinations of the two sides' ephemeral and static keys along with every public key involved. The ephemeral-only
+/// term gives forward secrecy once both ephemeral keys are dropped; the terms involving the static keys mean only the
+/// holders of the two static private keys can derive the session key, which the responder proves with
+/// [`SessionKey::confirmation`].
+pub struct Handshake {
+    role: HandshakeRole,
+    ephemeral_key: crate::PrivateKey,
+}
+
+pub struct SessionKey {
+    pub cipher: crate::Cipher,
+    /// Sent by the responder and checked by the initiator before it switches to `cipher`
+    pub confirmation: [u8; CONFIRMATION_SIZE],
+}
+
+impl Handshake {
+    pub fn new(role: HandshakeRole) -> Self {
+        Self {
+            role,
+            ephemeral_key: crate::PrivateKey::random(&mut rand::rng()),
+        }
+    }
+
+    /// The ephemeral public key to send to the peer
+    pub fn public_key(&self) -> crate::PublicKey {
+        self.ephemeral_key.public_key()
+    }
+
+    /// Derive the session key for `suite` once the peer's ephemeral public key has arrived
+    pub fn finish(
+        &self,
+        suite: CipherSuite,
+        static_key: &crate::PrivateKey,
+        peer_static_key: &crate::PublicKey,
+        peer_ephemeral_key: &crate::PublicKey,
+    ) -> SessionKey {
+        use sha3::Digest;
+
+        let ours = (static_key.public_key(), self.ephemeral_key.public_key());
+        let theirs = (*peer_static_key, *peer_ephemeral_key);
+        let ((initiator_static, initiator_ephemeral), (responder_static, responder_ephemeral)) = match self.role {
+            HandshakeRole::Initiator => (ours, theirs),
+            HandshakeRole::Responder => (theirs, ours),
+        };
+        // ee, es, se and ss as the initiator sees them; each side computes the same terms with its own private keys
+        let (es, se) = match self.role {
+            HandshakeRole::Initiator => (
+                diffie_hellman(&self.ephemeral_key, peer_static_key),
+                diffie_hellman(static_key, peer_ephemeral_key),
+            ),
+            HandshakeRole::Responder => (
+                diffie_hellman(static_key, peer_ephemeral_key),
+                diffie_hellman(&self.ephemeral_key, peer_static_key),
+            ),
+        };
+        let ee = diffie_hellman(&self.ephemeral_key, peer_ephemeral_key);
+        let ss = diffie_hellman(static_key, peer_static_key);
+
+        let mut hasher = sha3::Sha3_256::new();
+        hasher.update(HANDSHAKE_PROTOCOL_NAME);
+        hasher.update([suite as u8]);
+        for public_key in [
+            initiator_static,
+            responder_static,
+            initiator_ephemeral,
+            responder_ephemeral,
+        ] {
+            hasher.update(public_key.to_sec1_bytes());
+        }
+        for shared_secret in [ee, es, se, ss] {
+            hasher.update(shared_secret.raw_secret_bytes().as_slice());
+        }
+        let key = hasher.finalize();
+
+        let mut hasher = sha3::Sha3_256::new();
+        hasher.update(HANDSHAKE_PROTOCOL_NAME);
+        hasher.update(b"confirmation");
+        hasher.update(key.as_slice());
+
+        SessionKey {
+            cipher: crate::Cipher::new(suite, &key.into()),
+            confirmation: hasher.finalize().into(),
+        }
+    }
+}
+
+fn diffie_hellman(
+    private_key: &crate::PrivateKey,
+    public_key: &crate::PublicKey,
+) -> k256::elliptic_curve::ecdh::SharedSecret<k256::Secp256k1> {
+    k256::elliptic_curve::ecdh::diffie_hellman(private_key.to_nonzero_scalar(), public_key.as_affine())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use aead::{Aead, AeadCore, Payload};
+    use aead::Payload;
 
     #[test]
     fn test_shared_secret() {
@@ -45,9 +344,7 @@ mod tests {
         let cipher_1 = cipher_from_shared_secret(&key_1, &key_2.public_key());
         let cipher_2 = cipher_from_shared_secret(&key_2, &key_1.public_key());
 
-        let nonce = crate::Cipher::generate_nonce()
-            .map_err(|_| crate::EncodeError::Encryption)
-            .unwrap();
+        let nonce = rand::random::<[u8; crate::codec::NONCE_SIZE]>();
 
         let original_bytes = &[1; 256];
         let aad = &[2; 256];
@@ -66,4 +363,155 @@ mod tests {
 
         assert_eq!(original_bytes, decrypted_bytes.as_slice());
     }
+
+    #[test]
+    fn test_suites_only_use_their_nonce_size() {
+        for suite in CipherSuite::ALL {
+            let cipher = Cipher::new(suite, &[42; KEY_SIZE]);
+            let nonce = rand::random::<[u8; crate::codec::NONCE_SIZE]>();
+            let bytes = cipher.encrypt(&nonce, [1; 64].as_slice().into()).unwrap();
+
+            // The bytes past the suite's nonce size don't take part
+            let mut padded = nonce;
+            padded[suite.nonce_size()..].fill(0);
+            assert_eq!(cipher.decrypt(&padded, bytes.as_slice().into()).unwrap(), [1; 64]);
+            padded[suite.nonce_size() - 1] ^= 1;
+            assert!(cipher.decrypt(&padded, bytes.as_slice().into()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_tunnel_keys() {
+        use crate::messages::TunnelId;
+        for suite in CipherSuite::ALL {
+            let cipher = Cipher::new(suite, &[42; KEY_SIZE]);
+            let tunnel_key = cipher.tunnel_key(&TunnelId::Id(1));
+            assert_eq!(tunnel_key.cipher.suite(), suite);
+
+            // Both peers derive the same key from the same session key
+            let nonce = rand::random::<[u8; crate::codec::NONCE_SIZE]>();
+            let bytes = tunnel_key.cipher.encrypt(&nonce, [1; 64].as_slice().into()).unwrap();
+            let far_gate_key = Cipher::new(suite, &[42; KEY_SIZE]).tunnel_key(&TunnelId::Id(1));
+            assert_eq!(far_gate_key.tag, tunnel_key.tag);
+            assert_eq!(
+                far_gate_key.cipher.decrypt(&nonce, bytes.as_slice().into()).unwrap(),
+                [1; 64]
+            );
+
+            // Neither the session key nor other tunnels' keys open it
+            assert!(cipher.decrypt(&nonce, bytes.as_slice().into()).is_err());
+            for other in [TunnelId::Id(2), TunnelId::Name("1".into())] {
+                let other_key = cipher.tunnel_key(&other);
+                assert_ne!(other_key.tag, tunnel_key.tag);
+                assert!(other_key.cipher.decrypt(&nonce, bytes.as_slice().into()).is_err());
+            }
+            let other_session = Cipher::new(suite, &[43; KEY_SIZE]).tunnel_key(&TunnelId::Id(1));
+            assert!(other_session.cipher.decrypt(&nonce, bytes.as_slice().into()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let initiator_key = k256::SecretKey::random(&mut rand::rng());
+        let responder_key = k256::SecretKey::random(&mut rand::rng());
+
+        for suite in CipherSuite::ALL {
+            let initiator = Handshake::new(HandshakeRole::Initiator);
+            let responder = Handshake::new(HandshakeRole::Responder);
+            let initiator_session = initiator.finish(
+                suite,
+                &initiator_key,
+                &responder_key.public_key(),
+                &responder.public_key(),
+            );
+            let responder_session = responder.finish(
+                suite,
+                &responder_key,
+                &initiator_key.public_key(),
+                &initiator.public_key(),
+            );
+            assert_eq!(initiator_session.confirmation, responder_session.confirmation);
+            assert_eq!(initiator_session.cipher.suite(), suite);
+
+            let nonce = rand::random::<[u8; crate::codec::NONCE_SIZE]>();
+            let bytes = initiator_session
+                .cipher
+                .encrypt(&nonce, [1; 64].as_slice().into())
+                .unwrap();
+            assert_eq!(
+                responder_session
+                    .cipher
+                    .decrypt(&nonce, bytes.as_slice().into())
+                    .unwrap(),
+                [1; 64]
+            );
+
+            // Another run of the handshake between the same peers gives a different key
+            let initiator = Handshake::new(HandshakeRole::Initiator);
+            let responder = Handshake::new(HandshakeRole::Responder);
+            let again = initiator.finish(
+                suite,
+                &initiator_key,
+                &responder_key.public_key(),
+                &responder.public_key(),
+            );
+            assert_ne!(again.confirmation, initiator_session.confirmation);
+        }
+    }
+
+    #[test]
+    fn test_handshake_binds_suite() {
+        let initiator_key = k256::SecretKey::random(&mut rand::rng());
+        let responder_key = k256::SecretKey::random(&mut rand::rng());
+
+        let initiator = Handshake::new(HandshakeRole::Initiator);
+        let responder = Handshake::new(HandshakeRole::Responder);
+        let initiator_session = initiator.finish(
+            CipherSuite::ChaCha20Poly1305,
+            &initiator_key,
+            &responder_key.public_key(),
+            &responder.public_key(),
+        );
+        let responder_session = responder.finish(
+            CipherSuite::XChaCha20Poly1305,
+            &responder_key,
+            &initiator_key.public_key(),
+            &initiator.public_key(),
+        );
+        assert_ne!(initiator_session.confirmation, responder_session.confirmation);
+
+        // Suites with the same nonce size are told apart too
+        let aes_session = responder.finish(
+            CipherSuite::Aes256Gcm,
+            &responder_key,
+            &initiator_key.public_key(),
+            &initiator.public_key(),
+        );
+        assert_ne!(initiator_session.confirmation, aes_session.confirmation);
+    }
+
+    #[test]
+    fn test_handshake_authenticates_responder() {
+        let initiator_key = k256::SecretKey::random(&mut rand::rng());
+        let responder_key = k256::SecretKey::random(&mut rand::rng());
+        let impostor_key = k256::SecretKey::random(&mut rand::rng());
+        let suite = CipherSuite::ChaCha20Poly1305;
+
+        // Without the responder's static private key, a party answering the handshake can't derive the same key
+        let initiator = Handshake::new(HandshakeRole::Initiator);
+        let impostor = Handshake::new(HandshakeRole::Responder);
+        let initiator_session = initiator.finish(
+            suite,
+            &initiator_key,
+            &responder_key.public_key(),
+            &impostor.public_key(),
+        );
+        let impostor_session = impostor.finish(
+            suite,
+            &impostor_key,
+            &initiator_key.public_key(),
+            &initiator.public_key(),
+        );
+        assert_ne!(initiator_session.confirmation, impostor_session.confirmation);
+    }
 }
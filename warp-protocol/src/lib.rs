@@ -1,12 +1,14 @@
 pub mod codec;
+pub mod cookie;
 pub mod crypto;
 pub mod messages;
+pub mod replay;
 
 pub use aead::Aead;
 
 pub type PrivateKey = k256::SecretKey;
 pub type PublicKey = k256::PublicKey;
-pub type Cipher = chacha20poly1305::ChaCha20Poly1305;
+pub use crypto::{Cipher, CipherSuite};
 
 pub const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::standard();
 
@@ -24,6 +26,10 @@ pub enum EncodeError {
     Bincode(#[from] bincode::error::EncodeError),
     #[error("Encryption error")]
     Encryption,
+    #[error("Message of {0} bytes is too large to pad")]
+    TooLargeToPad(usize),
+    #[error("Datagram of {0} bytes is too large to frame")]
+    TooLargeToFrame(usize),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -1,5 +1,8 @@
-// What is the right way to define a protocol like this in Rust?
-// Bincode is space-efficient but makes it difficult to ensure forward/backward compatibility.
+// Peers running different versions must keep understanding each other, so once a message has been released:
+// - new fields go after the existing ones, marked #[AeadExtension], with a type whose Default means "not sent"
+//   (typically an Option)
+// - existing fields are never removed, reordered or retyped; replace the message with a new message_id instead
+use crate::codec::Message;
 use warp_protocol_derive::AeadMessage;
 
 #[derive(Debug, Clone, PartialEq, AeadMessage)]
@@ -10,6 +13,11 @@ pub struct RegisterRequest {
     pub pubkey: crate::PublicKey,
     #[Aead(encrypted)]
     pub timestamp: std::time::SystemTime,
+    // The addresses the registering socket is bound to, as seen from its own host, for peers on the same network to
+    // reach it at without going through NATs; empty from clients that don't send them
+    #[AeadExtension]
+    #[Aead(encrypted)]
+    pub host_candidates: Vec<std::net::SocketAddr>,
 }
 
 #[derive(Debug, Clone, PartialEq, AeadMessage)]
@@ -21,6 +29,19 @@ pub struct RegisterResponse {
     pub timestamp: std::time::SystemTime,
     #[Aead(encrypted)]
     pub request_timestamp: std::time::SystemTime,
+    // The second port warp-map answers NatProbeRequests on, at the same IP address; `None` from warp-maps without one
+    #[AeadExtension]
+    #[Aead(encrypted)]
+    pub probe_port: Option<u16>,
+}
+
+/// Identifies a registration exchange in both the client's and warp-map's logs; warp-map echoes the request's
+/// timestamp back as `request_timestamp`, so both ends can derive it without changing the protocol
+pub fn registration_correlation_id(request_timestamp: std::time::SystemTime) -> u128 {
+    request_timestamp
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default()
 }
 
 #[derive(Debug, Clone, PartialEq, AeadMessage)]
@@ -42,6 +63,96 @@ pub struct DeregisterResponse {
     pub request_timestamp: std::time::SystemTime,
 }
 
+// Sent to a warp-map that relays to have it pass `data`, a message sealed for the peer with public key `peer_pubkey`, on
+// to that peer's registered addresses, for peers that can't reach each other directly. warp-map passes it on in a
+// RelayPayload of its own, whose `peer_pubkey` is the sender's.
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0x16]
+pub struct RelayPayload {
+    #[Aead(encrypted)]
+    #[AeadSerialisation(bincode(with_serde))]
+    pub peer_pubkey: crate::PublicKey,
+    #[Aead(encrypted)]
+    pub data: Vec<u8>,
+}
+
+// One of a client's registered addresses in a MapSync, with when it last registered from there by the sending warp-map's
+// clock and the host candidates it registered with
+#[derive(Debug, Clone, PartialEq, bincode::Encode, bincode::Decode)]
+pub struct SyncedRegistration {
+    #[bincode(with_serde)]
+    pub pubkey: crate::PublicKey,
+    pub address: std::net::SocketAddr,
+    pub last_seen: std::time::SystemTime,
+    pub host_candidates: Vec<std::net::SocketAddr>,
+}
+
+// Sent now and then by a warp-map to each warp-map it is federated with, sealed with the two servers' keys, listing
+// registrations it knows of so that a client registered with one can be found through any of them. Each keeps whichever
+// registration of an address was renewed last.
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0x17]
+pub struct MapSync {
+    #[Aead(encrypted)]
+    pub registrations: Vec<SyncedRegistration>,
+}
+
+// Why warp-map turned a request down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub enum ErrorCode {
+    // The client's public key isn't allowed to register with this warp-map
+    Unauthorized,
+    // The request needs a registration from the address it came from, and there is none, or it has expired
+    NotRegistered,
+    // This warp-map doesn't handle messages with the request's message_id, or not from this client
+    UnknownMessage,
+    // The request decrypted but couldn't be decoded
+    Malformed,
+    // warp-map failed to handle the request
+    Internal,
+}
+
+// Sent by warp-map instead of the response to a request it turned down or failed to handle, sealed as that response
+// would have been, so the client hears why rather than waiting for a response that won't come. Requests that can't be
+// decrypted get nothing, as warp-map can't tell who sent them.
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0x18]
+pub struct ErrorResponse {
+    #[Aead(encrypted)]
+    pub code: ErrorCode,
+    #[Aead(encrypted)]
+    pub message: String,
+    // The message_id of the request
+    #[Aead(encrypted)]
+    pub request_message_id: u8,
+    // The request's timestamp, if it could be decoded and has one
+    #[Aead(encrypted)]
+    pub request_timestamp: Option<std::time::SystemTime>,
+}
+
+// Sent to warp-map's probe port from a registered socket, to find out whether the client's NAT maps that socket to the
+// address it registered from whoever it sends to: if the NatProbeResponse names another address, the NAT is endpoint
+// dependent (symmetric), and peers only see where the client sends to them from through PeerAddressOverrides
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0x19]
+pub struct NatProbeRequest {
+    #[AeadSerialisation(bincode(with_serde))]
+    #[Aead(associated_data)]
+    pub pubkey: crate::PublicKey,
+    #[Aead(encrypted)]
+    pub timestamp: std::time::SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0x1A]
+pub struct NatProbeResponse {
+    // Where the probe came from, as warp-map saw it
+    #[Aead(encrypted)]
+    pub address: std::net::SocketAddr,
+    #[Aead(encrypted)]
+    pub request_timestamp: std::time::SystemTime,
+}
+
 #[derive(Debug, Clone, PartialEq, AeadMessage)]
 #[message_id = 0x12]
 pub struct MappingRequest {
@@ -50,6 +161,12 @@ pub struct MappingRequest {
     pub peer_pubkey: crate::PublicKey,
     #[Aead(encrypted)]
     pub timestamp: std::time::SystemTime,
+    // Whether warp-map should also send a MappingResponse for `peer_pubkey` to this address unasked, each time the peer
+    // registers an address it hadn't, until no request has renewed this for as long as a registration lasts; false from
+    // clients that only poll
+    #[AeadExtension]
+    #[Aead(encrypted)]
+    pub subscribe: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, AeadMessage)]
@@ -62,6 +179,26 @@ pub struct MappingResponse {
     pub endpoints: Vec<std::net::SocketAddr>,
     #[Aead(encrypted)]
     pub timestamp: std::time::SystemTime,
+    // The host candidates the peer registered `endpoints` with, for reaching it directly when on the same network; empty
+    // from warp-maps that don't send them
+    #[AeadExtension]
+    #[Aead(encrypted)]
+    pub host_candidates: Vec<std::net::SocketAddr>,
+    // Whether the peer is registered, which empty `endpoints` alone can't say; `None` from warp-maps that don't send it
+    #[AeadExtension]
+    #[Aead(encrypted)]
+    pub peer_status: Option<PeerStatus>,
+}
+
+// Whether warp-map has the peer a MappingResponse is about registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub enum PeerStatus {
+    // Registered at the response's endpoints
+    Registered,
+    // Registered until lately, but no longer: its addresses have expired or been deregistered
+    Expired,
+    // Never registered, or not lately enough for warp-map to remember it
+    Unknown,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, bincode::Encode, bincode::Decode)]
@@ -70,11 +207,15 @@ pub enum TunnelId {
     Id(u64),
 }
 
+// One of `num_parts` shards of the payload with tracer `parent_tracer`; any `required_parts` of them reconstruct its
+// `payload_size` bytes of data
 #[derive(Debug, Clone, PartialEq, bincode::Encode, bincode::Decode)]
 pub struct MultipartIdentifier {
-    parent_tracer: u64,
-    num_parts: u64,
-    part_id: u64,
+    pub parent_tracer: u64,
+    pub num_parts: u64,
+    pub required_parts: u64,
+    pub part_id: u64,
+    pub payload_size: u64,
 }
 
 #[derive(Debug, Clone, PartialEq, bincode::Encode, bincode::Decode, Default)]
@@ -85,17 +226,30 @@ pub enum ReconstructionTag {
     Multipart(MultipartIdentifier),
 }
 
+// How the sender compressed a tunnel payload's `data`; only ever other than None for peers that agreed to decompress
+// (see RekeyRequest)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode, Default)]
+pub enum PayloadCompression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
 #[derive(Debug, Clone, PartialEq, AeadMessage)]
 #[message_id = 0xF1] // Warp at faster than F1 speeds!
 pub struct TunnelPayload {
     #[Aead(encrypted)]
     pub tunnel_id: TunnelId,
-    #[Aead(Nonce)]
+    #[Aead(encrypted)]
     pub tracer: u64,
     #[Aead(encrypted)]
     pub reconstruction_tag: ReconstructionTag,
     #[Aead(encrypted)]
     pub data: Vec<u8>,
+    #[AeadExtension]
+    #[Aead(encrypted)]
+    pub compression: PayloadCompression,
 }
 
 impl TunnelPayload {
@@ -105,8 +259,22 @@ impl TunnelPayload {
             tracer,
             data,
             reconstruction_tag: ReconstructionTag::Plain,
+            compression: PayloadCompression::None,
         }
     }
+
+    /// Encode this as the KeyedTunnelPayload with `key_tag` that `KeyedTunnelPayload::new` would make of it, without
+    /// copying the payload into one first
+    pub fn encode_keyed(
+        &self,
+        key_tag: [u8; crate::crypto::TUNNEL_KEY_TAG_SIZE],
+    ) -> Result<crate::codec::UnencryptedWireMessage, crate::EncodeError> {
+        // The two have the same encrypted fields; only the key tag and the message id tell them apart
+        let mut encoded = self.encode()?;
+        encoded.message_id = KeyedTunnelPayload::MESSAGE_ID;
+        encoded.public = bincode::encode_to_vec(key_tag, crate::BINCODE_CONFIG)?;
+        Ok(encoded)
+    }
 }
 
 // This message is sent to inform a peer to send to the origin of this message instead of the specified address.
@@ -115,88 +283,244 @@ impl TunnelPayload {
 pub struct PeerAddressOverride {
     #[Aead(encrypted)]
     pub replace: std::net::SocketAddr,
+    // Grows with each round of overrides the peer sends, across restarts too, so that one delayed or replayed behind a
+    // later one can be told apart; 0 from peers that don't send it
+    #[AeadExtension]
+    #[Aead(encrypted)]
+    pub sequence: u64,
+}
+
+// Sent to a peer over one path (a local interface and a peer address) to measure its round trip time and loss; the
+// peer answers with a PathProbeReply to the address the probe came from
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0xF3]
+pub struct PathProbe {
+    #[Aead(encrypted)]
+    pub sequence: u64,
+    #[Aead(encrypted)]
+    pub timestamp: std::time::SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0xF4]
+pub struct PathProbeReply {
+    #[Aead(encrypted)]
+    pub sequence: u64,
+    #[Aead(encrypted)]
+    pub timestamp: std::time::SystemTime,
+    #[Aead(encrypted)]
+    pub probe_timestamp: std::time::SystemTime,
+}
+
+// Sent by the peer with the lower public key to start a session and then to replace its key; `public_key` and the one
+// in the RekeyResponse are the ephemeral keys of a `crypto::Handshake`, and the responder picks the session key's suite
+// from `cipher_suites`
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0xF5]
+pub struct RekeyRequest {
+    #[Aead(encrypted)]
+    pub epoch: u64,
+    #[Aead(encrypted)]
+    #[AeadSerialisation(bincode(with_serde))]
+    pub public_key: crate::PublicKey,
+    #[Aead(encrypted)]
+    #[AeadSerialisation(bincode(with_serde))]
+    pub cipher_suites: Vec<crate::CipherSuite>,
+    // Whether the initiator can seal tunnel payloads under the new key with per-tunnel keys (see KeyedTunnelPayload)
+    #[AeadExtension]
+    #[Aead(encrypted)]
+    pub tunnel_keys: bool,
+    // Whether the initiator can decompress tunnel payloads (see PayloadCompression) sent under the new key
+    #[AeadExtension]
+    #[Aead(encrypted)]
+    pub compression: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0xF6]
+pub struct RekeyResponse {
+    #[Aead(encrypted)]
+    pub epoch: u64,
+    #[Aead(encrypted)]
+    #[AeadSerialisation(bincode(with_serde))]
+    pub public_key: crate::PublicKey,
+    #[Aead(encrypted)]
+    #[AeadSerialisation(bincode(with_serde))]
+    pub cipher_suite: crate::CipherSuite,
+    #[Aead(encrypted)]
+    pub confirmation: [u8; crate::crypto::CONFIRMATION_SIZE],
+    // Whether both peers seal tunnel payloads under the new key with per-tunnel keys; only if the request offered to
+    #[AeadExtension]
+    #[Aead(encrypted)]
+    pub tunnel_keys: bool,
+    // Whether both peers may compress the tunnel payloads they send under the new key; only if the request offered to
+    #[AeadExtension]
+    #[Aead(encrypted)]
+    pub compression: bool,
+}
+
+// Sent by a gate of a reliable tunnel for each payload it receives, including ones it had already received, so that
+// the far gate stops retransmitting them; `tracers` are those of the payloads (not of their FEC shards)
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0xF7]
+pub struct TunnelAck {
+    #[Aead(encrypted)]
+    pub tunnel_id: TunnelId,
+    #[Aead(encrypted)]
+    pub tracers: Vec<u64>,
+}
+
+// Sent over every path to a peer now and then, so that the peer hears from each of our addresses even when nothing else
+// is being sent
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0xF8]
+pub struct Heartbeat {
+    #[Aead(encrypted)]
+    pub timestamp: std::time::SystemTime,
+}
+
+// A TunnelPayload sealed with its tunnel's own key (see crypto::Cipher::tunnel_key) instead of the session key, once
+// the peers agreed on that in a rekey; `key_tag` is that key's tag, so the far gate knows which key to open it with
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0xF9]
+pub struct KeyedTunnelPayload {
+    #[Aead(associated_data)]
+    pub key_tag: [u8; crate::crypto::TUNNEL_KEY_TAG_SIZE],
+    #[Aead(encrypted)]
+    pub tunnel_id: TunnelId,
+    #[Aead(encrypted)]
+    pub tracer: u64,
+    #[Aead(encrypted)]
+    pub reconstruction_tag: ReconstructionTag,
+    #[Aead(encrypted)]
+    pub data: Vec<u8>,
+    #[AeadExtension]
+    #[Aead(encrypted)]
+    pub compression: PayloadCompression,
+}
+
+impl KeyedTunnelPayload {
+    pub fn new(tunnel_payload: TunnelPayload, key_tag: [u8; crate::crypto::TUNNEL_KEY_TAG_SIZE]) -> Self {
+        KeyedTunnelPayload {
+            key_tag,
+            tunnel_id: tunnel_payload.tunnel_id,
+            tracer: tunnel_payload.tracer,
+            reconstruction_tag: tunnel_payload.reconstruction_tag,
+            data: tunnel_payload.data,
+            compression: tunnel_payload.compression,
+        }
+    }
+}
+
+impl From<KeyedTunnelPayload> for TunnelPayload {
+    fn from(keyed: KeyedTunnelPayload) -> Self {
+        TunnelPayload {
+            tunnel_id: keyed.tunnel_id,
+            tracer: keyed.tracer,
+            reconstruction_tag: keyed.reconstruction_tag,
+            data: keyed.data,
+            compression: keyed.compression,
+        }
+    }
+}
+
+// One of the tunnels in a TunnelSync: its name, and the TOML of the warp_config::WarpTunnelConfig to open it with,
+// apart from its far gate
+#[derive(Debug, Clone, PartialEq, bincode::Encode, bincode::Decode)]
+pub struct SyncedTunnel {
+    pub name: String,
+    pub config: String,
+}
+
+// Sent now and then by a peer to each far gate, listing every tunnel it opened while running that the far gate should
+// open its end of. The list is complete each time, so the far gate closes the ones it opened for earlier TunnelSyncs
+// that are no longer listed.
+#[derive(Debug, Clone, PartialEq, AeadMessage)]
+#[message_id = 0xFA]
+pub struct TunnelSync {
+    #[Aead(encrypted)]
+    pub tunnels: Vec<SyncedTunnel>,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::codec::Message;
-    use aead::KeyInit;
 
     const TEST_KEY: [u8; 32] = [42; 32];
 
     // This is the lower bound of the overhead for the tunnel payload:
+    // - 01 bytes: nonce length
     // - 12 bytes: nonce (encrytion)
     // - 16 bytes: aead tag (MAC-ish thing)
     // - 01 bytes: message id
     // - 01 bytes: tunnel id
+    // - 01 bytes: tracer
     // - 01 bytes: reconstruction tag
+    // - 01 bytes: compression
     // ----------------------------------------
-    // Total: 31 bytes
+    // Total: 34 bytes
 
     #[test]
     fn tunnel_payload_overhead_1024_bytes() {
-        let cipher = crate::Cipher::new(&aead::Key::<crate::Cipher>::from(TEST_KEY));
+        let cipher = crate::Cipher::new(crate::CipherSuite::ChaCha20Poly1305, &TEST_KEY);
         let data = [1; 1024];
         let message = TunnelPayload::new(TunnelId::Id(0), 0, data.to_vec());
         let wire_bytes = message.encode().unwrap().encrypt(&cipher).unwrap().to_bytes().unwrap();
 
-        assert_eq!(wire_bytes.len(), data.len() + 39);
+        assert_eq!(wire_bytes.len(), data.len() + 42);
     }
 
     #[test]
     fn tunnel_payload_overhead_8_bytes() {
-        let cipher = crate::Cipher::new(&aead::Key::<crate::Cipher>::from(TEST_KEY));
+        let cipher = crate::Cipher::new(crate::CipherSuite::ChaCha20Poly1305, &TEST_KEY);
 
         let data = [1; 8];
         let message = TunnelPayload::new(TunnelId::Id(0), 0, data.to_vec());
 
         let wire_bytes = message.encode().unwrap().encrypt(&cipher).unwrap().to_bytes().unwrap();
 
-        assert_eq!(wire_bytes.len(), data.len() + 35);
+        assert_eq!(wire_bytes.len(), data.len() + 38);
     }
 
     #[test]
-    fn test_tunnel_payload_uses_tracer_as_nonce() {
-        use crate::codec::Message;
-        use aead::KeyInit;
-
-        const NONCE: u64 = 42;
-
-        let cipher = crate::Cipher::new(&aead::Key::<crate::Cipher>::from(TEST_KEY));
-        let data = vec![1, 2, 3, 4, 5];
-        let message = TunnelPayload::new(TunnelId::Id(42), NONCE, data.clone());
-
-        // Test that extract_nonce returns the tracer bytes
-        let mut extracted_nonce = None;
-        let has_nonce = message
-            .with_nonce_bytes(|bytes| {
-                extracted_nonce = Some(bytes.to_vec());
-                Ok(())
-            })
+    fn test_tunnel_payload_roundtrip() {
+        let cipher = crate::Cipher::new(crate::CipherSuite::ChaCha20Poly1305, &TEST_KEY);
+        let message = TunnelPayload::new(TunnelId::Id(42), 42, vec![1, 2, 3, 4, 5]);
+
+        let bytes = message
+            .clone()
+            .encode()
+            .unwrap()
+            .encrypt(&cipher)
+            .unwrap()
+            .to_bytes()
             .unwrap();
-        assert!(has_nonce);
-        assert!(extracted_nonce.is_some());
-        let nonce_bytes = extracted_nonce.unwrap();
-        assert_eq!(nonce_bytes.as_slice(), &message.tracer.to_le_bytes());
-
-        // Test encryption with custom nonce from tracer (now handled automatically)
-        let encrypted_msg = message.clone().encode().unwrap().encrypt(&cipher).unwrap();
-        let bytes = encrypted_msg.to_bytes().unwrap();
         let rx_encrypted_msg = crate::codec::WireMessage::from_slice(&bytes).unwrap().0;
+        let reconstructed_msg: TunnelPayload = rx_encrypted_msg.decrypt(&cipher).unwrap().decode().unwrap();
 
-        // The nonce should start with our tracer bytes (first 8 bytes)
-        assert_eq!(&rx_encrypted_msg.nonce[..8], &message.tracer.to_le_bytes());
+        assert_eq!(reconstructed_msg, message);
+    }
 
-        // Verify the message can be decrypted and reconstructed
-        let decrypted_msg = rx_encrypted_msg.decrypt(&cipher).unwrap();
-        let reconstructed_msg: TunnelPayload = decrypted_msg.decode().unwrap();
+    #[test]
+    fn test_encode_keyed_matches_keyed_tunnel_payload() {
+        let message = TunnelPayload {
+            reconstruction_tag: ReconstructionTag::Multipart(MultipartIdentifier {
+                parent_tracer: 7,
+                num_parts: 3,
+                required_parts: 2,
+                part_id: 1,
+                payload_size: 5,
+            }),
+            compression: PayloadCompression::Zstd,
+            ..TunnelPayload::new(TunnelId::Name("video".to_string()), 42, vec![1, 2, 3])
+        };
+        let keyed = KeyedTunnelPayload::new(message.clone(), [9, 8, 7, 6]);
 
-        // The reconstructed message should have the original data
-        assert_eq!(reconstructed_msg.tunnel_id, message.tunnel_id);
-        assert_eq!(reconstructed_msg.reconstruction_tag, message.reconstruction_tag);
-        assert_eq!(reconstructed_msg.data, message.data);
-        // The tracer field retains its original value during reconstruction since it's a nonce field
-        assert_eq!(reconstructed_msg.tracer, NONCE);
+        let encoded = message.encode_keyed(keyed.key_tag).unwrap();
+        let expected = keyed.encode().unwrap();
+        assert_eq!(encoded.message_id, expected.message_id);
+        assert_eq!(encoded.public_bytes(), expected.public_bytes());
+        assert_eq!(encoded.secret_bytes(), expected.secret_bytes());
+        assert_eq!(encoded.decode::<KeyedTunnelPayload>().unwrap(), keyed);
     }
 }
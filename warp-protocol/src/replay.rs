@@ -0,0 +1,224 @@
+//! Replay protection for the messages peers send each other
+//!
+//! A sender numbers its messages with a [`NonceSequence`], which puts each number in the message's nonce where the AEAD
+//! authenticates it. The receiver keeps a [`ReplayWindow`] per sender and accepts each number at most once; numbers
+//! that fall more than [`REPLAY_WINDOW_SIZE`] behind the newest one accepted can't be told apart from replays any more,
+//! so they are rejected as well.
+//!
+//! Sequences start from the sender's clock in nanoseconds, so they keep increasing across restarts of the sender. A
+//! window starts out empty, so a receiver that restarts accepts whichever number it sees first.
+//!
+//! Both peers encrypt with the same key, so their sequences alone could produce the same nonce. The first bit after the
+//! sequence number keeps them apart: it is set in the nonces of the peer with the lower public key and clear in the
+//! other's, which also lets a receiver tell its own messages reflected back at it from its peer's. The rest of the nonce
+//! is random.
+
+use crate::codec::NONCE_SIZE;
+use crate::PublicKey;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How far behind the newest message a message can arrive and still be accepted, in messages
+pub const REPLAY_WINDOW_SIZE: u64 = 8192;
+
+const WORD_BITS: u64 = u64::BITS as u64;
+
+// Where the direction bit is, right after the sequence number so that 12-byte nonces have it too
+const DIRECTION_BYTE: usize = 8;
+const DIRECTION_BIT: u8 = 0x80;
+
+pub struct NonceSequence {
+    sender: PublicKey,
+    next: AtomicU64,
+}
+
+impl NonceSequence {
+    /// A sequence for the messages that the holder of `sender`'s private key sends
+    pub fn new(sender: PublicKey) -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_nanos() as u64)
+            .unwrap_or_default();
+        Self {
+            sender,
+            next: AtomicU64::new(now),
+        }
+    }
+
+    /// A nonce for a message to `receiver`, holding the next number in the sequence and the direction bit, followed by
+    /// random bytes
+    pub fn next_nonce(&self, receiver: &PublicKey) -> [u8; NONCE_SIZE] {
+        let sequence = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut nonce: [u8; NONCE_SIZE] = rand::random();
+        nonce[..8].copy_from_slice(&sequence.to_le_bytes());
+        if self.sender < *receiver {
+            nonce[DIRECTION_BYTE] |= DIRECTION_BIT;
+        } else {
+            nonce[DIRECTION_BYTE] &= !DIRECTION_BIT;
+        }
+        nonce
+    }
+}
+
+/// Whether the direction bit of `nonce` is the one `sender` sets in its nonces for `receiver`
+pub fn is_from(nonce: &[u8; NONCE_SIZE], sender: &PublicKey, receiver: &PublicKey) -> bool {
+    (nonce[DIRECTION_BYTE] & DIRECTION_BIT != 0) == (sender < receiver)
+}
+
+/// The number a nonce from [`NonceSequence::next_nonce`] holds
+pub fn sequence_of(nonce: &[u8; NONCE_SIZE]) -> u64 {
+    let mut sequence = [0u8; 8];
+    sequence.copy_from_slice(&nonce[..8]);
+    u64::from_le_bytes(sequence)
+}
+
+pub struct ReplayWindow {
+    // The newest sequence number accepted; None until the first is
+    newest: Option<u64>,
+    // Bit `sequence % REPLAY_WINDOW_SIZE` is set if `sequence` has been accepted, for the sequence numbers in the window
+    seen: Box<[u64; (REPLAY_WINDOW_SIZE / WORD_BITS) as usize]>,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self {
+            newest: None,
+            seen: Box::new([0; (REPLAY_WINDOW_SIZE / WORD_BITS) as usize]),
+        }
+    }
+
+    /// The newest sequence number accepted so far
+    pub fn newest(&self) -> Option<u64> {
+        self.newest
+    }
+
+    /// Record `sequence` as received, returning false if it is a replay or too old to tell
+    ///
+    /// Only call this once the message carrying `sequence` has been decrypted, or forged messages could move the window.
+    pub fn accept(&mut self, sequence: u64) -> bool {
+        match self.newest {
+            Some(newest) if sequence <= newest => {
+                if newest - sequence >= REPLAY_WINDOW_SIZE {
+                    return false;
+                }
+                self.mark(sequence)
+            }
+            newest => {
+                // Forget the numbers that the window slides past
+                match newest {
+                    Some(newest) if sequence - newest < REPLAY_WINDOW_SIZE => {
+                        for passed in newest + 1..=sequence {
+                            self.unmark(passed);
+                        }
+                    }
+                    _ => self.seen.fill(0),
+                }
+                self.newest = Some(sequence);
+                self.mark(sequence)
+            }
+        }
+    }
+
+    // Returns false if `sequence` was already marked
+    fn mark(&mut self, sequence: u64) -> bool {
+        let (word, bit) = Self::position(sequence);
+        let unseen = self.seen[word] & bit == 0;
+        self.seen[word] |= bit;
+        unseen
+    }
+
+    fn unmark(&mut self, sequence: u64) {
+        let (word, bit) = Self::position(sequence);
+        self.seen[word] &= !bit;
+    }
+
+    fn position(sequence: u64) -> (usize, u64) {
+        let index = sequence % REPLAY_WINDOW_SIZE;
+        ((index / WORD_BITS) as usize, 1 << (index % WORD_BITS))
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public_key() -> PublicKey {
+        crate::PrivateKey::random(&mut rand::rng()).public_key()
+    }
+
+    #[test]
+    fn test_nonce_sequence_increases() {
+        let (sender, receiver) = (public_key(), public_key());
+        let sequence = NonceSequence::new(sender);
+        let first = sequence_of(&sequence.next_nonce(&receiver));
+        assert_eq!(sequence_of(&sequence.next_nonce(&receiver)), first + 1);
+        // A restarted sender carries on from a later number
+        assert!(sequence_of(&NonceSequence::new(sender).next_nonce(&receiver)) > first + 1);
+    }
+
+    #[test]
+    fn test_peers_nonces_never_collide() {
+        let (a_key, b_key) = (public_key(), public_key());
+        let (a, b) = (NonceSequence::new(a_key), NonceSequence::new(b_key));
+        b.next.store(a.next.load(Ordering::Relaxed), Ordering::Relaxed);
+        for _ in 0..1000 {
+            // Even at the same number and in the 12 bytes that the shorter nonces keep, whatever the random bytes are
+            let (a_nonce, b_nonce) = (a.next_nonce(&b_key), b.next_nonce(&a_key));
+            assert_eq!(sequence_of(&a_nonce), sequence_of(&b_nonce));
+            assert_ne!(a_nonce[..12], b_nonce[..12]);
+            assert!(is_from(&a_nonce, &a_key, &b_key) && is_from(&b_nonce, &b_key, &a_key));
+            // Neither peer takes its own nonces for the other's
+            assert!(!is_from(&a_nonce, &b_key, &a_key) && !is_from(&b_nonce, &a_key, &b_key));
+        }
+    }
+
+    #[test]
+    fn test_rejects_duplicates() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(100));
+        assert!(!window.accept(100));
+        assert!(window.accept(101));
+        assert!(!window.accept(100));
+        assert!(!window.accept(101));
+    }
+
+    #[test]
+    fn test_accepts_reordering_within_window() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(10000));
+        assert!(window.accept(10005));
+        assert!(window.accept(10002));
+        assert!(window.accept(10001));
+        assert!(!window.accept(10002));
+        assert!(window.accept(10005 - REPLAY_WINDOW_SIZE + 1));
+        assert!(!window.accept(10005 - REPLAY_WINDOW_SIZE));
+    }
+
+    #[test]
+    fn test_rejects_stale() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(REPLAY_WINDOW_SIZE * 3));
+        assert!(!window.accept(REPLAY_WINDOW_SIZE * 2));
+        assert!(!window.accept(0));
+    }
+
+    #[test]
+    fn test_sliding_forgets_passed_numbers() {
+        let mut window = ReplayWindow::new();
+        assert!(window.accept(5));
+        assert!(window.accept(7));
+        // 5 + REPLAY_WINDOW_SIZE shares 5's bit, which must not make it look like a replay
+        assert!(window.accept(5 + REPLAY_WINDOW_SIZE));
+        assert!(!window.accept(5));
+        assert!(window.accept(7 + REPLAY_WINDOW_SIZE));
+        // A jump past the whole window forgets everything
+        assert!(window.accept(10 * REPLAY_WINDOW_SIZE));
+        assert!(window.accept(10 * REPLAY_WINDOW_SIZE - 1));
+        assert!(!window.accept(10 * REPLAY_WINDOW_SIZE - 1));
+    }
+}
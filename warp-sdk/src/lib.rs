@@ -0,0 +1,425 @@
+//! Embed warp in a Rust application
+//!
+//! Tunnels created through [`Builder::tunnel`] use an in-process gate, so the application exchanges payloads with warp
+//! over channels instead of a loopback or Unix domain socket:
+//!
+//! ```no_run
+//! # async fn example(config: warp_config::WarpConfig, transport: warp_config::WarpTransportConfig) -> Result<(), warp_sdk::Error> {
+//! let mut builder = warp_sdk::Warp::builder(config);
+//! let mut tunnel = builder.tunnel("telemetry", None, transport)?;
+//! let warp = builder.start();
+//!
+//! tunnel.send(b"hello".to_vec()).await?;
+//! if let Some(reply) = tunnel.recv().await {
+//!     println!("{} bytes from the far gate", reply.len());
+//! }
+//!
+//! warp.shutdown().await
+//! # }
+//! ```
+//!
+//! [`Tunnel::into_stream`] turns a tunnel into a [`futures::Stream`] and [`futures::Sink`] of payloads, to use with the
+//! rest of the async ecosystem.
+//!
+//! Once started, warp can open and close tunnels with the gates of the config ([`Warp::open_tunnel`]), switch to
+//! another config ([`Warp::reload`]) and report what happens to it ([`Warp::subscribe`]).
+
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+mod stream;
+
+pub use stream::TunnelStream;
+pub use warp::control::TunnelStatus;
+pub use warp::health::HealthReport;
+pub use warp::transport::Network;
+
+// Events a subscriber may fall behind by before it misses some
+const EVENT_CAPACITY: usize = 64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("warp is no longer running")]
+    Closed,
+    #[error("warp refused the request: {0}")]
+    Refused(String),
+    #[error(transparent)]
+    Warp(#[from] anyhow::Error),
+}
+
+/// Something that happened to a running warp; see [`Warp::subscribe`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The interfaces found when warp started have been bound and the tunnels opened
+    Ready,
+    /// A far gate has been heard from again after going down
+    FarGateUp(warp_protocol::PublicKey),
+    /// None of a far gate's addresses has been heard from for `interfaces.liveness.timeout`
+    FarGateDown(warp_protocol::PublicKey),
+}
+
+/// Configures tunnels before warp starts
+pub struct Builder {
+    core: warp::WarpCore,
+    shutdown: oneshot::Sender<()>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Builder {
+    pub fn new(config: warp_config::WarpConfig) -> Self {
+        let (core, shutdown) = warp::WarpCore::new(config);
+        Self::from_core(core, shutdown)
+    }
+
+    /// Run warp over `network` instead of the host's interfaces
+    pub fn with_network(config: warp_config::WarpConfig, network: Arc<dyn Network>) -> Self {
+        let (core, shutdown) = warp::WarpCore::with_network(config, network);
+        Self::from_core(core, shutdown)
+    }
+
+    fn from_core(mut core: warp::WarpCore, shutdown: oneshot::Sender<()>) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        core.on_peer_liveness({
+            let events = events.clone();
+            move |far_gate, alive| {
+                let _ = events.send(match alive {
+                    true => Event::FarGateUp(far_gate),
+                    false => Event::FarGateDown(far_gate),
+                });
+            }
+        });
+        core.on_ready({
+            let events = events.clone();
+            move || {
+                let _ = events.send(Event::Ready);
+            }
+        });
+        Self { core, shutdown, events }
+    }
+
+    /// Receive what happens to warp from when it starts, [`Event::Ready`] included
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Add an in-process tunnel; without a `tunnel_id` the far gate must have a tunnel with the same name
+    ///
+    /// Tunnels from the config keep their configured gates and run alongside these.
+    pub fn tunnel(
+        &mut self,
+        name: &str,
+        tunnel_id: Option<u64>,
+        transport: warp_config::WarpTransportConfig,
+    ) -> Result<Tunnel, Error> {
+        let gate = self.core.add_channel_tunnel(name, tunnel_id, transport)?;
+        Ok(Tunnel {
+            name: name.to_string(),
+            to_gate: gate.to_gate,
+            from_gate: gate.from_gate,
+            send_deadline: gate.send_deadline,
+        })
+    }
+
+    /// Take up the in-process gate of the config's tunnels with `gate = { channel = "..." }`
+    ///
+    /// Such tunnels are opened, reopened and closed with the config, and keep exchanging payloads with the returned
+    /// end throughout.
+    pub fn channel_gate(&mut self, channel: &str) -> Result<Tunnel, Error> {
+        let gate = self.core.channel_gate(channel)?;
+        Ok(Tunnel {
+            name: channel.to_string(),
+            to_gate: gate.to_gate,
+            from_gate: gate.from_gate,
+            send_deadline: gate.send_deadline,
+        })
+    }
+
+    /// Start warp on the current tokio runtime
+    pub fn start(self) -> Warp {
+        let mut core = self.core;
+        let controller = core.controller();
+        let reloads = core.reloader();
+        let health = core.health();
+        let task = tokio::task::Builder::new()
+            .name("warp-sdk core")
+            .spawn(async move { core.run().await })
+            .expect("task initialised");
+
+        Warp {
+            task: Some(task),
+            shutdown: Some(self.shutdown),
+            controller,
+            reloads,
+            health,
+            events: self.events,
+        }
+    }
+}
+
+/// A running warp instance; dropping it stops warp immediately, without deregistering from warp-map
+pub struct Warp {
+    task: Option<JoinHandle<()>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    controller: warp::control::Controller,
+    reloads: mpsc::UnboundedSender<warp_config::WarpConfig>,
+    health: warp::health::Health,
+    events: broadcast::Sender<Event>,
+}
+
+impl Warp {
+    pub fn builder(config: warp_config::WarpConfig) -> Builder {
+        Builder::new(config)
+    }
+
+    /// Open `tunnel` as `name`, and have its far gate open its end with the gate `far_end` if that is given
+    ///
+    /// The tunnel stays open across reloads until it is closed or warp stops; see [`warp::control`].
+    pub async fn open_tunnel(
+        &self,
+        name: &str,
+        tunnel: warp_config::WarpTunnelConfig,
+        far_end: Option<warp_config::WarpGateConfig>,
+    ) -> Result<Vec<TunnelStatus>, Error> {
+        self.request(warp::control::Request::Open {
+            name: name.to_string(),
+            tunnel: Box::new(tunnel),
+            far_end,
+        })
+        .await
+    }
+
+    /// Close a tunnel opened by [`Self::open_tunnel`]
+    pub async fn close_tunnel(&self, name: &str) -> Result<Vec<TunnelStatus>, Error> {
+        self.request(warp::control::Request::Close { name: name.to_string() })
+            .await
+    }
+
+    /// The tunnels warp has, or is trying to open
+    pub async fn tunnels(&self) -> Result<Vec<TunnelStatus>, Error> {
+        self.request(warp::control::Request::List {}).await
+    }
+
+    async fn request(&self, request: warp::control::Request) -> Result<Vec<TunnelStatus>, Error> {
+        let response = self.controller.request(request).await.map_err(|_| Error::Closed)?;
+        match response.error {
+            Some(error) => Err(Error::Refused(error)),
+            None => Ok(response.tunnels),
+        }
+    }
+
+    /// Switch to `config` while running; see [`warp::WarpCore::reloader`] for what takes effect
+    pub fn reload(&self, config: warp_config::WarpConfig) -> Result<(), Error> {
+        self.reloads.send(config).map_err(|_| Error::Closed)
+    }
+
+    /// Whether warp's tasks are keeping up; see [`warp::health`]
+    pub fn health(&self) -> HealthReport {
+        self.health.report()
+    }
+
+    /// Receive what happens to warp from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Deregister from warp-map, send what the tunnels have been given so far and wait for warp to stop
+    pub async fn shutdown(mut self) -> Result<(), Error> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(task) = self.task.take() {
+            task.await.map_err(anyhow::Error::from)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Warp {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+/// The application's end of an in-process tunnel
+///
+/// Each [`Self::send`] becomes one tunnel payload at the far gate, and each payload from the far gate is returned by
+/// one [`Self::recv`].
+#[derive(Debug)]
+pub struct Tunnel {
+    name: String,
+    to_gate: mpsc::Sender<Vec<u8>>,
+    from_gate: mpsc::UnboundedReceiver<Vec<u8>>,
+    send_deadline: Option<std::time::Duration>,
+}
+
+impl Tunnel {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Queue `data` to be warped to the far gate
+    ///
+    /// Like a datagram socket, this succeeds even if warp has no path to the far gate yet; the payload is dropped if
+    /// it can't be sent before the tunnel's send deadline. It waits while warp already has `queues.capacity` payloads
+    /// of the tunnel's to take.
+    pub async fn send(&self, data: impl Into<Vec<u8>>) -> Result<(), Error> {
+        self.to_gate.send(data.into()).await.map_err(|_| Error::Closed)
+    }
+
+    /// Wait for the next payload from the far gate; returns `None` once warp has stopped
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.from_gate.recv().await
+    }
+
+    /// Take the next payload from the far gate if one has already arrived
+    pub fn try_recv(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match self.from_gate.try_recv() {
+            Ok(data) => Ok(Some(data)),
+            Err(mpsc::error::TryRecvError::Empty) => Ok(None),
+            Err(mpsc::error::TryRecvError::Disconnected) => Err(Error::Closed),
+        }
+    }
+
+    /// Exchange payloads through a [`futures::Stream`] and [`futures::Sink`] instead
+    pub fn into_stream(self) -> TunnelStream {
+        TunnelStream::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use warp_sim::harness;
+    use warp_sim::network::SimNetwork;
+
+    #[tokio::test]
+    async fn test_tunnel_between_embedded_peers() {
+        let network = SimNetwork::new(0);
+        let map_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+        let map = harness::spawn_map(&network, map_key.clone()).unwrap();
+
+        let a_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+        let b_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+        let a_host = network.host(&[("sim0", harness::PEER_A_ADDRESS)]);
+        let b_host = network.host(&[("sim0", harness::PEER_B_ADDRESS)]);
+
+        let mut a = Builder::with_network(harness::peer_config(a_key.clone(), &map_key, &b_key), a_host);
+        let mut b = Builder::with_network(harness::peer_config(b_key, &map_key, &a_key), b_host);
+        let a_tunnel = a.tunnel("embedded", None, harness::transport_config()).unwrap();
+        let mut b_tunnel = b.tunnel("embedded", None, harness::transport_config()).unwrap();
+        assert!(a.tunnel("embedded", None, harness::transport_config()).is_err());
+
+        let mut a_events = a.subscribe();
+        let a = a.start();
+        let b = b.start();
+
+        // Keep sending until both peers have registered and learned each other's addresses
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                a_tunnel.send(b"hello".as_slice()).await.unwrap();
+                if let Ok(Some(data)) = tokio::time::timeout(Duration::from_millis(50), b_tunnel.recv()).await {
+                    break data;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, b"hello");
+
+        // The far gate hasn't gone down since warp got going
+        let mut events = Vec::new();
+        while let Ok(event) = a_events.try_recv() {
+            events.push(event);
+        }
+        assert_eq!(events, [Event::Ready]);
+
+        // Only tunnels opened while running can be closed
+        assert!(matches!(a.close_tunnel("embedded").await, Err(Error::Refused(_))));
+
+        // Payloads sent just before shutting down still get through
+        for index in 0..10u8 {
+            a_tunnel.send([index].as_slice()).await.unwrap();
+        }
+        a.shutdown().await.unwrap();
+        let mut delivered = Vec::new();
+        while let Ok(Some(data)) = tokio::time::timeout(Duration::from_millis(500), b_tunnel.recv()).await {
+            if data != b"hello" {
+                delivered.push(data[0]);
+            }
+        }
+        delivered.sort();
+        assert_eq!(delivered, (0..10).collect::<Vec<_>>());
+
+        drop(b);
+        map.abort();
+
+        // Both cores have stopped, so the tunnels are closed
+        assert!(matches!(a_tunnel.send(b"late".as_slice()).await, Err(Error::Closed)));
+        while let Ok(Some(_)) = b_tunnel.try_recv() {}
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), b_tunnel.recv())
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configured_channel_gates() {
+        let network = SimNetwork::new(0);
+        let map_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+        let map = harness::spawn_map(&network, map_key.clone()).unwrap();
+
+        let a_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+        let b_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+        let a_host = network.host(&[("sim0", harness::PEER_A_ADDRESS)]);
+        let b_host = network.host(&[("sim0", harness::PEER_B_ADDRESS)]);
+        let with_tunnel = |mut config: warp_config::WarpConfig| {
+            config.tunnels.insert(
+                "configured".to_string(),
+                warp_config::WarpTunnelConfig {
+                    gate: warp_config::WarpGateConfig::Channel(warp_config::ChannelConfig {
+                        channel: "app".to_string(),
+                    }),
+                    transport: harness::transport_config(),
+                    tunnel_id: None,
+                    far_gate: None,
+                    priority: None,
+                },
+            );
+            config
+        };
+        let mut a = Builder::with_network(
+            with_tunnel(harness::peer_config(a_key.clone(), &map_key, &b_key)),
+            a_host,
+        );
+        let mut b = Builder::with_network(with_tunnel(harness::peer_config(b_key, &map_key, &a_key)), b_host);
+        let a_tunnel = a.channel_gate("app").unwrap();
+        let mut b_tunnel = b.channel_gate("app").unwrap();
+        assert!(a.channel_gate("app").is_err());
+        let a = a.start();
+        let b = b.start();
+
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                a_tunnel.send(b"hello".as_slice()).await.unwrap();
+                if let Ok(Some(data)) = tokio::time::timeout(Duration::from_millis(50), b_tunnel.recv()).await {
+                    break data;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, b"hello");
+
+        // The tunnel came from the config, so it isn't the control socket's to close
+        assert!(matches!(a.close_tunnel("configured").await, Err(Error::Refused(_))));
+
+        drop((a, b));
+        map.abort();
+    }
+}
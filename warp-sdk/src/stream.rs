@@ -0,0 +1,153 @@
+//! [`Tunnel`]s as async streams and sinks of payloads
+
+use crate::{Error, Tunnel};
+use bytes::Bytes;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
+
+/// A [`Tunnel`] as a [`futures::Stream`] of the payloads from the far gate and a [`futures::Sink`] of payloads to it
+///
+/// The sink is ready while warp has room to queue one of the tunnel's payloads, which it has for `queues.capacity` of
+/// them. A payload that has waited the tunnel's send deadline for room would be too late to send anyway, so once the
+/// sink has been waiting that long it takes the next payload and drops it, counting it in [`Self::dropped`]. An
+/// application is held back while warp can't keep up, but by no more than the send deadline at a time.
+///
+/// Tunnels from [`crate::Builder::channel_gate`] take the smallest send deadline of the config's tunnels using the
+/// channel; with none in the config, the sink waits for room however long it takes.
+pub struct TunnelStream {
+    name: String,
+    to_gate: PollSender<Vec<u8>>,
+    from_gate: mpsc::UnboundedReceiver<Vec<u8>>,
+    send_deadline: Option<Duration>,
+    // Started when the sink began waiting for room
+    waiting: Option<Pin<Box<tokio::time::Sleep>>>,
+    drop_next: bool,
+    dropped: u64,
+}
+
+impl TunnelStream {
+    pub(crate) fn new(tunnel: Tunnel) -> Self {
+        Self {
+            name: tunnel.name,
+            to_gate: PollSender::new(tunnel.to_gate),
+            from_gate: tunnel.from_gate,
+            send_deadline: tunnel.send_deadline,
+            waiting: None,
+            drop_next: false,
+            dropped: 0,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How many payloads the sink has dropped for having waited the send deadline for room
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+impl futures::Stream for TunnelStream {
+    type Item = Bytes;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        self.from_gate.poll_recv(cx).map(|data| data.map(Bytes::from))
+    }
+}
+
+impl futures::Sink<Bytes> for TunnelStream {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = &mut *self;
+        if this.drop_next {
+            return Poll::Ready(Ok(()));
+        }
+        match this.to_gate.poll_reserve(cx) {
+            Poll::Ready(Ok(())) => {
+                this.waiting = None;
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(_)) => Poll::Ready(Err(Error::Closed)),
+            Poll::Pending => {
+                let Some(send_deadline) = this.send_deadline else {
+                    return Poll::Pending;
+                };
+                let waiting = this
+                    .waiting
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(send_deadline)));
+                match waiting.as_mut().poll(cx) {
+                    Poll::Ready(()) => {
+                        this.waiting = None;
+                        this.drop_next = true;
+                        Poll::Ready(Ok(()))
+                    }
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> Result<(), Error> {
+        if std::mem::take(&mut self.drop_next) {
+            self.to_gate.abort_send();
+            self.dropped += 1;
+            return Ok(());
+        }
+        self.to_gate.send_item(item.into()).map_err(|_| Error::Closed)
+    }
+
+    // Payloads are warp's to send once queued, so there is nothing to flush
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.to_gate.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+
+    #[tokio::test]
+    async fn test_sink_waits_no_longer_than_send_deadline() {
+        let send_deadline = Duration::from_millis(20);
+        let (to_gate, mut from_application) = mpsc::channel(1);
+        let (to_application, from_gate) = mpsc::unbounded_channel();
+        let mut stream = Tunnel {
+            name: "test".to_string(),
+            to_gate,
+            from_gate,
+            send_deadline: Some(send_deadline),
+        }
+        .into_stream();
+
+        stream.send(Bytes::from_static(b"first")).await.unwrap();
+        // The gate hasn't taken the first payload, so the second waits out the send deadline and is dropped
+        let started = std::time::Instant::now();
+        stream.send(Bytes::from_static(b"second")).await.unwrap();
+        assert!(started.elapsed() >= send_deadline);
+        assert_eq!(stream.dropped(), 1);
+
+        assert_eq!(from_application.recv().await.unwrap(), b"first");
+        stream.send(Bytes::from_static(b"third")).await.unwrap();
+        assert_eq!(from_application.recv().await.unwrap(), b"third");
+        assert_eq!(stream.dropped(), 1);
+
+        to_application.send(b"reply".to_vec()).unwrap();
+        drop(to_application);
+        assert_eq!(stream.next().await, Some(Bytes::from_static(b"reply")));
+        assert_eq!(stream.next().await, None);
+
+        stream.close().await.unwrap();
+        assert_eq!(from_application.recv().await, None);
+    }
+}
@@ -0,0 +1,783 @@
+//! A warp-map and two warp peers running in one process over a [`SimNetwork`]
+//!
+//! Each peer has one tunnel with a loopback gate, so tests drive it the way a real application would: by sending and
+//! receiving UDP datagrams on localhost. Only the traffic between the peers and warp-map is simulated.
+
+use crate::network::{SimHost, SimNetwork};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+pub const MAP_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 13116);
+pub const PEER_A_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1));
+pub const PEER_B_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 2, 1));
+
+const TUNNEL_NAME: &str = "sim";
+const INTERFACE_NAME: &str = "sim0";
+const CLIENT_EXPIRY: Duration = Duration::from_secs(60);
+
+pub struct Harness {
+    pub network: SimNetwork,
+    pub a: SimPeer,
+    pub b: SimPeer,
+    map_key: warp_protocol::PrivateKey,
+    map_task: JoinHandle<()>,
+}
+
+/// One warp core plus the application socket attached to its tunnel's gate
+pub struct SimPeer {
+    /// Add or remove interfaces here to simulate network changes; warp picks them up on its next interface scan
+    pub host: Arc<SimHost>,
+    application: tokio::net::UdpSocket,
+    gate_address: SocketAddr,
+    core_task: JoinHandle<()>,
+    config: warp_config::WarpConfig,
+    reloader: tokio::sync::mpsc::UnboundedSender<warp_config::WarpConfig>,
+    // Whether the far gate was alive, each time that changed
+    far_gate_liveness: Arc<std::sync::Mutex<Vec<bool>>>,
+    // Dropping this would shut the core down
+    _shutdown: tokio::sync::oneshot::Sender<()>,
+}
+
+impl Harness {
+    /// Start warp-map and both peers on a fresh network whose randomness is seeded with `seed`
+    ///
+    /// Links are lossless with no added latency until changed through [`Self::network`].
+    pub async fn start(seed: u64) -> anyhow::Result<Self> {
+        Self::start_with_transport(seed, transport_config()).await
+    }
+
+    /// Like [`Self::start`], with both peers' tunnels using `transport` instead of [`transport_config`]
+    pub async fn start_with_transport(seed: u64, transport: warp_config::WarpTransportConfig) -> anyhow::Result<Self> {
+        let network = SimNetwork::new(seed);
+
+        let map_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+        let map_task = spawn_map(&network, map_key.clone())?;
+
+        let a_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+        let b_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+
+        let a = SimPeer::start(
+            &network,
+            (INTERFACE_NAME, PEER_A_ADDRESS),
+            a_key.clone(),
+            &map_key,
+            &b_key,
+            transport.clone(),
+        )
+        .await?;
+        let b = SimPeer::start(
+            &network,
+            (INTERFACE_NAME, PEER_B_ADDRESS),
+            b_key,
+            &map_key,
+            &a_key,
+            transport,
+        )
+        .await?;
+
+        Ok(Self {
+            network,
+            a,
+            b,
+            map_key,
+            map_task,
+        })
+    }
+
+    /// Stop warp-map and start a fresh one with the same key, losing every registration
+    pub async fn restart_map(&mut self) -> anyhow::Result<()> {
+        self.map_task.abort();
+        // Wait for the old task to drop its socket so the address is free again
+        let _ = (&mut self.map_task).await;
+        self.map_task = spawn_map(&self.network, self.map_key.clone())?;
+        Ok(())
+    }
+}
+
+/// Run warp-map on `network` at [`MAP_ADDRESS`] until the returned task is aborted
+pub fn spawn_map(network: &SimNetwork, private_key: warp_protocol::PrivateKey) -> anyhow::Result<JoinHandle<()>> {
+    let socket = network.bind(MAP_ADDRESS)?;
+    let server = warp_map::WarpMapServer::new(private_key, MAP_ADDRESS, CLIENT_EXPIRY).with_relay();
+
+    let task = tokio::task::Builder::new().name("sim warp-map").spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+            match server.handle_datagram(&buf[..len], &from).await {
+                Ok(datagrams) => {
+                    for (to, datagram) in datagrams {
+                        socket.send_to(&datagram, to);
+                    }
+                }
+                Err(e) => tracing::warn!("sim warp-map failed to process datagram from {}: {}", from, e),
+            }
+        }
+    })?;
+
+    Ok(task)
+}
+
+/// Config for a peer using the warp-map from [`spawn_map`], with fast interface scans and no tunnels
+pub fn peer_config(
+    private_key: warp_protocol::PrivateKey,
+    map_key: &warp_protocol::PrivateKey,
+    far_gate_key: &warp_protocol::PrivateKey,
+) -> warp_config::WarpConfig {
+    warp_config::WarpConfig {
+        private_key,
+        interfaces: warp_config::InterfacesConfig {
+            interface_scan_interval: Duration::from_millis(50),
+            interface_watch: None,
+            holepunch_keep_alive_interval: Duration::from_millis(50),
+            bind_to_device: Some(false),
+            exclusion_patterns: regex::RegexSet::empty(),
+            inclusion_patterns: regex::RegexSet::new([".*"]).expect("valid pattern"),
+            max_consecutive_failures: 10,
+            batch_size: None,
+            so_rcvbuf: None,
+            so_sndbuf: None,
+            dscp: None,
+            rate_limits: None,
+            policies: None,
+            fibs: None,
+            path_probing: Some(warp_config::PathProbingConfig {
+                interval: Duration::from_millis(50),
+                timeout: Duration::from_millis(500),
+            }),
+            liveness: Some(warp_config::LivenessConfig {
+                heartbeat_interval: Duration::from_millis(50),
+                timeout: Duration::from_millis(300),
+            }),
+            flap_damping: Some(warp_config::FlapDampingConfig {
+                hold_down: Duration::from_millis(100),
+                max_hold_down: Duration::from_secs(1),
+                half_life: Duration::from_secs(5),
+            }),
+            // Tests that cut the link between the peers expect nothing to get through
+            relay: None,
+            traffic_log: None,
+        },
+        warp_map: warp_config::WarpMapConfig {
+            address: MAP_ADDRESS,
+            public_key: map_key.public_key(),
+            tcp_address: None,
+        },
+        far_gate: warp_config::WarpFarGateConfig {
+            public_key: far_gate_key.public_key(),
+        },
+        tunnels: Default::default(),
+        rekey: None,
+        cipher_suites: None,
+        metrics: None,
+        queues: None,
+        shutdown: None,
+        state_file: None,
+        daemon: None,
+        control: None,
+        telemetry: None,
+    }
+}
+
+/// Transport settings for tunnels in simulations
+pub fn transport_config() -> warp_config::WarpTransportConfig {
+    warp_config::WarpTransportConfig {
+        redundancy: warp_config::RedundancyConfig {
+            num_shards: 1,
+            required_shards: 1,
+            adaptive: None,
+        },
+        mtu: 1400,
+        ordered: false,
+        reordering: None,
+        path_selection: None,
+        interfaces: None,
+        metered: None,
+        reliable: None,
+        dscp: None,
+        rate_limit: None,
+        compression: None,
+        pad_to: None,
+        packing: None,
+        send_deadline: Duration::from_secs(1),
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.map_task.abort();
+    }
+}
+
+impl SimPeer {
+    async fn start(
+        network: &SimNetwork,
+        interface: (&str, IpAddr),
+        private_key: warp_protocol::PrivateKey,
+        map_key: &warp_protocol::PrivateKey,
+        far_gate_key: &warp_protocol::PrivateKey,
+        transport: warp_config::WarpTransportConfig,
+    ) -> anyhow::Result<Self> {
+        let application = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let gate_address = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), free_udp_port()?);
+
+        let mut config = peer_config(private_key, map_key, far_gate_key);
+        config.tunnels.insert(
+            TUNNEL_NAME.to_string(),
+            warp_config::WarpTunnelConfig {
+                tunnel_id: None,
+                gate: warp_config::WarpGateConfig::Loopback(warp_config::LoopbackConfig {
+                    ipv4: true,
+                    application_to_gate: gate_address.port(),
+                    gate_to_application: Some(application.local_addr()?.port()),
+                }),
+                transport,
+                far_gate: None,
+                priority: None,
+            },
+        );
+
+        let host = network.host(&[interface]);
+        let (mut core, shutdown) = warp::WarpCore::with_network(config.clone(), host.clone());
+        let reloader = core.reloader();
+        let far_gate_liveness = Arc::new(std::sync::Mutex::new(Vec::new()));
+        core.on_peer_liveness({
+            let far_gate_liveness = far_gate_liveness.clone();
+            move |_far_gate, alive| far_gate_liveness.lock().unwrap().push(alive)
+        });
+        let core_task = tokio::task::Builder::new()
+            .name(&format!("sim warp core {}", interface.1))
+            .spawn(async move { core.run().await })?;
+
+        Ok(Self {
+            host,
+            application,
+            gate_address,
+            core_task,
+            config,
+            reloader,
+            far_gate_liveness,
+            _shutdown: shutdown,
+        })
+    }
+
+    /// Reload the core with its config changed by `change`, as a SIGHUP would
+    pub fn reload(&mut self, change: impl FnOnce(&mut warp_config::WarpConfig)) -> anyhow::Result<()> {
+        change(&mut self.config);
+        self.reloader.send(self.config.clone())?;
+        Ok(())
+    }
+
+    /// Whether the core took its far gate to be alive, each time that changed
+    pub fn far_gate_liveness_changes(&self) -> Vec<bool> {
+        self.far_gate_liveness.lock().unwrap().clone()
+    }
+
+    /// False once the warp core has stopped, which only happens if one of its tasks died
+    pub fn is_running(&self) -> bool {
+        !self.core_task.is_finished()
+    }
+
+    /// Send a datagram into the tunnel as the application
+    pub async fn send(&self, data: &[u8]) -> anyhow::Result<()> {
+        self.application.send_to(data, self.gate_address).await?;
+        Ok(())
+    }
+
+    /// Wait for the next datagram the tunnel delivers to the application; cancel safe
+    pub async fn recv_into(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.application.recv(buf).await
+    }
+
+    /// Receive the next datagram the tunnel delivers to the application, or `None` if nothing arrives in time
+    pub async fn recv(&self, timeout: Duration) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0u8; 65536];
+        match tokio::time::timeout(timeout, self.application.recv(&mut buf)).await {
+            Ok(size) => {
+                buf.truncate(size?);
+                Ok(Some(buf))
+            }
+            Err(_elapsed) => Ok(None),
+        }
+    }
+
+    /// Keep sending `data` until the other peer's application receives it, which first needs both peers to have
+    /// registered with warp-map and learned each other's addresses
+    pub async fn send_until_received(&self, other: &SimPeer, data: &[u8], timeout: Duration) -> anyhow::Result<bool> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while tokio::time::Instant::now() < deadline {
+            self.send(data).await?;
+            if let Some(received) = other.recv(Duration::from_millis(50)).await?
+                && received == data
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Drop for SimPeer {
+    fn drop(&mut self) {
+        self.core_task.abort();
+    }
+}
+
+// The loopback gate binds its own socket, so reserve a port the OS considers free and hand it over
+fn free_udp_port() -> std::io::Result<u16> {
+    Ok(std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::LinkConditions;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn test_end_to_end_delivery() {
+        let harness = Harness::start(1).await.unwrap();
+
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"a to b", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        assert!(
+            harness
+                .b
+                .send_until_received(&harness.a, b"b to a", TIMEOUT)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fec_end_to_end_delivery() {
+        let mut transport = transport_config();
+        transport.redundancy = warp_config::RedundancyConfig {
+            num_shards: 5,
+            required_shards: 3,
+            adaptive: None,
+        };
+        let harness = Harness::start_with_transport(3, transport).await.unwrap();
+
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        assert!(harness.a.send_until_received(&harness.b, &data, TIMEOUT).await.unwrap());
+        assert!(
+            harness
+                .b
+                .send_until_received(&harness.a, b"odd length", TIMEOUT)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_payloads_larger_than_mtu() {
+        let harness = Harness::start(5).await.unwrap();
+
+        let data: Vec<u8> = (0..=255).cycle().take(4 * transport_config().mtu as usize).collect();
+        assert!(harness.a.send_until_received(&harness.b, &data, TIMEOUT).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_ordered_tunnel_delivers_in_order() {
+        let mut transport = transport_config();
+        transport.ordered = true;
+        let harness = Harness::start_with_transport(4, transport).await.unwrap();
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"warmup", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        while harness.b.recv(Duration::from_millis(100)).await.unwrap().is_some() {}
+
+        // Enough jitter to reorder datagrams sent back to back, well within the default reordering timeout
+        harness.network.set_default_conditions(LinkConditions {
+            jitter: Duration::from_millis(10),
+            ..Default::default()
+        });
+        for counter in 0..50u8 {
+            harness.a.send(&[counter]).await.unwrap();
+        }
+
+        let mut received = Vec::new();
+        while let Some(data) = harness.b.recv(Duration::from_millis(500)).await.unwrap() {
+            received.push(data[0]);
+        }
+        assert!(!received.is_empty());
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]), "{received:?}");
+    }
+
+    #[tokio::test]
+    async fn test_reliable_tunnel_delivers_despite_loss() {
+        let mut transport = transport_config();
+        transport.reliable = Some(true);
+        let harness = Harness::start_with_transport(8, transport).await.unwrap();
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"warmup", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        while harness.b.recv(Duration::from_millis(100)).await.unwrap().is_some() {}
+
+        // Payloads and acknowledgements are both lost
+        harness.network.set_default_conditions(LinkConditions {
+            loss: 0.3,
+            ..Default::default()
+        });
+        for counter in 0..30u8 {
+            harness.a.send(&[counter]).await.unwrap();
+        }
+
+        let mut received = Vec::new();
+        while let Some(data) = harness.b.recv(Duration::from_secs(1)).await.unwrap() {
+            // Retransmitted copies of the warmup may still turn up
+            if data != b"warmup" {
+                received.push(data[0]);
+            }
+        }
+        received.sort();
+        // Every payload, and each only once
+        assert_eq!(received, (0..30).collect::<Vec<_>>());
+    }
+
+    // How many copies of 10 payloads reach b when a has two interfaces, and so two paths to b
+    // Returns how many copies of each payload went over the network, and how many payloads the application received
+    async fn copies_sent(seed: u64, path_selection: warp_config::PathSelection) -> (usize, usize) {
+        let mut transport = transport_config();
+        transport.path_selection = Some(path_selection);
+        let harness = Harness::start_with_transport(seed, transport).await.unwrap();
+        harness
+            .a
+            .host
+            .add_interface("sim1", IpAddr::V4(Ipv4Addr::new(10, 0, 1, 2)));
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"warmup", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        // Give a's interface scan time to pick up the second interface
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        while harness.b.recv(Duration::from_millis(100)).await.unwrap().is_some() {}
+
+        // Large enough to stand out from registrations, probes and overrides
+        let payload_size = 4096;
+        let sent_bytes = harness.network.stats().sent_bytes;
+        for counter in 0..10u8 {
+            harness.a.send(&vec![counter; payload_size]).await.unwrap();
+        }
+        // Waiting longer for the first, which may still be queued behind a's path changes when the machine is busy
+        let mut received = 0;
+        let mut timeout = TIMEOUT;
+        while harness.b.recv(timeout).await.unwrap().is_some() {
+            received += 1;
+            timeout = Duration::from_millis(200);
+        }
+        let copies = (harness.network.stats().sent_bytes - sent_bytes) as f64 / (10 * payload_size) as f64;
+        (copies.round() as usize, received)
+    }
+
+    #[tokio::test]
+    async fn test_path_selection_limits_copies() {
+        // The far gate drops the second copy of each payload
+        assert_eq!(copies_sent(6, warp_config::PathSelection::All).await, (2, 10));
+        assert_eq!(copies_sent(7, warp_config::PathSelection::Best).await, (1, 10));
+    }
+
+    #[tokio::test]
+    async fn test_best_path_follows_probed_latency() {
+        let mut transport = transport_config();
+        transport.path_selection = Some(warp_config::PathSelection::Best);
+        let harness = Harness::start_with_transport(8, transport).await.unwrap();
+        let fast_address = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 2));
+        harness.a.host.add_interface("sim1", fast_address);
+        let slow = LinkConditions {
+            latency: Duration::from_millis(150),
+            ..Default::default()
+        };
+        harness
+            .network
+            .set_link_conditions(PEER_A_ADDRESS, PEER_B_ADDRESS, slow);
+
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"warmup", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        // Let probes measure both paths
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        while harness.b.recv(Duration::from_millis(200)).await.unwrap().is_some() {}
+
+        let sent = tokio::time::Instant::now();
+        harness.a.send(b"fast").await.unwrap();
+        assert_eq!(harness.b.recv(TIMEOUT).await.unwrap().as_deref(), Some(&b"fast"[..]));
+        assert!(sent.elapsed() < Duration::from_millis(100), "{:?}", sent.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_total_loss_blocks_delivery_until_restored() {
+        let harness = Harness::start(2).await.unwrap();
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"before", TIMEOUT)
+                .await
+                .unwrap()
+        );
+
+        let lossy = LinkConditions {
+            loss: 1.0,
+            ..Default::default()
+        };
+        harness
+            .network
+            .set_link_conditions(PEER_A_ADDRESS, PEER_B_ADDRESS, lossy);
+        // Drain anything still in flight before the link went down
+        while harness.b.recv(Duration::from_millis(100)).await.unwrap().is_some() {}
+
+        assert!(
+            !harness
+                .a
+                .send_until_received(&harness.b, b"during", Duration::from_millis(500))
+                .await
+                .unwrap()
+        );
+        // The other direction is unaffected
+        assert!(
+            harness
+                .b
+                .send_until_received(&harness.a, b"reverse", TIMEOUT)
+                .await
+                .unwrap()
+        );
+
+        harness.network.clear_link_conditions(PEER_A_ADDRESS, PEER_B_ADDRESS);
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"after", TIMEOUT)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dead_far_gate_is_detected() {
+        let harness = Harness::start(9).await.unwrap();
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"before", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        assert!(harness.a.far_gate_liveness_changes().is_empty());
+
+        // Well beyond the simulation's liveness timeout
+        let lossy = LinkConditions {
+            loss: 1.0,
+            ..Default::default()
+        };
+        harness
+            .network
+            .set_link_conditions(PEER_B_ADDRESS, PEER_A_ADDRESS, lossy);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(harness.a.far_gate_liveness_changes(), vec![false]);
+        // b still hears a
+        assert!(harness.b.far_gate_liveness_changes().is_empty());
+
+        harness.network.clear_link_conditions(PEER_B_ADDRESS, PEER_A_ADDRESS);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(harness.a.far_gate_liveness_changes(), vec![false, true]);
+    }
+
+    #[tokio::test]
+    async fn test_relays_when_peers_cant_reach_each_other() {
+        let mut harness = Harness::start(10).await.unwrap();
+        for peer in [&mut harness.a, &mut harness.b] {
+            peer.reload(|config| {
+                config.interfaces.relay = Some(warp_config::RelayConfig {
+                    timeout: Duration::from_millis(100),
+                })
+            })
+            .unwrap();
+        }
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"direct", TIMEOUT)
+                .await
+                .unwrap()
+        );
+
+        // Neither peer hears the other directly any more, but both still reach warp-map
+        let lossy = LinkConditions {
+            loss: 1.0,
+            ..Default::default()
+        };
+        harness
+            .network
+            .set_link_conditions(PEER_A_ADDRESS, PEER_B_ADDRESS, lossy);
+        harness
+            .network
+            .set_link_conditions(PEER_B_ADDRESS, PEER_A_ADDRESS, lossy);
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"a to b", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        assert!(
+            harness
+                .b
+                .send_until_received(&harness.a, b"b to a", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        // Heartbeats through warp-map keep each far gate alive
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(harness.a.far_gate_liveness_changes().is_empty());
+        assert!(harness.b.far_gate_liveness_changes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_datagrams_are_dropped() {
+        let harness = Harness::start(10).await.unwrap();
+        // Once b has bound its interface socket
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"warmup", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        let attacker = harness
+            .network
+            .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 3, 1)), 0))
+            .unwrap();
+        // b's interface socket is the first one bound on its address
+        let b_address = SocketAddr::new(PEER_B_ADDRESS, crate::network::FIRST_EPHEMERAL_PORT);
+
+        let framed = warp_protocol::codec::WireMessage {
+            nonce: vec![0; 24],
+            encrypted_message: vec![1; 40],
+            associated_data: vec![],
+        }
+        .to_bytes()
+        .unwrap();
+        attacker.send_to(&[0xFF; 3], b_address);
+        attacker.send_to(&framed[..framed.len() / 2], b_address);
+        attacker.send_to(&[framed.as_slice(), &[0xFF; 3]].concat(), b_address);
+
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"still here", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        assert!(harness.b.is_running());
+    }
+
+    #[tokio::test]
+    async fn test_reload_closes_and_reopens_tunnels() {
+        let mut harness = Harness::start(9).await.unwrap();
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"before", TIMEOUT)
+                .await
+                .unwrap()
+        );
+
+        let tunnel = harness.a.config.tunnels[TUNNEL_NAME].clone();
+        harness.a.reload(|config| config.tunnels.clear()).unwrap();
+        // Give the reload time to close the gate, then drain anything already in flight
+        while harness.b.recv(Duration::from_millis(200)).await.unwrap().is_some() {}
+        assert!(
+            !harness
+                .a
+                .send_until_received(&harness.b, b"closed", Duration::from_millis(500))
+                .await
+                .unwrap()
+        );
+
+        // The reopened gate binds the same port the closed one had
+        harness
+            .a
+            .reload(|config| {
+                config.tunnels.insert(TUNNEL_NAME.to_string(), tunnel);
+                config.interfaces.holepunch_keep_alive_interval = Duration::from_millis(100);
+            })
+            .unwrap();
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"reopened", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        assert!(
+            harness
+                .b
+                .send_until_received(&harness.a, b"reverse", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        assert!(harness.a.is_running());
+    }
+
+    #[tokio::test]
+    async fn test_interface_changes_are_picked_up_between_scans() {
+        let mut harness = Harness::start(12).await.unwrap();
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"warmup", TIMEOUT)
+                .await
+                .unwrap()
+        );
+        // No scan comes round during the test, so only the host telling warp can bring the new interface in
+        harness
+            .a
+            .reload(|config| config.interfaces.interface_scan_interval = Duration::from_secs(60))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // The old interface's socket stays bound, so cut its link too
+        let cut = LinkConditions {
+            loss: 1.0,
+            ..Default::default()
+        };
+        harness.network.set_link_conditions(PEER_A_ADDRESS, PEER_B_ADDRESS, cut);
+        harness.network.set_link_conditions(PEER_B_ADDRESS, PEER_A_ADDRESS, cut);
+        harness.a.host.remove_interface(INTERFACE_NAME);
+        harness
+            .a
+            .host
+            .add_interface("sim1", IpAddr::V4(Ipv4Addr::new(10, 0, 1, 2)));
+
+        assert!(
+            harness
+                .a
+                .send_until_received(&harness.b, b"moved", TIMEOUT)
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,8 @@
+//! In-process end-to-end tests for warp
+//!
+//! [`network::SimNetwork`] stands in for the real network through `warp::transport`, and [`harness::Harness`] runs
+//! warp-map and two warp peers on it so tests can shape loss and latency between them without touching real
+//! interfaces.
+
+pub mod harness;
+pub mod network;
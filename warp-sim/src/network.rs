@@ -0,0 +1,355 @@
+//! A simulated datagram network with configurable loss, latency and jitter per link
+//!
+//! All randomness comes from a single seeded RNG so a given seed drops the same datagrams for the same sequence of
+//! sends. NAT is not simulated: every socket is reachable at the address it was bound to.
+
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::ReadBuf;
+use tokio::sync::mpsc;
+use warp::interface::NetworkInterfaceId;
+use warp::transport::{DatagramSocket, Network, SocketOptions};
+
+pub(crate) const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/// How datagrams are treated on their way from one address to another
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Probability (0 to 1) that a datagram is silently dropped
+    pub loss: f64,
+    /// Fixed delay added to every datagram
+    pub latency: Duration,
+    /// Extra delay chosen uniformly between zero and this for each datagram; this can reorder datagrams
+    pub jitter: Duration,
+}
+
+/// Running totals of what happened to datagrams sent over the network
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimNetworkStats {
+    pub sent: u64,
+    pub sent_bytes: u64,
+    pub lost: u64,
+    /// Dropped because nothing was bound to the destination address
+    pub unroutable: u64,
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+struct NetworkState {
+    rng: rand::rngs::StdRng,
+    sockets: HashMap<SocketAddr, mpsc::UnboundedSender<Datagram>>,
+    default_conditions: LinkConditions,
+    // Keyed by (source, destination) and applied in one direction only
+    link_conditions: HashMap<(IpAddr, IpAddr), LinkConditions>,
+    next_port: HashMap<IpAddr, u16>,
+    stats: SimNetworkStats,
+}
+
+/// Handle to a simulated network; clones refer to the same network
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(NetworkState {
+                rng: rand::rngs::StdRng::seed_from_u64(seed),
+                sockets: HashMap::new(),
+                default_conditions: LinkConditions::default(),
+                link_conditions: HashMap::new(),
+                next_port: HashMap::new(),
+                stats: SimNetworkStats::default(),
+            })),
+        }
+    }
+
+    /// Conditions for every link without its own conditions set by [`Self::set_link_conditions`]
+    pub fn set_default_conditions(&self, conditions: LinkConditions) {
+        self.state.lock().unwrap().default_conditions = conditions;
+    }
+
+    /// Conditions for datagrams sent from `source` to `destination` (but not the other way around)
+    pub fn set_link_conditions(&self, source: IpAddr, destination: IpAddr, conditions: LinkConditions) {
+        self.state
+            .lock()
+            .unwrap()
+            .link_conditions
+            .insert((source, destination), conditions);
+    }
+
+    /// Go back to using the default conditions for datagrams sent from `source` to `destination`
+    pub fn clear_link_conditions(&self, source: IpAddr, destination: IpAddr) {
+        self.state
+            .lock()
+            .unwrap()
+            .link_conditions
+            .remove(&(source, destination));
+    }
+
+    pub fn stats(&self) -> SimNetworkStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Bind a socket to `address`; a port of 0 picks an unused ephemeral port
+    pub fn bind(&self, address: SocketAddr) -> io::Result<SimSocket> {
+        let mut state = self.state.lock().unwrap();
+
+        let address = if address.port() == 0 {
+            let next_port = state.next_port.entry(address.ip()).or_insert(FIRST_EPHEMERAL_PORT);
+            let port = *next_port;
+            *next_port = next_port
+                .checked_add(1)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "ran out of ephemeral ports"))?;
+            SocketAddr::new(address.ip(), port)
+        } else {
+            address
+        };
+
+        if state.sockets.contains_key(&address) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, address.to_string()));
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        state.sockets.insert(address, tx);
+
+        Ok(SimSocket {
+            local_addr: address,
+            network: self.clone(),
+            inbound: Mutex::new(rx),
+        })
+    }
+
+    /// Create a host with the given `(name, ip)` interfaces, for use as a warp core's [`Network`]
+    pub fn host(&self, interfaces: &[(&str, IpAddr)]) -> Arc<SimHost> {
+        let host = Arc::new(SimHost {
+            network: self.clone(),
+            interfaces: Mutex::new(Vec::new()),
+            changes: tokio::sync::watch::Sender::new(()),
+        });
+        for (name, ip) in interfaces {
+            host.add_interface(name, *ip);
+        }
+        host
+    }
+
+    fn send(&self, from: SocketAddr, to: SocketAddr, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.stats.sent += 1;
+        state.stats.sent_bytes += data.len() as u64;
+
+        let conditions = state
+            .link_conditions
+            .get(&(from.ip(), to.ip()))
+            .copied()
+            .unwrap_or(state.default_conditions);
+
+        if conditions.loss > 0.0 && state.rng.random::<f64>() < conditions.loss {
+            state.stats.lost += 1;
+            return;
+        }
+
+        let Some(destination) = state.sockets.get(&to).cloned() else {
+            state.stats.unroutable += 1;
+            return;
+        };
+
+        let delay = conditions.latency + conditions.jitter.mul_f64(state.rng.random::<f64>());
+        let datagram = (data.to_vec(), from);
+        if delay.is_zero() {
+            let _ = destination.send(datagram);
+        } else {
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let _ = destination.send(datagram);
+            });
+        }
+    }
+
+    fn unbind(&self, address: SocketAddr) {
+        self.state.lock().unwrap().sockets.remove(&address);
+    }
+}
+
+/// Socket bound to an address on a [`SimNetwork`]; unbound when dropped
+pub struct SimSocket {
+    local_addr: SocketAddr,
+    network: SimNetwork,
+    inbound: Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+impl SimSocket {
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        (self as &dyn DatagramSocket).recv_from(buf).await
+    }
+
+    pub fn send_to(&self, buf: &[u8], target: SocketAddr) -> usize {
+        self.network.send(self.local_addr, target, buf);
+        buf.len()
+    }
+}
+
+impl DatagramSocket for SimSocket {
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<SocketAddr>> {
+        match self.inbound.lock().unwrap().poll_recv(cx) {
+            Poll::Ready(Some((data, from))) => {
+                // Like UDP, anything that doesn't fit in the buffer is discarded
+                let len = data.len().min(buf.remaining());
+                buf.put_slice(&data[..len]);
+                Poll::Ready(Ok(from))
+            }
+            Poll::Ready(None) => Poll::Ready(Err(io::Error::new(io::ErrorKind::NotConnected, "socket unbound"))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_send_to(&self, _cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(self.send_to(buf, target)))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        self.network.unbind(self.local_addr);
+    }
+}
+
+/// A machine on a [`SimNetwork`] whose interfaces can be added and removed while warp is running
+pub struct SimHost {
+    network: SimNetwork,
+    interfaces: Mutex<Vec<NetworkInterfaceId>>,
+    // Tells warp the interfaces changed, as netlink would
+    changes: tokio::sync::watch::Sender<()>,
+}
+
+impl SimHost {
+    pub fn add_interface(&self, name: &str, ip: IpAddr) {
+        self.interfaces.lock().unwrap().push(NetworkInterfaceId {
+            name: name.to_string(),
+            ip,
+            // Simulated hosts share no network, so peers reach each other through their mapped addresses
+            prefix_len: if ip.is_ipv4() { 32 } else { 128 },
+        });
+        self.changes.send_replace(());
+    }
+
+    pub fn remove_interface(&self, name: &str) {
+        self.interfaces
+            .lock()
+            .unwrap()
+            .retain(|interface| interface.name != name);
+        self.changes.send_replace(());
+    }
+}
+
+impl Network for SimHost {
+    fn interfaces(&self) -> Vec<NetworkInterfaceId> {
+        self.interfaces.lock().unwrap().clone()
+    }
+
+    fn bind(&self, interface: &NetworkInterfaceId, options: &SocketOptions) -> anyhow::Result<Arc<dyn DatagramSocket>> {
+        let socket = match options.port {
+            Some(port) => self
+                .network
+                .bind(SocketAddr::new(interface.ip, port))
+                .or_else(|_| self.network.bind(SocketAddr::new(interface.ip, 0)))?,
+            None => self.network.bind(SocketAddr::new(interface.ip, 0))?,
+        };
+        Ok(Arc::new(socket))
+    }
+
+    fn watch_interfaces(&self) -> anyhow::Result<tokio::sync::watch::Receiver<()>> {
+        Ok(self.changes.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(ip: [u8; 4], port: u16) -> SocketAddr {
+        SocketAddr::from((ip, port))
+    }
+
+    #[tokio::test]
+    async fn test_delivery_and_ephemeral_ports() {
+        let network = SimNetwork::new(0);
+        let a = network.bind(address([10, 0, 0, 1], 0)).unwrap();
+        let b = network.bind(address([10, 0, 0, 2], 5000)).unwrap();
+        assert_eq!(a.local_addr().unwrap(), address([10, 0, 0, 1], FIRST_EPHEMERAL_PORT));
+        assert!(network.bind(address([10, 0, 0, 2], 5000)).is_err());
+
+        a.send_to(b"hello", address([10, 0, 0, 2], 5000));
+        let mut buf = [0u8; 16];
+        let (len, from) = b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(from, a.local_addr().unwrap());
+
+        // Nothing is listening here any more
+        drop(b);
+        a.send_to(b"hello", address([10, 0, 0, 2], 5000));
+        assert_eq!(
+            network.stats(),
+            SimNetworkStats {
+                sent: 2,
+                sent_bytes: 10,
+                lost: 0,
+                unroutable: 1
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_loss_is_deterministic_and_directional() {
+        let run = |seed| {
+            let network = SimNetwork::new(seed);
+            let a = network.bind(address([10, 0, 0, 1], 1)).unwrap();
+            let b = network.bind(address([10, 0, 0, 2], 1)).unwrap();
+            network.set_link_conditions(
+                a.local_addr().unwrap().ip(),
+                address([10, 0, 0, 2], 1).ip(),
+                LinkConditions {
+                    loss: 0.5,
+                    ..Default::default()
+                },
+            );
+            for _ in 0..1000 {
+                a.send_to(b"x", address([10, 0, 0, 2], 1));
+                b.send_to(b"x", address([10, 0, 0, 1], 1));
+            }
+            network.stats().lost
+        };
+
+        let lost = run(7);
+        assert_eq!(lost, run(7));
+        // Only the a -> b direction is lossy
+        assert!((400..600).contains(&lost), "lost {lost}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latency() {
+        let network = SimNetwork::new(0);
+        let a = network.bind(address([10, 0, 0, 1], 1)).unwrap();
+        let b = network.bind(address([10, 0, 0, 2], 1)).unwrap();
+        network.set_default_conditions(LinkConditions {
+            latency: Duration::from_millis(20),
+            ..Default::default()
+        });
+
+        let start = tokio::time::Instant::now();
+        a.send_to(b"x", address([10, 0, 0, 2], 1));
+        let mut buf = [0u8; 1];
+        b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,278 @@
+//! Long-running stability test: pushes warp-gauge style load through two in-process warp peers while interfaces come
+//! and go and warp-map restarts, reporting delivery, latency and resource usage as it goes.
+
+use clap::Parser;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, SystemTime};
+use tracing_subscriber::util::SubscriberInitExt;
+use warp_sim::harness::Harness;
+use warp_sim::network::LinkConditions;
+
+const PACKET_SIZE: usize = 1000;
+// Each peer also has this interface, which is toggled so at least one path is always up
+const SECONDARY_INTERFACE: &str = "sim1";
+const PEER_A_SECONDARY_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 2));
+const PEER_B_SECONDARY_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 2, 2));
+
+#[derive(Parser)]
+#[command(name = "warp-soak")]
+#[command(about = "Soak test warp against interface changes and warp-map restarts")]
+struct Args {
+    /// How long to run for, in seconds
+    #[arg(long, default_value_t = 3600)]
+    duration: u64,
+
+    /// Payloads per second sent through the tunnel
+    #[arg(long, default_value_t = 200)]
+    pps: u64,
+
+    /// Seconds between adding or removing each peer's secondary interface; 0 disables this
+    #[arg(long, default_value_t = 30)]
+    interface_toggle_interval: u64,
+
+    /// Seconds between warp-map restarts; 0 disables this
+    #[arg(long, default_value_t = 300)]
+    map_restart_interval: u64,
+
+    /// Seconds between progress reports
+    #[arg(long, default_value_t = 10)]
+    report_interval: u64,
+
+    /// Probability of dropping each datagram between the simulated hosts
+    #[arg(long, default_value_t = 0.0)]
+    loss: f64,
+
+    /// Latency added to each datagram between the simulated hosts, in milliseconds
+    #[arg(long, default_value_t = 0)]
+    latency_ms: u64,
+
+    /// Up to this much extra latency is added to each datagram, in milliseconds
+    #[arg(long, default_value_t = 0)]
+    jitter_ms: u64,
+
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Fail if less than this fraction of payloads is delivered over the whole run
+    #[arg(long, default_value_t = 0.95)]
+    min_delivery_ratio: f64,
+
+    /// Fail if resident memory grows by more than this many MiB between the first and last report
+    #[arg(long, default_value_t = 64)]
+    max_rss_growth_mib: u64,
+
+    #[arg(short, long, default_value_t = tracing_subscriber::filter::LevelFilter::WARN)]
+    verbosity: tracing_subscriber::filter::LevelFilter,
+}
+
+#[derive(bincode::Encode, bincode::Decode)]
+struct Payload {
+    counter: u64,
+    timestamp: SystemTime,
+}
+
+#[derive(Default)]
+struct Totals {
+    sent: u64,
+    received: u64,
+    duplicates: u64,
+    send_errors: u64,
+    interface_toggles: u64,
+    map_restarts: u64,
+}
+
+// Reset after every report
+#[derive(Default)]
+struct Window {
+    received: u64,
+    latencies: Vec<Duration>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    tracing_subscriber::fmt().with_max_level(args.verbosity).finish().init();
+
+    let mut harness = Harness::start(args.seed).await?;
+    harness.network.set_default_conditions(LinkConditions {
+        loss: args.loss,
+        latency: Duration::from_millis(args.latency_ms),
+        jitter: Duration::from_millis(args.jitter_ms),
+    });
+    harness
+        .a
+        .host
+        .add_interface(SECONDARY_INTERFACE, PEER_A_SECONDARY_ADDRESS);
+    harness
+        .b
+        .host
+        .add_interface(SECONDARY_INTERFACE, PEER_B_SECONDARY_ADDRESS);
+
+    if !harness
+        .a
+        .send_until_received(&harness.b, b"warmup", Duration::from_secs(30))
+        .await?
+    {
+        anyhow::bail!("the tunnel never came up");
+    }
+    println!("Tunnel is up; soaking for {}s", args.duration);
+
+    let start = tokio::time::Instant::now();
+    let mut send_interval = tokio::time::interval(Duration::from_secs_f64(1.0 / args.pps.max(1) as f64));
+    send_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut toggle_interval = optional_interval(args.interface_toggle_interval);
+    let mut map_restart_interval = optional_interval(args.map_restart_interval);
+    let mut report_interval = tokio::time::interval(Duration::from_secs(args.report_interval.max(1)));
+    let finished = tokio::time::sleep(Duration::from_secs(args.duration));
+    tokio::pin!(finished);
+
+    let mut totals = Totals::default();
+    let mut window = Window::default();
+    let mut seen = HashSet::new();
+    let mut secondary_interfaces_up = true;
+    let mut first_rss = None;
+    let mut last_rss = None;
+    let mut buf = vec![0u8; 65536];
+
+    loop {
+        tokio::select! {
+            _ = &mut finished => break,
+            _ = send_interval.tick() => {
+                totals.sent += 1;
+                let payload = Payload { counter: totals.sent, timestamp: SystemTime::now() };
+                let mut data = bincode::encode_to_vec(payload, bincode::config::standard())?;
+                data.resize(PACKET_SIZE, b'*');
+                if let Err(e) = harness.a.send(&data).await {
+                    totals.send_errors += 1;
+                    tracing::warn!("Failed to send payload {}: {}", totals.sent, e);
+                }
+            }
+            received = harness.b.recv_into(&mut buf) => {
+                let size = received?;
+                if size != PACKET_SIZE {
+                    // Left over from the warmup
+                    continue;
+                }
+                let Ok((payload, _)) =
+                    bincode::decode_from_slice::<Payload, _>(&buf[..size], bincode::config::standard())
+                else {
+                    tracing::warn!("Received a payload that isn't from this soak test");
+                    continue;
+                };
+                if !seen.insert(payload.counter) {
+                    totals.duplicates += 1;
+                    continue;
+                }
+                totals.received += 1;
+                window.received += 1;
+                window.latencies.push(payload.timestamp.elapsed().unwrap_or_default());
+            }
+            _ = toggle_interval.tick() => {
+                secondary_interfaces_up = !secondary_interfaces_up;
+                if secondary_interfaces_up {
+                    harness.a.host.add_interface(SECONDARY_INTERFACE, PEER_A_SECONDARY_ADDRESS);
+                    harness.b.host.add_interface(SECONDARY_INTERFACE, PEER_B_SECONDARY_ADDRESS);
+                } else {
+                    harness.a.host.remove_interface(SECONDARY_INTERFACE);
+                    harness.b.host.remove_interface(SECONDARY_INTERFACE);
+                }
+                totals.interface_toggles += 1;
+            }
+            _ = map_restart_interval.tick() => {
+                harness.restart_map().await?;
+                totals.map_restarts += 1;
+            }
+            _ = report_interval.tick() => {
+                let rss = resident_memory_bytes();
+                first_rss = first_rss.or(rss);
+                last_rss = rss;
+                report(start.elapsed(), &totals, &mut window, rss);
+
+                // Payloads more than a report interval old are never coming, so stop tracking them
+                let horizon = totals.sent.saturating_sub(args.pps * args.report_interval.max(1));
+                seen.retain(|counter| *counter > horizon);
+
+                if !harness.a.is_running() || !harness.b.is_running() {
+                    anyhow::bail!("a warp core stopped unexpectedly");
+                }
+            }
+        }
+    }
+
+    report(start.elapsed(), &totals, &mut window, resident_memory_bytes());
+    println!(
+        "Interface toggles: {}, warp-map restarts: {}, simulated network: {:?}",
+        totals.interface_toggles,
+        totals.map_restarts,
+        harness.network.stats()
+    );
+
+    let delivery_ratio = totals.received as f64 / totals.sent.max(1) as f64;
+    if delivery_ratio < args.min_delivery_ratio {
+        anyhow::bail!(
+            "delivered {:.2}% of payloads; expected at least {:.2}%",
+            delivery_ratio * 100.0,
+            args.min_delivery_ratio * 100.0
+        );
+    }
+    if let (Some(first), Some(last)) = (first_rss, last_rss) {
+        let growth_mib = last.saturating_sub(first) / (1024 * 1024);
+        if growth_mib > args.max_rss_growth_mib {
+            anyhow::bail!("resident memory grew by {growth_mib} MiB");
+        }
+    }
+    if !harness.a.is_running() || !harness.b.is_running() {
+        anyhow::bail!("a warp core stopped unexpectedly");
+    }
+
+    println!("Soak passed");
+    Ok(())
+}
+
+fn optional_interval(seconds: u64) -> tokio::time::Interval {
+    // A disabled interval still needs to be pollable in select!, so make it effectively never fire
+    let period = if seconds == 0 {
+        Duration::from_secs(u32::MAX as u64)
+    } else {
+        Duration::from_secs(seconds)
+    };
+    tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+}
+
+fn report(elapsed: Duration, totals: &Totals, window: &mut Window, rss: Option<u64>) {
+    window.latencies.sort();
+    let percentile = |p: f64| {
+        window
+            .latencies
+            .get(((window.latencies.len() as f64 * p) as usize).min(window.latencies.len().saturating_sub(1)))
+            .map(|latency| latency.as_secs_f64() * 1000.0)
+            .unwrap_or(0.0)
+    };
+
+    println!(
+        "{:>6}s sent={} received={} duplicates={} send_errors={} | window received={} p50={:.2}ms p99={:.2}ms \
+         max={:.2}ms | rss={} tasks={}",
+        elapsed.as_secs(),
+        totals.sent,
+        totals.received,
+        totals.duplicates,
+        totals.send_errors,
+        window.received,
+        percentile(0.5),
+        percentile(0.99),
+        percentile(1.0),
+        rss.map(|rss| format!("{}MiB", rss / (1024 * 1024)))
+            .unwrap_or_else(|| "unknown".to_string()),
+        tokio::runtime::Handle::current().metrics().num_alive_tasks(),
+    );
+
+    *window = Window::default();
+}
+
+fn resident_memory_bytes() -> Option<u64> {
+    // The second field of statm is the resident set size in pages
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
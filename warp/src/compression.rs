@@ -0,0 +1,105 @@
+//! Compressing tunnel payloads, configured by each tunnel's `transport.compression`
+//!
+//! The accelerator compresses each tunnel payload, or each of its FEC shards, on its own just before sealing it, but
+//! only for far gates whose session agreed to it (see [`crate::session`]), and marks it with how it was compressed. A
+//! payload that doesn't get any smaller is sent as it was. The far gate's gate decompresses payloads as they arrive,
+//! before reassembling any FEC shards, so nothing past it ever sees compressed data.
+
+use warp_protocol::messages::{PayloadCompression, TunnelPayload};
+
+// No tunnel payload is bigger than the gates' receive buffers, so one that claims to decompress to more is refused
+// before anything is allocated for it
+const MAX_DECOMPRESSED_SIZE: usize = 65536;
+
+/// `tunnel_payload` compressed with `compression`, or None if that doesn't make it any smaller
+pub fn compress(tunnel_payload: &TunnelPayload, compression: warp_config::Compression) -> Option<TunnelPayload> {
+    let (data, payload_compression) = match compression {
+        warp_config::Compression::Lz4 => (
+            lz4_flex::compress_prepend_size(&tunnel_payload.data),
+            PayloadCompression::Lz4,
+        ),
+        warp_config::Compression::Zstd(level) => (
+            zstd::bulk::compress(&tunnel_payload.data, level).ok()?,
+            PayloadCompression::Zstd,
+        ),
+    };
+    (data.len() < tunnel_payload.data.len()).then(|| TunnelPayload {
+        tunnel_id: tunnel_payload.tunnel_id.clone(),
+        tracer: tunnel_payload.tracer,
+        reconstruction_tag: tunnel_payload.reconstruction_tag.clone(),
+        data,
+        compression: payload_compression,
+    })
+}
+
+/// Undo whatever compression the far gate applied to `tunnel_payload`
+pub fn decompress(tunnel_payload: &mut TunnelPayload) -> anyhow::Result<()> {
+    tunnel_payload.data = match tunnel_payload.compression {
+        PayloadCompression::None => return Ok(()),
+        PayloadCompression::Lz4 => {
+            let (size, compressed) = lz4_flex::block::uncompressed_size(&tunnel_payload.data)?;
+            anyhow::ensure!(
+                size <= MAX_DECOMPRESSED_SIZE,
+                "payload would decompress to {size} bytes, more than {MAX_DECOMPRESSED_SIZE}"
+            );
+            lz4_flex::decompress(compressed, size)?
+        }
+        PayloadCompression::Zstd => zstd::bulk::decompress(&tunnel_payload.data, MAX_DECOMPRESSED_SIZE)?,
+    };
+    tunnel_payload.compression = PayloadCompression::None;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp_protocol::messages::TunnelId;
+
+    fn text_payload() -> TunnelPayload {
+        let data = "GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(20);
+        TunnelPayload::new(TunnelId::Id(1), 7, data.into_bytes())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for compression in [warp_config::Compression::Lz4, warp_config::Compression::Zstd(3)] {
+            let original = text_payload();
+            let mut compressed = compress(&original, compression).unwrap();
+            assert!(compressed.data.len() < original.data.len());
+            assert_ne!(compressed.compression, PayloadCompression::None);
+            assert_eq!(compressed.tracer, original.tracer);
+
+            decompress(&mut compressed).unwrap();
+            assert_eq!(compressed, original);
+        }
+    }
+
+    #[test]
+    fn test_incompressible_payloads_are_left_alone() {
+        let random: Vec<u8> = (0..256).map(|_| rand::random()).collect();
+        let payload = TunnelPayload::new(TunnelId::Id(1), 7, random);
+        assert_eq!(compress(&payload, warp_config::Compression::Lz4), None);
+        assert_eq!(compress(&payload, warp_config::Compression::Zstd(3)), None);
+
+        // Which the far gate passes on as they are
+        let mut received = payload.clone();
+        decompress(&mut received).unwrap();
+        assert_eq!(received, payload);
+    }
+
+    #[test]
+    fn test_oversized_payloads_are_refused() {
+        let zeros = TunnelPayload::new(TunnelId::Id(1), 7, vec![0; MAX_DECOMPRESSED_SIZE + 1]);
+        for compression in [warp_config::Compression::Lz4, warp_config::Compression::Zstd(3)] {
+            let mut compressed = compress(&zeros, compression).unwrap();
+            assert!(decompress(&mut compressed).is_err());
+        }
+
+        // Nor is garbage passed on
+        let mut garbage = TunnelPayload {
+            compression: PayloadCompression::Zstd,
+            ..TunnelPayload::new(TunnelId::Id(1), 7, vec![1, 2, 3])
+        };
+        assert!(decompress(&mut garbage).is_err());
+    }
+}
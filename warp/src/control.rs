@@ -0,0 +1,470 @@
+//! Opening and closing tunnels while warp runs, through [`crate::WarpCore::controller`] or the socket at
+//! `control.socket`; configured by `control`
+//!
+//! Tunnels opened this way are kept apart from the config's, so reloading the config leaves them open, and they last
+//! until warp stops. One opened with a `far_end` gate is opened at its far gate too, if the far gate sets
+//! `control.accept_far_gate_tunnels`: every [`TUNNEL_SYNC_INTERVAL`], and whenever they change, warp sends each far gate
+//! a `TunnelSync` listing all the tunnels it should have for warp. The far gate opens those it lacks and closes those
+//! that are no longer listed, so a lost sync or a restart at either end is made good by the next one.
+//!
+//! The socket takes one request per connection, as a TOML document, and answers with one; for example
+//! `printf '[close]\nname = "video"\n' | socat - UNIX-CONNECT:/run/warp/control.sock`.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use warp_protocol::messages::{SyncedTunnel, TunnelSync};
+
+/// How often each far gate is told which tunnels to have for us, besides whenever they change
+pub const TUNNEL_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+// The largest request the socket takes
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Request {
+    /// Open `tunnel` as `name`, and have its far gate open its end with the gate `far_end` if that is given
+    Open {
+        name: String,
+        tunnel: Box<warp_config::WarpTunnelConfig>,
+        far_end: Option<warp_config::WarpGateConfig>,
+    },
+    /// Close a tunnel that was opened by an `Open` request
+    Close { name: String },
+    /// Only list the tunnels
+    List {},
+    /// List the tunnels and report whether warp's tasks are keeping up; see [`crate::health`]
+    Health {},
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Response {
+    /// Why the request failed, if it did
+    pub error: Option<String>,
+    /// The tunnels warp has, or is trying to open, once the request is handled
+    pub tunnels: Vec<TunnelStatus>,
+    /// For a `Health` request
+    pub health: Option<crate::health::HealthReport>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TunnelStatus {
+    pub name: String,
+    pub far_gate: String,
+    pub opened_by: OpenedBy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenedBy {
+    Config,
+    Control,
+    FarGate,
+}
+
+/// Sends requests to a running [`crate::WarpCore`]
+#[derive(Clone)]
+pub struct Controller {
+    requests: mpsc::UnboundedSender<(Request, oneshot::Sender<Response>)>,
+}
+
+impl Controller {
+    pub(crate) fn new(requests: mpsc::UnboundedSender<(Request, oneshot::Sender<Response>)>) -> Self {
+        Self { requests }
+    }
+
+    /// Have warp carry out `request`; fails only if warp isn't running
+    pub async fn request(&self, request: Request) -> anyhow::Result<Response> {
+        let (response_tx, response) = oneshot::channel();
+        self.requests
+            .send((request, response_tx))
+            .map_err(|_| anyhow::anyhow!("warp isn't running"))?;
+        response.await.map_err(|_| anyhow::anyhow!("warp stopped"))
+    }
+}
+
+/// The tunnels opened while warp runs: through requests, and by far gates
+#[derive(Default)]
+pub(crate) struct DynamicTunnels {
+    // With the gate for the far gate to open its end with, if any
+    control: BTreeMap<String, (warp_config::WarpTunnelConfig, Option<warp_config::WarpGateConfig>)>,
+    // The tunnels each far gate's latest TunnelSync asked for
+    far_gates: BTreeMap<warp_protocol::PublicKey, BTreeMap<String, warp_config::WarpTunnelConfig>>,
+}
+
+impl DynamicTunnels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `config` with these tunnels added to it; the far gates' only if `config` accepts them, and only for far gates
+    /// warp has tunnels to anyway. Tunnels whose names are taken already are left out.
+    pub fn apply_to(&self, config: &warp_config::WarpConfig) -> warp_config::WarpConfig {
+        let mut applied = config.clone();
+        for (name, (tunnel, _)) in &self.control {
+            applied.tunnels.entry(name.clone()).or_insert_with(|| tunnel.clone());
+        }
+        if accepts_far_gate_tunnels(config) {
+            let far_gates = applied.far_gates();
+            for (far_gate, tunnels) in self
+                .far_gates
+                .iter()
+                .filter(|(far_gate, _)| far_gates.contains(far_gate))
+            {
+                for (name, tunnel) in tunnels {
+                    applied
+                        .tunnels
+                        .entry(name.clone())
+                        .or_insert_with(|| warp_config::WarpTunnelConfig {
+                            far_gate: Some(warp_config::WarpFarGateConfig { public_key: *far_gate }),
+                            ..tunnel.clone()
+                        });
+                }
+            }
+        }
+        applied
+    }
+
+    /// Add a tunnel for an `Open` request; `config` is the config the tunnels are applied to
+    pub fn open(
+        &mut self,
+        config: &warp_config::WarpConfig,
+        name: String,
+        tunnel: warp_config::WarpTunnelConfig,
+        far_end: Option<warp_config::WarpGateConfig>,
+    ) -> anyhow::Result<()> {
+        if config.tunnels.contains_key(&name) || self.control.contains_key(&name) {
+            anyhow::bail!("tunnel {name} already exists");
+        }
+        self.control.insert(name, (tunnel, far_end));
+        Ok(())
+    }
+
+    /// Remove a tunnel for a `Close` request
+    pub fn close(&mut self, name: &str) -> anyhow::Result<()> {
+        self.control
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| anyhow::anyhow!("tunnel {name} wasn't opened through the control API"))
+    }
+
+    /// Record the tunnels `far_gate` asks for, skipping any whose config can't be read; returns whether they changed
+    pub fn synced(&mut self, far_gate: warp_protocol::PublicKey, sync: TunnelSync) -> bool {
+        let tunnels: BTreeMap<_, _> = sync
+            .tunnels
+            .into_iter()
+            .filter_map(|synced| match toml::from_str(&synced.config) {
+                Ok(tunnel) => Some((synced.name, tunnel)),
+                Err(e) => {
+                    tracing::event!(
+                        tracing::Level::WARN,
+                        peer = warp_protocol::crypto::pubkey_to_string(&far_gate),
+                        tunnel = synced.name,
+                        error = %e,
+                        "TUNNEL_SYNC_INVALID"
+                    );
+                    None
+                }
+            })
+            .collect();
+        let previous = if tunnels.is_empty() {
+            self.far_gates.remove(&far_gate)
+        } else {
+            self.far_gates.insert(far_gate, tunnels)
+        };
+        previous.as_ref() != self.far_gates.get(&far_gate)
+    }
+
+    /// The `TunnelSync` for each of `peers`, listing the tunnels opened with a `far_end` that go to it; `config` is
+    /// the config the tunnels are applied to
+    pub fn syncs(
+        &self,
+        config: &warp_config::WarpConfig,
+        peers: &[warp_protocol::PublicKey],
+    ) -> Vec<(warp_protocol::PublicKey, TunnelSync)> {
+        peers
+            .iter()
+            .map(|peer| {
+                let tunnels = self
+                    .control
+                    .iter()
+                    .filter(|(_, (tunnel, _))| config.far_gate_of(tunnel) == *peer)
+                    .filter_map(|(name, (tunnel, far_end))| {
+                        let far_end = warp_config::WarpTunnelConfig {
+                            gate: far_end.clone()?,
+                            far_gate: None,
+                            ..tunnel.clone()
+                        };
+                        Some(SyncedTunnel {
+                            name: name.clone(),
+                            config: toml::to_string(&far_end).expect("tunnel configs always serialise"),
+                        })
+                    })
+                    .collect();
+                (*peer, TunnelSync { tunnels })
+            })
+            .collect()
+    }
+
+    /// The tunnels of `config` with these applied to it, and what opened them
+    pub fn status(&self, applied: &warp_config::WarpConfig) -> Vec<TunnelStatus> {
+        applied
+            .tunnels
+            .iter()
+            .map(|(name, tunnel)| {
+                let far_gate = applied.far_gate_of(tunnel);
+                let opened_by = if self.control.contains_key(name) {
+                    OpenedBy::Control
+                } else if self
+                    .far_gates
+                    .get(&far_gate)
+                    .is_some_and(|tunnels| tunnels.contains_key(name))
+                    && tunnel.far_gate.is_some()
+                {
+                    OpenedBy::FarGate
+                } else {
+                    OpenedBy::Config
+                };
+                TunnelStatus {
+                    name: name.clone(),
+                    far_gate: warp_protocol::crypto::pubkey_to_string(&far_gate),
+                    opened_by,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Whether `config` lets far gates open tunnels
+pub(crate) fn accepts_far_gate_tunnels(config: &warp_config::WarpConfig) -> bool {
+    config
+        .control
+        .as_ref()
+        .and_then(|control| control.accept_far_gate_tunnels)
+        .unwrap_or(false)
+}
+
+/// Bind the control socket at `path`, replacing whatever was left there, so that only the current user can connect to it
+#[cfg(unix)]
+pub fn listen(path: &std::path::Path) -> anyhow::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Answer requests on `listener` until warp stops
+#[cfg(unix)]
+pub async fn serve(listener: tokio::net::UnixListener, controller: Controller) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let controller = controller.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = answer(stream, &controller).await {
+                        tracing::warn!("Failed to answer a control request: {:#}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                tracing::warn!("Failed to accept a control connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn answer(mut stream: tokio::net::UnixStream, controller: &Controller) -> anyhow::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut request = String::new();
+    (&mut stream)
+        .take(MAX_REQUEST_SIZE)
+        .read_to_string(&mut request)
+        .await?;
+    let response = match toml::from_str(&request) {
+        Ok(request) => controller.request(request).await?,
+        Err(e) => Response {
+            error: Some(format!("invalid request: {e}")),
+            ..Default::default()
+        },
+    };
+    stream.write_all(toml::to_string(&response)?.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(far_gate: warp_protocol::PublicKey, accept: bool) -> warp_config::WarpConfig {
+        let mut config: warp_config::WarpConfig = toml::from_str(&format!(
+            r#"
+                private_key = "{}"
+                tunnels = {{}}
+                [interfaces]
+                interface_scan_interval = 1.0
+                holepunch_keep_alive_interval = 1.0
+                exclusion_patterns = []
+                inclusion_patterns = []
+                max_consecutive_failures = 3
+                [warp_map]
+                address = "192.0.2.1:13116"
+                public_key = "{}"
+                [far_gate]
+                public_key = "{}"
+            "#,
+            warp_protocol::crypto::privkey_to_string(&warp_protocol::PrivateKey::random(&mut rand::rng())),
+            warp_protocol::crypto::pubkey_to_string(&far_gate),
+            warp_protocol::crypto::pubkey_to_string(&far_gate),
+        ))
+        .unwrap();
+        config.control = Some(warp_config::ControlConfig {
+            socket: None,
+            accept_far_gate_tunnels: Some(accept),
+        });
+        config
+    }
+
+    fn request(name: &str, gate: &str, far_end: Option<&str>) -> Request {
+        let far_end = far_end
+            .map(|far_end| format!("far_end = {far_end}"))
+            .unwrap_or_default();
+        toml::from_str(&format!(
+            r#"
+                [open]
+                name = "{name}"
+                {far_end}
+                [open.tunnel]
+                gate = {gate}
+                [open.tunnel.transport]
+                mtu = 1400
+                ordered = false
+                send_deadline = 1.0
+                [open.tunnel.transport.redundancy]
+                num_shards = 1
+                required_shards = 1
+            "#
+        ))
+        .unwrap()
+    }
+
+    fn open(tunnels: &mut DynamicTunnels, config: &warp_config::WarpConfig, request: Request) -> anyhow::Result<()> {
+        let Request::Open { name, tunnel, far_end } = request else {
+            panic!("not an open request");
+        };
+        tunnels.open(config, name, *tunnel, far_end)
+    }
+
+    #[test]
+    fn test_far_gate_opens_synced_tunnels() {
+        let (near_key, far_key) = (
+            warp_protocol::PrivateKey::random(&mut rand::rng()).public_key(),
+            warp_protocol::PrivateKey::random(&mut rand::rng()).public_key(),
+        );
+        let near_config = config(far_key, false);
+        let mut near = DynamicTunnels::new();
+        open(
+            &mut near,
+            &near_config,
+            request(
+                "video",
+                r#"{ path = "/run/near.sock" }"#,
+                Some(r#"{ path = "/run/far.sock" }"#),
+            ),
+        )
+        .unwrap();
+        open(
+            &mut near,
+            &near_config,
+            request("local", r#"{ path = "/run/local.sock" }"#, None),
+        )
+        .unwrap();
+        assert!(
+            open(
+                &mut near,
+                &near_config,
+                request("video", r#"{ path = "/run/other.sock" }"#, None)
+            )
+            .is_err()
+        );
+        let applied = near.apply_to(&near_config);
+        assert_eq!(applied.tunnels.len(), 2);
+
+        // Only the tunnel with a far end is synced, with the far end's gate and no far gate
+        let [(peer, sync)] = near.syncs(&applied, &[far_key]).try_into().unwrap();
+        assert_eq!(peer, far_key);
+        assert_eq!(sync.tunnels.len(), 1);
+        assert_eq!(sync.tunnels[0].name, "video");
+
+        let mut far = DynamicTunnels::new();
+        assert!(far.synced(near_key, sync.clone()));
+        assert!(!far.synced(near_key, sync));
+        // Not opened unless the far gate accepts them, and only for its peers
+        let mut far_config = config(near_key, false);
+        assert!(far.apply_to(&far_config).tunnels.is_empty());
+        far_config.control = Some(warp_config::ControlConfig {
+            socket: None,
+            accept_far_gate_tunnels: Some(true),
+        });
+        let far_applied = far.apply_to(&far_config);
+        let video = &far_applied.tunnels["video"];
+        assert_eq!(far_applied.far_gate_of(video), near_key);
+        assert_eq!(
+            video.gate,
+            warp_config::WarpGateConfig::UnixDomainSocket(warp_config::UnixDomainSocketConfig {
+                path: "/run/far.sock".into()
+            })
+        );
+        assert_eq!(far.status(&far_applied)[0].opened_by, OpenedBy::FarGate);
+        assert!(far.apply_to(&config(far_key, true)).tunnels.is_empty());
+
+        // Closing it empties the next sync, which closes it at the far gate
+        near.close("video").unwrap();
+        assert!(near.close("video").is_err());
+        let [(_, sync)] = near.syncs(&near.apply_to(&near_config), &[far_key]).try_into().unwrap();
+        assert!(far.synced(near_key, sync));
+        assert!(far.apply_to(&far_config).tunnels.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_socket_answers_requests() {
+        let path = std::env::temp_dir().join(format!("warp-control-test-{}.sock", std::process::id()));
+        let listener = listen(&path).unwrap();
+        let (requests_tx, mut requests) = mpsc::unbounded_channel();
+        tokio::spawn(serve(listener, Controller::new(requests_tx)));
+        tokio::spawn(async move {
+            while let Some((request, response)) = requests.recv().await {
+                let _ = response.send(Response {
+                    error: (request != Request::List {}).then(|| "unexpected".to_string()),
+                    tunnels: Vec::new(),
+                    health: None,
+                });
+            }
+        });
+
+        let ask = |request: &'static str| {
+            let path = path.clone();
+            async move {
+                use tokio::io::{AsyncReadExt, AsyncWriteExt};
+                let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+                stream.write_all(request.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                toml::from_str::<Response>(&response).unwrap()
+            }
+        };
+        assert_eq!(ask("[list]\n").await, Response::default());
+        assert!(ask("[nonsense]\n").await.error.unwrap().starts_with("invalid request"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+//! Running warp as a service: readiness and watchdog notifications for systemd, the pidfile, and dropping root
+//! privileges once the sockets that need them are bound; configured by `daemon`
+
+use std::ffi::{CStr, CString};
+
+/// Tell systemd about warp's state, e.g. `READY=1`, if it started warp with `Type=notify`
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send_notification(&path, state) {
+        tracing::warn!("Failed to notify systemd of {}: {}", state.replace('\n', " "), e);
+    }
+}
+
+fn send_notification(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    // A leading @ names a socket in the abstract namespace
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "abstract sockets are only supported on Linux",
+            ));
+        }
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// How often systemd expects `WATCHDOG=1` from warp, if it watches warp at all
+pub fn watchdog_interval() -> Option<std::time::Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID")
+        && pid.parse() != Ok(std::process::id())
+    {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(std::time::Duration::from_micros(usec))
+}
+
+/// Holds the pidfile and removes it when dropped
+pub struct Pidfile {
+    path: std::path::PathBuf,
+}
+
+impl Pidfile {
+    pub fn create(path: &std::path::Path) -> anyhow::Result<Self> {
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        // Once privileges are dropped this fails if the pidfile's directory belongs to root
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!("Failed to remove pidfile {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Switch the whole process to `user` and `group`, or to `user`'s own groups if `group` isn't given
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> anyhow::Result<()> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match (group, &user) {
+        (Some(group), _) => Some(lookup_group(group)?),
+        (None, Some((_, _, gid))) => Some(*gid),
+        (None, None) => None,
+    };
+
+    // Groups first, since changing them needs the privileges that changing the user gives up
+    if let Some(gid) = gid {
+        let ret = match &user {
+            Some((name, _, _)) => unsafe { libc::initgroups(name.as_ptr(), gid as _) },
+            None => unsafe { libc::setgroups(1, &gid) },
+        };
+        if ret != 0 {
+            anyhow::bail!(
+                "Failed to set supplementary groups: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            anyhow::bail!("Failed to set group {}: {}", gid, std::io::Error::last_os_error());
+        }
+    }
+    if let Some((name, uid, _)) = &user {
+        if unsafe { libc::setuid(*uid) } != 0 {
+            anyhow::bail!(
+                "Failed to set user {}: {}",
+                name.to_string_lossy(),
+                std::io::Error::last_os_error()
+            );
+        }
+        // Make sure there is no way back
+        if *uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            anyhow::bail!(
+                "Privileges could be regained after switching to {}",
+                name.to_string_lossy()
+            );
+        }
+    }
+    Ok(())
+}
+
+// The user's name, id and primary group
+fn lookup_user(name: &str) -> anyhow::Result<(CString, libc::uid_t, libc::gid_t)> {
+    let c_name = CString::new(name)?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let ret = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret != 0 {
+        anyhow::bail!(
+            "Failed to look up user {}: {}",
+            name,
+            std::io::Error::from_raw_os_error(ret)
+        );
+    }
+    if result.is_null() {
+        anyhow::bail!("No such user {}", name);
+    }
+    // Copied out before `buf`, which the name points into, goes away
+    let c_name = unsafe { CStr::from_ptr(passwd.pw_name) }.to_owned();
+    Ok((c_name, passwd.pw_uid, passwd.pw_gid))
+}
+
+fn lookup_group(name: &str) -> anyhow::Result<libc::gid_t> {
+    let c_name = CString::new(name)?;
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let ret = unsafe { libc::getgrnam_r(c_name.as_ptr(), &mut group, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret != 0 {
+        anyhow::bail!(
+            "Failed to look up group {}: {}",
+            name,
+            std::io::Error::from_raw_os_error(ret)
+        );
+    }
+    if result.is_null() {
+        anyhow::bail!("No such group {}", name);
+    }
+    Ok(group.gr_gid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notification_reaches_socket() {
+        let path = std::env::temp_dir().join(format!("warp-notify-test-{}.sock", std::process::id()));
+        let systemd = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        send_notification(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let len = systemd.recv(&mut buf).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[test]
+    fn test_looks_up_users_and_groups() {
+        let (name, uid, _) = lookup_user("root").unwrap();
+        assert_eq!((name.to_str().unwrap(), uid), ("root", 0));
+        assert!(lookup_user("no-such-warp-user").is_err());
+        assert!(lookup_group("no-such-warp-group").is_err());
+    }
+}
@@ -0,0 +1,463 @@
+//! Forward error correction for tunnel payloads, configured by each tunnel's `RedundancyConfig`
+//!
+//! With `num_shards = n` and `required_shards = k`, a payload's data is split into k equally sized (zero padded) data
+//! shards, followed by n - k parity shards, Reed-Solomon coded by `warp_gf256::rs` so that any k of the n shards are
+//! enough to reconstruct the data. Every shard is sent as its own tunnel payload tagged with
+//! `ReconstructionTag::Multipart`. `n = k = 1` disables FEC: payloads are sent `Plain`.
+//!
+//! Shards are also how payloads too big for the tunnel's MTU are fragmented. A payload that doesn't fit in k shards gets
+//! as many data shards as it needs, with parity shards added in the configured proportion.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use warp_gf256::rs::ReedSolomon;
+use warp_protocol::codec::Message;
+use warp_protocol::messages::{MultipartIdentifier, PayloadCompression, ReconstructionTag, TunnelId, TunnelPayload};
+
+// Shards of this many payloads can be waiting for the rest of their payload; beyond that the oldest are dropped
+const MAX_PENDING_PAYLOADS: usize = 256;
+// A payload whose shards are spread over longer than this is treated as lost, and its tracer as free for reuse (the
+// far gate's tracers start again from 0 when it restarts)
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(2);
+// The MTU covers the IP and UDP headers as well as the datagram; IPv6's are the larger
+pub const IP_UDP_HEADER_SIZE: usize = 40 + 8;
+// Part ids index rows of a GF(256) matrix
+const MAX_PARTS: usize = u8::MAX as usize;
+
+// How many more bytes than its data a tunnel payload shard of this tunnel takes on the wire, at most
+fn wire_overhead(tunnel_id: &TunnelId) -> anyhow::Result<usize> {
+    // Sizes don't depend on the key, so any will do
+    let key = warp_protocol::PrivateKey::random(&mut rand::rng());
+    let cipher = warp_protocol::crypto::cipher_from_shared_secret(&key, &key.public_key());
+    let data_size = u16::MAX as usize;
+    let largest_shard = TunnelPayload {
+        tunnel_id: tunnel_id.clone(),
+        tracer: u64::MAX,
+        reconstruction_tag: ReconstructionTag::Multipart(MultipartIdentifier {
+            parent_tracer: u64::MAX,
+            num_parts: u64::MAX,
+            required_parts: u64::MAX,
+            part_id: u64::MAX,
+            payload_size: u64::MAX,
+        }),
+        data: vec![0; data_size],
+        compression: PayloadCompression::Zstd,
+    };
+    Ok(largest_shard.encode()?.encrypt(&cipher)?.to_bytes()?.len() - data_size)
+}
+
+/// Whether `tunnel_payload` is a shard of a payload that can be reconstructed without some of its shards
+pub fn is_redundant_shard(tunnel_payload: &TunnelPayload) -> bool {
+    matches!(
+        &tunnel_payload.reconstruction_tag,
+        ReconstructionTag::Multipart(identifier) if identifier.num_parts > identifier.required_parts
+    )
+}
+
+/// Turns each outbound tunnel payload into the tunnel payloads that carry it over the wire
+pub struct Encoder {
+    num_shards: usize,
+    required_shards: usize,
+    max_shard_size: usize,
+    // Shards need tracers of their own, since each is encrypted separately and the tracer is part of the nonce
+    shard_tracer_generator: u64,
+}
+
+impl Encoder {
+    pub fn new(transport: &warp_config::WarpTransportConfig, tunnel_id: &TunnelId) -> anyhow::Result<Self> {
+        let config = &transport.redundancy;
+        if config.required_shards == 0 || config.required_shards > config.num_shards {
+            anyhow::bail!(
+                "required_shards must be between 1 and num_shards ({}), not {}",
+                config.num_shards,
+                config.required_shards
+            );
+        }
+
+        // Padding can add up to a bucket's worth, less a byte
+        let padding = transport.pad_to.map_or(0, |bucket| {
+            warp_protocol::codec::PADDING_OVERHEAD + bucket.get() as usize - 1
+        });
+        let overhead = IP_UDP_HEADER_SIZE + wire_overhead(tunnel_id)? + padding;
+        let max_shard_size = (transport.mtu as usize)
+            .checked_sub(overhead)
+            .filter(|&max_shard_size| max_shard_size > 0)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "an MTU of {} leaves no room after {overhead} bytes of headers",
+                    transport.mtu
+                )
+            })?;
+
+        Ok(Self {
+            num_shards: config.num_shards as usize,
+            required_shards: config.required_shards as usize,
+            max_shard_size,
+            shard_tracer_generator: 0,
+        })
+    }
+
+    /// Split payloads into `num_shards` shards from now on, keeping the number required to reconstruct them
+    pub fn set_num_shards(&mut self, num_shards: usize) {
+        self.num_shards = num_shards.clamp(self.required_shards, MAX_PARTS);
+    }
+
+    /// Either `tunnel_payload` itself, or its shards
+    pub fn encode(&mut self, tunnel_payload: TunnelPayload) -> anyhow::Result<Vec<TunnelPayload>> {
+        let payload_size = tunnel_payload.data.len();
+        let fragments = payload_size.div_ceil(self.max_shard_size);
+        let (num_shards, required_shards) = if fragments <= self.required_shards {
+            (self.num_shards, self.required_shards)
+        } else {
+            let parity_shards = ((self.num_shards - self.required_shards) * fragments).div_ceil(self.required_shards);
+            ((fragments + parity_shards).min(MAX_PARTS), fragments)
+        };
+        if required_shards > MAX_PARTS {
+            anyhow::bail!(
+                "{payload_size} byte payload needs more than {MAX_PARTS} fragments of {} bytes",
+                self.max_shard_size
+            );
+        }
+        if num_shards == 1 {
+            return Ok(vec![tunnel_payload]);
+        }
+
+        let shard_size = payload_size.div_ceil(required_shards);
+        let code = ReedSolomon::new(required_shards, num_shards - required_shards)?;
+        let mut parity = code.stream_encoder(shard_size);
+        parity.update(&tunnel_payload.data)?;
+
+        let mut shards: Vec<Vec<u8>> = tunnel_payload
+            .data
+            .chunks(shard_size.max(1))
+            .map(|chunk| {
+                let mut shard = chunk.to_vec();
+                shard.resize(shard_size, 0);
+                shard
+            })
+            .collect();
+        shards.resize(required_shards, vec![0; shard_size]);
+        shards.extend(parity.finish());
+
+        Ok(shards
+            .into_iter()
+            .enumerate()
+            .map(|(part_id, data)| {
+                let tracer = self.shard_tracer_generator;
+                self.shard_tracer_generator = self.shard_tracer_generator.wrapping_add(1);
+                TunnelPayload {
+                    tunnel_id: tunnel_payload.tunnel_id.clone(),
+                    tracer,
+                    reconstruction_tag: ReconstructionTag::Multipart(MultipartIdentifier {
+                        parent_tracer: tunnel_payload.tracer,
+                        num_parts: num_shards as u64,
+                        required_parts: required_shards as u64,
+                        part_id: part_id as u64,
+                        payload_size: payload_size as u64,
+                    }),
+                    data,
+                    compression: PayloadCompression::None,
+                }
+            })
+            .collect())
+    }
+}
+
+struct PendingPayload {
+    identifier: MultipartIdentifier,
+    first_seen: Instant,
+    shards: BTreeMap<usize, Vec<u8>>,
+}
+
+/// Reassembles tunnel payloads from their shards, passing plain tunnel payloads straight through
+#[derive(Default)]
+pub struct Decoder {
+    pending: BTreeMap<u64, PendingPayload>,
+    // Keyed by parent tracer, so shards that arrive after their payload was reconstructed are ignored
+    reconstructed: BTreeMap<u64, Instant>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The payload `tunnel_payload` completes, if any
+    pub fn decode(&mut self, tunnel_payload: TunnelPayload) -> anyhow::Result<Option<TunnelPayload>> {
+        let identifier = match &tunnel_payload.reconstruction_tag {
+            ReconstructionTag::Plain => return Ok(Some(tunnel_payload)),
+            ReconstructionTag::Xor(_, _) => anyhow::bail!("XOR reconstruction is not supported"),
+            ReconstructionTag::Multipart(identifier) => identifier.clone(),
+        };
+        Self::validate(&identifier, tunnel_payload.data.len())?;
+
+        let now = Instant::now();
+        let parent_tracer = identifier.parent_tracer;
+        if let Some(reconstructed_at) = self.reconstructed.get(&parent_tracer) {
+            if now.duration_since(*reconstructed_at) < REASSEMBLY_TIMEOUT {
+                return Ok(None);
+            }
+            self.reconstructed.remove(&parent_tracer);
+        }
+        if self
+            .pending
+            .get(&parent_tracer)
+            .is_some_and(|pending| now.duration_since(pending.first_seen) >= REASSEMBLY_TIMEOUT)
+        {
+            self.pending.remove(&parent_tracer);
+        }
+
+        let pending = self.pending.entry(parent_tracer).or_insert_with(|| PendingPayload {
+            identifier: identifier.clone(),
+            first_seen: now,
+            shards: BTreeMap::new(),
+        });
+        if pending.identifier.num_parts != identifier.num_parts
+            || pending.identifier.required_parts != identifier.required_parts
+            || pending.identifier.payload_size != identifier.payload_size
+        {
+            anyhow::bail!(
+                "shard {} disagrees with earlier shards of payload {}",
+                identifier.part_id,
+                parent_tracer
+            );
+        }
+        pending.shards.insert(identifier.part_id as usize, tunnel_payload.data);
+
+        if pending.shards.len() < identifier.required_parts as usize {
+            while self.pending.len() > MAX_PENDING_PAYLOADS {
+                self.pending.pop_first();
+            }
+            return Ok(None);
+        }
+
+        let pending = self
+            .pending
+            .remove(&parent_tracer)
+            .expect("pending payload was just updated");
+        self.reconstructed.insert(parent_tracer, now);
+        while self.reconstructed.len() > MAX_PENDING_PAYLOADS {
+            self.reconstructed.pop_first();
+        }
+
+        Ok(Some(TunnelPayload {
+            tunnel_id: tunnel_payload.tunnel_id,
+            tracer: parent_tracer,
+            reconstruction_tag: ReconstructionTag::Plain,
+            data: Self::reconstruct(pending)?,
+            compression: PayloadCompression::None,
+        }))
+    }
+
+    fn validate(identifier: &MultipartIdentifier, shard_size: usize) -> anyhow::Result<()> {
+        if identifier.required_parts == 0
+            || identifier.required_parts > identifier.num_parts
+            || identifier.num_parts > u8::MAX as u64
+            || identifier.part_id >= identifier.num_parts
+        {
+            anyhow::bail!("invalid shard {:?}", identifier);
+        }
+        if identifier.payload_size.div_ceil(identifier.required_parts) != shard_size as u64 {
+            anyhow::bail!("{} byte shard doesn't match {:?}", shard_size, identifier);
+        }
+        Ok(())
+    }
+
+    fn reconstruct(mut pending: PendingPayload) -> anyhow::Result<Vec<u8>> {
+        let num_parts = pending.identifier.num_parts as usize;
+        let required_parts = pending.identifier.required_parts as usize;
+        let payload_size = pending.identifier.payload_size as usize;
+        let shard_size = payload_size.div_ceil(required_parts);
+
+        let present: Vec<bool> = (0..num_parts)
+            .map(|part_id| pending.shards.contains_key(&part_id))
+            .collect();
+        let mut shards: Vec<Vec<u8>> = (0..num_parts)
+            .map(|part_id| pending.shards.remove(&part_id).unwrap_or_else(|| vec![0; shard_size]))
+            .collect();
+        ReedSolomon::new(required_parts, num_parts - required_parts)?.reconstruct_data(&mut shards, &present)?;
+
+        shards.truncate(required_parts);
+        let mut data = shards.concat();
+        data.truncate(payload_size);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp_protocol::messages::TunnelId;
+
+    fn transport(num_shards: u8, required_shards: u8, mtu: u16) -> warp_config::WarpTransportConfig {
+        warp_config::WarpTransportConfig {
+            redundancy: warp_config::RedundancyConfig {
+                num_shards,
+                required_shards,
+                adaptive: None,
+            },
+            mtu,
+            ordered: false,
+            send_deadline: Duration::from_secs(1),
+            reordering: None,
+            path_selection: None,
+            interfaces: None,
+            metered: None,
+            reliable: None,
+            dscp: None,
+            rate_limit: None,
+            compression: None,
+            pad_to: None,
+            packing: None,
+        }
+    }
+
+    fn encoder(num_shards: u8, required_shards: u8) -> Encoder {
+        Encoder::new(&transport(num_shards, required_shards, 1400), &TunnelId::Id(1)).unwrap()
+    }
+
+    fn payload(tracer: u64, data: &[u8]) -> TunnelPayload {
+        TunnelPayload::new(TunnelId::Id(1), tracer, data.to_vec())
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        for (num_shards, required_shards, mtu) in [(3, 0, 1400), (3, 4, 1400), (0, 0, 1400), (1, 1, 60)] {
+            assert!(Encoder::new(&transport(num_shards, required_shards, mtu), &TunnelId::Id(1)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_plain_passthrough() {
+        let shards = encoder(1, 1).encode(payload(7, b"data")).unwrap();
+        assert_eq!(shards, vec![payload(7, b"data")]);
+        assert_eq!(
+            Decoder::new().decode(shards[0].clone()).unwrap(),
+            Some(payload(7, b"data"))
+        );
+    }
+
+    #[test]
+    fn test_reconstruct_from_any_required_shards() {
+        let data: Vec<u8> = (0..=255).cycle().take(1001).collect();
+        let shards = encoder(5, 3).encode(payload(9, &data)).unwrap();
+        assert_eq!(shards.len(), 5);
+
+        // Every ordered choice of 3 of the 5 shards
+        for first in 0..5 {
+            for second in 0..5 {
+                for third in 0..5 {
+                    if first == second || second == third || first == third {
+                        continue;
+                    }
+                    let mut decoder = Decoder::new();
+                    assert_eq!(decoder.decode(shards[first].clone()).unwrap(), None);
+                    assert_eq!(decoder.decode(shards[second].clone()).unwrap(), None);
+                    assert_eq!(decoder.decode(shards[third].clone()).unwrap(), Some(payload(9, &data)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_late_shards_are_not_delivered_again() {
+        let shards = encoder(4, 1).encode(payload(3, b"hello")).unwrap();
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(shards[2].clone()).unwrap(), Some(payload(3, b"hello")));
+        for shard in &shards {
+            assert_eq!(decoder.decode(shard.clone()).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_empty_and_interleaved_payloads() {
+        let mut encoder = encoder(3, 2);
+        let empty = encoder.encode(payload(0, b"")).unwrap();
+        let full = encoder.encode(payload(1, b"abc")).unwrap();
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.decode(empty[2].clone()).unwrap(), None);
+        assert_eq!(decoder.decode(full[1].clone()).unwrap(), None);
+        assert_eq!(decoder.decode(empty[0].clone()).unwrap(), Some(payload(0, b"")));
+        assert_eq!(decoder.decode(full[2].clone()).unwrap(), Some(payload(1, b"abc")));
+    }
+
+    #[test]
+    fn test_fragments_to_fit_mtu() {
+        let data: Vec<u8> = (0..=255).cycle().take(4800).collect();
+        let cipher = warp_protocol::crypto::cipher_from_shared_secret(
+            &warp_protocol::PrivateKey::random(&mut rand::rng()),
+            &warp_protocol::PrivateKey::random(&mut rand::rng()).public_key(),
+        );
+
+        for (num_shards, required_shards, expected_shards) in [(1, 1, 4), (3, 2, 6), (5, 3, 7)] {
+            let mut encoder = Encoder::new(
+                &transport(num_shards, required_shards, 1400),
+                &TunnelId::Name("fragmented".into()),
+            )
+            .unwrap();
+            let shards = encoder
+                .encode(TunnelPayload::new(TunnelId::Name("fragmented".into()), 1, data.clone()))
+                .unwrap();
+            assert_eq!(shards.len(), expected_shards);
+            for shard in &shards {
+                let wire_size = shard
+                    .clone()
+                    .encode()
+                    .unwrap()
+                    .encrypt(&cipher)
+                    .unwrap()
+                    .to_bytes()
+                    .unwrap()
+                    .len();
+                assert!(wire_size + IP_UDP_HEADER_SIZE <= 1400);
+            }
+
+            // Starting from the end makes the decoder use parity shards when there are any
+            let mut decoder = Decoder::new();
+            let reconstructed = shards
+                .into_iter()
+                .rev()
+                .find_map(|shard| decoder.decode(shard).unwrap())
+                .unwrap();
+            assert_eq!(reconstructed.data, data);
+        }
+    }
+
+    #[test]
+    fn test_padded_shards_fit_mtu() {
+        let cipher = warp_protocol::crypto::cipher_from_shared_secret(
+            &warp_protocol::PrivateKey::random(&mut rand::rng()),
+            &warp_protocol::PrivateKey::random(&mut rand::rng()).public_key(),
+        );
+        let bucket = std::num::NonZeroU16::new(256).unwrap();
+        let transport = warp_config::WarpTransportConfig {
+            pad_to: Some(bucket),
+            ..transport(1, 1, 1400)
+        };
+
+        for size in [1, 1000, 1300, 3000] {
+            let shards = Encoder::new(&transport, &TunnelId::Id(1))
+                .unwrap()
+                .encode(payload(1, &vec![7; size]))
+                .unwrap();
+            for shard in shards {
+                let wire_size = shard
+                    .encode()
+                    .unwrap()
+                    .encrypt_padded(&cipher, bucket)
+                    .unwrap()
+                    .to_bytes()
+                    .unwrap()
+                    .len();
+                assert!(wire_size + IP_UDP_HEADER_SIZE <= 1400);
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_shard_rejected() {
+        let mut shards = encoder(3, 2).encode(payload(0, b"abcd")).unwrap();
+        shards[0].data.pop();
+        assert!(Decoder::new().decode(shards[0].clone()).is_err());
+    }
+}
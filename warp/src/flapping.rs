@@ -0,0 +1,135 @@
+//! Flap damping for interfaces, configured by `InterfacesConfig.flap_damping`
+//!
+//! Each time an interface dies or disappears its score goes up by one, and the score halves every `half_life`, so it
+//! says how often the interface has failed lately. A failed interface is held down, left unused even if it is detected
+//! again, for `hold_down` doubled for each point of score beyond the first, up to `max_hold_down`. An interface that
+//! fails once in a while comes back after `hold_down`; one that keeps flapping stays out for longer and longer.
+
+use crate::interface::NetworkInterfaceId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Interfaces whose score has decayed below this, and that aren't held down, are forgotten
+const FORGOTTEN_SCORE: f64 = 0.01;
+
+struct History {
+    score: f64,
+    scored_at: Instant,
+    held_until: Instant,
+}
+
+impl History {
+    fn score(&self, half_life: Duration, now: Instant) -> f64 {
+        let half_lives = now.saturating_duration_since(self.scored_at).as_secs_f64() / half_life.as_secs_f64();
+        self.score * 0.5f64.powf(half_lives)
+    }
+}
+
+#[derive(Default)]
+pub struct FlapDamping {
+    interfaces: HashMap<NetworkInterfaceId, History>,
+}
+
+impl FlapDamping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that `id` died or disappeared, returning its score and how long it is held down for
+    pub fn failed(
+        &mut self,
+        id: &NetworkInterfaceId,
+        config: &warp_config::FlapDampingConfig,
+        now: Instant,
+    ) -> (f64, Duration) {
+        let score = 1.0
+            + self
+                .interfaces
+                .get(id)
+                .map_or(0.0, |history| history.score(config.half_life, now));
+        let hold_down = config
+            .hold_down
+            .mul_f64(2f64.powf(score - 1.0))
+            .min(config.max_hold_down);
+        self.interfaces.insert(
+            id.clone(),
+            History {
+                score,
+                scored_at: now,
+                held_until: now + hold_down,
+            },
+        );
+        (score, hold_down)
+    }
+
+    /// Whether `id` failed too recently to be used again
+    pub fn is_held_down(&self, id: &NetworkInterfaceId, now: Instant) -> bool {
+        self.interfaces.get(id).is_some_and(|history| history.held_until > now)
+    }
+
+    /// Forget the interfaces that have gone long enough without failing
+    pub fn forget_stable(&mut self, config: &warp_config::FlapDampingConfig, now: Instant) {
+        self.interfaces
+            .retain(|_, history| history.held_until > now || history.score(config.half_life, now) >= FORGOTTEN_SCORE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> warp_config::FlapDampingConfig {
+        warp_config::FlapDampingConfig {
+            hold_down: Duration::from_secs(5),
+            max_hold_down: Duration::from_secs(60),
+            half_life: Duration::from_secs(60),
+        }
+    }
+
+    fn id(name: &str) -> NetworkInterfaceId {
+        NetworkInterfaceId {
+            name: name.to_string(),
+            ip: "192.0.2.1".parse().unwrap(),
+            prefix_len: 24,
+        }
+    }
+
+    #[test]
+    fn test_hold_down_grows_while_flapping() {
+        let (config, now) = (config(), Instant::now());
+        let mut damping = FlapDamping::new();
+        assert!(!damping.is_held_down(&id("wlan0"), now));
+
+        assert_eq!(
+            damping.failed(&id("wlan0"), &config, now),
+            (1.0, Duration::from_secs(5))
+        );
+        assert!(damping.is_held_down(&id("wlan0"), now + Duration::from_secs(4)));
+        assert!(!damping.is_held_down(&id("wlan0"), now + Duration::from_secs(5)));
+        // Other interfaces aren't affected
+        assert!(!damping.is_held_down(&id("eth0"), now));
+
+        // Failing again straight away doubles the hold-down each time, up to the limit
+        assert_eq!(damping.failed(&id("wlan0"), &config, now).1, Duration::from_secs(10));
+        assert_eq!(damping.failed(&id("wlan0"), &config, now).1, Duration::from_secs(20));
+        assert_eq!(damping.failed(&id("wlan0"), &config, now).1, Duration::from_secs(40));
+        assert_eq!(damping.failed(&id("wlan0"), &config, now).1, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_old_failures_count_for_less() {
+        let (config, now) = (config(), Instant::now());
+        let mut damping = FlapDamping::new();
+        damping.failed(&id("wlan0"), &config, now);
+        damping.failed(&id("wlan0"), &config, now);
+        // Two half-lives later the score of 2 has decayed to 0.5
+        let (score, hold_down) = damping.failed(&id("wlan0"), &config, now + Duration::from_secs(120));
+        assert!((score - 1.5).abs() < 1e-9);
+        assert!(hold_down > Duration::from_secs(7) && hold_down < Duration::from_secs(8));
+
+        damping.forget_stable(&config, now + Duration::from_secs(120));
+        assert_eq!(damping.interfaces.len(), 1);
+        damping.forget_stable(&config, now + Duration::from_secs(3600));
+        assert!(damping.interfaces.is_empty());
+    }
+}
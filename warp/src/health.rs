@@ -0,0 +1,186 @@
+//! Noticing warp's own tasks stalling, e.g. an rx worker stuck on one datagram
+//!
+//! Each long-running task registers for a [`Heartbeat`] and holds a [`Working`] guard from taking on a piece of work
+//! (a tick of its interval, a message from its queue) until it is done with it. Waiting for work is never a stall, so a
+//! quiet task is healthy however long it waits; one that has held its guard for longer than its limit is stalled. The
+//! health monitor logs tasks stalling and recovering, and the report is served on the metrics endpoint's `/health`, by
+//! the control socket's `health` request, and to systemd's watchdog, which is only notified while warp is healthy.
+//! The report also counts the times each task has been restarted after panicking.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The tasks warp has registered, shared by everything that reports on them
+#[derive(Clone)]
+pub struct Health {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    // What busy_since counts from
+    epoch: Instant,
+    tasks: Mutex<BTreeMap<String, Arc<TaskState>>>,
+    restarts: Mutex<BTreeMap<String, u64>>,
+}
+
+struct TaskState {
+    // Microseconds from the epoch to when the task took on its current work, plus one; 0 while it waits for work
+    busy_since: AtomicU64,
+    limit: Duration,
+}
+
+/// What a task reports its work through
+pub struct Heartbeat {
+    state: Arc<TaskState>,
+    epoch: Instant,
+}
+
+/// Marks a task as working until dropped
+pub struct Working<'a> {
+    state: &'a TaskState,
+}
+
+/// Whether every task is keeping up, and which aren't
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub stalled: Vec<StalledTask>,
+    /// How many times each task that panicked has been restarted
+    #[serde(default)]
+    pub restarts: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct StalledTask {
+    pub name: String,
+    /// How long it has been working on its current piece of work
+    pub busy_ms: u64,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                epoch: Instant::now(),
+                tasks: Mutex::new(BTreeMap::new()),
+                restarts: Mutex::new(BTreeMap::new()),
+            }),
+        }
+    }
+
+    /// Register the task called `name`, which is stalled once it works on one thing for longer than `limit`; a task
+    /// registered again under the same name replaces the earlier one
+    pub fn register(&self, name: impl Into<String>, limit: Duration) -> Heartbeat {
+        let state = Arc::new(TaskState {
+            busy_since: AtomicU64::new(0),
+            limit,
+        });
+        self.inner.tasks.lock().unwrap().insert(name.into(), state.clone());
+        Heartbeat {
+            state,
+            epoch: self.inner.epoch,
+        }
+    }
+
+    /// Count a restart of the task called `name`; returns how many it has had
+    pub(crate) fn restarted(&self, name: &str) -> u64 {
+        let mut restarts = self.inner.restarts.lock().unwrap();
+        let restarts = restarts.entry(name.to_string()).or_default();
+        *restarts += 1;
+        *restarts
+    }
+
+    pub fn report(&self) -> HealthReport {
+        self.report_at(Instant::now())
+    }
+
+    fn report_at(&self, now: Instant) -> HealthReport {
+        let now = now.saturating_duration_since(self.inner.epoch).as_micros() as u64;
+        let stalled: Vec<_> = self
+            .inner
+            .tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(name, state)| {
+                let busy_since = state.busy_since.load(Ordering::Relaxed);
+                if busy_since == 0 {
+                    return None;
+                }
+                let busy = Duration::from_micros(now.saturating_sub(busy_since - 1));
+                (busy > state.limit).then(|| StalledTask {
+                    name: name.clone(),
+                    busy_ms: busy.as_millis() as u64,
+                })
+            })
+            .collect();
+        HealthReport {
+            healthy: stalled.is_empty(),
+            stalled,
+            restarts: self.inner.restarts.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heartbeat {
+    /// The task has taken on a piece of work, until the returned guard is dropped
+    pub fn working(&self) -> Working<'_> {
+        let since = self.epoch.elapsed().as_micros() as u64 + 1;
+        self.state.busy_since.store(since, Ordering::Relaxed);
+        Working { state: &self.state }
+    }
+}
+
+impl Drop for Working<'_> {
+    fn drop(&mut self) {
+        self.state.busy_since.store(0, Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.healthy {
+            return f.write_str("healthy");
+        }
+        f.write_str("stalled:")?;
+        for task in &self.stalled {
+            write!(f, " {} ({} ms)", task.name, task.busy_ms)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_long_work_is_a_stall() {
+        let health = Health::new();
+        let scan = health.register("interface scan", Duration::from_secs(5));
+        let idle = health.register("path prober", Duration::from_secs(5));
+        let start = Instant::now();
+
+        // Waiting for work, however long, is healthy
+        assert!(health.report_at(start + Duration::from_secs(60)).healthy);
+
+        let working = scan.working();
+        let report = health.report_at(start + Duration::from_secs(60));
+        assert_eq!(report.stalled.len(), 1);
+        assert_eq!(report.stalled[0].name, "interface scan");
+        assert!(!report.healthy);
+        assert!(report.to_string().starts_with("stalled: interface scan ("));
+
+        drop(working);
+        drop(idle);
+        assert!(health.report_at(start + Duration::from_secs(60)).healthy);
+    }
+}
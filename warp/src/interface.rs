@@ -1,30 +1,143 @@
+use crate::trace::{SpanId, TraceContext};
 use std::fmt::Display;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use tokio::task::JoinHandle;
 
 const BUFFER_SIZE: usize = 65536;
+// How many datagrams are sent or received per system call when `interfaces.batch_size` is omitted
+const DEFAULT_BATCH_SIZE: usize = 32;
+// How long a datagram without a deadline may wait for its interface to send it
+// TODO: What should this default to? Configurable?
+const DEFAULT_SEND_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
+// Registrations sent over UDP without an answer before an interface registers over TCP, if warp-map serves it
+const UNANSWERED_REGISTRATIONS_BEFORE_TCP: usize = 3;
+// How long connecting to warp-map over TCP may take
+const MAP_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Each interface sends the datagrams of the highest priority tunnels first, and of those the one nearest its deadline.
+// Datagrams without a deadline (registrations, probes, heartbeats and the like) are small and few, so they go ahead of
+// all the rest.
+type SendOrder = (bool, std::cmp::Reverse<u8>, Option<std::time::Instant>);
+
+fn send_order(payload: &TxPayload) -> SendOrder {
+    (
+        payload.deadline.is_some(),
+        std::cmp::Reverse(payload.class.priority),
+        payload.deadline,
+    )
+}
+
+type SendPriority = fn(&TxPayload) -> SendOrder;
+type SenderQueue =
+    warp_mpscpq::Receiver<TxPayload, warp_mpscpq::MinPriority, warp_mpscpq::KeyFn<SendPriority, SendOrder>>;
 
 #[derive(Debug)]
 pub struct RxPayload {
     pub from: SocketAddr,
     pub receiver: SocketAddr,
     pub receiver_name: String,
-    pub data: Vec<u8>,
+    /// Logged with `INTERFACE_RX`, so whoever decrypts the data can link their events to it
+    pub span_id: SpanId,
+    /// When warp read the datagram from its socket
+    pub received_at: std::time::Instant,
+    /// When the kernel received the datagram, if the socket keeps track of that
+    pub kernel_received_at: Option<std::time::Instant>,
+    /// The receiving interface's traffic, for the tunnel payloads in `data` to be counted towards their tunnels
+    pub traffic: Arc<crate::traffic::InterfaceTraffic>,
+    pub data: bytes::Bytes,
+}
+
+impl RxPayload {
+    /// When the datagram arrived, as near as is known: when the kernel received it if the socket keeps track of that, and
+    /// when warp read it otherwise
+    pub fn arrived_at(&self) -> std::time::Instant {
+        self.kernel_received_at.unwrap_or(self.received_at)
+    }
 }
 
 #[derive(Debug)]
 pub struct TxPayload {
     pub to: SocketAddr,
     pub deadline: Option<std::time::Instant>,
-    // TODO: Change this to a warp-protocol::codec::Message so the interface can trace the nonce/tracer
-    pub data: Vec<u8>,
+    pub class: TrafficClass,
+    /// Set when `data` carries a tunnel payload, so the send can be correlated with the rest of its journey
+    pub trace: Option<TraceContext>,
+    /// Shared with every other copy of the same datagram queued on any interface
+    pub data: bytes::Bytes,
+}
+
+/// How a tunnel's datagrams are treated on their way out of an interface
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrafficClass {
+    /// Datagrams with a higher priority are sent first
+    pub priority: u8,
+    /// Marks the datagrams with a DSCP other than the interface's
+    pub dscp: Option<warp_config::Dscp>,
+    /// Pads the tunnel's sealed payloads to a multiple of this many bytes; the interface sends them as they come
+    pub pad_to: Option<std::num::NonZeroU16>,
+    /// Packs the tunnel's sealed payloads together into shared datagrams before they are queued on the interface
+    pub packing: Option<crate::packing::Packing>,
+}
+
+/// How the NAT in front of an interface maps its socket, going by the addresses warp-map's two ports see it at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NatMapping {
+    /// The same external address whoever the socket sends to, which is the address warp-map gives peers
+    EndpointIndependent,
+    /// Another external address for each destination (a symmetric NAT); peers only learn where they see the socket at
+    /// from its PeerAddressOverrides
+    EndpointDependent,
+}
+
+// A TCP connection to warp-map from an interface, which its registrations go over while it is open
+struct MapConnection {
+    writer: tokio::net::tcp::OwnedWriteHalf,
+    // Passes on what warp-map sends over the connection as though it had come to the interface's socket from warp-map
+    reader_task: JoinHandle<()>,
+}
+
+impl Drop for MapConnection {
+    fn drop(&mut self) {
+        self.reader_task.abort();
+    }
+}
+
+// How sending a datagram went, with how long the send took
+enum SendOutcome<'a> {
+    Sent(usize, std::time::Duration),
+    DeadlineMissed,
+    Failed(&'a std::io::Error, std::time::Duration),
+    TimedOut(std::time::Duration),
 }
 
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct NetworkInterfaceId {
     pub name: String,
     pub ip: IpAddr,
+    // The length of the prefix of `ip` that the addresses on the same network share
+    pub prefix_len: u8,
+}
+
+impl NetworkInterfaceId {
+    /// Whether `ip` is on the same network as the interface
+    pub fn is_on_network(&self, ip: IpAddr) -> bool {
+        match (self.ip, ip) {
+            (IpAddr::V4(own), IpAddr::V4(other)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len.min(32)))
+                    .unwrap_or(0);
+                u32::from(own) & mask == u32::from(other) & mask
+            }
+            (IpAddr::V6(own), IpAddr::V6(other)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len.min(128)))
+                    .unwrap_or(0);
+                u128::from(own) & mask == u128::from(other) & mask
+            }
+            _ => false,
+        }
+    }
 }
 
 impl Display for NetworkInterfaceId {
@@ -35,34 +148,88 @@ impl Display for NetworkInterfaceId {
 
 pub struct NetworkInterface {
     pub id: NetworkInterfaceId,
-    socket: tokio::net::UdpSocket,
+    socket: Arc<dyn crate::transport::DatagramSocket>,
     receiver_addr: SocketAddr,
     max_consecutive_failures: usize,
+    batch_size: usize,
+    rate_limit: Option<warp_config::RateLimitConfig>,
+    policy: warp_config::InterfacePolicyConfig,
 
     consecutive_failures: std::sync::atomic::AtomicUsize,
     registration_task: tokio::sync::OnceCell<JoinHandle<()>>,
     receiver_task: tokio::sync::OnceCell<JoinHandle<()>>,
 
-    sender_queue_tx: tokio::sync::mpsc::UnboundedSender<TxPayload>,
+    sender_queue_tx: warp_mpscpq::Sender<TxPayload>,
     sender_task: tokio::sync::OnceCell<JoinHandle<()>>,
+    // Cancelled to stop the interface; its sender task carries on until the send queue is empty
+    shutdown: tokio_util::sync::CancellationToken,
+    // Cancelled once the sender task has stopped
+    sender_stopped: tokio_util::sync::CancellationToken,
+
+    // How well sending to each peer address from this interface has been going
+    path_stats: std::sync::Mutex<std::collections::HashMap<SocketAddr, crate::routing::PathStats>>,
+    traffic: Arc<crate::traffic::InterfaceTraffic>,
 
     // External address as seen by warp-map (for PeerAddressOverride)
     // TODO: Is this the right way to do this? I just want a C++ like Atomic<Option<SocketAddr>>
     external_address_notifier: tokio::sync::watch::Sender<Option<SocketAddr>>,
     external_address_watch: tokio::sync::watch::Receiver<Option<SocketAddr>>,
+
+    // The cookie warp-map last asked for, sent back ahead of each registration
+    map_cookie: std::sync::Mutex<Option<warp_protocol::cookie::Cookie>>,
+    // Notified to register straight away rather than at the next scan
+    registration_wanted: tokio::sync::Notify,
+    // Where warp-map answers NAT probes, if its last RegisterResponse said, and what they have shown so far
+    map_probe_address: std::sync::Mutex<Option<SocketAddr>>,
+    nat_mapping: std::sync::Mutex<Option<NatMapping>>,
+    // Registrations sent over UDP since warp-map last answered one
+    unanswered_registrations: std::sync::atomic::AtomicUsize,
+    // Open once registrations over UDP have gone unanswered, if warp-map serves clients over TCP; once open, it is used
+    // until it closes
+    map_connection: tokio::sync::Mutex<Option<MapConnection>>,
 }
 
 impl NetworkInterface {
+    /// Bind a socket on the interface, to `port` if it is free, and start registering it with warp-map, following
+    /// changes to `config`; what it sends and receives is counted in `traffic`
     pub fn new(
         id: NetworkInterfaceId,
-        config: &warp_config::WarpConfig,
-        rx_channel: tokio::sync::mpsc::UnboundedSender<RxPayload>,
+        port: Option<u16>,
+        config: &tokio::sync::watch::Receiver<warp_config::WarpConfig>,
+        network: &dyn crate::transport::Network,
+        rx_channel: crate::queue::ShardedSender<RxPayload>,
+        traffic: Arc<crate::traffic::InterfaceTraffic>,
     ) -> anyhow::Result<Arc<Self>> {
-        let bind_to_device = config.interfaces.bind_to_device.unwrap_or(false);
-        let socket = Self::create_socket(&id, bind_to_device)?;
+        let config_watch = config;
+        let config = config_watch.borrow().clone();
+        let socket_options = crate::transport::SocketOptions {
+            port,
+            bind_to_device: config.interfaces.bind_to_device.unwrap_or(false),
+            fib: config
+                .interfaces
+                .fibs
+                .as_ref()
+                .and_then(|fibs| fibs.get(&id.name))
+                .copied(),
+            recv_buffer_size: config.interfaces.so_rcvbuf,
+            send_buffer_size: config.interfaces.so_sndbuf,
+            dscp: config.interfaces.dscp,
+        };
+        let socket = network.bind(&id, &socket_options)?;
         let receiver_addr = socket.local_addr()?;
 
-        let (outbound_sender, outbound_receiver) = tokio::sync::mpsc::unbounded_channel::<TxPayload>();
+        let queues = config.queues.clone().unwrap_or_default();
+        let (outbound_sender, outbound_receiver) =
+            warp_mpscpq::unbounded_priority_queue_with_key::<_, warp_mpscpq::MinPriority, _, _>(
+                send_order as SendPriority,
+            );
+        let outbound_receiver = outbound_receiver.with_capacity_limit(
+            queues.capacity.max(1),
+            match queues.overflow {
+                warp_config::QueueOverflow::DropOldest => warp_mpscpq::OverflowPolicy::DropOldest,
+                warp_config::QueueOverflow::DropNewest => warp_mpscpq::OverflowPolicy::RejectNew,
+            },
+        );
         let (external_address_notifier, external_address_watch) = tokio::sync::watch::channel(None);
 
         let interface = Arc::new(Self {
@@ -70,18 +237,44 @@ impl NetworkInterface {
             socket,
             receiver_addr,
             max_consecutive_failures: config.interfaces.max_consecutive_failures,
+            batch_size: config.interfaces.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1),
+            rate_limit: config
+                .interfaces
+                .rate_limits
+                .as_ref()
+                .and_then(|rate_limits| rate_limits.get(&id.name))
+                .cloned(),
+            policy: config
+                .interfaces
+                .policies
+                .as_ref()
+                .and_then(|policies| policies.get(&id.name))
+                .cloned()
+                .unwrap_or_default(),
             consecutive_failures: std::sync::atomic::AtomicUsize::new(0),
             registration_task: tokio::sync::OnceCell::new(),
             receiver_task: tokio::sync::OnceCell::new(),
             sender_queue_tx: outbound_sender,
             sender_task: tokio::sync::OnceCell::new(),
+            shutdown: tokio_util::sync::CancellationToken::new(),
+            sender_stopped: tokio_util::sync::CancellationToken::new(),
+            path_stats: std::sync::Mutex::new(std::collections::HashMap::new()),
+            traffic,
             external_address_notifier,
             external_address_watch,
+            map_cookie: std::sync::Mutex::new(None),
+            registration_wanted: tokio::sync::Notify::new(),
+            map_probe_address: std::sync::Mutex::new(None),
+            nat_mapping: std::sync::Mutex::new(None),
+            unanswered_registrations: std::sync::atomic::AtomicUsize::new(0),
+            map_connection: tokio::sync::Mutex::new(None),
         });
 
-        interface
-            .registration_task
-            .set(Self::spawn_registration_task(interface.clone(), config)?)?;
+        interface.registration_task.set(Self::spawn_registration_task(
+            interface.clone(),
+            config_watch.clone(),
+            rx_channel.clone(),
+        )?)?;
 
         interface
             .receiver_task
@@ -94,89 +287,65 @@ impl NetworkInterface {
         Ok(interface)
     }
 
-    fn create_socket(interface: &NetworkInterfaceId, bind_to_device: bool) -> anyhow::Result<tokio::net::UdpSocket> {
-        let std_socket = std::net::UdpSocket::bind(SocketAddr::new(interface.ip, 0))?;
-
-        let interface_name_cstr = std::ffi::CString::new(interface.name.clone())?;
-
-        // TODO: This is an ugly hack to work around routing shenanigans and may need root
-        if bind_to_device {
-            #[cfg(target_os = "linux")]
-            unsafe {
-                use std::os::fd::AsRawFd;
-                tracing::info!("Using SO_BINDTODEVICE for {}", interface);
-                let ret = libc::setsockopt(
-                    std_socket.as_raw_fd(),
-                    libc::SOL_SOCKET,
-                    libc::SO_BINDTODEVICE,
-                    interface_name_cstr.as_ptr() as *const libc::c_void,
-                    interface_name_cstr.as_bytes_with_nul().len() as libc::socklen_t,
-                );
-                if ret != 0 {
-                    return Err(std::io::Error::last_os_error().into());
-                }
-            }
-            #[cfg(target_os = "macos")]
-            unsafe {
-                tracing::info!("Using IP_BOUND_IF for {}", interface);
-                use std::os::fd::AsRawFd;
-
-                let interface_index = libc::if_nametoindex(interface_name_cstr.as_ptr());
-                if interface_index == 0 {
-                    return Err(std::io::Error::last_os_error().into());
-                }
-
-                let ret = libc::setsockopt(
-                    std_socket.as_raw_fd(),
-                    libc::IPPROTO_IP,
-                    libc::IP_BOUND_IF,
-                    &interface_index as *const u32 as *const libc::c_void,
-                    std::mem::size_of::<u32>() as libc::socklen_t,
-                );
-                if ret != 0 {
-                    return Err(std::io::Error::last_os_error().into());
-                }
-            }
-            #[cfg(not(any(target_os = "linux", target_os = "macos")))]
-            return Err("bind_to_device is not supported on {}", std::env::consts::OS);
-        }
-
-        std_socket.set_nonblocking(true)?;
-        Ok(tokio::net::UdpSocket::from_std(std_socket)?)
-    }
-
     // Having the interface manage its own registration task means the interface needs to know a lot about the things
     // like the warp-map, keys etc.
     // TODO: Move the registration task out into main.rs
     fn spawn_registration_task(
         interface: Arc<Self>,
-        config: &warp_config::WarpConfig,
+        config: tokio::sync::watch::Receiver<warp_config::WarpConfig>,
+        rx_channel: crate::queue::ShardedSender<RxPayload>,
     ) -> anyhow::Result<JoinHandle<()>> {
         let task = tokio::task::Builder::new()
             .name(&format!("interface {} registration task", interface.id))
-            .spawn({
-                let public_key = config.private_key.public_key();
-                let peer_pubkey = config.far_gate.public_key;
-                let warp_map_addr = config.warp_map.address;
-                let cipher =
-                    warp_protocol::crypto::cipher_from_shared_secret(&config.private_key, &config.warp_map.public_key);
-                let mut interval = tokio::time::interval(config.interfaces.interface_scan_interval);
+            .spawn(crate::warp_core::until_cancelled(interface.shutdown.clone(), {
+                // The key and warp-map can't be reloaded
+                let (public_key, warp_map_addr, warp_map_tcp_addr, cipher, scan_interval) = {
+                    let config = config.borrow();
+                    (
+                        config.private_key.public_key(),
+                        config.warp_map.address,
+                        config.warp_map.tcp_address,
+                        warp_protocol::crypto::cipher_from_shared_secret(
+                            &config.private_key,
+                            &config.warp_map.public_key,
+                        ),
+                        config.interfaces.interface_scan_interval,
+                    )
+                };
+                let mut interval = tokio::time::interval(scan_interval);
 
                 async move {
                     loop {
-                        interval.tick().await;
+                        let scan_interval = config.borrow().interfaces.interface_scan_interval;
+                        tokio::select! {
+                            _ = crate::warp_core::tick_every(&mut interval, scan_interval) => {}
+                            _ = interface.registration_wanted.notified() => {}
+                        }
+                        let peer_pubkeys = config.borrow().far_gates();
 
                         tracing::info!("Registering interface {} with warp-map", interface.id);
 
-                        if let Err(e) =
-                            Self::register_interface(&interface, &public_key, &peer_pubkey, warp_map_addr, &cipher)
-                                .await
-                        {
+                        let registered =
+                            match Self::registration_payload(&interface, &public_key, &peer_pubkeys, &cipher) {
+                                Ok((payload, timestamp)) => {
+                                    Self::register_interface(
+                                        &interface,
+                                        payload,
+                                        timestamp,
+                                        warp_map_addr,
+                                        warp_map_tcp_addr,
+                                        &rx_channel,
+                                    )
+                                    .await
+                                }
+                                Err(e) => Err(e),
+                            };
+                        if let Err(e) = registered {
                             tracing::error!("Registration failed for {}: {}", interface.id, e);
                         }
                     }
                 }
-            })
+            }))
             .expect("task initialised");
 
         Ok(task)
@@ -184,161 +353,313 @@ impl NetworkInterface {
 
     fn spawn_receiver_task(
         interface: Arc<Self>,
-        rx_channel: tokio::sync::mpsc::UnboundedSender<RxPayload>,
+        rx_channel: crate::queue::ShardedSender<RxPayload>,
     ) -> anyhow::Result<JoinHandle<()>> {
         let task = tokio::task::Builder::new()
             .name(&format!("interface {} receiver", interface.id))
-            .spawn({
+            .spawn(crate::warp_core::until_cancelled(interface.shutdown.clone(), {
                 let receiver_addr = interface.receiver_addr;
 
                 async move {
-                    let mut buf = vec![0u8; BUFFER_SIZE];
+                    let mut batch = crate::transport::RecvBatch::new(interface.batch_size, BUFFER_SIZE);
 
-                    loop {
-                        match interface.socket.recv_from(&mut buf).await {
-                            Ok((size, from)) => {
-                                tracing::event!(
-                                    tracing::Level::DEBUG,
-                                    interface = %interface.id,
-                                    from_addr = %from,
-                                    payload_size = size,
-                                    "INTERFACE_RX"
-                                );
-                                let payload = RxPayload {
-                                    from,
-                                    receiver: receiver_addr,
-                                    receiver_name: interface.id.name.clone(),
-                                    data: buf[..size].to_vec(),
-                                };
-                                rx_channel.send(payload).expect("Channel should be open");
-                            }
-                            Err(e) => {
-                                tracing::event!(
-                                    tracing::Level::WARN,
-                                    interface = %interface.id,
-                                    error = %e,
-                                    "INTERFACE_RX_FAILED"
-                                );
+                    'receive: loop {
+                        if let Err(e) = interface.socket.recv_batch(&mut batch).await {
+                            tracing::event!(
+                                tracing::Level::WARN,
+                                interface = %interface.id,
+                                error = %e,
+                                "INTERFACE_RX_FAILED"
+                            );
+                            continue;
+                        }
+                        let (received_at, system_received_at) =
+                            (std::time::Instant::now(), std::time::SystemTime::now());
+                        // The datagrams are copied out of the batch into one allocation they are then split off of
+                        let mut received =
+                            bytes::BytesMut::with_capacity(batch.datagrams().map(|(data, _, _)| data.len()).sum());
+                        for (data, from, timestamp) in batch.datagrams() {
+                            // Kernel timestamps are on the system clock, which only Instants taken at the same time
+                            // relate to
+                            let kernel_received_at = timestamp.and_then(|timestamp| {
+                                let waited = system_received_at.duration_since(timestamp).unwrap_or_default();
+                                crate::metrics::RX_SOCKET_WAIT_SECONDS.observe_duration(waited);
+                                received_at.checked_sub(waited)
+                            });
+                            received.extend_from_slice(data);
+                            let data = received.split().freeze();
+                            interface.traffic.record_received(data.len());
+                            let span_id = crate::trace::new_span_id();
+                            tracing::event!(
+                                tracing::Level::DEBUG,
+                                interface = %interface.id,
+                                from_addr = %from,
+                                payload_size = data.len(),
+                                span_id = span_id,
+                                "INTERFACE_RX"
+                            );
+                            let payload = RxPayload {
+                                from,
+                                receiver: receiver_addr,
+                                receiver_name: interface.id.name.clone(),
+                                span_id,
+                                received_at,
+                                kernel_received_at,
+                                traffic: interface.traffic.clone(),
+                                data,
+                            };
+                            // Datagrams from one address are processed by one rx worker, in the order they came
+                            match rx_channel.push(&from, payload) {
+                                Ok(None) => {}
+                                Ok(Some(dropped)) => {
+                                    crate::metrics::RX_QUEUE_DROPS.inc();
+                                    tracing::event!(
+                                        tracing::Level::WARN,
+                                        interface = %interface.id,
+                                        from_addr = %dropped.from,
+                                        span_id = dropped.span_id,
+                                        payload_size = dropped.data.len(),
+                                        "RX_QUEUE_FULL"
+                                    );
+                                }
+                                // The core has stopped processing received data
+                                Err(_) => break 'receive,
                             }
                         }
                     }
                 }
-            })?;
+            }))?;
 
         Ok(task)
     }
 
-    fn spawn_sender_task(
-        interface: Arc<Self>,
-        mut outbound_rx: tokio::sync::mpsc::UnboundedReceiver<TxPayload>,
-    ) -> anyhow::Result<JoinHandle<()>> {
+    fn spawn_sender_task(interface: Arc<Self>, mut outbound_rx: SenderQueue) -> anyhow::Result<JoinHandle<()>> {
         let task = tokio::task::Builder::new()
             .name(&format!("interface {} sender", interface.id))
             .spawn({
                 async move {
-                    while let Some(tx_payload) = outbound_rx.recv().await {
+                    let _stopped = interface.sender_stopped.clone().drop_guard();
+                    let mut dropped_so_far = 0;
+                    let mut batch = Vec::with_capacity(interface.batch_size);
+                    let mut sent = vec![0; interface.batch_size];
+                    let mut rate_limit = interface
+                        .rate_limit
+                        .as_ref()
+                        .map(|rate_limit| crate::pacing::TokenBucket::new(rate_limit, std::time::Instant::now()));
+                    loop {
+                        let received = tokio::select! {
+                            biased;
+                            received = outbound_rx.recv_many(&mut batch, interface.batch_size) => received,
+                            // Only once nothing is left to send
+                            () = interface.shutdown.cancelled() => 0,
+                        };
+                        if received == 0 {
+                            break;
+                        }
                         let queue_length = outbound_rx.len();
-                        if let Some(deadline) = tx_payload.deadline
-                            && deadline < std::time::Instant::now()
-                        {
+                        // The queue is held to its capacity as datagrams are taken from it
+                        let dropped = outbound_rx.evicted_count() - dropped_so_far;
+                        if dropped > 0 {
+                            dropped_so_far += dropped;
+                            crate::metrics::TX_SEND_QUEUE_DROPS.add(dropped);
                             tracing::event!(
                                 tracing::Level::WARN,
                                 interface = interface.id.name,
-                                destination = %tx_payload.to,
-                                payload_size = tx_payload.data.len(),
+                                dropped = dropped,
                                 queue_length = queue_length,
-                                "INTERFACE_SEND_DEADLINE_MISSED"
+                                "INTERFACE_SEND_QUEUE_FULL"
                             );
-                            continue;
-                        }
-                        let send_start_time = std::time::Instant::now();
-                        let send_result = if let Some(deadline) = tx_payload.deadline {
-                            tokio::time::timeout_at(
-                                deadline.into(),
-                                interface.socket.send_to(&tx_payload.data, tx_payload.to),
-                            )
-                        } else {
-                            // TODO: What should this default to? Configurable?
-                            tokio::time::timeout(
-                                std::time::Duration::from_millis(100),
-                                interface.socket.send_to(&tx_payload.data, tx_payload.to),
-                            )
                         }
-                        .await;
-                        let send_duration = send_start_time.elapsed();
-                        match send_result {
-                            Ok(Ok(sent_bytes)) if sent_bytes == tx_payload.data.len() => {
-                                interface
-                                    .consecutive_failures
-                                    .store(0, std::sync::atomic::Ordering::Release);
-                                tracing::event!(
-                                    tracing::Level::DEBUG,
-                                    interface = interface.id.name,
-                                    destination = %tx_payload.to,
-                                    send_duration_us = send_duration.as_micros(),
-                                    payload_size = tx_payload.data.len(),
-                                    queue_length = queue_length,
-                                    "INTERFACE_SEND"
-                                );
-                            }
-                            Ok(Ok(sent_bytes)) => {
-                                interface
-                                    .consecutive_failures
-                                    .fetch_add(1, std::sync::atomic::Ordering::Release);
-                                tracing::event!(
-                                    tracing::Level::WARN,
-                                    interface = interface.id.name,
-                                    destination = %tx_payload.to,
-                                    send_duration_us = send_duration.as_micros(),
-                                    payload_size = tx_payload.data.len(),
-                                    sent_bytes = sent_bytes,
-                                    queue_length = queue_length,
-                                    "INTERFACE_SEND_INCOMPLETE"
-                                );
+
+                        let now = std::time::Instant::now();
+                        batch.retain(|tx_payload| {
+                            let expired = tx_payload.deadline.is_some_and(|deadline| deadline < now);
+                            if expired {
+                                interface.report_send(tx_payload, SendOutcome::DeadlineMissed, queue_length);
                             }
-                            Ok(Err(e)) => {
-                                interface
-                                    .consecutive_failures
-                                    .fetch_add(1, std::sync::atomic::Ordering::Release);
-                                tracing::event!(
-                                    tracing::Level::WARN,
-                                    interface = interface.id.name,
-                                    destination = %tx_payload.to,
-                                    send_duration_us = send_duration.as_micros(),
-                                    payload_size = tx_payload.data.len(),
-                                    queue_length = queue_length,
-                                    error = %e,
-                                    "INTERFACE_SEND_FAILED"
-                                );
+                            !expired
+                        });
+
+                        let mut remaining = batch.as_slice();
+                        while !remaining.is_empty() {
+                            // Only as many datagrams as the rate limit allows go out together
+                            let mut allowed = remaining.len();
+                            if let Some(rate_limit) = &mut rate_limit {
+                                let now = std::time::Instant::now();
+                                let ready_at = rate_limit.ready_at(now);
+                                if ready_at > now {
+                                    crate::metrics::TX_PACING_WAITS.inc();
+                                    tokio::time::sleep_until(ready_at.into()).await;
+                                }
+                                allowed = remaining
+                                    .iter()
+                                    .take_while(|tx_payload| rate_limit.try_take(tx_payload.data.len(), ready_at))
+                                    .count();
                             }
-                            Err(_timeout_err) => {
-                                interface
-                                    .consecutive_failures
-                                    .fetch_add(1, std::sync::atomic::Ordering::Release);
-                                tracing::event!(
-                                    tracing::Level::WARN,
-                                    interface = interface.id.name,
-                                    destination = %tx_payload.to,
-                                    send_duration_us = send_duration.as_micros(),
-                                    payload_size = tx_payload.data.len(),
-                                    queue_length = queue_length,
-                                    "INTERFACE_SEND_TIMEOUT"
-                                );
+                            let datagrams: Vec<_> = remaining[..allowed]
+                                .iter()
+                                .map(|tx_payload| crate::transport::OutgoingDatagram {
+                                    data: &tx_payload.data,
+                                    to: tx_payload.to,
+                                    dscp: tx_payload.class.dscp,
+                                })
+                                .collect();
+                            let send_start_time = std::time::Instant::now();
+                            // The datagrams go out together, so they can only wait as long as the most urgent can
+                            let deadline = remaining[..allowed]
+                                .iter()
+                                .map(|tx_payload| tx_payload.deadline.unwrap_or(send_start_time + DEFAULT_SEND_TIMEOUT))
+                                .min()
+                                .expect("remaining is not empty");
+                            let send_result = tokio::time::timeout_at(
+                                deadline.into(),
+                                interface.socket.send_batch(&datagrams, &mut sent),
+                            )
+                            .await;
+                            let send_duration = send_start_time.elapsed();
+                            match send_result {
+                                Ok(Ok(count)) => {
+                                    for (tx_payload, sent_bytes) in remaining[..count].iter().zip(&sent) {
+                                        interface.report_send(
+                                            tx_payload,
+                                            SendOutcome::Sent(*sent_bytes, send_duration),
+                                            queue_length,
+                                        );
+                                    }
+                                    remaining = &remaining[count..];
+                                }
+                                Ok(Err(e)) => {
+                                    interface.report_send(
+                                        &remaining[0],
+                                        SendOutcome::Failed(&e, send_duration),
+                                        queue_length,
+                                    );
+                                    remaining = &remaining[1..];
+                                }
+                                Err(_timeout_err) => {
+                                    for tx_payload in remaining {
+                                        interface.report_send(
+                                            tx_payload,
+                                            SendOutcome::TimedOut(send_duration),
+                                            queue_length,
+                                        );
+                                    }
+                                    break;
+                                }
                             }
                         }
+                        batch.clear();
                     }
                 }
             })?;
 
         Ok(task)
     }
-    async fn register_interface(
+
+    // Log how sending `tx_payload` went, and count it towards the interface's failures and its path's deliveries
+    fn report_send(&self, tx_payload: &TxPayload, outcome: SendOutcome, queue_length: usize) {
+        let correlation_id = tx_payload
+            .trace
+            .as_ref()
+            .map(|trace| tracing::field::display(&trace.correlation));
+        let span_id = tx_payload.trace.as_ref().map(|trace| trace.span_id);
+        let parent_span_id = tx_payload.trace.as_ref().and_then(|trace| trace.parent_span_id);
+        if let SendOutcome::Sent(sent_bytes, _) = outcome {
+            let tunnel = tx_payload.trace.as_ref().map(|trace| trace.correlation.tunnel());
+            self.traffic.record_sent(tunnel, sent_bytes);
+        }
+        match outcome {
+            SendOutcome::Sent(sent_bytes, send_duration) if sent_bytes == tx_payload.data.len() => {
+                if let Some(trace) = &tx_payload.trace {
+                    trace.export("interface_send");
+                }
+                self.consecutive_failures.store(0, std::sync::atomic::Ordering::Release);
+                tracing::event!(
+                    tracing::Level::DEBUG,
+                    interface = self.id.name,
+                    destination = %tx_payload.to,
+                    correlation_id = correlation_id,
+                    span_id = span_id,
+                    parent_span_id = parent_span_id,
+                    send_duration_us = send_duration.as_micros(),
+                    payload_size = tx_payload.data.len(),
+                    queue_length = queue_length,
+                    "INTERFACE_SEND"
+                );
+                return;
+            }
+            SendOutcome::DeadlineMissed => {
+                tracing::event!(
+                    tracing::Level::WARN,
+                    interface = self.id.name,
+                    destination = %tx_payload.to,
+                    correlation_id = correlation_id,
+                    span_id = span_id,
+                    parent_span_id = parent_span_id,
+                    payload_size = tx_payload.data.len(),
+                    queue_length = queue_length,
+                    "INTERFACE_SEND_DEADLINE_MISSED"
+                );
+                self.record_path_delivery(tx_payload.to, false);
+                return;
+            }
+            SendOutcome::Sent(sent_bytes, send_duration) => {
+                tracing::event!(
+                    tracing::Level::WARN,
+                    interface = self.id.name,
+                    destination = %tx_payload.to,
+                    correlation_id = correlation_id,
+                    span_id = span_id,
+                    parent_span_id = parent_span_id,
+                    send_duration_us = send_duration.as_micros(),
+                    payload_size = tx_payload.data.len(),
+                    sent_bytes = sent_bytes,
+                    queue_length = queue_length,
+                    "INTERFACE_SEND_INCOMPLETE"
+                );
+            }
+            SendOutcome::Failed(e, send_duration) => {
+                tracing::event!(
+                    tracing::Level::WARN,
+                    interface = self.id.name,
+                    destination = %tx_payload.to,
+                    correlation_id = correlation_id,
+                    span_id = span_id,
+                    parent_span_id = parent_span_id,
+                    send_duration_us = send_duration.as_micros(),
+                    payload_size = tx_payload.data.len(),
+                    queue_length = queue_length,
+                    error = %e,
+                    "INTERFACE_SEND_FAILED"
+                );
+            }
+            SendOutcome::TimedOut(send_duration) => {
+                tracing::event!(
+                    tracing::Level::WARN,
+                    interface = self.id.name,
+                    destination = %tx_payload.to,
+                    correlation_id = correlation_id,
+                    span_id = span_id,
+                    parent_span_id = parent_span_id,
+                    send_duration_us = send_duration.as_micros(),
+                    payload_size = tx_payload.data.len(),
+                    queue_length = queue_length,
+                    "INTERFACE_SEND_TIMEOUT"
+                );
+            }
+        }
+        self.consecutive_failures
+            .fetch_add(1, std::sync::atomic::Ordering::Release);
+        self.record_path_delivery(tx_payload.to, false);
+    }
+
+    // The cookie, registration and mapping requests sent to warp-map each scan, with the time they were made at
+    fn registration_payload(
         interface: &NetworkInterface,
         public_key: &warp_protocol::PublicKey,
-        peer_pubkey: &warp_protocol::PublicKey,
-        warp_map_addr: SocketAddr,
+        peer_pubkeys: &[warp_protocol::PublicKey],
         cipher: &warp_protocol::Cipher,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<(Vec<u8>, std::time::SystemTime)> {
         use warp_protocol::codec::Message;
         let timestamp = std::time::SystemTime::now();
 
@@ -346,49 +667,324 @@ impl NetworkInterface {
         let registration = warp_protocol::messages::RegisterRequest {
             pubkey: *public_key,
             timestamp,
+            host_candidates: interface.host_candidates(),
         };
-        let mut payload = registration.encode()?.encrypt(cipher)?.to_bytes()?;
-
-        // Query peer address
-        let query = warp_protocol::messages::MappingRequest {
-            peer_pubkey: *peer_pubkey,
-            timestamp,
+        let mut payload = match *interface.map_cookie.lock().unwrap() {
+            Some(cookie) => cookie.to_wire_message().to_bytes()?,
+            None => Vec::new(),
         };
+        payload.append(&mut registration.encode()?.encrypt(cipher)?.to_bytes()?);
 
-        payload.append(&mut query.encode()?.encrypt(cipher)?.to_bytes()?);
+        // Query each peer's addresses
+        for peer_pubkey in peer_pubkeys {
+            let query = warp_protocol::messages::MappingRequest {
+                peer_pubkey: *peer_pubkey,
+                timestamp,
+                // Hear of the peer's new addresses as soon as it registers them, rather than at the next scan
+                subscribe: true,
+            };
 
-        interface.queue_send(payload, &warp_map_addr, None)?;
+            payload.append(&mut query.encode()?.encrypt(cipher)?.to_bytes()?);
+        }
+
+        Ok((payload, timestamp))
+    }
+
+    // Send `payload` to warp-map over UDP, or over TCP to `warp_map_tcp_addr` once registrations over UDP have gone
+    // unanswered for long enough
+    async fn register_interface(
+        interface: &NetworkInterface,
+        payload: Vec<u8>,
+        timestamp: std::time::SystemTime,
+        warp_map_addr: SocketAddr,
+        warp_map_tcp_addr: Option<SocketAddr>,
+        rx_channel: &crate::queue::ShardedSender<RxPayload>,
+    ) -> anyhow::Result<()> {
+        if let Some(tcp_addr) = warp_map_tcp_addr {
+            let mut connection = interface.map_connection.lock().await;
+            // Warp-map has closed it, or it has gone idle
+            if connection
+                .as_ref()
+                .is_some_and(|connection| connection.reader_task.is_finished())
+            {
+                *connection = None;
+            }
+            if connection.is_none()
+                && interface
+                    .unanswered_registrations
+                    .load(std::sync::atomic::Ordering::Relaxed)
+                    >= UNANSWERED_REGISTRATIONS_BEFORE_TCP
+            {
+                match interface.connect_to_map(tcp_addr, warp_map_addr, rx_channel).await {
+                    Ok(connected) => {
+                        tracing::warn!(
+                            "Registrations from {} went unanswered over UDP; registering over TCP to {}",
+                            interface.id,
+                            tcp_addr
+                        );
+                        *connection = Some(connected);
+                    }
+                    Err(e) => tracing::error!(
+                        "Failed to connect to warp-map at {} from {}: {}",
+                        tcp_addr,
+                        interface.id,
+                        e
+                    ),
+                }
+            }
+            if let Some(open) = connection.as_mut() {
+                use tokio::io::AsyncWriteExt;
+                match open.writer.write_all(&warp_protocol::codec::frame(&payload)?).await {
+                    Ok(()) => {
+                        tracing::event!(
+                            tracing::Level::DEBUG,
+                            interface = %interface.id,
+                            warp_map_addr = %tcp_addr,
+                            correlation_id = warp_protocol::messages::registration_correlation_id(timestamp),
+                            "REGISTRATION_SENT"
+                        );
+                        return Ok(());
+                    }
+                    // Back to UDP, until that goes unanswered again
+                    Err(e) => {
+                        tracing::warn!("Connection to warp-map from {} failed: {}", interface.id, e);
+                        *connection = None;
+                    }
+                }
+            }
+        }
+
+        interface
+            .unanswered_registrations
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        interface.queue_send(payload, &warp_map_addr, None, TrafficClass::default(), None)?;
+        tracing::event!(
+            tracing::Level::DEBUG,
+            interface = %interface.id,
+            warp_map_addr = %warp_map_addr,
+            correlation_id = warp_protocol::messages::registration_correlation_id(timestamp),
+            "REGISTRATION_SENT"
+        );
 
         Ok(())
     }
 
+    // Connect to warp-map at `tcp_addr` from the interface's IP address, passing what comes back over the connection to
+    // `rx_channel` as though warp-map had sent it to the interface's socket from `warp_map_addr`
+    async fn connect_to_map(
+        &self,
+        tcp_addr: SocketAddr,
+        warp_map_addr: SocketAddr,
+        rx_channel: &crate::queue::ShardedSender<RxPayload>,
+    ) -> anyhow::Result<MapConnection> {
+        let socket = match self.id.ip {
+            IpAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+            IpAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+        };
+        socket.bind(SocketAddr::new(self.id.ip, 0))?;
+        let stream = tokio::time::timeout(MAP_CONNECT_TIMEOUT, socket.connect(tcp_addr)).await??;
+        stream.set_nodelay(true)?;
+        let receiver = stream.local_addr()?;
+        let (mut reader, writer) = stream.into_split();
+
+        let receiver_name = self.id.name.clone();
+        let traffic = self.traffic.clone();
+        let rx_channel = rx_channel.clone();
+        let reader_task = tokio::task::Builder::new()
+            .name(&format!("interface {} warp-map connection", self.id))
+            .spawn(crate::warp_core::until_cancelled(self.shutdown.clone(), async move {
+                use tokio::io::AsyncReadExt;
+                let mut buf = vec![0; BUFFER_SIZE];
+                loop {
+                    let mut header = [0; warp_protocol::codec::FRAME_HEADER_SIZE];
+                    if let Err(e) = reader.read_exact(&mut header).await {
+                        tracing::info!("Connection to warp-map from {} closed: {}", receiver_name, e);
+                        break;
+                    }
+                    let length = warp_protocol::codec::frame_length(header);
+                    if let Err(e) = reader.read_exact(&mut buf[..length]).await {
+                        tracing::info!("Connection to warp-map from {} closed: {}", receiver_name, e);
+                        break;
+                    }
+                    traffic.record_received(length);
+                    let payload = RxPayload {
+                        from: warp_map_addr,
+                        receiver,
+                        receiver_name: receiver_name.clone(),
+                        span_id: crate::trace::new_span_id(),
+                        received_at: std::time::Instant::now(),
+                        kernel_received_at: None,
+                        traffic: traffic.clone(),
+                        data: bytes::Bytes::copy_from_slice(&buf[..length]),
+                    };
+                    match rx_channel.push(&warp_map_addr, payload) {
+                        Ok(None) => {}
+                        Ok(Some(_)) => crate::metrics::RX_QUEUE_DROPS.inc(),
+                        Err(_) => break,
+                    }
+                }
+            }))?;
+
+        Ok(MapConnection { writer, reader_task })
+    }
+
     pub fn queue_send(
         &self,
-        data: Vec<u8>,
+        data: impl Into<bytes::Bytes>,
         address: &SocketAddr,
         deadline: Option<std::time::Instant>,
+        class: TrafficClass,
+        trace: Option<TraceContext>,
     ) -> anyhow::Result<()> {
-        self.sender_queue_tx.send(TxPayload {
-            data,
+        self.queue(TxPayload {
+            data: data.into(),
             deadline,
+            class,
+            trace,
             to: *address,
-        })?;
+        })
+    }
+
+    /// Queue a datagram that is already assembled, like [`Self::queue_send`]
+    pub fn queue(&self, tx_payload: TxPayload) -> anyhow::Result<()> {
+        if self.sender_queue_tx.is_closed() {
+            anyhow::bail!("the sender task of {} has stopped", self.id);
+        }
+        self.sender_queue_tx.send(tx_payload);
         Ok(())
     }
 
+    pub fn path_stats(&self, address: SocketAddr) -> crate::routing::PathStats {
+        self.path_stats
+            .lock()
+            .unwrap()
+            .get(&address)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn record_path_rtt(&self, address: SocketAddr, rtt: std::time::Duration) {
+        self.path_stats
+            .lock()
+            .unwrap()
+            .entry(address)
+            .or_default()
+            .record_rtt(rtt);
+    }
+
+    pub fn record_path_delivery(&self, address: SocketAddr, delivered: bool) {
+        self.path_stats
+            .lock()
+            .unwrap()
+            .entry(address)
+            .or_default()
+            .record_delivery(delivered);
+    }
+
+    /// Forget the stats of paths to addresses `keep` rejects
+    pub fn retain_paths(&self, mut keep: impl FnMut(&SocketAddr) -> bool) {
+        self.path_stats.lock().unwrap().retain(|address, _| keep(address));
+    }
+
+    /// How many datagrams are waiting to be sent
+    pub fn queue_depth(&self) -> usize {
+        self.sender_queue_tx.queued_hint()
+    }
+
+    /// Whether using the interface is paid for by the byte, so tunnels keep off it while they can
+    pub fn is_metered(&self) -> bool {
+        self.policy.metered.unwrap_or(false)
+    }
+
+    /// What using the interface costs relative to the others; cheaper interfaces are picked first
+    pub fn link_cost(&self) -> u32 {
+        self.policy.cost.unwrap_or(0)
+    }
+
     pub fn is_alive(&self) -> bool {
         self.consecutive_failures.load(std::sync::atomic::Ordering::Relaxed) < self.max_consecutive_failures
     }
 
+    /// The address the interface's socket is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.receiver_addr
+    }
+
+    /// The addresses peers on the same network could reach this interface's socket at, bypassing NATs: the one it is
+    /// bound to, which is also where everything it sends comes from
+    pub fn host_candidates(&self) -> Vec<SocketAddr> {
+        if self.receiver_addr.ip().is_unspecified() {
+            return Vec::new();
+        }
+        vec![self.receiver_addr]
+    }
+
     pub fn get_external_address(&self) -> Option<SocketAddr> {
         *self.external_address_watch.borrow()
     }
 
     pub fn set_external_address(&self, address: SocketAddr) {
+        self.unanswered_registrations
+            .store(0, std::sync::atomic::Ordering::Relaxed);
         self.external_address_notifier.send_replace(Some(address));
     }
 
-    fn stop(&mut self) {
+    /// Where warp-map answers NAT probes from this interface, if it does
+    pub fn map_probe_address(&self) -> Option<SocketAddr> {
+        *self.map_probe_address.lock().unwrap()
+    }
+
+    pub fn set_map_probe_address(&self, address: Option<SocketAddr>) {
+        *self.map_probe_address.lock().unwrap() = address;
+    }
+
+    /// How the interface's NAT maps it, once warp-map has answered a NAT probe
+    pub fn nat_mapping(&self) -> Option<NatMapping> {
+        *self.nat_mapping.lock().unwrap()
+    }
+
+    /// Compare `probed`, where warp-map's probe address saw the interface, with where its registration was seen; returns
+    /// the mapping if that is news
+    pub fn record_nat_probe(&self, probed: SocketAddr) -> Option<NatMapping> {
+        let registered = self.get_external_address()?;
+        let mapping = if probed == registered {
+            NatMapping::EndpointIndependent
+        } else {
+            NatMapping::EndpointDependent
+        };
+        let previous = self.nat_mapping.lock().unwrap().replace(mapping);
+        (previous != Some(mapping)).then_some(mapping)
+    }
+
+    /// Send `cookie` back to warp-map ahead of registrations from now on, and register again straight away with it
+    pub fn set_map_cookie(&self, cookie: warp_protocol::cookie::Cookie) {
+        *self.map_cookie.lock().unwrap() = Some(cookie);
+        self.register_now();
+    }
+
+    /// Register with warp-map straight away rather than at the next scan
+    pub fn register_now(&self) {
+        self.registration_wanted.notify_one();
+    }
+
+    /// Stop the interface once its send queue is empty, or after `timeout` with whatever is left in it dropped
+    pub async fn shutdown(&self, timeout: std::time::Duration) {
+        self.shutdown.cancel();
+        if tokio::time::timeout(timeout, self.sender_stopped.cancelled())
+            .await
+            .is_err()
+        {
+            tracing::event!(
+                tracing::Level::WARN,
+                interface = %self.id,
+                queue_length = self.queue_depth(),
+                "INTERFACE_DRAIN_TIMED_OUT"
+            );
+        }
+        self.stop();
+    }
+
+    fn stop(&self) {
         if let Some(task) = self.registration_task.get() {
             task.abort();
         }
@@ -406,3 +1002,67 @@ impl Drop for NetworkInterface {
         self.stop();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(priority: u8, deadline: Option<std::time::Instant>) -> TxPayload {
+        TxPayload {
+            to: "127.0.0.1:1".parse().unwrap(),
+            deadline,
+            class: TrafficClass {
+                priority,
+                ..TrafficClass::default()
+            },
+            trace: None,
+            data: vec![priority].into(),
+        }
+    }
+
+    #[test]
+    fn test_send_order() {
+        let now = std::time::Instant::now();
+        let later = now + std::time::Duration::from_millis(10);
+        let mut payloads = [
+            payload(0, Some(now)),
+            payload(5, Some(later)),
+            payload(0, None),
+            payload(5, Some(now)),
+            payload(1, Some(now)),
+        ];
+        payloads.sort_by_key(send_order);
+        let order: Vec<_> = payloads
+            .iter()
+            .map(|payload| (payload.class.priority, payload.deadline))
+            .collect();
+        // Control datagrams first, then by priority, then by deadline
+        assert_eq!(
+            order,
+            [
+                (0, None),
+                (5, Some(now)),
+                (5, Some(later)),
+                (1, Some(now)),
+                (0, Some(now))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_on_network() {
+        let id = |ip: &str, prefix_len| NetworkInterfaceId {
+            name: "eth0".to_string(),
+            ip: ip.parse().unwrap(),
+            prefix_len,
+        };
+        let lan = id("192.168.1.10", 24);
+        assert!(lan.is_on_network("192.168.1.2".parse().unwrap()));
+        assert!(!lan.is_on_network("192.168.2.2".parse().unwrap()));
+        assert!(!lan.is_on_network("::1".parse().unwrap()));
+        // Point-to-point links have only their own address, and a prefix of 0 covers everything
+        assert!(!id("10.0.0.1", 32).is_on_network("10.0.0.2".parse().unwrap()));
+        assert!(id("10.0.0.1", 0).is_on_network("192.168.1.2".parse().unwrap()));
+        assert!(id("fd00::1", 64).is_on_network("fd00::2".parse().unwrap()));
+    }
+}
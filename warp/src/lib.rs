@@ -0,0 +1,29 @@
+mod compression;
+pub mod control;
+mod fec;
+mod flapping;
+pub mod health;
+pub mod interface;
+mod metrics;
+mod pacing;
+mod packing;
+mod queue;
+mod redundancy;
+mod relay;
+mod reliable;
+mod reorder;
+mod routing;
+pub mod self_test;
+mod session;
+mod state;
+mod supervisor;
+mod tcp_gate;
+pub mod telemetry;
+pub mod trace;
+pub mod traffic;
+pub mod transport;
+mod tunnel;
+mod warp_core;
+
+pub use tunnel::ChannelGate;
+pub use warp_core::WarpCore;
@@ -0,0 +1,198 @@
+//! Metrics exported by the daemon; see `warp_metrics` for the naming conventions
+
+use std::sync::LazyLock;
+use warp_metrics::{Counter, Gauge, Histogram};
+
+pub static ACTIVE_INTERFACES: LazyLock<Gauge> =
+    LazyLock::new(|| warp_metrics::global().gauge("warp_active_interfaces", "Interfaces in use after the latest scan"));
+
+pub static SYMMETRIC_NAT_INTERFACES: LazyLock<Gauge> = LazyLock::new(|| {
+    warp_metrics::global().gauge(
+        "warp_symmetric_nat_interfaces",
+        "Interfaces whose NATs map them to another address for each destination, going by warp-map's NAT probes",
+    )
+});
+
+pub static DEAD_PEERS: LazyLock<Gauge> = LazyLock::new(|| {
+    warp_metrics::global().gauge(
+        "warp_dead_peers",
+        "Far gates that haven't been heard from, directly or through warp-map, within the liveness timeout",
+    )
+});
+
+pub static RELAYED_PEERS: LazyLock<Gauge> = LazyLock::new(|| {
+    warp_metrics::global().gauge(
+        "warp_relayed_peers",
+        "Far gates sent to through warp-map, as none of their addresses have been heard from within the relay timeout",
+    )
+});
+
+pub static RX_PAYLOADS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter("warp_rx_payloads_total", "Datagrams received across all interfaces")
+});
+
+pub static RX_MESSAGES: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_rx_messages_total",
+        "Wire messages received (a datagram may hold several)",
+    )
+});
+
+pub static RX_INVALID_MESSAGES: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_rx_invalid_messages_total",
+        "Wire messages from peers that failed to decrypt",
+    )
+});
+
+pub static RX_TRIAL_DECRYPTIONS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_rx_trial_decryptions_total",
+        "Wire messages from peers tried against every far gate's session, not being from where one was expected",
+    )
+});
+
+pub static RX_RELAYED_MESSAGES: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_rx_relayed_messages_total",
+        "Wire messages from peers that warp-map relayed to us",
+    )
+});
+
+pub static RX_DUPLICATE_MESSAGES: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_rx_duplicate_messages_total",
+        "Wire messages from peers dropped as already received or too old: redundant copies and replays",
+    )
+});
+
+pub static RX_REJECTED_OVERRIDES: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_rx_rejected_overrides_total",
+        "PeerAddressOverrides from far gates that were ignored: for addresses not theirs, out of order or too frequent",
+    )
+});
+
+pub static RX_MALFORMED_MESSAGES: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_rx_malformed_messages_total",
+        "Wire messages that couldn't be parsed or decoded",
+    )
+});
+
+/// Times a task panicked and was run again, labelled by task
+pub fn task_restarts(task: &str) -> Counter {
+    warp_metrics::global().counter_with_labels(
+        "warp_task_restarts_total",
+        "Times a task panicked and was run again",
+        &[("task", task)],
+    )
+}
+
+pub static STALLED_TASKS: LazyLock<Gauge> = LazyLock::new(|| {
+    warp_metrics::global().gauge(
+        "warp_stalled_tasks",
+        "Tasks that have been working on one thing for longer than their stall limit",
+    )
+});
+
+pub static RX_QUEUE_DEPTH: LazyLock<Gauge> = LazyLock::new(|| {
+    warp_metrics::global().gauge(
+        "warp_rx_queue_depth",
+        "Datagrams waiting in the queue of the rx worker that last picked one up",
+    )
+});
+
+pub static RX_QUEUE_DROPS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_rx_queue_drops_total",
+        "Received datagrams dropped because too many were already waiting to be processed",
+    )
+});
+
+pub static RX_SOCKET_WAIT_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    warp_metrics::global().histogram(
+        "warp_rx_socket_wait_seconds",
+        "Time received datagrams waited in their socket's buffer, from the kernel's timestamp until warp read them",
+        warp_metrics::LATENCY_BUCKETS,
+    )
+});
+
+pub static RX_QUEUE_WAIT_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    warp_metrics::global().histogram(
+        "warp_rx_queue_wait_seconds",
+        "Time received datagrams waited for an rx worker after warp read them",
+        warp_metrics::LATENCY_BUCKETS,
+    )
+});
+
+pub static RX_PROCESSING_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    warp_metrics::global().histogram(
+        "warp_rx_processing_seconds",
+        "Time spent processing each received datagram",
+        warp_metrics::LATENCY_BUCKETS,
+    )
+});
+
+pub static TELEMETRY_SPANS_DROPPED: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_telemetry_spans_dropped_total",
+        "Spans not exported because the exporter had fallen behind",
+    )
+});
+
+pub static TX_TUNNEL_PAYLOADS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_tx_tunnel_payloads_total",
+        "Tunnel payloads accepted from applications for sending",
+    )
+});
+
+pub static TX_SENDS_QUEUED: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_tx_sends_queued_total",
+        "Datagrams queued on an interface (one per interface and peer address)",
+    )
+});
+
+pub static TX_PACKED_PAYLOADS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_tx_packed_payloads_total",
+        "Tunnel payloads queued in packed datagrams (see transport.packing)",
+    )
+});
+
+pub static TX_SEND_QUEUE_ERRORS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_tx_send_queue_errors_total",
+        "Datagrams that could not be queued on an interface",
+    )
+});
+
+pub static TX_SEND_QUEUE_DROPS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_tx_send_queue_drops_total",
+        "Datagrams dropped because their interface's send queue was full",
+    )
+});
+
+pub static TX_PACING_WAITS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_tx_pacing_waits_total",
+        "Times an interface held back datagrams to stay within its rate limit",
+    )
+});
+
+pub static TX_RETRANSMISSIONS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_tx_retransmissions_total",
+        "Payloads of reliable tunnels sent again for want of an acknowledgement",
+    )
+});
+
+pub static TX_UNACKNOWLEDGED_PAYLOADS: LazyLock<Counter> = LazyLock::new(|| {
+    warp_metrics::global().counter(
+        "warp_tx_unacknowledged_payloads_total",
+        "Payloads of reliable tunnels given up on without an acknowledgement",
+    )
+});
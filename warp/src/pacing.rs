@@ -0,0 +1,119 @@
+//! Rate limiting for interfaces and tunnels
+//!
+//! A [`TokenBucket`] fills at the configured rate up to its burst size, and each send takes as many tokens as it has
+//! bytes. A send may take more tokens than are left, so datagrams larger than the burst still go out; the bucket then
+//! has to refill past zero before the next send, which keeps the average rate where it should be.
+
+use std::time::{Duration, Instant};
+
+pub struct TokenBucket {
+    // Bytes per second
+    rate: f64,
+    burst: f64,
+    pacing_interval: Duration,
+    // May be negative after a send larger than what was left
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket, so the first burst goes out straight away
+    pub fn new(config: &warp_config::RateLimitConfig, now: Instant) -> Self {
+        Self {
+            rate: config.rate as f64,
+            burst: config.burst as f64,
+            pacing_interval: config.pacing_interval,
+            tokens: config.burst as f64,
+            refilled_at: now,
+        }
+    }
+
+    /// Take `bytes` tokens if anything is left, returning false if the send has to wait
+    pub fn try_take(&mut self, bytes: usize, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens <= 0.0 {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
+
+    /// When [`Self::try_take`] will next succeed; never sooner than a pacing interval away unless it would succeed now
+    pub fn ready_at(&mut self, now: Instant) -> Instant {
+        self.refill(now);
+        if self.tokens > 0.0 {
+            return now;
+        }
+        if self.rate <= 0.0 {
+            // Nothing will ever be sent; check back in case the limit is reloaded
+            return now + self.pacing_interval.max(Duration::from_secs(1));
+        }
+        // Just past the point where the bucket is no longer empty
+        let refill_time = Duration::from_secs_f64(-self.tokens / self.rate) + Duration::from_micros(1);
+        now + refill_time.max(self.pacing_interval)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(rate: u64, burst: u64, pacing_interval: Duration) -> warp_config::RateLimitConfig {
+        warp_config::RateLimitConfig {
+            rate,
+            burst,
+            pacing_interval,
+        }
+    }
+
+    #[test]
+    fn test_bursts_then_paces() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&config(1000, 3000, Duration::from_millis(1)), start);
+        // The burst goes out at once
+        assert!(bucket.try_take(1500, start));
+        assert!(bucket.try_take(1500, start));
+        assert!(!bucket.try_take(1, start));
+
+        // Then a 1000 byte send about each second
+        let ready_at = bucket.ready_at(start);
+        assert!(ready_at > start && ready_at - start < Duration::from_millis(2));
+        assert!(bucket.try_take(1000, ready_at));
+        let next = bucket.ready_at(ready_at);
+        assert!(next - ready_at >= Duration::from_millis(998) && next - ready_at < Duration::from_secs(1));
+        assert!(!bucket.try_take(1000, next - Duration::from_millis(1)));
+        assert!(bucket.try_take(1000, next));
+    }
+
+    #[test]
+    fn test_large_sends_go_into_debt() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&config(1000, 100, Duration::from_millis(1)), start);
+        // Larger than the whole burst, but not held back forever
+        assert!(bucket.try_take(1500, start));
+        // It is paid for before anything else is sent
+        let ready_at = bucket.ready_at(start);
+        assert!(ready_at - start >= Duration::from_millis(1400));
+        assert!(bucket.try_take(1500, ready_at));
+    }
+
+    #[test]
+    fn test_waits_at_least_a_pacing_interval() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&config(1_000_000, 1000, Duration::from_millis(5)), start);
+        assert!(bucket.try_take(1001, start));
+        // A microsecond would do, but the wait is rounded up so the sender wakes up less often
+        assert_eq!(bucket.ready_at(start) - start, Duration::from_millis(5));
+        // By then the bucket has refilled to more than a single datagram
+        let ready_at = bucket.ready_at(start);
+        assert!(bucket.try_take(600, ready_at));
+        assert!(bucket.try_take(600, ready_at));
+        assert!(!bucket.try_take(600, ready_at));
+    }
+}
@@ -0,0 +1,216 @@
+//! Packing a tunnel's small sealed payloads into shared datagrams, configured by each tunnel's `transport.packing`
+//!
+//! Wire messages say how long they are, and the rx loop already reads every message in a datagram, so a datagram can
+//! carry more than one. The accelerator hands the sealed payloads of tunnels with packing set to a [`Packer`] rather
+//! than queueing them on their interfaces straight away. Payloads of the same tunnel going to the same address over the
+//! same interface are appended to one datagram until the next would take it over the tunnel's MTU, or the first has
+//! waited `packing.delay`, and the datagram is queued then. A payload too big to share a datagram is queued as it is.
+
+use crate::interface::TxPayload;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How a tunnel's payloads are packed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Packing {
+    /// How big a packed datagram may get, leaving room for the IP and UDP headers within the tunnel's MTU
+    pub max_size: usize,
+    /// How long a payload may wait for others to share its datagram
+    pub delay: Duration,
+}
+
+impl Packing {
+    /// How the tunnel configured by `transport` packs its payloads, if it does
+    pub fn for_tunnel(transport: &warp_config::WarpTransportConfig) -> Option<Self> {
+        transport.packing.as_ref().map(|packing| Self {
+            max_size: (transport.mtu as usize).saturating_sub(crate::fec::IP_UDP_HEADER_SIZE),
+            delay: packing.delay,
+        })
+    }
+}
+
+// A datagram being packed, with where it goes once it is full or due
+struct Pack<D> {
+    via: D,
+    tx_payload: TxPayload,
+    data: Vec<u8>,
+    max_size: usize,
+    num_payloads: usize,
+    due_at: Instant,
+}
+
+impl<D> Pack<D> {
+    fn finish(self) -> Packed<D> {
+        Packed {
+            via: self.via,
+            num_payloads: self.num_payloads,
+            tx_payload: TxPayload {
+                data: self.data.into(),
+                ..self.tx_payload
+            },
+        }
+    }
+}
+
+/// A datagram ready to be queued on its interface
+pub struct Packed<D> {
+    /// Which interface the datagram goes out of
+    pub via: D,
+    /// How many tunnel payloads the datagram carries
+    pub num_payloads: usize,
+    /// The datagram, with the earliest deadline and the trace of the first of its tunnel payloads
+    pub tx_payload: TxPayload,
+}
+
+/// Datagrams being packed, one for each key: a tunnel, an interface and a peer address
+pub struct Packer<K, D> {
+    packs: HashMap<K, Pack<D>>,
+}
+
+impl<K, D> Default for Packer<K, D> {
+    fn default() -> Self {
+        Self { packs: HashMap::new() }
+    }
+}
+
+impl<K: Clone + Eq + std::hash::Hash, D> Packer<K, D> {
+    /// Add `tx_payload` to the datagram being packed for `key`, which goes out of `via`, returning the datagrams that
+    /// are ready to be queued: the one it was being packed into if it doesn't fit, and itself if it fills one alone
+    pub fn push(&mut self, key: K, via: D, tx_payload: TxPayload, packing: Packing, now: Instant) -> Vec<Packed<D>> {
+        let mut ready = Vec::new();
+        if let Some(pack) = self.packs.get(&key)
+            && pack.data.len() + tx_payload.data.len() > pack.max_size
+        {
+            ready.extend(self.packs.remove(&key).map(Pack::finish));
+        }
+        if tx_payload.data.len() >= packing.max_size {
+            ready.push(Packed {
+                via,
+                num_payloads: 1,
+                tx_payload,
+            });
+            return ready;
+        }
+
+        match self.packs.get_mut(&key) {
+            Some(pack) => {
+                pack.data.extend_from_slice(&tx_payload.data);
+                pack.num_payloads += 1;
+                pack.tx_payload.deadline = match (pack.tx_payload.deadline, tx_payload.deadline) {
+                    (Some(deadline), Some(other)) => Some(deadline.min(other)),
+                    (deadline, other) => deadline.or(other),
+                };
+            }
+            None => {
+                let mut data = Vec::with_capacity(packing.max_size);
+                data.extend_from_slice(&tx_payload.data);
+                self.packs.insert(
+                    key,
+                    Pack {
+                        via,
+                        tx_payload,
+                        data,
+                        max_size: packing.max_size,
+                        num_payloads: 1,
+                        due_at: now + packing.delay,
+                    },
+                );
+            }
+        }
+        ready
+    }
+
+    /// When [`Self::take_due`] will next have a datagram to return
+    pub fn next_due(&self) -> Option<Instant> {
+        self.packs.values().map(|pack| pack.due_at).min()
+    }
+
+    /// The datagrams whose first payload has waited as long as it may
+    pub fn take_due(&mut self, now: Instant) -> Vec<Packed<D>> {
+        let due: Vec<K> = self
+            .packs
+            .iter()
+            .filter(|(_, pack)| pack.due_at <= now)
+            .map(|(key, _)| key)
+            .cloned()
+            .collect();
+        due.into_iter()
+            .filter_map(|key| self.packs.remove(&key))
+            .map(Pack::finish)
+            .collect()
+    }
+
+    /// Every datagram being packed, whether it is due or not
+    pub fn take_all(&mut self) -> Vec<Packed<D>> {
+        self.packs.drain().map(|(_, pack)| pack.finish()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKING: Packing = Packing {
+        max_size: 100,
+        delay: Duration::from_millis(2),
+    };
+
+    fn tx_payload(data: &[u8], deadline: Instant) -> TxPayload {
+        TxPayload {
+            to: "127.0.0.1:1".parse().unwrap(),
+            deadline: Some(deadline),
+            class: crate::interface::TrafficClass::default(),
+            trace: None,
+            data: data.to_vec().into(),
+        }
+    }
+
+    #[test]
+    fn test_packs_until_due() {
+        let now = Instant::now();
+        let mut packer = Packer::default();
+        assert!(
+            packer
+                .push(
+                    "a",
+                    (),
+                    tx_payload(&[1; 30], now + Duration::from_secs(1)),
+                    PACKING,
+                    now
+                )
+                .is_empty()
+        );
+        assert!(packer.push("a", (), tx_payload(&[2; 30], now), PACKING, now).is_empty());
+        assert!(packer.push("b", (), tx_payload(&[3; 30], now), PACKING, now).is_empty());
+        assert_eq!(packer.next_due(), Some(now + PACKING.delay));
+
+        assert!(packer.take_due(now + Duration::from_millis(1)).is_empty());
+        let mut due = packer.take_due(now + PACKING.delay);
+        due.sort_by_key(|packed| packed.num_payloads);
+        assert_eq!(due.len(), 2);
+        assert_eq!(due[0].tx_payload.data[..], [3; 30]);
+        assert_eq!(due[1].num_payloads, 2);
+        assert_eq!(due[1].tx_payload.data[..], [[1; 30], [2; 30]].concat());
+        // The datagram can only wait as long as its most urgent payload
+        assert_eq!(due[1].tx_payload.deadline, Some(now));
+        assert_eq!(packer.next_due(), None);
+    }
+
+    #[test]
+    fn test_full_datagrams_go_at_once() {
+        let now = Instant::now();
+        let mut packer = Packer::default();
+        assert!(packer.push("a", (), tx_payload(&[1; 60], now), PACKING, now).is_empty());
+
+        // Doesn't fit with the first
+        let ready = packer.push("a", (), tx_payload(&[2; 60], now), PACKING, now);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].tx_payload.data[..], [1; 60]);
+
+        // Too big to share a datagram at all, and not sent ahead of the payload before it
+        let ready = packer.push("a", (), tx_payload(&[3; 100], now), PACKING, now);
+        let sent: Vec<_> = ready.iter().map(|packed| packed.tx_payload.data[0]).collect();
+        assert_eq!(sent, [2, 3]);
+        assert!(packer.take_all().is_empty());
+    }
+}
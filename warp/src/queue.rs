@@ -0,0 +1,244 @@
+//! Bounded queues between the tasks that send and receive datagrams
+//!
+//! Datagrams can't be held back at their source: peers keep sending whether or not we keep up, and a datagram that
+//! waits too long for its interface is worthless anyway. So rather than making the sender wait, a full queue makes room
+//! by dropping a datagram, and hands it back to the sender to be counted.
+//!
+//! Received datagrams are spread over several queues, one per rx worker, by a key such as their source address, so that
+//! they can be processed on several cores while those with the same key are still processed in order.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use warp_config::QueueOverflow;
+
+struct State<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    // Only the receiver waits, so a notification sent while it isn't waiting is kept for its next wait
+    notify: tokio::sync::Notify,
+    capacity: usize,
+    overflow: QueueOverflow,
+}
+
+/// Create a queue holding at most `capacity` items, which drops one as `overflow` says when it is full
+pub fn bounded<T>(capacity: usize, overflow: QueueOverflow) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::new(),
+            senders: 1,
+            receiver_alive: true,
+        }),
+        notify: tokio::sync::Notify::new(),
+        capacity: capacity.max(1),
+        overflow,
+    });
+    (QueueSender { shared: shared.clone() }, QueueReceiver { shared })
+}
+
+/// The receiver has been dropped; holds the item that couldn't be queued
+#[derive(Debug)]
+pub struct Closed<T>(pub T);
+
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueSender<T> {
+    /// Queue `item`, returning the item dropped to make room for it if the queue was full
+    pub fn push(&self, item: T) -> Result<Option<T>, Closed<T>> {
+        let dropped = {
+            let mut state = self.shared.state.lock().unwrap();
+            if !state.receiver_alive {
+                return Err(Closed(item));
+            }
+            if state.items.len() < self.shared.capacity {
+                state.items.push_back(item);
+                None
+            } else {
+                match self.shared.overflow {
+                    QueueOverflow::DropNewest => return Ok(Some(item)),
+                    QueueOverflow::DropOldest => {
+                        state.items.push_back(item);
+                        state.items.pop_front()
+                    }
+                }
+            }
+        };
+        self.shared.notify.notify_one();
+        Ok(dropped)
+    }
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.notify.notify_one();
+        }
+    }
+}
+
+/// Create `shards` queues like [`bounded`], and a sender that queues each item on the one its key picks
+pub fn sharded<T>(
+    shards: usize,
+    capacity: usize,
+    overflow: QueueOverflow,
+) -> (ShardedSender<T>, Vec<QueueReceiver<T>>) {
+    let (senders, receivers) = (0..shards.max(1)).map(|_| bounded(capacity, overflow)).unzip();
+    let sender = ShardedSender {
+        shards: senders,
+        hasher: std::hash::RandomState::new(),
+    };
+    (sender, receivers)
+}
+
+pub struct ShardedSender<T> {
+    shards: Vec<QueueSender<T>>,
+    hasher: std::hash::RandomState,
+}
+
+impl<T> ShardedSender<T> {
+    /// Queue `item` on the queue `key` picks, returning the item dropped to make room for it if that one was full;
+    /// items with equal keys always go to the same queue
+    pub fn push(&self, key: &impl std::hash::Hash, item: T) -> Result<Option<T>, Closed<T>> {
+        use std::hash::BuildHasher;
+        let shard = self.hasher.hash_one(key) % self.shards.len() as u64;
+        self.shards[shard as usize].push(item)
+    }
+}
+
+impl<T> Clone for ShardedSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+            hasher: self.hasher.clone(),
+        }
+    }
+}
+
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueReceiver<T> {
+    /// Wait for the oldest item, or None once every sender is gone and nothing is left
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(item) = state.items.pop_front() {
+                    return Some(item);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    /// How many items are waiting to be received
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().items.len()
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receiver_alive = false;
+        state.items.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let (sender, mut receiver) = bounded(2, QueueOverflow::DropOldest);
+        assert_eq!(sender.push(1).unwrap(), None);
+        assert_eq!(sender.push(2).unwrap(), None);
+        assert_eq!(sender.push(3).unwrap(), Some(1));
+        assert_eq!(receiver.len(), 2);
+        assert_eq!(receiver.recv().await, Some(2));
+        assert_eq!(receiver.recv().await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_drop_newest() {
+        let (sender, mut receiver) = bounded(2, QueueOverflow::DropNewest);
+        assert_eq!(sender.push(1).unwrap(), None);
+        assert_eq!(sender.push(2).unwrap(), None);
+        assert_eq!(sender.push(3).unwrap(), Some(3));
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(receiver.recv().await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_closing() {
+        let (sender, mut receiver) = bounded(4, QueueOverflow::DropOldest);
+        let waiting = tokio::spawn(async move { (receiver.recv().await, receiver.recv().await) });
+        tokio::task::yield_now().await;
+        let other_sender = sender.clone();
+        drop(sender);
+        other_sender.push(1).unwrap();
+        tokio::task::yield_now().await;
+        // A waiting receiver wakes when the last sender goes
+        drop(other_sender);
+        assert_eq!(waiting.await.unwrap(), (Some(1), None));
+
+        let (sender, mut receiver) = bounded(4, QueueOverflow::DropOldest);
+        sender.push(1).unwrap();
+        drop(sender);
+        // What was queued is still received once the senders are gone
+        assert_eq!(receiver.recv().await, Some(1));
+        assert_eq!(receiver.recv().await, None);
+
+        let (sender, receiver) = bounded(4, QueueOverflow::DropOldest);
+        drop(receiver);
+        assert!(matches!(sender.push(1), Err(Closed(1))));
+    }
+
+    #[tokio::test]
+    async fn test_sharding_keeps_keys_together() {
+        let (sender, mut receivers) = sharded(4, 64, QueueOverflow::DropOldest);
+        for item in 0..64 {
+            sender.push(&(item % 8), item).unwrap();
+        }
+        drop(sender);
+
+        let mut received = 0;
+        for receiver in &mut receivers {
+            let mut items = Vec::new();
+            while let Some(item) = receiver.recv().await {
+                items.push(item);
+            }
+            received += items.len();
+            // Each key's items are all on one queue, in the order they were pushed
+            for key in 0..8 {
+                let of_key: Vec<_> = items.iter().filter(|&&item| item % 8 == key).collect();
+                assert!(of_key.is_empty() || of_key.len() == 8);
+                assert!(of_key.is_sorted());
+            }
+        }
+        assert_eq!(received, 64);
+    }
+}
@@ -0,0 +1,197 @@
+//! Adapts how many FEC shards a tunnel sends to how its paths are doing, configured by `RedundancyConfig.adaptive`
+//!
+//! Parity shards only help against losses that happen regardless of how much is sent. A congested path loses datagrams
+//! because it is sent too much, and says so with a round trip time well above its usual one; more shards would only make
+//! that worse, so loss on such a path sheds a shard instead of adding one. The gap between the two loss thresholds, and
+//! moving by at most one shard per interval, keep the shard count from flapping. Extra shards are spread across the
+//! tunnel's paths like the rest, so they also put more of the paths to use.
+
+use crate::routing::PathStats;
+use std::time::{Duration, Instant};
+
+// The lowest round trip time seen creeps up by this fraction each interval, so that paths that got slower for good stop
+// being treated as congested
+const MIN_RTT_DRIFT: f64 = 0.01;
+
+pub struct RedundancyController {
+    min_shards: usize,
+    max_shards: usize,
+    raise_loss: f64,
+    lower_loss: f64,
+    congestion_rtt_ratio: f64,
+    interval: Duration,
+
+    num_shards: usize,
+    min_rtt: Option<Duration>,
+    updated_at: Instant,
+}
+
+impl RedundancyController {
+    /// Start from `redundancy.num_shards`, brought within the configured bounds
+    pub fn new(
+        redundancy: &warp_config::RedundancyConfig,
+        config: &warp_config::AdaptiveRedundancyConfig,
+        now: Instant,
+    ) -> anyhow::Result<Self> {
+        if config.min_shards < redundancy.required_shards || config.max_shards < config.min_shards {
+            anyhow::bail!(
+                "adaptive redundancy needs required_shards ({}) <= min_shards ({}) <= max_shards ({})",
+                redundancy.required_shards,
+                config.min_shards,
+                config.max_shards
+            );
+        }
+        if config.lower_loss < 0.0 || config.lower_loss >= config.raise_loss {
+            anyhow::bail!(
+                "adaptive redundancy needs 0 <= lower_loss ({}) < raise_loss ({})",
+                config.lower_loss,
+                config.raise_loss
+            );
+        }
+        if config.congestion_rtt_ratio <= 1.0 {
+            anyhow::bail!(
+                "congestion_rtt_ratio must be above 1, not {}",
+                config.congestion_rtt_ratio
+            );
+        }
+
+        let (min_shards, max_shards) = (config.min_shards as usize, config.max_shards as usize);
+        Ok(Self {
+            min_shards,
+            max_shards,
+            raise_loss: config.raise_loss,
+            lower_loss: config.lower_loss,
+            congestion_rtt_ratio: config.congestion_rtt_ratio,
+            interval: config.interval,
+            num_shards: (redundancy.num_shards as usize).clamp(min_shards, max_shards),
+            min_rtt: None,
+            updated_at: now,
+        })
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.num_shards
+    }
+
+    /// Whether an interval has passed since the last update
+    pub fn is_due(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.updated_at) >= self.interval
+    }
+
+    /// Adjust the shard count to `stats` for the tunnel's paths, returning the new count if it changed
+    pub fn update(&mut self, stats: Option<PathStats>, now: Instant) -> Option<usize> {
+        self.updated_at = now;
+        let stats = stats?;
+        let congested = match (stats.rtt, self.min_rtt) {
+            (Some(rtt), Some(min_rtt)) => rtt > min_rtt.mul_f64(self.congestion_rtt_ratio),
+            _ => false,
+        };
+        if let Some(rtt) = stats.rtt {
+            self.min_rtt = Some(
+                self.min_rtt
+                    .map_or(rtt, |min_rtt| min_rtt.mul_f64(1.0 + MIN_RTT_DRIFT).min(rtt)),
+            );
+        }
+
+        let num_shards = if stats.loss > self.raise_loss && !congested {
+            self.num_shards + 1
+        } else if stats.loss > self.raise_loss || stats.loss < self.lower_loss {
+            self.num_shards.saturating_sub(1)
+        } else {
+            self.num_shards
+        }
+        .clamp(self.min_shards, self.max_shards);
+
+        if num_shards == self.num_shards {
+            return None;
+        }
+        self.num_shards = num_shards;
+        Some(num_shards)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller(num_shards: u8) -> RedundancyController {
+        let redundancy = warp_config::RedundancyConfig {
+            num_shards,
+            required_shards: 2,
+            adaptive: None,
+        };
+        let config = warp_config::AdaptiveRedundancyConfig {
+            min_shards: 3,
+            max_shards: 5,
+            raise_loss: 0.1,
+            lower_loss: 0.02,
+            congestion_rtt_ratio: 2.0,
+            interval: Duration::from_secs(1),
+        };
+        RedundancyController::new(&redundancy, &config, Instant::now()).unwrap()
+    }
+
+    fn stats(loss: f64, rtt_ms: u64) -> Option<PathStats> {
+        Some(PathStats {
+            rtt: Some(Duration::from_millis(rtt_ms)),
+            loss,
+        })
+    }
+
+    #[test]
+    fn test_follows_loss_within_bounds() {
+        let mut controller = controller(8);
+        let now = Instant::now();
+        // Brought within the bounds to start with
+        assert_eq!(controller.num_shards(), 5);
+        assert!(!controller.is_due(now));
+        assert!(controller.is_due(now + Duration::from_secs(1)));
+
+        assert_eq!(controller.update(stats(0.01, 20), now), Some(4));
+        assert_eq!(controller.update(stats(0.0, 20), now), Some(3));
+        assert_eq!(controller.update(stats(0.0, 20), now), None);
+        // Loss between the thresholds changes nothing either way
+        assert_eq!(controller.update(stats(0.05, 20), now), None);
+        assert_eq!(controller.update(stats(0.2, 20), now), Some(4));
+        assert_eq!(controller.update(stats(0.05, 20), now), None);
+        assert_eq!(controller.update(stats(0.2, 20), now), Some(5));
+        assert_eq!(controller.update(stats(0.5, 20), now), None);
+        // Nothing to go on
+        assert_eq!(controller.update(None, now), None);
+    }
+
+    #[test]
+    fn test_sheds_shards_under_congestion() {
+        let now = Instant::now();
+        let mut controller = controller(4);
+        assert_eq!(controller.update(stats(0.05, 20), now), None);
+        // The same loss with the round trip time well up is congestion
+        assert_eq!(controller.update(stats(0.2, 50), now), Some(3));
+        assert_eq!(controller.update(stats(0.2, 30), now), Some(4));
+    }
+
+    #[test]
+    fn test_rejects_bad_bounds() {
+        let redundancy = warp_config::RedundancyConfig {
+            num_shards: 5,
+            required_shards: 3,
+            adaptive: None,
+        };
+        let config = warp_config::AdaptiveRedundancyConfig {
+            min_shards: 2,
+            max_shards: 6,
+            raise_loss: 0.1,
+            lower_loss: 0.02,
+            congestion_rtt_ratio: 2.0,
+            interval: Duration::from_secs(1),
+        };
+        // Fewer shards than are required to reconstruct a payload
+        assert!(RedundancyController::new(&redundancy, &config, Instant::now()).is_err());
+        let thresholds_swapped = warp_config::AdaptiveRedundancyConfig {
+            min_shards: 3,
+            raise_loss: 0.01,
+            ..config
+        };
+        assert!(RedundancyController::new(&redundancy, &thresholds_swapped, Instant::now()).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+//! Relaying through warp-map, for far gates that can't be reached directly; configured by `interfaces.relay`
+//!
+//! A message for a relayed far gate is sealed for it as usual, then wrapped in a RelayPayload for warp-map, which passes
+//! it on to the far gate's registered addresses. Those addresses already take datagrams from warp-map, since the far
+//! gate keeps registering with it, so this gets through NATs that no holepunching can. The far gate unwraps it and
+//! handles the message as if it had come directly; warp-map never sees what is inside.
+
+use warp_protocol::codec::Message;
+
+pub struct Relay {
+    address: std::net::SocketAddr,
+    cipher: warp_protocol::Cipher,
+}
+
+impl Relay {
+    /// Relay through the warp-map at `address`, which `cipher` is shared with
+    pub fn new(address: std::net::SocketAddr, cipher: warp_protocol::Cipher) -> Self {
+        Self { address, cipher }
+    }
+
+    pub fn address(&self) -> std::net::SocketAddr {
+        self.address
+    }
+
+    /// Wrap `data`, a message sealed for `far_gate`, for warp-map to pass on to it
+    pub fn wrap(
+        &self,
+        far_gate: &warp_protocol::PublicKey,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, warp_protocol::EncodeError> {
+        warp_protocol::messages::RelayPayload {
+            peer_pubkey: *far_gate,
+            data,
+        }
+        .encode()?
+        .encrypt(&self.cipher)?
+        .to_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        let (map_key, client_key) = (
+            warp_protocol::PrivateKey::random(&mut rand::rng()),
+            warp_protocol::PrivateKey::random(&mut rand::rng()),
+        );
+        let far_gate = warp_protocol::PrivateKey::random(&mut rand::rng()).public_key();
+        let relay = Relay::new(
+            "192.0.2.1:13116".parse().unwrap(),
+            warp_protocol::crypto::cipher_from_shared_secret(&client_key, &map_key.public_key()),
+        );
+
+        let wrapped = relay.wrap(&far_gate, vec![1, 2, 3]).unwrap();
+
+        // warp-map can read who it is for, but the data stays as it was sealed
+        let map_cipher = warp_protocol::crypto::cipher_from_shared_secret(&map_key, &client_key.public_key());
+        let (message, rest) = warp_protocol::codec::WireMessage::from_slice(&wrapped).unwrap();
+        assert!(rest.is_empty());
+        let relayed: warp_protocol::messages::RelayPayload = message.decrypt(&map_cipher).unwrap().decode().unwrap();
+        assert_eq!(relayed.peer_pubkey, far_gate);
+        assert_eq!(relayed.data, vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,348 @@
+//! Acknowledged delivery for the payloads of a tunnel with `reliable` set
+//!
+//! The receiving gate acknowledges each payload with a `TunnelAck`, including copies of payloads it has already
+//! delivered (the acknowledgement of the first copy may have been lost), but delivers each payload only once. The
+//! sending warp keeps every payload in a [`RetransmissionQueue`] until it is acknowledged, and sends it again whenever
+//! its retransmission timeout (RTO) passes.
+//!
+//! RTOs are estimated per far gate from the round trip times of acknowledged payloads, the way TCP does (RFC 6298).
+//! Only payloads acknowledged without having been retransmitted are timed, since it's unknown which copy of the others
+//! was acknowledged, and each retransmission of a payload doubles its RTO. A payload still unacknowledged
+//! [`GIVE_UP_AFTER`] it was first sent is given up on.
+//!
+//! Gates remember the payloads they delivered for as long as they could be retransmitted. The far gate's tracers start
+//! again from 0 when it restarts, so payloads from a far gate that restarted within that time can be taken for
+//! duplicates.
+
+use crate::trace::TraceContext;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use warp_protocol::messages::{ReconstructionTag, TunnelAck, TunnelId, TunnelPayload};
+
+// The RTO before any round trip to the far gate has been timed
+const INITIAL_RTO: Duration = Duration::from_millis(200);
+const MIN_RTO: Duration = Duration::from_millis(20);
+const MAX_RTO: Duration = Duration::from_secs(2);
+/// How often the queue is checked for payloads to retransmit, which limits how precisely RTOs are kept to
+pub const RETRANSMISSION_INTERVAL: Duration = Duration::from_millis(10);
+/// How long a payload is retransmitted for before it is given up on
+pub const GIVE_UP_AFTER: Duration = Duration::from_secs(10);
+/// How many tracers a `TunnelAck` holds at most, to keep it well within any MTU
+pub const MAX_TRACERS_PER_ACK: usize = 128;
+
+/// The tracer a payload is acknowledged by: that of the payload the tunnel payload carries, or is a shard of
+pub fn payload_tracer(tunnel_payload: &TunnelPayload) -> u64 {
+    match &tunnel_payload.reconstruction_tag {
+        ReconstructionTag::Multipart(identifier) => identifier.parent_tracer,
+        _ => tunnel_payload.tracer,
+    }
+}
+
+#[derive(Default)]
+struct RttEstimator {
+    // None until the first round trip is timed
+    smoothed: Option<Duration>,
+    variation: Duration,
+}
+
+impl RttEstimator {
+    fn observe(&mut self, rtt: Duration) {
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(rtt);
+                self.variation = rtt / 2;
+            }
+            Some(smoothed) => {
+                self.variation = self.variation * 3 / 4 + smoothed.abs_diff(rtt) / 4;
+                self.smoothed = Some(smoothed * 7 / 8 + rtt / 8);
+            }
+        }
+    }
+
+    fn rto(&self) -> Duration {
+        match self.smoothed {
+            None => INITIAL_RTO,
+            Some(smoothed) => (smoothed + self.variation * 4).clamp(MIN_RTO, MAX_RTO),
+        }
+    }
+}
+
+/// A payload waiting to be acknowledged, with what's needed to send it again
+#[derive(Clone)]
+pub struct Unacknowledged {
+    pub far_gate: warp_protocol::PublicKey,
+    /// The payload as it goes over the wire: a single tunnel payload, or its FEC shards; shared with the accelerator,
+    /// which sealed it the first time
+    pub tunnel_payloads: std::sync::Arc<[TunnelPayload]>,
+    pub routes: std::sync::Arc<crate::routing::RoutePolicy>,
+    /// How long each copy may wait in an interface's send queue
+    pub send_deadline: Duration,
+    pub traffic_class: crate::interface::TrafficClass,
+    pub trace: TraceContext,
+}
+
+struct Pending {
+    payload: Unacknowledged,
+    first_sent: Instant,
+    last_sent: Instant,
+    retransmissions: u32,
+    retransmit_at: Instant,
+}
+
+/// The payloads whose RTO has passed
+#[derive(Default)]
+pub struct Due {
+    pub retransmit: Vec<Unacknowledged>,
+    pub given_up: Vec<Unacknowledged>,
+}
+
+#[derive(Default)]
+pub struct RetransmissionQueue {
+    // By far gate, then by tunnel and payload tracer
+    pending: BTreeMap<warp_protocol::PublicKey, HashMap<(TunnelId, u64), Pending>>,
+    estimators: BTreeMap<warp_protocol::PublicKey, RttEstimator>,
+}
+
+impl RetransmissionQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait for the acknowledgement of a payload that has just been sent
+    pub fn sent(&mut self, payload: Unacknowledged, now: Instant) {
+        let Some(tunnel_payload) = payload.tunnel_payloads.first() else {
+            return;
+        };
+        let key = (tunnel_payload.tunnel_id.clone(), payload_tracer(tunnel_payload));
+        let rto = self.rto(&payload.far_gate);
+        self.pending.entry(payload.far_gate).or_default().insert(
+            key,
+            Pending {
+                payload,
+                first_sent: now,
+                last_sent: now,
+                retransmissions: 0,
+                retransmit_at: now + rto,
+            },
+        );
+    }
+
+    /// Stop retransmitting the payloads `ack` acknowledges; returns how many of them were waiting for it
+    pub fn acknowledge(&mut self, far_gate: &warp_protocol::PublicKey, ack: &TunnelAck, now: Instant) -> usize {
+        let Some(pending) = self.pending.get_mut(far_gate) else {
+            return 0;
+        };
+        let mut acknowledged = 0;
+        for tracer in &ack.tracers {
+            let Some(payload) = pending.remove(&(ack.tunnel_id.clone(), *tracer)) else {
+                continue;
+            };
+            acknowledged += 1;
+            if payload.retransmissions == 0 {
+                self.estimators
+                    .entry(*far_gate)
+                    .or_default()
+                    .observe(now.saturating_duration_since(payload.last_sent));
+            }
+        }
+        if pending.is_empty() {
+            self.pending.remove(far_gate);
+        }
+        acknowledged
+    }
+
+    /// Take the payloads whose RTO has passed by `now`; those to send again are due again after twice their last RTO
+    pub fn due(&mut self, now: Instant) -> Due {
+        let mut due = Due::default();
+        for (far_gate, pending) in &mut self.pending {
+            let rto = self.estimators.get(far_gate).map_or(INITIAL_RTO, RttEstimator::rto);
+            pending.retain(|_, payload| {
+                if payload.retransmit_at > now {
+                    return true;
+                }
+                if now.duration_since(payload.first_sent) >= GIVE_UP_AFTER {
+                    due.given_up.push(payload.payload.clone());
+                    return false;
+                }
+                payload.retransmissions += 1;
+                payload.last_sent = now;
+                let backoff = 1 << payload.retransmissions.min(u16::BITS);
+                payload.retransmit_at = now + rto.saturating_mul(backoff).min(MAX_RTO);
+                due.retransmit.push(payload.payload.clone());
+                true
+            });
+        }
+        self.pending.retain(|_, pending| !pending.is_empty());
+        due
+    }
+
+    /// The retransmission timeout for payloads sent to `far_gate` that haven't been retransmitted yet
+    pub fn rto(&self, far_gate: &warp_protocol::PublicKey) -> Duration {
+        self.estimators.get(far_gate).map_or(INITIAL_RTO, RttEstimator::rto)
+    }
+}
+
+/// The tracers of the payloads a gate has delivered, remembered for as long as the far gate might retransmit them
+#[derive(Default)]
+pub struct DeliveredPayloads {
+    delivered: HashSet<u64>,
+    // Oldest first
+    delivered_at: VecDeque<(Instant, u64)>,
+}
+
+impl DeliveredPayloads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&mut self, tracer: u64, now: Instant) -> bool {
+        self.forget(now);
+        self.delivered.contains(&tracer)
+    }
+
+    /// Record the payload with `tracer` as delivered
+    pub fn insert(&mut self, tracer: u64, now: Instant) {
+        self.forget(now);
+        if self.delivered.insert(tracer) {
+            self.delivered_at.push_back((now, tracer));
+        }
+    }
+
+    fn forget(&mut self, now: Instant) {
+        while let Some((delivered_at, tracer)) = self.delivered_at.front()
+            && now.duration_since(*delivered_at) >= GIVE_UP_AFTER
+        {
+            self.delivered.remove(tracer);
+            self.delivered_at.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::Correlation;
+    use warp_protocol::messages::MultipartIdentifier;
+
+    fn far_gate() -> warp_protocol::PublicKey {
+        warp_protocol::PrivateKey::random(&mut rand::rng()).public_key()
+    }
+
+    fn unacknowledged(far_gate: warp_protocol::PublicKey, tracer: u64) -> Unacknowledged {
+        let tunnel_id = TunnelId::Id(1);
+        Unacknowledged {
+            far_gate,
+            tunnel_payloads: vec![TunnelPayload::new(tunnel_id.clone(), tracer, vec![1, 2, 3])].into(),
+            routes: std::sync::Arc::new(crate::routing::RoutePolicy::any(warp_config::PathSelection::All)),
+            send_deadline: Duration::from_secs(1),
+            traffic_class: crate::interface::TrafficClass::default(),
+            trace: TraceContext::root(Correlation::new(&crate::trace::tunnel_label(&tunnel_id), tracer)),
+        }
+    }
+
+    fn ack(tracers: &[u64]) -> TunnelAck {
+        TunnelAck {
+            tunnel_id: TunnelId::Id(1),
+            tracers: tracers.to_vec(),
+        }
+    }
+
+    fn tracers(payloads: &[Unacknowledged]) -> Vec<u64> {
+        let mut tracers: Vec<u64> = payloads
+            .iter()
+            .map(|payload| payload.tunnel_payloads[0].tracer)
+            .collect();
+        tracers.sort();
+        tracers
+    }
+
+    #[test]
+    fn test_payload_tracer_of_shards() {
+        let mut shard = TunnelPayload::new(TunnelId::Id(1), 7, vec![]);
+        assert_eq!(payload_tracer(&shard), 7);
+        shard.reconstruction_tag = ReconstructionTag::Multipart(MultipartIdentifier {
+            parent_tracer: 3,
+            num_parts: 2,
+            required_parts: 1,
+            part_id: 0,
+            payload_size: 0,
+        });
+        assert_eq!(payload_tracer(&shard), 3);
+    }
+
+    #[test]
+    fn test_retransmits_with_backoff_until_acknowledged() {
+        let far_gate = far_gate();
+        let mut queue = RetransmissionQueue::new();
+        let start = Instant::now();
+        queue.sent(unacknowledged(far_gate, 0), start);
+        queue.sent(unacknowledged(far_gate, 1), start);
+
+        assert!(queue.due(start + INITIAL_RTO / 2).retransmit.is_empty());
+        let due = queue.due(start + INITIAL_RTO);
+        assert_eq!(tracers(&due.retransmit), vec![0, 1]);
+        // The second retransmission waits twice as long
+        assert!(queue.due(start + INITIAL_RTO * 2).retransmit.is_empty());
+        assert_eq!(tracers(&queue.due(start + INITIAL_RTO * 3).retransmit), vec![0, 1]);
+
+        assert_eq!(queue.acknowledge(&far_gate, &ack(&[1, 5]), start + INITIAL_RTO * 3), 1);
+        assert_eq!(tracers(&queue.due(start + INITIAL_RTO * 10).retransmit), vec![0]);
+        // Acknowledgements of retransmitted payloads aren't timed
+        assert_eq!(queue.rto(&far_gate), INITIAL_RTO);
+    }
+
+    #[test]
+    fn test_rto_follows_round_trip_times() {
+        let far_gate = far_gate();
+        let mut queue = RetransmissionQueue::new();
+        let start = Instant::now();
+        let rtt = Duration::from_millis(10);
+        for tracer in 0..20 {
+            let sent_at = start + rtt * tracer as u32;
+            queue.sent(unacknowledged(far_gate, tracer), sent_at);
+            assert_eq!(queue.acknowledge(&far_gate, &ack(&[tracer]), sent_at + rtt), 1);
+        }
+        // Steady round trips leave little variation, and the RTO doesn't go below its minimum
+        assert!(queue.rto(&far_gate) < INITIAL_RTO);
+        assert!(queue.rto(&far_gate) >= MIN_RTO.max(rtt));
+        // Other far gates are unaffected
+        assert_eq!(queue.rto(&self::far_gate()), INITIAL_RTO);
+    }
+
+    #[test]
+    fn test_gives_up() {
+        let far_gate = far_gate();
+        let mut queue = RetransmissionQueue::new();
+        let start = Instant::now();
+        queue.sent(unacknowledged(far_gate, 0), start);
+
+        let mut now = start;
+        let mut retransmissions = 0;
+        loop {
+            now += MIN_RTO;
+            let due = queue.due(now);
+            retransmissions += due.retransmit.len();
+            if !due.given_up.is_empty() {
+                assert_eq!(tracers(&due.given_up), vec![0]);
+                break;
+            }
+        }
+        assert!(now - start >= GIVE_UP_AFTER);
+        assert!(retransmissions > 2);
+        assert!(queue.due(now + GIVE_UP_AFTER).retransmit.is_empty());
+    }
+
+    #[test]
+    fn test_delivered_payloads_are_forgotten() {
+        let mut delivered = DeliveredPayloads::new();
+        let start = Instant::now();
+        delivered.insert(1, start);
+        delivered.insert(2, start + GIVE_UP_AFTER / 2);
+        assert!(delivered.contains(1, start + GIVE_UP_AFTER / 2));
+        assert!(!delivered.contains(3, start));
+
+        assert!(!delivered.contains(1, start + GIVE_UP_AFTER));
+        assert!(delivered.contains(2, start + GIVE_UP_AFTER));
+        assert!(!delivered.contains(2, start + GIVE_UP_AFTER * 2));
+    }
+}
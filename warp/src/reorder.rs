@@ -0,0 +1,186 @@
+//! Restores tracer order to the payloads of a tunnel with `ordered` set
+//!
+//! A payload that arrives ahead of a missing one is held back until the gap fills, or until `window` payloads are held
+//! or the gap has been waited on for `timeout`, at which point the missing payloads are given up on. Payloads that turn
+//! up after their turn has passed, and duplicates, are dropped: delivering them would break the ordering.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+pub enum Pushed<T> {
+    /// These items, in order, can be delivered now; possibly none
+    Released(Vec<T>),
+    /// The item arrived too late or was a duplicate
+    Dropped(T),
+}
+
+pub struct ReorderBuffer<T> {
+    window: usize,
+    timeout: Duration,
+    next_tracer: Option<u64>,
+    held: BTreeMap<u64, T>,
+    // When the buffer started waiting for `next_tracer`; None while nothing is held
+    gap_since: Option<Instant>,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new(config: &warp_config::ReorderingConfig) -> Self {
+        Self {
+            window: config.window,
+            timeout: config.timeout,
+            next_tracer: None,
+            held: BTreeMap::new(),
+            gap_since: None,
+        }
+    }
+
+    pub fn push(&mut self, tracer: u64, item: T, now: Instant) -> Pushed<T> {
+        let next_tracer = *self.next_tracer.get_or_insert(tracer);
+
+        if tracer < next_tracer {
+            if next_tracer - tracer <= self.window as u64 {
+                return Pushed::Dropped(item);
+            }
+            // Too far behind to be a late arrival: the far gate has restarted and its tracers started again from 0
+            let mut released: Vec<T> = std::mem::take(&mut self.held).into_values().collect();
+            released.push(item);
+            self.next_tracer = Some(tracer + 1);
+            self.gap_since = None;
+            return Pushed::Released(released);
+        }
+        if self.held.contains_key(&tracer) {
+            return Pushed::Dropped(item);
+        }
+        self.held.insert(tracer, item);
+
+        let mut released = self.release_consecutive();
+        while self.held.len() > self.window {
+            released.extend(self.skip_gap());
+        }
+        self.update_gap(!released.is_empty(), now);
+        Pushed::Released(released)
+    }
+
+    /// When [`Self::expire`] will next give up on a missing payload
+    pub fn deadline(&self) -> Option<Instant> {
+        self.gap_since.map(|gap_since| gap_since + self.timeout)
+    }
+
+    /// Give up on the missing payload if it has been waited on for too long, returning what that releases
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        if self.deadline().is_none_or(|deadline| now < deadline) {
+            return Vec::new();
+        }
+        let released = self.skip_gap();
+        self.update_gap(true, now);
+        released
+    }
+
+    fn release_consecutive(&mut self) -> Vec<T> {
+        let mut released = Vec::new();
+        while let Some(next_tracer) = self.next_tracer
+            && let Some(item) = self.held.remove(&next_tracer)
+        {
+            released.push(item);
+            self.next_tracer = Some(next_tracer + 1);
+        }
+        released
+    }
+
+    fn skip_gap(&mut self) -> Vec<T> {
+        if let Some((&first_held, _)) = self.held.first_key_value() {
+            self.next_tracer = Some(first_held);
+        }
+        self.release_consecutive()
+    }
+
+    fn update_gap(&mut self, progressed: bool, now: Instant) {
+        if self.held.is_empty() {
+            self.gap_since = None;
+        } else if progressed || self.gap_since.is_none() {
+            self.gap_since = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(window: usize) -> ReorderBuffer<u64> {
+        ReorderBuffer::new(&warp_config::ReorderingConfig {
+            window,
+            timeout: Duration::from_millis(50),
+        })
+    }
+
+    fn released(pushed: Pushed<u64>) -> Vec<u64> {
+        match pushed {
+            Pushed::Released(items) => items,
+            Pushed::Dropped(item) => panic!("{item} was dropped"),
+        }
+    }
+
+    #[test]
+    fn test_reorders_within_window() {
+        let now = Instant::now();
+        let mut buffer = buffer(8);
+        assert_eq!(released(buffer.push(10, 10, now)), vec![10]);
+        assert_eq!(released(buffer.push(12, 12, now)), Vec::<u64>::new());
+        assert_eq!(released(buffer.push(13, 13, now)), Vec::<u64>::new());
+        assert!(buffer.deadline().is_some());
+        assert_eq!(released(buffer.push(11, 11, now)), vec![11, 12, 13]);
+        assert!(buffer.deadline().is_none());
+    }
+
+    #[test]
+    fn test_drops_late_and_duplicate_payloads() {
+        let now = Instant::now();
+        let mut buffer = buffer(8);
+        released(buffer.push(0, 0, now));
+        released(buffer.push(2, 2, now));
+        assert!(matches!(buffer.push(0, 0, now), Pushed::Dropped(0)));
+        assert!(matches!(buffer.push(2, 2, now), Pushed::Dropped(2)));
+    }
+
+    #[test]
+    fn test_full_window_skips_gap() {
+        let now = Instant::now();
+        let mut buffer = buffer(3);
+        released(buffer.push(0, 0, now));
+        assert_eq!(released(buffer.push(2, 2, now)), Vec::<u64>::new());
+        assert_eq!(released(buffer.push(3, 3, now)), Vec::<u64>::new());
+        assert_eq!(released(buffer.push(5, 5, now)), Vec::<u64>::new());
+        assert_eq!(released(buffer.push(6, 6, now)), vec![2, 3]);
+        // 1 was given up on
+        assert!(matches!(buffer.push(1, 1, now), Pushed::Dropped(1)));
+    }
+
+    #[test]
+    fn test_timeout_skips_gap() {
+        let start = Instant::now();
+        let mut buffer = buffer(8);
+        released(buffer.push(0, 0, start));
+        released(buffer.push(2, 2, start));
+        released(buffer.push(4, 4, start));
+        assert_eq!(buffer.expire(start + Duration::from_millis(10)), Vec::<u64>::new());
+
+        let first_timeout = start + Duration::from_millis(50);
+        assert_eq!(buffer.deadline(), Some(first_timeout));
+        assert_eq!(buffer.expire(first_timeout), vec![2]);
+        // Waiting for 3 only started when 2 was released
+        assert_eq!(buffer.deadline(), Some(first_timeout + Duration::from_millis(50)));
+        assert_eq!(buffer.expire(first_timeout + Duration::from_millis(50)), vec![4]);
+        assert_eq!(buffer.deadline(), None);
+    }
+
+    #[test]
+    fn test_far_gate_restart_resets_sequence() {
+        let now = Instant::now();
+        let mut buffer = buffer(4);
+        released(buffer.push(100, 100, now));
+        released(buffer.push(102, 102, now));
+        assert_eq!(released(buffer.push(0, 0, now)), vec![102, 0]);
+        assert_eq!(released(buffer.push(1, 1, now)), vec![1]);
+    }
+}
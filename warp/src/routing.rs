@@ -1,21 +1,356 @@
+pub(crate) type PeerAddresses = std::collections::BTreeMap<warp_protocol::PublicKey, Vec<std::net::SocketAddr>>;
+// The address to send to instead of each (interface name, peer address) pair, learned from PeerAddressOverrides
+pub(crate) type AddressOverrides = std::collections::HashMap<(String, std::net::SocketAddr), std::net::SocketAddr>;
+
+// Paths that haven't been measured yet are assumed to be this fast, so they get tried ahead of paths known to be slow
+const UNMEASURED_RTT: std::time::Duration = std::time::Duration::from_millis(100);
+// Roughly how long each datagram already queued on an interface holds up a new one
+const QUEUED_DATAGRAM_DELAY: std::time::Duration = std::time::Duration::from_micros(100);
+// How much each new measurement moves a path's smoothed RTT and loss
+const SMOOTHING: f64 = 0.125;
+// Keeps the cost of a path that has lost everything finite, so such paths are still ordered by latency
+const MIN_DELIVERY_RATE: f64 = 0.01;
+// A far gate can only move an override to another address this long after it last did, so that it can't keep
+// redirecting our traffic
+const MIN_OVERRIDE_CHANGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+// How many addresses peer_at remembers the far gate of from decrypting their messages; all are forgotten once there are
+// more, and relearned as messages come in
+const MAX_LEARNED_ADDRESSES: usize = 4096;
+
+/// How well a path, from one of our interfaces to one peer address, has been delivering
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PathStats {
+    /// Smoothed round trip time; `None` until the path has been measured
+    pub rtt: Option<std::time::Duration>,
+    /// Smoothed fraction of datagrams lost, from 0 to 1
+    pub loss: f64,
+}
+
+impl PathStats {
+    pub fn record_rtt(&mut self, rtt: std::time::Duration) {
+        self.rtt = Some(match self.rtt {
+            None => rtt,
+            Some(smoothed) => smoothed.mul_f64(1.0 - SMOOTHING) + rtt.mul_f64(SMOOTHING),
+        });
+    }
+
+    pub fn record_delivery(&mut self, delivered: bool) {
+        let lost = if delivered { 0.0 } else { 1.0 };
+        self.loss = self.loss * (1.0 - SMOOTHING) + lost * SMOOTHING;
+    }
+
+    /// Expected seconds for a datagram to get through when `queue_depth` datagrams are ahead of it, counting resends
+    /// for losses; lower is better
+    pub fn cost(&self, queue_depth: usize) -> f64 {
+        let delay =
+            self.rtt.unwrap_or(UNMEASURED_RTT).as_secs_f64() + QUEUED_DATAGRAM_DELAY.as_secs_f64() * queue_depth as f64;
+        delay / (1.0 - self.loss).max(MIN_DELIVERY_RATE)
+    }
+}
+
+/// Path probes that are waiting for their replies, each sent over a path `P`
+struct PathProbes<P> {
+    next_sequence: u64,
+    pending: std::collections::HashMap<u64, (P, std::time::Instant)>,
+}
+
+impl<P> PathProbes<P> {
+    fn new() -> Self {
+        Self {
+            next_sequence: 0,
+            pending: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Remember that a probe is being sent over `path`, returning its sequence number
+    fn sent(&mut self, path: P, now: std::time::Instant) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.pending.insert(sequence, (path, now));
+        sequence
+    }
+
+    /// The path the probe was sent over and its round trip time, unless it was unknown or already expired
+    fn answered(&mut self, sequence: u64, now: std::time::Instant) -> Option<(P, std::time::Duration)> {
+        self.pending
+            .remove(&sequence)
+            .map(|(path, sent)| (path, now.saturating_duration_since(sent)))
+    }
+
+    /// Give up on the probes sent `timeout` or longer ago, returning their paths
+    fn expire(&mut self, timeout: std::time::Duration, now: std::time::Instant) -> Vec<P> {
+        let expired: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, sent))| now.saturating_duration_since(*sent) >= timeout)
+            .map(|(sequence, _)| *sequence)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|sequence| self.pending.remove(&sequence))
+            .map(|(path, _sent)| path)
+            .collect()
+    }
+}
+
+/// A change in whether a peer, or one of its addresses, is alive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LivenessChange {
+    Address {
+        peer: warp_protocol::PublicKey,
+        address: std::net::SocketAddr,
+        alive: bool,
+    },
+    Peer {
+        peer: warp_protocol::PublicKey,
+        alive: bool,
+    },
+}
+
+struct AddressLiveness {
+    // When a message last came from the address, or when it became known if none has yet
+    heard: std::time::Instant,
+    alive: bool,
+    // Whether any message has come from the address yet
+    confirmed: bool,
+}
+
+/// Whether each peer address has been heard from lately; addresses and peers start out alive
+#[derive(Default)]
+struct Liveness {
+    addresses: std::collections::BTreeMap<(warp_protocol::PublicKey, std::net::SocketAddr), AddressLiveness>,
+    // When a message relayed through warp-map last came from each peer
+    relayed: std::collections::BTreeMap<warp_protocol::PublicKey, std::time::Instant>,
+    peers: std::collections::BTreeMap<warp_protocol::PublicKey, bool>,
+}
+
+impl Liveness {
+    fn heard_from(&mut self, peer: warp_protocol::PublicKey, address: std::net::SocketAddr, now: std::time::Instant) {
+        let liveness = self.addresses.entry((peer, address)).or_insert(AddressLiveness {
+            heard: now,
+            alive: true,
+            confirmed: true,
+        });
+        liveness.heard = now;
+        liveness.confirmed = true;
+    }
+
+    fn heard_via_relay(&mut self, peer: warp_protocol::PublicKey, now: std::time::Instant) {
+        self.relayed.insert(peer, now);
+    }
+
+    /// When any of `peer`'s addresses was last heard from, or became known if none has been yet
+    fn last_heard_directly(&self, peer: &warp_protocol::PublicKey) -> Option<std::time::Instant> {
+        self.addresses
+            .iter()
+            .filter(|((address_peer, _), _)| address_peer == peer)
+            .map(|(_, liveness)| liveness.heard)
+            .max()
+    }
+
+    /// Track the `known` peer addresses, forgetting any others, and mark those not heard from for `timeout` as dead;
+    /// returns what changed
+    fn update(
+        &mut self,
+        known: &std::collections::BTreeSet<(warp_protocol::PublicKey, std::net::SocketAddr)>,
+        timeout: std::time::Duration,
+        now: std::time::Instant,
+    ) -> Vec<LivenessChange> {
+        self.addresses.retain(|key, _| known.contains(key));
+        for key in known {
+            self.addresses.entry(*key).or_insert(AddressLiveness {
+                heard: now,
+                alive: true,
+                confirmed: false,
+            });
+        }
+
+        let mut changes = Vec::new();
+        let mut peers = std::collections::BTreeMap::new();
+        for (&(peer, address), liveness) in &mut self.addresses {
+            let alive = now.saturating_duration_since(liveness.heard) < timeout;
+            if alive != liveness.alive {
+                liveness.alive = alive;
+                changes.push(LivenessChange::Address { peer, address, alive });
+            }
+            *peers.entry(peer).or_insert(false) |= alive;
+        }
+        // Relayed messages keep a peer alive, though none of its addresses
+        for (peer, heard) in &self.relayed {
+            if let Some(alive) = peers.get_mut(peer) {
+                *alive |= now.saturating_duration_since(*heard) < timeout;
+            }
+        }
+        for (&peer, &alive) in &peers {
+            if self.peers.get(&peer).copied().unwrap_or(true) != alive {
+                changes.push(LivenessChange::Peer { peer, alive });
+            }
+        }
+        self.peers = peers;
+        changes
+    }
+
+    fn is_alive(&self, peer: &warp_protocol::PublicKey, address: std::net::SocketAddr) -> bool {
+        self.addresses
+            .get(&(*peer, address))
+            .is_none_or(|liveness| liveness.alive)
+    }
+
+    fn is_confirmed(&self, peer: &warp_protocol::PublicKey, address: std::net::SocketAddr) -> bool {
+        self.addresses
+            .get(&(*peer, address))
+            .is_some_and(|liveness| liveness.alive && liveness.confirmed)
+    }
+}
+
+/// Why a PeerAddressOverride was ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverrideRejection {
+    /// The address to replace isn't one of the sender's, as warp-map reported them
+    UnknownAddress,
+    /// It came from an address that can't be sent to
+    InvalidSource,
+    /// An override sent after it has already been applied
+    Stale,
+    /// The override was moved to another address too recently to move again
+    RateLimited,
+}
+
+impl std::fmt::Display for OverrideRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::UnknownAddress => "not an address of the far gate",
+            Self::InvalidSource => "invalid source address",
+            Self::Stale => "older than the override in use",
+            Self::RateLimited => "changed too recently",
+        })
+    }
+}
+
+// How the override of an (interface name, peer address) pair came about, to judge the next one by
+struct OverrideRecord {
+    // The highest sequence seen in an override for the pair
+    sequence: u64,
+    changed_at: std::time::Instant,
+    // When the far gate last sent the override, whether or not it changed
+    refreshed_at: std::time::Instant,
+}
+
+/// Which paths a tunnel's payloads may go over, and how many of them
+#[derive(Clone, Debug)]
+pub struct RoutePolicy {
+    pub selection: warp_config::PathSelection,
+    /// Whether metered interfaces may be used while there are unmetered ones
+    pub metered: bool,
+    /// The interfaces to keep to while any of them can be used
+    pub affinity: Option<warp_config::InterfacePatterns>,
+}
+
+impl RoutePolicy {
+    /// The policy of a tunnel with `transport` and `priority`
+    pub fn for_tunnel(transport: &warp_config::WarpTransportConfig, priority: u8) -> Self {
+        Self {
+            selection: transport.path_selection.unwrap_or_default(),
+            metered: transport.metered.unwrap_or(priority > 0),
+            affinity: transport.interfaces.clone(),
+        }
+    }
+
+    /// Any path, metered or not, picked by `selection`, for messages that aren't any tunnel's
+    pub fn any(selection: warp_config::PathSelection) -> Self {
+        Self {
+            selection,
+            metered: true,
+            affinity: None,
+        }
+    }
+}
+
+/// Leave only the candidates that are `preferred`, unless none of them are
+fn prefer<T>(candidates: &mut Vec<T>, preferred: impl Fn(&T) -> bool) {
+    if candidates.iter().any(&preferred) {
+        candidates.retain(preferred);
+    }
+}
+
+/// The candidates `policy` picks given their costs: first the interface's cost of using it, then the path's expected
+/// latency. When it picks them all they stay in their original order
+fn select_paths<T>(mut candidates: Vec<(T, (u32, f64))>, policy: warp_config::PathSelection) -> Vec<T> {
+    let count = match policy {
+        warp_config::PathSelection::Best => 1,
+        warp_config::PathSelection::Redundant(n) => n.max(1),
+        warp_config::PathSelection::All => candidates.len(),
+    };
+    if count < candidates.len() {
+        candidates.sort_by(|(_, (a_cost, a_latency)), (_, (b_cost, b_latency))| {
+            a_cost.cmp(b_cost).then(a_latency.total_cmp(b_latency))
+        });
+        candidates.truncate(count);
+    }
+    candidates.into_iter().map(|(candidate, _cost)| candidate).collect()
+}
+
+/// The paths among `candidates` that `policy` picks, keeping to the interfaces it prefers while it can
+fn apply_policy(
+    mut candidates: Vec<(std::sync::Arc<crate::interface::NetworkInterface>, std::net::SocketAddr)>,
+    policy: &RoutePolicy,
+) -> Vec<(std::sync::Arc<crate::interface::NetworkInterface>, std::net::SocketAddr)> {
+    if let Some(affinity) = &policy.affinity {
+        prefer(&mut candidates, |(interface, _)| affinity.is_match(&interface.id.name));
+    }
+    if !policy.metered {
+        prefer(&mut candidates, |(interface, _)| !interface.is_metered());
+    }
+    let candidates = candidates
+        .into_iter()
+        .map(|(interface, address)| {
+            let cost = (
+                interface.link_cost(),
+                interface.path_stats(address).cost(interface.queue_depth()),
+            );
+            ((interface, address), cost)
+        })
+        .collect();
+    select_paths(candidates, policy.selection)
+}
+
 pub(crate) struct RoutingState {
     interfaces_tx: tokio::sync::watch::Sender<Vec<std::sync::Arc<crate::interface::NetworkInterface>>>,
     interfaces_watch: tokio::sync::watch::Receiver<Vec<std::sync::Arc<crate::interface::NetworkInterface>>>,
 
-    peer_addresses_tx: tokio::sync::watch::Sender<Vec<std::net::SocketAddr>>,
-    peer_addresses_watch: tokio::sync::watch::Receiver<Vec<std::net::SocketAddr>>,
+    // Each far gate's addresses, as reported by warp-map
+    peer_addresses_tx: tokio::sync::watch::Sender<PeerAddresses>,
+    peer_addresses_watch: tokio::sync::watch::Receiver<PeerAddresses>,
+    // The host candidates each far gate registered with warp-map, sent to directly from interfaces on the same network
+    peer_host_candidates: std::sync::Mutex<PeerAddresses>,
+
+    address_overrides_tx: tokio::sync::watch::Sender<AddressOverrides>,
+    address_overrides_watch: tokio::sync::watch::Receiver<AddressOverrides>,
+    override_records: std::sync::Mutex<std::collections::HashMap<(String, std::net::SocketAddr), OverrideRecord>>,
+
+    path_probes:
+        std::sync::Mutex<PathProbes<(std::sync::Arc<crate::interface::NetworkInterface>, std::net::SocketAddr)>>,
+
+    liveness: std::sync::Mutex<Liveness>,
+
+    // The far gate whose messages last came from each address, as found by decrypting them
+    learned_peers: std::sync::Mutex<std::collections::HashMap<std::net::SocketAddr, warp_protocol::PublicKey>>,
 
-    address_overrides_tx:
-        tokio::sync::watch::Sender<std::collections::HashMap<(String, std::net::SocketAddr), std::net::SocketAddr>>,
-    address_overrides_watch:
-        tokio::sync::watch::Receiver<std::collections::HashMap<(String, std::net::SocketAddr), std::net::SocketAddr>>,
+    // The far gates that messages go to through warp-map's relay
+    relayed_peers: std::sync::Mutex<std::collections::BTreeSet<warp_protocol::PublicKey>>,
+    // Whether warp-map last said each far gate was registered, for those it has said so about
+    peer_statuses:
+        std::sync::Mutex<std::collections::BTreeMap<warp_protocol::PublicKey, warp_protocol::messages::PeerStatus>>,
+
+    // What each interface has sent and received, by name, kept when it goes away in case it comes back
+    traffic: std::sync::Mutex<std::collections::BTreeMap<String, std::sync::Arc<crate::traffic::InterfaceTraffic>>>,
 }
 
 impl RoutingState {
     /// Create a new PacketRoutingState with empty initial state
     pub fn new() -> Self {
         let (interfaces_tx, interfaces_watch) = tokio::sync::watch::channel(Vec::new());
-        let (peer_addresses_tx, peer_addresses_watch) = tokio::sync::watch::channel(Vec::new());
+        let (peer_addresses_tx, peer_addresses_watch) = tokio::sync::watch::channel(PeerAddresses::new());
         let (address_overrides_tx, address_overrides_watch) =
             tokio::sync::watch::channel(std::collections::HashMap::new());
 
@@ -26,21 +361,61 @@ impl RoutingState {
             interfaces_tx,
             peer_addresses_tx,
             address_overrides_tx,
+            peer_host_candidates: std::sync::Mutex::new(PeerAddresses::new()),
+            override_records: std::sync::Mutex::new(std::collections::HashMap::new()),
+            path_probes: std::sync::Mutex::new(PathProbes::new()),
+            liveness: std::sync::Mutex::new(Liveness::default()),
+            learned_peers: std::sync::Mutex::new(std::collections::HashMap::new()),
+            relayed_peers: std::sync::Mutex::new(std::collections::BTreeSet::new()),
+            peer_statuses: std::sync::Mutex::new(std::collections::BTreeMap::new()),
+            traffic: std::sync::Mutex::new(std::collections::BTreeMap::new()),
         }
     }
 
+    /// Where the interface called `name` counts its traffic, carrying on from an interface of the same name before it
+    pub fn interface_traffic(&self, name: &str) -> std::sync::Arc<crate::traffic::InterfaceTraffic> {
+        self.traffic
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// What each interface has sent and received since warp started, by interface name
+    pub fn traffic(&self) -> std::collections::BTreeMap<String, crate::traffic::TrafficReport> {
+        self.traffic
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, traffic)| (name.clone(), traffic.report()))
+            .collect()
+    }
+
     pub fn interfaces(&self) -> tokio::sync::watch::Ref<'_, Vec<std::sync::Arc<crate::interface::NetworkInterface>>> {
         self.interfaces_watch.borrow()
     }
 
-    /// Update the peer addresses from warp-map
-    pub fn handle_mapping_response(&self, mapping: &warp_protocol::messages::MappingResponse) {
-        self.peer_addresses_tx.send_replace(mapping.endpoints.clone());
+    /// Update a peer's addresses and host candidates from warp-map; returns the peer's status if it has changed
+    pub fn handle_mapping_response(
+        &self,
+        mapping: &warp_protocol::messages::MappingResponse,
+    ) -> Option<warp_protocol::messages::PeerStatus> {
+        let changed_status = mapping
+            .peer_status
+            .filter(|&status| self.peer_statuses.lock().unwrap().insert(mapping.peer_pubkey, status) != Some(status));
+        self.peer_host_candidates
+            .lock()
+            .unwrap()
+            .insert(mapping.peer_pubkey, mapping.host_candidates.clone());
+        self.peer_addresses_tx.send_modify(|peer_addresses| {
+            peer_addresses.insert(mapping.peer_pubkey, mapping.endpoints.clone());
+        });
 
-        // Clean up stale override mappings - remove overrides for addresses no longer in peer list
+        // Clean up stale override mappings - remove overrides for addresses no longer in any peer's list
         self.address_overrides_tx.send_modify(|overrides| {
             let valid_addresses: std::collections::HashSet<std::net::SocketAddr> =
-                mapping.endpoints.iter().copied().collect();
+                self.peer_addresses_watch.borrow().values().flatten().copied().collect();
 
             overrides.retain(|(_interface_name, replace_addr), _mapped_addr| {
                 let should_keep = valid_addresses.contains(replace_addr);
@@ -49,37 +424,371 @@ impl RoutingState {
                 }
                 should_keep
             });
+            self.override_records
+                .lock()
+                .unwrap()
+                .retain(|key, _| overrides.contains_key(key));
         });
+
+        // Forget paths to addresses that no peer can be reached at any more
+        let reachable: std::collections::HashSet<std::net::SocketAddr> = self
+            .peer_addresses_watch
+            .borrow()
+            .values()
+            .flatten()
+            .copied()
+            .chain(self.address_overrides_watch.borrow().values().copied())
+            .chain(self.peer_host_candidates.lock().unwrap().values().flatten().copied())
+            .collect();
+        for interface in self.interfaces().iter() {
+            interface.retain_paths(|address| reachable.contains(address));
+        }
+        changed_status
+    }
+
+    /// Whether warp-map last said `peer` wasn't registered, in which case it has nowhere to pass relayed messages on to
+    pub fn is_unregistered(&self, peer: &warp_protocol::PublicKey) -> bool {
+        self.peer_statuses
+            .lock()
+            .unwrap()
+            .get(peer)
+            .is_some_and(|status| *status != warp_protocol::messages::PeerStatus::Registered)
     }
 
     /// Apply address overrides to resolve the final destination addresses
     ///
     /// This method takes the base peer addresses and applies any interface-specific
-    /// overrides to handle symmetric NAT scenarios correctly.
-    pub fn resolve_peer_addresses(&self, outbound_interface_name: &str) -> Vec<std::net::SocketAddr> {
+    /// overrides to handle symmetric NAT scenarios correctly. The peer's direct candidates for the interface (see
+    /// [`Self::direct_candidates`]) come first.
+    pub fn resolve_peer_addresses(
+        &self,
+        peer: &warp_protocol::PublicKey,
+        outbound_interface: &crate::interface::NetworkInterfaceId,
+        external_address: Option<std::net::SocketAddr>,
+    ) -> Vec<std::net::SocketAddr> {
+        let mut addresses = self.direct_candidates(peer, outbound_interface, external_address);
+
         let peer_addresses = self.peer_addresses_watch.borrow();
         let address_overrides = self.address_overrides_watch.borrow();
+        for addr in peer_addresses.get(peer).into_iter().flatten() {
+            // Look for override specific to this (interface, remote_address) pair
+            let override_key = (outbound_interface.name.clone(), *addr);
+            let address = address_overrides.get(&override_key).copied().unwrap_or(*addr);
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+        // warp-map may know the peer by IPv4 and IPv6 addresses, but an interface's socket only reaches its own family
+        addresses.retain(|address| address.is_ipv4() == outbound_interface.ip.is_ipv4());
+        addresses
+    }
 
-        peer_addresses
+    /// The peer's host candidates that `outbound_interface`, which warp-map sees at `external_address`, can reach
+    /// without going through NATs: those on its network, or all of them if the peer registered from the same external
+    /// IP, being behind the same NAT
+    fn direct_candidates(
+        &self,
+        peer: &warp_protocol::PublicKey,
+        outbound_interface: &crate::interface::NetworkInterfaceId,
+        external_address: Option<std::net::SocketAddr>,
+    ) -> Vec<std::net::SocketAddr> {
+        let host_candidates = self.peer_host_candidates.lock().unwrap();
+        let Some(candidates) = host_candidates.get(peer) else {
+            return Vec::new();
+        };
+        let behind_same_nat = external_address.is_some_and(|external_address| {
+            self.peer_addresses_watch
+                .borrow()
+                .get(peer)
+                .is_some_and(|addresses| addresses.iter().any(|address| address.ip() == external_address.ip()))
+        });
+        candidates
             .iter()
-            .map(|addr| {
-                // Look for override specific to this (interface, remote_address) pair
-                let override_key = (outbound_interface_name.to_string(), *addr);
-                address_overrides.get(&override_key).copied().unwrap_or(*addr)
-            })
+            .filter(|candidate| behind_same_nat || outbound_interface.is_on_network(candidate.ip()))
+            .copied()
             .collect()
     }
 
-    /// This is used when receiving PeerAddressOverride messages to handle symmetric NAT holepunching
+    /// The paths to send a datagram for `peer` over, chosen by `policy` from every alive interface and peer address
+    ///
+    /// Peer addresses that have gone quiet are left out, unless all of them have: the peer may still hear us. Once a
+    /// direct candidate has been heard from, only direct candidates are used, keeping traffic between peers on the same
+    /// network off their NATs.
+    pub fn select_routes(
+        &self,
+        peer: &warp_protocol::PublicKey,
+        policy: &RoutePolicy,
+    ) -> Vec<(std::sync::Arc<crate::interface::NetworkInterface>, std::net::SocketAddr)> {
+        let mut candidates: Vec<_> = self
+            .interfaces()
+            .iter()
+            .filter(|interface| interface.is_alive())
+            .flat_map(|interface| {
+                let external_address = interface.get_external_address();
+                let direct = self.direct_candidates(peer, &interface.id, external_address);
+                self.resolve_peer_addresses(peer, &interface.id, external_address)
+                    .into_iter()
+                    .map(move |address| (interface.clone(), address, direct.contains(&address)))
+            })
+            .collect();
+        {
+            let liveness = self.liveness.lock().unwrap();
+            prefer(&mut candidates, |(_, address, _)| liveness.is_alive(peer, *address));
+            prefer(&mut candidates, |(_, address, direct)| {
+                *direct && liveness.is_confirmed(peer, *address)
+            });
+        }
+        let candidates = candidates
+            .into_iter()
+            .map(|(interface, address, _)| (interface, address))
+            .collect();
+        apply_policy(candidates, policy)
+    }
+
+    /// The paths to warp-map to relay messages over, chosen by `policy` from the interfaces registered with it, as
+    /// warp-map only relays for registered addresses
+    pub fn select_relay_routes(
+        &self,
+        relay_address: std::net::SocketAddr,
+        policy: &RoutePolicy,
+    ) -> Vec<(std::sync::Arc<crate::interface::NetworkInterface>, std::net::SocketAddr)> {
+        let candidates = self
+            .interfaces()
+            .iter()
+            .filter(|interface| interface.is_alive() && interface.get_external_address().is_some())
+            .map(|interface| (interface.clone(), relay_address))
+            .collect();
+        apply_policy(candidates, policy)
+    }
+
+    /// The average loss and round trip time of the paths `policy` picks for `peer`, or None if there are no paths
+    pub fn selected_path_stats(&self, peer: &warp_protocol::PublicKey, policy: &RoutePolicy) -> Option<PathStats> {
+        let stats: Vec<PathStats> = self
+            .select_routes(peer, policy)
+            .iter()
+            .map(|(interface, address)| interface.path_stats(*address))
+            .collect();
+        if stats.is_empty() {
+            return None;
+        }
+        let rtts: Vec<std::time::Duration> = stats.iter().filter_map(|stats| stats.rtt).collect();
+        Some(PathStats {
+            rtt: (!rtts.is_empty()).then(|| rtts.iter().sum::<std::time::Duration>() / rtts.len() as u32),
+            loss: stats.iter().map(|stats| stats.loss).sum::<f64>() / stats.len() as f64,
+        })
+    }
+
+    /// Start a probe of the path from `interface` to `address`, returning the sequence number to send it with
+    pub fn start_path_probe(
+        &self,
+        interface: &std::sync::Arc<crate::interface::NetworkInterface>,
+        address: std::net::SocketAddr,
+        now: std::time::Instant,
+    ) -> u64 {
+        self.path_probes.lock().unwrap().sent((interface.clone(), address), now)
+    }
+
+    /// Record the round trip time a probe reply measured, returning the path it was for and the round trip time
+    pub fn handle_path_probe_reply(
+        &self,
+        reply: &warp_protocol::messages::PathProbeReply,
+        now: std::time::Instant,
+    ) -> Option<(
+        std::sync::Arc<crate::interface::NetworkInterface>,
+        std::net::SocketAddr,
+        std::time::Duration,
+    )> {
+        let ((interface, address), rtt) = self.path_probes.lock().unwrap().answered(reply.sequence, now)?;
+        interface.record_path_rtt(address, rtt);
+        interface.record_path_delivery(address, true);
+        Some((interface, address, rtt))
+    }
+
+    /// Count the probes that have gone unanswered for `timeout` as lost, returning the paths they were sent over
+    pub fn expire_path_probes(
+        &self,
+        timeout: std::time::Duration,
+        now: std::time::Instant,
+    ) -> Vec<(std::sync::Arc<crate::interface::NetworkInterface>, std::net::SocketAddr)> {
+        let expired = self.path_probes.lock().unwrap().expire(timeout, now);
+        for (interface, address) in &expired {
+            interface.record_path_delivery(*address, false);
+        }
+        expired
+    }
+
+    /// Record that an authenticated message from `peer` came from `address`
+    pub fn heard_from(&self, peer: &warp_protocol::PublicKey, address: std::net::SocketAddr, now: std::time::Instant) {
+        self.liveness.lock().unwrap().heard_from(*peer, address, now);
+    }
+
+    /// Record that an authenticated message from `peer` was relayed to us through warp-map
+    pub fn heard_via_relay(&self, peer: &warp_protocol::PublicKey, now: std::time::Instant) {
+        self.liveness.lock().unwrap().heard_via_relay(*peer, now);
+    }
+
+    /// Relay to the `peers` none of whose addresses has been heard from for `timeout`, and stop relaying to those one
+    /// of whose addresses has; nothing is relayed without a timeout. Returns the peers that changed, with whether they
+    /// are now relayed to.
+    ///
+    /// Peers without any addresses are relayed to straight away, since there is no direct path to wait for, unless warp-map
+    /// has said they aren't registered; those are only relayed to once they register again.
+    pub fn update_relays(
+        &self,
+        peers: &[warp_protocol::PublicKey],
+        timeout: Option<std::time::Duration>,
+        now: std::time::Instant,
+    ) -> Vec<(warp_protocol::PublicKey, bool)> {
+        let relayed: std::collections::BTreeSet<warp_protocol::PublicKey> = match timeout {
+            None => std::collections::BTreeSet::new(),
+            Some(timeout) => {
+                let liveness = self.liveness.lock().unwrap();
+                peers
+                    .iter()
+                    .filter(|peer| {
+                        liveness
+                            .last_heard_directly(peer)
+                            .is_none_or(|heard| now.saturating_duration_since(heard) >= timeout)
+                    })
+                    .filter(|peer| !self.is_unregistered(peer))
+                    .copied()
+                    .collect()
+            }
+        };
+
+        let mut relayed_peers = self.relayed_peers.lock().unwrap();
+        let changes = relayed
+            .symmetric_difference(&relayed_peers)
+            .map(|peer| (*peer, relayed.contains(peer)))
+            .collect();
+        *relayed_peers = relayed;
+        changes
+    }
+
+    /// Whether messages for `peer` go through warp-map's relay
+    pub fn is_relayed(&self, peer: &warp_protocol::PublicKey) -> bool {
+        self.relayed_peers.lock().unwrap().contains(peer)
+    }
+
+    /// Mark the peer addresses that haven't been heard from for `timeout` as dead, and those that have as alive again,
+    /// returning the addresses and peers that changed
+    pub fn update_liveness(&self, timeout: std::time::Duration, now: std::time::Instant) -> Vec<LivenessChange> {
+        let peers: Vec<warp_protocol::PublicKey> = self.peer_addresses_watch.borrow().keys().copied().collect();
+        let interfaces: Vec<_> = self
+            .interfaces()
+            .iter()
+            .map(|interface| (interface.id.clone(), interface.get_external_address()))
+            .collect();
+        let known = peers
+            .iter()
+            .flat_map(|peer| {
+                interfaces
+                    .iter()
+                    .flat_map(|(id, external_address)| self.resolve_peer_addresses(peer, id, *external_address))
+                    .map(|address| (*peer, address))
+            })
+            .collect();
+        self.liveness.lock().unwrap().update(&known, timeout, now)
+    }
+
+    /// The peer that datagrams from `address` most likely come from: the one whose messages were last decrypted from it,
+    /// or else going by warp-map and address overrides
+    pub fn peer_at(&self, address: std::net::SocketAddr) -> Option<warp_protocol::PublicKey> {
+        if let Some(peer) = self.learned_peers.lock().unwrap().get(&address) {
+            return Some(*peer);
+        }
+
+        let peer_addresses = self.peer_addresses_watch.borrow();
+        let address_overrides = self.address_overrides_watch.borrow();
+
+        // An override maps an address warp-map reported to the one the peer's datagrams actually come from
+        let reported_address = address_overrides
+            .iter()
+            .find(|(_, override_address)| **override_address == address)
+            .map(|((_interface_name, replace_addr), _)| *replace_addr)
+            .unwrap_or(address);
+
+        if let Some((peer, _)) = peer_addresses
+            .iter()
+            .find(|(_, addresses)| addresses.contains(&reported_address))
+        {
+            return Some(*peer);
+        }
+
+        // Peers on the same network send from their host candidates
+        self.peer_host_candidates
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, candidates)| candidates.contains(&address))
+            .map(|(peer, _)| *peer)
+    }
+
+    /// Record that a message from `address` decrypted as `peer`'s, though [`Self::peer_at`] didn't expect it to, so that
+    /// later ones are tried against `peer` first; several far gates may share one address over time, or be behind NATs
+    /// that warp-map hasn't seen their datagrams come through
+    pub fn learn_peer_at(&self, address: std::net::SocketAddr, peer: warp_protocol::PublicKey) {
+        let mut learned_peers = self.learned_peers.lock().unwrap();
+        if learned_peers.len() >= MAX_LEARNED_ADDRESSES && !learned_peers.contains_key(&address) {
+            learned_peers.clear();
+        }
+        learned_peers.insert(address, peer);
+    }
+
+    /// Send to `from` instead of the address `override_msg` replaces when sending from the interface called
+    /// `interface_name`, as `peer` asked; this is how peers behind symmetric NATs are reached
+    ///
+    /// The override is ignored if the address isn't one of the peer's, if a later override has already been applied, or
+    /// if it would move the pair's override again within MIN_OVERRIDE_CHANGE_INTERVAL.
     pub fn handle_peer_address_override(
         &self,
+        peer: &warp_protocol::PublicKey,
         override_msg: &warp_protocol::messages::PeerAddressOverride,
         from: std::net::SocketAddr,
         interface_name: &str,
-    ) {
+        now: std::time::Instant,
+    ) -> Result<(), OverrideRejection> {
+        if !self
+            .peer_addresses_watch
+            .borrow()
+            .get(peer)
+            .is_some_and(|addresses| addresses.contains(&override_msg.replace))
+        {
+            return Err(OverrideRejection::UnknownAddress);
+        }
+        if from.port() == 0 || from.ip().is_unspecified() || from.ip().is_multicast() {
+            return Err(OverrideRejection::InvalidSource);
+        }
+
+        let key = (interface_name.to_string(), override_msg.replace);
+        let mut records = self.override_records.lock().unwrap();
+        let current = self.address_overrides_watch.borrow().get(&key).copied();
+        if let (Some(record), Some(current)) = (records.get(&key), current) {
+            // Peers that don't send a sequence can't be checked for order
+            let sequenced = override_msg.sequence != 0 && record.sequence != 0;
+            if sequenced
+                && (override_msg.sequence < record.sequence
+                    || (override_msg.sequence == record.sequence && current != from))
+            {
+                return Err(OverrideRejection::Stale);
+            }
+            if current != from && now.saturating_duration_since(record.changed_at) < MIN_OVERRIDE_CHANGE_INTERVAL {
+                return Err(OverrideRejection::RateLimited);
+            }
+        }
+        let record = records.entry(key.clone()).or_insert(OverrideRecord {
+            sequence: 0,
+            changed_at: now,
+            refreshed_at: now,
+        });
+        record.sequence = record.sequence.max(override_msg.sequence);
+        record.refreshed_at = now;
+        if current != Some(from) {
+            record.changed_at = now;
+        }
+
         self.address_overrides_tx.send_modify(|overrides| {
-            let key = (interface_name.to_string(), override_msg.replace);
-            let old_mapping = overrides.insert(key.clone(), from);
+            let old_mapping = overrides.insert(key, from);
 
             if let Some(old_address_override) = old_mapping {
                 if old_address_override != from {
@@ -100,6 +809,60 @@ impl RoutingState {
                 );
             }
         });
+        Ok(())
+    }
+
+    /// Drop the overrides that their far gates haven't sent again for `ttl`, as the NAT bindings they pointed at have
+    /// likely gone; returns each interface name, replaced address and overriding address dropped
+    pub fn expire_overrides(
+        &self,
+        ttl: std::time::Duration,
+        now: std::time::Instant,
+    ) -> Vec<(String, std::net::SocketAddr, std::net::SocketAddr)> {
+        let mut records = self.override_records.lock().unwrap();
+        let mut expired = Vec::new();
+        self.address_overrides_tx.send_if_modified(|overrides| {
+            overrides.retain(|key, address| {
+                let fresh = records
+                    .get(key)
+                    .is_some_and(|record| now.saturating_duration_since(record.refreshed_at) < ttl);
+                if !fresh {
+                    expired.push((key.0.clone(), key.1, *address));
+                }
+                fresh
+            });
+            records.retain(|key, _| overrides.contains_key(key));
+            !expired.is_empty()
+        });
+        expired
+    }
+
+    /// Each far gate's addresses and the address overrides, to be saved across restarts
+    pub fn learned_addresses(&self) -> (PeerAddresses, AddressOverrides) {
+        (
+            self.peer_addresses_watch.borrow().clone(),
+            self.address_overrides_watch.borrow().clone(),
+        )
+    }
+
+    /// Start from addresses saved by an earlier run, until warp-map and the far gates say otherwise
+    ///
+    /// The overrides count as refreshed now, so they expire unless their far gates send them again.
+    pub fn restore_addresses(&self, peer_addresses: PeerAddresses, address_overrides: AddressOverrides) {
+        let now = std::time::Instant::now();
+        *self.override_records.lock().unwrap() = address_overrides
+            .keys()
+            .map(|key| {
+                let record = OverrideRecord {
+                    sequence: 0,
+                    changed_at: now,
+                    refreshed_at: now,
+                };
+                (key.clone(), record)
+            })
+            .collect();
+        self.peer_addresses_tx.send_replace(peer_addresses);
+        self.address_overrides_tx.send_replace(address_overrides);
     }
 
     /// Get the number of active address overrides (for logging/debugging)
@@ -120,3 +883,520 @@ impl Default for RoutingState {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use warp_config::PathSelection;
+
+    fn peer() -> warp_protocol::PublicKey {
+        warp_protocol::PrivateKey::random(&mut rand::rng()).public_key()
+    }
+
+    fn eth0() -> crate::interface::NetworkInterfaceId {
+        crate::interface::NetworkInterfaceId {
+            name: "eth0".to_string(),
+            ip: "192.168.1.10".parse().unwrap(),
+            prefix_len: 24,
+        }
+    }
+
+    fn mapping(peer_pubkey: warp_protocol::PublicKey, endpoints: &[&str]) -> warp_protocol::messages::MappingResponse {
+        warp_protocol::messages::MappingResponse {
+            peer_pubkey,
+            endpoints: endpoints.iter().map(|endpoint| endpoint.parse().unwrap()).collect(),
+            timestamp: std::time::SystemTime::now(),
+            host_candidates: Vec::new(),
+            peer_status: None,
+        }
+    }
+
+    #[test]
+    fn test_peer_addresses_are_tracked_per_peer() {
+        let routing_state = RoutingState::new();
+        let (a, b) = (peer(), peer());
+        routing_state.handle_mapping_response(&mapping(a, &["1.1.1.1:1000"]));
+        routing_state.handle_mapping_response(&mapping(b, &["2.2.2.2:2000", "2.2.2.3:2000"]));
+
+        assert_eq!(
+            routing_state.resolve_peer_addresses(&a, &eth0(), None),
+            vec!["1.1.1.1:1000".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(routing_state.resolve_peer_addresses(&b, &eth0(), None).len(), 2);
+        assert_eq!(routing_state.peer_at("2.2.2.3:2000".parse().unwrap()), Some(b));
+        assert_eq!(routing_state.peer_at("3.3.3.3:3000".parse().unwrap()), None);
+
+        // A new mapping for one peer leaves the other's addresses alone
+        routing_state.handle_mapping_response(&mapping(a, &["1.1.1.2:1000"]));
+        assert_eq!(routing_state.resolve_peer_addresses(&b, &eth0(), None).len(), 2);
+        assert_eq!(routing_state.peer_at("1.1.1.1:1000".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_only_addresses_of_the_interface_family_are_used() {
+        let routing_state = RoutingState::new();
+        let a = peer();
+        routing_state.handle_mapping_response(&mapping(a, &["1.1.1.1:1000", "[2001:db8::1]:1000"]));
+
+        assert_eq!(
+            routing_state.resolve_peer_addresses(&a, &eth0(), None),
+            vec!["1.1.1.1:1000".parse::<SocketAddr>().unwrap()]
+        );
+        let eth0_v6 = crate::interface::NetworkInterfaceId {
+            name: "eth0".to_string(),
+            ip: "2001:db8:1::2".parse().unwrap(),
+            prefix_len: 64,
+        };
+        assert_eq!(
+            routing_state.resolve_peer_addresses(&a, &eth0_v6, None),
+            vec!["[2001:db8::1]:1000".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_direct_candidates_come_first() {
+        let routing_state = RoutingState::new();
+        let a = peer();
+        let (lan_address, other_lan_address): (SocketAddr, SocketAddr) =
+            ("192.168.1.2:4000".parse().unwrap(), "10.0.0.2:4000".parse().unwrap());
+        let mut response = mapping(a, &["1.1.1.1:1000"]);
+        response.host_candidates = vec![lan_address, other_lan_address];
+        routing_state.handle_mapping_response(&response);
+
+        // Only candidates on the interface's network are direct...
+        let public_address: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+        assert_eq!(
+            routing_state.resolve_peer_addresses(&a, &eth0(), None),
+            vec![lan_address, public_address]
+        );
+        // ...unless the peer is behind the same NAT, going by the address warp-map sees
+        assert_eq!(
+            routing_state.resolve_peer_addresses(&a, &eth0(), Some("1.1.1.1:2000".parse().unwrap())),
+            vec![lan_address, other_lan_address, public_address]
+        );
+        assert_eq!(
+            routing_state.resolve_peer_addresses(&a, &eth0(), Some("3.3.3.3:2000".parse().unwrap())),
+            vec![lan_address, public_address]
+        );
+        assert_eq!(routing_state.peer_at(lan_address), Some(a));
+
+        // A candidate is only preferred once it has been heard from
+        let start = std::time::Instant::now();
+        routing_state.liveness.lock().unwrap().update(
+            &[(a, lan_address), (a, public_address)].into_iter().collect(),
+            Duration::from_secs(10),
+            start,
+        );
+        assert!(!routing_state.liveness.lock().unwrap().is_confirmed(&a, lan_address));
+        routing_state.heard_from(&a, lan_address, start);
+        assert!(routing_state.liveness.lock().unwrap().is_confirmed(&a, lan_address));
+    }
+
+    #[test]
+    fn test_peer_at_prefers_learned_peers() {
+        let routing_state = RoutingState::new();
+        let (a, b) = (peer(), peer());
+        routing_state.handle_mapping_response(&mapping(a, &["1.1.1.1:1000"]));
+        routing_state.handle_mapping_response(&mapping(b, &["2.2.2.2:2000"]));
+
+        // b's messages started coming from behind a NAT warp-map hasn't seen, and from an address a used to have
+        routing_state.learn_peer_at("3.3.3.3:3000".parse().unwrap(), b);
+        routing_state.learn_peer_at("1.1.1.1:1000".parse().unwrap(), b);
+        assert_eq!(routing_state.peer_at("3.3.3.3:3000".parse().unwrap()), Some(b));
+        assert_eq!(routing_state.peer_at("1.1.1.1:1000".parse().unwrap()), Some(b));
+        assert_eq!(routing_state.peer_at("2.2.2.2:2000".parse().unwrap()), Some(b));
+
+        routing_state.learn_peer_at("1.1.1.1:1000".parse().unwrap(), a);
+        assert_eq!(routing_state.peer_at("1.1.1.1:1000".parse().unwrap()), Some(a));
+    }
+
+    #[test]
+    fn test_path_cost() {
+        let fast = PathStats {
+            rtt: Some(Duration::from_millis(10)),
+            loss: 0.0,
+        };
+        let lossy = PathStats {
+            rtt: Some(Duration::from_millis(10)),
+            loss: 0.5,
+        };
+        let slow = PathStats {
+            rtt: Some(Duration::from_millis(200)),
+            loss: 0.0,
+        };
+        assert!(fast.cost(0) < lossy.cost(0));
+        assert!(lossy.cost(0) < slow.cost(0));
+        assert!(PathStats::default().cost(0) < slow.cost(0));
+        // A long enough send queue outweighs a better RTT
+        assert!(fast.cost(10_000) > slow.cost(0));
+    }
+
+    #[test]
+    fn test_path_stats_are_smoothed() {
+        let mut stats = PathStats::default();
+        stats.record_rtt(Duration::from_millis(80));
+        assert_eq!(stats.rtt, Some(Duration::from_millis(80)));
+        stats.record_rtt(Duration::from_millis(160));
+        assert_eq!(stats.rtt, Some(Duration::from_millis(90)));
+
+        stats.record_delivery(false);
+        assert_eq!(stats.loss, SMOOTHING);
+        for _ in 0..100 {
+            stats.record_delivery(true);
+        }
+        assert!(stats.loss < 0.001);
+    }
+
+    #[test]
+    fn test_path_probes() {
+        let start = std::time::Instant::now();
+        let mut probes = PathProbes::new();
+        let answered = probes.sent("fast", start);
+        let lost = probes.sent("lossy", start);
+        assert_ne!(answered, lost);
+
+        let later = start + Duration::from_millis(30);
+        assert_eq!(
+            probes.answered(answered, later),
+            Some(("fast", Duration::from_millis(30)))
+        );
+        // Duplicate and unknown replies are ignored
+        assert_eq!(probes.answered(answered, later), None);
+        assert_eq!(probes.answered(1000, later), None);
+
+        assert!(probes.expire(Duration::from_secs(1), later).is_empty());
+        assert_eq!(
+            probes.expire(Duration::from_secs(1), start + Duration::from_secs(1)),
+            vec!["lossy"]
+        );
+        // A reply that turns up after its probe expired doesn't count
+        assert_eq!(probes.answered(lost, start + Duration::from_secs(2)), None);
+    }
+
+    #[test]
+    fn test_liveness() {
+        let (a, b) = (peer(), peer());
+        let (a1, a2, b1): (SocketAddr, SocketAddr, SocketAddr) = (
+            "1.1.1.1:1000".parse().unwrap(),
+            "1.1.1.2:1000".parse().unwrap(),
+            "2.2.2.2:2000".parse().unwrap(),
+        );
+        let known = std::collections::BTreeSet::from([(a, a1), (a, a2), (b, b1)]);
+        let timeout = Duration::from_secs(5);
+        let start = std::time::Instant::now();
+        let mut liveness = Liveness::default();
+
+        // Addresses get `timeout` to be heard from once they are known
+        assert!(liveness.update(&known, timeout, start).is_empty());
+        liveness.heard_from(a, a1, start + Duration::from_secs(4));
+        liveness.heard_from(b, b1, start + Duration::from_secs(4));
+        assert_eq!(
+            liveness.update(&known, timeout, start + timeout),
+            vec![LivenessChange::Address {
+                peer: a,
+                address: a2,
+                alive: false
+            }]
+        );
+        assert!(!liveness.is_alive(&a, a2));
+        assert!(liveness.is_alive(&a, a1));
+
+        // A peer is down once all of its addresses are
+        let changes = liveness.update(&known, timeout, start + Duration::from_secs(9));
+        assert_eq!(changes.len(), 4);
+        assert!(changes.contains(&LivenessChange::Peer { peer: a, alive: false }));
+        assert!(changes.contains(&LivenessChange::Peer { peer: b, alive: false }));
+
+        liveness.heard_from(a, a2, start + Duration::from_secs(10));
+        let changes = liveness.update(&known, timeout, start + Duration::from_secs(10));
+        assert_eq!(
+            changes,
+            vec![
+                LivenessChange::Address {
+                    peer: a,
+                    address: a2,
+                    alive: true
+                },
+                LivenessChange::Peer { peer: a, alive: true }
+            ]
+        );
+
+        // Addresses that are no longer known are forgotten
+        let known = std::collections::BTreeSet::from([(a, a2)]);
+        assert!(
+            liveness
+                .update(&known, timeout, start + Duration::from_secs(10))
+                .is_empty()
+        );
+        assert!(liveness.is_alive(&b, b1));
+    }
+
+    #[test]
+    fn test_peers_warp_map_has_lost_are_not_relayed_to() {
+        use warp_protocol::messages::PeerStatus;
+        let routing_state = RoutingState::new();
+        let a = peer();
+        let timeout = Duration::from_secs(5);
+        let start = std::time::Instant::now();
+
+        let expired = warp_protocol::messages::MappingResponse {
+            peer_status: Some(PeerStatus::Expired),
+            ..mapping(a, &[])
+        };
+        assert_eq!(
+            routing_state.handle_mapping_response(&expired),
+            Some(PeerStatus::Expired)
+        );
+        assert_eq!(routing_state.handle_mapping_response(&expired), None);
+        assert!(routing_state.update_relays(&[a], Some(timeout), start).is_empty());
+
+        // Relayed to as soon as it registers again, having no addresses that have been heard from
+        let registered = warp_protocol::messages::MappingResponse {
+            peer_status: Some(PeerStatus::Registered),
+            ..mapping(a, &["1.1.1.1:1000"])
+        };
+        assert_eq!(
+            routing_state.handle_mapping_response(&registered),
+            Some(PeerStatus::Registered)
+        );
+        assert_eq!(
+            routing_state.update_relays(&[a], Some(timeout), start + timeout),
+            vec![(a, true)]
+        );
+    }
+
+    #[test]
+    fn test_relay_fallback() {
+        let routing_state = RoutingState::new();
+        let (a, b) = (peer(), peer());
+        let a1: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+        let known = std::collections::BTreeSet::from([(a, a1)]);
+        let timeout = Duration::from_secs(5);
+        let start = std::time::Instant::now();
+        routing_state.liveness.lock().unwrap().update(&known, timeout, start);
+
+        assert!(routing_state.update_relays(&[a, b], None, start).is_empty());
+        // b has no address to try, while a's gets `timeout` to be heard from
+        assert_eq!(
+            routing_state.update_relays(&[a, b], Some(timeout), start),
+            vec![(b, true)]
+        );
+        assert!(!routing_state.is_relayed(&a));
+        assert_eq!(
+            routing_state.update_relays(&[a, b], Some(timeout), start + timeout),
+            vec![(a, true)]
+        );
+        assert!(routing_state.is_relayed(&a));
+
+        // Relayed messages keep a alive even though its address is dead
+        routing_state.heard_via_relay(&a, start + timeout);
+        assert_eq!(
+            routing_state
+                .liveness
+                .lock()
+                .unwrap()
+                .update(&known, timeout, start + timeout),
+            vec![LivenessChange::Address {
+                peer: a,
+                address: a1,
+                alive: false
+            }]
+        );
+
+        // Hearing from a directly again ends the relaying
+        routing_state.heard_from(&a, a1, start + Duration::from_secs(6));
+        assert_eq!(
+            routing_state.update_relays(&[a, b], Some(timeout), start + Duration::from_secs(6)),
+            vec![(a, false)]
+        );
+        assert_eq!(
+            routing_state.update_relays(&[a, b], None, start + Duration::from_secs(6)),
+            vec![(b, false)]
+        );
+    }
+
+    #[test]
+    fn test_path_selection_policies() {
+        let candidates = vec![("a", (0, 3.0)), ("b", (0, 1.0)), ("c", (0, 2.0))];
+        assert_eq!(select_paths(candidates.clone(), PathSelection::Best), vec!["b"]);
+        assert_eq!(
+            select_paths(candidates.clone(), PathSelection::Redundant(2)),
+            vec!["b", "c"]
+        );
+        assert_eq!(
+            select_paths(candidates.clone(), PathSelection::Redundant(5)),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(select_paths(candidates, PathSelection::All), vec!["a", "b", "c"]);
+        assert!(select_paths(Vec::<(&str, (u32, f64))>::new(), PathSelection::Best).is_empty());
+    }
+
+    #[test]
+    fn test_cheaper_interfaces_come_first() {
+        // A slow cheap path beats a fast expensive one, and the expensive one is only picked for redundancy
+        let candidates = vec![("lte", (10, 0.02)), ("wifi", (0, 0.08)), ("ethernet", (0, 0.05))];
+        assert_eq!(select_paths(candidates.clone(), PathSelection::Best), vec!["ethernet"]);
+        assert_eq!(
+            select_paths(candidates.clone(), PathSelection::Redundant(3)),
+            vec!["lte", "wifi", "ethernet"]
+        );
+
+        let mut paths = vec![("lte", true), ("wifi", false)];
+        prefer(&mut paths, |(_, metered)| !metered);
+        assert_eq!(paths, vec![("wifi", false)]);
+        // With nothing else left, metered paths are still used
+        let mut paths = vec![("lte", true)];
+        prefer(&mut paths, |(_, metered)| !metered);
+        assert_eq!(paths, vec![("lte", true)]);
+    }
+
+    #[test]
+    fn test_overridden_address_identifies_peer() {
+        let routing_state = RoutingState::new();
+        let a = peer();
+        routing_state.handle_mapping_response(&mapping(a, &["1.1.1.1:1000"]));
+        let actual_address: SocketAddr = "1.1.1.1:5555".parse().unwrap();
+        routing_state
+            .handle_peer_address_override(
+                &a,
+                &warp_protocol::messages::PeerAddressOverride {
+                    replace: "1.1.1.1:1000".parse().unwrap(),
+                    sequence: 1,
+                },
+                actual_address,
+                "eth0",
+                std::time::Instant::now(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            routing_state.resolve_peer_addresses(&a, &eth0(), None),
+            vec![actual_address]
+        );
+        assert_eq!(routing_state.peer_at(actual_address), Some(a));
+    }
+
+    #[test]
+    fn test_overrides_are_validated_and_rate_limited() {
+        let routing_state = RoutingState::new();
+        let a = peer();
+        routing_state.handle_mapping_response(&mapping(a, &["1.1.1.1:1000"]));
+        let start = std::time::Instant::now();
+        let handle = |replace: &str, sequence, from: &str, at| {
+            routing_state.handle_peer_address_override(
+                &a,
+                &warp_protocol::messages::PeerAddressOverride {
+                    replace: replace.parse().unwrap(),
+                    sequence,
+                },
+                from.parse().unwrap(),
+                "eth0",
+                start + at,
+            )
+        };
+
+        assert_eq!(
+            handle("2.2.2.2:1000", 1, "2.2.2.2:5555", Duration::ZERO),
+            Err(OverrideRejection::UnknownAddress)
+        );
+        assert_eq!(
+            handle("1.1.1.1:1000", 1, "0.0.0.0:5555", Duration::ZERO),
+            Err(OverrideRejection::InvalidSource)
+        );
+        assert_eq!(handle("1.1.1.1:1000", 5, "1.1.1.1:5555", Duration::ZERO), Ok(()));
+        // Moving it straight away is refused, but refreshing it isn't
+        assert_eq!(
+            handle("1.1.1.1:1000", 6, "1.1.1.1:6666", Duration::from_millis(100)),
+            Err(OverrideRejection::RateLimited)
+        );
+        assert_eq!(
+            handle("1.1.1.1:1000", 6, "1.1.1.1:5555", Duration::from_millis(100)),
+            Ok(())
+        );
+        // An override from before the one in use is refused however late it arrives
+        assert_eq!(
+            handle("1.1.1.1:1000", 4, "1.1.1.1:6666", Duration::from_secs(10)),
+            Err(OverrideRejection::Stale)
+        );
+        assert_eq!(
+            handle("1.1.1.1:1000", 7, "1.1.1.1:6666", Duration::from_secs(10)),
+            Ok(())
+        );
+        assert_eq!(
+            routing_state.resolve_peer_addresses(&a, &eth0(), None),
+            vec!["1.1.1.1:6666".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_overrides_expire_unless_refreshed() {
+        let routing_state = RoutingState::new();
+        let a = peer();
+        routing_state.handle_mapping_response(&mapping(a, &["1.1.1.1:1000", "1.1.1.1:2000"]));
+        let start = std::time::Instant::now();
+        let ttl = Duration::from_secs(15);
+        for (replace, from) in [("1.1.1.1:1000", "1.1.1.1:5555"), ("1.1.1.1:2000", "1.1.1.1:6666")] {
+            let override_msg = warp_protocol::messages::PeerAddressOverride {
+                replace: replace.parse().unwrap(),
+                sequence: 1,
+            };
+            routing_state
+                .handle_peer_address_override(&a, &override_msg, from.parse().unwrap(), "eth0", start)
+                .unwrap();
+        }
+        // Only the first is sent again
+        let override_msg = warp_protocol::messages::PeerAddressOverride {
+            replace: "1.1.1.1:1000".parse().unwrap(),
+            sequence: 2,
+        };
+        routing_state
+            .handle_peer_address_override(
+                &a,
+                &override_msg,
+                "1.1.1.1:5555".parse().unwrap(),
+                "eth0",
+                start + Duration::from_secs(10),
+            )
+            .unwrap();
+
+        assert!(
+            routing_state
+                .expire_overrides(ttl, start + Duration::from_secs(14))
+                .is_empty()
+        );
+        assert_eq!(
+            routing_state.expire_overrides(ttl, start + Duration::from_secs(16)),
+            vec![(
+                "eth0".to_string(),
+                "1.1.1.1:2000".parse().unwrap(),
+                "1.1.1.1:6666".parse().unwrap()
+            )]
+        );
+        assert_eq!(routing_state.active_overrides_count(), 1);
+        // Datagrams go back to the address warp-map reported
+        assert_eq!(
+            routing_state.resolve_peer_addresses(&a, &eth0(), None),
+            vec![
+                "1.1.1.1:5555".parse::<SocketAddr>().unwrap(),
+                "1.1.1.1:2000".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_traffic_outlives_interfaces() {
+        let routing_state = RoutingState::new();
+        routing_state.interface_traffic("eth0").record_received(100);
+        // An interface of the same name that replaces it carries on counting
+        routing_state.interface_traffic("eth0").record_received(50);
+        routing_state.interface_traffic("wwan0").record_sent(None, 20);
+
+        let traffic = routing_state.traffic();
+        assert_eq!(traffic.keys().collect::<Vec<_>>(), ["eth0", "wwan0"]);
+        assert_eq!(traffic["eth0"].total.received.bytes, 150);
+        assert_eq!(traffic["eth0"].total.received.packets, 2);
+        assert_eq!(traffic["wwan0"].total.sent.bytes, 20);
+    }
+}
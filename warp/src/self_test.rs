@@ -0,0 +1,366 @@
+//! Checking a config end to end without a second machine: `warp --self-test`
+//!
+//! The self-test runs warp-map and two warp cores in this process, talking over localhost. The near core has the config's
+//! settings and the far core a copy of them with a key of its own. Each of the config's tunnels is opened between them
+//! with in-process gates in place of its own, and payloads of a few sizes, some larger than the tunnel's MTU, are sent
+//! through it both ways. They are fragmented, FEC encoded, encrypted, routed and put back together just as they would
+//! be between two machines, so a tunnel whose transport settings don't work fails here. The gates, interfaces and
+//! warp-map are the self-test's own, so those parts of the config aren't exercised.
+
+use crate::WarpCore;
+use crate::interface::NetworkInterfaceId;
+use crate::transport::{DatagramSocket, Network, SocketOptions, SystemNetwork};
+use crate::tunnel::ChannelGate;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Each is sent this many times through each tunnel, each way
+const PAYLOAD_SIZES: [usize; 3] = [64, 1200, 4000];
+const PAYLOADS_PER_SIZE: usize = 10;
+// How long the cores get to find each other through warp-map and agree on session keys
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+// How long the payloads get to arrive once sent
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+// How often a probe goes through a tunnel while waiting for it to connect
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+// Scans find the single localhost interface, so they only need to happen often enough to register quickly
+const SCAN_INTERVAL: Duration = Duration::from_millis(100);
+// How long the cores get to shut down gracefully before they are aborted
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+const MAP_CLIENT_EXPIRY: Duration = Duration::from_secs(60);
+const INTERFACE_NAME: &str = "self-test";
+// The index of the payloads sent while waiting for a tunnel to connect, which aren't counted
+const PROBE_INDEX: u32 = u32::MAX;
+
+/// How each of the config's tunnels did
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestReport {
+    pub tunnels: Vec<TunnelReport>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TunnelReport {
+    pub name: String,
+    /// From the near gate to the far gate
+    pub outbound: Delivery,
+    /// From the far gate back to the near gate
+    pub inbound: Delivery,
+}
+
+/// What became of the payloads sent one way through a tunnel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivery {
+    /// Whether the tunnel carried anything at all within [`CONNECT_TIMEOUT`]; nothing is sent if it didn't
+    pub connected: bool,
+    pub sent: usize,
+    /// Each payload only counts once, however many copies of it arrived
+    pub received: usize,
+    /// Payloads that arrived with different contents from those sent
+    pub corrupted: usize,
+    /// The median time from sending a payload to receiving it
+    pub median_latency: Option<Duration>,
+}
+
+impl SelfTestReport {
+    /// Whether every payload got through every tunnel intact, both ways
+    pub fn passed(&self) -> bool {
+        self.tunnels
+            .iter()
+            .all(|tunnel| tunnel.outbound.passed() && tunnel.inbound.passed())
+    }
+}
+
+impl Delivery {
+    fn passed(&self) -> bool {
+        self.connected && self.corrupted == 0 && self.received == self.sent
+    }
+}
+
+impl std::fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for tunnel in &self.tunnels {
+            writeln!(f, "{}:", tunnel.name)?;
+            writeln!(f, "  near to far: {}", tunnel.outbound)?;
+            writeln!(f, "  far to near: {}", tunnel.inbound)?;
+        }
+        write!(f, "{}", if self.passed() { "PASSED" } else { "FAILED" })
+    }
+}
+
+impl std::fmt::Display for Delivery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.connected {
+            return write!(f, "nothing got through within {CONNECT_TIMEOUT:?}");
+        }
+        write!(f, "{} of {} received", self.received, self.sent)?;
+        if self.corrupted > 0 {
+            write!(f, ", {} corrupted", self.corrupted)?;
+        }
+        if let Some(median_latency) = self.median_latency {
+            write!(f, ", median latency {median_latency:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Run the self-test for `config`'s tunnels; fails if the cores can't be set up, not if the tunnels don't work
+pub async fn run(config: &warp_config::WarpConfig) -> anyhow::Result<SelfTestReport> {
+    if config.tunnels.is_empty() {
+        anyhow::bail!("the config has no tunnels to test");
+    }
+
+    let map_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+    let map_socket = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let warp_map = warp_config::WarpMapConfig {
+        address: map_socket.local_addr()?,
+        public_key: map_key.public_key(),
+        tcp_address: None,
+    };
+    let map_task = tokio::task::Builder::new()
+        .name("self-test warp-map")
+        .spawn(serve_map(map_socket, map_key))?;
+    let _map_task = AbortOnDrop(map_task);
+
+    let far_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+    let near_config = core_config(config, config.private_key.clone(), far_key.public_key(), &warp_map);
+    let far_config = core_config(config, far_key, config.private_key.public_key(), &warp_map);
+    let (mut near, near_shutdown) = WarpCore::with_network(near_config, Arc::new(LoopbackNetwork));
+    let (mut far, far_shutdown) = WarpCore::with_network(far_config, Arc::new(LoopbackNetwork));
+
+    let mut gates = Vec::new();
+    for (name, tunnel) in &config.tunnels {
+        let near_gate = near.add_channel_tunnel(name, tunnel.tunnel_id, tunnel.transport.clone())?;
+        let far_gate = far.add_channel_tunnel(name, tunnel.tunnel_id, tunnel.transport.clone())?;
+        gates.push((name.clone(), near_gate, far_gate));
+    }
+
+    let near_task = tokio::task::Builder::new()
+        .name("self-test near core")
+        .spawn(async move { near.run().await })?;
+    let far_task = tokio::task::Builder::new()
+        .name("self-test far core")
+        .spawn(async move { far.run().await })?;
+    let mut core_tasks = [AbortOnDrop(near_task), AbortOnDrop(far_task)];
+
+    let mut tunnels = Vec::new();
+    for (name, mut near_gate, mut far_gate) in gates {
+        tracing::info!("Self-testing tunnel {}", name);
+        let outbound = deliver(&near_gate, &mut far_gate).await;
+        let inbound = deliver(&far_gate, &mut near_gate).await;
+        tunnels.push(TunnelReport {
+            name,
+            outbound,
+            inbound,
+        });
+    }
+
+    let _ = near_shutdown.send(());
+    let _ = far_shutdown.send(());
+    let stopped = futures::future::join_all(core_tasks.iter_mut().map(|task| &mut task.0));
+    let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, stopped).await;
+    Ok(SelfTestReport { tunnels })
+}
+
+// The config a self-test core runs with: `config`'s, as `private_key`, on localhost only, and with no tunnels, metrics,
+// state file or anything else that would touch the host besides the sockets it binds
+fn core_config(
+    config: &warp_config::WarpConfig,
+    private_key: warp_protocol::PrivateKey,
+    far_gate: warp_protocol::PublicKey,
+    warp_map: &warp_config::WarpMapConfig,
+) -> warp_config::WarpConfig {
+    let mut core_config = config.clone();
+    core_config.private_key = private_key;
+    core_config.far_gate = warp_config::WarpFarGateConfig { public_key: far_gate };
+    core_config.warp_map = warp_map.clone();
+    core_config.tunnels.clear();
+    core_config.metrics = None;
+    core_config.state_file = None;
+    core_config.daemon = None;
+    core_config.control = None;
+    core_config.telemetry = None;
+
+    let interfaces = &mut core_config.interfaces;
+    interfaces.interface_scan_interval = SCAN_INTERVAL;
+    interfaces.interface_watch = Some(warp_config::InterfaceWatch::Scan);
+    interfaces.bind_to_device = Some(false);
+    interfaces.inclusion_patterns = regex::RegexSet::new([".*"]).expect("valid pattern");
+    interfaces.exclusion_patterns = regex::RegexSet::empty();
+    interfaces.fibs = None;
+    interfaces.relay = None;
+    interfaces.traffic_log = None;
+    core_config
+}
+
+// Send payloads through a tunnel from `from` to `to`, once it carries a probe
+async fn deliver(from: &ChannelGate, to: &mut ChannelGate) -> Delivery {
+    let payloads: Vec<_> = PAYLOAD_SIZES
+        .iter()
+        .flat_map(|&size| std::iter::repeat_n(size, PAYLOADS_PER_SIZE))
+        .enumerate()
+        .map(|(index, size)| payload(index as u32, size))
+        .collect();
+    let mut delivery = Delivery {
+        connected: false,
+        sent: 0,
+        received: 0,
+        corrupted: 0,
+        median_latency: None,
+    };
+
+    let connect_deadline = tokio::time::Instant::now() + CONNECT_TIMEOUT;
+    while !delivery.connected && tokio::time::Instant::now() < connect_deadline {
+        if from.to_gate.send(payload(PROBE_INDEX, PAYLOAD_SIZES[0])).await.is_err() {
+            return delivery;
+        }
+        let probed_until = tokio::time::Instant::now() + PROBE_INTERVAL;
+        while let Ok(Some(received)) = tokio::time::timeout_at(probed_until, to.from_gate.recv()).await {
+            if index(&received) == Some(PROBE_INDEX) {
+                delivery.connected = true;
+            }
+        }
+    }
+    if !delivery.connected {
+        return delivery;
+    }
+
+    let mut sent_at = Vec::with_capacity(payloads.len());
+    for payload in &payloads {
+        sent_at.push(Instant::now());
+        if from.to_gate.send(payload.clone()).await.is_err() {
+            break;
+        }
+    }
+    delivery.sent = sent_at.len();
+
+    let mut received = vec![false; payloads.len()];
+    let mut latencies = Vec::new();
+    let deadline = tokio::time::Instant::now() + DELIVERY_TIMEOUT;
+    while delivery.received + delivery.corrupted < delivery.sent {
+        let Ok(Some(data)) = tokio::time::timeout_at(deadline, to.from_gate.recv()).await else {
+            break;
+        };
+        let Some(index) = index(&data).filter(|&index| index != PROBE_INDEX) else {
+            continue;
+        };
+        match payloads.get(index as usize) {
+            Some(payload) if *payload == data => {
+                if !std::mem::replace(&mut received[index as usize], true) {
+                    delivery.received += 1;
+                    latencies.push(sent_at[index as usize].elapsed());
+                }
+            }
+            _ => delivery.corrupted += 1,
+        }
+    }
+    latencies.sort();
+    delivery.median_latency = latencies.get(latencies.len() / 2).copied();
+    delivery
+}
+
+// A payload of `size` bytes, starting with its index, whose contents are checked on arrival
+fn payload(index: u32, size: usize) -> Vec<u8> {
+    let mut payload = index.to_le_bytes().to_vec();
+    payload.extend((payload.len()..size).map(|i| (i as u8).wrapping_add(index as u8)));
+    payload
+}
+
+fn index(payload: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(payload.get(..4)?.try_into().ok()?))
+}
+
+async fn serve_map(socket: tokio::net::UdpSocket, private_key: warp_protocol::PrivateKey) {
+    let Ok(address) = socket.local_addr() else {
+        return;
+    };
+    let server = warp_map::WarpMapServer::new(private_key, address, MAP_CLIENT_EXPIRY);
+    let mut buf = vec![0u8; 65536];
+    while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+        match server.handle_datagram(&buf[..len], &from).await {
+            Ok(datagrams) => {
+                for (to, datagram) in datagrams {
+                    let _ = socket.send_to(&datagram, to).await;
+                }
+            }
+            Err(e) => tracing::debug!("Self-test warp-map failed to process datagram from {}: {}", from, e),
+        }
+    }
+}
+
+// The host's own sockets, but only ever on localhost
+struct LoopbackNetwork;
+
+impl Network for LoopbackNetwork {
+    fn interfaces(&self) -> Vec<NetworkInterfaceId> {
+        vec![NetworkInterfaceId {
+            name: INTERFACE_NAME.to_string(),
+            ip: Ipv4Addr::LOCALHOST.into(),
+            prefix_len: 8,
+        }]
+    }
+
+    fn bind(&self, interface: &NetworkInterfaceId, options: &SocketOptions) -> anyhow::Result<Arc<dyn DatagramSocket>> {
+        let options = SocketOptions {
+            bind_to_device: false,
+            fib: None,
+            ..*options
+        };
+        SystemNetwork.bind(interface, &options)
+    }
+}
+
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_config_passes_over_localhost() {
+        let key = |key: &warp_protocol::PrivateKey| warp_protocol::crypto::pubkey_to_string(&key.public_key());
+        let private_key = warp_protocol::PrivateKey::random(&mut rand::rng());
+        let config: warp_config::WarpConfig = toml::from_str(&format!(
+            r#"
+                private_key = "{}"
+                [interfaces]
+                interface_scan_interval = 1.0
+                holepunch_keep_alive_interval = 0.1
+                exclusion_patterns = []
+                inclusion_patterns = []
+                max_consecutive_failures = 3
+                [warp_map]
+                address = "192.0.2.1:13116"
+                public_key = "{}"
+                [far_gate]
+                public_key = "{}"
+                [tunnels.video]
+                gate = {{ ipv4 = true, application_to_gate = 9 }}
+                [tunnels.video.transport]
+                mtu = 1400
+                ordered = true
+                send_deadline = 1.0
+                [tunnels.video.transport.redundancy]
+                num_shards = 3
+                required_shards = 2
+            "#,
+            warp_protocol::crypto::privkey_to_string(&private_key),
+            key(&private_key),
+            key(&private_key),
+        ))
+        .unwrap();
+
+        let report = run(&config).await.unwrap();
+        assert!(report.passed(), "{report}");
+        assert_eq!(
+            report.tunnels[0].outbound.received,
+            PAYLOAD_SIZES.len() * PAYLOADS_PER_SIZE
+        );
+    }
+}
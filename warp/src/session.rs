@@ -0,0 +1,871 @@
+//! The keys warp encrypts messages to each far gate with, replaced on a schedule so a leaked key exposes little traffic
+//!
+//! Each peer pair starts out with the static cipher derived from their warp keys. The peer with the lower public key
+//! (the initiator) runs a handshake on first contact to replace it, and again whenever the session key is
+//! `rekey.interval` old or has carried `rekey.max_bytes`: it sends a `RekeyRequest` holding a fresh ephemeral public key
+//! and the responder answers with a `RekeyResponse` holding one of its own. The session key comes from both peers'
+//! ephemeral and warp keys (see [`warp_protocol::crypto::Handshake`]); the ephemeral keys are dropped as soon as it is
+//! derived, so neither peer's warp key is enough to recover it later. The initiator only switches once the response
+//! proves that the responder derived the same key, which only the holder of the far gate's private key can do.
+//!
+//! The switch is staggered so that nothing in flight is lost. The responder accepts the new key straight away but keeps
+//! sending with the old one until something arrives under the new key; the initiator sends with the new key as soon as
+//! the response arrives. Both keep accepting the key they replaced for [`PREVIOUS_KEY_LIFETIME`].
+//!
+//! Once a rekey agrees on it (`tunnel_keys` in both messages), tunnel payloads are sealed with a key of their own tunnel
+//! rather than the session key itself: each is derived from the session key with HKDF, taking the tunnel id as the
+//! context (see [`warp_protocol::Cipher::tunnel_key`]), and sent as a `KeyedTunnelPayload` tagged with the key it is
+//! sealed under. Both peers derive the keys of the tunnels they have with each other, as set by
+//! [`Sessions::set_tunnels`]; payloads of any other tunnel are sealed with the session key as before.
+//!
+//! Likewise, tunnel payloads are only compressed for a peer once a rekey has agreed on it (`compression` in both
+//! messages), so that peers that can't decompress them never receive any.
+//!
+//! A message under the static cipher from a peer that had moved past it means that the peer restarted, so the session
+//! starts over. Messages are checked against the peer's replay window before anything like that happens, so replaying
+//! an old message can't push a session back.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use warp_protocol::codec::{Message, UnencryptedWireMessage, WireMessage};
+use warp_protocol::crypto::{Handshake, HandshakeRole, TUNNEL_KEY_TAG_SIZE, TunnelKey};
+use warp_protocol::messages::{KeyedTunnelPayload, RekeyRequest, RekeyResponse, TunnelId, TunnelPayload};
+use warp_protocol::replay::ReplayWindow;
+use warp_protocol::{CipherSuite, PublicKey};
+
+/// How long a replaced key is still accepted, for messages that were in flight when it was replaced
+pub const PREVIOUS_KEY_LIFETIME: Duration = Duration::from_secs(10);
+
+/// How long the initiator waits for a `RekeyResponse` before sending its request again
+pub const REKEY_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+struct Key {
+    epoch: u64,
+    cipher: warp_protocol::Cipher,
+    // The keys of the tunnels with the peer, if the peers agreed to seal tunnel payloads with them under this key
+    tunnel_keys: Option<HashMap<TunnelId, TunnelKey>>,
+    // Whether the peers agreed that tunnel payloads sent under this key may be compressed
+    compression: bool,
+}
+
+impl Key {
+    fn new(
+        epoch: u64,
+        cipher: warp_protocol::Cipher,
+        tunnel_keys: bool,
+        compression: bool,
+        tunnels: &[TunnelId],
+    ) -> Self {
+        let mut key = Self {
+            epoch,
+            cipher,
+            tunnel_keys: tunnel_keys.then(HashMap::new),
+            compression,
+        };
+        key.derive_tunnel_keys(tunnels);
+        key
+    }
+
+    // Derive the keys of `tunnels` that are missing and drop those of other tunnels
+    fn derive_tunnel_keys(&mut self, tunnels: &[TunnelId]) {
+        if let Some(tunnel_keys) = &mut self.tunnel_keys {
+            tunnel_keys.retain(|tunnel_id, _| tunnels.contains(tunnel_id));
+            for tunnel_id in tunnels {
+                if !tunnel_keys.contains_key(tunnel_id) {
+                    tunnel_keys.insert(tunnel_id.clone(), self.cipher.tunnel_key(tunnel_id));
+                }
+            }
+        }
+    }
+
+    // The cipher a message would be sealed with under this key: the tunnel key with its `tag` if it has one, or else
+    // the key itself
+    fn cipher_for(&self, tag: Option<[u8; TUNNEL_KEY_TAG_SIZE]>) -> &warp_protocol::Cipher {
+        tag.and_then(|tag| {
+            self.tunnel_keys
+                .as_ref()?
+                .values()
+                .find(|tunnel_key| tunnel_key.tag == tag)
+        })
+        .map_or(&self.cipher, |tunnel_key| &tunnel_key.cipher)
+    }
+}
+
+/// How to seal a tunnel's payloads for a peer
+pub enum TunnelSealing {
+    /// As `TunnelPayload`s under the session key
+    Session(warp_protocol::Cipher),
+    /// As `KeyedTunnelPayload`s under the tunnel's own key
+    Tunnel(TunnelKey),
+}
+
+impl TunnelSealing {
+    /// Encode `tunnel_payload` to be sealed this way, with the cipher to seal it with; the payload is left as it was,
+    /// so that a retransmission can be sealed from it again
+    pub fn encode(
+        &self,
+        tunnel_payload: &TunnelPayload,
+    ) -> Result<(UnencryptedWireMessage, &warp_protocol::Cipher), warp_protocol::EncodeError> {
+        match self {
+            TunnelSealing::Session(cipher) => Ok((tunnel_payload.encode()?, cipher)),
+            TunnelSealing::Tunnel(tunnel_key) => Ok((tunnel_payload.encode_keyed(tunnel_key.tag)?, &tunnel_key.cipher)),
+        }
+    }
+}
+
+// The initiator's side of a rekey that hasn't been answered yet
+struct PendingRekey {
+    request: RekeyRequest,
+    handshake: warp_protocol::crypto::Handshake,
+    sent: Instant,
+}
+
+// The responder's side of a rekey whose key hasn't been used by the initiator yet
+struct NextKey {
+    key: Key,
+    request: RekeyRequest,
+    response: RekeyResponse,
+}
+
+pub enum Decrypted {
+    Message(UnencryptedWireMessage),
+    /// An authentic message that was already received, or is too old to tell
+    Duplicate(UnencryptedWireMessage),
+}
+
+// Which of a session's keys a message was encrypted with
+enum KeyUsed {
+    Current,
+    Next,
+    Previous,
+    Static,
+}
+
+struct Session {
+    static_cipher: warp_protocol::Cipher,
+    // Sequence numbers carry on across the peer's restarts, so this outlives restarted sessions
+    replay_window: ReplayWindow,
+    current: Key,
+    established: Instant,
+    bytes: u64,
+    previous: Option<(Key, Instant)>,
+    pending: Option<PendingRekey>,
+    next: Option<NextKey>,
+    // The tunnels with the peer, whose keys are derived from each session key that has tunnel keys
+    tunnels: Vec<TunnelId>,
+}
+
+impl Session {
+    fn new(static_cipher: warp_protocol::Cipher, now: Instant) -> Self {
+        Self {
+            current: Key::new(0, static_cipher.clone(), false, false, &[]),
+            static_cipher,
+            replay_window: ReplayWindow::new(),
+            established: now,
+            bytes: 0,
+            previous: None,
+            pending: None,
+            next: None,
+            tunnels: Vec::new(),
+        }
+    }
+
+    fn replace_current(&mut self, key: Key, now: Instant) {
+        let previous = std::mem::replace(&mut self.current, key);
+        self.previous = Some((previous, now + PREVIOUS_KEY_LIFETIME));
+        self.established = now;
+        self.bytes = 0;
+    }
+
+    fn previous_key(&self, now: Instant) -> Option<&Key> {
+        self.previous
+            .as_ref()
+            .filter(|(_, expiry)| now < *expiry)
+            .map(|(key, _)| key)
+    }
+}
+
+pub struct Sessions {
+    private_key: warp_protocol::PrivateKey,
+    // Derived once from the private key, as it is needed for every message received
+    public_key: PublicKey,
+    // The suites new session keys may use, most preferred first
+    cipher_suites: Mutex<Vec<CipherSuite>>,
+    sessions: Mutex<BTreeMap<PublicKey, Session>>,
+}
+
+impl Sessions {
+    pub fn new(
+        private_key: warp_protocol::PrivateKey,
+        cipher_suites: Vec<CipherSuite>,
+        peers: &[PublicKey],
+        now: Instant,
+    ) -> Self {
+        let sessions = Self {
+            public_key: private_key.public_key(),
+            private_key,
+            cipher_suites: Mutex::new(cipher_suites),
+            sessions: Mutex::new(BTreeMap::new()),
+        };
+        sessions.set_peers(peers, now);
+        sessions
+    }
+
+    /// Use `cipher_suites` for the session keys agreed from now on; keys already in use keep their suite
+    pub fn set_cipher_suites(&self, cipher_suites: Vec<CipherSuite>) {
+        *self.cipher_suites.lock().unwrap() = cipher_suites;
+    }
+
+    /// Start sessions with peers that are new, and end those with peers that are no longer in `peers`
+    pub fn set_peers(&self, peers: &[PublicKey], now: Instant) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|peer, _| peers.contains(peer));
+        for peer in peers {
+            sessions.entry(*peer).or_insert_with(|| {
+                Session::new(
+                    warp_protocol::crypto::cipher_from_shared_secret(&self.private_key, peer),
+                    now,
+                )
+            });
+        }
+    }
+
+    /// Derive tunnel keys for the tunnels with each peer in `tunnels`, and no others
+    pub fn set_tunnels(&self, tunnels: &BTreeMap<PublicKey, Vec<TunnelId>>) {
+        for (peer, session) in self.sessions.lock().unwrap().iter_mut() {
+            session.tunnels = tunnels.get(peer).cloned().unwrap_or_default();
+            let tunnels = &session.tunnels;
+            session.current.derive_tunnel_keys(tunnels);
+            if let Some((previous, _)) = &mut session.previous {
+                previous.derive_tunnel_keys(tunnels);
+            }
+            if let Some(next) = &mut session.next {
+                next.key.derive_tunnel_keys(tunnels);
+            }
+        }
+    }
+
+    pub fn peers(&self) -> Vec<PublicKey> {
+        self.sessions.lock().unwrap().keys().copied().collect()
+    }
+
+    /// The cipher to send to each peer with
+    pub fn ciphers(&self) -> Vec<(PublicKey, warp_protocol::Cipher)> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .iter()
+            .map(|(peer, session)| (*peer, session.current.cipher.clone()))
+            .collect()
+    }
+
+    /// The cipher to send to `peer` with, if warp has a session with it
+    pub fn cipher(&self, peer: &PublicKey) -> Option<warp_protocol::Cipher> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(peer).map(|session| session.current.cipher.clone())
+    }
+
+    /// How to seal payloads of the tunnel `tunnel_id` for `peer`, if warp has a session with it
+    pub fn tunnel_sealing(&self, peer: &PublicKey, tunnel_id: &TunnelId) -> Option<TunnelSealing> {
+        let sessions = self.sessions.lock().unwrap();
+        let current = &sessions.get(peer)?.current;
+        Some(
+            match current
+                .tunnel_keys
+                .as_ref()
+                .and_then(|tunnel_keys| tunnel_keys.get(tunnel_id))
+            {
+                Some(tunnel_key) => TunnelSealing::Tunnel(tunnel_key.clone()),
+                None => TunnelSealing::Session(current.cipher.clone()),
+            },
+        )
+    }
+
+    /// Whether tunnel payloads for `peer` may be compressed, as agreed for the key they are sealed with
+    pub fn compression(&self, peer: &PublicKey) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(peer).is_some_and(|session| session.current.compression)
+    }
+
+    /// Count bytes sent to or received from `peer` towards its session key's `rekey.max_bytes`
+    pub fn record_bytes(&self, peer: &PublicKey, bytes: usize) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(peer) {
+            session.bytes += bytes as u64;
+        }
+    }
+
+    /// Decrypt a message from `peer` with any key it might have used; None if it isn't from `peer`
+    pub fn decrypt(&self, peer: &PublicKey, msg: &WireMessage, now: Instant) -> Option<Decrypted> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(peer)?;
+
+        // A tagged message can only be opened with the tunnel key of that tag, if any, under each session key
+        let tag = tunnel_key_tag(msg);
+        let next = session.next.as_ref().map(|next| (&next.key, KeyUsed::Next));
+        let previous = session.previous_key(now).map(|previous| (previous, KeyUsed::Previous));
+        let (decrypted, key_used) = [(&session.current, KeyUsed::Current)]
+            .into_iter()
+            .chain(next)
+            .chain(previous)
+            .map(|(key, key_used)| (key.cipher_for(tag), key_used))
+            .chain([(&session.static_cipher, KeyUsed::Static)])
+            .find_map(|(cipher, key_used)| msg.decrypt(cipher).ok().map(|decrypted| (decrypted, key_used)))?;
+        // Both peers have the same keys, so a message of our own reflected back at us would decrypt too
+        if !warp_protocol::replay::is_from(&decrypted.nonce, peer, &self.public_key) {
+            return None;
+        }
+
+        // Unless the static cipher is the key being replaced, a message under it is from a restarted peer, whose sequence
+        // carries on past everything it sent before; anything else under it was delayed or replayed
+        let sequence = warp_protocol::replay::sequence_of(&decrypted.nonce);
+        let restarted = matches!(key_used, KeyUsed::Static)
+            && session.current.epoch != 0
+            && session.previous_key(now).is_none_or(|key| key.epoch != 0);
+        if restarted && session.replay_window.newest().is_some_and(|newest| sequence <= newest)
+            || !session.replay_window.accept(sequence)
+        {
+            return Some(Decrypted::Duplicate(decrypted));
+        }
+
+        match key_used {
+            KeyUsed::Next => {
+                // The initiator has switched over, so the responder can too
+                let next = session.next.take().expect("decrypted with it");
+                session.replace_current(next.key, now);
+                tracing::event!(
+                    tracing::Level::INFO,
+                    peer = warp_protocol::crypto::pubkey_to_string(peer),
+                    epoch = session.current.epoch,
+                    "SESSION_REKEYED"
+                );
+            }
+            KeyUsed::Static if restarted => {
+                tracing::event!(
+                    tracing::Level::INFO,
+                    peer = warp_protocol::crypto::pubkey_to_string(peer),
+                    epoch = session.current.epoch,
+                    "SESSION_RESTARTED"
+                );
+                let replay_window = std::mem::take(&mut session.replay_window);
+                let tunnels = std::mem::take(&mut session.tunnels);
+                *session = Session::new(session.static_cipher.clone(), now);
+                session.replay_window = replay_window;
+                session.tunnels = tunnels;
+            }
+            _ => {}
+        }
+        Some(Decrypted::Message(decrypted))
+    }
+
+    /// The `RekeyRequest`s to send now: new ones for sessions that are due a new key, and repeats of unanswered ones
+    pub fn rekey_requests(&self, rekey: &warp_config::RekeyConfig, now: Instant) -> Vec<(PublicKey, RekeyRequest)> {
+        let our_key = self.public_key;
+        let mut requests = Vec::new();
+        for (peer, session) in self.sessions.lock().unwrap().iter_mut() {
+            if our_key > *peer {
+                // The peer starts rekeys with us
+                continue;
+            }
+            if let Some(pending) = &mut session.pending {
+                if now.duration_since(pending.sent) >= REKEY_RETRY_INTERVAL {
+                    pending.sent = now;
+                    requests.push((*peer, pending.request.clone()));
+                }
+                continue;
+            }
+
+            let due = session.current.epoch == 0
+                || now.duration_since(session.established) >= rekey.interval
+                || session.bytes >= rekey.max_bytes;
+            // Rekeying again while the previous key is still accepted could leave the responder without the key in use
+            if due && session.previous_key(now).is_none() {
+                let handshake = Handshake::new(HandshakeRole::Initiator);
+                let request = RekeyRequest {
+                    epoch: session.current.epoch + 1,
+                    public_key: handshake.public_key(),
+                    cipher_suites: self.cipher_suites.lock().unwrap().clone(),
+                    tunnel_keys: true,
+                    compression: true,
+                };
+                requests.push((*peer, request.clone()));
+                session.pending = Some(PendingRekey {
+                    request,
+                    handshake,
+                    sent: now,
+                });
+            }
+        }
+        requests
+    }
+
+    /// Answer a `RekeyRequest` from `peer`, accepting the new key; returns None if the request is stale or offers none
+    /// of our cipher suites
+    ///
+    /// The new key uses the first of our suites that the request offers, so the responder's preference wins.
+    pub fn handle_rekey_request(&self, peer: &PublicKey, request: &RekeyRequest) -> Option<RekeyResponse> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(peer)?;
+
+        if let Some(next) = &session.next
+            && next.request == *request
+        {
+            // The response was lost; the initiator may already have derived the key from it
+            return Some(next.response.clone());
+        }
+        if request.epoch <= session.current.epoch {
+            return None;
+        }
+
+        let Some(cipher_suite) = self
+            .cipher_suites
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .find(|suite| request.cipher_suites.contains(suite))
+        else {
+            tracing::event!(
+                tracing::Level::WARN,
+                peer = warp_protocol::crypto::pubkey_to_string(peer),
+                offered = ?request.cipher_suites,
+                "NO_COMMON_CIPHER_SUITE"
+            );
+            return None;
+        };
+        let handshake = Handshake::new(HandshakeRole::Responder);
+        let session_key = handshake.finish(cipher_suite, &self.private_key, peer, &request.public_key);
+        let response = RekeyResponse {
+            epoch: request.epoch,
+            public_key: handshake.public_key(),
+            cipher_suite,
+            confirmation: session_key.confirmation,
+            tunnel_keys: request.tunnel_keys,
+            compression: request.compression,
+        };
+        session.next = Some(NextKey {
+            key: Key::new(
+                request.epoch,
+                session_key.cipher,
+                response.tunnel_keys,
+                response.compression,
+                &session.tunnels,
+            ),
+            request: request.clone(),
+            response: response.clone(),
+        });
+        Some(response)
+    }
+
+    /// Switch to the key a `RekeyResponse` from `peer` completes; returns false if it doesn't answer our request or
+    /// doesn't prove that the peer derived the same key
+    pub fn handle_rekey_response(&self, peer: &PublicKey, response: &RekeyResponse, now: Instant) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(peer) else {
+            return false;
+        };
+        if session.pending.as_ref().is_none_or(|pending| {
+            pending.request.epoch != response.epoch || !pending.request.cipher_suites.contains(&response.cipher_suite)
+        }) {
+            return false;
+        }
+
+        let pending = session.pending.as_ref().expect("checked above");
+        let session_key =
+            pending
+                .handshake
+                .finish(response.cipher_suite, &self.private_key, peer, &response.public_key);
+        if session_key.confirmation != response.confirmation {
+            // Keep waiting, in case the genuine response is still to come
+            tracing::event!(
+                tracing::Level::WARN,
+                peer = warp_protocol::crypto::pubkey_to_string(peer),
+                epoch = response.epoch,
+                "HANDSHAKE_CONFIRMATION_FAILED"
+            );
+            return false;
+        }
+
+        let tunnel_keys = response.tunnel_keys && pending.request.tunnel_keys;
+        let compression = response.compression && pending.request.compression;
+        session.pending = None;
+        let key = Key::new(
+            response.epoch,
+            session_key.cipher,
+            tunnel_keys,
+            compression,
+            &session.tunnels,
+        );
+        session.replace_current(key, now);
+        true
+    }
+}
+
+// The tag of the tunnel key a `KeyedTunnelPayload` was sealed with; other peer messages carry no associated data
+fn tunnel_key_tag(msg: &WireMessage) -> Option<[u8; TUNNEL_KEY_TAG_SIZE]> {
+    if msg.associated_data.is_empty() {
+        return None;
+    }
+    msg.decode_public::<KeyedTunnelPayload>()
+        .ok()
+        .map(|associated_data| associated_data.key_tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp_protocol::messages::PathProbe;
+    use warp_protocol::replay::NonceSequence;
+
+    struct Peer {
+        key: PublicKey,
+        sessions: Sessions,
+        nonces: NonceSequence,
+    }
+
+    impl Peer {
+        fn new(private_key: warp_protocol::PrivateKey, peer: PublicKey, now: Instant) -> Self {
+            let key = private_key.public_key();
+            Self {
+                key,
+                sessions: Sessions::new(private_key, CipherSuite::ALL.to_vec(), &[peer], now),
+                nonces: NonceSequence::new(key),
+            }
+        }
+    }
+
+    // Returns the initiator first
+    fn peers(now: Instant) -> (Peer, Peer) {
+        let a = warp_protocol::PrivateKey::random(&mut rand::rng());
+        let b = warp_protocol::PrivateKey::random(&mut rand::rng());
+        let (a_key, b_key) = (a.public_key(), b.public_key());
+        let (a, b) = (Peer::new(a, b_key, now), Peer::new(b, a_key, now));
+        if a_key < b_key { (a, b) } else { (b, a) }
+    }
+
+    fn sealed(from: &Peer, to: &Peer) -> WireMessage {
+        let mut encoded = PathProbe {
+            sequence: 0,
+            timestamp: std::time::SystemTime::now(),
+        }
+        .encode()
+        .unwrap();
+        encoded.nonce = from.nonces.next_nonce(&to.key);
+        encoded.encrypt(&from.sessions.cipher(&to.key).unwrap()).unwrap()
+    }
+
+    fn received(to: &Peer, from: &Peer, msg: &WireMessage, now: Instant) -> bool {
+        matches!(to.sessions.decrypt(&from.key, msg, now), Some(Decrypted::Message(_)))
+    }
+
+    fn delivered(from: &Peer, to: &Peer, now: Instant) -> bool {
+        received(to, from, &sealed(from, to), now)
+    }
+
+    fn rekey(initiator: &Peer, responder: &Peer, now: Instant) {
+        let rekey = warp_config::RekeyConfig::default();
+        let [(peer, request)] = initiator.sessions.rekey_requests(&rekey, now).try_into().unwrap();
+        assert_eq!(peer, responder.key);
+        let response = responder
+            .sessions
+            .handle_rekey_request(&initiator.key, &request)
+            .unwrap();
+        assert!(initiator.sessions.handle_rekey_response(&responder.key, &response, now));
+    }
+
+    fn sealed_tunnel_payload(from: &Peer, to: &Peer, tunnel_id: TunnelId) -> (WireMessage, bool) {
+        let tunnel_payload = warp_protocol::messages::TunnelPayload::new(tunnel_id.clone(), 0, vec![1, 2, 3]);
+        let sealing = from.sessions.tunnel_sealing(&to.key, &tunnel_id).unwrap();
+        let (mut encoded, cipher) = sealing.encode(&tunnel_payload).unwrap();
+        encoded.nonce = from.nonces.next_nonce(&to.key);
+        (
+            encoded.encrypt(cipher).unwrap(),
+            matches!(sealing, TunnelSealing::Tunnel(_)),
+        )
+    }
+
+    fn epoch(peer: &Peer, other: &Peer) -> u64 {
+        peer.sessions.sessions.lock().unwrap()[&other.key].current.epoch
+    }
+
+    #[test]
+    fn test_rekey_keeps_in_flight_messages() {
+        let now = Instant::now();
+        let (initiator, responder) = peers(now);
+        let rekey = warp_config::RekeyConfig::default();
+        // Only the initiator starts rekeys, and it starts one straight away
+        assert!(responder.sessions.rekey_requests(&rekey, now).is_empty());
+
+        let in_flight_to_initiator = sealed(&responder, &initiator);
+        let in_flight_to_responder = sealed(&initiator, &responder);
+        self::rekey(&initiator, &responder, now);
+        assert_eq!(epoch(&initiator, &responder), 1);
+        // The responder keeps sending with the old key until the initiator uses the new one
+        assert_eq!(epoch(&responder, &initiator), 0);
+
+        let in_flight_later = sealed(&responder, &initiator);
+        assert!(received(&initiator, &responder, &in_flight_to_initiator, now));
+        assert!(received(&responder, &initiator, &in_flight_to_responder, now));
+        assert!(delivered(&initiator, &responder, now));
+        assert_eq!(epoch(&responder, &initiator), 1);
+        assert!(delivered(&responder, &initiator, now));
+
+        // The old key is only accepted for a while, and a late message under it doesn't look like a restart
+        let later = now + PREVIOUS_KEY_LIFETIME;
+        assert!(!received(&initiator, &responder, &in_flight_later, later));
+    }
+
+    #[test]
+    fn test_rejects_reflected_messages() {
+        let now = Instant::now();
+        let (initiator, responder) = peers(now);
+        // Both peers can decrypt what either sends, but the nonce says which of them sent it
+        let to_responder = sealed(&initiator, &responder);
+        assert!(initiator.sessions.decrypt(&responder.key, &to_responder, now).is_none());
+        let to_initiator = sealed(&responder, &initiator);
+        assert!(responder.sessions.decrypt(&initiator.key, &to_initiator, now).is_none());
+        assert!(received(&initiator, &responder, &to_initiator, now));
+        assert!(received(&responder, &initiator, &to_responder, now));
+    }
+
+    #[test]
+    fn test_rekeys_on_schedule() {
+        let now = Instant::now();
+        let (initiator, responder) = peers(now);
+        let rekey = warp_config::RekeyConfig {
+            interval: Duration::from_secs(60),
+            max_bytes: 1000,
+        };
+        self::rekey(&initiator, &responder, now);
+        assert!(delivered(&initiator, &responder, now));
+
+        // Not while the previous key is still accepted, even though the bytes are used up
+        initiator.sessions.record_bytes(&responder.key, 1000);
+        assert!(initiator.sessions.rekey_requests(&rekey, now).is_empty());
+        let later = now + PREVIOUS_KEY_LIFETIME;
+        let [(_, request)] = initiator.sessions.rekey_requests(&rekey, later).try_into().unwrap();
+        assert_eq!(request.epoch, 2);
+
+        // Unanswered requests are repeated
+        assert!(initiator.sessions.rekey_requests(&rekey, later).is_empty());
+        let retry = later + REKEY_RETRY_INTERVAL;
+        let [(_, repeated)] = initiator.sessions.rekey_requests(&rekey, retry).try_into().unwrap();
+        assert_eq!(repeated, request);
+        // And answered the same way, in case the initiator got the first response after all
+        let response = responder
+            .sessions
+            .handle_rekey_request(&initiator.key, &request)
+            .unwrap();
+        assert_eq!(
+            responder.sessions.handle_rekey_request(&initiator.key, &repeated),
+            Some(response)
+        );
+    }
+
+    #[test]
+    fn test_restarted_peer_starts_over() {
+        let now = Instant::now();
+        let (initiator, responder) = peers(now);
+        let before_rekey = sealed(&initiator, &responder);
+        rekey(&initiator, &responder, now);
+        assert!(delivered(&initiator, &responder, now));
+
+        // Once the static cipher's grace period is over, a message under it means the peer restarted, unless it is a
+        // replay of an old message
+        let later = now + PREVIOUS_KEY_LIFETIME;
+        assert!(matches!(
+            responder.sessions.decrypt(&initiator.key, &before_rekey, later),
+            Some(Decrypted::Duplicate(_))
+        ));
+        assert_eq!(epoch(&responder, &initiator), 1);
+
+        let restarted = Peer::new(initiator.sessions.private_key.clone(), responder.key, later);
+        assert!(delivered(&restarted, &responder, later));
+        assert_eq!(epoch(&responder, &restarted), 0);
+        rekey(&restarted, &responder, later);
+        assert!(delivered(&restarted, &responder, later));
+        assert!(delivered(&responder, &restarted, later));
+    }
+
+    #[test]
+    fn test_stale_requests_are_ignored() {
+        let now = Instant::now();
+        let (initiator, responder) = peers(now);
+        let rekey = warp_config::RekeyConfig::default();
+        let [(_, request)] = initiator.sessions.rekey_requests(&rekey, now).try_into().unwrap();
+        let response = responder
+            .sessions
+            .handle_rekey_request(&initiator.key, &request)
+            .unwrap();
+        assert!(initiator.sessions.handle_rekey_response(&responder.key, &response, now));
+        assert!(delivered(&initiator, &responder, now));
+
+        assert_eq!(responder.sessions.handle_rekey_request(&initiator.key, &request), None);
+        assert!(!initiator.sessions.handle_rekey_response(&responder.key, &response, now));
+    }
+
+    #[test]
+    fn test_handshake_needs_far_gate_key() {
+        let now = Instant::now();
+        let (initiator, responder) = peers(now);
+        let impostor = Peer::new(warp_protocol::PrivateKey::random(&mut rand::rng()), initiator.key, now);
+        let rekey = warp_config::RekeyConfig::default();
+        let [(_, request)] = initiator.sessions.rekey_requests(&rekey, now).try_into().unwrap();
+
+        // Someone who can read the request but doesn't hold the responder's key can't answer it
+        let forged = impostor
+            .sessions
+            .handle_rekey_request(&initiator.key, &request)
+            .unwrap();
+        assert!(!initiator.sessions.handle_rekey_response(&responder.key, &forged, now));
+        assert_eq!(epoch(&initiator, &responder), 0);
+
+        let response = responder
+            .sessions
+            .handle_rekey_request(&initiator.key, &request)
+            .unwrap();
+        assert!(initiator.sessions.handle_rekey_response(&responder.key, &response, now));
+        assert_eq!(epoch(&initiator, &responder), 1);
+        assert!(delivered(&initiator, &responder, now));
+    }
+
+    #[test]
+    fn test_negotiates_cipher_suite() {
+        use CipherSuite::{Aes256Gcm, ChaCha20Poly1305, XChaCha20Poly1305};
+        let now = Instant::now();
+        let (initiator, responder) = peers(now);
+        let config = warp_config::RekeyConfig::default();
+
+        // The responder's preference wins among the suites both allow
+        initiator
+            .sessions
+            .set_cipher_suites(vec![ChaCha20Poly1305, XChaCha20Poly1305]);
+        responder
+            .sessions
+            .set_cipher_suites(vec![XChaCha20Poly1305, ChaCha20Poly1305]);
+        rekey(&initiator, &responder, now);
+        assert_eq!(
+            initiator.sessions.cipher(&responder.key).unwrap().suite(),
+            XChaCha20Poly1305
+        );
+        assert!(delivered(&initiator, &responder, now));
+        assert!(delivered(&responder, &initiator, now));
+
+        // Without a suite in common the session keeps its key
+        let later = now + config.interval + PREVIOUS_KEY_LIFETIME;
+        initiator.sessions.set_cipher_suites(vec![ChaCha20Poly1305]);
+        responder.sessions.set_cipher_suites(vec![XChaCha20Poly1305]);
+        let [(_, request)] = initiator.sessions.rekey_requests(&config, later).try_into().unwrap();
+        assert_eq!(responder.sessions.handle_rekey_request(&initiator.key, &request), None);
+        assert_eq!(epoch(&initiator, &responder), 1);
+        assert!(delivered(&initiator, &responder, later));
+
+        // AES-256-GCM is only picked by peers that list it
+        let (initiator, responder) = peers(now);
+        initiator.sessions.set_cipher_suites(vec![ChaCha20Poly1305, Aes256Gcm]);
+        responder.sessions.set_cipher_suites(vec![Aes256Gcm, XChaCha20Poly1305]);
+        rekey(&initiator, &responder, now);
+        assert_eq!(initiator.sessions.cipher(&responder.key).unwrap().suite(), Aes256Gcm);
+        assert!(delivered(&initiator, &responder, now));
+        assert!(delivered(&responder, &initiator, now));
+    }
+
+    #[test]
+    fn test_tunnel_keys() {
+        let now = Instant::now();
+        let (initiator, responder) = peers(now);
+        let tunnels = |peer: &Peer| BTreeMap::from([(peer.key, vec![TunnelId::Id(1), TunnelId::Id(2)])]);
+        initiator.sessions.set_tunnels(&tunnels(&responder));
+        responder.sessions.set_tunnels(&tunnels(&initiator));
+
+        // The static cipher never has tunnel keys
+        let (msg, keyed) = sealed_tunnel_payload(&initiator, &responder, TunnelId::Id(1));
+        assert!(!keyed);
+        assert!(received(&responder, &initiator, &msg, now));
+
+        rekey(&initiator, &responder, now);
+        let (msg, keyed) = sealed_tunnel_payload(&initiator, &responder, TunnelId::Id(1));
+        assert!(keyed);
+        let Some(Decrypted::Message(decrypted)) = responder.sessions.decrypt(&initiator.key, &msg, now) else {
+            panic!("not received");
+        };
+        assert_eq!(decrypted.message_id, KeyedTunnelPayload::MESSAGE_ID);
+        // Which also moved the responder over to the new key and its tunnel keys
+        let (msg, keyed) = sealed_tunnel_payload(&responder, &initiator, TunnelId::Id(2));
+        assert!(keyed);
+        assert!(received(&initiator, &responder, &msg, now));
+
+        // Tunnels the peers don't both know of are sealed with the session key
+        let (msg, keyed) = sealed_tunnel_payload(&initiator, &responder, TunnelId::Id(3));
+        assert!(!keyed);
+        assert!(received(&responder, &initiator, &msg, now));
+        // And once a tunnel is closed at the far gate, its payloads can't be opened there
+        responder
+            .sessions
+            .set_tunnels(&BTreeMap::from([(initiator.key, vec![TunnelId::Id(2)])]));
+        let (msg, keyed) = sealed_tunnel_payload(&initiator, &responder, TunnelId::Id(1));
+        assert!(keyed);
+        assert!(responder.sessions.decrypt(&initiator.key, &msg, now).is_none());
+    }
+
+    #[test]
+    fn test_tunnel_keys_need_both_peers() {
+        let now = Instant::now();
+        let (initiator, responder) = peers(now);
+        let tunnels = |peer: &Peer| BTreeMap::from([(peer.key, vec![TunnelId::Id(1)])]);
+        initiator.sessions.set_tunnels(&tunnels(&responder));
+        responder.sessions.set_tunnels(&tunnels(&initiator));
+
+        // A request from an initiator that predates tunnel keys leaves them out
+        let rekey = warp_config::RekeyConfig::default();
+        let [(_, mut request)] = initiator.sessions.rekey_requests(&rekey, now).try_into().unwrap();
+        request.tunnel_keys = false;
+        let response = responder
+            .sessions
+            .handle_rekey_request(&initiator.key, &request)
+            .unwrap();
+        assert!(!response.tunnel_keys);
+        assert!(initiator.sessions.handle_rekey_response(&responder.key, &response, now));
+
+        let (msg, keyed) = sealed_tunnel_payload(&initiator, &responder, TunnelId::Id(1));
+        assert!(!keyed);
+        assert!(received(&responder, &initiator, &msg, now));
+        let (msg, keyed) = sealed_tunnel_payload(&responder, &initiator, TunnelId::Id(1));
+        assert!(!keyed);
+        assert!(received(&initiator, &responder, &msg, now));
+    }
+
+    #[test]
+    fn test_compression_needs_both_peers() {
+        let now = Instant::now();
+        let (initiator, responder) = peers(now);
+        // Not under the static cipher, which was never agreed on
+        assert!(!initiator.sessions.compression(&responder.key));
+
+        rekey(&initiator, &responder, now);
+        assert!(initiator.sessions.compression(&responder.key));
+        // The responder only moves over once something arrives under the new key
+        assert!(!responder.sessions.compression(&initiator.key));
+        assert!(delivered(&initiator, &responder, now));
+        assert!(responder.sessions.compression(&initiator.key));
+
+        // An initiator that predates compression leaves it out
+        let later = now + PREVIOUS_KEY_LIFETIME + warp_config::RekeyConfig::default().interval;
+        let rekey = warp_config::RekeyConfig::default();
+        let [(_, mut request)] = initiator.sessions.rekey_requests(&rekey, later).try_into().unwrap();
+        request.compression = false;
+        let response = responder
+            .sessions
+            .handle_rekey_request(&initiator.key, &request)
+            .unwrap();
+        assert!(!response.compression);
+        assert!(
+            initiator
+                .sessions
+                .handle_rekey_response(&responder.key, &response, later)
+        );
+        assert!(!initiator.sessions.compression(&responder.key));
+        assert!(delivered(&initiator, &responder, later));
+        assert!(!responder.sessions.compression(&initiator.key));
+    }
+}
@@ -0,0 +1,187 @@
+//! What warp learns while running that is worth keeping across a restart, saved to `state_file`
+//!
+//! Without it a restarted warp knows no far gate addresses until warp-map answers, and binds new ports that neither the
+//! NATs on the way nor the far gates know about. With it warp sends to the addresses it last used straight away, and
+//! binds each interface's old port again so that the external address warp-map saw for it still holds; anything out
+//! of date is replaced as soon as warp-map and the far gates are heard from.
+
+use crate::routing::RoutingState;
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct State {
+    #[serde(default)]
+    pub peers: Vec<PeerState>,
+    #[serde(default)]
+    pub address_overrides: Vec<AddressOverride>,
+    #[serde(default)]
+    pub interfaces: Vec<InterfaceState>,
+}
+
+/// A far gate's addresses, as warp-map last gave them
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PeerState {
+    pub public_key: String,
+    pub addresses: Vec<SocketAddr>,
+}
+
+/// Send to `address` instead of `replace` from the interface called `interface`, as a far gate asked
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+pub struct AddressOverride {
+    pub interface: String,
+    pub replace: SocketAddr,
+    pub address: SocketAddr,
+}
+
+/// The port an interface was bound to and the address warp-map saw it from
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+pub struct InterfaceState {
+    pub name: String,
+    pub ip: IpAddr,
+    pub port: u16,
+    pub external_address: Option<SocketAddr>,
+}
+
+impl State {
+    /// The state saved at `path`, or `None` if nothing has been saved there yet
+    pub async fn load(path: &std::path::Path) -> anyhow::Result<Option<Self>> {
+        match tokio::fs::read_to_string(path).await {
+            Ok(contents) => Ok(Some(toml::from_str(&contents)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save to `path`, replacing what was there only once the new state has been written in full
+    pub async fn save(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        tokio::fs::write(&temporary, toml::to_string(self)?).await?;
+        tokio::fs::rename(&temporary, path).await?;
+        Ok(())
+    }
+
+    /// What `routing_state` has learned so far
+    pub fn capture(routing_state: &RoutingState) -> Self {
+        let (peer_addresses, address_overrides) = routing_state.learned_addresses();
+        let mut address_overrides: Vec<_> = address_overrides
+            .into_iter()
+            .map(|((interface, replace), address)| AddressOverride {
+                interface,
+                replace,
+                address,
+            })
+            .collect();
+        address_overrides.sort();
+        let mut interfaces: Vec<_> = routing_state
+            .interfaces()
+            .iter()
+            .map(|interface| InterfaceState {
+                name: interface.id.name.clone(),
+                ip: interface.id.ip,
+                port: interface.local_addr().port(),
+                external_address: interface.get_external_address(),
+            })
+            .collect();
+        interfaces.sort();
+
+        Self {
+            peers: peer_addresses
+                .into_iter()
+                .map(|(public_key, addresses)| PeerState {
+                    public_key: warp_protocol::crypto::pubkey_to_string(&public_key),
+                    addresses,
+                })
+                .collect(),
+            address_overrides,
+            interfaces,
+        }
+    }
+
+    /// Start `routing_state` from the saved addresses, skipping far gates whose keys can't be read
+    pub fn restore(&self, routing_state: &RoutingState) {
+        let peer_addresses = self
+            .peers
+            .iter()
+            .filter_map(
+                |peer| match warp_protocol::crypto::pubkey_from_string(&peer.public_key) {
+                    Ok(public_key) => Some((public_key, peer.addresses.clone())),
+                    Err(e) => {
+                        tracing::warn!("Ignoring saved addresses of far gate {}: {}", peer.public_key, e);
+                        None
+                    }
+                },
+            )
+            .collect();
+        let address_overrides = self
+            .address_overrides
+            .iter()
+            .map(|address_override| {
+                (
+                    (address_override.interface.clone(), address_override.replace),
+                    address_override.address,
+                )
+            })
+            .collect();
+        routing_state.restore_addresses(peer_addresses, address_overrides);
+    }
+
+    /// What was saved for the interface called `name` with address `ip`
+    pub fn interface(&self, name: &str, ip: IpAddr) -> Option<&InterfaceState> {
+        self.interfaces
+            .iter()
+            .find(|interface| interface.name == name && interface.ip == ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_restore() {
+        let far_gate = warp_protocol::PrivateKey::random(&mut rand::rng()).public_key();
+        let state = State {
+            peers: vec![PeerState {
+                public_key: warp_protocol::crypto::pubkey_to_string(&far_gate),
+                addresses: vec!["198.51.100.7:4000".parse().unwrap()],
+            }],
+            address_overrides: vec![AddressOverride {
+                interface: "eth0".to_string(),
+                replace: "198.51.100.7:4000".parse().unwrap(),
+                address: "192.168.1.20:4000".parse().unwrap(),
+            }],
+            interfaces: vec![InterfaceState {
+                name: "eth0".to_string(),
+                ip: "192.168.1.10".parse().unwrap(),
+                port: 41000,
+                external_address: Some("203.0.113.5:52000".parse().unwrap()),
+            }],
+        };
+
+        let path = std::env::temp_dir().join(format!("warp-state-test-{}.toml", std::process::id()));
+        assert_eq!(State::load(&path).await.unwrap(), None);
+        state.save(&path).await.unwrap();
+        let loaded = State::load(&path).await.unwrap().unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(
+            loaded
+                .interface("eth0", "192.168.1.10".parse().unwrap())
+                .map(|i| i.port),
+            Some(41000)
+        );
+        assert!(loaded.interface("eth0", "192.168.1.11".parse().unwrap()).is_none());
+
+        // Restored into the routing state, it is captured again as it was, apart from the interfaces it doesn't hold
+        let routing_state = RoutingState::new();
+        loaded.restore(&routing_state);
+        assert_eq!(
+            State::capture(&routing_state),
+            State {
+                interfaces: Vec::new(),
+                ..state
+            }
+        );
+    }
+}
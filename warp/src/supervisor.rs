@@ -0,0 +1,130 @@
+//! Restarting warp's tasks when they panic, rather than leaving warp without them
+//!
+//! The core's tasks and each gate's run under [`Supervisor::run`]. A task that returns is done: they loop until warp
+//! stops, or, like the rx workers, until what they take work from is closed. One that panics is run again after a backoff, which
+//! starts at [`INITIAL_BACKOFF`] and doubles with each panic in a row up to [`MAX_BACKOFF`], so a task that keeps
+//! failing doesn't spin. A task is run again from its closure, so the closure's captures carry over (e.g. the receiver of
+//! its queue) and the locals in its body start afresh. Restarts are logged as `TASK_RESTARTING`, counted in the
+//! `warp_task_restarts_total` metric and reported, by task, in [`crate::health::HealthReport::restarts`].
+
+use crate::health::Health;
+use futures::FutureExt;
+use std::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// A task that runs this long before panicking is back to the initial backoff
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// Runs tasks, and runs them again when they panic, until `stop` is cancelled
+#[derive(Clone)]
+pub(crate) struct Supervisor {
+    health: Health,
+    stop: tokio_util::sync::CancellationToken,
+}
+
+impl Supervisor {
+    pub fn new(health: Health, stop: tokio_util::sync::CancellationToken) -> Self {
+        Self { health, stop }
+    }
+
+    /// A supervisor that stops running tasks again once `stop` is cancelled instead
+    pub fn stopping_with(&self, stop: tokio_util::sync::CancellationToken) -> Self {
+        Self {
+            health: self.health.clone(),
+            stop,
+        }
+    }
+
+    /// Run `task`, called `name`, until it returns, running it again whenever it panics
+    pub async fn run(self, name: impl Into<String>, mut task: impl AsyncFnMut()) {
+        let name = name.into();
+        let mut backoff = Backoff::default();
+        loop {
+            let started = Instant::now();
+            let Err(panic) = std::panic::AssertUnwindSafe(task()).catch_unwind().await else {
+                return;
+            };
+            if self.stop.is_cancelled() {
+                return;
+            }
+            let delay = backoff.after_panic(started.elapsed());
+            let restarts = self.health.restarted(&name);
+            crate::metrics::task_restarts(&name).inc();
+            tracing::event!(
+                tracing::Level::WARN,
+                task = %name,
+                restarts,
+                backoff_ms = delay.as_millis() as u64,
+                error = panic_message(&*panic),
+                "TASK_RESTARTING"
+            );
+            tokio::select! {
+                () = tokio::time::sleep(delay) => {}
+                () = self.stop.cancelled() => return,
+            }
+        }
+    }
+}
+
+// How long to wait before running a task again
+#[derive(Debug, Default)]
+struct Backoff {
+    // The last wait, while the task keeps panicking
+    last: Option<Duration>,
+}
+
+impl Backoff {
+    fn after_panic(&mut self, ran_for: Duration) -> Duration {
+        let next = match self.last {
+            Some(last) if ran_for < STABLE_RUN => (last * 2).min(MAX_BACKOFF),
+            _ => INITIAL_BACKOFF,
+        };
+        self.last = Some(next);
+        next
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("panicked")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_until_the_task_runs_stably() {
+        let mut backoff = Backoff::default();
+        let quick = Duration::from_millis(5);
+        assert_eq!(backoff.after_panic(quick), INITIAL_BACKOFF);
+        assert_eq!(backoff.after_panic(quick), INITIAL_BACKOFF * 2);
+        assert_eq!(backoff.after_panic(quick), INITIAL_BACKOFF * 4);
+        for _ in 0..20 {
+            backoff.after_panic(quick);
+        }
+        assert_eq!(backoff.after_panic(quick), MAX_BACKOFF);
+        assert_eq!(backoff.after_panic(STABLE_RUN), INITIAL_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_panicking_task_is_run_again() {
+        let health = Health::new();
+        let supervisor = Supervisor::new(health.clone(), tokio_util::sync::CancellationToken::new());
+        let mut runs = 0;
+        supervisor
+            .run("flaky", async || {
+                runs += 1;
+                if runs < 3 {
+                    panic!("flaky task failed");
+                }
+            })
+            .await;
+        assert_eq!(runs, 3);
+        assert_eq!(health.report().restarts.get("flaky"), Some(&2));
+    }
+}
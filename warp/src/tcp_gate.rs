@@ -0,0 +1,485 @@
+//! Carries TCP connections through a tunnel as streams of tunnel payloads
+//!
+//! Each payload holds one [`Frame`]: a chunk of one connection's byte stream, tagged with the stream's id and the
+//! chunk's offset in it. The gate that accepted the connection picks a random stream id; the far gate opens a
+//! connection for each stream id it hasn't seen before. A frame marked `fin` closes its direction of the stream.
+//!
+//! Tunnels don't retransmit, so a stream only survives on a tunnel that delivers every payload in order. When a frame
+//! goes missing the stream is reset: its connection is dropped rather than handed a corrupted byte stream.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// Leaves room for the frame header and the tunnel's own overhead in a typical MTU
+const FRAME_DATA_SIZE: usize = 1300;
+const FRAME_HEADER_SIZE: usize = 17;
+
+/// A chunk of one stream's bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub stream_id: u64,
+    /// Where `data` starts in the stream
+    pub offset: u64,
+    /// No more data follows in this direction
+    pub fin: bool,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(FRAME_HEADER_SIZE + self.data.len());
+        bytes.extend_from_slice(&self.stream_id.to_le_bytes());
+        bytes.extend_from_slice(&self.offset.to_le_bytes());
+        bytes.push(self.fin as u8);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.len() < FRAME_HEADER_SIZE {
+            anyhow::bail!("{} byte frame is shorter than its header", bytes.len());
+        }
+        let (stream_id, rest) = bytes.split_at(8);
+        let (offset, rest) = rest.split_at(8);
+        let (fin, data) = rest.split_at(1);
+        Ok(Self {
+            stream_id: u64::from_le_bytes(stream_id.try_into()?),
+            offset: u64::from_le_bytes(offset.try_into()?),
+            fin: match fin[0] {
+                0 => false,
+                1 => true,
+                flag => anyhow::bail!("invalid fin flag {flag}"),
+            },
+            data: data.to_vec(),
+        })
+    }
+}
+
+enum ToConnection {
+    Data(Vec<u8>),
+    Fin,
+}
+
+// Where frames from the far gate go; dropping `to_connection` without sending `Fin` resets the connection
+struct Stream {
+    to_connection: mpsc::UnboundedSender<ToConnection>,
+    next_offset: u64,
+}
+
+/// What became of a frame from the far gate
+#[derive(Debug, PartialEq)]
+pub enum Delivery {
+    Delivered,
+    /// The stream is new; its connection is being opened
+    Opened,
+    /// A frame went missing before this one; the stream was reset
+    Reset,
+    /// The frame was a duplicate, or for a stream that no longer exists
+    Dropped,
+}
+
+pub struct TcpGate {
+    tunnel_name: String,
+    connect: Option<SocketAddr>,
+    from_streams: tokio::sync::Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+    frames: mpsc::UnboundedSender<Vec<u8>>,
+    streams: Arc<Mutex<HashMap<u64, Stream>>>,
+    accept_task: Option<JoinHandle<()>>,
+}
+
+impl TcpGate {
+    pub fn bind(config: &warp_config::TcpListenerConfig, tunnel_name: &str) -> anyhow::Result<Arc<Self>> {
+        let (frames, from_streams) = mpsc::unbounded_channel();
+        let listener = match config.listen {
+            Some(listen) => {
+                let std_listener = std::net::TcpListener::bind(listen)?;
+                std_listener.set_nonblocking(true)?;
+                tracing::info!("warp-gate {}: accepting TCP connections at {}", tunnel_name, listen);
+                Some(tokio::net::TcpListener::from_std(std_listener)?)
+            }
+            None => None,
+        };
+        if let Some(connect) = config.connect {
+            tracing::info!("warp-gate {}: opening TCP connections to {}", tunnel_name, connect);
+        }
+
+        Ok(Arc::new_cyclic(|gate: &std::sync::Weak<Self>| {
+            let accept_task = listener.map(|listener| {
+                let gate = gate.clone();
+                let tunnel_name = tunnel_name.to_string();
+                tokio::task::Builder::new()
+                    .name(&format!("warp-gate {tunnel_name}: TCP listener"))
+                    .spawn(async move {
+                        loop {
+                            match listener.accept().await {
+                                Ok((connection, peer)) => {
+                                    let Some(gate) = gate.upgrade() else {
+                                        break;
+                                    };
+                                    let stream_id = rand::random();
+                                    tracing::event!(
+                                        tracing::Level::INFO,
+                                        tunnel_name = tunnel_name,
+                                        stream_id = stream_id,
+                                        peer = %peer,
+                                        "TCP_STREAM_ACCEPTED"
+                                    );
+                                    let to_connection = gate.add_stream(stream_id);
+                                    gate.run_connection(stream_id, connection, to_connection);
+                                }
+                                Err(e) => tracing::event!(
+                                    tracing::Level::WARN,
+                                    tunnel_name = tunnel_name,
+                                    error = %e,
+                                    "TCP_ACCEPT_FAILED"
+                                ),
+                            }
+                        }
+                    })
+                    .expect("task initialised")
+            });
+
+            Self {
+                tunnel_name: tunnel_name.to_string(),
+                connect: config.connect,
+                from_streams: tokio::sync::Mutex::new(from_streams),
+                frames,
+                streams: Arc::new(Mutex::new(HashMap::new())),
+                accept_task,
+            }
+        }))
+    }
+
+    /// The next encoded frame to send to the far gate
+    pub async fn recv_frame(&self) -> Option<Vec<u8>> {
+        self.from_streams.lock().await.recv().await
+    }
+
+    /// Pass a frame from the far gate on to its stream's connection, opening the connection if the stream is new
+    pub fn deliver(&self, frame: Frame) -> Delivery {
+        let mut streams = self.streams.lock().unwrap();
+        let Some(stream) = streams.get_mut(&frame.stream_id) else {
+            drop(streams);
+            return self.open(frame);
+        };
+
+        if frame.offset < stream.next_offset {
+            return Delivery::Dropped;
+        }
+        if frame.offset > stream.next_offset {
+            // Dropping the sender closes the connection
+            streams.remove(&frame.stream_id);
+            return Delivery::Reset;
+        }
+
+        stream.next_offset += frame.data.len() as u64;
+        let mut closed = !frame.data.is_empty() && stream.to_connection.send(ToConnection::Data(frame.data)).is_err();
+        if frame.fin {
+            let _ = stream.to_connection.send(ToConnection::Fin);
+            closed = true;
+        }
+        if closed {
+            streams.remove(&frame.stream_id);
+        }
+        Delivery::Delivered
+    }
+
+    fn open(&self, frame: Frame) -> Delivery {
+        // A stream that doesn't start at 0 has already been reset, or lost its first frame
+        let Some(connect) = self.connect.filter(|_| frame.offset == 0) else {
+            return Delivery::Dropped;
+        };
+
+        let to_connection = self.add_stream(frame.stream_id);
+        let gate = self.clone_handle();
+        let tunnel_name = self.tunnel_name.clone();
+        let stream_id = frame.stream_id;
+        tokio::task::Builder::new()
+            .name(&format!("warp-gate {tunnel_name}: TCP connect"))
+            .spawn(async move {
+                match tokio::net::TcpStream::connect(connect).await {
+                    Ok(connection) => {
+                        tracing::event!(
+                            tracing::Level::INFO,
+                            tunnel_name = tunnel_name,
+                            stream_id = stream_id,
+                            peer = %connect,
+                            "TCP_STREAM_CONNECTED"
+                        );
+                        gate.run_connection(stream_id, connection, to_connection);
+                    }
+                    Err(e) => {
+                        tracing::event!(
+                            tracing::Level::WARN,
+                            tunnel_name = tunnel_name,
+                            stream_id = stream_id,
+                            peer = %connect,
+                            error = %e,
+                            "TCP_STREAM_CONNECT_FAILED"
+                        );
+                        gate.streams.lock().unwrap().remove(&stream_id);
+                        // Tell the far gate its stream has nothing behind it
+                        let _ = gate.frames.send(
+                            Frame {
+                                stream_id,
+                                offset: 0,
+                                fin: true,
+                                data: Vec::new(),
+                            }
+                            .encode(),
+                        );
+                    }
+                }
+            })
+            .expect("task initialised");
+
+        // The frame goes through the new stream like any other, queueing until the connection is open
+        match self.deliver(frame) {
+            Delivery::Delivered => Delivery::Opened,
+            delivery => delivery,
+        }
+    }
+
+    fn add_stream(&self, stream_id: u64) -> mpsc::UnboundedReceiver<ToConnection> {
+        let (to_connection, from_far_gate) = mpsc::unbounded_channel();
+        self.streams.lock().unwrap().insert(
+            stream_id,
+            Stream {
+                to_connection,
+                next_offset: 0,
+            },
+        );
+        from_far_gate
+    }
+
+    // The parts of the gate that connection tasks need
+    fn clone_handle(&self) -> ConnectionHandle {
+        ConnectionHandle {
+            tunnel_name: self.tunnel_name.clone(),
+            frames: self.frames.clone(),
+            streams: self.streams.clone(),
+        }
+    }
+
+    fn run_connection(
+        &self,
+        stream_id: u64,
+        connection: tokio::net::TcpStream,
+        from_far_gate: mpsc::UnboundedReceiver<ToConnection>,
+    ) {
+        self.clone_handle().run_connection(stream_id, connection, from_far_gate);
+    }
+}
+
+impl Drop for TcpGate {
+    fn drop(&mut self) {
+        if let Some(task) = &self.accept_task {
+            task.abort();
+        }
+    }
+}
+
+struct ConnectionHandle {
+    tunnel_name: String,
+    frames: mpsc::UnboundedSender<Vec<u8>>,
+    streams: Arc<Mutex<HashMap<u64, Stream>>>,
+}
+
+impl ConnectionHandle {
+    // Copy the connection's bytes into frames for the far gate, and the far gate's frames into the connection
+    fn run_connection(
+        self,
+        stream_id: u64,
+        connection: tokio::net::TcpStream,
+        mut from_far_gate: mpsc::UnboundedReceiver<ToConnection>,
+    ) {
+        let (mut reader, mut writer) = connection.into_split();
+
+        let reader_task = tokio::task::Builder::new()
+            .name(&format!(
+                "warp-gate {}: TCP stream {stream_id} reader",
+                self.tunnel_name
+            ))
+            .spawn({
+                let tunnel_name = self.tunnel_name.clone();
+                let frames = self.frames.clone();
+                async move {
+                    let mut buf = vec![0u8; FRAME_DATA_SIZE];
+                    let mut offset = 0u64;
+                    loop {
+                        let (data, fin) = match reader.read(&mut buf).await {
+                            Ok(0) => (Vec::new(), true),
+                            Ok(size) => (buf[..size].to_vec(), false),
+                            Err(e) => {
+                                tracing::event!(
+                                    tracing::Level::WARN,
+                                    tunnel_name = tunnel_name,
+                                    stream_id = stream_id,
+                                    error = %e,
+                                    "TCP_STREAM_READ_FAILED"
+                                );
+                                (Vec::new(), true)
+                            }
+                        };
+                        let size = data.len() as u64;
+                        let frame = Frame {
+                            stream_id,
+                            offset,
+                            fin,
+                            data,
+                        };
+                        if frames.send(frame.encode()).is_err() || fin {
+                            break;
+                        }
+                        offset += size;
+                    }
+                }
+            })
+            .expect("task initialised");
+
+        tokio::task::Builder::new()
+            .name(&format!(
+                "warp-gate {}: TCP stream {stream_id} writer",
+                self.tunnel_name
+            ))
+            .spawn({
+                let tunnel_name = self.tunnel_name;
+                async move {
+                    loop {
+                        match from_far_gate.recv().await {
+                            Some(ToConnection::Data(data)) => {
+                                if let Err(e) = writer.write_all(&data).await {
+                                    tracing::event!(
+                                        tracing::Level::WARN,
+                                        tunnel_name = tunnel_name,
+                                        stream_id = stream_id,
+                                        error = %e,
+                                        "TCP_STREAM_WRITE_FAILED"
+                                    );
+                                    self.streams.lock().unwrap().remove(&stream_id);
+                                    reader_task.abort();
+                                    return;
+                                }
+                            }
+                            Some(ToConnection::Fin) => {
+                                // The reader carries on until the application closes its side too
+                                let _ = writer.shutdown().await;
+                                return;
+                            }
+                            None => {
+                                tracing::event!(
+                                    tracing::Level::INFO,
+                                    tunnel_name = tunnel_name,
+                                    stream_id = stream_id,
+                                    "TCP_STREAM_RESET"
+                                );
+                                // Dropping both halves closes the connection
+                                reader_task.abort();
+                                return;
+                            }
+                        }
+                    }
+                }
+            })
+            .expect("task initialised");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_round_trip() {
+        let frame = Frame {
+            stream_id: 0x0102030405060708,
+            offset: 1300,
+            fin: true,
+            data: vec![1, 2, 3],
+        };
+        assert_eq!(Frame::decode(&frame.encode()).unwrap(), frame);
+        assert!(Frame::decode(&[0; FRAME_HEADER_SIZE - 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_through_gates() {
+        let service = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // Reserve a port the OS considers free for the near gate to listen on
+        let near_address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let near = TcpGate::bind(
+            &warp_config::TcpListenerConfig {
+                listen: Some(near_address),
+                connect: None,
+            },
+            "near",
+        )
+        .unwrap();
+        let far = TcpGate::bind(
+            &warp_config::TcpListenerConfig {
+                listen: None,
+                connect: Some(service.local_addr().unwrap()),
+            },
+            "far",
+        )
+        .unwrap();
+
+        let mut client = tokio::net::TcpStream::connect(near_address).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+
+        // Carry frames between the gates until the service has the client's bytes
+        let frame = Frame::decode(&near.recv_frame().await.unwrap()).unwrap();
+        assert_eq!(frame.offset, 0);
+        assert_eq!(far.deliver(frame), Delivery::Opened);
+        let (mut accepted, _) = service.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        accepted.write_all(b"world").await.unwrap();
+        let frame = Frame::decode(&far.recv_frame().await.unwrap()).unwrap();
+        assert_eq!(near.deliver(frame), Delivery::Delivered);
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+
+        // Closing the service's end closes the client's end
+        drop(accepted);
+        let frame = Frame::decode(&far.recv_frame().await.unwrap()).unwrap();
+        assert!(frame.fin);
+        assert_eq!(near.deliver(frame), Delivery::Delivered);
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_missing_frame_resets_stream() {
+        let gate = TcpGate::bind(
+            &warp_config::TcpListenerConfig {
+                listen: None,
+                connect: None,
+            },
+            "gate",
+        )
+        .unwrap();
+        let mut from_far_gate = gate.add_stream(7);
+        let frame = |offset: u64, data: &[u8]| Frame {
+            stream_id: 7,
+            offset,
+            fin: false,
+            data: data.to_vec(),
+        };
+
+        assert_eq!(gate.deliver(frame(0, b"abc")), Delivery::Delivered);
+        assert_eq!(gate.deliver(frame(0, b"abc")), Delivery::Dropped);
+        assert_eq!(gate.deliver(frame(6, b"ghi")), Delivery::Reset);
+        assert!(matches!(from_far_gate.recv().await, Some(ToConnection::Data(data)) if data == b"abc"));
+        assert!(from_far_gate.recv().await.is_none());
+        // Without `connect`, streams can't be opened from the far end
+        assert_eq!(gate.deliver(frame(0, b"abc")), Delivery::Dropped);
+    }
+}
@@ -0,0 +1,230 @@
+//! Exporting the hops of tunnel payloads as OpenTelemetry spans over OTLP; configured by `telemetry`
+//!
+//! Each payload gets a trace of its own on each host, made of the hops in [`crate::trace`]. Sending, `gate_rx` lasts
+//! from the gate reading the payload until it has been warped, and holds `encode` (splitting it into FEC shards) and
+//! `accelerate` (waiting for the core and picking routes), which in turn holds an `encrypt` sealing its tunnel payloads
+//! and an `interface_send` per copy sent, queueing included. Receiving, `interface_rx` lasts from the datagram arriving
+//! (as the kernel stamped it, on Linux) until an rx worker gets to it, `decrypt` until the tunnel payload is decoded,
+//! and `gate_tx` until the gate has handed it to the application, reordering and reassembly included. Every span
+//! carries the payload's correlation id, which is how a trace is matched with the far gate's trace of the same payload.
+//!
+//! Finished spans are queued for a task that exports them in batches. If it falls behind, spans are dropped and counted
+//! rather than holding up the payloads.
+
+use crate::metrics;
+use crate::trace::TraceContext;
+use opentelemetry::trace::{SpanContext, SpanKind, Status, TraceFlags, TraceState};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{SpanData, SpanExporter};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
+
+// Finished spans waiting to be exported
+const QUEUE_SIZE: usize = 16384;
+const BATCH_SIZE: usize = 512;
+// How long spans gather before a batch that isn't full is exported
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+struct Exporter {
+    spans: tokio::sync::mpsc::Sender<FinishedSpan>,
+    // The payloads whose first hop's span id hashes below this are sampled; all of them if this is None
+    sample_below: Option<u64>,
+}
+
+struct FinishedSpan {
+    name: &'static str,
+    trace: TraceContext,
+    ended: Instant,
+}
+
+/// Start exporting spans as `config` says; fails if spans are already being exported
+pub fn start(config: &warp_config::TelemetryConfig) -> anyhow::Result<()> {
+    let mut exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(&config.otlp_endpoint)
+        .build()?;
+    exporter.set_resource(
+        &opentelemetry_sdk::Resource::builder()
+            .with_service_name(config.service_name.clone().unwrap_or_else(|| "warp".to_string()))
+            .build(),
+    );
+
+    let (spans_tx, spans_rx) = tokio::sync::mpsc::channel(QUEUE_SIZE);
+    // Trace ids are this followed by the span id of the payload's first hop, so that they differ between runs
+    let trace_id_prefix = rand::random();
+    let sample_below = match config.sample_ratio {
+        Some(ratio) if ratio < 1.0 => Some((ratio.max(0.0) * u64::MAX as f64) as u64),
+        _ => None,
+    };
+    EXPORTER
+        .set(Exporter {
+            spans: spans_tx,
+            sample_below,
+        })
+        .map_err(|_| anyhow::anyhow!("Spans are already being exported"))?;
+    tokio::task::Builder::new()
+        .name("telemetry exporter")
+        .spawn(export_spans(exporter, spans_rx, trace_id_prefix))?;
+    Ok(())
+}
+
+/// Queue `trace` for export as a span called `name` that ended at `ended`, if spans are exported and its payload is
+/// sampled
+pub(crate) fn export(trace: &TraceContext, name: &'static str, ended: Instant) {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    if !sampled(exporter.sample_below, trace.root_span_id) {
+        return;
+    }
+    let span = FinishedSpan {
+        name,
+        trace: trace.clone(),
+        ended,
+    };
+    if exporter.spans.try_send(span).is_err() {
+        metrics::TELEMETRY_SPANS_DROPPED.inc();
+    }
+}
+
+// Whether the payload whose first hop had span id `root_span_id` is sampled; all its spans are, or none of them
+fn sampled(sample_below: Option<u64>, root_span_id: crate::trace::SpanId) -> bool {
+    let Some(sample_below) = sample_below else {
+        return true;
+    };
+    // Span ids count up, so they are mixed (splitmix64) for every n-th payload not to be picked
+    let mut hash = root_span_id.wrapping_add(0x9e3779b97f4a7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^= hash >> 31;
+    hash < sample_below
+}
+
+async fn export_spans(
+    exporter: opentelemetry_otlp::SpanExporter,
+    mut spans: tokio::sync::mpsc::Receiver<FinishedSpan>,
+    trace_id_prefix: u64,
+) {
+    let scope = opentelemetry::InstrumentationScope::builder("warp").build();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    loop {
+        if spans.recv_many(&mut batch, BATCH_SIZE).await == 0 {
+            break;
+        }
+        let full = batch.len() == BATCH_SIZE;
+        let (now, system_now) = (Instant::now(), SystemTime::now());
+        let span_data = batch
+            .drain(..)
+            .map(|span| span_data(span, trace_id_prefix, &scope, now, system_now))
+            .collect();
+        if let Err(e) = exporter.export(span_data).await {
+            tracing::event!(tracing::Level::WARN, error = %e, "TELEMETRY_EXPORT_FAILED");
+        }
+        // Spans already waiting go out straight away, so that the queue doesn't fill up
+        if !full {
+            tokio::time::sleep(EXPORT_INTERVAL).await;
+        }
+    }
+}
+
+// `span` as OpenTelemetry has it, given that `now` is `system_now`
+fn span_data(
+    span: FinishedSpan,
+    trace_id_prefix: u64,
+    scope: &opentelemetry::InstrumentationScope,
+    now: Instant,
+    system_now: SystemTime,
+) -> SpanData {
+    let system_time = |instant: Instant| system_now - now.saturating_duration_since(instant);
+    let trace = span.trace;
+    let trace_id = (u128::from(trace_id_prefix) << 64) | u128::from(trace.root_span_id);
+    SpanData {
+        span_context: SpanContext::new(
+            trace_id.into(),
+            trace.span_id.into(),
+            TraceFlags::SAMPLED,
+            false,
+            TraceState::default(),
+        ),
+        parent_span_id: trace
+            .parent_span_id
+            .map_or(opentelemetry::trace::SpanId::INVALID, Into::into),
+        parent_span_is_remote: false,
+        span_kind: SpanKind::Internal,
+        name: span.name.into(),
+        start_time: system_time(trace.started),
+        end_time: system_time(span.ended),
+        attributes: vec![
+            opentelemetry::KeyValue::new("warp.correlation_id", trace.correlation.to_string()),
+            opentelemetry::KeyValue::new("warp.tracer", trace.correlation.tracer as i64),
+        ],
+        dropped_attributes_count: 0,
+        events: Default::default(),
+        links: Default::default(),
+        status: Status::Unset,
+        instrumentation_scope: scope.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::Correlation;
+
+    #[test]
+    fn test_hops_become_spans_of_one_trace() {
+        let root = TraceContext::root(Correlation::new(&"video".into(), 7));
+        let child = root.child();
+        let ended = child.started + Duration::from_millis(3);
+        let (now, system_now) = (ended + Duration::from_millis(10), SystemTime::now());
+        let scope = opentelemetry::InstrumentationScope::builder("warp").build();
+
+        let root_span = span_data(
+            FinishedSpan {
+                name: "gate_rx",
+                trace: root.clone(),
+                ended,
+            },
+            5,
+            &scope,
+            now,
+            system_now,
+        );
+        let child_span = span_data(
+            FinishedSpan {
+                name: "encode",
+                trace: child.clone(),
+                ended,
+            },
+            5,
+            &scope,
+            now,
+            system_now,
+        );
+
+        assert_eq!(child_span.span_context.trace_id(), root_span.span_context.trace_id());
+        assert_eq!(child_span.parent_span_id, root_span.span_context.span_id());
+        assert_eq!(root_span.parent_span_id, opentelemetry::trace::SpanId::INVALID);
+        assert_eq!(child_span.end_time, system_now - Duration::from_millis(10));
+        assert_eq!(
+            child_span.end_time.duration_since(child_span.start_time).unwrap(),
+            Duration::from_millis(3)
+        );
+        assert!(
+            child_span
+                .attributes
+                .contains(&opentelemetry::KeyValue::new("warp.correlation_id", "video:7"))
+        );
+    }
+
+    #[test]
+    fn test_sampling_keeps_whole_payloads() {
+        let half = Some(u64::MAX / 2);
+        let kept = (1..10_000).filter(|&root_span_id| sampled(half, root_span_id)).count();
+        assert!((4_500..5_500).contains(&kept), "{kept} of 10000 sampled");
+        assert!((1..1000).all(|root_span_id| sampled(None, root_span_id)));
+        assert!(!(1..1000).any(|root_span_id| sampled(Some(0), root_span_id)));
+    }
+}
@@ -0,0 +1,158 @@
+//! Identifiers for reconstructing a tunnel payload's journey from the logs of both ends of a tunnel
+//!
+//! Every event about a tunnel payload carries a `correlation_id` of `<tunnel>:<tracer>`. The tunnel id and tracer travel
+//! inside the payload, so the sender's and receiver's logs agree on it.
+//!
+//! Within one host, each hop the payload passes through (gate → accelerator → interface on the sender; interface → rx
+//! worker → gate on the receiver) gets its own `span_id` and records the span before it as `parent_span_id`. The
+//! receiving interface can't decrypt the datagram, so its `INTERFACE_RX` event has a span id but no correlation id; the
+//! rx worker's `TUNNEL_PAYLOAD_RX` event links the two. Span ids are only unique within one process.
+//!
+//! Each hop also knows when it started, so that it can be exported as an OpenTelemetry span once it ends; see
+//! [`crate::telemetry`].
+
+use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub type SpanId = u64;
+
+pub fn new_span_id() -> SpanId {
+    static NEXT_SPAN_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_SPAN_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// How a tunnel appears in correlation ids: its name, or `#<id>` for numeric tunnel ids
+pub fn tunnel_label(tunnel_id: &warp_protocol::messages::TunnelId) -> Arc<str> {
+    match tunnel_id {
+        warp_protocol::messages::TunnelId::Name(name) => name.as_str().into(),
+        warp_protocol::messages::TunnelId::Id(id) => format!("#{id}").into(),
+    }
+}
+
+/// Identifies one tunnel payload on both ends of the tunnel
+#[derive(Debug, Clone)]
+pub struct Correlation {
+    tunnel: Arc<str>,
+    pub tracer: u64,
+}
+
+impl Correlation {
+    pub fn new(tunnel: &Arc<str>, tracer: u64) -> Self {
+        Self {
+            tunnel: tunnel.clone(),
+            tracer,
+        }
+    }
+
+    /// The tunnel, as [`tunnel_label`] has it
+    pub fn tunnel(&self) -> &Arc<str> {
+        &self.tunnel
+    }
+
+    /// The correlation of the application payload that `tunnel_payload` carries, or carries a shard of
+    pub fn of_tunnel_payload(tunnel_payload: &warp_protocol::messages::TunnelPayload) -> Self {
+        let tracer = match &tunnel_payload.reconstruction_tag {
+            warp_protocol::messages::ReconstructionTag::Multipart(identifier) => identifier.parent_tracer,
+            _ => tunnel_payload.tracer,
+        };
+        Self {
+            tunnel: tunnel_label(&tunnel_payload.tunnel_id),
+            tracer,
+        }
+    }
+}
+
+impl Display for Correlation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.tunnel, self.tracer)
+    }
+}
+
+/// A tunnel payload's position on this host, handed from each hop to the next
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+    pub correlation: Correlation,
+    pub span_id: SpanId,
+    pub parent_span_id: Option<SpanId>,
+    /// The span id of the payload's first hop on this host
+    pub root_span_id: SpanId,
+    pub started: std::time::Instant,
+}
+
+impl TraceContext {
+    /// The first hop on this host, where nothing came before
+    pub fn root(correlation: Correlation) -> Self {
+        let span_id = new_span_id();
+        Self {
+            correlation,
+            span_id,
+            parent_span_id: None,
+            root_span_id: span_id,
+            started: std::time::Instant::now(),
+        }
+    }
+
+    /// The first hop of a payload that an interface received at `received_at` and logged with span id `span_id`,
+    /// before anyone could tell which payload it was
+    pub fn received(correlation: Correlation, span_id: SpanId, received_at: std::time::Instant) -> Self {
+        Self {
+            correlation,
+            span_id,
+            parent_span_id: None,
+            root_span_id: span_id,
+            started: received_at,
+        }
+    }
+
+    /// The next hop after this one
+    pub fn child(&self) -> Self {
+        Self {
+            correlation: self.correlation.clone(),
+            span_id: new_span_id(),
+            parent_span_id: Some(self.span_id),
+            root_span_id: self.root_span_id,
+            started: std::time::Instant::now(),
+        }
+    }
+
+    /// Export this hop as a span called `name` that ends now, if spans are exported at all
+    pub fn export(&self, name: &'static str) {
+        self.export_ended_at(name, std::time::Instant::now());
+    }
+
+    /// Export this hop as a span called `name` that ended at `ended`, if spans are exported at all
+    pub fn export_ended_at(&self, name: &'static str, ended: std::time::Instant) {
+        crate::telemetry::export(self, name, ended);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp_protocol::messages::TunnelId;
+
+    #[test]
+    fn test_correlation_id_matches_across_hosts() {
+        // Each end labels the tunnel from the id in the payload, not its own config
+        let sender = Correlation::new(&tunnel_label(&TunnelId::Id(7)), 42);
+        let receiver = Correlation::new(&tunnel_label(&TunnelId::Id(7)), 42);
+        assert_eq!(sender.to_string(), "#7:42");
+        assert_eq!(sender.to_string(), receiver.to_string());
+        assert_eq!(
+            Correlation::new(&tunnel_label(&TunnelId::Name("video".into())), 3).to_string(),
+            "video:3"
+        );
+    }
+
+    #[test]
+    fn test_spans_chain() {
+        let root = TraceContext::root(Correlation::new(&"t".into(), 1));
+        let child = root.child();
+        assert_eq!(root.parent_span_id, None);
+        assert_eq!(child.parent_span_id, Some(root.span_id));
+        assert_ne!(child.span_id, root.span_id);
+        assert_eq!(child.correlation.tracer, 1);
+        assert_eq!(child.child().root_span_id, root.span_id);
+    }
+}
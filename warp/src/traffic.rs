@@ -0,0 +1,131 @@
+//! Counting the bytes and datagrams each interface sends and receives, in all and by tunnel
+//!
+//! Interfaces count every datagram they send or receive. Those sent are also counted towards the tunnel whose payload
+//! they carry; those received are only counted towards a tunnel once an rx worker has decrypted them and found a tunnel
+//! payload, so the tunnels' counts leave out probes, heartbeats and the like. Counts are kept by interface name from
+//! when warp starts and are never reset, so an interface that goes away and comes back carries on from where it was.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Bytes and datagrams going one way
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficCount {
+    pub bytes: u64,
+    pub packets: u64,
+}
+
+impl TrafficCount {
+    fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        self.packets += 1;
+    }
+}
+
+/// What an interface, or one tunnel on it, has sent and received
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub sent: TrafficCount,
+    pub received: TrafficCount,
+}
+
+/// The traffic of one interface so far
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrafficReport {
+    pub total: TrafficStats,
+    /// By tunnel, as tunnels appear in correlation ids (see [`crate::trace::tunnel_label`])
+    pub tunnels: BTreeMap<Arc<str>, TrafficStats>,
+}
+
+/// The counts of one interface, shared by its sender and receiver tasks and the rx workers
+#[derive(Debug, Default)]
+pub struct InterfaceTraffic {
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: TrafficStats,
+    tunnels: HashMap<Arc<str>, TrafficStats>,
+}
+
+impl InterfaceTraffic {
+    /// Count a datagram of `bytes` that was sent, towards `tunnel` too if it carried one of its payloads
+    pub fn record_sent(&self, tunnel: Option<&Arc<str>>, bytes: usize) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total.sent.add(bytes);
+        if let Some(tunnel) = tunnel {
+            counts.tunnels.entry(tunnel.clone()).or_default().sent.add(bytes);
+        }
+    }
+
+    /// Count a datagram of `bytes` that was received
+    pub fn record_received(&self, bytes: usize) {
+        self.counts.lock().unwrap().total.received.add(bytes);
+    }
+
+    /// Count a payload of `tunnel`, `bytes` long as it was received, towards that tunnel; the datagram it came in has
+    /// already been counted towards the interface
+    pub fn record_tunnel_received(&self, tunnel: &Arc<str>, bytes: usize) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.tunnels.entry(tunnel.clone()).or_default().received.add(bytes);
+    }
+
+    pub fn report(&self) -> TrafficReport {
+        let counts = self.counts.lock().unwrap();
+        TrafficReport {
+            total: counts.total,
+            tunnels: counts
+                .tunnels
+                .iter()
+                .map(|(tunnel, stats)| (tunnel.clone(), *stats))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_interface_and_tunnels() {
+        let traffic = InterfaceTraffic::default();
+        let video: Arc<str> = "video".into();
+        traffic.record_sent(Some(&video), 1200);
+        traffic.record_sent(None, 40);
+        traffic.record_received(1300);
+        traffic.record_tunnel_received(&video, 1250);
+
+        let report = traffic.report();
+        assert_eq!(
+            report.total,
+            TrafficStats {
+                sent: TrafficCount {
+                    bytes: 1240,
+                    packets: 2
+                },
+                received: TrafficCount {
+                    bytes: 1300,
+                    packets: 1
+                },
+            }
+        );
+        assert_eq!(
+            report.tunnels,
+            BTreeMap::from([(
+                video,
+                TrafficStats {
+                    sent: TrafficCount {
+                        bytes: 1200,
+                        packets: 1
+                    },
+                    received: TrafficCount {
+                        bytes: 1250,
+                        packets: 1
+                    },
+                }
+            )])
+        );
+    }
+}
@@ -0,0 +1,952 @@
+//! The seam between warp and the network it runs on
+//!
+//! [`SystemNetwork`] uses the host's real interfaces and UDP sockets; tests can supply their own [`Network`] to run warp
+//! over a simulated network instead.
+//!
+//! Interfaces send and receive datagrams in batches. On Linux, a UDP socket moves a whole batch with one `sendmmsg` or
+//! `recvmmsg` call; other sockets fall back to one datagram per call, which is all the batch methods do by default.
+//! Each datagram in a batch can carry its own DSCP, which only Linux UDP sockets apply; the rest send it with their
+//! socket's DSCP. Linux UDP sockets also have the kernel stamp each datagram with when it arrived (`SO_TIMESTAMPNS`), so
+//! that the time it then waited in the socket's buffer can be told apart from the time it took to get here.
+//!
+//! On Linux, [`SystemNetwork`] also listens to rtnetlink for links and addresses changing, so that warp rescans its
+//! interfaces as soon as they do.
+
+use crate::interface::NetworkInterfaceId;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::io::ReadBuf;
+
+/// An unconnected datagram socket, modelled on the `poll_*` methods of `tokio::net::UdpSocket`
+pub trait DatagramSocket: Send + Sync {
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<SocketAddr>>;
+
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Send datagrams in order, as many of them as can be sent at once, returning how many were
+    ///
+    /// `sent[i]` is set to the number of bytes of `datagrams[i]` that were sent. An error means the first datagram
+    /// couldn't be sent.
+    fn poll_send_batch(
+        &self,
+        cx: &mut Context<'_>,
+        datagrams: &[OutgoingDatagram<'_>],
+        sent: &mut [usize],
+    ) -> Poll<io::Result<usize>> {
+        let Some(datagram) = datagrams.first() else {
+            return Poll::Ready(Ok(0));
+        };
+        self.poll_send_to(cx, datagram.data, datagram.to).map_ok(|sent_bytes| {
+            sent[0] = sent_bytes;
+            1
+        })
+    }
+
+    /// Receive as many datagrams as have arrived, up to the size of `batch`
+    fn poll_recv_batch(&self, cx: &mut Context<'_>, batch: &mut RecvBatch) -> Poll<io::Result<()>> {
+        batch.received.clear();
+        let mut read_buf = ReadBuf::new(&mut batch.buffers[0]);
+        self.poll_recv_from(cx, &mut read_buf).map_ok(|from| {
+            let size = read_buf.filled().len();
+            batch.received.push((size, from, None));
+        })
+    }
+}
+
+/// A datagram for [`DatagramSocket::poll_send_batch`] to send
+#[derive(Debug, Clone, Copy)]
+pub struct OutgoingDatagram<'a> {
+    pub data: &'a [u8],
+    pub to: SocketAddr,
+    /// Marks this datagram with a DSCP other than its socket's
+    pub dscp: Option<warp_config::Dscp>,
+}
+
+/// Buffers for [`DatagramSocket::poll_recv_batch`] to receive datagrams into
+pub struct RecvBatch {
+    buffers: Vec<Vec<u8>>,
+    // The size of each datagram received into `buffers`, in order, where it came from, and when the kernel received it
+    received: Vec<(usize, SocketAddr, Option<SystemTime>)>,
+}
+
+impl RecvBatch {
+    /// Room for `size` datagrams of up to `buffer_size` bytes each
+    pub fn new(size: usize, buffer_size: usize) -> Self {
+        Self {
+            buffers: vec![vec![0u8; buffer_size]; size.max(1)],
+            received: Vec::with_capacity(size.max(1)),
+        }
+    }
+
+    /// The datagrams received by the latest call, where each came from, and when the kernel received it if the socket
+    /// keeps track of that
+    pub fn datagrams(&self) -> impl Iterator<Item = (&[u8], SocketAddr, Option<SystemTime>)> {
+        self.buffers
+            .iter()
+            .zip(&self.received)
+            .map(|(buffer, (size, from, timestamp))| (&buffer[..*size], *from, *timestamp))
+    }
+}
+
+impl dyn DatagramSocket {
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        std::future::poll_fn(|cx| {
+            let mut read_buf = ReadBuf::new(buf);
+            self.poll_recv_from(cx, &mut read_buf)
+                .map_ok(|from| (read_buf.filled().len(), from))
+        })
+        .await
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        std::future::poll_fn(|cx| self.poll_send_to(cx, buf, target)).await
+    }
+
+    pub async fn send_batch(&self, datagrams: &[OutgoingDatagram<'_>], sent: &mut [usize]) -> io::Result<usize> {
+        std::future::poll_fn(|cx| self.poll_send_batch(cx, datagrams, sent)).await
+    }
+
+    pub async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_recv_batch(cx, batch)).await
+    }
+}
+
+impl DatagramSocket for tokio::net::UdpSocket {
+    #[inline]
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<SocketAddr>> {
+        tokio::net::UdpSocket::poll_recv_from(self, cx, buf)
+    }
+
+    #[inline]
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        tokio::net::UdpSocket::poll_send_to(self, cx, buf, target)
+    }
+
+    #[inline]
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        tokio::net::UdpSocket::local_addr(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_send_batch(
+        &self,
+        cx: &mut Context<'_>,
+        datagrams: &[OutgoingDatagram<'_>],
+        sent: &mut [usize],
+    ) -> Poll<io::Result<usize>> {
+        use std::os::fd::AsRawFd;
+        loop {
+            std::task::ready!(self.poll_send_ready(cx))?;
+            match self.try_io(tokio::io::Interest::WRITABLE, || {
+                mmsg::send(self.as_raw_fd(), datagrams, sent)
+            }) {
+                // The socket wasn't writable after all; poll_send_ready now waits until it is
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_recv_batch(&self, cx: &mut Context<'_>, batch: &mut RecvBatch) -> Poll<io::Result<()>> {
+        use std::os::fd::AsRawFd;
+        loop {
+            std::task::ready!(self.poll_recv_ready(cx))?;
+            match self.try_io(tokio::io::Interest::READABLE, || mmsg::recv(self.as_raw_fd(), batch)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use super::{OutgoingDatagram, RecvBatch};
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+    use std::os::fd::RawFd;
+    use std::time::{Duration, SystemTime};
+
+    // Room for the one control message that marks a datagram with its own DSCP
+    const CONTROL_SIZE: usize =
+        unsafe { libc::CMSG_SPACE(std::mem::size_of::<libc::c_int>() as libc::c_uint) } as usize;
+    // Room for the one control message that says when a datagram was received
+    const RECV_CONTROL_SIZE: usize =
+        unsafe { libc::CMSG_SPACE(std::mem::size_of::<libc::timespec>() as libc::c_uint) } as usize;
+
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    union Control<const SIZE: usize> {
+        // Only here to align the buffer for a control message header
+        _header: libc::cmsghdr,
+        bytes: [u8; SIZE],
+    }
+
+    pub fn send(fd: RawFd, datagrams: &[OutgoingDatagram<'_>], sent: &mut [usize]) -> io::Result<usize> {
+        let count = datagrams.len().min(sent.len());
+        let datagrams = &datagrams[..count];
+        let mut addresses: Vec<_> = datagrams.iter().map(|datagram| to_sockaddr(&datagram.to)).collect();
+        let mut iovecs: Vec<_> = datagrams
+            .iter()
+            .map(|datagram| libc::iovec {
+                iov_base: datagram.data.as_ptr() as *mut libc::c_void,
+                iov_len: datagram.data.len(),
+            })
+            .collect();
+        let mut controls = vec![
+            Control::<CONTROL_SIZE> {
+                bytes: [0; CONTROL_SIZE]
+            };
+            count
+        ];
+        let mut messages: Vec<_> = addresses
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .map(|((address, address_len), iovec)| message(address, *address_len, iovec))
+            .collect();
+        for ((message, control), datagram) in messages.iter_mut().zip(controls.iter_mut()).zip(datagrams) {
+            if let Some(dscp) = datagram.dscp {
+                // SAFETY: the control buffer is aligned for, and has room for, one control message holding an int
+                unsafe { mark(&mut message.msg_hdr, control, &datagram.to, dscp) };
+            }
+        }
+
+        // SAFETY: every message points at an address, a buffer and control data that outlive the call
+        let result = unsafe { libc::sendmmsg(fd, messages.as_mut_ptr(), count as libc::c_uint, 0) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let result = result as usize;
+        for (sent, message) in sent.iter_mut().zip(&messages[..result]) {
+            *sent = message.msg_len as usize;
+        }
+        Ok(result)
+    }
+
+    // Attach a control message to `header` that sets the TOS (or traffic class, for IPv6) of its datagram
+    unsafe fn mark(
+        header: &mut libc::msghdr,
+        control: &mut Control<CONTROL_SIZE>,
+        to: &SocketAddr,
+        dscp: warp_config::Dscp,
+    ) {
+        let (level, kind) = match to {
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_TOS),
+            SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+        };
+        header.msg_control = control as *mut Control<CONTROL_SIZE> as *mut libc::c_void;
+        header.msg_controllen = CONTROL_SIZE as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(header);
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = kind;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<libc::c_int>() as libc::c_uint) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, dscp.tos() as libc::c_int);
+        }
+    }
+
+    pub fn recv(fd: RawFd, batch: &mut RecvBatch) -> io::Result<()> {
+        batch.received.clear();
+        // SAFETY: all zeroes is a valid (if unspecified) socket address
+        let mut addresses = vec![unsafe { std::mem::zeroed::<libc::sockaddr_storage>() }; batch.buffers.len()];
+        let mut iovecs: Vec<_> = batch
+            .buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            })
+            .collect();
+        let mut controls = vec![
+            Control::<RECV_CONTROL_SIZE> {
+                bytes: [0; RECV_CONTROL_SIZE]
+            };
+            batch.buffers.len()
+        ];
+        let address_len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let mut messages: Vec<_> = addresses
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .zip(controls.iter_mut())
+            .map(|((address, iovec), control)| {
+                let mut message = message(address, address_len, iovec);
+                message.msg_hdr.msg_control = control as *mut Control<RECV_CONTROL_SIZE> as *mut libc::c_void;
+                message.msg_hdr.msg_controllen = RECV_CONTROL_SIZE as _;
+                message
+            })
+            .collect();
+
+        // SAFETY: every message points at an address, a buffer and a control buffer that outlive the call
+        let result = unsafe {
+            libc::recvmmsg(
+                fd,
+                messages.as_mut_ptr(),
+                messages.len() as libc::c_uint,
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (message, address) in messages[..result as usize].iter().zip(&addresses) {
+            // SAFETY: the kernel wrote the message's control data, if any, into the buffer it points at
+            let timestamp = unsafe { timestamp(&message.msg_hdr) };
+            batch
+                .received
+                .push((message.msg_len as usize, from_sockaddr(address)?, timestamp));
+        }
+        Ok(())
+    }
+
+    // When the kernel received a datagram, if its socket has `SO_TIMESTAMPNS` set and the timestamp fit in the control
+    // buffer
+    unsafe fn timestamp(header: &libc::msghdr) -> Option<SystemTime> {
+        if header.msg_flags & libc::MSG_CTRUNC != 0 {
+            return None;
+        }
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(header);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPNS {
+                    let time = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec);
+                    return Some(SystemTime::UNIX_EPOCH + Duration::new(time.tv_sec as u64, time.tv_nsec as u32));
+                }
+                cmsg = libc::CMSG_NXTHDR(header, cmsg);
+            }
+        }
+        None
+    }
+
+    fn message(
+        address: &mut libc::sockaddr_storage,
+        address_len: libc::socklen_t,
+        iovec: &mut libc::iovec,
+    ) -> libc::mmsghdr {
+        // SAFETY: all zeroes is a valid message header with no control data
+        let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+        header.msg_name = address as *mut libc::sockaddr_storage as *mut libc::c_void;
+        header.msg_namelen = address_len;
+        header.msg_iov = iovec;
+        header.msg_iovlen = 1;
+        libc::mmsghdr {
+            msg_hdr: header,
+            msg_len: 0,
+        }
+    }
+
+    fn to_sockaddr(address: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        // SAFETY: all zeroes is a valid socket address, and both kinds fit in sockaddr_storage
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let len = match address {
+            SocketAddr::V4(address) => {
+                let sockaddr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+                sockaddr.sin_port = address.port().to_be();
+                sockaddr.sin_addr = libc::in_addr {
+                    s_addr: u32::from_ne_bytes(address.ip().octets()),
+                };
+                std::mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(address) => {
+                let sockaddr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sockaddr.sin6_port = address.port().to_be();
+                sockaddr.sin6_flowinfo = address.flowinfo();
+                sockaddr.sin6_addr = libc::in6_addr {
+                    s6_addr: address.ip().octets(),
+                };
+                sockaddr.sin6_scope_id = address.scope_id();
+                std::mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                // SAFETY: the kernel wrote a sockaddr_in, which fits in sockaddr_storage
+                let sockaddr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                Ok(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::from(sockaddr.sin_addr.s_addr.to_ne_bytes())),
+                    u16::from_be(sockaddr.sin_port),
+                ))
+            }
+            libc::AF_INET6 => {
+                // SAFETY: the kernel wrote a sockaddr_in6, which fits in sockaddr_storage
+                let sockaddr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(sockaddr.sin6_addr.s6_addr),
+                    u16::from_be(sockaddr.sin6_port),
+                    sockaddr.sin6_flowinfo,
+                    sockaddr.sin6_scope_id,
+                )))
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("datagram from unsupported address family {}", family),
+            )),
+        }
+    }
+}
+
+/// Source of network interfaces and the sockets bound to them
+pub trait Network: Send + Sync {
+    /// Every interface currently available, each with a single address; warp applies its own inclusion/exclusion
+    /// patterns on top of this
+    fn interfaces(&self) -> Vec<NetworkInterfaceId>;
+
+    /// Bind a socket on `interface`, to `options.port` if it is free and to an ephemeral port otherwise
+    fn bind(&self, interface: &NetworkInterfaceId, options: &SocketOptions) -> anyhow::Result<Arc<dyn DatagramSocket>>;
+
+    /// Watch for interfaces coming, going or changing address, for warp to rescan them straight away instead of at its
+    /// next scan; the watch ends when the receiver is dropped. Networks that can't tell fail, and are only scanned
+    fn watch_interfaces(&self) -> anyhow::Result<tokio::sync::watch::Receiver<()>> {
+        anyhow::bail!("watching for interface changes isn't supported")
+    }
+}
+
+/// How [`Network::bind`] sets up a socket; see `warp_config::InterfacesConfig`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SocketOptions {
+    /// The port this interface was bound to before, kept so that far gates and NATs still recognise its address
+    pub port: Option<u16>,
+    pub bind_to_device: bool,
+    /// On FreeBSD, the routing table that stands in for binding to the interface
+    pub fib: Option<u32>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    pub dscp: Option<warp_config::Dscp>,
+}
+
+/// The host's real network interfaces and UDP sockets
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemNetwork;
+
+impl Network for SystemNetwork {
+    fn interfaces(&self) -> Vec<NetworkInterfaceId> {
+        match system_interfaces() {
+            Ok(interfaces) => interfaces,
+            Err(e) => {
+                tracing::warn!("Failed to list network interfaces: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn bind(&self, interface: &NetworkInterfaceId, options: &SocketOptions) -> anyhow::Result<Arc<dyn DatagramSocket>> {
+        let std_socket = match options.port {
+            Some(port) => std::net::UdpSocket::bind(SocketAddr::new(interface.ip, port))
+                .or_else(|_| std::net::UdpSocket::bind(SocketAddr::new(interface.ip, 0)))?,
+            None => std::net::UdpSocket::bind(SocketAddr::new(interface.ip, 0))?,
+        };
+
+        // TODO: This is an ugly hack to work around routing shenanigans and may need root
+        if options.bind_to_device {
+            bind_to_device(&std_socket, interface, options.fib)?;
+        }
+        if let Some(size) = options.recv_buffer_size {
+            set_socket_option(&std_socket, SOL_SOCKET, SO_RCVBUF, size.try_into()?)?;
+        }
+        if let Some(size) = options.send_buffer_size {
+            set_socket_option(&std_socket, SOL_SOCKET, SO_SNDBUF, size.try_into()?)?;
+        }
+        if let Some(dscp) = options.dscp {
+            let (level, name) = match interface.ip {
+                std::net::IpAddr::V4(_) => (IPPROTO_IP, IP_TOS),
+                std::net::IpAddr::V6(_) => (IPPROTO_IPV6, IPV6_TCLASS),
+            };
+            set_socket_option(&std_socket, level, name, dscp.tos().into())?;
+        }
+        // Only recvmmsg reads the timestamps, and without them datagrams are still received
+        #[cfg(target_os = "linux")]
+        if let Err(e) = set_socket_option(&std_socket, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, 1) {
+            tracing::debug!("Failed to enable receive timestamps for {}: {}", interface, e);
+        }
+
+        std_socket.set_nonblocking(true)?;
+        Ok(Arc::new(tokio::net::UdpSocket::from_std(std_socket)?))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn watch_interfaces(&self) -> anyhow::Result<tokio::sync::watch::Receiver<()>> {
+        let socket = tokio::io::unix::AsyncFd::new(netlink::subscribe()?)?;
+        let (changes_tx, changes) = tokio::sync::watch::channel(());
+        tokio::task::Builder::new().name("netlink watcher").spawn(async move {
+            loop {
+                tokio::select! {
+                    result = netlink::wait_for_change(&socket) => {
+                        if let Err(e) = result {
+                            tracing::warn!("Stopped watching for interface changes: {}", e);
+                            break;
+                        }
+                        changes_tx.send_replace(());
+                    }
+                    _ = changes_tx.closed() => break,
+                }
+            }
+        })?;
+        Ok(changes)
+    }
+}
+
+// Listening for rtnetlink's link and address change notifications
+#[cfg(target_os = "linux")]
+mod netlink {
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    /// A netlink socket that is told of links and of IPv4 and IPv6 addresses changing
+    pub fn subscribe() -> io::Result<OwnedFd> {
+        // SAFETY: plain system calls; the descriptor is owned as soon as it is created
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+        let ret = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                (&address as *const libc::sockaddr_nl).cast(),
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    /// Wait for the next notification, and any that came with it. The notifications aren't parsed: the interfaces are
+    /// scanned again whatever changed
+    pub async fn wait_for_change(socket: &tokio::io::unix::AsyncFd<OwnedFd>) -> io::Result<()> {
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let mut guard = socket.readable().await?;
+            let received = guard.try_io(|socket| {
+                // SAFETY: `buf` is valid for its whole length
+                let len = unsafe { libc::recv(socket.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+                if len < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            });
+            match received {
+                Ok(Ok(())) => return Ok(()),
+                // Notifications were dropped for want of buffer space, so something did change
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+// Each interface with its first IPv4 address
+// TODO: Only listing IPv4 interfaces; IPv6 should also just work but we haven't tested them
+#[cfg(unix)]
+fn system_interfaces() -> io::Result<Vec<NetworkInterfaceId>> {
+    let mut addresses = std::ptr::null_mut();
+    // SAFETY: on success the list is ours until it is freed below
+    if unsafe { libc::getifaddrs(&mut addresses) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut interfaces: Vec<NetworkInterfaceId> = Vec::new();
+    let mut next = addresses;
+    while !next.is_null() {
+        // SAFETY: every entry in the list, its name and its address stay valid until the list is freed
+        let entry = unsafe { &*next };
+        next = entry.ifa_next;
+        if entry.ifa_addr.is_null() || unsafe { (*entry.ifa_addr).sa_family } as libc::c_int != libc::AF_INET {
+            continue;
+        }
+        let address = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+        // Point-to-point links may not have a netmask; their network is just the address
+        let prefix_len = if entry.ifa_netmask.is_null() {
+            32
+        } else {
+            let netmask = unsafe { &*(entry.ifa_netmask as *const libc::sockaddr_in) };
+            u32::from_be(netmask.sin_addr.s_addr).count_ones() as u8
+        };
+        let name = unsafe { std::ffi::CStr::from_ptr(entry.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        if !interfaces.iter().any(|interface| interface.name == name) {
+            interfaces.push(NetworkInterfaceId {
+                name,
+                ip: std::net::Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)).into(),
+                prefix_len,
+            });
+        }
+    }
+    // SAFETY: the list came from getifaddrs and nothing borrowed from it is left
+    unsafe { libc::freeifaddrs(addresses) };
+    Ok(interfaces)
+}
+
+#[cfg(windows)]
+fn system_interfaces() -> io::Result<Vec<NetworkInterfaceId>> {
+    Ok(windows::adapters()?
+        .into_iter()
+        .filter_map(|adapter| {
+            adapter.ipv4.map(|(ip, prefix_len)| NetworkInterfaceId {
+                name: adapter.name,
+                ip: ip.into(),
+                prefix_len,
+            })
+        })
+        .collect())
+}
+
+// Make the socket send out of `interface` whatever the routing table says
+#[cfg(target_os = "linux")]
+fn bind_to_device(
+    socket: &std::net::UdpSocket,
+    interface: &NetworkInterfaceId,
+    _fib: Option<u32>,
+) -> anyhow::Result<()> {
+    tracing::info!("Using SO_BINDTODEVICE for {}", interface);
+    let name = std::ffi::CString::new(interface.name.clone())?;
+    set_socket_option_bytes(
+        socket,
+        libc::SOL_SOCKET,
+        libc::SO_BINDTODEVICE,
+        name.as_bytes_with_nul(),
+    )?;
+    Ok(())
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn bind_to_device(
+    socket: &std::net::UdpSocket,
+    interface: &NetworkInterfaceId,
+    _fib: Option<u32>,
+) -> anyhow::Result<()> {
+    tracing::info!("Using IP_BOUND_IF for {}", interface);
+    let name = std::ffi::CString::new(interface.name.clone())?;
+    // SAFETY: the name is a valid C string
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let (level, option) = match interface.ip {
+        std::net::IpAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_BOUND_IF),
+        std::net::IpAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_BOUND_IF),
+    };
+    set_socket_option(socket, level, option, index as libc::c_int)?;
+    Ok(())
+}
+
+// FreeBSD can't bind a socket to an interface, but it can have the socket use a routing table (FIB) that only routes
+// out of that interface
+#[cfg(target_os = "freebsd")]
+fn bind_to_device(
+    socket: &std::net::UdpSocket,
+    interface: &NetworkInterfaceId,
+    fib: Option<u32>,
+) -> anyhow::Result<()> {
+    let Some(fib) = fib else {
+        anyhow::bail!(
+            "bind_to_device on FreeBSD needs a FIB for {} in interfaces.fibs",
+            interface.name
+        );
+    };
+    tracing::info!("Using SO_SETFIB {} for {}", fib, interface);
+    set_socket_option(socket, libc::SOL_SOCKET, libc::SO_SETFIB, fib.try_into()?)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn bind_to_device(
+    socket: &std::net::UdpSocket,
+    interface: &NetworkInterfaceId,
+    _fib: Option<u32>,
+) -> anyhow::Result<()> {
+    tracing::info!("Using IP_UNICAST_IF for {}", interface);
+    let Some(index) = windows::adapters()?
+        .into_iter()
+        .find(|adapter| adapter.name == interface.name)
+        .map(|adapter| adapter.index)
+    else {
+        anyhow::bail!("interface {} no longer exists", interface.name);
+    };
+    // IPv4 takes the index in network byte order, IPv6 in host byte order
+    let (level, option, index) = match interface.ip {
+        std::net::IpAddr::V4(_) => (IPPROTO_IP, windows::IP_UNICAST_IF, index.to_be()),
+        std::net::IpAddr::V6(_) => (IPPROTO_IPV6, windows::IPV6_UNICAST_IF, index),
+    };
+    set_socket_option(socket, level, option, index as i32)?;
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    windows
+)))]
+fn bind_to_device(
+    _socket: &std::net::UdpSocket,
+    _interface: &NetworkInterfaceId,
+    _fib: Option<u32>,
+) -> anyhow::Result<()> {
+    anyhow::bail!("bind_to_device is not supported on {}", std::env::consts::OS);
+}
+
+#[cfg(unix)]
+use libc::{IP_TOS, IPPROTO_IP, IPPROTO_IPV6, IPV6_TCLASS, SO_RCVBUF, SO_SNDBUF, SOL_SOCKET};
+#[cfg(windows)]
+use windows::{IP_TOS, IPPROTO_IP, IPPROTO_IPV6, IPV6_TCLASS, SO_RCVBUF, SO_SNDBUF, SOL_SOCKET};
+
+#[cfg(unix)]
+fn set_socket_option(
+    socket: &std::net::UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    set_socket_option_bytes(socket, level, name, &value.to_ne_bytes())
+}
+
+#[cfg(unix)]
+fn set_socket_option_bytes(
+    socket: &std::net::UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &[u8],
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: the option value outlives the call
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_socket_option(socket: &std::net::UdpSocket, level: i32, name: i32, value: i32) -> io::Result<()> {
+    windows::set_socket_option(socket, level, name, value)
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::io;
+    use windows_sys::Win32::Foundation::{ERROR_BUFFER_OVERFLOW, ERROR_NO_DATA, NO_ERROR};
+    use windows_sys::Win32::NetworkManagement::IpHelper::{
+        GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER, GAA_FLAG_SKIP_MULTICAST, GetAdaptersAddresses,
+        IP_ADAPTER_ADDRESSES_LH,
+    };
+    use windows_sys::Win32::Networking::WinSock::{AF_INET, SOCKADDR_IN};
+    pub use windows_sys::Win32::Networking::WinSock::{
+        IP_TOS, IP_UNICAST_IF, IPPROTO_IP, IPPROTO_IPV6, IPV6_TCLASS, IPV6_UNICAST_IF, SO_RCVBUF, SO_SNDBUF, SOL_SOCKET,
+    };
+
+    // Only a starting point; GetAdaptersAddresses says how much it needs if this isn't enough
+    const ADAPTERS_BUFFER_SIZE: u32 = 16 * 1024;
+
+    pub struct Adapter {
+        // The name shown to users, e.g. "Wi-Fi", which is what the interface patterns match
+        pub name: String,
+        pub index: u32,
+        // The first IPv4 address and the length of its on-link prefix
+        pub ipv4: Option<(std::net::Ipv4Addr, u8)>,
+    }
+
+    pub fn adapters() -> io::Result<Vec<Adapter>> {
+        let mut size = ADAPTERS_BUFFER_SIZE;
+        // u64s to align the buffer for the adapter entries
+        let mut buffer: Vec<u64>;
+        loop {
+            buffer = vec![0; (size as usize).div_ceil(8)];
+            // SAFETY: the buffer has room for `size` bytes
+            let ret = unsafe {
+                GetAdaptersAddresses(
+                    AF_INET as u32,
+                    GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST | GAA_FLAG_SKIP_DNS_SERVER,
+                    std::ptr::null(),
+                    buffer.as_mut_ptr() as *mut IP_ADAPTER_ADDRESSES_LH,
+                    &mut size,
+                )
+            };
+            match ret {
+                NO_ERROR => break,
+                ERROR_BUFFER_OVERFLOW => continue,
+                ERROR_NO_DATA => return Ok(Vec::new()),
+                error => return Err(io::Error::from_raw_os_error(error as i32)),
+            }
+        }
+
+        let mut adapters = Vec::new();
+        let mut next = buffer.as_ptr() as *const IP_ADAPTER_ADDRESSES_LH;
+        while !next.is_null() {
+            // SAFETY: the entries and everything they point to live in `buffer`
+            let adapter = unsafe { &*next };
+            next = adapter.Next;
+            let name = unsafe { wide_string(adapter.FriendlyName) };
+            let mut ipv4 = None;
+            let mut unicast = adapter.FirstUnicastAddress;
+            while !unicast.is_null() && ipv4.is_none() {
+                let address = unsafe { &*unicast };
+                unicast = address.Next;
+                let sockaddr = address.Address.lpSockaddr;
+                if !sockaddr.is_null() && unsafe { (*sockaddr).sa_family } == AF_INET {
+                    let sockaddr = unsafe { &*(sockaddr as *const SOCKADDR_IN) };
+                    ipv4 = Some((
+                        std::net::Ipv4Addr::from(u32::from_be(unsafe { sockaddr.sin_addr.S_un.S_addr })),
+                        address.OnLinkPrefixLength,
+                    ));
+                }
+            }
+            adapters.push(Adapter {
+                name,
+                index: unsafe { adapter.Anonymous1.Anonymous.IfIndex },
+                ipv4,
+            });
+        }
+        Ok(adapters)
+    }
+
+    // SAFETY: `string` must be null or point to a null-terminated UTF-16 string
+    unsafe fn wide_string(string: *const u16) -> String {
+        if string.is_null() {
+            return String::new();
+        }
+        let mut len = 0;
+        while unsafe { *string.add(len) } != 0 {
+            len += 1;
+        }
+        String::from_utf16_lossy(unsafe { std::slice::from_raw_parts(string, len) })
+    }
+
+    pub fn set_socket_option(socket: &std::net::UdpSocket, level: i32, name: i32, value: i32) -> io::Result<()> {
+        use std::os::windows::io::AsRawSocket;
+        // SAFETY: the option value is an int that outlives the call
+        let ret = unsafe {
+            windows_sys::Win32::Networking::WinSock::setsockopt(
+                socket.as_raw_socket() as _,
+                level,
+                name,
+                &value as *const i32 as *const u8,
+                std::mem::size_of::<i32>() as i32,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lists_loopback() {
+        let interfaces = SystemNetwork.interfaces();
+        assert!(
+            interfaces
+                .iter()
+                .any(|interface| interface.ip == std::net::IpAddr::from(std::net::Ipv4Addr::LOCALHOST))
+        );
+        // One address per interface
+        let mut names: Vec<_> = interfaces.iter().map(|interface| &interface.name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), interfaces.len());
+    }
+
+    #[tokio::test]
+    async fn test_batches_round_trip() {
+        let loopback = NetworkInterfaceId {
+            name: "lo".to_string(),
+            ip: std::net::Ipv4Addr::LOCALHOST.into(),
+            prefix_len: 8,
+        };
+        let options = SocketOptions {
+            port: None,
+            bind_to_device: false,
+            fib: None,
+            recv_buffer_size: Some(1 << 20),
+            send_buffer_size: Some(1 << 20),
+            dscp: Some(warp_config::Dscp::try_from(46).unwrap()),
+        };
+        let sender = SystemNetwork.bind(&loopback, &options).unwrap();
+        let receiver = SystemNetwork.bind(&loopback, &SocketOptions::default()).unwrap();
+        let to = receiver.local_addr().unwrap();
+
+        let datagram = |data: &'static [u8], dscp: Option<u8>| OutgoingDatagram {
+            data,
+            to,
+            dscp: dscp.map(|dscp| warp_config::Dscp::try_from(dscp).unwrap()),
+        };
+        // A datagram can be marked differently from its socket
+        let datagrams = [
+            datagram(b"one", None),
+            datagram(b"two", Some(34)),
+            datagram(b"three", None),
+        ];
+        let mut sent = [0; 3];
+        let mut count = 0;
+        while count < datagrams.len() {
+            count += sender
+                .send_batch(&datagrams[count..], &mut sent[count..])
+                .await
+                .unwrap();
+        }
+        assert_eq!(sent, [3, 3, 5]);
+
+        let mut batch = RecvBatch::new(8, 64);
+        let mut received = Vec::new();
+        while received.len() < datagrams.len() {
+            receiver.recv_batch(&mut batch).await.unwrap();
+            received.extend(batch.datagrams().map(|(data, from, _)| (data.to_vec(), from)));
+            // The kernel stamped each datagram as it arrived, which was before it was read
+            if cfg!(target_os = "linux") {
+                let read_at = SystemTime::now();
+                assert!(
+                    batch
+                        .datagrams()
+                        .all(|(_, _, timestamp)| timestamp.is_some_and(|at| at <= read_at))
+                );
+            }
+        }
+        let from = sender.local_addr().unwrap();
+        assert_eq!(
+            received,
+            vec![
+                (b"one".to_vec(), from),
+                (b"two".to_vec(), from),
+                (b"three".to_vec(), from)
+            ]
+        );
+    }
+}
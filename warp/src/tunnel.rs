@@ -1,21 +1,102 @@
+use crate::reliable::DeliveredPayloads;
+use crate::reorder::{Pushed, ReorderBuffer};
+use crate::trace::{Correlation, TraceContext};
+use futures::FutureExt;
 use std::sync::Arc;
 use tokio::sync::{OnceCell, mpsc, watch};
 use tokio::task::JoinHandle;
-use warp_config::WarpGateConfig;
+use warp_config::{WarpGateConfig, WarpTransportConfig};
 
 const BUFFER_SIZE: usize = 65536;
 
-enum ApplicationSocket {
+pub(crate) enum ApplicationSocket {
     Loopback {
         socket: tokio::net::UdpSocket,
         fixed_destination: Option<std::net::SocketAddr>,
         current_destination: watch::Sender<Option<std::net::SocketAddr>>,
     },
+    #[cfg(unix)]
     UnixDomainSocket(tokio::net::UnixDatagram),
+    // Shared with the gates that had the same channels before, if the tunnel was reopened
+    Channel {
+        from_application: Arc<tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>>,
+        to_application: mpsc::UnboundedSender<Vec<u8>>,
+    },
+    Tcp(Arc<crate::tcp_gate::TcpGate>),
+}
+
+/// The application's ends of an in-process gate; see [`crate::WarpCore::add_channel_tunnel`] and
+/// [`crate::WarpCore::channel_gate`]
+///
+/// Each message sent on `to_gate` becomes one tunnel payload, and each tunnel payload received from the far gate
+/// arrives on `from_gate`. Dropping `to_gate` stops the gate listening for application data. `to_gate` holds up to
+/// `queues.capacity` payloads, so an application sending faster than warp can is held back rather than queueing without
+/// bound.
+#[derive(Debug)]
+pub struct ChannelGate {
+    pub to_gate: mpsc::Sender<Vec<u8>>,
+    pub from_gate: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// The send deadline of the tunnels using the gate, if known: a payload that waits longer than this for room in
+    /// `to_gate` would be too late to send anyway
+    pub send_deadline: Option<std::time::Duration>,
+}
+
+impl ChannelGate {
+    pub(crate) fn new(capacity: usize, send_deadline: Option<std::time::Duration>) -> (Self, ApplicationSocket) {
+        let (to_gate, from_application) = mpsc::channel(capacity.max(1));
+        let (to_application, from_gate) = mpsc::unbounded_channel();
+        let channel_gate = Self {
+            to_gate,
+            from_gate,
+            send_deadline,
+        };
+        let socket = ApplicationSocket::Channel {
+            from_application: Arc::new(tokio::sync::Mutex::new(from_application)),
+            to_application,
+        };
+        (channel_gate, socket)
+    }
+}
+
+/// The gates' ends of the in-process gates that tunnels configured with a `channel` gate use, by channel name
+#[derive(Clone, Default)]
+pub(crate) struct ChannelSockets(Arc<std::sync::Mutex<std::collections::HashMap<String, ApplicationSocket>>>);
+
+impl ChannelSockets {
+    /// Make the in-process gate for tunnels configured with `channel`, returning the application's ends
+    pub fn register(
+        &self,
+        channel: &str,
+        capacity: usize,
+        send_deadline: Option<std::time::Duration>,
+    ) -> anyhow::Result<ChannelGate> {
+        let mut sockets = self.0.lock().unwrap();
+        if sockets.contains_key(channel) {
+            anyhow::bail!("channel {channel} is already registered");
+        }
+        let (channel_gate, socket) = ChannelGate::new(capacity, send_deadline);
+        sockets.insert(channel.to_string(), socket);
+        Ok(channel_gate)
+    }
+
+    // A socket on the channels registered as `channel`, which every gate opened with them shares
+    fn socket(&self, channel: &str) -> Option<ApplicationSocket> {
+        match self.0.lock().unwrap().get(channel)? {
+            ApplicationSocket::Channel {
+                from_application,
+                to_application,
+            } => Some(ApplicationSocket::Channel {
+                from_application: from_application.clone(),
+                to_application: to_application.clone(),
+            }),
+            _ => None,
+        }
+    }
 }
 
 impl ApplicationSocket {
-    async fn recv_from_application<'a>(&self, buf: &'a mut [u8]) -> anyhow::Result<&'a [u8]> {
+    // Returns None once the application can no longer send to the gate
+    async fn recv_from_application<'a>(&self, buf: &'a mut [u8]) -> anyhow::Result<Option<&'a [u8]>> {
         let size = match self {
             Self::Loopback {
                 socket,
@@ -31,9 +112,31 @@ impl ApplicationSocket {
 
                 size
             }
+            #[cfg(unix)]
             Self::UnixDomainSocket(socket) => socket.recv(buf).await?,
+            Self::Channel { from_application, .. } => {
+                let Some(data) = from_application.lock().await.recv().await else {
+                    return Ok(None);
+                };
+                if data.len() > buf.len() {
+                    anyhow::bail!(
+                        "{} byte application payload exceeds {} byte buffer",
+                        data.len(),
+                        buf.len()
+                    );
+                }
+                buf[..data.len()].copy_from_slice(&data);
+                data.len()
+            }
+            Self::Tcp(tcp_gate) => {
+                let Some(frame) = tcp_gate.recv_frame().await else {
+                    return Ok(None);
+                };
+                buf[..frame.len()].copy_from_slice(&frame);
+                frame.len()
+            }
         };
-        Ok(&buf[..size])
+        Ok(Some(&buf[..size]))
     }
 
     async fn send_to_application(
@@ -51,35 +154,161 @@ impl ApplicationSocket {
                 (None, Some(fallback_addr)) => Ok(socket.send_to(data, fallback_addr).await?),
                 (None, None) => Err(anyhow::anyhow!("no destination address provided"))?,
             },
+            #[cfg(unix)]
             Self::UnixDomainSocket(socket) => Ok(socket.send(data).await?),
+            Self::Channel { to_application, .. } => {
+                to_application
+                    .send(data.to_vec())
+                    .map_err(|_| anyhow::anyhow!("application stopped receiving"))?;
+                Ok(data.len())
+            }
+            Self::Tcp(tcp_gate) => {
+                let frame = crate::tcp_gate::Frame::decode(data)?;
+                let stream_id = frame.stream_id;
+                match tcp_gate.deliver(frame) {
+                    crate::tcp_gate::Delivery::Delivered | crate::tcp_gate::Delivery::Opened => Ok(data.len()),
+                    crate::tcp_gate::Delivery::Reset => Err(anyhow::anyhow!(
+                        "frames of TCP stream {stream_id} went missing; reset it"
+                    )),
+                    crate::tcp_gate::Delivery::Dropped => Err(anyhow::anyhow!(
+                        "dropped frame of closed or duplicate TCP stream {stream_id}"
+                    )),
+                }
+            }
         }
     }
 }
 
 pub struct OutboundTunnelPayload {
-    pub tunnel_payload: warp_protocol::messages::TunnelPayload,
+    /// One application payload as it goes over the wire: a single tunnel payload, or its FEC shards; shared with the
+    /// retransmission queue if the tunnel is reliable
+    pub tunnel_payloads: std::sync::Arc<[warp_protocol::messages::TunnelPayload]>,
+    /// The peer the tunnel payloads are for
+    pub far_gate: warp_protocol::PublicKey,
+    pub routes: std::sync::Arc<crate::routing::RoutePolicy>,
     pub deadline: std::time::Instant,
+    /// Whether to send the payload again until the far gate acknowledges it
+    pub reliable: bool,
+    /// How to compress the tunnel payloads, if the far gate's session agreed to it
+    pub compression: Option<warp_config::Compression>,
+    pub traffic_class: crate::interface::TrafficClass,
     pub completion_notifier: tokio::sync::oneshot::Sender<()>,
+    pub trace: TraceContext,
+}
+
+/// A payload that a reliable tunnel's gate received, for the far gate that sent it to stop retransmitting
+pub struct Acknowledgement {
+    pub far_gate: warp_protocol::PublicKey,
+    pub tunnel_id: warp_protocol::messages::TunnelId,
+    pub tracer: u64,
+}
+
+/// Where gates send what they have for the far gate, and where they find out how the paths to it are doing
+#[derive(Clone)]
+pub struct GateChannels {
+    pub outbound: mpsc::Sender<OutboundTunnelPayload>,
+    pub acknowledgements: mpsc::UnboundedSender<Acknowledgement>,
+    pub routing_state: Arc<crate::routing::RoutingState>,
+    /// Runs the gate's tasks again if they panic
+    pub supervisor: crate::supervisor::Supervisor,
+    /// Where gates configured with a `channel` find their channels
+    pub channel_sockets: ChannelSockets,
 }
 
 pub struct Gate {
-    application_inbound_channel: mpsc::UnboundedSender<warp_protocol::messages::TunnelPayload>,
+    application_inbound_channel: mpsc::UnboundedSender<(warp_protocol::messages::TunnelPayload, TraceContext)>,
     application_listener_task: OnceCell<JoinHandle<()>>,
     application_sender_task: OnceCell<JoinHandle<()>>,
+    // Cancelled to stop taking data from the application
+    closing: tokio_util::sync::CancellationToken,
+    // Cancelled once the listener has passed on the last payload it took from the application
+    listener_stopped: tokio_util::sync::CancellationToken,
 }
 
 impl Gate {
     pub fn new(
         tunnel_name: &str,
         tunnel_id: warp_protocol::messages::TunnelId,
+        far_gate: warp_protocol::PublicKey,
         config: WarpGateConfig,
-        send_deadline: std::time::Duration,
-        application_outbound_channel: mpsc::UnboundedSender<OutboundTunnelPayload>,
+        transport: &WarpTransportConfig,
+        priority: u8,
+        channels: GateChannels,
     ) -> anyhow::Result<Arc<Self>> {
-        let (destination_announce, destination_watch) = watch::channel(None);
+        if matches!(config, WarpGateConfig::TcpListener(_)) && !transport.ordered {
+            tracing::warn!(
+                "warp-gate {}: TCP streams will be reset whenever payloads arrive out of order; set transport.ordered",
+                tunnel_name
+            );
+        }
+        let socket = Self::create_socket(&config, tunnel_name, &channels.channel_sockets)?;
+        Self::with_socket(tunnel_name, tunnel_id, far_gate, socket, transport, priority, channels)
+    }
 
-        let socket = Self::create_socket(&config, tunnel_name, destination_announce)?;
+    /// Create a gate that exchanges data with the application over the channels in `socket` from [`ChannelGate::new`]
+    pub fn new_channel(
+        tunnel_name: &str,
+        tunnel_id: warp_protocol::messages::TunnelId,
+        far_gate: warp_protocol::PublicKey,
+        socket: ApplicationSocket,
+        transport: &WarpTransportConfig,
+        priority: u8,
+        channels: GateChannels,
+    ) -> anyhow::Result<Arc<Self>> {
+        tracing::info!("warp-gate {}: communicating with application in-process", tunnel_name);
+        Self::with_socket(tunnel_name, tunnel_id, far_gate, socket, transport, priority, channels)
+    }
+
+    fn with_socket(
+        tunnel_name: &str,
+        tunnel_id: warp_protocol::messages::TunnelId,
+        far_gate: warp_protocol::PublicKey,
+        socket: ApplicationSocket,
+        transport: &WarpTransportConfig,
+        priority: u8,
+        channels: GateChannels,
+    ) -> anyhow::Result<Arc<Self>> {
+        // Where the application last sent from, for gates that send back to wherever that is
+        let destination_watch = match &socket {
+            ApplicationSocket::Loopback {
+                current_destination, ..
+            } => current_destination.subscribe(),
+            _ => watch::channel(None).1,
+        };
         let socket = Arc::new(socket);
+        let GateChannels {
+            outbound: application_outbound_channel,
+            acknowledgements,
+            routing_state,
+            supervisor,
+            channel_sockets: _,
+        } = channels;
+        let send_deadline = transport.send_deadline;
+        let routes = std::sync::Arc::new(crate::routing::RoutePolicy::for_tunnel(transport, priority));
+        let reliable = transport.reliable.unwrap_or_default();
+        let compression = transport.compression;
+        let traffic_class = crate::interface::TrafficClass {
+            priority,
+            dscp: transport.dscp,
+            pad_to: transport.pad_to,
+            packing: crate::packing::Packing::for_tunnel(transport),
+        };
+        let mut rate_limit = transport
+            .rate_limit
+            .as_ref()
+            .map(|rate_limit| crate::pacing::TokenBucket::new(rate_limit, std::time::Instant::now()));
+        let mut fec_encoder = crate::fec::Encoder::new(transport, &tunnel_id)?;
+        let mut redundancy = transport
+            .redundancy
+            .adaptive
+            .as_ref()
+            .map(|adaptive| {
+                crate::redundancy::RedundancyController::new(&transport.redundancy, adaptive, std::time::Instant::now())
+            })
+            .transpose()?;
+        if let Some(redundancy) = &redundancy {
+            fec_encoder.set_num_shards(redundancy.num_shards());
+        }
 
         let (application_inbound_channel, mut application_inbound_channel_rx) = tokio::sync::mpsc::unbounded_channel();
 
@@ -87,58 +316,156 @@ impl Gate {
             application_inbound_channel,
             application_listener_task: OnceCell::new(),
             application_sender_task: OnceCell::new(),
+            closing: tokio_util::sync::CancellationToken::new(),
+            listener_stopped: tokio_util::sync::CancellationToken::new(),
         });
 
         let application_listener_task = tokio::task::Builder::new()
             .name(&format!("warp-gate {tunnel_name}: application to gate listener"))
             .spawn({
+                let task_name = format!("warp-gate {tunnel_name}: application to gate listener");
+                // Not run again once the gate is closing, as it would only stop straight away
+                let supervisor = supervisor.stopping_with(gate.closing.clone());
                 let tracer_generator = std::sync::atomic::AtomicU64::new(0);
                 let tunnel_name = tunnel_name.to_string();
+                let tunnel_id = tunnel_id.clone();
+                let tunnel_label = crate::trace::tunnel_label(&tunnel_id);
                 let socket = socket.clone();
-                async move {
+                let closing = gate.closing.clone();
+                let listener_stopped = gate.listener_stopped.clone();
+                let listener = supervisor.run(task_name, async move || {
                     let mut buf = vec![0u8; BUFFER_SIZE];
                     loop {
-                        match socket.recv_from_application(&mut buf).await {
-                            Ok(data) => {
+                        let received = tokio::select! {
+                            received = socket.recv_from_application(&mut buf) => received,
+                            // What the application handed over before the gate closed is still sent
+                            () = closing.cancelled() => match socket.recv_from_application(&mut buf).now_or_never() {
+                                Some(received) => received,
+                                None => break,
+                            },
+                        };
+                        match received {
+                            Ok(None) => {
+                                tracing::info!("warp-gate {}: application closed its end of the gate", tunnel_name);
+                                break;
+                            }
+                            Ok(Some(data)) => {
                                 let tunnel_payload = warp_protocol::messages::TunnelPayload::new(
                                     tunnel_id.clone(),
                                     tracer_generator.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
                                     data.to_vec(),
                                 );
                                 let tracer = tunnel_payload.tracer;
+                                let trace = TraceContext::root(Correlation::new(&tunnel_label, tracer));
                                 tracing::event!(
                                     tracing::Level::DEBUG,
                                     tunnel_name = tunnel_name,
                                     tracer = tracer,
+                                    correlation_id = %trace.correlation,
+                                    span_id = trace.span_id,
                                     payload_size = tunnel_payload.data.len(),
                                     "APPLICATION_TO_GATE_DATA_RX"
                                 );
+                                let span_id = trace.span_id;
+
+                                if let Some(redundancy) = &mut redundancy {
+                                    let now = std::time::Instant::now();
+                                    if redundancy.is_due(now) {
+                                        let stats = routing_state.selected_path_stats(&far_gate, &routes);
+                                        if let Some(num_shards) = redundancy.update(stats, now) {
+                                            fec_encoder.set_num_shards(num_shards);
+                                            tracing::event!(
+                                                tracing::Level::INFO,
+                                                tunnel_name = tunnel_name,
+                                                num_shards = num_shards,
+                                                loss = stats.map(|stats| stats.loss),
+                                                rtt_us = stats.and_then(|stats| stats.rtt).map(|rtt| rtt.as_micros()),
+                                                "TUNNEL_REDUNDANCY_CHANGED"
+                                            );
+                                        }
+                                    }
+                                }
+
+                                let encode = trace.child();
+                                let tunnel_payloads = match fec_encoder.encode(tunnel_payload) {
+                                    Ok(tunnel_payloads) => {
+                                        encode.export("encode");
+                                        tunnel_payloads
+                                    }
+                                    Err(e) => {
+                                        tracing::event!(
+                                            tracing::Level::WARN,
+                                            tunnel_name = tunnel_name,
+                                            tracer = tracer,
+                                            correlation_id = %trace.correlation,
+                                            span_id = span_id,
+                                            error = %e,
+                                            "TUNNEL_PAYLOAD_ENCODE_FAILED"
+                                        );
+                                        continue;
+                                    }
+                                };
+
+                                // Wait for the rate limit before the payload's send deadline starts counting down
+                                if let Some(rate_limit) = &mut rate_limit {
+                                    let now = std::time::Instant::now();
+                                    let ready_at = rate_limit.ready_at(now);
+                                    if ready_at > now {
+                                        tracing::event!(
+                                            tracing::Level::DEBUG,
+                                            tunnel_name = tunnel_name,
+                                            tracer = tracer,
+                                            correlation_id = %trace.correlation,
+                                            span_id = span_id,
+                                            wait_us = (ready_at - now).as_micros(),
+                                            "TUNNEL_RATE_LIMITED"
+                                        );
+                                        tokio::time::sleep_until(ready_at.into()).await;
+                                    }
+                                    let size = tunnel_payloads.iter().map(|tunnel_payload| tunnel_payload.data.len());
+                                    rate_limit.try_take(size.sum(), ready_at);
+                                }
 
                                 let (completion_notifier, completion_waiter) = tokio::sync::oneshot::channel();
                                 let outbound = OutboundTunnelPayload {
-                                    tunnel_payload,
+                                    tunnel_payloads: tunnel_payloads.into(),
+                                    far_gate,
+                                    routes: routes.clone(),
                                     deadline: std::time::Instant::now() + send_deadline,
+                                    reliable,
+                                    compression,
+                                    traffic_class,
                                     completion_notifier,
+                                    trace: trace.child(),
                                 };
 
+                                // Waits for room when the core is behind, which holds the application back too
                                 application_outbound_channel
                                     .send(outbound)
+                                    .await
                                     .expect("Channel should be open");
 
                                 // Wait for this tunnel payload to be warped over the interwebs; this will provide
                                 // backpressure to any application that is sending data to us over a "blocking"
                                 // mechanism (like a Unix Domain Socket).
                                 match completion_waiter.await {
-                                    Ok(()) => tracing::event!(
-                                        tracing::Level::DEBUG,
-                                        tunnel_name = tunnel_name,
-                                        tracer = tracer,
-                                        "TUNNEL_PAYLOAD_WARPED"
-                                    ),
+                                    Ok(()) => {
+                                        trace.export("gate_rx");
+                                        tracing::event!(
+                                            tracing::Level::DEBUG,
+                                            tunnel_name = tunnel_name,
+                                            tracer = tracer,
+                                            correlation_id = %trace.correlation,
+                                            span_id = span_id,
+                                            "TUNNEL_PAYLOAD_WARPED"
+                                        )
+                                    }
                                     Err(e) => tracing::event!(
                                         tracing::Level::WARN,
                                         tunnel_name = tunnel_name,
                                         tracer = tracer,
+                                        correlation_id = %trace.correlation,
+                                        span_id = span_id,
                                         error = %e,
                                         "TUNNEL_PAYLOAD_WARP_FAILED"
                                     ),
@@ -154,6 +481,10 @@ impl Gate {
                             }
                         }
                     }
+                });
+                async move {
+                    let _stopped = listener_stopped.drop_guard();
+                    listener.await
                 }
             })?;
         gate.application_listener_task
@@ -163,53 +494,138 @@ impl Gate {
         let application_sender_task = tokio::task::Builder::new()
             .name(&format!("warp-gate {tunnel_name}: gate to application tx"))
             .spawn({
+                let task_name = format!("warp-gate {tunnel_name}: gate to application tx");
                 let tunnel_name = tunnel_name.to_string();
                 let socket = socket.clone();
                 let destination_watch = destination_watch.clone();
-                async move {
-                    while let Some(tunnel_payload) = application_inbound_channel_rx.recv().await {
-                        let fallback_destination = *destination_watch.borrow();
-                        let queue_length = application_inbound_channel_rx.len();
-
-                        match socket
-                            .send_to_application(&tunnel_payload.data, fallback_destination)
-                            .await
-                        {
-                            Ok(sent) if sent == tunnel_payload.data.len() => {
-                                tracing::event!(
-                                    tracing::Level::DEBUG,
-                                    tunnel_name = tunnel_name,
-                                    tracer = tunnel_payload.tracer,
-                                    payload_size = tunnel_payload.data.len(),
-                                    queue_length = queue_length,
-                                    "GATE_TO_APPLICATION_DATA_SUCCESS"
-                                );
+                let mut fec_decoder = crate::fec::Decoder::new();
+                let mut delivered_payloads = reliable.then(DeliveredPayloads::new);
+                let acknowledge = move |tracer| {
+                    // Only fails once warp is shutting down
+                    let _ = acknowledgements.send(Acknowledgement {
+                        far_gate,
+                        tunnel_id: tunnel_id.clone(),
+                        tracer,
+                    });
+                };
+                let mut reorder_buffer = transport
+                    .ordered
+                    .then(|| ReorderBuffer::new(&transport.reordering.clone().unwrap_or_default()));
+                supervisor.run(task_name, async move || {
+                    loop {
+                        let reorder_deadline = reorder_buffer.as_ref().and_then(ReorderBuffer::deadline);
+                        let released = tokio::select! {
+                            received = application_inbound_channel_rx.recv() => {
+                                let Some((mut tunnel_payload, trace)) = received else {
+                                    break;
+                                };
+                                let now = std::time::Instant::now();
+                                let payload_tracer = crate::reliable::payload_tracer(&tunnel_payload);
+                                if let Some(delivered_payloads) = &mut delivered_payloads
+                                    && delivered_payloads.contains(payload_tracer, now)
+                                {
+                                    // A retransmission; the far gate may have missed the first acknowledgement
+                                    acknowledge(payload_tracer);
+                                    tracing::event!(
+                                        tracing::Level::DEBUG,
+                                        tunnel_name = tunnel_name,
+                                        tracer = payload_tracer,
+                                        correlation_id = %trace.correlation,
+                                        span_id = trace.span_id,
+                                        parent_span_id = trace.parent_span_id,
+                                        "TUNNEL_PAYLOAD_DUPLICATE_DROPPED"
+                                    );
+                                    continue;
+                                }
+                                if let Err(e) = crate::compression::decompress(&mut tunnel_payload) {
+                                    tracing::event!(
+                                        tracing::Level::WARN,
+                                        tunnel_name = tunnel_name,
+                                        tracer = tunnel_payload.tracer,
+                                        correlation_id = %trace.correlation,
+                                        span_id = trace.span_id,
+                                        parent_span_id = trace.parent_span_id,
+                                        error = %e,
+                                        "TUNNEL_PAYLOAD_DECOMPRESSION_FAILED"
+                                    );
+                                    continue;
+                                }
+                                let tunnel_payload = match fec_decoder.decode(tunnel_payload) {
+                                    Ok(Some(tunnel_payload)) => tunnel_payload,
+                                    // Waiting for more of its shards
+                                    Ok(None) => continue,
+                                    Err(e) => {
+                                        tracing::event!(
+                                            tracing::Level::WARN,
+                                            tunnel_name = tunnel_name,
+                                            correlation_id = %trace.correlation,
+                                            span_id = trace.span_id,
+                                            parent_span_id = trace.parent_span_id,
+                                            error = %e,
+                                            "TUNNEL_PAYLOAD_RECONSTRUCTION_FAILED"
+                                        );
+                                        continue;
+                                    }
+                                };
+                                if let Some(delivered_payloads) = &mut delivered_payloads {
+                                    delivered_payloads.insert(payload_tracer, now);
+                                    acknowledge(payload_tracer);
+                                }
+                                match &mut reorder_buffer {
+                                    None => vec![(tunnel_payload, trace)],
+                                    Some(reorder_buffer) => match reorder_buffer.push(
+                                        tunnel_payload.tracer,
+                                        (tunnel_payload, trace),
+                                        std::time::Instant::now(),
+                                    ) {
+                                        Pushed::Released(released) => released,
+                                        Pushed::Dropped((tunnel_payload, trace)) => {
+                                            tracing::event!(
+                                                tracing::Level::DEBUG,
+                                                tunnel_name = tunnel_name,
+                                                tracer = tunnel_payload.tracer,
+                                                correlation_id = %trace.correlation,
+                                                span_id = trace.span_id,
+                                                parent_span_id = trace.parent_span_id,
+                                                "TUNNEL_PAYLOAD_OUT_OF_ORDER_DROPPED"
+                                            );
+                                            continue;
+                                        }
+                                    },
+                                }
                             }
-                            Ok(sent) => {
+                            _ = tokio::time::sleep_until(
+                                reorder_deadline.unwrap_or_else(std::time::Instant::now).into()
+                            ), if reorder_deadline.is_some() => {
+                                let released = reorder_buffer
+                                    .as_mut()
+                                    .map(|reorder_buffer| reorder_buffer.expire(std::time::Instant::now()))
+                                    .unwrap_or_default();
                                 tracing::event!(
-                                    tracing::Level::WARN,
-                                    tunnel_name = tunnel_name,
-                                    tracer = tunnel_payload.tracer,
-                                    payload_size = tunnel_payload.data.len(),
-                                    sent_bytes = sent,
-                                    queue_length = queue_length,
-                                    "GATE_TO_APPLICATION_DATA_INCOMPLETE"
-                                );
-                            }
-                            Err(e) => {
-                                tracing::event!(
-                                    tracing::Level::WARN,
+                                    tracing::Level::DEBUG,
                                     tunnel_name = tunnel_name,
-                                    tracer = tunnel_payload.tracer,
-                                    payload_size = tunnel_payload.data.len(),
-                                    queue_length = queue_length,
-                                    error = %e,
-                                    "GATE_TO_APPLICATION_DATA_FAILED"
+                                    released = released.len(),
+                                    "TUNNEL_PAYLOAD_REORDER_TIMEOUT"
                                 );
+                                released
                             }
+                        };
+
+                        for (tunnel_payload, trace) in released {
+                            let fallback_destination = *destination_watch.borrow();
+                            let queue_length = application_inbound_channel_rx.len();
+                            Self::deliver_to_application(
+                                &socket,
+                                &tunnel_name,
+                                tunnel_payload,
+                                trace,
+                                fallback_destination,
+                                queue_length,
+                            )
+                            .await;
                         }
                     }
-                }
+                })
             })?;
         gate.application_sender_task
             .set(application_sender_task)
@@ -218,10 +634,67 @@ impl Gate {
         Ok(gate)
     }
 
+    async fn deliver_to_application(
+        socket: &ApplicationSocket,
+        tunnel_name: &str,
+        tunnel_payload: warp_protocol::messages::TunnelPayload,
+        trace: TraceContext,
+        fallback_destination: Option<std::net::SocketAddr>,
+        queue_length: usize,
+    ) {
+        match socket
+            .send_to_application(&tunnel_payload.data, fallback_destination)
+            .await
+        {
+            Ok(sent) if sent == tunnel_payload.data.len() => {
+                trace.export("gate_tx");
+                tracing::event!(
+                    tracing::Level::DEBUG,
+                    tunnel_name = tunnel_name,
+                    tracer = tunnel_payload.tracer,
+                    correlation_id = %trace.correlation,
+                    span_id = trace.span_id,
+                    parent_span_id = trace.parent_span_id,
+                    payload_size = tunnel_payload.data.len(),
+                    queue_length = queue_length,
+                    "GATE_TO_APPLICATION_DATA_SUCCESS"
+                );
+            }
+            Ok(sent) => {
+                tracing::event!(
+                    tracing::Level::WARN,
+                    tunnel_name = tunnel_name,
+                    tracer = tunnel_payload.tracer,
+                    correlation_id = %trace.correlation,
+                    span_id = trace.span_id,
+                    parent_span_id = trace.parent_span_id,
+                    payload_size = tunnel_payload.data.len(),
+                    sent_bytes = sent,
+                    queue_length = queue_length,
+                    "GATE_TO_APPLICATION_DATA_INCOMPLETE"
+                );
+            }
+            Err(e) => {
+                tracing::event!(
+                    tracing::Level::WARN,
+                    tunnel_name = tunnel_name,
+                    tracer = tunnel_payload.tracer,
+                    correlation_id = %trace.correlation,
+                    span_id = trace.span_id,
+                    parent_span_id = trace.parent_span_id,
+                    payload_size = tunnel_payload.data.len(),
+                    queue_length = queue_length,
+                    error = %e,
+                    "GATE_TO_APPLICATION_DATA_FAILED"
+                );
+            }
+        }
+    }
+
     fn create_socket(
         config: &WarpGateConfig,
         tunnel_name: &str,
-        dest_tx: watch::Sender<Option<std::net::SocketAddr>>,
+        channel_sockets: &ChannelSockets,
     ) -> anyhow::Result<ApplicationSocket> {
         match config {
             WarpGateConfig::Loopback(config) => {
@@ -244,7 +717,6 @@ impl Gate {
 
                 let fixed_destination = if let Some(port) = config.gate_to_application {
                     let dest_addr = std::net::SocketAddr::new(ip, port);
-                    dest_tx.send_replace(Some(dest_addr));
                     tracing::info!("warp-gate {}: sending application data to {}", tunnel_name, dest_addr);
                     Some(dest_addr)
                 } else {
@@ -254,9 +726,17 @@ impl Gate {
                 Ok(ApplicationSocket::Loopback {
                     socket,
                     fixed_destination,
-                    current_destination: dest_tx,
+                    current_destination: watch::Sender::new(fixed_destination),
                 })
             }
+            #[cfg(not(unix))]
+            WarpGateConfig::UnixDomainSocket(_) => {
+                anyhow::bail!(
+                    "warp-gate {}: Unix domain sockets are only supported on Unix",
+                    tunnel_name
+                )
+            }
+            #[cfg(unix)]
             WarpGateConfig::UnixDomainSocket(config) => {
                 let _ = std::fs::remove_file(&config.path);
                 let socket = tokio::net::UnixDatagram::bind(&config.path)?;
@@ -269,11 +749,41 @@ impl Gate {
 
                 Ok(ApplicationSocket::UnixDomainSocket(socket))
             }
+            WarpGateConfig::TcpListener(config) => Ok(ApplicationSocket::Tcp(crate::tcp_gate::TcpGate::bind(
+                config,
+                tunnel_name,
+            )?)),
+            WarpGateConfig::Channel(config) => {
+                let socket = channel_sockets.socket(&config.channel).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "warp-gate {}: no application embedding warp has registered channel {}",
+                        tunnel_name,
+                        config.channel
+                    )
+                })?;
+                tracing::info!(
+                    "warp-gate {}: communicating with application in-process over channel {}",
+                    tunnel_name,
+                    config.channel
+                );
+                Ok(socket)
+            }
         }
     }
 
-    pub async fn send_to_application(&self, tunnel_payload: warp_protocol::messages::TunnelPayload) {
-        self.application_inbound_channel.send(tunnel_payload).unwrap();
+    /// Stop taking data from the application, returning once what was already taken has been sent
+    pub async fn close(&self) {
+        self.closing.cancel();
+        self.listener_stopped.cancelled().await;
+    }
+
+    /// Queue `tunnel_payload` for delivery to the application; `trace` is the gate's hop in its journey
+    pub async fn send_to_application(
+        &self,
+        tunnel_payload: warp_protocol::messages::TunnelPayload,
+        trace: TraceContext,
+    ) {
+        self.application_inbound_channel.send((tunnel_payload, trace)).unwrap();
     }
 }
 
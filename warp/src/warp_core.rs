@@ -0,0 +1,2626 @@
+use crate::trace::{Correlation, TraceContext};
+use crate::transport::{Network, SystemNetwork};
+use crate::{
+    compression, control, health, interface, metrics, packing, relay, reliable, routing, session, state, supervisor,
+    tunnel,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use warp_protocol::codec::Message;
+
+// How often the state file is brought up to date, if anything changed
+const STATE_SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+// A changed tunnel's new gate may need an address that its old gate's tasks haven't let go of yet
+const GATE_OPEN_ATTEMPTS: usize = 20;
+const GATE_OPEN_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(25);
+// Received datagrams are processed by one worker per core, up to this many, unless `queues.rx_workers` says otherwise
+const DEFAULT_MAX_RX_WORKERS: usize = 4;
+// How long to let the rest of a burst of interface changes come in before rescanning
+const INTERFACE_CHANGE_SETTLE_TIME: std::time::Duration = std::time::Duration::from_millis(20);
+// Address overrides that far gates haven't sent again for this many holepunch keep-alive intervals are dropped
+const OVERRIDE_TTL_KEEP_ALIVES: u32 = 3;
+// A task that spends longer than this on one tick or message is stalled
+const TASK_STALL_LIMIT: std::time::Duration = std::time::Duration::from_secs(10);
+// How often tasks are checked for stalls
+const HEALTH_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+// Tunnel ids only need to be unique per far gate
+type TunnelGates = BTreeMap<warp_protocol::PublicKey, HashMap<warp_protocol::messages::TunnelId, Arc<tunnel::Gate>>>;
+// Called with a far gate's key and whether it is now alive
+type PeerLivenessCallback = Arc<dyn Fn(warp_protocol::PublicKey, bool) + Send + Sync>;
+type ReadyCallback = Box<dyn FnOnce() + Send>;
+type ControlRequest = (control::Request, tokio::sync::oneshot::Sender<control::Response>);
+// Paths to send over: the interface to send from and the address to send to
+type Routes = Vec<(Arc<interface::NetworkInterface>, std::net::SocketAddr)>;
+// Tunnel payloads are packed together by tunnel, interface and peer address
+type Packer = packing::Packer<
+    (
+        warp_protocol::messages::TunnelId,
+        interface::NetworkInterfaceId,
+        std::net::SocketAddr,
+    ),
+    Arc<interface::NetworkInterface>,
+>;
+// The tracers of the payloads to acknowledge, by far gate and tunnel
+type TunnelAcknowledgements = BTreeMap<warp_protocol::PublicKey, HashMap<warp_protocol::messages::TunnelId, Vec<u64>>>;
+
+pub struct WarpCore {
+    warp_config: warp_config::WarpConfig,
+    network: Arc<dyn Network>,
+    shutdown: tokio::sync::oneshot::Receiver<()>,
+    channel_tunnels: Vec<ChannelTunnel>,
+    channel_sockets: tunnel::ChannelSockets,
+    reloads_tx: tokio::sync::mpsc::UnboundedSender<warp_config::WarpConfig>,
+    reloads: tokio::sync::mpsc::UnboundedReceiver<warp_config::WarpConfig>,
+    control_requests_tx: tokio::sync::mpsc::UnboundedSender<ControlRequest>,
+    control_requests: tokio::sync::mpsc::UnboundedReceiver<ControlRequest>,
+    peer_liveness_callback: Option<PeerLivenessCallback>,
+    ready_callback: Option<ReadyCallback>,
+    health: health::Health,
+}
+
+// A tunnel added through WarpCore::add_channel_tunnel, waiting for run() to create its gate
+struct ChannelTunnel {
+    name: String,
+    tunnel_id: Option<u64>,
+    transport: warp_config::WarpTransportConfig,
+    socket: tunnel::ApplicationSocket,
+}
+
+impl WarpCore {
+    /// Create a warp core using the host's network; send on (or drop) the returned sender to shut it down gracefully
+    pub fn new(warp_config: warp_config::WarpConfig) -> (Self, tokio::sync::oneshot::Sender<()>) {
+        Self::with_network(warp_config, Arc::new(SystemNetwork))
+    }
+
+    /// Create a warp core that discovers interfaces and binds sockets through `network`
+    pub fn with_network(
+        warp_config: warp_config::WarpConfig,
+        network: Arc<dyn Network>,
+    ) -> (Self, tokio::sync::oneshot::Sender<()>) {
+        let (shutdown_notifier, shutdown) = tokio::sync::oneshot::channel();
+        let (reloads_tx, reloads) = tokio::sync::mpsc::unbounded_channel();
+        let (control_requests_tx, control_requests) = tokio::sync::mpsc::unbounded_channel();
+        let warp_core = WarpCore {
+            warp_config,
+            network,
+            shutdown,
+            channel_tunnels: Vec::new(),
+            channel_sockets: tunnel::ChannelSockets::default(),
+            reloads_tx,
+            reloads,
+            control_requests_tx,
+            control_requests,
+            peer_liveness_callback: None,
+            ready_callback: None,
+            health: health::Health::new(),
+        };
+        (warp_core, shutdown_notifier)
+    }
+
+    /// Add a tunnel whose gate is a pair of in-process channels rather than a socket
+    ///
+    /// This is for applications that embed warp; call it before [`Self::run`]. The name must not clash with a tunnel
+    /// from the config.
+    pub fn add_channel_tunnel(
+        &mut self,
+        name: &str,
+        tunnel_id: Option<u64>,
+        transport: warp_config::WarpTransportConfig,
+    ) -> anyhow::Result<tunnel::ChannelGate> {
+        if self.warp_config.tunnels.contains_key(name) || self.channel_tunnels.iter().any(|t| t.name == name) {
+            anyhow::bail!("tunnel {name} already exists");
+        }
+
+        let (channel_gate, socket) =
+            tunnel::ChannelGate::new(self.gate_queue_capacity(), Some(transport.send_deadline));
+        self.channel_tunnels.push(ChannelTunnel {
+            name: name.to_string(),
+            tunnel_id,
+            transport,
+            socket,
+        });
+        Ok(channel_gate)
+    }
+
+    /// Register the in-process gate that tunnels configured with `gate = { channel = "..." }` use
+    ///
+    /// Unlike [`Self::add_channel_tunnel`], the tunnels come from the config, so they can be opened, closed and
+    /// reopened by reloads and control requests like any other; each time, the gate takes up the same channels. Call
+    /// this before [`Self::run`], or tunnels configured with `channel` fail to open.
+    pub fn channel_gate(&mut self, channel: &str) -> anyhow::Result<tunnel::ChannelGate> {
+        let send_deadline = self
+            .warp_config
+            .tunnels
+            .values()
+            .filter(
+                |tunnel| matches!(&tunnel.gate, warp_config::WarpGateConfig::Channel(gate) if gate.channel == channel),
+            )
+            .map(|tunnel| tunnel.transport.send_deadline)
+            .min();
+        self.channel_sockets
+            .register(channel, self.gate_queue_capacity(), send_deadline)
+    }
+
+    // How many payloads an in-process gate holds for the gate to take, as many as the gates' queue to the accelerator
+    fn gate_queue_capacity(&self) -> usize {
+        self.warp_config.queues.clone().unwrap_or_default().capacity
+    }
+
+    /// Send a config on the returned sender to switch to it while running
+    ///
+    /// Tunnels are opened, closed or reopened to match the new config; tunnels whose config is unchanged carry on
+    /// undisturbed. Interface patterns, intervals and far gates take effect within one interface scan. The private
+    /// key, warp-map and metrics settings only change on restart, so changes to them are ignored.
+    pub fn reloader(&self) -> tokio::sync::mpsc::UnboundedSender<warp_config::WarpConfig> {
+        self.reloads_tx.clone()
+    }
+
+    /// Open and close tunnels while running through the returned controller, without touching the config
+    ///
+    /// Tunnels opened this way stay open across reloads, until they are closed or warp stops; see [`control`].
+    pub fn controller(&self) -> control::Controller {
+        control::Controller::new(self.control_requests_tx.clone())
+    }
+
+    /// Call `callback` with a far gate's key and `false` when the far gate goes down, or `true` when it comes back up
+    ///
+    /// A far gate is down once none of its addresses has been heard from for `interfaces.liveness.timeout`. Call this
+    /// before [`Self::run`]; the callback runs on one of warp's tasks, so it should return quickly.
+    pub fn on_peer_liveness(&mut self, callback: impl Fn(warp_protocol::PublicKey, bool) + Send + Sync + 'static) {
+        self.peer_liveness_callback = Some(Arc::new(callback));
+    }
+
+    /// Call `callback` once, when the interfaces found when warp starts have been bound and the tunnels opened
+    ///
+    /// Call this before [`Self::run`]; the callback holds up `run`, so it should return quickly.
+    pub fn on_ready(&mut self, callback: impl FnOnce() + Send + 'static) {
+        self.ready_callback = Some(Box::new(callback));
+    }
+
+    /// Whether warp's tasks are keeping up; see [`health`]
+    ///
+    /// The returned handle can be kept and asked again, before, during or after [`Self::run`].
+    pub fn health(&self) -> health::Health {
+        self.health.clone()
+    }
+
+    /// Run until shut down; core tasks that panic are run again after a backoff
+    pub async fn run(&mut self) {
+        let mut futures = futures::stream::FuturesUnordered::new();
+
+        // Create consolidated packet routing state
+        let routing_state = std::sync::Arc::new(routing::RoutingState::new());
+        // The config the tasks follow; it changes when the config is reloaded
+        let (config_tx, config_watch) = tokio::sync::watch::channel(self.warp_config.clone());
+
+        let warp_map_cipher = warp_protocol::crypto::cipher_from_shared_secret(
+            &self.warp_config.private_key,
+            &self.warp_config.warp_map.public_key,
+        );
+        // In-process tunnels always go to the far gate that was the default when warp started
+        let channel_far_gate = self.warp_config.far_gate.public_key;
+        let sessions = Arc::new(session::Sessions::new(
+            self.warp_config.private_key.clone(),
+            self.warp_config.cipher_suites(),
+            &peers(&self.warp_config, channel_far_gate),
+            std::time::Instant::now(),
+        ));
+        // Numbers every message sent to a far gate so that the far gate can reject replays of it
+        let nonces = Arc::new(warp_protocol::replay::NonceSequence::new(
+            self.warp_config.private_key.public_key(),
+        ));
+        // For far gates that can only be reached through warp-map
+        let relay = Arc::new(relay::Relay::new(
+            self.warp_config.warp_map.address,
+            warp_map_cipher.clone(),
+        ));
+        // The payloads of reliable tunnels that their far gates haven't acknowledged yet
+        let retransmissions = Arc::new(std::sync::Mutex::new(reliable::RetransmissionQueue::new()));
+
+        // Cancelled at shutdown to stop the tasks below, except the accelerator: it stops once the gates have closed and
+        // it has passed on all they sent it
+        let stop_tasks = tokio_util::sync::CancellationToken::new();
+        // Runs the tasks again if they panic
+        let supervisor = supervisor::Supervisor::new(self.health.clone(), stop_tasks.clone());
+
+        if let Some(metrics_config) = &self.warp_config.metrics {
+            let bind = metrics_config.bind;
+            let health = self.health.clone();
+            let health_check: warp_metrics::exporter::HealthCheck = Arc::new(move || {
+                let report = health.report();
+                if report.healthy {
+                    Ok(())
+                } else {
+                    Err(report.to_string())
+                }
+            });
+            tokio::task::Builder::new()
+                .name("metrics exporter")
+                .spawn(until_cancelled(stop_tasks.clone(), async move {
+                    if let Err(e) =
+                        warp_metrics::exporter::bind_and_serve(warp_metrics::global().clone(), Some(health_check), bind)
+                            .await
+                    {
+                        tracing::error!("Metrics exporter on {} stopped: {}", bind, e);
+                    }
+                }))
+                .unwrap();
+        }
+
+        // Start from what was learned before the last restart, if it was saved
+        let saved_state = match &self.warp_config.state_file {
+            Some(path) => match state::State::load(path).await {
+                Ok(saved_state) => saved_state.unwrap_or_default(),
+                Err(e) => {
+                    tracing::event!(
+                        tracing::Level::WARN,
+                        path = %path.display(),
+                        error = %e,
+                        "STATE_LOAD_FAILED"
+                    );
+                    state::State::default()
+                }
+            },
+            None => state::State::default(),
+        };
+        saved_state.restore(&routing_state);
+
+        // There's no way to hold the remote sender back, so once this queue is full datagrams are dropped instead
+        let queues = self.warp_config.queues.clone().unwrap_or_default();
+        let rx_workers = queues.rx_workers.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, |cores| cores.get().min(DEFAULT_MAX_RX_WORKERS))
+        });
+        let (tx, rx_workers) =
+            crate::queue::sharded::<interface::RxPayload>(rx_workers, queues.capacity, queues.overflow);
+        let (first_scan_tx, first_scan) = tokio::sync::oneshot::channel();
+
+        let interface_scan_task = tokio::task::Builder::new()
+            .name("interface scan task")
+            .spawn(until_cancelled(stop_tasks.clone(), {
+                let config_watch = config_watch.clone();
+                let network = self.network.clone();
+                let mut interfaces = Vec::new();
+                let mut flap_damping = crate::flapping::FlapDamping::new();
+                let routing_state = routing_state.clone();
+                let mut first_scan_tx = Some(first_scan_tx);
+                let heartbeat = self.health.register("interface scan", TASK_STALL_LIMIT);
+                supervisor.clone().run("interface scan", async move || {
+                    let mut interval = tokio::time::interval(config_watch.borrow().interfaces.interface_scan_interval);
+                    let mut watching = None;
+                    let mut changes = None;
+
+                    loop {
+                        let scan_interval = config_watch.borrow().interfaces.interface_scan_interval;
+                        let interface_watch = config_watch.borrow().interfaces.interface_watch.unwrap_or_default();
+                        if watching != Some(interface_watch) {
+                            watching = Some(interface_watch);
+                            changes = match interface_watch {
+                                warp_config::InterfaceWatch::Scan => None,
+                                warp_config::InterfaceWatch::Netlink => network
+                                    .watch_interfaces()
+                                    .inspect_err(|e| {
+                                        tracing::event!(
+                                            tracing::Level::INFO,
+                                            error = %e,
+                                            "INTERFACE_WATCH_UNAVAILABLE"
+                                        )
+                                    })
+                                    .ok(),
+                            };
+                        }
+                        tokio::select! {
+                            _ = tick_every(&mut interval, scan_interval) => {}
+                            _ = interface_change(&mut changes) => {
+                                // Changes come in bursts, e.g. a link and then its addresses; one scan covers them
+                                tokio::time::sleep(INTERFACE_CHANGE_SETTLE_TIME).await;
+                                if let Some(changes) = &mut changes {
+                                    changes.mark_unchanged();
+                                }
+                            }
+                        }
+                        let _working = heartbeat.working();
+                        let interfaces_config = config_watch.borrow().interfaces.clone();
+                        let damping_config = interfaces_config.flap_damping.clone().unwrap_or_default();
+                        let now = std::time::Instant::now();
+                        let mut hold_down = |id: &interface::NetworkInterfaceId| {
+                            let (score, hold_down) = flap_damping.failed(id, &damping_config, now);
+                            tracing::event!(
+                                tracing::Level::WARN,
+                                interface = %id,
+                                score,
+                                hold_down_ms = hold_down.as_millis() as u64,
+                                "INTERFACE_HELD_DOWN"
+                            );
+                        };
+
+                        // TODO: Extract this into a method so we can handle errors properly
+                        {
+                            let ipv4_interfacse: Vec<_> = network
+                                .interfaces()
+                                .into_iter()
+                                .filter(|iface| interfaces_config.inclusion_patterns.is_match(&iface.name))
+                                .filter(|iface| !interfaces_config.exclusion_patterns.is_match(&iface.name))
+                                .collect();
+
+                            interfaces.retain(|existing_interface: &std::sync::Arc<interface::NetworkInterface>| {
+                                let alive = existing_interface.is_alive();
+                                if !alive {
+                                    tracing::warn!("{} is no longer alive", existing_interface.id);
+                                    hold_down(&existing_interface.id);
+                                }
+                                alive
+                            });
+                            interfaces.retain(|existing_interface: &std::sync::Arc<interface::NetworkInterface>| {
+                                let retain = ipv4_interfacse
+                                    .iter()
+                                    .any(|current_id| &existing_interface.id == current_id);
+                                if !retain {
+                                    tracing::info!("Interface {} no longer detected; removing", existing_interface.id);
+                                    hold_down(&existing_interface.id);
+                                }
+                                retain
+                            });
+
+                            let new_interface_ids: Vec<_> = ipv4_interfacse
+                                .iter()
+                                .filter(|new_interface| {
+                                    !interfaces
+                                        .iter()
+                                        .any(|existing_interface| &existing_interface.id == *new_interface)
+                                })
+                                .filter(|new_interface| !flap_damping.is_held_down(new_interface, now))
+                                .collect();
+                            flap_damping.forget_stable(&damping_config, now);
+
+                            for new_interface_id in new_interface_ids {
+                                let saved = saved_state.interface(&new_interface_id.name, new_interface_id.ip);
+                                match interface::NetworkInterface::new(
+                                    new_interface_id.clone(),
+                                    saved.map(|saved| saved.port),
+                                    &config_watch,
+                                    network.as_ref(),
+                                    tx.clone(),
+                                    routing_state.interface_traffic(&new_interface_id.name),
+                                ) {
+                                    Ok(new_interface) => {
+                                        // The NAT mapping warp-map saw may well have outlived the restart, if the
+                                        // port is the same
+                                        if let Some(external_address) = saved
+                                            .filter(|saved| saved.port == new_interface.local_addr().port())
+                                            .and_then(|saved| saved.external_address)
+                                        {
+                                            new_interface.set_external_address(external_address);
+                                        }
+                                        interfaces.push(new_interface)
+                                    }
+                                    Err(e) => {
+                                        tracing::warn!("Failed to create new interface {}: {}", new_interface_id, e)
+                                    }
+                                }
+                            }
+                        }
+                        metrics::ACTIVE_INTERFACES.set(interfaces.len() as i64);
+                        routing_state.interfaces_sender().send_replace(interfaces.clone());
+                        if let Some(first_scan_tx) = first_scan_tx.take() {
+                            let _ = first_scan_tx.send(());
+                        }
+                    }
+                })
+            }))
+            .unwrap();
+        futures.push(interface_scan_task);
+
+        if let Some(path) = self.warp_config.state_file.clone() {
+            let state_saver_task = tokio::task::Builder::new()
+                .name("state saver task")
+                .spawn(until_cancelled(stop_tasks.clone(), {
+                    let routing_state = routing_state.clone();
+                    supervisor.clone().run("state saver", async move || {
+                        let mut saved = None;
+                        let mut interval = tokio::time::interval(STATE_SAVE_INTERVAL);
+                        loop {
+                            interval.tick().await;
+                            let state = state::State::capture(&routing_state);
+                            if saved.as_ref() != Some(&state) {
+                                match state.save(&path).await {
+                                    Ok(()) => saved = Some(state),
+                                    Err(e) => log_state_save_failure(&path, &e),
+                                }
+                            }
+                        }
+                    })
+                }))
+                .unwrap();
+            futures.push(state_saver_task);
+        }
+
+        let (outbound_tunnel_payload_publisher, mut outbound_tunnel_payloads) =
+            tokio::sync::mpsc::channel::<crate::tunnel::OutboundTunnelPayload>(queues.capacity.max(1));
+
+        let (acknowledgement_publisher, mut acknowledgements) =
+            tokio::sync::mpsc::unbounded_channel::<tunnel::Acknowledgement>();
+
+        let (tunnel_gates_tx, tunnel_gates) = tokio::sync::watch::channel(TunnelGates::new());
+        let mut tunnels = Tunnels {
+            configured: BTreeMap::new(),
+            channel_gates: Vec::new(),
+            channels: tunnel::GateChannels {
+                outbound: outbound_tunnel_payload_publisher,
+                acknowledgements: acknowledgement_publisher,
+                routing_state: routing_state.clone(),
+                supervisor: supervisor.clone(),
+                channel_sockets: self.channel_sockets.clone(),
+            },
+            gates_tx: tunnel_gates_tx,
+            sessions: sessions.clone(),
+            dynamic: control::DynamicTunnels::new(),
+        };
+
+        for channel_tunnel in std::mem::take(&mut self.channel_tunnels) {
+            let tunnel_id = tunnel_id(&channel_tunnel.name, channel_tunnel.tunnel_id);
+            let gate = tunnel::Gate::new_channel(
+                &channel_tunnel.name,
+                tunnel_id.clone(),
+                channel_far_gate,
+                channel_tunnel.socket,
+                &channel_tunnel.transport,
+                // Priorities only come from the config, so in-process tunnels get the lowest
+                0,
+                tunnels.channels.clone(),
+            )
+            .unwrap();
+            tunnels.channel_gates.push((channel_far_gate, tunnel_id, gate));
+        }
+        tunnels.apply(&self.warp_config).await.unwrap();
+        if let Some(ready_callback) = self.ready_callback.take() {
+            // If the scan task died instead, that is noticed below
+            let _ = first_scan.await;
+            ready_callback();
+        }
+
+        let override_sender_task = tokio::task::Builder::new()
+            .name("Holepunching: peer address override sender")
+            .spawn(until_cancelled(stop_tasks.clone(), {
+                let routing_state = routing_state.clone();
+                let sessions = sessions.clone();
+                let config_watch = config_watch.clone();
+                let nonces = nonces.clone();
+                let heartbeat = self.health.register("override sender", TASK_STALL_LIMIT);
+
+                supervisor.clone().run("override sender", async move || {
+                    let mut interval =
+                        tokio::time::interval(config_watch.borrow().interfaces.holepunch_keep_alive_interval);
+
+                    loop {
+                        let keep_alive_interval = config_watch.borrow().interfaces.holepunch_keep_alive_interval;
+                        tick_every(&mut interval, keep_alive_interval).await;
+                        let _working = heartbeat.working();
+
+                        let peer_ciphers = sessions.ciphers();
+                        let interfaces = routing_state.interfaces();
+
+                        for interface in interfaces.iter() {
+                            if !interface.is_alive() {
+                                continue;
+                            }
+
+                            // Send override message if we know our external address
+                            let Some(external_addr) = interface.get_external_address() else {
+                                continue;
+                            };
+                            let override_msg = warp_protocol::messages::PeerAddressOverride {
+                                replace: external_addr,
+                                sequence: override_sequence(std::time::SystemTime::now()),
+                            };
+
+                            for (far_gate, peer_cipher) in &peer_ciphers {
+                                for peer_addr in routing_state.resolve_peer_addresses(
+                                    far_gate,
+                                    &interface.id,
+                                    interface.get_external_address(),
+                                ) {
+                                    // Sealed once per address since the far gate drops repeats of a message as replays
+                                    if let Err(e) = seal_for_peer(override_msg.clone(), far_gate, peer_cipher, &nonces)
+                                        .map_err(anyhow::Error::from)
+                                        .and_then(|data| {
+                                            interface.queue_send(
+                                                data,
+                                                &peer_addr,
+                                                None,
+                                                interface::TrafficClass::default(),
+                                                None,
+                                            )
+                                        })
+                                    {
+                                        tracing::event!(
+                                            tracing::Level::WARN,
+                                            interface = %interface.id,
+                                            peer_addr = %peer_addr,
+                                            error = %e,
+                                            "OVERRIDE_SEND_FAILED"
+                                        );
+                                    } else {
+                                        tracing::event!(
+                                            tracing::Level::DEBUG,
+                                            interface = %interface.id,
+                                            peer_addr = %peer_addr,
+                                            replace_addr = %external_addr,
+                                            "OVERRIDE_SENT_PERIODIC"
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                    }
+                })
+            }))
+            .unwrap();
+        futures.push(override_sender_task);
+
+        let override_expiry_task = tokio::task::Builder::new()
+            .name("override expirer")
+            .spawn(until_cancelled(stop_tasks.clone(), {
+                let routing_state = routing_state.clone();
+                let config_watch = config_watch.clone();
+
+                supervisor.clone().run("override expirer", async move || {
+                    let mut interval =
+                        tokio::time::interval(config_watch.borrow().interfaces.holepunch_keep_alive_interval);
+
+                    loop {
+                        let keep_alive_interval = config_watch.borrow().interfaces.holepunch_keep_alive_interval;
+                        tick_every(&mut interval, keep_alive_interval).await;
+
+                        let ttl = keep_alive_interval * OVERRIDE_TTL_KEEP_ALIVES;
+                        for (interface_name, replace_addr, override_addr) in
+                            routing_state.expire_overrides(ttl, std::time::Instant::now())
+                        {
+                            tracing::event!(
+                                tracing::Level::INFO,
+                                interface = interface_name,
+                                replace_addr = %replace_addr,
+                                override_addr = %override_addr,
+                                "OVERRIDE_EXPIRED"
+                            );
+                        }
+                    }
+                })
+            }))
+            .unwrap();
+        futures.push(override_expiry_task);
+
+        let traffic_log_task = tokio::task::Builder::new()
+            .name("traffic logger")
+            .spawn(until_cancelled(stop_tasks.clone(), {
+                let routing_state = routing_state.clone();
+                let mut config_watch = config_watch.clone();
+
+                supervisor.clone().run("traffic logger", async move || {
+                    let mut interval = None;
+                    loop {
+                        let traffic_log = config_watch.borrow_and_update().interfaces.traffic_log.clone();
+                        let Some(traffic_log) = traffic_log else {
+                            // Until a reload turns the log on
+                            interval = None;
+                            if config_watch.changed().await.is_err() {
+                                break;
+                            }
+                            continue;
+                        };
+                        let interval = interval.get_or_insert_with(|| {
+                            let start = tokio::time::Instant::now() + traffic_log.interval;
+                            tokio::time::interval_at(start, traffic_log.interval)
+                        });
+                        tick_every(interval, traffic_log.interval).await;
+                        log_traffic(&routing_state);
+                    }
+                })
+            }))
+            .unwrap();
+        futures.push(traffic_log_task);
+
+        let path_probe_task = tokio::task::Builder::new()
+            .name("path prober")
+            .spawn(until_cancelled(stop_tasks.clone(), {
+                let routing_state = routing_state.clone();
+                let sessions = sessions.clone();
+                let config_watch = config_watch.clone();
+                let nonces = nonces.clone();
+                let heartbeat = self.health.register("path prober", TASK_STALL_LIMIT);
+
+                supervisor.clone().run("path prober", async move || {
+                    let path_probing = || {
+                        config_watch
+                            .borrow()
+                            .interfaces
+                            .path_probing
+                            .clone()
+                            .unwrap_or_default()
+                    };
+                    let mut interval = tokio::time::interval(path_probing().interval);
+
+                    loop {
+                        tick_every(&mut interval, path_probing().interval).await;
+                        let _working = heartbeat.working();
+                        let path_probing = path_probing();
+                        let peer_ciphers = sessions.ciphers();
+                        let now = std::time::Instant::now();
+
+                        for (interface, peer_addr) in routing_state.expire_path_probes(path_probing.timeout, now) {
+                            tracing::event!(
+                                tracing::Level::DEBUG,
+                                interface = %interface.id,
+                                peer_addr = %peer_addr,
+                                loss = interface.path_stats(peer_addr).loss,
+                                "PATH_PROBE_LOST"
+                            );
+                        }
+
+                        // Cloned so the watch isn't borrowed while probes are sent
+                        let interfaces = routing_state.interfaces().clone();
+                        for interface in interfaces.iter().filter(|interface| interface.is_alive()) {
+                            for (far_gate, peer_cipher) in &peer_ciphers {
+                                for peer_addr in routing_state.resolve_peer_addresses(
+                                    far_gate,
+                                    &interface.id,
+                                    interface.get_external_address(),
+                                ) {
+                                    let probe = warp_protocol::messages::PathProbe {
+                                        sequence: routing_state.start_path_probe(interface, peer_addr, now),
+                                        timestamp: std::time::SystemTime::now(),
+                                    };
+
+                                    if let Err(e) = seal_for_peer(probe, far_gate, peer_cipher, &nonces)
+                                        .map_err(anyhow::Error::from)
+                                        .and_then(|data| {
+                                            interface.queue_send(
+                                                data,
+                                                &peer_addr,
+                                                None,
+                                                interface::TrafficClass::default(),
+                                                None,
+                                            )
+                                        })
+                                    {
+                                        tracing::event!(
+                                            tracing::Level::WARN,
+                                            interface = %interface.id,
+                                            peer_addr = %peer_addr,
+                                            error = %e,
+                                            "PATH_PROBE_SEND_FAILED"
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                    }
+                })
+            }))
+            .unwrap();
+        futures.push(path_probe_task);
+
+        let liveness_task = tokio::task::Builder::new()
+            .name("peer liveness monitor")
+            .spawn(until_cancelled(stop_tasks.clone(), {
+                let routing_state = routing_state.clone();
+                let sessions = sessions.clone();
+                let config_watch = config_watch.clone();
+                let nonces = nonces.clone();
+                let relay = relay.clone();
+                let callback = self.peer_liveness_callback.clone();
+                let heartbeat = self.health.register("peer liveness monitor", TASK_STALL_LIMIT);
+
+                supervisor.clone().run("peer liveness monitor", async move || {
+                    let liveness = || config_watch.borrow().interfaces.liveness.clone().unwrap_or_default();
+                    let mut interval = tokio::time::interval(liveness().heartbeat_interval);
+
+                    loop {
+                        tick_every(&mut interval, liveness().heartbeat_interval).await;
+                        let _working = heartbeat.working();
+                        let liveness = liveness();
+                        let now = std::time::Instant::now();
+
+                        for change in routing_state.update_liveness(liveness.timeout, now) {
+                            match change {
+                                routing::LivenessChange::Address { peer, address, alive } => tracing::event!(
+                                    tracing::Level::INFO,
+                                    peer = warp_protocol::crypto::pubkey_to_string(&peer),
+                                    peer_addr = %address,
+                                    alive = alive,
+                                    "PEER_ADDRESS_LIVENESS_CHANGED"
+                                ),
+                                routing::LivenessChange::Peer { peer, alive } => {
+                                    if alive {
+                                        metrics::DEAD_PEERS.dec();
+                                        tracing::event!(
+                                            tracing::Level::INFO,
+                                            peer = warp_protocol::crypto::pubkey_to_string(&peer),
+                                            "PEER_UP"
+                                        );
+                                    } else {
+                                        metrics::DEAD_PEERS.inc();
+                                        tracing::event!(
+                                            tracing::Level::WARN,
+                                            peer = warp_protocol::crypto::pubkey_to_string(&peer),
+                                            "PEER_DOWN"
+                                        );
+                                    }
+                                    if let Some(callback) = &callback {
+                                        callback(peer, alive);
+                                    }
+                                }
+                            }
+                        }
+
+                        let relay_timeout = config_watch
+                            .borrow()
+                            .interfaces
+                            .relay
+                            .as_ref()
+                            .map(|relay| relay.timeout);
+                        for (peer, relayed) in routing_state.update_relays(&sessions.peers(), relay_timeout, now) {
+                            if relayed {
+                                metrics::RELAYED_PEERS.inc();
+                                tracing::event!(
+                                    tracing::Level::WARN,
+                                    peer = warp_protocol::crypto::pubkey_to_string(&peer),
+                                    "PEER_RELAY_STARTED"
+                                );
+                            } else {
+                                metrics::RELAYED_PEERS.dec();
+                                tracing::event!(
+                                    tracing::Level::INFO,
+                                    peer = warp_protocol::crypto::pubkey_to_string(&peer),
+                                    "PEER_RELAY_STOPPED"
+                                );
+                            }
+                        }
+
+                        // Cloned so the watch isn't borrowed while heartbeats are sent
+                        let interfaces = routing_state.interfaces().clone();
+                        for interface in interfaces.iter().filter(|interface| interface.is_alive()) {
+                            for (far_gate, peer_cipher) in sessions.ciphers() {
+                                for peer_addr in routing_state.resolve_peer_addresses(
+                                    &far_gate,
+                                    &interface.id,
+                                    interface.get_external_address(),
+                                ) {
+                                    let heartbeat = warp_protocol::messages::Heartbeat {
+                                        timestamp: std::time::SystemTime::now(),
+                                    };
+                                    if let Err(e) = seal_for_peer(heartbeat, &far_gate, &peer_cipher, &nonces)
+                                        .map_err(anyhow::Error::from)
+                                        .and_then(|data| {
+                                            interface.queue_send(
+                                                data,
+                                                &peer_addr,
+                                                None,
+                                                interface::TrafficClass::default(),
+                                                None,
+                                            )
+                                        })
+                                    {
+                                        tracing::event!(
+                                            tracing::Level::WARN,
+                                            interface = %interface.id,
+                                            peer_addr = %peer_addr,
+                                            error = %e,
+                                            "HEARTBEAT_SEND_FAILED"
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                        // Relayed far gates get one through warp-map as well, so they keep hearing from us
+                        for (far_gate, peer_cipher) in sessions.ciphers() {
+                            let heartbeat = warp_protocol::messages::Heartbeat {
+                                timestamp: std::time::SystemTime::now(),
+                            };
+                            if let Err(e) =
+                                send_via_relay(heartbeat, &far_gate, &peer_cipher, &nonces, &routing_state, &relay)
+                            {
+                                tracing::event!(
+                                    tracing::Level::WARN,
+                                    peer = warp_protocol::crypto::pubkey_to_string(&far_gate),
+                                    error = %e,
+                                    "HEARTBEAT_RELAY_FAILED"
+                                );
+                            }
+                        }
+                    }
+                })
+            }))
+            .unwrap();
+        futures.push(liveness_task);
+
+        let rekey_task = tokio::task::Builder::new()
+            .name("session rekeyer")
+            .spawn(until_cancelled(stop_tasks.clone(), {
+                let routing_state = routing_state.clone();
+                let sessions = sessions.clone();
+                let config_watch = config_watch.clone();
+                let nonces = nonces.clone();
+                let relay = relay.clone();
+                let heartbeat = self.health.register("session rekeyer", TASK_STALL_LIMIT);
+
+                supervisor.clone().run("session rekeyer", async move || {
+                    let mut interval = tokio::time::interval(session::REKEY_RETRY_INTERVAL);
+
+                    loop {
+                        interval.tick().await;
+                        let _working = heartbeat.working();
+                        let rekey = config_watch.borrow().rekey.clone().unwrap_or_default();
+
+                        // Cloned so the watch isn't borrowed while requests are sent
+                        let interfaces = routing_state.interfaces().clone();
+                        for (far_gate, request) in sessions.rekey_requests(&rekey, std::time::Instant::now()) {
+                            let Some(peer_cipher) = sessions.cipher(&far_gate) else {
+                                continue;
+                            };
+                            tracing::event!(
+                                tracing::Level::DEBUG,
+                                peer = warp_protocol::crypto::pubkey_to_string(&far_gate),
+                                epoch = request.epoch,
+                                "REKEY_REQUEST"
+                            );
+
+                            // Sent over every path, as any one of them might be down
+                            for interface in interfaces.iter().filter(|interface| interface.is_alive()) {
+                                for peer_addr in routing_state.resolve_peer_addresses(
+                                    &far_gate,
+                                    &interface.id,
+                                    interface.get_external_address(),
+                                ) {
+                                    if let Err(e) = seal_for_peer(request.clone(), &far_gate, &peer_cipher, &nonces)
+                                        .map_err(anyhow::Error::from)
+                                        .and_then(|data| {
+                                            interface.queue_send(
+                                                data,
+                                                &peer_addr,
+                                                None,
+                                                interface::TrafficClass::default(),
+                                                None,
+                                            )
+                                        })
+                                    {
+                                        tracing::event!(
+                                            tracing::Level::WARN,
+                                            interface = %interface.id,
+                                            peer_addr = %peer_addr,
+                                            error = %e,
+                                            "REKEY_REQUEST_SEND_FAILED"
+                                        );
+                                    }
+                                }
+                            }
+                            if let Err(e) =
+                                send_via_relay(request, &far_gate, &peer_cipher, &nonces, &routing_state, &relay)
+                            {
+                                tracing::event!(
+                                    tracing::Level::WARN,
+                                    peer = warp_protocol::crypto::pubkey_to_string(&far_gate),
+                                    error = %e,
+                                    "REKEY_REQUEST_RELAY_FAILED"
+                                );
+                            }
+                        }
+                    }
+                })
+            }))
+            .unwrap();
+        futures.push(rekey_task);
+
+        let tx_context = Arc::new(TxContext {
+            routing_state: routing_state.clone(),
+            sessions: sessions.clone(),
+            nonces: nonces.clone(),
+            relay: relay.clone(),
+            packer: std::sync::Mutex::new(Packer::default()),
+        });
+
+        let warp_accelerator_task = tokio::task::Builder::new()
+            .name("warp-accelerator")
+            .spawn({
+                let context = tx_context.clone();
+                let retransmissions = retransmissions.clone();
+                let heartbeat = self.health.register("warp-accelerator", TASK_STALL_LIMIT);
+
+                supervisor.clone().run("warp-accelerator", async move || {
+                    loop {
+                        let packing_due = context.packer.lock().unwrap().next_due();
+                        let outbound = tokio::select! {
+                            outbound = outbound_tunnel_payloads.recv() => outbound,
+                            () = tokio::time::sleep_until(
+                                packing_due.unwrap_or_else(std::time::Instant::now).into()
+                            ), if packing_due.is_some() => {
+                                let due = context.packer.lock().unwrap().take_due(std::time::Instant::now());
+                                queue_packed(due);
+                                continue;
+                            }
+                        };
+                        let Some(outbound) = outbound else {
+                            // Whatever is still being packed goes out as it is
+                            let rest = context.packer.lock().unwrap().take_all();
+                            queue_packed(rest);
+                            break;
+                        };
+                        let _working = heartbeat.working();
+                        metrics::TX_TUNNEL_PAYLOADS.inc();
+                        // Compressed once here, so retransmissions don't compress them again
+                        let tunnel_payloads = match outbound.compression {
+                            Some(compression) if context.sessions.compression(&outbound.far_gate) => outbound
+                                .tunnel_payloads
+                                .iter()
+                                .map(|tunnel_payload| {
+                                    compression::compress(tunnel_payload, compression)
+                                        .unwrap_or_else(|| tunnel_payload.clone())
+                                })
+                                .collect(),
+                            _ => outbound.tunnel_payloads,
+                        };
+                        let unacknowledged = outbound.reliable.then(|| reliable::Unacknowledged {
+                            far_gate: outbound.far_gate,
+                            tunnel_payloads: tunnel_payloads.clone(),
+                            routes: outbound.routes.clone(),
+                            send_deadline: outbound.deadline.saturating_duration_since(std::time::Instant::now()),
+                            traffic_class: outbound.traffic_class,
+                            trace: outbound.trace.clone(),
+                        });
+
+                        if !send_tunnel_payloads(
+                            &context,
+                            &outbound.far_gate,
+                            &tunnel_payloads,
+                            &outbound.routes,
+                            outbound.deadline,
+                            outbound.traffic_class,
+                            &outbound.trace,
+                        ) {
+                            let _ = outbound.completion_notifier.send(());
+                            continue;
+                        }
+                        if let Some(unacknowledged) = unacknowledged {
+                            retransmissions
+                                .lock()
+                                .unwrap()
+                                .sent(unacknowledged, std::time::Instant::now());
+                        }
+                        // The gate may have been closed while the payload was queued
+                        let _ = outbound.completion_notifier.send(());
+                    }
+                })
+            })
+            .unwrap();
+
+        futures.push(warp_accelerator_task);
+
+        let retransmission_task = tokio::task::Builder::new()
+            .name("tunnel retransmitter")
+            .spawn(until_cancelled(stop_tasks.clone(), {
+                let context = tx_context.clone();
+                let retransmissions = retransmissions.clone();
+                let heartbeat = self.health.register("tunnel retransmitter", TASK_STALL_LIMIT);
+
+                supervisor.clone().run("tunnel retransmitter", async move || {
+                    let mut interval = tokio::time::interval(reliable::RETRANSMISSION_INTERVAL);
+
+                    loop {
+                        interval.tick().await;
+                        let _working = heartbeat.working();
+                        let now = std::time::Instant::now();
+                        let due = retransmissions.lock().unwrap().due(now);
+
+                        for payload in due.given_up {
+                            metrics::TX_UNACKNOWLEDGED_PAYLOADS.inc();
+                            tracing::event!(
+                                tracing::Level::WARN,
+                                peer = warp_protocol::crypto::pubkey_to_string(&payload.far_gate),
+                                correlation_id = %payload.trace.correlation,
+                                span_id = payload.trace.span_id,
+                                "TUNNEL_PAYLOAD_UNACKNOWLEDGED"
+                            );
+                        }
+                        for payload in due.retransmit {
+                            metrics::TX_RETRANSMISSIONS.inc();
+                            // Each retransmission gets its own span so it can be told apart from the first send
+                            let trace = payload.trace.child();
+                            tracing::event!(
+                                tracing::Level::DEBUG,
+                                correlation_id = %trace.correlation,
+                                span_id = trace.span_id,
+                                parent_span_id = trace.parent_span_id,
+                                "TUNNEL_PAYLOAD_RETRANSMIT"
+                            );
+                            send_tunnel_payloads(
+                                &context,
+                                &payload.far_gate,
+                                &payload.tunnel_payloads,
+                                &payload.routes,
+                                now + payload.send_deadline,
+                                // Not packed: only the accelerator sends packed datagrams on when they are due
+                                interface::TrafficClass {
+                                    packing: None,
+                                    ..payload.traffic_class
+                                },
+                                &trace,
+                            );
+                        }
+                    }
+                })
+            }))
+            .unwrap();
+        futures.push(retransmission_task);
+
+        let acknowledgement_task = tokio::task::Builder::new()
+            .name("tunnel acknowledger")
+            .spawn(until_cancelled(stop_tasks.clone(), {
+                let routing_state = routing_state.clone();
+                let sessions = sessions.clone();
+                let nonces = nonces.clone();
+                let relay = relay.clone();
+                let heartbeat = self.health.register("tunnel acknowledger", TASK_STALL_LIMIT);
+
+                supervisor.clone().run("tunnel acknowledger", async move || {
+                    while let Some(acknowledgement) = acknowledgements.recv().await {
+                        let _working = heartbeat.working();
+                        // Everything received since the last acknowledgements went out is acknowledged together
+                        let mut pending: TunnelAcknowledgements = BTreeMap::new();
+                        let mut next = Some(acknowledgement);
+                        while let Some(acknowledgement) = next {
+                            pending
+                                .entry(acknowledgement.far_gate)
+                                .or_default()
+                                .entry(acknowledgement.tunnel_id)
+                                .or_default()
+                                .push(acknowledgement.tracer);
+                            next = acknowledgements.try_recv().ok();
+                        }
+
+                        for (far_gate, tunnels) in pending {
+                            let Some(peer_cipher) = sessions.cipher(&far_gate) else {
+                                continue;
+                            };
+                            // Sent over every path, as losing it costs a retransmission
+                            let (routes, via_relay) = routes_to_peer(
+                                &routing_state,
+                                &relay,
+                                &far_gate,
+                                &routing::RoutePolicy::any(warp_config::PathSelection::All),
+                            );
+                            for (tunnel_id, tracers) in tunnels {
+                                for tracers in tracers.chunks(reliable::MAX_TRACERS_PER_ACK) {
+                                    let ack = warp_protocol::messages::TunnelAck {
+                                        tunnel_id: tunnel_id.clone(),
+                                        tracers: tracers.to_vec(),
+                                    };
+                                    for (interface, peer_addr) in &routes {
+                                        if let Err(e) =
+                                            seal_for_route(ack.clone(), &far_gate, &peer_cipher, &nonces, via_relay)
+                                                .map_err(anyhow::Error::from)
+                                                .and_then(|data| {
+                                                    interface.queue_send(
+                                                        data,
+                                                        peer_addr,
+                                                        None,
+                                                        interface::TrafficClass::default(),
+                                                        None,
+                                                    )
+                                                })
+                                        {
+                                            tracing::event!(
+                                                tracing::Level::WARN,
+                                                interface = %interface.id,
+                                                peer_addr = %peer_addr,
+                                                error = %e,
+                                                "TUNNEL_ACK_SEND_FAILED"
+                                            );
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                })
+            }))
+            .unwrap();
+        futures.push(acknowledgement_task);
+
+        let (tunnel_syncs_tx, mut tunnel_syncs) =
+            tokio::sync::mpsc::unbounded_channel::<(warp_protocol::PublicKey, warp_protocol::messages::TunnelSync)>();
+
+        let rx_context = Arc::new(RxContext {
+            warp_config: self.warp_config.clone(),
+            warp_map_cipher: warp_map_cipher.clone(),
+            routing_state: routing_state.clone(),
+            sessions: sessions.clone(),
+            tunnel_gates: tunnel_gates.clone(),
+            nonces: nonces.clone(),
+            retransmissions: retransmissions.clone(),
+            relay: relay.clone(),
+            tunnel_syncs: tunnel_syncs_tx,
+        });
+        // Each worker takes the datagrams from its share of the source addresses, so a peer's messages are still
+        // processed in the order they came
+        for (worker, mut rx) in rx_workers.into_iter().enumerate() {
+            let rx_processing_task = tokio::task::Builder::new()
+                .name(&format!("rx worker {worker}"))
+                .spawn(until_cancelled(stop_tasks.clone(), {
+                    let context = rx_context.clone();
+                    let heartbeat = self.health.register(format!("rx worker {worker}"), TASK_STALL_LIMIT);
+                    supervisor.clone().run(format!("rx worker {worker}"), async move || {
+                        while let Some(payload) = rx.recv().await {
+                            let _working = heartbeat.working();
+                            process_rx_payload(&context, &payload, rx.len()).await;
+                        }
+                    })
+                }))
+                .unwrap();
+            futures.push(rx_processing_task);
+        }
+
+        let health_monitor_task = tokio::task::Builder::new()
+            .name("health monitor")
+            .spawn(until_cancelled(stop_tasks.clone(), {
+                let health = self.health.clone();
+
+                supervisor.clone().run("health monitor", async move || {
+                    let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+                    let mut stalled = std::collections::BTreeSet::new();
+
+                    loop {
+                        interval.tick().await;
+                        let report = health.report();
+                        metrics::STALLED_TASKS.set(report.stalled.len() as i64);
+
+                        let now_stalled: std::collections::BTreeSet<_> =
+                            report.stalled.iter().map(|task| task.name.clone()).collect();
+                        for task in report.stalled.iter().filter(|task| !stalled.contains(&task.name)) {
+                            tracing::event!(
+                                tracing::Level::WARN,
+                                task = %task.name,
+                                busy_ms = task.busy_ms,
+                                "TASK_STALLED"
+                            );
+                        }
+                        for task in stalled.difference(&now_stalled) {
+                            tracing::event!(tracing::Level::INFO, task = %task, "TASK_RECOVERED");
+                        }
+                        stalled = now_stalled;
+                    }
+                })
+            }))
+            .unwrap();
+        futures.push(health_monitor_task);
+
+        // The tasks would otherwise outlive this future if it is dropped, e.g. by an application embedding warp
+        let _abort_tasks = AbortOnDrop(futures.iter().map(|task| task.abort_handle()).collect());
+
+        // Wait for either tasks to complete or shutdown signal
+        use futures::StreamExt;
+
+        let mut tunnel_sync_interval = tokio::time::interval(control::TUNNEL_SYNC_INTERVAL);
+        loop {
+            tokio::select! {
+                Some(ended) = futures.next() => {
+                    // Only once what the task takes its work from has gone, which shouldn't happen before shutdown
+                    tracing::event!(
+                        tracing::Level::ERROR,
+                        error = ended.err().map(|e| e.to_string()),
+                        "TASK_ENDED"
+                    );
+                }
+                Some(warp_config) = self.reloads.recv() => {
+                    self.reload(warp_config, &mut tunnels, &config_tx, &sessions, channel_far_gate).await;
+                }
+                Some((request, response)) = self.control_requests.recv() => {
+                    let changed = !matches!(request, control::Request::List {} | control::Request::Health {});
+                    let _ = response.send(self.control(request, &mut tunnels, &config_tx, &sessions, channel_far_gate).await);
+                    if changed && self.warp_config.control.is_some() {
+                        send_tunnel_syncs(&tx_context, &tunnels, &config_tx.borrow());
+                    }
+                }
+                Some((far_gate, sync)) = tunnel_syncs.recv() => {
+                    if !tunnels.dynamic.synced(far_gate, sync) {
+                        continue;
+                    }
+                    if !control::accepts_far_gate_tunnels(&self.warp_config) {
+                        tracing::event!(
+                            tracing::Level::WARN,
+                            peer = warp_protocol::crypto::pubkey_to_string(&far_gate),
+                            "TUNNEL_SYNC_REFUSED"
+                        );
+                        continue;
+                    }
+                    tracing::event!(
+                        tracing::Level::INFO,
+                        peer = warp_protocol::crypto::pubkey_to_string(&far_gate),
+                        "TUNNEL_SYNC_APPLIED"
+                    );
+                    if let Err(e) = self.apply_tunnels(&mut tunnels, &config_tx, &sessions, channel_far_gate).await {
+                        tracing::event!(tracing::Level::WARN, error = %e, "TUNNEL_SYNC_INCOMPLETE");
+                    }
+                }
+                _ = tunnel_sync_interval.tick() => {
+                    if self.warp_config.control.is_some() {
+                        send_tunnel_syncs(&tx_context, &tunnels, &config_tx.borrow());
+                    }
+                }
+                _ = &mut self.shutdown => {
+                    tracing::info!("Graceful shutdown initiated");
+                    break;
+                }
+            }
+        }
+
+        let drain_timeout = config_watch.borrow().shutdown.clone().unwrap_or_default().drain_timeout;
+        let drain_deadline = tokio::time::Instant::now() + drain_timeout;
+
+        // Cloned so the watch isn't borrowed while the interfaces drain
+        let interfaces = routing_state.interfaces().clone();
+        for interface in interfaces.iter() {
+            let deregister_request = warp_protocol::messages::DeregisterRequest {
+                pubkey: self.warp_config.private_key.public_key(),
+                timestamp: std::time::SystemTime::now(),
+            };
+
+            if let Ok(data) = deregister_request
+                .encode()
+                .and_then(|encoded| encoded.encrypt(&warp_map_cipher))
+                .and_then(|encrypted| encrypted.to_bytes())
+            {
+                if let Err(e) = interface.queue_send(
+                    data,
+                    &self.warp_config.warp_map.address,
+                    None,
+                    interface::TrafficClass::default(),
+                    None,
+                ) {
+                    tracing::warn!(
+                        interface = %interface.id,
+                        error = %e,
+                        "INTERFACE_DEREGISTRATION_FAILED"
+                    );
+                } else {
+                    tracing::info!(
+                        interface = %interface.id,
+                        "INTERFACE_DEREGISTRATION_SENT"
+                    );
+                }
+            }
+        }
+
+        // Gates stop taking data from their applications and pass on what they already took. Without the gates and
+        // their channel, the accelerator stops once it has queued everything they sent.
+        if tokio::time::timeout_at(drain_deadline, tunnels.close()).await.is_err() {
+            tracing::warn!(
+                "Gates didn't close within {:?}; dropping the payloads they were sending",
+                drain_timeout
+            );
+        }
+        drop(tunnels);
+        stop_tasks.cancel();
+        let tasks_stopped = async { while futures.next().await.is_some() {} };
+        if tokio::time::timeout_at(drain_deadline, tasks_stopped).await.is_err() {
+            tracing::warn!(
+                "Tasks didn't stop within {:?}; dropping the payloads they were sending",
+                drain_timeout
+            );
+        }
+
+        // Then the interfaces send what is left in their queues
+        let remaining = drain_deadline.saturating_duration_since(tokio::time::Instant::now());
+        futures::future::join_all(interfaces.iter().map(|interface| interface.shutdown(remaining))).await;
+        if let Some(path) = &self.warp_config.state_file
+            && let Err(e) = state::State::capture(&routing_state).save(path).await
+        {
+            log_state_save_failure(path, &e);
+        }
+        tracing::info!("Graceful shutdown complete");
+    }
+
+    async fn reload(
+        &mut self,
+        mut warp_config: warp_config::WarpConfig,
+        tunnels: &mut Tunnels,
+        config_tx: &tokio::sync::watch::Sender<warp_config::WarpConfig>,
+        sessions: &session::Sessions,
+        channel_far_gate: warp_protocol::PublicKey,
+    ) {
+        // These are only read when warp starts
+        let mut ignored = Vec::new();
+        if warp_config.private_key.public_key() != self.warp_config.private_key.public_key() {
+            ignored.push("private_key");
+            warp_config.private_key = self.warp_config.private_key.clone();
+        }
+        if warp_config.warp_map != self.warp_config.warp_map {
+            ignored.push("warp_map");
+            warp_config.warp_map = self.warp_config.warp_map.clone();
+        }
+        if warp_config.metrics != self.warp_config.metrics {
+            ignored.push("metrics");
+            warp_config.metrics = self.warp_config.metrics.clone();
+        }
+        if warp_config.state_file != self.warp_config.state_file {
+            ignored.push("state_file");
+            warp_config.state_file = self.warp_config.state_file.clone();
+        }
+        if warp_config.daemon != self.warp_config.daemon {
+            ignored.push("daemon");
+            warp_config.daemon = self.warp_config.daemon.clone();
+        }
+        if warp_config.telemetry != self.warp_config.telemetry {
+            ignored.push("telemetry");
+            warp_config.telemetry = self.warp_config.telemetry.clone();
+        }
+        let control_socket =
+            |config: &warp_config::WarpConfig| config.control.clone().and_then(|control| control.socket);
+        if control_socket(&warp_config) != control_socket(&self.warp_config) {
+            ignored.push("control.socket");
+            warp_config.control.get_or_insert_default().socket = control_socket(&self.warp_config);
+        }
+        if !ignored.is_empty() {
+            tracing::warn!("Ignoring changes to {} until warp restarts", ignored.join(", "));
+        }
+
+        sessions.set_cipher_suites(warp_config.cipher_suites());
+        self.warp_config = warp_config;
+        let applied = self.apply_tunnels(tunnels, config_tx, sessions, channel_far_gate).await;
+
+        match applied {
+            Ok(()) => tracing::event!(
+                tracing::Level::INFO,
+                tunnels = self.warp_config.tunnels.len(),
+                far_gates = self.warp_config.far_gates().len(),
+                "CONFIG_RELOADED"
+            ),
+            Err(e) => tracing::event!(tracing::Level::WARN, error = %e, "CONFIG_RELOAD_INCOMPLETE"),
+        }
+    }
+
+    /// Open and close tunnels to match the config with the tunnels opened while running added to it, and have the tasks
+    /// follow that config
+    async fn apply_tunnels(
+        &mut self,
+        tunnels: &mut Tunnels,
+        config_tx: &tokio::sync::watch::Sender<warp_config::WarpConfig>,
+        sessions: &session::Sessions,
+        channel_far_gate: warp_protocol::PublicKey,
+    ) -> anyhow::Result<()> {
+        let warp_config = tunnels.dynamic.apply_to(&self.warp_config);
+        // New tunnels need a session with their far gate before they can send
+        sessions.set_peers(&peers(&warp_config, channel_far_gate), std::time::Instant::now());
+        let applied = tunnels.apply(&warp_config).await;
+        config_tx.send_replace(warp_config);
+        applied
+    }
+
+    async fn control(
+        &mut self,
+        request: control::Request,
+        tunnels: &mut Tunnels,
+        config_tx: &tokio::sync::watch::Sender<warp_config::WarpConfig>,
+        sessions: &session::Sessions,
+        channel_far_gate: warp_protocol::PublicKey,
+    ) -> control::Response {
+        let health = matches!(request, control::Request::Health {}).then(|| self.health.report());
+        let handled = match request {
+            control::Request::Open { name, tunnel, far_end } => {
+                match tunnels.dynamic.open(&self.warp_config, name.clone(), *tunnel, far_end) {
+                    Ok(()) => {
+                        let applied = self.apply_tunnels(tunnels, config_tx, sessions, channel_far_gate).await;
+                        if tunnels.configured.contains_key(&name) {
+                            Ok(())
+                        } else {
+                            // Not left to be retried on the next reload, since whoever asked is told it failed
+                            tunnels.dynamic.close(&name).expect("opened above");
+                            let _ = self.apply_tunnels(tunnels, config_tx, sessions, channel_far_gate).await;
+                            Err(applied
+                                .err()
+                                .unwrap_or_else(|| anyhow::anyhow!("failed to open tunnel {name}")))
+                        }
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            control::Request::Close { name } => match tunnels.dynamic.close(&name) {
+                Ok(()) => {
+                    // Any other tunnel that fails to open is no concern of this request's
+                    let _ = self.apply_tunnels(tunnels, config_tx, sessions, channel_far_gate).await;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            control::Request::List {} | control::Request::Health {} => Ok(()),
+        };
+        if let Err(e) = &handled {
+            tracing::event!(tracing::Level::WARN, error = %e, "CONTROL_REQUEST_FAILED");
+        }
+        control::Response {
+            error: handled.err().map(|e| format!("{e:#}")),
+            tunnels: tunnels.dynamic.status(&config_tx.borrow()),
+            health,
+        }
+    }
+}
+
+// A tunnel from the config, with what its gate was opened with
+struct ConfiguredTunnel {
+    config: warp_config::WarpTunnelConfig,
+    far_gate: warp_protocol::PublicKey,
+    tunnel_id: warp_protocol::messages::TunnelId,
+    gate: Arc<tunnel::Gate>,
+}
+
+// The open gates, published to the rx workers, and to the sessions for their tunnel keys, whenever they change
+struct Tunnels {
+    configured: BTreeMap<String, ConfiguredTunnel>,
+    channel_gates: Vec<(
+        warp_protocol::PublicKey,
+        warp_protocol::messages::TunnelId,
+        Arc<tunnel::Gate>,
+    )>,
+    channels: tunnel::GateChannels,
+    gates_tx: tokio::sync::watch::Sender<TunnelGates>,
+    sessions: Arc<session::Sessions>,
+    // Opened while running, and applied to the config before it is
+    dynamic: control::DynamicTunnels,
+}
+
+impl Tunnels {
+    /// Close the tunnels that are no longer in the config or whose config changed, then open the ones that are missing
+    ///
+    /// Tunnels that fail to open are left closed (and retried on the next apply); the first failure is returned.
+    async fn apply(&mut self, warp_config: &warp_config::WarpConfig) -> anyhow::Result<()> {
+        self.configured.retain(|name, tunnel| {
+            let unchanged = warp_config.tunnels.get(name).is_some_and(|tunnel_config| {
+                *tunnel_config == tunnel.config && warp_config.far_gate_of(tunnel_config) == tunnel.far_gate
+            });
+            if !unchanged {
+                tracing::info!("Closing tunnel {}", name);
+            }
+            unchanged
+        });
+        self.publish();
+
+        let mut applied = Ok(());
+        for (name, tunnel_config) in &warp_config.tunnels {
+            if self.configured.contains_key(name) {
+                continue;
+            }
+            match self
+                .open(name, tunnel_config, warp_config.far_gate_of(tunnel_config))
+                .await
+            {
+                Ok(tunnel) => {
+                    tracing::info!("Opened tunnel {}", name);
+                    self.configured.insert(name.clone(), tunnel);
+                }
+                Err(e) => applied = applied.and(Err(e.context(format!("failed to open tunnel {name}")))),
+            }
+        }
+        self.publish();
+        applied
+    }
+
+    async fn open(
+        &self,
+        name: &str,
+        tunnel_config: &warp_config::WarpTunnelConfig,
+        far_gate: warp_protocol::PublicKey,
+    ) -> anyhow::Result<ConfiguredTunnel> {
+        let tunnel_id = tunnel_id(name, tunnel_config.tunnel_id);
+        if self
+            .channel_gates
+            .iter()
+            .any(|(channel_far_gate, channel_tunnel_id, _)| {
+                *channel_far_gate == far_gate && *channel_tunnel_id == tunnel_id
+            })
+        {
+            anyhow::bail!("an in-process tunnel already uses {:?}", tunnel_id);
+        }
+
+        let mut attempt = 1;
+        let gate = loop {
+            match tunnel::Gate::new(
+                name,
+                tunnel_id.clone(),
+                far_gate,
+                tunnel_config.gate.clone(),
+                &tunnel_config.transport,
+                tunnel_config.priority.unwrap_or_default(),
+                self.channels.clone(),
+            ) {
+                Ok(gate) => break gate,
+                Err(_) if attempt < GATE_OPEN_ATTEMPTS => {
+                    attempt += 1;
+                    tokio::time::sleep(GATE_OPEN_RETRY_DELAY).await;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        Ok(ConfiguredTunnel {
+            config: tunnel_config.clone(),
+            far_gate,
+            tunnel_id,
+            gate,
+        })
+    }
+
+    /// Have every gate stop taking data from its application, returning once they have passed on what they took
+    async fn close(&self) {
+        let gates = self
+            .configured
+            .values()
+            .map(|tunnel| &tunnel.gate)
+            .chain(self.channel_gates.iter().map(|(_, _, gate)| gate));
+        futures::future::join_all(gates.map(|gate| gate.close())).await;
+    }
+
+    fn publish(&self) {
+        let mut gates = TunnelGates::new();
+        let configured = self
+            .configured
+            .values()
+            .map(|tunnel| (tunnel.far_gate, tunnel.tunnel_id.clone(), tunnel.gate.clone()));
+        for (far_gate, tunnel_id, gate) in configured.chain(self.channel_gates.iter().cloned()) {
+            gates.entry(far_gate).or_default().insert(tunnel_id, gate);
+        }
+        self.sessions.set_tunnels(
+            &gates
+                .iter()
+                .map(|(far_gate, gates)| (*far_gate, gates.keys().cloned().collect()))
+                .collect(),
+        );
+        self.gates_tx.send_replace(gates);
+    }
+}
+
+fn tunnel_id(name: &str, tunnel_id: Option<u64>) -> warp_protocol::messages::TunnelId {
+    match tunnel_id {
+        Some(id) => warp_protocol::messages::TunnelId::Id(id),
+        None => warp_protocol::messages::TunnelId::Name(name.to_owned()),
+    }
+}
+
+// The far gates in the config, and the one in-process tunnels use
+fn peers(
+    warp_config: &warp_config::WarpConfig,
+    channel_far_gate: warp_protocol::PublicKey,
+) -> Vec<warp_protocol::PublicKey> {
+    let mut far_gates = warp_config.far_gates();
+    if !far_gates.contains(&channel_far_gate) {
+        far_gates.push(channel_far_gate);
+    }
+    far_gates
+}
+
+fn log_state_save_failure(path: &std::path::Path, error: &anyhow::Error) {
+    tracing::event!(
+        tracing::Level::WARN,
+        path = %path.display(),
+        error = %error,
+        "STATE_SAVE_FAILED"
+    );
+}
+
+/// Run `task` until it finishes or `token` is cancelled
+pub(crate) async fn until_cancelled(token: tokio_util::sync::CancellationToken, task: impl Future<Output = ()>) {
+    token.run_until_cancelled_owned(task).await;
+}
+
+/// The sequence of the PeerAddressOverrides sent at `now`: microseconds since the Unix epoch, so that it keeps growing
+/// across restarts as long as the clock isn't set back
+fn override_sequence(now: std::time::SystemTime) -> u64 {
+    now.duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_micros() as u64)
+}
+
+/// Wait for `changes` to say interfaces changed, forever if there is nothing to watch; a watch that ends is dropped
+async fn interface_change(changes: &mut Option<tokio::sync::watch::Receiver<()>>) {
+    loop {
+        match changes {
+            None => std::future::pending().await,
+            Some(receiver) => {
+                if receiver.changed().await.is_ok() {
+                    return;
+                }
+                *changes = None;
+            }
+        }
+    }
+}
+
+/// Wait for the next tick of `interval`, first restarting it if its period is no longer `period`
+pub(crate) async fn tick_every(interval: &mut tokio::time::Interval, period: std::time::Duration) {
+    if interval.period() != period {
+        *interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    }
+    interval.tick().await;
+}
+
+// Log what each interface has sent and received so far, in all and by tunnel
+fn log_traffic(routing_state: &routing::RoutingState) {
+    for (interface, report) in routing_state.traffic() {
+        tracing::event!(
+            tracing::Level::INFO,
+            interface = interface,
+            bytes_sent = report.total.sent.bytes,
+            packets_sent = report.total.sent.packets,
+            bytes_received = report.total.received.bytes,
+            packets_received = report.total.received.packets,
+            "INTERFACE_TRAFFIC"
+        );
+        for (tunnel, stats) in report.tunnels {
+            tracing::event!(
+                tracing::Level::INFO,
+                interface = interface,
+                tunnel = %tunnel,
+                bytes_sent = stats.sent.bytes,
+                packets_sent = stats.sent.packets,
+                bytes_received = stats.received.bytes,
+                packets_received = stats.received.packets,
+                "INTERFACE_TUNNEL_TRAFFIC"
+            );
+        }
+    }
+}
+
+/// Encode and encrypt a message for `far_gate`, numbering it from `nonces` so the far gate can reject replays of it
+fn seal_for_peer<M: Message>(
+    message: M,
+    far_gate: &warp_protocol::PublicKey,
+    cipher: &warp_protocol::Cipher,
+    nonces: &warp_protocol::replay::NonceSequence,
+) -> Result<Vec<u8>, warp_protocol::EncodeError> {
+    seal_encoded(message.encode()?, far_gate, cipher, nonces, None)
+}
+
+// Number and encrypt a message that has already been encoded, as `seal_for_peer` does, padded to a multiple of
+// `pad_to` bytes if that is given
+fn seal_encoded(
+    mut encoded: warp_protocol::codec::UnencryptedWireMessage,
+    far_gate: &warp_protocol::PublicKey,
+    cipher: &warp_protocol::Cipher,
+    nonces: &warp_protocol::replay::NonceSequence,
+    pad_to: Option<std::num::NonZeroU16>,
+) -> Result<Vec<u8>, warp_protocol::EncodeError> {
+    encoded.nonce = nonces.next_nonce(far_gate);
+    match pad_to {
+        Some(bucket) => encoded.encrypt_padded(cipher, bucket)?.to_bytes(),
+        None => encoded.encrypt(cipher)?.to_bytes(),
+    }
+}
+
+/// Seal a message for a far gate like [`seal_for_peer`], then wrap it for warp-map to pass on if it goes through `relay`;
+/// the result can be queued on any number of paths without being copied
+fn seal_for_route<M: Message>(
+    message: M,
+    far_gate: &warp_protocol::PublicKey,
+    cipher: &warp_protocol::Cipher,
+    nonces: &warp_protocol::replay::NonceSequence,
+    relay: Option<&relay::Relay>,
+) -> Result<bytes::Bytes, warp_protocol::EncodeError> {
+    wrap_for_route(seal_for_peer(message, far_gate, cipher, nonces)?, far_gate, relay)
+}
+
+// Wrap a sealed message for warp-map to pass on to `far_gate` if it goes through `relay`
+fn wrap_for_route(
+    data: Vec<u8>,
+    far_gate: &warp_protocol::PublicKey,
+    relay: Option<&relay::Relay>,
+) -> Result<bytes::Bytes, warp_protocol::EncodeError> {
+    match relay {
+        Some(relay) => relay.wrap(far_gate, data),
+        None => Ok(data),
+    }
+    .map(bytes::Bytes::from)
+}
+
+/// Seal each of `tunnel_payloads` once, as `sealing` says and padded to a multiple of `pad_to` bytes, for it to be
+/// queued on any number of paths; the tunnel payloads are only borrowed, so that the ones kept for retransmission
+/// aren't copied first
+fn seal_tunnel_payloads(
+    tunnel_payloads: &[warp_protocol::messages::TunnelPayload],
+    sealing: &session::TunnelSealing,
+    far_gate: &warp_protocol::PublicKey,
+    nonces: &warp_protocol::replay::NonceSequence,
+    relay: Option<&relay::Relay>,
+    pad_to: Option<std::num::NonZeroU16>,
+) -> Result<Vec<bytes::Bytes>, warp_protocol::EncodeError> {
+    tunnel_payloads
+        .iter()
+        .map(|tunnel_payload| {
+            let (encoded, cipher) = sealing.encode(tunnel_payload)?;
+            wrap_for_route(
+                seal_encoded(encoded, far_gate, cipher, nonces, pad_to)?,
+                far_gate,
+                relay,
+            )
+        })
+        .collect()
+}
+
+/// The paths `policy` picks for messages to `far_gate`; if it is relayed to they lead to warp-map, and come with the
+/// relay to wrap the messages for it with
+fn routes_to_peer<'a>(
+    routing_state: &routing::RoutingState,
+    relay: &'a relay::Relay,
+    far_gate: &warp_protocol::PublicKey,
+    policy: &routing::RoutePolicy,
+) -> (Routes, Option<&'a relay::Relay>) {
+    if routing_state.is_relayed(far_gate) {
+        (routing_state.select_relay_routes(relay.address(), policy), Some(relay))
+    } else {
+        (routing_state.select_routes(far_gate, policy), None)
+    }
+}
+
+/// Also send `message` to `far_gate` through warp-map if it is relayed to, for messages that go over every direct path
+/// whether or not it works
+fn send_via_relay<M: Message>(
+    message: M,
+    far_gate: &warp_protocol::PublicKey,
+    cipher: &warp_protocol::Cipher,
+    nonces: &warp_protocol::replay::NonceSequence,
+    routing_state: &routing::RoutingState,
+    relay: &relay::Relay,
+) -> anyhow::Result<()> {
+    let (routes, Some(relay)) = routes_to_peer(
+        routing_state,
+        relay,
+        far_gate,
+        &routing::RoutePolicy::any(warp_config::PathSelection::Best),
+    ) else {
+        return Ok(());
+    };
+    // The best path is the only one, if any interface is registered with warp-map
+    let Some((interface, relay_address)) = routes.into_iter().next() else {
+        return Ok(());
+    };
+    interface.queue_send(
+        seal_for_route(message, far_gate, cipher, nonces, Some(relay))?,
+        &relay_address,
+        None,
+        interface::TrafficClass::default(),
+        None,
+    )
+}
+
+// What the warp accelerator and the retransmitter need to send tunnel payloads
+struct TxContext {
+    routing_state: Arc<routing::RoutingState>,
+    sessions: Arc<session::Sessions>,
+    nonces: Arc<warp_protocol::replay::NonceSequence>,
+    relay: Arc<relay::Relay>,
+    // Only the accelerator packs tunnel payloads
+    packer: std::sync::Mutex<Packer>,
+}
+
+/// Tell each far gate which of the tunnels opened while running it should open its end of; `warp_config` is the config
+/// those tunnels are applied to
+fn send_tunnel_syncs(context: &TxContext, tunnels: &Tunnels, warp_config: &warp_config::WarpConfig) {
+    let TxContext {
+        routing_state,
+        sessions,
+        nonces,
+        relay,
+        ..
+    } = context;
+    for (far_gate, sync) in tunnels.dynamic.syncs(warp_config, &sessions.peers()) {
+        let Some(cipher) = sessions.cipher(&far_gate) else {
+            continue;
+        };
+        let (routes, via_relay) = routes_to_peer(
+            routing_state,
+            relay,
+            &far_gate,
+            &routing::RoutePolicy::any(warp_config::PathSelection::All),
+        );
+        for (interface, peer_addr) in routes {
+            if let Err(e) = seal_for_route(sync.clone(), &far_gate, &cipher, nonces, via_relay)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| interface.queue_send(data, &peer_addr, None, interface::TrafficClass::default(), None))
+            {
+                tracing::event!(
+                    tracing::Level::WARN,
+                    interface = %interface.id,
+                    peer_addr = %peer_addr,
+                    error = %e,
+                    "TUNNEL_SYNC_SEND_FAILED"
+                );
+            }
+        }
+    }
+}
+
+/// Seal the tunnel payloads of one application payload for `far_gate` and queue them on the paths `routes` picks; returns false if there is no session with the far gate
+fn send_tunnel_payloads(
+    context: &TxContext,
+    far_gate: &warp_protocol::PublicKey,
+    tunnel_payloads: &[warp_protocol::messages::TunnelPayload],
+    routes: &routing::RoutePolicy,
+    deadline: std::time::Instant,
+    traffic_class: interface::TrafficClass,
+    trace: &TraceContext,
+) -> bool {
+    let TxContext {
+        routing_state,
+        sessions,
+        nonces,
+        relay,
+        packer,
+    } = context;
+    // The tunnel payloads of one application payload all belong to the same tunnel
+    let Some(tunnel_id) = tunnel_payloads
+        .first()
+        .map(|tunnel_payload| tunnel_payload.tunnel_id.clone())
+    else {
+        return true;
+    };
+    let Some(sealing) = sessions.tunnel_sealing(far_gate, &tunnel_id) else {
+        // The far gate was removed from the config while the payload was queued
+        tracing::event!(
+            tracing::Level::WARN,
+            correlation_id = %trace.correlation,
+            span_id = trace.span_id,
+            "TUNNEL_PAYLOAD_FAR_GATE_REMOVED"
+        );
+        return false;
+    };
+
+    let (routes, via_relay) = routes_to_peer(routing_state, relay, far_gate, routes);
+
+    let encrypt = trace.child();
+    // TODO: Error handle this better
+    let sealed = seal_tunnel_payloads(
+        tunnel_payloads,
+        &sealing,
+        far_gate,
+        nonces,
+        via_relay,
+        traffic_class.pad_to,
+    )
+    .unwrap();
+    encrypt.export("encrypt");
+
+    let spread_across_routes = tunnel_payloads.iter().all(crate::fec::is_redundant_shard);
+    for (index, (tunnel_payload, data)) in tunnel_payloads.iter().zip(sealed).enumerate() {
+        let tracer = tunnel_payload.tracer;
+
+        // Whole payloads and plain fragments are sent over every route, but FEC shards are spread across the routes
+        // since the erasure code already provides the redundancy. The far gate keeps whichever copy of a payload
+        // arrives first and drops the rest as replays.
+        let payload_routes = match routes.len() {
+            num_routes if spread_across_routes && num_routes > 0 => std::slice::from_ref(&routes[index % num_routes]),
+            _ => &routes[..],
+        };
+        sessions.record_bytes(far_gate, data.len() * payload_routes.len());
+
+        for (interface, resolved_address) in payload_routes {
+            let tx_payload = interface::TxPayload {
+                to: *resolved_address,
+                deadline: Some(deadline),
+                class: traffic_class,
+                // Each copy gets its own span so its send can be told apart from the others
+                trace: Some(trace.child()),
+                data: data.clone(),
+            };
+            let queued = match traffic_class.packing {
+                // Held for the tunnel's next payloads to the same address to share its datagram
+                Some(packing) => {
+                    let key = (tunnel_id.clone(), interface.id.clone(), *resolved_address);
+                    let ready = packer.lock().unwrap().push(
+                        key,
+                        interface.clone(),
+                        tx_payload,
+                        packing,
+                        std::time::Instant::now(),
+                    );
+                    queue_packed(ready);
+                    Ok(())
+                }
+                None => interface.queue(tx_payload).inspect(|()| metrics::TX_SENDS_QUEUED.inc()),
+            };
+            match queued {
+                Ok(()) => {
+                    tracing::event!(
+                        tracing::Level::DEBUG,
+                        tracer = tracer,
+                        correlation_id = %trace.correlation,
+                        span_id = trace.span_id,
+                        parent_span_id = trace.parent_span_id,
+                        interface = %interface.id,
+                        resolved_addr = %resolved_address,
+                        packed = traffic_class.packing.is_some(),
+                        "TUNNEL_PAYLOAD_SEND_QUEUED"
+                    );
+                }
+                Err(e) => {
+                    metrics::TX_SEND_QUEUE_ERRORS.inc();
+                    tracing::event!(
+                        tracing::Level::WARN,
+                        tracer = tracer,
+                        correlation_id = %trace.correlation,
+                        span_id = trace.span_id,
+                        parent_span_id = trace.parent_span_id,
+                        interface = %interface.id,
+                        resolved_addr = %resolved_address,
+                        error = %e,
+                        "TUNNEL_PAYLOAD_SEND_QUEUE_ERROR"
+                    );
+                }
+            }
+        }
+    }
+    trace.export("accelerate");
+    true
+}
+
+/// Queue the datagrams the accelerator's packer has finished packing on their interfaces
+fn queue_packed(packed: Vec<packing::Packed<Arc<interface::NetworkInterface>>>) {
+    for packing::Packed {
+        via,
+        num_payloads,
+        tx_payload,
+    } in packed
+    {
+        let (to, payload_size) = (tx_payload.to, tx_payload.data.len());
+        match via.queue(tx_payload) {
+            Ok(()) => {
+                metrics::TX_SENDS_QUEUED.inc();
+                metrics::TX_PACKED_PAYLOADS.add(num_payloads as u64);
+                tracing::event!(
+                    tracing::Level::DEBUG,
+                    interface = %via.id,
+                    resolved_addr = %to,
+                    num_payloads = num_payloads,
+                    payload_size = payload_size,
+                    "PACKED_DATAGRAM_QUEUED"
+                );
+            }
+            Err(e) => {
+                metrics::TX_SEND_QUEUE_ERRORS.inc();
+                tracing::event!(
+                    tracing::Level::WARN,
+                    interface = %via.id,
+                    resolved_addr = %to,
+                    num_payloads = num_payloads,
+                    error = %e,
+                    "PACKED_DATAGRAM_QUEUE_ERROR"
+                );
+            }
+        }
+    }
+}
+
+/// Send `message` to a far gate over the path that a message `from` it came in on, through warp-map if that one was
+/// `relayed`
+fn reply_to_peer<M: Message>(
+    message: M,
+    far_gate: &warp_protocol::PublicKey,
+    receiver_name: &str,
+    from: std::net::SocketAddr,
+    relayed: bool,
+    context: &RxContext,
+) -> anyhow::Result<()> {
+    let RxContext {
+        routing_state,
+        sessions,
+        nonces,
+        relay,
+        ..
+    } = context;
+    let interface = routing_state
+        .interfaces()
+        .iter()
+        .find(|interface| interface.id.name == receiver_name)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("interface {receiver_name} is gone"))?;
+    let cipher = sessions
+        .cipher(far_gate)
+        .ok_or_else(|| anyhow::anyhow!("no session with the far gate"))?;
+    interface.queue_send(
+        seal_for_route(message, far_gate, &cipher, nonces, relayed.then_some(relay.as_ref()))?,
+        &from,
+        None,
+        interface::TrafficClass::default(),
+        None,
+    )
+}
+
+/// Decrypt a message from one of the far gates, trying the one `from` belongs to first and then each of the others, and
+/// remember which it was from if it wasn't the first
+fn decrypt_from_peer(
+    msg: &warp_protocol::codec::WireMessage,
+    from: std::net::SocketAddr,
+    routing_state: &routing::RoutingState,
+    sessions: &session::Sessions,
+) -> Option<(warp_protocol::PublicKey, session::Decrypted)> {
+    let now = std::time::Instant::now();
+    let likely_peer = routing_state.peer_at(from);
+    if let Some(far_gate) = likely_peer
+        && let Some(decrypted) = sessions.decrypt(&far_gate, msg, now)
+    {
+        return Some((far_gate, decrypted));
+    }
+
+    metrics::RX_TRIAL_DECRYPTIONS.inc();
+    let (far_gate, decrypted) = sessions
+        .peers()
+        .into_iter()
+        .filter(|far_gate| Some(*far_gate) != likely_peer)
+        .find_map(|far_gate| {
+            sessions
+                .decrypt(&far_gate, msg, now)
+                .map(|decrypted| (far_gate, decrypted))
+        })?;
+    routing_state.learn_peer_at(from, far_gate);
+    Some((far_gate, decrypted))
+}
+
+// What the rx workers need to act on the messages they receive
+struct RxContext {
+    warp_config: warp_config::WarpConfig,
+    warp_map_cipher: warp_protocol::Cipher,
+    routing_state: Arc<routing::RoutingState>,
+    sessions: Arc<session::Sessions>,
+    tunnel_gates: tokio::sync::watch::Receiver<TunnelGates>,
+    nonces: Arc<warp_protocol::replay::NonceSequence>,
+    retransmissions: Arc<std::sync::Mutex<reliable::RetransmissionQueue>>,
+    relay: Arc<relay::Relay>,
+    // Where TunnelSyncs from far gates go, to be applied along with the config
+    tunnel_syncs: tokio::sync::mpsc::UnboundedSender<(warp_protocol::PublicKey, warp_protocol::messages::TunnelSync)>,
+}
+
+// Act on each of the messages in a received datagram, `queue_length` being how many datagrams wait behind it
+async fn process_rx_payload(context: &RxContext, payload: &interface::RxPayload, queue_length: usize) {
+    let rx_start_time = std::time::Instant::now();
+    let rx_queue_duration = rx_start_time.saturating_duration_since(payload.received_at);
+    metrics::RX_PAYLOADS.inc();
+    metrics::RX_QUEUE_DEPTH.set(queue_length as i64);
+    metrics::RX_QUEUE_WAIT_SECONDS.observe_duration(rx_queue_duration);
+
+    let mut message_index = 0;
+    let mut remaining_buf = &payload.data[..];
+    loop {
+        let (msg, buf) = match warp_protocol::codec::WireMessage::from_slice(remaining_buf) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                // Without this message's length, the messages after it can't be found either
+                metrics::RX_MALFORMED_MESSAGES.inc();
+                tracing::event!(
+                    tracing::Level::WARN,
+                    interface = payload.receiver_name,
+                    from_addr = %payload.from,
+                    message_index = message_index,
+                    payload_size = payload.data.len(),
+                    error = %e,
+                    "RX_PAYLOAD_MALFORMED"
+                );
+                break;
+            }
+        };
+        let message_size = remaining_buf.len() - buf.len();
+        metrics::RX_MESSAGES.inc();
+        tracing::event!(
+            tracing::Level::DEBUG,
+            interface = payload.receiver_name,
+            from_addr = %payload.from,
+            message_index = message_index,
+            payload_size = payload.data.len(),
+            queue_length = queue_length,
+            "RX_MESSAGE"
+        );
+
+        // A message that can't be decoded is dropped without affecting the others
+        if let Err(e) = process_rx_message(context, payload, msg, message_size).await {
+            metrics::RX_MALFORMED_MESSAGES.inc();
+            tracing::event!(
+                tracing::Level::WARN,
+                interface = payload.receiver_name,
+                from_addr = %payload.from,
+                message_index = message_index,
+                error = %e,
+                "RX_MESSAGE_MALFORMED"
+            );
+        }
+
+        remaining_buf = buf;
+        if remaining_buf.is_empty() {
+            break;
+        }
+        message_index += 1;
+    }
+
+    // Log total RX processing time for this payload, apart from the time it spent waiting to be read and processed
+    let rx_processing_duration = rx_start_time.elapsed();
+    metrics::RX_PROCESSING_SECONDS.observe_duration(rx_processing_duration);
+    tracing::event!(
+        tracing::Level::DEBUG,
+        interface = payload.receiver_name,
+        socket_wait_us = payload.kernel_received_at.map(|kernel_received_at| payload
+            .received_at
+            .saturating_duration_since(kernel_received_at)
+            .as_micros()),
+        rx_queue_latency_us = rx_queue_duration.as_micros(),
+        rx_processing_latency_us = rx_processing_duration.as_micros(),
+        "Completed payload processing"
+    );
+}
+
+// The seconds from `sent`, on the sender's clock, until `payload` arrived, on ours; negative if the clocks are that far
+// apart
+fn latency_since(payload: &interface::RxPayload, sent: std::time::SystemTime) -> f32 {
+    let arrived = std::time::SystemTime::now() - payload.arrived_at().elapsed();
+    arrived
+        .duration_since(sent)
+        .map(|duration| duration.as_secs_f32())
+        .unwrap_or_else(|e| -e.duration().as_secs_f32())
+}
+
+// Act on one message from a received payload; returns an error if the message can't be decoded
+async fn process_rx_message(
+    context: &RxContext,
+    payload: &interface::RxPayload,
+    msg: warp_protocol::codec::WireMessage,
+    message_size: usize,
+) -> Result<(), warp_protocol::DecodeError> {
+    let RxContext {
+        warp_config,
+        warp_map_cipher,
+        routing_state,
+        ..
+    } = context;
+    match payload.from {
+        from if from == warp_config.warp_map.address => {
+            // warp-map wants the cookie back before it will open what this interface sends
+            if let Some(cookie) = warp_protocol::cookie::Cookie::from_wire_message(&msg) {
+                if let Some(interface) = routing_state
+                    .interfaces()
+                    .iter()
+                    .find(|interface| interface.id.name == payload.receiver_name)
+                {
+                    interface.set_map_cookie(cookie);
+                }
+                tracing::event!(
+                    tracing::Level::INFO,
+                    interface = payload.receiver_name,
+                    "MESSAGE_PROCESSED[Cookie]"
+                );
+                return Ok(());
+            }
+            let decrypted_wire_msg = msg.decrypt(warp_map_cipher)?;
+            match decrypted_wire_msg.message_id {
+                warp_protocol::messages::RegisterResponse::MESSAGE_ID => {
+                    let register_response: warp_protocol::messages::RegisterResponse = decrypted_wire_msg.decode()?;
+
+                    // Update external address for the receiving interface
+                    let interfaces = routing_state.interfaces();
+                    if let Some(interface) = interfaces
+                        .iter()
+                        .find(|interface| interface.id.name == payload.receiver_name)
+                    {
+                        interface.set_external_address(register_response.address);
+                        // Whether the NAT maps the interface to the same address for warp-map's other port
+                        let probe_address = register_response
+                            .probe_port
+                            .map(|port| std::net::SocketAddr::new(from.ip(), port));
+                        interface.set_map_probe_address(probe_address);
+                        if let Some(probe_address) = probe_address {
+                            send_nat_probe(context, interface, probe_address);
+                        }
+                    }
+
+                    tracing::event!(
+                        tracing::Level::INFO,
+                        interface = payload.receiver_name,
+                        public_address = %register_response.address,
+                        one_way_latency_warp_map = latency_since(payload, register_response.timestamp),
+                        round_trip_latency_warp_map = latency_since(payload, register_response.request_timestamp),
+                        correlation_id = warp_protocol::messages::registration_correlation_id(
+                            register_response.request_timestamp
+                        ),
+                        "MESSAGE_PROCESSED[RegisterResponse]"
+                    );
+                }
+                warp_protocol::messages::MappingResponse::MESSAGE_ID => {
+                    let mapping: warp_protocol::messages::MappingResponse = decrypted_wire_msg.decode()?;
+                    match routing_state.handle_mapping_response(&mapping) {
+                        // Most likely a far gate that isn't running yet, or a wrong public key in the config
+                        Some(status @ warp_protocol::messages::PeerStatus::Unknown) => tracing::event!(
+                            tracing::Level::WARN,
+                            peer = warp_protocol::crypto::pubkey_to_string(&mapping.peer_pubkey),
+                            status = ?status,
+                            "PEER_STATUS_CHANGED"
+                        ),
+                        Some(status) => tracing::event!(
+                            tracing::Level::INFO,
+                            peer = warp_protocol::crypto::pubkey_to_string(&mapping.peer_pubkey),
+                            status = ?status,
+                            "PEER_STATUS_CHANGED"
+                        ),
+                        None => {}
+                    }
+
+                    tracing::event!(
+                        tracing::Level::INFO,
+                        interface = payload.receiver_name,
+                        peer = warp_protocol::crypto::pubkey_to_string(&mapping.peer_pubkey),
+                        peer_addresses = format!("{:?}", mapping.endpoints),
+                        peer_host_candidates = format!("{:?}", mapping.host_candidates),
+                        active_overrides = routing_state.active_overrides_count(),
+                        one_way_latency_warp_map = latency_since(payload, mapping.timestamp),
+                        "MESSAGE_PROCESSED[MappingResponse]"
+                    );
+                }
+                warp_protocol::messages::ErrorResponse::MESSAGE_ID => {
+                    let error: warp_protocol::messages::ErrorResponse = decrypted_wire_msg.decode()?;
+                    // warp-map has lost the registration, perhaps after a restart; there's no need to wait for the next
+                    // scan to register again
+                    if error.code == warp_protocol::messages::ErrorCode::NotRegistered
+                        && let Some(interface) = routing_state
+                            .interfaces()
+                            .iter()
+                            .find(|interface| interface.id.name == payload.receiver_name)
+                    {
+                        interface.register_now();
+                    }
+
+                    tracing::event!(
+                        tracing::Level::ERROR,
+                        interface = payload.receiver_name,
+                        code = ?error.code,
+                        message = error.message.as_str(),
+                        request_message_id = error.request_message_id,
+                        correlation_id = error
+                            .request_timestamp
+                            .map(warp_protocol::messages::registration_correlation_id),
+                        "MESSAGE_PROCESSED[ErrorResponse]"
+                    );
+                }
+                warp_protocol::messages::RelayPayload::MESSAGE_ID => {
+                    let relayed: warp_protocol::messages::RelayPayload = decrypted_wire_msg.decode()?;
+                    // Only ever one message is wrapped
+                    let (msg, _) = warp_protocol::codec::WireMessage::from_slice(&relayed.data)?;
+                    metrics::RX_RELAYED_MESSAGES.inc();
+                    process_peer_message(context, payload, msg, relayed.data.len(), Some(relayed.peer_pubkey)).await?;
+                }
+                _ => {
+                    tracing::event!(
+                        tracing::Level::WARN,
+                        interface = payload.receiver_name,
+                        "UNKNOWN_MESSAGE_FROM_WARP_MAP"
+                    );
+                }
+            }
+        }
+        from if routing_state.interfaces().iter().any(|interface| {
+            interface.id.name == payload.receiver_name && interface.map_probe_address() == Some(from)
+        }) =>
+        {
+            let decrypted_wire_msg = msg.decrypt(warp_map_cipher)?;
+            let probe: warp_protocol::messages::NatProbeResponse = decrypted_wire_msg.decode()?;
+            let interfaces = routing_state.interfaces();
+            let Some(interface) = interfaces
+                .iter()
+                .find(|interface| interface.id.name == payload.receiver_name)
+            else {
+                return Ok(());
+            };
+            if let Some(mapping) = interface.record_nat_probe(probe.address) {
+                metrics::SYMMETRIC_NAT_INTERFACES.set(
+                    interfaces
+                        .iter()
+                        .filter(|interface| interface.nat_mapping() == Some(interface::NatMapping::EndpointDependent))
+                        .count() as i64,
+                );
+                tracing::event!(
+                    tracing::Level::INFO,
+                    interface = payload.receiver_name,
+                    public_address = ?interface.get_external_address(),
+                    probed_address = %probe.address,
+                    mapping = ?mapping,
+                    "NAT_MAPPING_DETECTED"
+                );
+            }
+            tracing::event!(
+                tracing::Level::DEBUG,
+                interface = payload.receiver_name,
+                probed_address = %probe.address,
+                round_trip_latency_warp_map = latency_since(payload, probe.request_timestamp),
+                "MESSAGE_PROCESSED[NatProbeResponse]"
+            );
+        }
+        _ => process_peer_message(context, payload, msg, message_size, None).await?,
+    }
+    Ok(())
+}
+
+// Ask warp-map's probe address where it sees `interface` at, to compare with where its registration was seen
+fn send_nat_probe(context: &RxContext, interface: &interface::NetworkInterface, probe_address: std::net::SocketAddr) {
+    let probe = warp_protocol::messages::NatProbeRequest {
+        pubkey: context.warp_config.private_key.public_key(),
+        timestamp: std::time::SystemTime::now(),
+    };
+    if let Err(e) = probe
+        .encode()
+        .and_then(|encoded| encoded.encrypt(&context.warp_map_cipher))
+        .and_then(|encrypted| encrypted.to_bytes())
+        .map_err(anyhow::Error::from)
+        .and_then(|data| interface.queue_send(data, &probe_address, None, interface::TrafficClass::default(), None))
+    {
+        tracing::event!(
+            tracing::Level::WARN,
+            interface = %interface.id,
+            probe_address = %probe_address,
+            error = %e,
+            "NAT_PROBE_SEND_FAILED"
+        );
+    }
+}
+
+// Act on one message from a far gate, which warp-map relayed from `relayed_from` if that is given; returns an error if
+// the message can't be decoded
+async fn process_peer_message(
+    context: &RxContext,
+    payload: &interface::RxPayload,
+    msg: warp_protocol::codec::WireMessage,
+    message_size: usize,
+    relayed_from: Option<warp_protocol::PublicKey>,
+) -> Result<(), warp_protocol::DecodeError> {
+    let RxContext {
+        routing_state,
+        sessions,
+        tunnel_gates,
+        retransmissions,
+        tunnel_syncs,
+        ..
+    } = context;
+    let from = payload.from;
+    let now = std::time::Instant::now();
+    let relayed = relayed_from.is_some();
+    let decrypted = match relayed_from {
+        // warp-map vouches for who sent it, so only that far gate's session can open it
+        Some(far_gate) => sessions
+            .decrypt(&far_gate, &msg, now)
+            .map(|decrypted| (far_gate, decrypted)),
+        None => decrypt_from_peer(&msg, from, routing_state, sessions),
+    };
+    // Relayed messages show the far gate is up, but nothing about the paths to it
+    let heard_from = |far_gate: &warp_protocol::PublicKey| {
+        if relayed {
+            routing_state.heard_via_relay(far_gate, now);
+        } else {
+            routing_state.heard_from(far_gate, from, now);
+        }
+    };
+    match decrypted {
+        Some((far_gate, session::Decrypted::Duplicate(decrypted_wire_msg))) => {
+            // Redundant copies still show that the path they came over works
+            heard_from(&far_gate);
+            metrics::RX_DUPLICATE_MESSAGES.inc();
+            tracing::event!(
+                tracing::Level::DEBUG,
+                interface = payload.receiver_name,
+                from_addr = %from,
+                message_id = decrypted_wire_msg.message_id,
+                "RX_MESSAGE_DUPLICATE"
+            );
+        }
+        Some((far_gate, session::Decrypted::Message(decrypted_wire_msg))) => {
+            heard_from(&far_gate);
+            sessions.record_bytes(&far_gate, message_size);
+            match decrypted_wire_msg.message_id {
+                message_id @ (warp_protocol::messages::TunnelPayload::MESSAGE_ID
+                | warp_protocol::messages::KeyedTunnelPayload::MESSAGE_ID) => {
+                    let tunnel_payload: warp_protocol::messages::TunnelPayload =
+                        if message_id == warp_protocol::messages::KeyedTunnelPayload::MESSAGE_ID {
+                            decrypted_wire_msg
+                                .decode::<warp_protocol::messages::KeyedTunnelPayload>()?
+                                .into()
+                        } else {
+                            decrypted_wire_msg.decode()?
+                        };
+                    // The interface's hop ends where decrypting starts
+                    let interface_rx = TraceContext::received(
+                        Correlation::of_tunnel_payload(&tunnel_payload),
+                        payload.span_id,
+                        payload.arrived_at(),
+                    );
+                    interface_rx.export_ended_at("interface_rx", now);
+                    payload
+                        .traffic
+                        .record_tunnel_received(interface_rx.correlation.tunnel(), message_size);
+                    let trace = TraceContext {
+                        started: now,
+                        ..interface_rx.child()
+                    };
+                    trace.export("decrypt");
+                    tracing::event!(
+                        tracing::Level::DEBUG,
+                        interface = payload.receiver_name,
+                        from_addr = %from,
+                        tracer = tunnel_payload.tracer,
+                        correlation_id = %trace.correlation,
+                        span_id = trace.span_id,
+                        parent_span_id = trace.parent_span_id,
+                        payload_size = tunnel_payload.data.len(),
+                        "TUNNEL_PAYLOAD_RX"
+                    );
+                    let gate = tunnel_gates
+                        .borrow()
+                        .get(&far_gate)
+                        .and_then(|gates| gates.get(&tunnel_payload.tunnel_id))
+                        .cloned();
+                    match gate {
+                        None => {
+                            tracing::warn!(
+                                "Received data at {} for unknown tunnel {:?} from {}",
+                                &payload.receiver,
+                                &tunnel_payload.tunnel_id,
+                                from
+                            );
+                        }
+                        Some(gate) => gate.send_to_application(tunnel_payload, trace.child()).await,
+                    }
+                }
+                warp_protocol::messages::TunnelAck::MESSAGE_ID => {
+                    let ack: warp_protocol::messages::TunnelAck = decrypted_wire_msg.decode()?;
+                    let acknowledged =
+                        retransmissions
+                            .lock()
+                            .unwrap()
+                            .acknowledge(&far_gate, &ack, std::time::Instant::now());
+                    tracing::event!(
+                        tracing::Level::DEBUG,
+                        interface = payload.receiver_name,
+                        from_addr = %from,
+                        tunnel_id = ?ack.tunnel_id,
+                        tracers = ack.tracers.len(),
+                        acknowledged = acknowledged,
+                        "TUNNEL_ACK_RX"
+                    );
+                }
+                warp_protocol::messages::TunnelSync::MESSAGE_ID => {
+                    let sync: warp_protocol::messages::TunnelSync = decrypted_wire_msg.decode()?;
+                    tracing::event!(
+                        tracing::Level::DEBUG,
+                        interface = payload.receiver_name,
+                        from_addr = %from,
+                        tunnels = sync.tunnels.len(),
+                        "TUNNEL_SYNC_RX"
+                    );
+                    let _ = tunnel_syncs.send((far_gate, sync));
+                }
+                warp_protocol::messages::Heartbeat::MESSAGE_ID => {
+                    let heartbeat: warp_protocol::messages::Heartbeat = decrypted_wire_msg.decode()?;
+                    tracing::event!(
+                        tracing::Level::TRACE,
+                        interface = payload.receiver_name,
+                        from_addr = %from,
+                        one_way_latency = latency_since(payload, heartbeat.timestamp),
+                        "HEARTBEAT_RX"
+                    );
+                }
+                warp_protocol::messages::PathProbe::MESSAGE_ID => {
+                    let probe: warp_protocol::messages::PathProbe = decrypted_wire_msg.decode()?;
+                    let reply = warp_protocol::messages::PathProbeReply {
+                        sequence: probe.sequence,
+                        timestamp: std::time::SystemTime::now(),
+                        probe_timestamp: probe.timestamp,
+                    };
+
+                    // Answer over the path the probe came in on
+                    if let Err(e) = reply_to_peer(reply, &far_gate, &payload.receiver_name, from, relayed, context) {
+                        tracing::event!(
+                            tracing::Level::WARN,
+                            interface = payload.receiver_name,
+                            peer_addr = %from,
+                            error = %e,
+                            "PATH_PROBE_REPLY_SEND_FAILED"
+                        );
+                    }
+                }
+                warp_protocol::messages::RekeyRequest::MESSAGE_ID => {
+                    let request: warp_protocol::messages::RekeyRequest = decrypted_wire_msg.decode()?;
+                    if let Some(response) = sessions.handle_rekey_request(&far_gate, &request)
+                        && let Err(e) =
+                            reply_to_peer(response, &far_gate, &payload.receiver_name, from, relayed, context)
+                    {
+                        tracing::event!(
+                            tracing::Level::WARN,
+                            interface = payload.receiver_name,
+                            peer_addr = %from,
+                            error = %e,
+                            "REKEY_RESPONSE_SEND_FAILED"
+                        );
+                    }
+                }
+                warp_protocol::messages::RekeyResponse::MESSAGE_ID => {
+                    let response: warp_protocol::messages::RekeyResponse = decrypted_wire_msg.decode()?;
+                    if sessions.handle_rekey_response(&far_gate, &response, std::time::Instant::now()) {
+                        tracing::event!(
+                            tracing::Level::INFO,
+                            peer = warp_protocol::crypto::pubkey_to_string(&far_gate),
+                            epoch = response.epoch,
+                            "SESSION_REKEYED"
+                        );
+                    }
+                }
+                warp_protocol::messages::PathProbeReply::MESSAGE_ID => {
+                    let reply: warp_protocol::messages::PathProbeReply = decrypted_wire_msg.decode()?;
+                    if let Some((interface, peer_addr, rtt)) =
+                        routing_state.handle_path_probe_reply(&reply, payload.arrived_at())
+                    {
+                        let stats = interface.path_stats(peer_addr);
+                        tracing::event!(
+                            tracing::Level::DEBUG,
+                            interface = %interface.id,
+                            peer_addr = %peer_addr,
+                            rtt = rtt.as_secs_f32(),
+                            smoothed_rtt = stats.rtt.map(|rtt| rtt.as_secs_f32()),
+                            loss = stats.loss,
+                            one_way_latency = latency_since(payload, reply.timestamp),
+                            "PATH_PROBE_REPLY"
+                        );
+                    }
+                }
+                warp_protocol::messages::PeerAddressOverride::MESSAGE_ID => {
+                    let override_msg: warp_protocol::messages::PeerAddressOverride = decrypted_wire_msg.decode()?;
+
+                    // Update address override for the specific interface that received this message; a relayed
+                    // one came from warp-map's address rather than the far gate's
+                    if !relayed
+                        && let Err(rejection) = routing_state.handle_peer_address_override(
+                            &far_gate,
+                            &override_msg,
+                            from,
+                            &payload.receiver_name,
+                            now,
+                        )
+                    {
+                        metrics::RX_REJECTED_OVERRIDES.inc();
+                        tracing::event!(
+                            tracing::Level::WARN,
+                            interface = payload.receiver_name,
+                            peer = warp_protocol::crypto::pubkey_to_string(&far_gate),
+                            peer_addr = %from,
+                            replace_addr = %override_msg.replace,
+                            sequence = override_msg.sequence,
+                            reason = %rejection,
+                            "OVERRIDE_REJECTED"
+                        );
+                    }
+                }
+                _ => {
+                    tracing::warn!(
+                        "Received unexpected message at {} from {}; {:?}",
+                        &payload.receiver,
+                        from,
+                        decrypted_wire_msg
+                    );
+                }
+            }
+        }
+        None => {
+            metrics::RX_INVALID_MESSAGES.inc();
+            tracing::info!(
+                "Received invalid message at {} from {}; ignoring",
+                &payload.receiver,
+                from
+            );
+        }
+    }
+    Ok(())
+}
+
+struct AbortOnDrop(Vec<tokio::task::AbortHandle>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}